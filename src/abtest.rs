@@ -0,0 +1,335 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io::*;
+use rustc_serialize::hex::ToHex;
+
+use binacle_manager::BinacleManager;
+use corpus::{self, Truth};
+use queries::Query;
+use search::SearchResponse;
+
+// Comparison of the results of two databases over a file of queries, to
+// check a migration (reindex, compaction, format upgrade) keeps them. Each
+// query is run against both databases, its results compared then dropped:
+// only the counts and a few examples are kept. When both databases have a
+// map the results are compared by the sha256 of the files (their path when
+// it was not recorded), the ids may differ by design. Otherwise the ids are.
+
+
+// results found on one side only, kept as examples, per query and side
+pub const MAX_EXAMPLES: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyMode {
+    Hash,
+    Id,
+}
+
+impl KeyMode {
+
+    pub fn of(a: &BinacleManager, b: &BinacleManager) -> KeyMode {
+        match a.is_map() && b.is_map() {
+            true => KeyMode::Hash,
+            false => KeyMode::Id,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KeyMode::Hash => "sha256",
+            KeyMode::Id => "id",
+        }
+    }
+}
+
+// results of a side against the ground truth
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Accuracy {
+    pub expected: usize,
+    pub found: usize,
+    pub true_positives: usize,
+}
+
+impl Accuracy {
+
+    fn add(&mut self, other: &Accuracy) {
+        self.expected += other.expected;
+        self.found += other.found;
+        self.true_positives += other.true_positives;
+    }
+
+    pub fn recall(&self) -> f64 {
+        match self.expected {
+            0 => 1.0,
+            n => self.true_positives as f64 / n as f64,
+        }
+    }
+
+    pub fn precision(&self) -> f64 {
+        match self.found {
+            0 => 1.0,
+            n => self.true_positives as f64 / n as f64,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub name: String,
+    pub line: usize,
+    // number of results of each side, the error when the query failed
+    pub a: ::std::result::Result<usize, String>,
+    pub b: ::std::result::Result<usize, String>,
+    pub only_a: usize,
+    pub only_b: usize,
+    // paths (ids without a map) of the first ones
+    pub examples_a: Vec<String>,
+    pub examples_b: Vec<String>,
+    // of each side, when the query is a planted pattern of the truth
+    pub accuracy: Option<(Accuracy, Accuracy)>,
+}
+
+impl Comparison {
+
+    pub fn agree(&self) -> bool {
+        match (&self.a, &self.b) {
+            (&Ok(_), &Ok(_)) => self.only_a == 0 && self.only_b == 0,
+            (&Err(_), &Err(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub queries: usize,
+    pub agreed: usize,
+    // on one side at least
+    pub failed: usize,
+    // summed over the queries of the truth
+    pub accuracy: Option<(Accuracy, Accuracy)>,
+}
+
+impl Summary {
+
+    pub fn disagreed(&self) -> usize {
+        self.queries - self.agreed
+    }
+}
+
+// (key, shown) of the results of a side, sorted by key, a key once
+fn keys(db: &BinacleManager, response: &SearchResponse, mode: KeyMode) -> Vec<(String, String)> {
+
+    let mut keys: Vec<(String, String)> = response.ids.iter().map(|id| {
+        match (mode, db.entry(*id)) {
+            (KeyMode::Hash, Some(entry)) => match entry.sha256 {
+                Some(ref h) => (format!("sha256:{}", h), entry.path.clone()),
+                None => (entry.path.clone(), entry.path.clone()),
+            },
+            _ => (id.to_string(), id.to_string()),
+        }
+    }).collect();
+    keys.sort();
+    keys.dedup_by(|x, y| x.0 == y.0);
+    keys
+}
+
+// the results found on one side only, both lists being sorted
+fn diff(a: &[(String, String)], b: &[(String, String)], cmp: &mut Comparison) {
+
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let order = match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) => x.0.cmp(&y.0),
+            (Some(_), None) => Ordering::Less,
+            (None, _) => Ordering::Greater,
+        };
+        match order {
+            Ordering::Equal => {
+                i += 1;
+                j += 1;
+            },
+            Ordering::Less => {
+                cmp.only_a += 1;
+                if cmp.examples_a.len() < MAX_EXAMPLES {
+                    cmp.examples_a.push(a[i].1.clone());
+                }
+                i += 1;
+            },
+            Ordering::Greater => {
+                cmp.only_b += 1;
+                if cmp.examples_b.len() < MAX_EXAMPLES {
+                    cmp.examples_b.push(b[j].1.clone());
+                }
+                j += 1;
+            },
+        }
+    }
+}
+
+fn accuracy(response: &SearchResponse, expected: &HashSet<String>) -> Accuracy {
+    let found: HashSet<String> = response.entries.iter().map(|p| corpus::normalize(p)).collect();
+    Accuracy {
+        expected: expected.len(),
+        found: found.len(),
+        true_positives: found.intersection(expected).count(),
+    }
+}
+
+// the planted files of a query made of a single pattern of the truth
+fn planted(truth: &Truth, query: &Query) -> Option<HashSet<String>> {
+    match query.request.patterns() {
+        [pattern] => {
+            let hex = pattern.to_hex();
+            truth.patterns.iter()
+                .find(|p| p.pattern.to_lowercase() == hex)
+                .map(|p| p.files.iter().map(|f| corpus::normalize(f)).collect())
+        },
+        _ => None,
+    }
+}
+
+// run each query against both databases, f is given each comparison
+pub fn ab_test<F: FnMut(&Comparison)>(a: &mut BinacleManager, b: &mut BinacleManager, queries: &[Query],
+                                      truth: Option<&Truth>, mut f: F) -> Result<Summary> {
+
+    if truth.is_some() && !(a.is_map() && b.is_map()) {
+        return Err(Error::new(ErrorKind::InvalidInput, "the ground truth needs two map databases"));
+    }
+
+    let mode = KeyMode::of(a, b);
+    let mut summary = Summary::default();
+    if truth.is_some() {
+        summary.accuracy = Some((Accuracy::default(), Accuracy::default()));
+    }
+
+    for query in queries {
+
+        let mut cmp = Comparison {
+            name: query.name.clone(),
+            line: query.line,
+            a: Ok(0),
+            b: Ok(0),
+            only_a: 0,
+            only_b: 0,
+            examples_a: Vec::new(),
+            examples_b: Vec::new(),
+            accuracy: None,
+        };
+        let expected = truth.and_then(|t| planted(t, query));
+
+        let ra = a.run(&query.request);
+        let rb = b.run(&query.request);
+        match (&ra, &rb) {
+            (&Ok(ref ra), &Ok(ref rb)) => {
+                diff(&keys(a, ra, mode), &keys(b, rb, mode), &mut cmp);
+                if let Some(ref expected) = expected {
+                    cmp.accuracy = Some((accuracy(ra, expected), accuracy(rb, expected)));
+                }
+            },
+            _ => summary.failed += 1,
+        }
+        cmp.a = ra.as_ref().map(|r| r.ids.len()).map_err(|e| e.to_string());
+        cmp.b = rb.as_ref().map(|r| r.ids.len()).map_err(|e| e.to_string());
+        drop(ra);
+        drop(rb);
+
+        summary.queries += 1;
+        if cmp.agree() {
+            summary.agreed += 1;
+        }
+        if let (Some(sum), Some(q)) = (summary.accuracy.as_mut(), cmp.accuracy) {
+            sum.0.add(&q.0);
+            sum.1.add(&q.1);
+        }
+        f(&cmp);
+    }
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+    use binacle_manager::tests::cleanup;
+    use corpus::{Plant, SizeDist};
+    use queries::parse_query;
+
+    fn query(line: &str) -> Query {
+        parse_query(line, 1).unwrap().unwrap()
+    }
+
+    #[test]
+    fn same_files_other_ids() {
+
+        let _ = fs::remove_dir_all("test_abtest_corpus");
+        let plants = Plant::parse_list("c0a80101@4,4765745072@4").unwrap();
+        let dist = SizeDist::parse("lognormal:7,0.5").unwrap();
+        let truth = corpus::generate("test_abtest_corpus", 12, dist, 5, &plants).unwrap();
+        let dropped = truth.patterns[1].files.iter().find(|f| !truth.patterns[0].files.contains(f)).unwrap().clone();
+        {
+            // the same files, numbered the other way round, and one of them missing
+            let mut a = BinacleManager::create("test_abtest_a.db", true, 1 << 30, 6, 22).unwrap();
+            let mut b = BinacleManager::create("test_abtest_b.db", true, 1 << 30, 4, 22).unwrap();
+            let n = truth.files.len() as u32;
+            for (i, path) in truth.files.iter().enumerate() {
+                a.insert_file(path, i as u32 + 1, false).unwrap();
+            }
+            // the ids of a database are inserted in increasing order
+            for (i, path) in truth.files.iter().enumerate().rev() {
+                if *path != dropped {
+                    b.insert_file(path, n - i as u32, false).unwrap();
+                }
+            }
+
+            let queries = vec![
+                query("planted: hex:c0a80101"),
+                query("both: hex:c0a80101 hex:4765745072"),
+                query("missing: hex:4765745072 | limit=1000"),
+            ];
+            let mut comparisons = Vec::new();
+            let summary = ab_test(&mut a, &mut b, &queries, Some(&truth), |c| comparisons.push(c.clone())).unwrap();
+
+            assert_eq!(3, summary.queries);
+            assert_eq!(2, summary.agreed);
+            assert_eq!(1, summary.disagreed());
+            assert_eq!(0, summary.failed);
+            assert!(comparisons[0].agree());
+            assert_eq!(Ok(4), comparisons[0].a);
+            assert!(comparisons[0].accuracy.is_some());
+            // not a single pattern of the truth
+            assert!(comparisons[1].accuracy.is_none());
+
+            let missing = &comparisons[2];
+            assert!(!missing.agree());
+            assert_eq!((1, 0), (missing.only_a, missing.only_b));
+            assert_eq!(vec![dropped.clone()], missing.examples_a);
+            let (qa, qb) = missing.accuracy.unwrap();
+            assert_eq!(1.0, qa.recall());
+            assert_eq!((4, 3), (qb.expected, qb.true_positives));
+
+            let (sa, sb) = summary.accuracy.unwrap();
+            assert_eq!(1.0, sa.recall());
+            assert_eq!(1.0, sa.precision());
+            assert!(sb.recall() < 1.0);
+
+            // without a map the ids are compared, they differ
+            let mut c = BinacleManager::create("test_abtest_c.db", false, 1 << 30, 6, 22).unwrap();
+            for (i, path) in truth.files.iter().enumerate() {
+                c.insert_file(path, i as u32 + 1, false).unwrap();
+            }
+            assert_eq!(KeyMode::Id, KeyMode::of(&a, &c));
+            assert!(ab_test(&mut a, &mut c, &queries, Some(&truth), |_| {}).is_err());
+            let summary = ab_test(&mut a, &mut c, &queries[.. 1], None, |_| {}).unwrap();
+            assert_eq!(1, summary.agreed);
+            let summary = ab_test(&mut b, &mut c, &queries[.. 1], None, |_| {}).unwrap();
+            assert_eq!(0, summary.agreed);
+        }
+        cleanup("test_abtest_a");
+        cleanup("test_abtest_b");
+        cleanup("test_abtest_c");
+        let _ = fs::remove_dir_all("test_abtest_corpus");
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::io::*;
+use std::path::{Path, PathBuf, Component, MAIN_SEPARATOR};
+
+use binacle_manager::BinacleManager;
+
+// Counts of the results of a search by tag, content type or directory: the
+// entries of the map are read one by one and only the count of each group is
+// kept, not its paths. An id without the attribute is counted under (none),
+// an id with several tags once in each of them.
+
+
+pub const NONE: &'static str = "(none)";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    Tag,
+    Type,
+    // the first directories of the path
+    Dir(usize),
+}
+
+impl Aggregation {
+
+    // count-by:tag, count-by:type or count-by:dir:<depth>
+    pub fn parse(spec: &str) -> Result<Aggregation> {
+        let invalid = || Error::new(ErrorKind::InvalidInput,
+            format!("invalid aggregation {}: count-by:tag, count-by:type or count-by:dir:<depth>", spec));
+        let by = match spec.starts_with("count-by:") {
+            true => &spec["count-by:".len() ..],
+            false => return Err(invalid()),
+        };
+        match by {
+            "tag" => Ok(Aggregation::Tag),
+            "type" => Ok(Aggregation::Type),
+            _ if by.starts_with("dir:") => match by["dir:".len() ..].parse::<usize>() {
+                Ok(depth) if depth > 0 => Ok(Aggregation::Dir(depth)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// prefix made of the first `depth` directories of the path
+// files with less than `depth` directories are grouped by their parent
+pub fn dir_prefix(path: &str, depth: usize) -> String {
+
+    let parent = match Path::new(path).parent() {
+        Some(p) => p,
+        None => return String::from("."),
+    };
+
+    let mut prefix = PathBuf::new();
+    let mut nb_dir = 0;
+
+    for c in parent.components() {
+        if nb_dir >= depth {
+            break;
+        }
+        if let Component::Normal(_) = c {
+            nb_dir += 1;
+        }
+        prefix.push(c.as_os_str());
+    }
+
+    let mut prefix = prefix.to_string_lossy().into_owned();
+    if prefix.is_empty() {
+        return String::from(".");
+    }
+    if !prefix.ends_with(MAIN_SEPARATOR) {
+        prefix.push(MAIN_SEPARATOR);
+    }
+    prefix
+}
+
+// the groups of the ids and their count, the biggest groups first
+pub fn count_by(db: &BinacleManager, ids: &[u32], by: Aggregation) -> Result<Vec<(String, usize)>> {
+
+    if !db.is_map() {
+        return Err(Error::new(ErrorKind::InvalidInput, "the aggregations need a map database"));
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut add = |group: &str| *counts.entry(String::from(group)).or_insert(0) += 1;
+    for id in ids {
+        let entry = match db.entry(*id) {
+            Some(e) => e,
+            None => {
+                add(NONE);
+                continue;
+            },
+        };
+        match by {
+            Aggregation::Tag => match entry.tags {
+                Some(ref tags) if !tags.is_empty() => {
+                    for tag in tags {
+                        add(tag);
+                    }
+                },
+                _ => add(NONE),
+            },
+            Aggregation::Type => add(entry.file_type.as_ref().map_or(NONE, |t| t.as_str())),
+            Aggregation::Dir(depth) => add(&dir_prefix(&entry.path, depth)),
+        }
+    }
+
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(counts)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle_manager::tests::cleanup;
+    use search::SearchRequest;
+
+    #[test]
+    fn prefix_depth() {
+        assert_eq!("/corpus/", dir_prefix("/corpus/packed/a.exe", 1));
+        assert_eq!("/corpus/packed/", dir_prefix("/corpus/packed/a.exe", 2));
+        assert_eq!("/corpus/packed/", dir_prefix("/corpus/packed/a.exe", 5));
+        assert_eq!("a/", dir_prefix("a/b/c.bin", 1));
+        assert_eq!(".", dir_prefix("c.bin", 1));
+    }
+
+    #[test]
+    fn specs() {
+        assert_eq!(Aggregation::Tag, Aggregation::parse("count-by:tag").unwrap());
+        assert_eq!(Aggregation::Type, Aggregation::parse("count-by:type").unwrap());
+        assert_eq!(Aggregation::Dir(2), Aggregation::parse("count-by:dir:2").unwrap());
+        for spec in &["count-by:dir:0", "count-by:dir", "count-by:label", "tag"] {
+            assert!(Aggregation::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn fixture_counts() {
+        let files: [(&str, &[u8], &[&str]); 6] = [
+            ("c/pe/1.exe", b"MZ GetProcAddress", &["emotet", "2024"]),
+            ("c/pe/2.exe", b"MZ GetProcAddress", &["emotet"]),
+            ("c/pe/3.exe", b"MZ GetProcAddress", &["trickbot"]),
+            ("c/elf/4", b"\x7fELF GetProcAddress", &[]),
+            ("d/5.bin", b"GetProcAddress", &["emotet"]),
+            ("d/6.bin", b"LoadLibraryA", &["emotet"]),
+        ];
+        {
+            let mut db = BinacleManager::create("test_aggregate.db", true, 1 << 30, 6, 16).unwrap();
+            for (i, &(path, content, tags)) in files.iter().enumerate() {
+                db.insert_reader(content, path, i as u32 + 1, false);
+                db.set_tags(i as u32 + 1, tags.iter().map(|t| String::from(*t)).collect()).unwrap();
+            }
+            let ids = db.run(&SearchRequest::pattern(b"GetProc")).unwrap().ids;
+            assert_eq!(5, ids.len());
+
+            let s = |v: Vec<(&str, usize)>| v.into_iter().map(|(g, n)| (String::from(g), n)).collect::<Vec<(String, usize)>>();
+            assert_eq!(s(vec![("emotet", 3), ("(none)", 1), ("2024", 1), ("trickbot", 1)]),
+                       count_by(&db, &ids, Aggregation::Tag).unwrap());
+            assert_eq!(s(vec![("pe", 3), ("elf", 1), ("text", 1)]),
+                       count_by(&db, &ids, Aggregation::Type).unwrap());
+            assert_eq!(s(vec![("c/", 4), ("d/", 1)]), count_by(&db, &ids, Aggregation::Dir(1)).unwrap());
+            assert_eq!(s(vec![("c/pe/", 3), ("c/elf/", 1), ("d/", 1)]),
+                       count_by(&db, &ids, Aggregation::Dir(2)).unwrap());
+        }
+        {
+            let mut db = BinacleManager::create("test_aggregate2.db", false, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "", 1, false);
+            assert!(count_by(&db, &[1], Aggregation::Tag).is_err());
+        }
+        cleanup("test_aggregate");
+        cleanup("test_aggregate2");
+    }
+}
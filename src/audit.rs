@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::io::*;
+use std::path::Path;
+
+use binacle_manager::BinacleManager;
+
+// Cross-check of the ids found in the indexes against the map.
+// Nothing is modified until the entries are applied.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditIssue {
+    // in the index, not in the map
+    Unmapped,
+    // in the map, the file is gone and no hash was stored
+    MissingFile,
+    // the same path was indexed again under a newer id
+    Superseded(u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub id: u32,
+    pub path: Option<String>,
+    pub issue: AuditIssue,
+}
+
+impl AuditIssue {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuditIssue::Unmapped => "unmapped",
+            AuditIssue::MissingFile => "missing",
+            AuditIssue::Superseded(_) => "superseded",
+        }
+    }
+
+    pub fn action(&self) -> &'static str {
+        match *self {
+            AuditIssue::Unmapped => "tombstone",
+            AuditIssue::MissingFile | AuditIssue::Superseded(_) => "tombstone and remove from map",
+        }
+    }
+}
+
+// list the stale ids of a map database, sorted by id
+pub fn audit_ids(db: &mut BinacleManager) -> Result<Vec<AuditEntry>> {
+
+    if !db.is_map() {
+        return Err(Error::new(ErrorKind::Other, "id audit needs a map database"));
+    }
+
+    let universe = try!(db.index_ids());
+    let mut report = Vec::new();
+
+    for id in universe {
+        if !db.is_tombstoned(id) && db.entry(id).is_none() {
+            report.push(AuditEntry { id: id, path: None, issue: AuditIssue::Unmapped });
+        }
+    }
+
+    // latest id of every path
+    let mut latest: HashMap<&str, u32> = HashMap::new();
+    for (id, entry) in db.entries() {
+        let last = latest.entry(entry.path.as_str()).or_insert(id);
+        if id > *last {
+            *last = id;
+        }
+    }
+
+    for (id, entry) in db.entries() {
+        if db.is_tombstoned(id) {
+            continue;
+        }
+        let last = latest[entry.path.as_str()];
+        let issue = if last != id {
+            AuditIssue::Superseded(last)
+        } else if entry.sha256.is_none() && !Path::new(&entry.path).exists() {
+            AuditIssue::MissingFile
+        } else {
+            continue;
+        };
+        report.push(AuditEntry { id: id, path: Some(entry.path.clone()), issue: issue });
+    }
+
+    report.sort_by_key(|e| e.id);
+    Ok(report)
+}
+
+// perform the recommended actions
+pub fn apply(db: &mut BinacleManager, report: &[AuditEntry]) -> Result<()> {
+
+    for entry in report {
+        db.tombstone(entry.id);
+        match entry.issue {
+            AuditIssue::Unmapped => {},
+            AuditIssue::MissingFile | AuditIssue::Superseded(_) => {
+                db.remove_entry(entry.id);
+            },
+        }
+    }
+    db.flush_map()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs::{File, remove_file};
+    use rustc_serialize::json;
+    use binacle_manager::MapEntry;
+
+    fn cleanup(name: &str) {
+        for ext in &["db", "map", "hashes", "db_index0.db", "db_index0.meta", "db_index0.bitmap"] {
+            let _ = remove_file(format!("{}.{}", name, ext));
+        }
+    }
+
+    #[test]
+    fn audit_categories() {
+        {
+            let mut db = BinacleManager::create("test_audit1.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_file("Cargo.toml", 1, false).unwrap();
+            db.insert_file("Cargo.toml", 2, false).unwrap();
+            db.insert_reader(&b"orphan content"[..], "orphan", 3, false);
+            db.remove_entry(3);
+            db.insert_reader(&b"missing content"[..], "does/not/exist", 4, false);
+            db.insert_reader(&b"kept content"[..], "does/not/exist/either", 5, false);
+        }
+        {
+            // entries written before hashes were stored
+            let mut encoded = String::new();
+            File::open("test_audit1.map").unwrap().read_to_string(&mut encoded).unwrap();
+            let mut map: HashMap<u32, MapEntry> = json::decode(&encoded).unwrap();
+            map.get_mut(&4).unwrap().sha256 = None;
+            File::create("test_audit1.map").unwrap().write_all(json::encode(&map).unwrap().as_bytes()).unwrap();
+        }
+        {
+            let mut db = BinacleManager::open("test_audit1.db").unwrap();
+            let report = audit_ids(&mut db).unwrap();
+            let issues: Vec<(u32, AuditIssue)> = report.iter().map(|e| (e.id, e.issue.clone())).collect();
+            assert_eq!(vec![
+                (1, AuditIssue::Superseded(2)),
+                (3, AuditIssue::Unmapped),
+                (4, AuditIssue::MissingFile),
+            ], issues);
+
+            // dry run by default
+            assert_eq!(3, audit_ids(&mut db).unwrap().len());
+            assert_eq!(1, db.search(b"orphan content").unwrap().len());
+
+            apply(&mut db, &report).unwrap();
+            assert!(audit_ids(&mut db).unwrap().is_empty());
+            assert!(db.search(b"orphan content").unwrap().is_empty());
+            assert!(db.path_of(1).is_none());
+            assert!(db.path_of(2).is_some());
+            assert!(db.path_of(5).is_some());
+        }
+        {
+            // tombstones are persisted
+            let mut db = BinacleManager::open("test_audit1.db").unwrap();
+            assert!(db.is_tombstoned(3));
+            assert!(db.search(b"missing content").unwrap().is_empty());
+        }
+        cleanup("test_audit1");
+    }
+}
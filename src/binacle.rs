@@ -2,24 +2,63 @@ extern crate memmap;
 extern crate rustc_serialize;
 extern crate fs2;
 
-use std::fs::{self, File};
+use std::fs::{self, File, Metadata, remove_file};
 use std::io::*;
 use std::ptr;
 use std::fs::OpenOptions;
 use std::cmp::{min, max};
-use std::collections::{HashSet};
-use std::path::PathBuf;
-use rustc_serialize::json;
+use std::collections::{HashSet, BTreeMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use std::thread;
+use std::sync::{Arc, Mutex};
 
 use self::memmap::{Mmap, Protection};
 use self::fs2::FileExt;
 
+use resize::{ResizeStrategy, ResizeStep, MapState};
+use cache::NegativeCache;
+use durability::sync_path;
+use planner::Plan;
+use lock;
+use compat::{self, Access, Unknown};
+use dedup::{SeenSet, DedupStats};
+use reads::{ReadPath, Pread};
+use occupancy::{self, Occupancy, Stamp, Slots, BitmapCheck};
+use dirty::{Snapshot, META_TRIES};
+
 pub struct BinacleFile {
     pub path: String,
     filesize: u64,
     file: File,
     map: Mmap,
+    // the lookups of the searches read the file instead, see reads.rs
+    pread: Option<Pread>,
     raw: BinacleStruct,
+    // fields of the meta written by a newer build, see compat.rs
+    unknown: Unknown,
+    resize: ResizeStrategy,
+    // the exclusive lock is held, see lock.rs
+    writer: bool,
+    // the n-grams written for the file inserted, see dedup.rs
+    seen: SeenSet,
+    dedup: bool,
+    last_dedup: DedupStats,
+    // each n-gram written to a list (tests)
+    insert_hook: Option<Box<dyn FnMut(u32) + Send + Sync>>,
+    // the header slots having a list, see occupancy.rs
+    occupancy: Mutex<Bitmap>,
+    // the bitmap in memory is the one of the sidecar
+    bitmap_written: bool,
+    // opened without its lock, read as of its meta, see dirty.rs
+    snapshot: Option<Snapshot>,
+}
+
+// the occupancy bitmap of a shard, read at the first scan by a reader
+enum Bitmap {
+    Unread,
+    Absent,
+    Present(Arc<Occupancy>),
 }
 
 #[derive(Clone, RustcDecodable, RustcEncodable)]
@@ -31,6 +70,144 @@ pub struct BinacleStruct {
     nb_file: u32,
     last_id: u32,
     average_size: f64,
+    // blocks left by the compaction, by size_log, reused by the next allocations
+    free_blocks: Option<BTreeMap<u8, Vec<u64>>>,
+    // features of the shard, optional or required (see compat.rs)
+    flags: Option<u64>,
+}
+
+// the blocks of the insertions grow to 2^12 bytes, the bulk ones to
+// BULK_LIST_BYTES
+const MAX_SIZE_LOG: u8 = 15;
+
+// problems of a shard kept by check_lists, the others are only counted
+pub const MAX_PROBLEMS: usize = 20;
+
+// the lists of a shard read by check_lists
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListCheck {
+    // the header does not fit the meta or the file, the lists were not read
+    pub header: Option<String>,
+    // header entries having a list
+    pub lists: u64,
+    pub blocks: u64,
+    pub bad_lists: u64,
+    // the first problems, with their n-gram
+    pub problems: Vec<String>,
+}
+
+impl ListCheck {
+
+    pub fn is_sound(&self) -> bool {
+        self.header.is_none() && self.bad_lists == 0
+    }
+}
+
+// the lists must start below it: their offsets are stored on offset_size
+// bytes, in units of 2^alignment bytes
+pub fn addressable_size(offset_size: u8, alignment: u8) -> u64 {
+    match offset_size as u32 * 8 + alignment as u32 {
+        bits if bits >= 64 => u64::max_value(),
+        bits => 1u64 << bits,
+    }
+}
+
+// bytes of the header of a new shard: an offset for each of the
+// 2^ngram_size reduced n-grams, then up to the next block. Computed in u64,
+// 5 * 2^32 at ngram_size 32 does not fit 32 bits
+pub fn header_size(offset_size: u8, alignment: u8, ngram_size: u8) -> u64 {
+    let size = offset_size as u64 * (1u64 << ngram_size);
+    let block = 1u64 << alignment;
+    size + block - size % block
+}
+
+// the bits of an offset stored on offset_size bytes, all of them at 8
+fn offset_mask(offset_size: u8) -> u64 {
+    match offset_size as u32 * 8 {
+        bits if bits >= 64 => u64::max_value(),
+        bits => (1u64 << bits) - 1,
+    }
+}
+
+// a pattern gives no 4-gram, the request is wrong whatever the shard
+pub fn short_pattern() -> Error {
+    Error::new(ErrorKind::InvalidInput, "pattern size is < 4")
+}
+
+// a search stopped in the lists of a shard, the ids read so far are not the
+// ones of the pattern
+pub fn check_deadline(deadline: Option<Instant>) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(Error::new(ErrorKind::TimedOut, "deadline passed in the lists")),
+        _ => Ok(()),
+    }
+}
+
+fn over_addressable(offset_size: u8, alignment: u8) -> Error {
+    Error::new(ErrorKind::Other, format!("index exceeds maximum addressable size for offset_size/alignment ({} bytes for {}/{}); \
+                                          compact or recreate with larger parameters",
+                                         addressable_size(offset_size, alignment), offset_size, alignment))
+}
+
+// what tells a file from another one renamed over it: the device and the
+// inode, and the size and modification time for its generation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileIdentity {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl FileIdentity {
+
+    pub fn of(meta: &Metadata) -> FileIdentity {
+        #[cfg(unix)]
+        let (dev, ino) = {
+            use std::os::unix::fs::MetadataExt;
+            (meta.dev(), meta.ino())
+        };
+        // only the generation tells the files apart
+        #[cfg(not(unix))]
+        let (dev, ino) = (0, 0);
+        FileIdentity {
+            dev: dev,
+            ino: ino,
+            size: meta.len(),
+            modified: meta.modified().ok(),
+        }
+    }
+
+    pub fn of_path(path: &str) -> Result<FileIdentity> {
+        fs::metadata(path).map(|m| FileIdentity::of(&m))
+    }
+}
+
+// every 4-gram of the content, once, whatever the size of the reads: the
+// last 3 bytes of a read are kept for the next one. Returns the size read.
+pub fn read_ngrams<R: Read, F: FnMut(u32) -> Result<()>>(mut reader: R, mut f: F) -> Result<u64> {
+
+    let mut buf = vec![0u8; 3 + 4096*256];
+    let mut size = 0u64;
+    // bytes of the previous read at the start of buf
+    let mut kept = 0;
+    loop {
+        let len = match reader.read(&mut buf[kept ..]) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if len == 0 {
+            return Ok(size);
+        }
+        size += len as u64;
+        let end = kept + len;
+        for i in 0 .. end.saturating_sub(3) {
+            try!(f(u32::from_ne_bytes([buf[i], buf[i+1], buf[i+2], buf[i+3]])));
+        }
+        kept = end.min(3);
+        buf.copy_within(end - kept .. end, 0);
+    }
 }
 
 #[allow(dead_code)]
@@ -42,10 +219,12 @@ impl BinacleFile {
                     .read(true)
                     .write(true)
                     .create_new(true)
-                    .open(path));
+                    .open(path)
+                    .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
 
         // lock the file to prevent parallel use
         try!(file.lock_exclusive());
+        lock::acquired(path);
 
         let mut header = BinacleStruct {
             size: 0,
@@ -55,37 +234,61 @@ impl BinacleFile {
             nb_file: 0,
             last_id: 0,
             average_size: 0.0,
+            free_blocks: None,
+            flags: None,
         };
 
-        let mut size = header.offset_size as u64 * (1u64 << header.ngram_size);
-        size += 2u64.pow(header.alignment as u32) - (size % 2u64.pow(header.alignment as u32));
+        let size = header_size(offset_size, alignment, ngram_size);
         header.size = size;
         
         let _ = file.set_len(size);
 
         // create a meta file
-        BinacleFile::write_meta(&path, &header);
+        BinacleFile::write_meta(&path, &header, &Unknown::default());
 
         let mmap = Mmap::open(&file, Protection::ReadWrite).unwrap();
 
-        let meta = BinacleFile::read_meta(&String::from(path));
+        let (meta, unknown) = try!(BinacleFile::read_meta(&String::from(path), Access::Write));
+
+        // the one of a removed shard of the same name
+        let _ = remove_file(occupancy::bitmap_path(path));
 
         Ok(BinacleFile { 
             path: String::from(path),
             filesize: size,
             file: file,
             map: mmap,
+            pread: None,
+            // before raw takes the meta
+            seen: SeenSet::new(meta.ngram_size),
+            occupancy: Mutex::new(Bitmap::Present(Arc::new(Occupancy::new(1u64 << meta.ngram_size)))),
+            bitmap_written: false,
+            snapshot: None,
             raw: meta,
+            unknown: unknown,
+            resize: ResizeStrategy::native(),
+            writer: true,
+            dedup: true,
+            last_dedup: DedupStats::default(),
+            insert_hook: None,
         })
     }
 
     // constructor
     // open a database file, read only
     pub fn open_read(path: &str) -> Result<BinacleFile> {
+        BinacleFile::open_read_with(path, ReadPath::Mmap)
+    }
+
+    // same, the searches reading it as given
+    pub fn open_read_with(path: &str, reads: ReadPath) -> Result<BinacleFile> {
+
+        try!(BinacleFile::recover_compaction(path));
 
         let file = try!(OpenOptions::new()
                     .read(true)
-                    .open(path));
+                    .open(path)
+                    .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
 
         // allow parallel reads but no write
         try!(file.lock_shared());      
@@ -94,40 +297,141 @@ impl BinacleFile {
 
         let mmap = Mmap::open(&file, Protection::Read).unwrap();
 
-        let meta = BinacleFile::read_meta(&String::from(path));
+        // the optional features unknown to this build are ignored
+        let (meta, unknown) = try!(BinacleFile::read_meta(&String::from(path), Access::Read));
+
+        let pread = match reads {
+            ReadPath::Mmap => None,
+            ReadPath::Pread => Some(Pread::new(try!(file.try_clone()))),
+        };
 
         Ok(BinacleFile { 
             path: String::from(path),
             filesize: size,
             file: file,
             map: mmap,
+            pread: pread,
+            // before raw takes the meta
+            seen: SeenSet::new(meta.ngram_size),
+            occupancy: Mutex::new(Bitmap::Unread),
+            bitmap_written: false,
+            snapshot: None,
+            raw: meta,
+            unknown: unknown,
+            resize: ResizeStrategy::native(),
+            writer: false,
+            dedup: true,
+            last_dedup: DedupStats::default(),
+            insert_hook: None,
+        })
+    }
+
+    // open a shard another process may be writing, without waiting for its
+    // lock: it is read as of its meta (see dirty.rs)
+    pub fn open_dirty(path: &str) -> Result<BinacleFile> {
+
+        try!(BinacleFile::recover_compaction(path));
+
+        let file = try!(OpenOptions::new()
+                    .read(true)
+                    .open(path)
+                    .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
+
+        // the writer truncates the meta before writing it again, a read in
+        // between is tried again
+        let mut tries = 0;
+        let (meta, unknown) = loop {
+            match BinacleFile::read_meta(&String::from(path), Access::Read) {
+                Ok(meta) => break meta,
+                Err(_) if tries < META_TRIES => {
+                    tries += 1;
+                    thread::sleep(Duration::from_millis(10));
+                },
+                Err(e) => return Err(e),
+            }
+        };
+
+        // after the meta: the shard is grown before its meta is written
+        let size = try!(file.metadata()).len();
+        let mmap = Mmap::open(&file, Protection::Read).unwrap();
+        let pread = Pread::new(try!(file.try_clone()));
+
+        Ok(BinacleFile {
+            path: String::from(path),
+            filesize: size,
+            file: file,
+            map: mmap,
+            pread: Some(pread),
+            // before raw takes the meta
+            seen: SeenSet::new(meta.ngram_size),
+            // the writer removed it
+            occupancy: Mutex::new(Bitmap::Absent),
+            bitmap_written: false,
+            snapshot: Some(Snapshot { size: min(meta.size, size), last_id: meta.last_id }),
             raw: meta,
+            unknown: unknown,
+            resize: ResizeStrategy::native(),
+            writer: false,
+            dedup: true,
+            last_dedup: DedupStats::default(),
+            insert_hook: None,
         })
     }
 
+    // the part of the shard read, when it was opened by open_dirty
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.snapshot
+    }
+
     pub fn open_write(path: &str) -> Result<BinacleFile> {
-        
+
+        try!(BinacleFile::recover_compaction(path));
+
         let file = try!(OpenOptions::new()
                     .read(true)
                     .write(true)
                     .create(false)
-                    .open(path));
+                    .open(path)
+                    .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
 
         // lock the file to prevent parallel use
         try!(file.lock_exclusive());
+        lock::acquired(path);
 
         let size = file.metadata().unwrap().len() as u64;
 
         let mmap = Mmap::open(&file, Protection::ReadWrite).unwrap();
 
-        let meta = BinacleFile::read_meta(&String::from(path));
+        let (meta, unknown) = try!(BinacleFile::read_meta(&String::from(path), Access::Write));
+
+        // the sidecar is removed while the shard changes: a process killed
+        // before it is written again leaves no bitmap behind
+        let bitmap = match Occupancy::read(path, 1u64 << meta.ngram_size, BinacleFile::stamp_of(&meta)) {
+            Ok(Some(occupancy)) => match remove_file(occupancy::bitmap_path(path)) {
+                Ok(()) => Bitmap::Present(Arc::new(occupancy)),
+                Err(_) => Bitmap::Absent,
+            },
+            _ => Bitmap::Absent,
+        };
 
         Ok(BinacleFile { 
             path: String::from(path),
             filesize: size,
             file: file,
             map: mmap,
+            pread: None,
+            // before raw takes the meta
+            seen: SeenSet::new(meta.ngram_size),
+            occupancy: Mutex::new(bitmap),
+            bitmap_written: false,
+            snapshot: None,
             raw: meta,
+            unknown: unknown,
+            resize: ResizeStrategy::native(),
+            writer: true,
+            dedup: true,
+            last_dedup: DedupStats::default(),
+            insert_hook: None,
         })
     }
 
@@ -135,30 +439,64 @@ impl BinacleFile {
     // insert a file from its filepath
     pub fn insert_file(&mut self, filepath: &str, id: u32) -> Result<u32> {
 
-        let mut file = try!(OpenOptions::new().read(true).open(filepath));
-        let size = try!(fs::metadata(&filepath)).len() as u32;
+        let file = try!(OpenOptions::new().read(true).open(filepath));
+        self.insert_reader(file, id)
+    }
 
-        let mut buf = vec![0u8; 4096*256];
-        loop {
-            match file.read(&mut buf).unwrap() {
-                0 => break,
-                1 | 2 | 3 => break,
-                len => {
-                    for i in 0 .. len-3 {
-                        let ptr_read = buf.as_ptr() as u64 + i as u64;
-                        let ngram = unsafe { ptr::read(ptr_read as *const u32)};
-                        let _ = self.insert_ngram(id, ngram);
-                    }
-                }
+    // insert the content of a reader
+    // the stats are only updated once the whole content was indexed
+    pub fn insert_reader<R: Read>(&mut self, reader: R, id: u32) -> Result<u32> {
+
+        self.seen.clear();
+        let mut stats = DedupStats::default();
+        let size = try!(read_ngrams(reader, |ngram| {
+            stats.ngrams += 1;
+            let reduced = self.reduce_ngram(ngram);
+            if self.dedup && !self.seen.insert(reduced) {
+                return Ok(());
             }
-        }
+            stats.writes += 1;
+            if let Some(ref mut hook) = self.insert_hook {
+                hook(reduced);
+            }
+            self.insert_ngram(id, ngram).map(|_| ())
+        }));
+        self.last_dedup = stats;
+        self.account_file(id, size);
+        Ok(id)
+    }
 
-        // update the meta file
-        self.raw.average_size = (self.raw.average_size * self.raw.nb_file as f64 + size as f64) / (self.raw.nb_file + 1) as f64;
+    // n-grams read and written by the last insertion
+    pub fn last_dedup(&self) -> DedupStats {
+        self.last_dedup
+    }
+
+    // write every occurrence of the n-grams, as before the seen-set
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    pub fn set_insert_hook(&mut self, hook: Box<dyn FnMut(u32) + Send + Sync>) {
+        self.insert_hook = Some(hook);
+    }
+
+    // update the meta file, the size of a file is not bounded (disk images)
+    fn account_file(&mut self, id: u32, size: u64) {
+        self.raw.average_size = (self.raw.average_size * self.raw.nb_file as f64 + size as f64) / (self.raw.nb_file as f64 + 1.0);
         self.raw.nb_file += 1;
         self.raw.last_id = id;
-        
-        Ok(id)
+    }
+
+    pub fn average_size(&self) -> f64 {
+        self.raw.average_size
+    }
+
+    pub fn nb_file(&self) -> u32 {
+        self.raw.nb_file
+    }
+
+    pub fn last_id(&self) -> u32 {
+        self.raw.last_id
     }
 
     // find all the files that contain all the ngrams
@@ -184,54 +522,133 @@ impl BinacleFile {
             }
             set = self.intersect_ids_by_ngram(set, *ngram_to_nb[i].0);
         }  
+        try!(self.check_reads());
         Ok(set)  
 
     }
 
+    // the first read of the searches that failed since the last call,
+    // always Ok over the map (see reads.rs)
+    pub fn check_reads(&self) -> Result<()> {
+        match self.pread.as_ref().and_then(|pread| pread.take_error()) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
     // find all file ids containing the pattern
     // split the pattern into a set of ngrams
     pub fn search(&self, pattern: &[u8]) -> Result<HashSet<u32>> {
 
-        // split the search pattern in 4-grams
+        let ngram_set = try!(BinacleFile::split_ngrams(pattern));
+        self.search_ngrams(&ngram_set)
+    }
+
+    // same as search_planned, the n-grams known to be absent are not looked up
+    // only for a shard that does not change anymore
+    pub fn search_cached(&self, pattern: &[u8], cache: &mut NegativeCache, preselect: usize,
+                         deadline: Option<Instant>) -> Result<(HashSet<u32>, Plan)> {
+
+        let ngram_set = try!(BinacleFile::split_ngrams(pattern));
+
+        for ngram in &ngram_set {
+            if cache.lookup(self.reduce_ngram(*ngram)) {
+                return Ok((HashSet::new(), Plan::default()));
+            }
+        }
+
+        for ngram in &ngram_set {
+            if self.ngram_list_ptr(*ngram) == 0 {
+                // an entry that could not be read is not known to be empty
+                try!(self.check_reads());
+                cache.insert(self.reduce_ngram(*ngram));
+                return Ok((HashSet::new(), Plan::default()));
+            }
+        }
+
+        self.search_planned_until(pattern, preselect, deadline)
+    }
+
+    // split the search pattern in 4-grams
+    pub fn split_ngrams(pattern: &[u8]) -> Result<HashSet<u32>> {
+
         if pattern.len() < 4 {
-            return Err(Error::new(ErrorKind::Other, "pattern size is < 4"));
+            return Err(short_pattern());
         }
 
         let mut ngram_set = HashSet::with_capacity(pattern.len()-3);
 
         for i in 0 .. pattern.len()-3 {
             let ptr_read = pattern.as_ptr() as u64 + i as u64;
-            let ngram: u32 = unsafe { ptr::read(ptr_read as *const u32)};
+            let ngram: u32 = unsafe { ptr::read_unaligned(ptr_read as *const u32)};
             ngram_set.insert(ngram);
         }
-
-        self.search_ngrams(&ngram_set)
+        Ok(ngram_set)
     }
 
 
     pub fn get_ids_by_ngram(&self, ngram: u32) -> HashSet<u32> {
+        // without a deadline the walk is never stopped
+        self.get_ids_until(ngram, None).unwrap_or_default()
+    }
+
+    // same as get_ids_by_ngram, TimedOut once the deadline is passed, checked
+    // before each block of the list
+    pub fn get_ids_until(&self, ngram: u32, deadline: Option<Instant>) -> Result<HashSet<u32>> {
 
         let mut list_off = self.ngram_list_ptr(ngram);
         let mut set: HashSet<u32> = HashSet::new();
         
         while list_off != 0 {
 
+            try!(check_deadline(deadline));
             let set_from_list = self.unpack_list(list_off);
             let prev_off = self.get_list_meta(list_off).3;
             list_off = prev_off;
 
             set.extend(&set_from_list);
         }
+        Ok(set)
+    }
+
+    // membership of one id, the blocks after the one holding it are not read
+    pub fn has_id(&self, ngram: u32, id: u32) -> bool {
+
+        let mut list_off = self.ngram_list_ptr(ngram);
+        while list_off != 0 {
+            if self.unpack_list(list_off).contains(&id) {
+                return true;
+            }
+            list_off = self.get_list_meta(list_off).3;
+        }
+        false
+    }
+
+    // every id present in the index, walks all the posting lists: only the
+    // slots of the occupancy bitmap are read when the shard has one, and the
+    // ids are decoded as they are read, without a set by list
+    pub fn all_ids(&self) -> HashSet<u32> {
+
+        let mut set = HashSet::new();
+        for (_, ids) in self.postings() {
+            set.extend(ids);
+        }
         set
     }
 
     pub fn intersect_ids_by_ngram(&self, set: HashSet<u32>, ngram: u32) -> HashSet<u32> {
+        self.intersect_ids_until(set, ngram, None).unwrap_or_default()
+    }
+
+    // same as intersect_ids_by_ngram, TimedOut once the deadline is passed
+    pub fn intersect_ids_until(&self, set: HashSet<u32>, ngram: u32, deadline: Option<Instant>) -> Result<HashSet<u32>> {
 
         let mut list_off = self.ngram_list_ptr(ngram);
         let mut new_set: HashSet<u32> = HashSet::with_capacity(set.len());
         
         while list_off != 0 {
 
+            try!(check_deadline(deadline));
             let set_from_list = self.unpack_list(list_off);
             let prev_off = self.get_list_meta(list_off).3;
             list_off = prev_off;
@@ -242,7 +659,7 @@ impl BinacleFile {
                 break;
             }
         }
-        new_set
+        Ok(new_set)
     }
 
     pub fn get_ids_size_by_ngram(&self, ngram: u32) -> u32 {
@@ -258,14 +675,619 @@ impl BinacleFile {
         nb_total
     }
 
+    // the pages of the blocks of a list given back, by the mapping and by
+    // the page cache, after a request over its budget (see server.rs): they
+    // are read again from the file when needed
+    pub fn release_list(&self, ngram: u32) {
+
+        let mut list_off = self.ngram_list_ptr(ngram);
+        while list_off != 0 {
+            let (size_log, _, _, prev_off) = self.get_list_meta(list_off);
+            self.release_pages(list_off, 1 << size_log);
+            list_off = prev_off;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn release_pages(&self, offset: u64, len: u64) {
+        use std::os::unix::io::AsRawFd;
+        const PAGE: u64 = 4096;
+        const MADV_DONTNEED: i32 = 4;
+        const POSIX_FADV_DONTNEED: i32 = 4;
+        extern "C" {
+            fn madvise(addr: *mut u8, len: usize, advice: i32) -> i32;
+            fn posix_fadvise(fd: i32, offset: i64, len: i64, advice: i32) -> i32;
+        }
+        // the whole pages of the blocks, a failure only keeps them
+        let start = offset / PAGE * PAGE;
+        let end = min(offset + len, self.map.len() as u64);
+        if end <= start {
+            return;
+        }
+        if self.pread.is_none() {
+            unsafe { madvise((self.map.ptr() as *mut u8).offset(start as isize), (end - start) as usize, MADV_DONTNEED) };
+        }
+        unsafe { posix_fadvise(self.file.as_raw_fd(), start as i64, (end - start) as i64, POSIX_FADV_DONTNEED) };
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn release_pages(&self, _offset: u64, _len: u64) {
+    }
+
+    // add ids to the list of a n-gram, they must be sorted and
+    // greater than the ids already in the list
+    pub fn insert_postings(&mut self, ngram: u32, ids: &[u32]) -> Result<()> {
+        for id in ids {
+            try!(self.insert_ngram(*id, ngram));
+        }
+        Ok(())
+    }
+
+    // file counters of another index holding the same files
+    pub fn copy_stats(&mut self, from: &BinacleFile) {
+        self.raw.nb_file = from.raw.nb_file;
+        self.raw.last_id = from.raw.last_id;
+        self.raw.average_size = from.raw.average_size;
+    }
+
+    pub fn ngram_size(&self) -> u8 {
+        self.raw.ngram_size
+    }
+
+    // of the file opened, which may no longer be the one at its path
+    pub fn identity(&self) -> Result<FileIdentity> {
+        self.file.metadata().map(|m| FileIdentity::of(&m))
+    }
+
+    pub fn max_addressable_size(&self) -> u64 {
+        addressable_size(self.raw.offset_size, self.raw.alignment)
+    }
+
     pub fn get_size(&self) -> u64 {
         return self.raw.size;
     }
 
-    pub fn fix_size(&mut self) {
-        let _ = self.file.set_len(self.raw.size);
-        self.filesize = self.raw.size;
-        self.map = Mmap::open(&self.file, Protection::ReadWrite).unwrap();
+    // size of the file when it was opened, at least get_size
+    pub fn file_size(&self) -> u64 {
+        self.filesize
+    }
+
+    // bytes of the header, a list offset for each reduced n-gram
+    pub fn header_len(&self) -> u64 {
+        (self.raw.offset_size as u64) << self.raw.ngram_size
+    }
+
+    // lists allocated: the header entries not null, the chains are not read
+    pub fn nb_lists(&self) -> u64 {
+        self.scan_slots().filter(|&ngram| self.ngram_list_ptr(ngram) != 0).count() as u64
+    }
+
+    // a list is allocated for the n-gram, reduced to its slot
+    pub fn has_list(&self, ngram: u32) -> bool {
+        self.ngram_list_ptr(ngram) != 0
+    }
+
+    // entries of the header, a list at most for each
+    pub fn header_slots(&self) -> u64 {
+        1u64 << self.raw.ngram_size
+    }
+
+    // the slots read by a scan of the whole header: the ones of the
+    // occupancy bitmap when the shard has one, all of them otherwise
+    pub fn scan_slots(&self) -> Slots {
+        match self.occupancy() {
+            Some(occupancy) => Slots::occupied(occupancy),
+            None => Slots::All(0 .. self.header_slots()),
+        }
+    }
+
+    // slots of the header a scan skips, None without a bitmap: it reads them all
+    pub fn header_skipped(&self) -> Option<u64> {
+        self.occupancy().map(|o| self.header_slots() - o.count())
+    }
+
+    // the bitmap compared with the header, every slot is read
+    pub fn check_bitmap(&self) -> BitmapCheck {
+        let occupancy = match self.occupancy() {
+            Some(occupancy) => occupancy,
+            None if occupancy::bitmap_path(&self.path).exists() => return BitmapCheck::Stale,
+            None => return BitmapCheck::Missing,
+        };
+        let lacking = (0 .. self.header_slots())
+            .filter(|&ngram| self.ngram_list_ptr(ngram as u32) != 0 && !occupancy.is_set(ngram as u32))
+            .count() as u64;
+        match lacking {
+            0 => BitmapCheck::InSync { slots: occupancy.count() },
+            n => BitmapCheck::Lacking(n),
+        }
+    }
+
+    // a new bitmap from a scan of every slot of the header, written with
+    // the stamp of the meta. Returns the slots set
+    pub fn rebuild_bitmap(&self) -> Result<u64> {
+        let mut occupancy = Occupancy::new(self.header_slots());
+        for ngram in 0 .. self.header_slots() {
+            if self.ngram_list_ptr(ngram as u32) != 0 {
+                occupancy.set(ngram as u32);
+            }
+        }
+        try!(occupancy.write(&self.path, self.stamp()));
+        let count = occupancy.count();
+        *self.occupancy.lock().unwrap() = Bitmap::Present(Arc::new(occupancy));
+        Ok(count)
+    }
+
+    // the structure of the shard, read without trusting it: the header
+    // against the meta and the file, then the chain of every slot of the
+    // header. An offset or a count out of the file is told, never followed
+    pub fn check_lists(&self) -> ListCheck {
+
+        let mut check = ListCheck::default();
+        let raw = &self.raw;
+        if raw.offset_size == 0 || raw.offset_size > 8 || raw.alignment > 32 || raw.ngram_size > 32 {
+            check.header = Some(format!("invalid meta: offset_size {}, alignment {}, ngram_size {}",
+                                        raw.offset_size, raw.alignment, raw.ngram_size));
+            return check;
+        }
+        let data = unsafe { self.map.as_slice() };
+        let len = min(data.len() as u64, self.filesize);
+        let header = header_size(raw.offset_size, raw.alignment, raw.ngram_size);
+        if header > len || header > raw.size {
+            check.header = Some(format!("header of {} bytes for offset_size {} and ngram_size {}, the file has {} and its meta tells {}",
+                                        header, raw.offset_size, raw.ngram_size, len, raw.size));
+            return check;
+        }
+        // a block past the size of the meta would be overwritten by the
+        // next allocation
+        let end = min(len, raw.size);
+
+        for ngram in 0 .. self.header_slots() {
+            let first = self.read_offset(data, ngram * raw.offset_size as u64);
+            if first == 0 {
+                continue;
+            }
+            check.lists += 1;
+            if let Err(problem) = self.check_chain(data, header, end, first, &mut check.blocks) {
+                check.bad_lists += 1;
+                if check.problems.len() < MAX_PROBLEMS {
+                    check.problems.push(format!("n-gram {:#x}: {}", ngram, problem));
+                }
+            }
+        }
+        check
+    }
+
+    fn read_offset(&self, data: &[u8], at: u64) -> u64 {
+        let mut offset = [0u8; 8];
+        offset[.. self.raw.offset_size as usize].copy_from_slice(&data[at as usize .. (at + self.raw.offset_size as u64) as usize]);
+        u64::from_le_bytes(offset) << self.raw.alignment
+    }
+
+    // the blocks of a chain between the header and the end of the lists
+    fn check_chain(&self, data: &[u8], header: u64, end: u64, first: u64, blocks: &mut u64) -> ::std::result::Result<(), String> {
+
+        let list_header = 5 + self.raw.offset_size as u64;
+        let mut visited = HashSet::new();
+        let mut list_off = first;
+        while list_off != 0 {
+            if list_off < header || list_off + list_header > end {
+                return Err(format!("block at {} out of the lists ({} to {})", list_off, header, end));
+            }
+            if !visited.insert(list_off) {
+                return Err(format!("the chain loops back to the block at {}", list_off));
+            }
+            // a block takes 2^alignment bytes at least
+            if visited.len() as u64 > (end - header) >> self.raw.alignment {
+                return Err(String::from("the chain has more blocks than the file can hold"));
+            }
+            *blocks += 1;
+
+            let meta = &data[list_off as usize .. (list_off + list_header) as usize];
+            let size_log = meta[0];
+            let nb_elem = u16::from_le_bytes([meta[1], meta[2]]);
+            let nb_bytes = u16::from_le_bytes([meta[3], meta[4]]) as u64;
+            if size_log < self.raw.alignment || size_log > MAX_SIZE_LOG {
+                return Err(format!("block at {}: size_log {} out of {} to {}", list_off, size_log, self.raw.alignment, MAX_SIZE_LOG));
+            }
+            // a bulk block announces the power of 2 below its size
+            if list_header + nb_bytes >= 2u64 << size_log {
+                return Err(format!("block at {}: nb_bytes {} over its size_log {}", list_off, nb_bytes, size_log));
+            }
+            if list_off + list_header + nb_bytes > end {
+                return Err(format!("block at {}: its {} bytes of ids go past the end of the lists", list_off, nb_bytes));
+            }
+            let content = &data[(list_off + list_header) as usize .. (list_off + list_header + nb_bytes) as usize];
+            try!(BinacleFile::check_block_ids(content, nb_elem).map_err(|e| format!("block at {}: {}", list_off, e)));
+
+            list_off = self.read_offset(data, list_off + 5);
+        }
+        Ok(())
+    }
+
+    // the layout of insert_ngram: the first id, the packed differences
+    // using exactly the bytes up to the last id, stored again at the end
+    fn check_block_ids(content: &[u8], nb_elem: u16) -> ::std::result::Result<(), String> {
+
+        let (low, high) = match nb_elem as usize {
+            0 => (0, 0),
+            1 => (4, 4),
+            n => (8 + n - 1, 8 + 4 * (n - 1)),
+        };
+        if content.len() < low || content.len() > high {
+            return Err(format!("nb_bytes {} for nb_elem {}, {} to {} expected", content.len(), nb_elem, low, high));
+        }
+        if nb_elem < 2 {
+            return Ok(());
+        }
+
+        let word = |at: usize| {
+            let mut bytes = [0u8; 4];
+            let n = min(4, content.len() - at);
+            bytes[.. n].copy_from_slice(&content[at .. at + n]);
+            u32::from_le_bytes(bytes)
+        };
+        let last = content.len() - 4;
+        let mut id = word(0);
+        let mut at = 4;
+        for _ in 1 .. nb_elem {
+            if at >= last {
+                return Err(format!("the differences of the {} ids go past nb_bytes {}", nb_elem, content.len()));
+            }
+            let (diff, len) = BinacleFile::unpack_integer(word(at));
+            at += len as usize;
+            id = match id.checked_add(diff) {
+                Some(next) if diff > 0 => next,
+                _ => return Err(format!("ids not increasing after {}", id)),
+            };
+        }
+        if at != last {
+            return Err(format!("the differences take {} of the {} bytes between the first and the last id", at - 4, last - 4));
+        }
+        match word(last) {
+            stored if stored != id => Err(format!("last id {} stored, {} decoded", stored, id)),
+            _ => Ok(()),
+        }
+    }
+
+    // the bitmap of the shard, None when it has none in sync with its meta
+    fn occupancy(&self) -> Option<Arc<Occupancy>> {
+        let mut bitmap = self.occupancy.lock().unwrap();
+        if let Bitmap::Unread = *bitmap {
+            *bitmap = match Occupancy::read(&self.path, self.header_slots(), self.stamp()) {
+                Ok(Some(occupancy)) => Bitmap::Present(Arc::new(occupancy)),
+                _ => Bitmap::Absent,
+            };
+        }
+        match *bitmap {
+            Bitmap::Present(ref occupancy) => Some(occupancy.clone()),
+            _ => None,
+        }
+    }
+
+    fn stamp(&self) -> Stamp {
+        BinacleFile::stamp_of(&self.raw)
+    }
+
+    fn stamp_of(meta: &BinacleStruct) -> Stamp {
+        Stamp { size: meta.size, nb_file: meta.nb_file, last_id: meta.last_id }
+    }
+
+    // the bitmap of a writer to its sidecar, with the meta
+    fn write_bitmap(&mut self) -> Result<()> {
+        let stamp = self.stamp();
+        if let Bitmap::Present(ref occupancy) = *self.occupancy.get_mut().unwrap() {
+            try!(occupancy.write(&self.path, stamp));
+            self.bitmap_written = true;
+        }
+        Ok(())
+    }
+
+    // offset in the file of the header entry of a n-gram
+    pub fn header_entry(&self, ngram: u32) -> u64 {
+        self.raw.offset_size as u64 * self.reduce_ngram(ngram) as u64
+    }
+
+    // offset and bytes read of the first n blocks of the list of a n-gram,
+    // the newest first as a search reads them
+    pub fn leading_blocks(&self, ngram: u32, n: usize) -> Vec<(u64, u64)> {
+        let mut blocks = Vec::new();
+        let mut list_off = self.ngram_list_ptr(ngram);
+        while list_off != 0 && blocks.len() < n {
+            let (_, _, nb_bytes, prev_off) = self.get_list_meta(list_off);
+            blocks.push((list_off, 5 + self.raw.offset_size as u64 + nb_bytes as u64));
+            list_off = prev_off;
+        }
+        blocks
+    }
+
+    // read a byte of the file, its page is faulted in the page cache:
+    // false past the end of the mapping
+    pub fn touch(&self, offset: u64) -> bool {
+        if offset >= self.map.len() as u64 {
+            return false;
+        }
+        unsafe { ptr::read_volatile((self.map.ptr() as u64 + offset) as *const u8) };
+        true
+    }
+
+    // write the mapped pages and the meta to the disk
+    pub fn flush(&mut self) -> Result<()> {
+
+        try!(self.map.flush());
+        BinacleFile::write_meta(&self.path, &self.raw, &self.unknown);
+        try!(self.write_bitmap());
+
+        let mut meta_path = PathBuf::from(&self.path);
+        meta_path.set_extension("meta");
+        try!(sync_path(meta_path.to_str().unwrap()));
+        self.file.sync_all()
+    }
+
+    pub fn fix_size(&mut self) -> Result<()> {
+        let size = self.raw.size;
+        self.resize_file(size)
+    }
+
+    // write the lists of this new empty index in one pass instead of
+    // inserting the ids one by one (see BulkWriter)
+    pub fn into_bulk_writer(mut self) -> Result<BulkWriter> {
+
+        if self.raw.nb_file != 0 || self.raw.last_id != 0 {
+            return Err(Error::new(ErrorKind::Other, format!("{} is not empty", self.path)));
+        }
+        let file = try!(self.file.try_clone());
+        // the bulk writer writes the bitmap when it is done
+        *self.occupancy.get_mut().unwrap() = Bitmap::Absent;
+        let _ = remove_file(occupancy::bitmap_path(&self.path));
+        Ok(BulkWriter {
+            file: file,
+            path: self.path.clone(),
+            raw: self.raw.clone(),
+            unknown: self.unknown.clone(),
+            header: Vec::new(),
+            header_pos: 0,
+            data: Vec::new(),
+            next_ngram: 0,
+            occupancy: Occupancy::new(1u64 << self.raw.ngram_size),
+            blocks: 0,
+        })
+    }
+
+    // each n-gram having a list, in header order, with the ids of its
+    // whole chain sorted: the n-grams are the reduced ones
+    pub fn iter_postings<'a>(&'a self) -> impl Iterator<Item=(u32, Vec<u32>)> + 'a {
+        self.scan_slots().filter_map(move |ngram| {
+            let mut ids: Vec<u32> = self.get_ids_by_ngram(ngram).into_iter().collect();
+            ids.sort();
+            match ids.is_empty() {
+                true => None,
+                false => Some((ngram, ids)),
+            }
+        })
+    }
+
+    // each n-gram having a list, in header order, with an iterator of its
+    // ids: unlike iter_postings, the ids are decoded as they are read, the
+    // memory used does not depend on the size of the lists
+    pub fn postings<'a>(&'a self) -> impl Iterator<Item=(u32, PostingIter<'a>)> + 'a {
+        self.scan_slots().filter_map(move |ngram| {
+            let ids = self.posting_iter(ngram);
+            match ids.blocks.is_empty() {
+                true => None,
+                false => Some((ngram, ids)),
+            }
+        })
+    }
+
+    // the ids of the list of a n-gram, ascending
+    pub fn posting_iter<'a>(&'a self, ngram: u32) -> PostingIter<'a> {
+        let mut blocks = Vec::new();
+        let mut list_off = self.ngram_list_ptr(ngram);
+        while list_off != 0 {
+            let (_, nb_elem, _, prev_off) = self.get_list_meta(list_off);
+            if nb_elem > 0 {
+                let first = list_off + 5 + self.raw.offset_size as u64;
+                blocks.push(BlockCursor {
+                    next: first + 4,
+                    left: nb_elem - 1,
+                    head: unsafe { ptr::read_unaligned((self.map.ptr() as u64 + first) as *const u32) },
+                });
+            }
+            list_off = prev_off;
+        }
+        PostingIter { file: self, blocks: blocks, last: None }
+    }
+
+    // calls f with each n-gram having a list and the number of blocks of its chain
+    pub fn for_each_chain<F: FnMut(u32, u32)>(&self, mut f: F) {
+        for ngram in self.scan_slots() {
+            let mut list_off = self.ngram_list_ptr(ngram);
+            let mut len = 0;
+            while list_off != 0 {
+                len += 1;
+                list_off = self.get_list_meta(list_off).3;
+            }
+            if len > 0 {
+                f(ngram, len);
+            }
+        }
+    }
+
+    // rewrite the whole shard as a bulk build does, the blocks of each chain
+    // one after the other (a single one up to BULK_LIST_BYTES), the free
+    // blocks dropped. Written to <index>.compact.tmp, renamed over the shard
+    // with its bitmap and its meta once <index>.compact.done marks them
+    // complete: interrupted before, the shard is as it was, after, the next
+    // open finishes the renames
+    pub fn compact(mut self) -> Result<Compacted> {
+
+        if !self.writer {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("{} is not opened for writing", self.path)));
+        }
+        let tmp = format!("{}.compact.tmp", self.path);
+        // left by a compaction interrupted before its files were complete
+        for path in BinacleFile::shard_files(&tmp) {
+            let _ = remove_file(path);
+        }
+
+        let mut report = Compacted { size_before: self.filesize, ..Compacted::default() };
+        self.for_each_chain(|_, len| {
+            report.lists += 1;
+            report.blocks_before += len as u64;
+        });
+
+        let mut shard = try!(BinacleFile::create(&tmp, self.raw.offset_size, self.raw.alignment, self.raw.ngram_size));
+        shard.raw.flags = self.raw.flags;
+        shard.unknown = self.unknown.clone();
+        let mut writer = try!(shard.into_bulk_writer());
+        for (ngram, ids) in self.postings() {
+            let mut ids: Vec<u32> = ids.collect();
+            ids.dedup();
+            try!(writer.write_list(ngram, &ids));
+        }
+        report.blocks_after = writer.blocks;
+        report.size_after = try!(writer.finish(self.raw.nb_file, self.raw.last_id, self.raw.average_size));
+
+        // the new files are complete and synced: from the marker on, an
+        // interrupted compaction is finished by the next open
+        let marker = BinacleFile::compact_marker(&self.path);
+        try!(File::create(&marker).and_then(|f| f.sync_all()).map_err(|e| Error::new(e.kind(), format!("{}: {}", marker, e))));
+        try!(BinacleFile::sync_dir(&self.path));
+        try!(BinacleFile::commit_compaction(&self.path));
+        // dropped, it writes the new meta again and no bitmap
+        let (meta, unknown) = try!(BinacleFile::read_meta(&self.path, Access::Write));
+        self.raw = meta;
+        self.unknown = unknown;
+        *self.occupancy.get_mut().unwrap() = Bitmap::Absent;
+        Ok(report)
+    }
+
+    // the data, the bitmap and the meta of a shard, in the order a
+    // compaction renames them
+    fn shard_files(path: &str) -> Vec<PathBuf> {
+        vec![PathBuf::from(path), occupancy::bitmap_path(path), PathBuf::from(path).with_extension("meta")]
+    }
+
+    fn compact_marker(path: &str) -> String {
+        format!("{}.compact.done", path)
+    }
+
+    fn sync_dir(path: &str) -> Result<()> {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        sync_path(dir.to_str().unwrap())
+    }
+
+    // the files of a compaction marked complete renamed over the shard, an
+    // open finishing it may race the compaction: the ones renamed already
+    // are skipped
+    fn commit_compaction(path: &str) -> Result<()> {
+        let tmp = format!("{}.compact.tmp", path);
+        for (from, to) in BinacleFile::shard_files(&tmp).into_iter().zip(BinacleFile::shard_files(path)) {
+            match fs::rename(&from, &to) {
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                renamed => try!(renamed.map_err(|e| Error::new(e.kind(), format!("{}: {}", from.display(), e)))),
+            }
+        }
+        try!(BinacleFile::sync_dir(path));
+        match remove_file(BinacleFile::compact_marker(path)) {
+            Err(ref e) if e.kind() != ErrorKind::NotFound => Err(Error::new(e.kind(), format!("{}: {}", BinacleFile::compact_marker(path), e))),
+            _ => Ok(()),
+        }
+    }
+
+    // a compaction interrupted after its files were complete is finished,
+    // before the shard is opened
+    fn recover_compaction(path: &str) -> Result<()> {
+        if !Path::new(&BinacleFile::compact_marker(path)).exists() {
+            return Ok(());
+        }
+        BinacleFile::commit_compaction(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: the compaction interrupted can not be finished: {}", path, e)))
+    }
+
+    // rewrite the chain of a n-gram in a single block (several for the lists
+    // over BULK_LIST_BYTES), its old blocks are freed for the next
+    // allocations. Returns the number of blocks freed
+    pub fn compact_ngram(&mut self, ngram: u32) -> Result<usize> {
+
+        let mut old = Vec::new();
+        let mut list_off = self.ngram_list_ptr(ngram);
+        while list_off != 0 {
+            let (size_log, _, _, prev_off) = self.get_list_meta(list_off);
+            old.push((list_off, size_log));
+            list_off = prev_off;
+        }
+        if old.len() <= 1 {
+            return Ok(0);
+        }
+
+        let mut ids: Vec<u32> = self.get_ids_by_ngram(ngram).into_iter().collect();
+        ids.sort();
+
+        // the new blocks are complete before the header points to them,
+        // the greatest ids are in the block of the header
+        let list_header = 5 + self.raw.offset_size as usize;
+        let mut prev_off = 0;
+        let mut start = 0;
+        while start < ids.len() {
+            let (nb, content) = BinacleFile::pack_ids(&ids[start ..], list_header);
+            let needed = (list_header + content.len()) as u64;
+            let size_log = max(self.raw.alignment, (64 - (needed - 1).leading_zeros()) as u8);
+            let list_off = try!(self.get_new_free_list(size_log));
+            self.update_list_meta(list_off, size_log, nb as u16, content.len() as u16, prev_off);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    content.as_ptr(),
+                    (self.map.ptr() as u64 + list_off + list_header as u64) as *mut u8,
+                    content.len());
+            }
+            prev_off = list_off;
+            start += nb;
+        }
+        try!(self.update_header(ngram, prev_off));
+
+        for &(list_off, size_log) in &old {
+            self.raw.free_blocks.get_or_insert(BTreeMap::new())
+                .entry(size_log).or_insert(Vec::new()).push(list_off);
+        }
+        Ok(old.len())
+    }
+
+    // number of blocks freed by the compaction and not used again
+    pub fn nb_free_blocks(&self) -> usize {
+        self.raw.free_blocks.iter().flat_map(|f| f.values()).map(|b| b.len()).sum()
+    }
+
+    // drop an id from all the lists, slow: walks them all. Returns the
+    // number of lists changed
+    pub fn remove_id(&mut self, id: u32) -> Result<u64> {
+        let mut changed = 0;
+        for ngram in self.scan_slots() {
+            if try!(self.remove_from_list(id, ngram)) {
+                changed += 1;
+            }
+        }
+        self.removed(changed);
+        Ok(changed)
+    }
+
+    // drop an id from the lists of these n-grams only, the ones of the file
+    // it was inserted with. The blocks holding it are rewritten in place,
+    // the ones left empty are unlinked from their chain and freed for the
+    // next allocations: the space is reused, not given back
+    pub fn remove_id_from(&mut self, id: u32, ngrams: &HashSet<u32>) -> Result<u64> {
+        let reduced: HashSet<u32> = ngrams.iter().map(|n| self.reduce_ngram(*n)).collect();
+        let mut changed = 0;
+        for ngram in reduced {
+            if try!(self.remove_from_list(id, ngram)) {
+                changed += 1;
+            }
+        }
+        self.removed(changed);
+        Ok(changed)
     }
 
 
@@ -279,14 +1301,14 @@ impl BinacleFile {
         let mut list_off = self.ngram_list_ptr(ngram);
 
         if list_off == 0 {
-            list_off = self.alloc_list(ngram);
+            list_off = try!(self.alloc_list(ngram));
         }
 
         // check if the list is large enough to store one more element
         let (mut size_log, mut nb_elem, mut nb_bytes, mut prev_off) = self.get_list_meta(list_off);
 
         if 2u64.pow(size_log as u32) < nb_bytes as u64 + 4 /* one more elem */ + 5 + self.raw.offset_size as u64 {
-            let relist = self.realloc_list(list_off, ngram);
+            let relist = try!(self.realloc_list(list_off, ngram));
             prev_off = list_off;
             list_off = relist.0;
             size_log = relist.1;
@@ -302,7 +1324,7 @@ impl BinacleFile {
         // do not insert if last id is the same
         if nb_elem != 0 {
             let mut last_id_ptr = list_ptr - 4;
-            let last_id = unsafe { ptr::read(last_id_ptr as *const u32)};
+            let last_id = unsafe { ptr::read_unaligned(last_id_ptr as *const u32)};
             
             // id == last_id => id already in, so we return
             if last_id == id {
@@ -330,21 +1352,14 @@ impl BinacleFile {
 
                     // then copy the elem itself (to allow hot insert)
                     last_id_ptr += pack_size as u64;
-                    ptr::copy_nonoverlapping(
-                        &id as *const u32, 
-                        last_id_ptr as *const u64 as *mut u32, 
-                        1);
+                    ptr::write_unaligned(last_id_ptr as *mut u32, id);
                 };
                 nb_bytes += pack_size as u16;
             }
 
         } else {
 
-            unsafe { ptr::copy_nonoverlapping(
-                        &id as *const u32, 
-                        list_ptr as *const u64 as *mut u32, 
-                        1);
-            };
+            unsafe { ptr::write_unaligned(list_ptr as *mut u32, id) };
             nb_bytes += 4;
         }
 
@@ -352,6 +1367,26 @@ impl BinacleFile {
         Ok(nb_elem+1)
     }
 
+    // list content holding as many of the ids as a block of BULK_LIST_BYTES,
+    // with the number of ids taken. Same layout as insert_ngram: the first
+    // id, the packed differences, then the last id again for the next insertion
+    fn pack_ids(ids: &[u32], list_header: usize) -> (usize, Vec<u8>) {
+
+        let mut content = Vec::new();
+        content.extend_from_slice(&ids[0].to_ne_bytes());
+        let mut nb = 1;
+        while nb < ids.len() && nb < u16::max_value() as usize
+              && content.len() + 4 + 4 <= BULK_LIST_BYTES - list_header {
+            let (packed, pack_size) = BinacleFile::pack_integer(ids[nb] - ids[nb - 1]);
+            content.extend_from_slice(&packed.to_ne_bytes()[.. pack_size as usize]);
+            nb += 1;
+        }
+        if nb > 1 {
+            content.extend_from_slice(&ids[nb - 1].to_ne_bytes());
+        }
+        (nb, content)
+    }
+
     // take an u32, compute the representation using var encoding
     // if the msb is 1, need one more byte
     // max number is 268435455 (takes 4 bytes)
@@ -399,8 +1434,117 @@ impl BinacleFile {
         }
     }
 
+    // the next id of a block, false once its ids are all read
+    fn advance(&self, block: &mut BlockCursor) -> bool {
+        if block.left == 0 {
+            return false;
+        }
+        let next = unsafe { ptr::read_unaligned((self.map.ptr() as u64 + block.next) as *const u32) };
+        let (diff, nb_bytes) = BinacleFile::unpack_integer(next);
+        block.next += nb_bytes as u64;
+        block.head += diff;
+        block.left -= 1;
+        true
+    }
+
+    // the ids of a block, in their order
+    fn block_ids(&self, list_off: u64) -> Vec<u32> {
+        let nb_elem = self.get_list_meta(list_off).1;
+        if nb_elem == 0 {
+            return Vec::new();
+        }
+        let first = list_off + 5 + self.raw.offset_size as u64;
+        let mut block = BlockCursor {
+            next: first + 4,
+            left: nb_elem - 1,
+            head: unsafe { ptr::read_unaligned((self.map.ptr() as u64 + first) as *const u32) },
+        };
+        let mut ids = Vec::with_capacity(nb_elem as usize);
+        ids.push(block.head);
+        while self.advance(&mut block) {
+            ids.push(block.head);
+        }
+        ids
+    }
+
+    // the file of the id was in the shard when a list changed
+    fn removed(&mut self, changed: u64) {
+        if changed > 0 {
+            self.raw.nb_file = self.raw.nb_file.saturating_sub(1);
+        }
+    }
+
+    // rewrite the blocks of the chain of a n-gram holding the id, false
+    // when none does. The first id of a block and the last one stored
+    // again at its end tell the ids it can hold without decoding it
+    fn remove_from_list(&mut self, id: u32, ngram: u32) -> Result<bool> {
+
+        let list_header = 5 + self.raw.offset_size as u64;
+        let mut removed = false;
+        // the block pointing to list_off, 0 for the header
+        let mut newer = 0;
+        let mut list_off = self.ngram_list_ptr(ngram);
+
+        while list_off != 0 {
+            let (size_log, nb_elem, nb_bytes, prev_off) = self.get_list_meta(list_off);
+            let content = self.map.ptr() as u64 + list_off + list_header;
+            let first = unsafe { ptr::read_unaligned(content as *const u32) };
+            let last = match nb_elem {
+                0 | 1 => first,
+                _ => unsafe { ptr::read_unaligned((content + nb_bytes as u64 - 4) as *const u32) },
+            };
+            if nb_elem == 0 || id < first || id > last || !self.block_ids(list_off).contains(&id) {
+                newer = list_off;
+                list_off = prev_off;
+                continue;
+            }
+            removed = true;
+
+            let ids: Vec<u32> = self.block_ids(list_off).into_iter().filter(|i| *i != id).collect();
+            if ids.is_empty() {
+                match newer {
+                    0 => try!(self.update_header(ngram, prev_off)),
+                    _ => {
+                        let (size, nb, bytes, _) = self.get_list_meta(newer);
+                        self.update_list_meta(newer, size, nb, bytes, prev_off);
+                    },
+                }
+                self.raw.free_blocks.get_or_insert(BTreeMap::new())
+                    .entry(size_log).or_insert(Vec::new()).push(list_off);
+                list_off = prev_off;
+                continue;
+            }
+
+            // the two differences around the id become one, it may not be packed
+            if ids.windows(2).any(|w| w[1] - w[0] > 268435456) {
+                return Err(Error::new(ErrorKind::Other, format!(
+                    "the ids around {} are too far apart to be packed without it", id)));
+            }
+            let mut packed = Vec::with_capacity(nb_bytes as usize);
+            packed.extend_from_slice(&ids[0].to_ne_bytes());
+            for w in ids.windows(2) {
+                let (diff, pack_size) = BinacleFile::pack_integer(w[1] - w[0]);
+                packed.extend_from_slice(&diff.to_ne_bytes()[.. pack_size as usize]);
+            }
+            if ids.len() > 1 {
+                packed.extend_from_slice(&ids[ids.len() - 1].to_ne_bytes());
+            }
+            unsafe {
+                ptr::copy_nonoverlapping(packed.as_ptr(), content as *mut u8, packed.len());
+            }
+            self.update_list_meta(list_off, size_log, ids.len() as u16, packed.len() as u16, prev_off);
+            newer = list_off;
+            list_off = prev_off;
+        }
+        Ok(removed)
+    }
+
     fn unpack_list(&self, list_off: u64) -> HashSet<u32> {
 
+        if let Some(ref pread) = self.pread {
+            return self.pread_unpack_list(pread, list_off);
+        }
+
         let list_ptr = self.map.ptr() as u64;
         let mut nb_elem = self.get_list_meta(list_off).1;
         
@@ -410,13 +1554,13 @@ impl BinacleFile {
         
         let mut set = HashSet::with_capacity(nb_elem as usize);
         let mut cur_ptr_list = list_ptr + list_off + 5 + self.raw.offset_size as u64;
-        let mut cur_elem = unsafe { ptr::read(cur_ptr_list as *const u32)};
+        let mut cur_elem = unsafe { ptr::read_unaligned(cur_ptr_list as *const u32)};
         cur_ptr_list += 4;
         nb_elem -= 1;
         set.insert(cur_elem);
 
         while nb_elem > 0 {
-            let next = unsafe { ptr::read(cur_ptr_list as *const u32)};
+            let next = unsafe { ptr::read_unaligned(cur_ptr_list as *const u32)};
             let (diff, nb_bytes) = BinacleFile::unpack_integer(next);
             cur_ptr_list += nb_bytes as u64;
             cur_elem += diff;
@@ -427,26 +1571,29 @@ impl BinacleFile {
         return set;
     }
 
-    fn read_meta(path: &str) -> BinacleStruct {
+    // refused when its flags need features this build does not have
+    fn read_meta(path: &str, access: Access) -> Result<(BinacleStruct, Unknown)> {
 
         let mut meta_path = PathBuf::from(&path);
         meta_path.set_extension("meta");
 
-        let mut file = OpenOptions::new()
+        let named = |e: Error| Error::new(e.kind(), format!("{}: {}", meta_path.display(), e));
+        let mut file = try!(OpenOptions::new()
                     .read(true)
-                    .open(meta_path.to_str().unwrap())
-                    .unwrap();
+                    .open(&meta_path)
+                    .map_err(&named));
 
         let mut encoded = String::new();
-        file.read_to_string(&mut encoded).unwrap();
+        try!(file.read_to_string(&mut encoded).map_err(&named));
 
-        let meta = json::decode(&encoded).unwrap();
+        let (meta, unknown): (BinacleStruct, Unknown) = try!(compat::decode(&encoded).map_err(&named));
+        try!(compat::check_flags(path, meta.flags.unwrap_or(0), compat::KNOWN_FLAGS, access));
 
-        meta
+        Ok((meta, unknown))
     }
 
-    fn write_meta(path: &str, meta: &BinacleStruct) {
-        let encoded = json::encode(meta).unwrap();
+    fn write_meta(path: &str, meta: &BinacleStruct, unknown: &Unknown) {
+        let encoded = compat::encode(meta, unknown).unwrap();
 
         let mut path = PathBuf::from(&path);
         path.set_extension("meta");
@@ -462,10 +1609,41 @@ impl BinacleFile {
     }
 
     
-    fn incr_size(&mut self, incr_size: u64) {
-        let _ = self.file.set_len(self.filesize + incr_size);
-        self.filesize += incr_size;
-        self.map = Mmap::open(&self.file, Protection::ReadWrite).unwrap();
+    fn incr_size(&mut self, incr_size: u64) -> Result<()> {
+        let size = self.filesize + incr_size;
+        self.resize_file(size)
+    }
+
+    // set the length of the mapped file following the platform strategy
+    // (see resize.rs), the map is always valid again when this returns
+    fn resize_file(&mut self, size: u64) -> Result<()> {
+
+        let strategy = self.resize;
+        let mut state = MapState::Mapped;
+
+        for step in strategy.steps() {
+            let next = try!(state.apply(strategy, *step));
+            if let Err(e) = self.run_resize_step(*step, size) {
+                for r in strategy.recover(state) {
+                    let _ = self.run_resize_step(*r, size);
+                }
+                return Err(e);
+            }
+            state = next;
+        }
+
+        self.filesize = size;
+        Ok(())
+    }
+
+    fn run_resize_step(&mut self, step: ResizeStep, size: u64) -> Result<()> {
+        match step {
+            // replace the file view with a tiny anonymous one to release it
+            ResizeStep::Unmap => self.map = try!(Mmap::anonymous(1, Protection::ReadWrite)),
+            ResizeStep::SetLen => try!(self.file.set_len(size)),
+            ResizeStep::Remap => self.map = try!(Mmap::open(&self.file, Protection::ReadWrite)),
+        };
+        Ok(())
     }
 
 
@@ -477,6 +1655,10 @@ impl BinacleFile {
     #[inline(always)]
     fn ngram_list_ptr(&self, ngram_f: u32) -> u64 {
 
+        if let Some(ref pread) = self.pread {
+            return self.pread_list_ptr(pread, ngram_f);
+        }
+
         let ngram = self.reduce_ngram(ngram_f);
 
         // rcompute offset of ngram list in header
@@ -484,10 +1666,10 @@ impl BinacleFile {
         offset += self.raw.offset_size as u64 * ngram as u64;
 
         // read the offset in the header
-        let mut list_off = unsafe { ptr::read(offset as *const u64)};
+        let mut list_off = unsafe { ptr::read_unaligned(offset as *const u64)};
 
         // reduce list_ptr according to offset_size
-        list_off &= (1u64 << (self.raw.offset_size*8)) - 1;
+        list_off &= offset_mask(self.raw.offset_size);
 
         // align the list_ptr on alignement
         list_off <<= self.raw.alignment;
@@ -495,12 +1677,24 @@ impl BinacleFile {
         list_off
     }
 
-    fn get_new_free_list(&mut self, size_log: u8) -> u64 {
+    fn get_new_free_list(&mut self, size_log: u8) -> Result<u64> {
+
+        // a block freed by the compaction first
+        if let Some(ref mut free) = self.raw.free_blocks {
+            if let Some(list_off) = free.get_mut(&size_log).and_then(|blocks| blocks.pop()) {
+                return Ok(list_off);
+            }
+        }
 
         let list_size = 2u64.pow(size_log as u32);
 
+        // an offset over it would wrap in the header
+        if self.raw.size + list_size > self.max_addressable_size() {
+            return Err(over_addressable(self.raw.offset_size, self.raw.alignment));
+        }
+
         if (self.raw.size + list_size) >= self.filesize {
-            self.incr_size(max(512*1024*1024, list_size));
+            try!(self.incr_size(max(512*1024*1024, list_size)));
         };
 
         // we alloc the new list at the end of the list
@@ -509,118 +1703,423 @@ impl BinacleFile {
         // update the size of the DB to handle the new list size
         self.raw.size += list_size;
 
-        list_off
+        Ok(list_off)
+    }
+
+    fn realloc_list(&mut self, list_off: u64, ngram: u32) -> Result<(u64, u8)> {
+
+        let (size_log, nb, _, _) = self.get_list_meta(list_off);
+
+        let new_size_log = min(size_log + 1, 12);
+
+        // get a new free bloc a requested size
+        let new_list_off = try!(self.get_new_free_list(new_size_log));
+
+        // write the new list_ptr into the header
+        try!(self.update_header(ngram, new_list_off));
+
+        // update the size of the new list
+        self.update_list_meta(new_list_off, new_size_log as u8, nb, 0, list_off);
+
+        Ok((new_list_off, new_size_log))
+    }
+
+    fn alloc_list(&mut self, ngram_f: u32) -> Result<u64> {
+        
+        let ngram = self.reduce_ngram(ngram_f);
+        let list_size_log = self.raw.alignment;
+
+        // we look throuh the map to see if a free list is available
+        let list_off = try!(self.get_new_free_list(list_size_log));
+        
+        // write the new list_ptr into the header
+        try!(self.update_header(ngram, list_off));
+
+        // init the new list with size and nb_elem
+        self.update_list_meta(list_off, list_size_log as u8, 0, 0, 0);
+
+        Ok(list_off)
+    }
+
+    fn update_header(&mut self, ngram_f: u32, mut list_off: u64) -> Result<()> {
+
+        if list_off >= self.max_addressable_size() {
+            return Err(over_addressable(self.raw.offset_size, self.raw.alignment));
+        }
+        let ngram = self.reduce_ngram(ngram_f);
+
+        // compute the offset in the header
+        let mut offset = self.map.ptr() as u64; 
+        offset += self.raw.offset_size as u64 * ngram as u64;
+
+        if list_off != 0 {
+            if let Bitmap::Present(ref mut occupancy) = *self.occupancy.get_mut().unwrap() {
+                if !occupancy.is_set(ngram) {
+                    // the sidecar written by the last flush lacks it now
+                    if self.bitmap_written {
+                        let _ = remove_file(occupancy::bitmap_path(&self.path));
+                        self.bitmap_written = false;
+                    }
+                    Arc::make_mut(occupancy).set(ngram);
+                }
+            }
+        }
+
+        list_off >>= self.raw.alignment;
+
+        // copy offset_size byte of list_off
+        unsafe { ptr::copy_nonoverlapping(
+                    &list_off as *const u64 as *const u8, 
+                    offset as *const u64 as *mut u8, 
+                    self.raw.offset_size as usize);
+        };
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn update_list_meta(&mut self, list_off: u64, size: u8, nb: u16, nb_bytes: u16, mut prev_off: u64) {
+
+        let mut list_ptr = self.map.ptr() as u64;
+        list_ptr += list_off; 
+
+        unsafe { 
+            ptr::copy_nonoverlapping(
+                &size as *const u8, 
+                list_ptr as *const u64 as *mut u8,
+                1);
+
+            // the counts are not aligned
+            list_ptr += 1;
+            ptr::write_unaligned(list_ptr as *mut u16, nb);
+
+            list_ptr += 2;
+            ptr::write_unaligned(list_ptr as *mut u16, nb_bytes);
+
+            list_ptr += 2;
+
+            prev_off >>= self.raw.alignment;
+
+            ptr::copy_nonoverlapping(
+                &prev_off as *const u64 as *const u8, 
+                list_ptr as *const u64 as *mut u8,
+                self.raw.offset_size as usize); 
+
+        };
+    }
+
+    #[inline(always)]
+    fn get_list_meta(&self, list_off: u64) -> (u8, u16, u16, u64) {
+
+        if let Some(ref pread) = self.pread {
+            return self.pread_list_meta(pread, list_off);
+        }
+
+        let mut list_ptr = self.map.ptr() as u64;
+        list_ptr += list_off;
+
+        let size = unsafe { ptr::read_unaligned(list_ptr as *const u8)};
+        list_ptr += 1;
+
+        let nb_id = unsafe { ptr::read_unaligned(list_ptr as *const u16)};
+        list_ptr += 2;
+
+        let nb_bytes = unsafe { ptr::read_unaligned(list_ptr as *const u16)};
+        list_ptr += 2;
+
+        let mut prev_off = unsafe { ptr::read_unaligned(list_ptr as *const u64)};
+        prev_off &= offset_mask(self.raw.offset_size);
+        prev_off <<= self.raw.alignment;
+
+        (size, nb_id, nb_bytes, prev_off)
+        
+    }
+
+    // ngram_list_ptr, get_list_meta and unpack_list over positioned reads
+    // (see reads.rs): the same layout, a read that failed gives zeros, an
+    // empty header entry or the end of a chain
+
+    fn pread_offset(&self, bytes: &[u8]) -> u64 {
+        let mut offset = [0u8; 8];
+        offset[.. bytes.len()].copy_from_slice(bytes);
+        u64::from_le_bytes(offset) << self.raw.alignment
+    }
+
+    fn pread_list_ptr(&self, pread: &Pread, ngram: u32) -> u64 {
+        let mut entry = [0u8; 8];
+        let entry = &mut entry[.. self.raw.offset_size as usize];
+        pread.read_at(self.header_entry(ngram), entry);
+        let list_off = self.pread_offset(entry);
+        match self.snapshot {
+            Some(ref snapshot) if !self.in_snapshot(snapshot, list_off) => 0,
+            _ => list_off,
+        }
+    }
+
+    fn pread_list_meta(&self, pread: &Pread, list_off: u64) -> (u8, u16, u16, u64) {
+        let mut meta = [0u8; 5 + 8];
+        let meta = &mut meta[.. 5 + self.raw.offset_size as usize];
+        pread.read_at(list_off, meta);
+        let (size_log, mut nb_elem, mut nb_bytes, mut prev_off) = (meta[0],
+            u16::from_ne_bytes([meta[1], meta[2]]),
+            u16::from_ne_bytes([meta[3], meta[4]]),
+            self.pread_offset(&meta[5 ..]));
+
+        if let Some(ref snapshot) = self.snapshot {
+            // written after the snapshot, or torn by the writer
+            let content = list_off + 5 + self.raw.offset_size as u64;
+            if !snapshot.holds(content, nb_bytes as u64) || (nb_elem > 0 && nb_bytes < 4) {
+                nb_elem = 0;
+                nb_bytes = 0;
+            }
+            // the previous block of a chain starts before it
+            if prev_off >= list_off || !self.in_snapshot(snapshot, prev_off) {
+                prev_off = 0;
+            }
+        }
+        (size_log, nb_elem, nb_bytes, prev_off)
+    }
+
+    // a block of a dirty read, 0 is the empty list
+    fn in_snapshot(&self, snapshot: &Snapshot, list_off: u64) -> bool {
+        list_off == 0 || (list_off >= self.header_len() && snapshot.holds(list_off, 5 + self.raw.offset_size as u64))
+    }
+
+    fn pread_unpack_list(&self, pread: &Pread, list_off: u64) -> HashSet<u32> {
+
+        let (_, nb_elem, nb_bytes, _) = self.pread_list_meta(pread, list_off);
+        if nb_elem == 0 {
+            return HashSet::new();
+        }
+
+        // the packed differences are decoded 4 bytes at a time, the last
+        // ones read past the content
+        let mut content = vec![0u8; nb_bytes as usize + 4];
+        pread.read_at(list_off + 5 + self.raw.offset_size as u64, &mut content[.. nb_bytes as usize]);
+        let read_u32 = |pos: usize| u32::from_ne_bytes([content[pos], content[pos + 1], content[pos + 2], content[pos + 3]]);
+
+        let mut set = HashSet::with_capacity(nb_elem as usize);
+        let mut cur_elem = read_u32(0);
+        let mut pos = 4;
+        set.insert(cur_elem);
+        for _ in 1 .. nb_elem {
+            // more ids than bytes: a block damaged, not decoded further
+            if pos + 4 > content.len() {
+                break;
+            }
+            let (diff, nb_bytes) = BinacleFile::unpack_integer(read_u32(pos));
+            pos += nb_bytes as usize;
+            cur_elem = cur_elem.wrapping_add(diff);
+            set.insert(cur_elem);
+        }
+        // the files being inserted when the shard was opened
+        if let Some(ref snapshot) = self.snapshot {
+            set.retain(|&id| id <= snapshot.last_id);
+        }
+        set
     }
+}
 
-    fn realloc_list(&mut self, list_off: u64, ngram: u32) -> (u64, u8) {
-
-        let (size_log, nb, _, _) = self.get_list_meta(list_off);
-
-        let new_size_log = min(size_log + 1, 12);
 
-        // get a new free bloc a requested size
-        let new_list_off = self.get_new_free_list(new_size_log);
+// the ids of a block of a list, decoded one at a time
+#[derive(Clone)]
+struct BlockCursor {
+    // offset in the file of the next packed difference
+    next: u64,
+    // ids of the block after head
+    left: u16,
+    head: u32,
+}
 
-        // write the new list_ptr into the header
-        self.update_header(ngram, new_list_off);
+// the ids of the list of a n-gram, ascending and without duplicates. The
+// ids of each block are ascending; the blocks of the chain are merged, so
+// the order of the blocks does not matter. Only a cursor by block of the
+// chain is kept.
+#[derive(Clone)]
+pub struct PostingIter<'a> {
+    file: &'a BinacleFile,
+    blocks: Vec<BlockCursor>,
+    last: Option<u32>,
+}
 
-        // update the size of the new list
-        self.update_list_meta(new_list_off, new_size_log as u8, nb, 0, list_off);
+impl<'a> Iterator for PostingIter<'a> {
+    type Item = u32;
 
-        (new_list_off, new_size_log)
+    fn next(&mut self) -> Option<u32> {
+        loop {
+            let n = match (0 .. self.blocks.len()).min_by_key(|&n| self.blocks[n].head) {
+                Some(n) => n,
+                None => return None,
+            };
+            let id = self.blocks[n].head;
+            if !self.file.advance(&mut self.blocks[n]) {
+                self.blocks.swap_remove(n);
+            }
+            // an id in two blocks of the chain
+            if self.last.map_or(true, |last| id > last) {
+                self.last = Some(id);
+                return Some(id);
+            }
+        }
     }
 
-    fn alloc_list(&mut self, ngram_f: u32) -> u64 {
-        
-        let ngram = self.reduce_ngram(ngram_f);
-        let list_size_log = self.raw.alignment;
-
-        // we look throuh the map to see if a free list is available
-        let list_off = self.get_new_free_list(list_size_log);
-        
-        // write the new list_ptr into the header
-        self.update_header(ngram, list_off);
-
-        // init the new list with size and nb_elem
-        self.update_list_meta(list_off, list_size_log as u8, 0, 0, 0);
-
-        list_off 
+    // the upper bound counts the ids present in several blocks each time
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.blocks.iter().map(|b| b.left as usize + 1).sum();
+        (min(left, 1), Some(left))
     }
+}
 
-    fn update_header(&mut self, ngram_f: u32, mut list_off: u64) {
-        let ngram = self.reduce_ngram(ngram_f);
 
-        // compute the offset in the header
-        let mut offset = self.map.ptr() as u64; 
-        offset += self.raw.offset_size as u64 * ngram as u64;
+// a shard rewritten by compact
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Compacted {
+    pub size_before: u64,
+    pub size_after: u64,
+    pub lists: u64,
+    pub blocks_before: u64,
+    pub blocks_after: u64,
+}
 
-        list_off >>= self.raw.alignment;
+impl Compacted {
 
-        // copy offset_size byte of list_off
-        unsafe { ptr::copy_nonoverlapping(
-                    &list_off as *const u64 as *const u8, 
-                    offset as *const u64 as *mut u8, 
-                    self.raw.offset_size as usize);
-        };
+    pub fn reclaimed(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after)
     }
+}
 
-    #[inline(always)]
-    fn update_list_meta(&mut self, list_off: u64, size: u8, nb: u16, nb_bytes: u16, mut prev_off: u64) {
-
-        let mut list_ptr = self.map.ptr() as u64;
-        list_ptr += list_off; 
+// lists of a bulk written index are at most this size, the longer ones
+// are chained like the ones grown by insertions
+pub const BULK_LIST_BYTES: usize = 1 << 15;
 
-        unsafe { 
-            ptr::copy_nonoverlapping(
-                &size as *const u8, 
-                list_ptr as *const u64 as *mut u8,
-                1);
+// bytes buffered before a write to the file
+const BULK_BUFFER_SIZE: usize = 1 << 20;
 
-            list_ptr += 1;
-            ptr::copy_nonoverlapping(
-                &nb as *const u16, 
-                list_ptr as *const u64 as *mut u16,
-                1); 
+// Writer of a whole index in one pass, the n-grams are given in increasing
+// order with all their ids: each list is written once, in header order, in
+// a block of its exact size (rounded to the alignment). The index has the
+// usual format, the insertions can go on after it.
+pub struct BulkWriter {
+    file: File,
+    path: String,
+    raw: BinacleStruct,
+    unknown: Unknown,
+    // header entries not written yet, starting at header_pos
+    header: Vec<u8>,
+    header_pos: u64,
+    // lists not written yet, ending at raw.size
+    data: Vec<u8>,
+    next_ngram: u64,
+    occupancy: Occupancy,
+    // blocks written
+    blocks: u64,
+}
 
-            list_ptr += 2;
-            ptr::copy_nonoverlapping(
-                &nb_bytes as *const u16, 
-                list_ptr as *const u64 as *mut u16,
-                1); 
+impl BulkWriter {
 
-            list_ptr += 2;
+    // ids must be sorted and distinct
+    pub fn write_list(&mut self, ngram: u32, ids: &[u32]) -> Result<()> {
 
-            prev_off >>= self.raw.alignment;
+        if (ngram as u64) < self.next_ngram || (ngram as u64) >> self.raw.ngram_size != 0 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("n-gram {:#x} out of order or over {} bits", ngram, self.raw.ngram_size)));
+        }
+        if ids.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(Error::new(ErrorKind::InvalidInput, "ids of a list must be sorted and distinct"));
+        }
+        self.next_ngram = ngram as u64 + 1;
+        if ids.is_empty() {
+            return Ok(());
+        }
 
-            ptr::copy_nonoverlapping(
-                &prev_off as *const u64 as *const u8, 
-                list_ptr as *const u64 as *mut u8,
-                self.raw.offset_size as usize); 
+        // the newest block, pointed by the header, holds the greatest ids
+        let mut prev_off = 0u64;
+        let mut start = 0;
+        while start < ids.len() {
+            let (end, block) = self.pack_block(&ids[start ..], prev_off);
+            prev_off = self.raw.size;
+            self.raw.size += block.len() as u64;
+            self.data.extend_from_slice(&block);
+            self.blocks += 1;
+            start += end;
+        }
+        if self.data.len() >= BULK_BUFFER_SIZE {
+            try!(self.flush_data());
+        }
 
-        };
+        let pos = self.raw.offset_size as u64 * ngram as u64;
+        let buffered_end = self.header_pos + self.header.len() as u64;
+        if pos - buffered_end > BULK_BUFFER_SIZE as u64 || self.header.len() >= BULK_BUFFER_SIZE {
+            // the entries in between are left to the zeros of the file
+            try!(self.flush_header());
+            self.header_pos = pos;
+        } else {
+            let gap = (pos - buffered_end) as usize;
+            self.header.extend(::std::iter::repeat(0u8).take(gap));
+        }
+        if prev_off >= addressable_size(self.raw.offset_size, self.raw.alignment) {
+            return Err(over_addressable(self.raw.offset_size, self.raw.alignment));
+        }
+        let entry = (prev_off >> self.raw.alignment).to_ne_bytes();
+        self.header.extend_from_slice(&entry[.. self.raw.offset_size as usize]);
+        self.occupancy.set(ngram);
+        Ok(())
     }
 
-    #[inline(always)]
-    fn get_list_meta(&self, list_off: u64) -> (u8, u16, u16, u64) {
+    // write what is left and the meta, the index can then be opened
+    pub fn finish(mut self, nb_file: u32, last_id: u32, average_size: f64) -> Result<u64> {
 
-        let mut list_ptr = self.map.ptr() as u64;
-        list_ptr += list_off;
+        try!(self.flush_header());
+        try!(self.flush_data());
+        try!(self.file.set_len(self.raw.size));
+        try!(self.file.sync_all());
 
-        let size = unsafe { ptr::read(list_ptr as *const u8)};
-        list_ptr += 1;
+        self.raw.nb_file = nb_file;
+        self.raw.last_id = last_id;
+        self.raw.average_size = average_size;
+        BinacleFile::write_meta(&self.path, &self.raw, &self.unknown);
 
-        let nb_id = unsafe { ptr::read(list_ptr as *const u16)};
-        list_ptr += 2;
+        let mut meta_path = PathBuf::from(&self.path);
+        meta_path.set_extension("meta");
+        try!(sync_path(meta_path.to_str().unwrap()));
+        try!(self.occupancy.write(&self.path, BinacleFile::stamp_of(&self.raw)));
+        Ok(self.raw.size)
+    }
 
-        let nb_bytes = unsafe { ptr::read(list_ptr as *const u16)};
-        list_ptr += 2;
+    // a block with as many of the ids as it can hold, the number of ids taken
+    fn pack_block(&self, ids: &[u32], prev_off: u64) -> (usize, Vec<u8>) {
+
+        let list_header = 5 + self.raw.offset_size as usize;
+        let (nb, content) = BinacleFile::pack_ids(ids, list_header);
+
+        // rounded to the alignment, the size announced is the power of 2
+        // below so an insertion never writes past the block
+        let align = 1usize << self.raw.alignment;
+        let len = (list_header + content.len() + align - 1) / align * align;
+        let size_log = (63 - (len as u64).leading_zeros()) as u8;
+
+        let mut block = Vec::with_capacity(len);
+        block.push(size_log);
+        block.extend_from_slice(&(nb as u16).to_ne_bytes());
+        block.extend_from_slice(&(content.len() as u16).to_ne_bytes());
+        block.extend_from_slice(&(prev_off >> self.raw.alignment).to_ne_bytes()[.. self.raw.offset_size as usize]);
+        block.extend_from_slice(&content);
+        block.resize(len, 0);
+        (nb, block)
+    }
 
-        let mut prev_off = unsafe { ptr::read(list_ptr as *const u64)};
-        prev_off &= (1u64 << (self.raw.offset_size * 8)) - 1;
-        prev_off <<= self.raw.alignment;
+    fn flush_header(&mut self) -> Result<()> {
+        try!(self.file.seek(SeekFrom::Start(self.header_pos)));
+        try!(self.file.write_all(&self.header));
+        self.header_pos += self.header.len() as u64;
+        self.header.clear();
+        Ok(())
+    }
 
-        (size, nb_id, nb_bytes, prev_off)
-        
+    fn flush_data(&mut self) -> Result<()> {
+        try!(self.file.seek(SeekFrom::Start(self.raw.size - self.data.len() as u64)));
+        try!(self.file.write_all(&self.data));
+        self.data.clear();
+        Ok(())
     }
 }
 
@@ -628,16 +2127,25 @@ impl BinacleFile {
 impl Drop for BinacleFile {
 
     fn drop(&mut self) {
-        let meta = self.raw.clone();
-        BinacleFile::write_meta(&self.path, &meta);
+        // a reader has the meta it read, the one of a dirty read is even
+        // older than the writer's: only the writer saves it. A reader may
+        // not be allowed to write it (a sandboxed server)
+        if self.writer {
+            let meta = self.raw.clone();
+            BinacleFile::write_meta(&self.path, &meta, &self.unknown);
+            // scanned without it until it is rebuilt when it can not be written
+            let _ = self.write_bitmap();
+            lock::released(&self.path);
+        }
     }
 }
 
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
 
     use super::*;
+    use std::cmp::min;
     use std::fs::{remove_file};
     use std::fs::OpenOptions;
     use std::ptr;
@@ -646,26 +2154,12 @@ mod tests {
 
     fn verify_file(database: &BinacleFile, filepath: &str, id: u32) -> Result<u32> {
 
-        let mut file = try!(OpenOptions::new().read(true).open(filepath));
-
-        let mut buf = vec![0u8; 4096*64];
-        loop {
-            match file.read(&mut buf).unwrap() {
-                0 => break,
-                1 | 2 | 3 => break,
-                len => {
-                    for i in 0 .. len-3 {
-                        let ptr_read = buf.as_ptr() as u64 + i as u64;
-                        let ngram: u32 = unsafe { ptr::read(ptr_read as *const u32)};
-
-                        assert_eq!(1, database.get_ids_size_by_ngram(ngram));
-
-                        let m = database.get_ids_by_ngram(ngram);
-                        assert!(m.contains(&id)); 
-                    }
-                }
-            }
-        }
+        let file = try!(OpenOptions::new().read(true).open(filepath));
+        try!(read_ngrams(file, |ngram| {
+            assert_eq!(1, database.get_ids_size_by_ngram(ngram));
+            assert!(database.get_ids_by_ngram(ngram).contains(&id));
+            Ok(())
+        }));
         Ok(id)
     }
 
@@ -679,6 +2173,172 @@ mod tests {
         }
         let _ = remove_file("test_file1.db");
         let _ = remove_file("test_file1.meta");
+        let _ = remove_file("test_file1.bitmap");
+    }
+
+    // reader failing after `ok` bytes
+    pub struct FaultyReader {
+        pub ok: usize,
+    }
+
+    impl Read for FaultyReader {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.ok == 0 {
+                return Err(Error::new(ErrorKind::Other, "injected read failure"));
+            }
+            let len = min(self.ok, min(buf.len(), 64));
+            for i in 0 .. len {
+                buf[i] = (self.ok - i) as u8;
+            }
+            self.ok -= len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn insert_read_failure() {
+        {
+            let mut db = BinacleFile::create("test19.db", 5, 6, 28).unwrap();
+            assert!(db.insert_reader(FaultyReader { ok: 1000 }, 7).is_err());
+            assert_eq!(0, db.raw.nb_file);
+            assert_eq!(0, db.raw.last_id);
+            assert_eq!(0.0, db.raw.average_size);
+
+            db.insert_reader(&b"some content"[..], 8).unwrap();
+            assert_eq!(1, db.raw.nb_file);
+            assert_eq!(8, db.raw.last_id);
+            assert_eq!(12.0, db.raw.average_size);
+        }
+        let _ = remove_file("test19.db");
+        let _ = remove_file("test19.meta");
+        let _ = remove_file("test19.bitmap");
+    }
+
+    // returns the content `chunk` bytes at a time
+    struct Chunked<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Chunked<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let len = self.1.min(self.0.len()).min(buf.len());
+            buf[.. len].copy_from_slice(&self.0[.. len]);
+            self.0 = &self.0[len ..];
+            Ok(len)
+        }
+    }
+
+    fn ngrams_of<R: Read>(reader: R) -> (u64, Vec<u32>) {
+        let mut ngrams = Vec::new();
+        let size = read_ngrams(reader, |ngram| {
+            ngrams.push(ngram);
+            Ok(())
+        }).unwrap();
+        ngrams.sort();
+        (size, ngrams)
+    }
+
+    #[test]
+    fn ngrams_whatever_the_reads() {
+
+        let mut contents: Vec<Vec<u8>> = (0 .. 9).map(|len| (0 .. len).map(|i| i as u8 * 7 + 1).collect()).collect();
+        contents.push((0 .. 3 * 4096 + 5).map(|i: u32| (i.wrapping_mul(2654435761) >> 24) as u8).collect());
+        contents.push(b"AAAAGetProcAddressBBBBAAAA".to_vec());
+
+        for content in &contents {
+            let (size, expected) = ngrams_of(&content[..]);
+            assert_eq!(content.len() as u64, size);
+            assert_eq!(content.len().saturating_sub(3), expected.len());
+            for &chunk in &[1, 3, 4096, content.len().max(1)] {
+                assert_eq!((size, expected.clone()), ngrams_of(Chunked(content, chunk)), "{} bytes read {} at a time", content.len(), chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn addressable_limit() {
+        assert_eq!(1 << 46, addressable_size(5, 6));
+        assert_eq!(u64::max_value(), addressable_size(8, 12));
+        {
+            // lists start below 2^(2*8 + 4) = 1 MB, after a header of 128 KB
+            // (and one block of alignment)
+            let mut db = BinacleFile::create("test22.db", 2, 4, 16).unwrap();
+            assert_eq!(1 << 20, db.max_addressable_size());
+            let mut failed = None;
+            for ngram in 0 .. 1 << 16 {
+                if let Err(e) = db.insert_ngram(1, ngram) {
+                    failed = Some((ngram, e));
+                    break;
+                }
+            }
+            let (ngram, e) = failed.unwrap();
+            assert!(e.to_string().contains("maximum addressable size"), "{}", e);
+            assert_eq!(((1 << 20) - (2 << 16) - 16) / 16, ngram);
+            assert!(db.get_size() <= 1 << 20);
+
+            // the lists written before are intact
+            assert!(db.get_ids_by_ngram(ngram - 1).contains(&1));
+            assert!(db.get_ids_by_ngram(ngram).is_empty());
+        }
+        let _ = remove_file("test22.db");
+        let _ = remove_file("test22.meta");
+        let _ = remove_file("test22.bitmap");
+    }
+
+    // the sizes at the edges of the parameters, in u64
+    #[test]
+    fn widest_parameters() {
+        assert_eq!(5 * (1u64 << 31) + 64, header_size(5, 6, 31));
+        assert_eq!(5 * (1u64 << 32) + 64, header_size(5, 6, 32));
+        assert_eq!(8 * (1u64 << 32) + 4096, header_size(8, 12, 32));
+        assert_eq!((1u64 << 40) - 1, offset_mask(5));
+        assert_eq!(u64::max_value(), offset_mask(8));
+        {
+            // offsets on 8 bytes, chained lists
+            let mut db = BinacleFile::create("test31.db", 8, 4, 16).unwrap();
+            assert_eq!(header_size(8, 4, 16), db.get_size());
+            helper_insert(&mut db, 1, 0xFFFFFFFF, 300);
+            helper_insert(&mut db, 1000, 0x1234, 3);
+            assert_eq!(2, db.nb_lists());
+        }
+        let _ = remove_file("test31.db");
+        let _ = remove_file("test31.meta");
+        let _ = remove_file("test31.bitmap");
+    }
+
+    #[test]
+    fn small_files_whatever_the_reads() {
+        {
+            let mut db = BinacleFile::create("test21.db", 5, 6, 28).unwrap();
+            db.insert_reader(Chunked(b"abcdefg", 1), 1).unwrap();
+            db.insert_reader(Chunked(b"abcd", 2), 2).unwrap();
+            db.insert_reader(Chunked(b"abc", 1), 3).unwrap();
+            assert_eq!(vec![1], db.search(b"defg").unwrap().into_iter().collect::<Vec<u32>>());
+            let mut ids: Vec<u32> = db.search(b"abcd").unwrap().into_iter().collect();
+            ids.sort();
+            assert_eq!(vec![1, 2], ids);
+            assert_eq!(3, db.raw.nb_file);
+        }
+        let _ = remove_file("test21.db");
+        let _ = remove_file("test21.meta");
+        let _ = remove_file("test21.bitmap");
+    }
+
+    #[test]
+    fn sizes_over_4gb() {
+        {
+            let mut db = BinacleFile::create("test20.db", 5, 6, 28).unwrap();
+            db.account_file(1, 5 << 30);
+            db.account_file(2, 1 << 30);
+            assert_eq!((3u64 << 30) as f64, db.average_size());
+        }
+        // the meta keeps the wide values
+        {
+            let db = BinacleFile::open_read("test20.db").unwrap();
+            assert_eq!((3u64 << 30) as f64, db.average_size());
+            assert_eq!(2, db.raw.nb_file);
+        }
+        let _ = remove_file("test20.db");
+        let _ = remove_file("test20.meta");
+        let _ = remove_file("test20.bitmap");
     }
 
     #[test]
@@ -695,6 +2355,7 @@ mod tests {
         }
         let _ = remove_file("test1.db");
         let _ = remove_file("test1.meta");
+        let _ = remove_file("test1.bitmap");
     }
 
     #[test]
@@ -709,6 +2370,8 @@ mod tests {
                 last_id: 0,
                 nb_file: 0,
                 average_size: 0.0,
+                free_blocks: None,
+                flags: None,
             };
             assert_eq!(db.raw.size, db.file.metadata().unwrap().len());
             assert_eq!(hd.offset_size, db.raw.offset_size);
@@ -717,6 +2380,7 @@ mod tests {
         }
         let _ = remove_file("test2.db");
         let _ = remove_file("test2.meta");
+        let _ = remove_file("test2.bitmap");
     }
 
     fn helper_insert(mut db: &mut BinacleFile, id: u32, ngram: u32, size: u32) {
@@ -757,6 +2421,7 @@ mod tests {
         }
         let _ = remove_file("test3.db");
         let _ = remove_file("test3.meta");
+        let _ = remove_file("test3.bitmap");
     }
 
     #[test]
@@ -768,6 +2433,7 @@ mod tests {
 
         let _ = remove_file("test4.db");
         let _ = remove_file("test4.meta");
+        let _ = remove_file("test4.bitmap");
     }
 
     #[test]
@@ -778,6 +2444,7 @@ mod tests {
         }
         let _ = remove_file("test5.db");
         let _ = remove_file("test5.meta");
+        let _ = remove_file("test5.bitmap");
     }
 
     #[test]
@@ -789,6 +2456,7 @@ mod tests {
 
         let _ = remove_file("test0.db");
         let _ = remove_file("test0.meta");
+        let _ = remove_file("test0.bitmap");
     }
 
     #[test]
@@ -800,6 +2468,7 @@ mod tests {
 
         let _ = remove_file("test6.db");
         let _ = remove_file("test6.meta");
+        let _ = remove_file("test6.bitmap");
     }
 
     #[test]
@@ -811,6 +2480,7 @@ mod tests {
 
         let _ = remove_file("test7.db");
         let _ = remove_file("test7.meta");
+        let _ = remove_file("test7.bitmap");
     }
 
     #[test]
@@ -822,6 +2492,7 @@ mod tests {
 
         let _ = remove_file("test8.db");
         let _ = remove_file("test8.meta");
+        let _ = remove_file("test8.bitmap");
     }
 
     #[test]
@@ -833,6 +2504,7 @@ mod tests {
 
         let _ = remove_file("test9.db");
         let _ = remove_file("test9.meta");
+        let _ = remove_file("test9.bitmap");
     }
 
     #[test]
@@ -844,6 +2516,7 @@ mod tests {
 
         let _ = remove_file("test10.db");
         let _ = remove_file("test10.meta");
+        let _ = remove_file("test10.bitmap");
     }
 
     #[test]
@@ -856,6 +2529,7 @@ mod tests {
 
         let _ = remove_file("test11.db");
         let _ = remove_file("test11.meta");
+        let _ = remove_file("test11.bitmap");
     }
 
     #[test]
@@ -868,6 +2542,26 @@ mod tests {
 
         let _ = remove_file("test12.db");
         let _ = remove_file("test12.meta");
+        let _ = remove_file("test12.bitmap");
+    }
+
+    #[test]
+    fn grow_unmap_first() {
+        {
+            // the Windows sequence must also work where set_len on a mapped file is allowed
+            let mut db = BinacleFile::create("test18.db", 5, 6, 28).unwrap();
+            db.resize = ResizeStrategy::UnmapFirst;
+            helper_insert(&mut db, 0x1337, 0x13874763, 256);
+            let size = db.filesize;
+            db.fix_size().unwrap();
+            assert!(db.filesize < size);
+            assert_eq!(db.raw.size, db.file.metadata().unwrap().len());
+            let m = db.get_ids_by_ngram(0x13874763);
+            assert_eq!(m.len(), 256);
+        }
+        let _ = remove_file("test18.db");
+        let _ = remove_file("test18.meta");
+        let _ = remove_file("test18.bitmap");
     }
 
     #[test]
@@ -882,6 +2576,7 @@ mod tests {
 
         let _ = remove_file("test13.db");
         let _ = remove_file("test13.meta");
+        let _ = remove_file("test13.bitmap");
     }
 
     #[test]
@@ -896,6 +2591,7 @@ mod tests {
 
         let _ = remove_file("test14.db");
         let _ = remove_file("test14.meta");
+        let _ = remove_file("test14.bitmap");
     }
 
     #[test]
@@ -913,12 +2609,14 @@ mod tests {
         {
             let mut db = BinacleFile::open_read("test16.db").unwrap();
             let result = panic::catch_unwind(AssertUnwindSafe(|| {
-                let _ = db.insert_ngram(0x123, 0x11223344);
+                db.insert_ngram(0x123, 0x11223344)
             }));
-            assert!(result.is_err());
+            // growing a read only index must fail
+            assert!(result.is_err() || result.unwrap().is_err());
         }
         let _ = remove_file("test16.db");
         let _ = remove_file("test16.meta");        
+        let _ = remove_file("test16.bitmap");        
     }
 
     #[test]
@@ -959,7 +2657,588 @@ mod tests {
 
         let _ = remove_file("test17.db");
         let _ = remove_file("test17.meta");  
+        let _ = remove_file("test17.bitmap");  
+
+    }
+
+    #[test]
+    fn bulk_lists() {
+        {
+            let db = BinacleFile::create("test34.db", 5, 4, 16).unwrap();
+            let mut w = db.into_bulk_writer().unwrap();
+            // 2-byte differences, the list takes two blocks
+            let long: Vec<u32> = (1 .. 20000).map(|i| i * 1000).collect();
+            w.write_list(0x11, &[7]).unwrap();
+            w.write_list(0x12, &long).unwrap();
+            w.write_list(0xfff0, &[1, 2, 300]).unwrap();
+            assert!(w.write_list(0x12, &[1]).is_err());
+            assert!(w.write_list(0xfff1, &[3, 2]).is_err());
+            w.finish(3, 19999000, 10.0).unwrap();
+        }
+        {
+            let mut db = BinacleFile::open_write("test34.db").unwrap();
+            assert_eq!(19999, db.get_ids_size_by_ngram(0x12));
+            assert_eq!(19999, db.get_ids_by_ngram(0x12).len());
+            assert_eq!(3, db.get_ids_by_ngram(0xfff0).len());
+            assert!(db.get_ids_by_ngram(0x13).is_empty());
+
+            // insertions after the bulk written lists
+            db.insert_ngram(8, 0x11).unwrap();
+            db.insert_ngram(20000000, 0x12).unwrap();
+            let mut ids: Vec<u32> = db.get_ids_by_ngram(0x11).into_iter().collect();
+            ids.sort();
+            assert_eq!(vec![7, 8], ids);
+            assert!(db.get_ids_by_ngram(0x12).contains(&20000000));
+        }
+
+        let _ = remove_file("test34.db");
+        let _ = remove_file("test34.meta");
+        let _ = remove_file("test34.bitmap");
+    }
+
+    #[test]
+    fn postings_streamed() {
+        {
+            let mut db = BinacleFile::create("test23.db", 5, 4, 16).unwrap();
+            // small alphabet: long chains for the common n-grams, short lists for the others
+            let mut seed = 0x2545f491u32;
+            for id in 1 .. 400 {
+                let content: Vec<u8> = (0 .. 256).map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 17;
+                    seed ^= seed << 5;
+                    b"abcdefgh"[(seed % 8) as usize]
+                }).collect();
+                db.insert_reader(&content[..], id * 7).unwrap();
+            }
+            // a n-gram compacted in a single block, another one left in a chain
+            assert!(db.compact_ngram(u32::from_ne_bytes(*b"abca")).unwrap() > 1);
+            let chained = u32::from_ne_bytes(*b"hgfe");
+            assert!(db.leading_blocks(chained, 2).len() > 1);
+
+            let mut ngrams = 0;
+            let mut previous = None;
+            for (ngram, ids) in db.postings() {
+                assert!(previous.map_or(true, |p| p < ngram));
+                previous = Some(ngram);
+                ngrams += 1;
+                let upper = ids.size_hint().1.unwrap();
+                let ids: Vec<u32> = ids.collect();
+                assert!(ids.windows(2).all(|w| w[0] < w[1]));
+                assert!(ids.len() <= upper);
+                // a sample of the n-grams, and the two above
+                if ngram % 7 == 0 || ngram == db.reduce_ngram(chained) || ngram == db.reduce_ngram(u32::from_ne_bytes(*b"abca")) {
+                    let mut expected: Vec<u32> = db.get_ids_by_ngram(ngram).into_iter().collect();
+                    expected.sort();
+                    assert_eq!(expected, ids);
+                }
+            }
+            assert_eq!(db.iter_postings().count(), ngrams);
+            assert_eq!(0, db.posting_iter(u32::from_ne_bytes(*b"zzzz")).count());
+        }
+
+        let _ = remove_file("test23.db");
+        let _ = remove_file("test23.meta");
+        let _ = remove_file("test23.bitmap");
+    }
+
+    #[test]
+    fn remove_ids() {
+        {
+            // small blocks, a long chain
+            let mut db = BinacleFile::create("test24.db", 5, 4, 16).unwrap();
+            let mut expected: Vec<u32> = (0 .. 60).map(|i| 1 + i * 3 + i * i * 50).collect();
+            for id in &expected {
+                db.insert_ngram(*id, 0x1234).unwrap();
+            }
+            let shared = expected[5];
+            db.insert_ngram(shared, 0x99).unwrap();
+            let blocks = db.leading_blocks(0x1234, 100);
+            assert!(blocks.len() > 3);
+
+            // the first id (alone in the oldest block), the last one, one
+            // in the middle of the chain
+            for id in &[expected[0], expected[59], expected[30]] {
+                assert_eq!(1, db.remove_id(*id).unwrap());
+                expected.retain(|i| i != id);
+            }
+            // all the ids of the second block: it is unlinked and freed too
+            let ngrams: HashSet<u32> = [0x1234, 0x77].iter().cloned().collect();
+            for id in db.block_ids(blocks[1].0) {
+                assert_eq!(1, db.remove_id_from(id, &ngrams).unwrap());
+                expected.retain(|i| *i != id);
+            }
+            assert_eq!(blocks.len() - 2, db.leading_blocks(0x1234, 100).len());
+            assert_eq!(2, db.nb_free_blocks());
+            assert_eq!(0, db.remove_id(2).unwrap());
+
+            let mut ids: Vec<u32> = db.get_ids_by_ngram(0x1234).into_iter().collect();
+            ids.sort();
+            assert_eq!(expected, ids);
+            assert!(db.posting_iter(0x1234).eq(expected.iter().cloned()));
+
+            // the only id of a list: the list is gone
+            db.remove_id(shared).unwrap();
+            expected.retain(|i| *i != shared);
+            assert!(db.get_ids_by_ngram(0x99).is_empty());
+            assert_eq!(0, db.ngram_list_ptr(0x99));
+
+            // the lists take more ids after the rewritten blocks
+            db.insert_ngram(20000000, 0x1234).unwrap();
+            db.insert_ngram(7, 0x99).unwrap();
+            assert_eq!(expected.len() + 1, db.get_ids_by_ngram(0x1234).len());
+            assert!(db.get_ids_by_ngram(0x1234).contains(&20000000));
+            assert_eq!(vec![7], db.posting_iter(0x99).collect::<Vec<u32>>());
+        }
+
+        let _ = remove_file("test24.db");
+        let _ = remove_file("test24.meta");
+        let _ = remove_file("test24.bitmap");
+    }
+
+    #[test]
+    fn repeated_ngrams_written_once() {
+        {
+            use std::sync::atomic::{AtomicU64, Ordering};
+
+            // padding and a repeated structure
+            let mut content = vec![0u8; 4096];
+            for i in 0 .. 64u8 {
+                content.extend_from_slice(b"\x4c\x8b\xdc\x49\x89\x5b\x08 GetProcAddress");
+                content.push(i);
+            }
+            let mut plain = BinacleFile::create("test25.db", 5, 6, 16).unwrap();
+            let mut dedup = BinacleFile::create("test26.db", 5, 6, 16).unwrap();
+            plain.set_dedup(false);
+            let mut inserted = Vec::new();
+            for db in [&mut plain, &mut dedup].iter_mut() {
+                let count = Arc::new(AtomicU64::new(0));
+                let counted = count.clone();
+                db.set_insert_hook(Box::new(move |_| { counted.fetch_add(1, Ordering::SeqCst); }));
+                db.insert_reader(&content[..], 1).unwrap();
+                inserted.push((db.last_dedup(), count.load(Ordering::SeqCst)));
+                db.insert_reader(&b"GetProcAddress GetProcAddress"[..], 2).unwrap();
+            }
+            let ((plain_stats, plain_calls), (dedup_stats, dedup_calls)) = (inserted[0], inserted[1]);
+
+            // every occurrence written by the old path, each distinct
+            // reduced n-gram once by the new one
+            let distinct: HashSet<u32> = content.windows(4).map(|w| u32::from_ne_bytes([w[0], w[1], w[2], w[3]]) & 0xffff).collect();
+            assert_eq!((content.len() - 3) as u64, plain_stats.ngrams);
+            assert_eq!((plain_stats.ngrams, plain_stats.ngrams), (plain_stats.writes, plain_calls));
+            assert_eq!(plain_stats.ngrams, dedup_stats.ngrams);
+            assert_eq!((distinct.len() as u64, distinct.len() as u64), (dedup_stats.writes, dedup_calls));
+            assert!(dedup_calls < plain_calls);
+            assert!(dedup_stats.ratio() > 10.0);
+            assert_eq!(26, dedup.last_dedup().ngrams);
+            assert!(dedup.last_dedup().writes < 26);
+
+            // the same lists, the same results
+            assert!(plain.iter_postings().eq(dedup.iter_postings()));
+            for pattern in &[&b"GetProcAddress"[..], &b"\x49\x89\x5b\x08 Get"[..], &[0u8; 64][..]] {
+                assert_eq!(plain.search(pattern).unwrap(), dedup.search(pattern).unwrap());
+            }
+        }
+        for name in &["test25", "test26"] {
+            let _ = remove_file(format!("{}.db", name));
+            let _ = remove_file(format!("{}.meta", name));
+            let _ = remove_file(format!("{}.bitmap", name));
+        }
+    }
+
+    #[test]
+    fn pread_reads() {
+        {
+            let mut db = BinacleFile::create("test27.db", 5, 4, 16).unwrap();
+            for id in 1 .. 200 {
+                let content = format!("GetProcAddress {} LoadLibraryA {}", id * 37, id % 5);
+                db.insert_reader(content.as_bytes(), id).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        {
+            // the same lists and results over the map and over positioned reads
+            let mapped = BinacleFile::open_read("test27.db").unwrap();
+            let pread = BinacleFile::open_read_with("test27.db", ReadPath::Pread).unwrap();
+            for ngram in 0 .. 1 << 16 {
+                assert_eq!(mapped.get_ids_size_by_ngram(ngram), pread.get_ids_size_by_ngram(ngram));
+                assert_eq!(mapped.get_ids_by_ngram(ngram), pread.get_ids_by_ngram(ngram));
+            }
+            for pattern in &[&b"GetProcAddress"[..], &b"LoadLibraryA 3"[..], &b"absent"[..]] {
+                assert_eq!(mapped.search(pattern).unwrap(), pread.search(pattern).unwrap());
+            }
+            assert_eq!(199, pread.search(b"GetProcAddress").unwrap().len());
+            drop(mapped);
+
+            // the blocks gone under it, as a read failing on network
+            // storage: an error, not a SIGBUS
+            let header = pread.header_len();
+            OpenOptions::new().write(true).open("test27.db").unwrap().set_len(header).unwrap();
+            assert!(pread.search(b"GetProcAddress").is_err());
+            assert!(pread.check_reads().is_ok());
+            assert!(pread.search_planned(b"LoadLibraryA", 8).is_err());
+        }
+
+        let _ = remove_file("test27.db");
+        let _ = remove_file("test27.meta");
+        let _ = remove_file("test27.bitmap");
+    }
+
+    // the slots having a list, read one by one
+    fn occupied_slots(db: &BinacleFile) -> Vec<u32> {
+        (0 .. db.header_slots()).map(|s| s as u32).filter(|&ngram| db.ngram_list_ptr(ngram) != 0).collect()
+    }
+
+    #[test]
+    fn occupancy_bitmap() {
+        {
+            let mut db = BinacleFile::create("test28.db", 5, 4, 16).unwrap();
+            for id in 1 .. 120 {
+                let content = format!("GetProcAddress {} LoadLibraryA {}", id * 37, id % 5);
+                db.insert_reader(content.as_bytes(), id).unwrap();
+                if id % 40 == 0 {
+                    assert_eq!(occupied_slots(&db), db.scan_slots().collect::<Vec<u32>>());
+                }
+            }
+            // the lists reallocated by the compaction and emptied by a removal
+            assert!(db.compact_ngram(u32::from_ne_bytes(*b"GetP")).unwrap() > 0);
+            db.remove_id(7).unwrap();
+            assert_eq!(BitmapCheck::InSync { slots: occupied_slots(&db).len() as u64 }, db.check_bitmap());
+
+            // the sidecar of the flush lacks the slots set after it
+            db.flush().unwrap();
+            assert!(occupancy::bitmap_path("test28.db").exists());
+            db.insert_reader(&b"a new content"[..], 200).unwrap();
+            assert!(!occupancy::bitmap_path("test28.db").exists());
+        }
+        {
+            // removed while the shard is written
+            let db = BinacleFile::open_write("test28.db").unwrap();
+            assert!(!occupancy::bitmap_path("test28.db").exists());
+            assert!(db.header_skipped().is_some());
+        }
+
+        // the scans give the same results with and without the bitmap
+        let scans = |db: &BinacleFile| {
+            let mut chains = Vec::new();
+            db.for_each_chain(|ngram, len| chains.push((ngram, len)));
+            let mut ids: Vec<u32> = db.all_ids().into_iter().collect();
+            ids.sort();
+            (db.iter_postings().collect::<Vec<(u32, Vec<u32>)>>(), chains, db.nb_lists(), ids)
+        };
+        let db = BinacleFile::open_read("test28.db").unwrap();
+        let skipped = db.header_skipped().unwrap();
+        assert_eq!(65536 - occupied_slots(&db).len() as u64, skipped);
+        let with_bitmap = scans(&db);
+        drop(db);
+
+        remove_file("test28.bitmap").unwrap();
+        let db = BinacleFile::open_read("test28.db").unwrap();
+        assert_eq!(None, db.header_skipped());
+        assert_eq!(BitmapCheck::Missing, db.check_bitmap());
+        assert_eq!(with_bitmap, scans(&db));
+        assert!(with_bitmap.0.len() > 100 && !with_bitmap.3.contains(&7));
+
+        // rebuilt from the header
+        assert_eq!(65536 - skipped, db.rebuild_bitmap().unwrap());
+        assert_eq!(Some(skipped), db.header_skipped());
+        drop(db);
+        let db = BinacleFile::open_read("test28.db").unwrap();
+        assert_eq!(Some(skipped), db.header_skipped());
+        drop(db);
+
+        // out of sync once the meta changes without it
+        let mut meta = BinacleFile::read_meta("test28.db", Access::Read).unwrap();
+        meta.0.nb_file += 1;
+        BinacleFile::write_meta("test28.db", &meta.0, &meta.1);
+        let db = BinacleFile::open_read("test28.db").unwrap();
+        assert_eq!(BitmapCheck::Stale, db.check_bitmap());
+        assert_eq!(with_bitmap, scans(&db));
+        drop(db);
+
+        let _ = remove_file("test28.db");
+        let _ = remove_file("test28.meta");
+        let _ = remove_file("test28.bitmap");
+    }
+
+    // a shard read without its lock while it is written: the ids after the
+    // snapshot, the blocks allocated since and a chain damaged are misses
+    #[test]
+    fn dirty_read_bounds() {
+
+        let ids = |from: u32, to: u32| (from .. to).collect::<HashSet<u32>>();
+        let mut db = BinacleFile::create("test29.db", 5, 6, 20).unwrap();
+        for id in 1 .. 10 {
+            db.insert_reader(&b"ABCD"[..], id).unwrap();
+        }
+        db.flush().unwrap();
+
+        // appended to the block of the snapshot, after its last id
+        for id in 10 .. 13 {
+            db.insert_reader(&b"ABCD"[..], id).unwrap();
+        }
+        let dirty = BinacleFile::open_dirty("test29.db").unwrap();
+        assert_eq!(9, dirty.snapshot().unwrap().last_id);
+        assert_eq!(ids(1, 10), dirty.search(b"ABCD").unwrap());
+        drop(dirty);
+
+        // a previous block not before its block ends the chain
+        let ngram = *BinacleFile::split_ngrams(b"ABCD").unwrap().iter().next().unwrap();
+        let list_off = db.ngram_list_ptr(ngram);
+        let (size, nb, nb_bytes, prev_off) = db.get_list_meta(list_off);
+        db.update_list_meta(list_off, size, nb, nb_bytes, list_off);
+        let dirty = BinacleFile::open_dirty("test29.db").unwrap();
+        assert_eq!(ids(1, 10), dirty.search(b"ABCD").unwrap());
+        drop(dirty);
+        db.update_list_meta(list_off, size, nb, nb_bytes, prev_off);
+
+        // the list moved to a block allocated after the snapshot
+        for id in 13 .. 81 {
+            db.insert_reader(&b"ABCD"[..], id).unwrap();
+        }
+        assert!(db.ngram_list_ptr(ngram) >= BinacleFile::open_dirty("test29.db").unwrap().snapshot().unwrap().size);
+        assert!(BinacleFile::open_dirty("test29.db").unwrap().search(b"ABCD").unwrap().is_empty());
+
+        // complete once flushed
+        db.flush().unwrap();
+        assert_eq!(ids(1, 81), BinacleFile::open_dirty("test29.db").unwrap().search(b"ABCD").unwrap());
+        assert_eq!(ids(1, 81), db.search(b"ABCD").unwrap());
+        drop(db);
+
+        let _ = remove_file("test29.db");
+        let _ = remove_file("test29.meta");
+        let _ = remove_file("test29.bitmap");
+    }
+
+    // searches of a shard while another thread inserts into it: each dirty
+    // read finds a part of what the shard finally has, never more
+    #[test]
+    fn dirty_read_while_written() {
+
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{mpsc, Arc};
+        use std::thread;
+
+        let content = |id: u32| {
+            let mut content = format!("file {} of the dirty read test", id).repeat(20).into_bytes();
+            if id % 3 == 0 {
+                content.extend_from_slice(b"a needle in the shard");
+            }
+            content
+        };
+        let patterns: [&[u8]; 3] = [b"needle", b"file 1", b"of the dirty"];
+
+        let done = Arc::new(AtomicBool::new(false));
+        let (created, ready) = mpsc::channel();
+        let writer = {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut db = BinacleFile::create("test30.db", 5, 6, 20).unwrap();
+                created.send(()).unwrap();
+                for id in 1 .. 301 {
+                    db.insert_reader(&content(id)[..], id).unwrap();
+                    if id % 25 == 0 {
+                        db.flush().unwrap();
+                    }
+                    // slow enough for reads between the flushes
+                    thread::sleep(Duration::from_millis(1));
+                }
+                db.flush().unwrap();
+                drop(db);
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+
+        ready.recv().unwrap();
+        let mut reads = Vec::new();
+        loop {
+            let finished = done.load(Ordering::SeqCst);
+            let dirty = BinacleFile::open_dirty("test30.db").unwrap();
+            let snapshot = dirty.snapshot().unwrap();
+            let found: Vec<HashSet<u32>> = patterns.iter().map(|p| dirty.search(p).unwrap()).collect();
+            for ids in &found {
+                assert!(ids.iter().all(|&id| id >= 1 && id <= snapshot.last_id));
+            }
+            reads.push((snapshot, found));
+            if finished {
+                break;
+            }
+        }
+        writer.join().unwrap();
+
+        let db = BinacleFile::open_read("test30.db").unwrap();
+        let complete: Vec<HashSet<u32>> = patterns.iter().map(|p| db.search(p).unwrap()).collect();
+        assert!((1 .. 301).filter(|id| id % 3 == 0).all(|id| complete[0].contains(&id)));
+        for window in reads.windows(2) {
+            assert!(window[0].0.last_id <= window[1].0.last_id);
+        }
+        for &(_, ref found) in &reads {
+            for (ids, all) in found.iter().zip(&complete) {
+                assert!(ids.is_subset(all));
+            }
+        }
+        assert!(reads.iter().any(|r| r.0.last_id > 0 && r.0.last_id < 300));
+        // the read after the writer closed the shard
+        assert_eq!(300, reads.last().unwrap().0.last_id);
+        assert_eq!(&complete, &reads.last().unwrap().1);
+        drop(db);
+
+        let _ = remove_file("test30.db");
+        let _ = remove_file("test30.meta");
+        let _ = remove_file("test30.bitmap");
+    }
+
+    #[test]
+    fn check_lists() {
+
+        use std::os::unix::fs::FileExt;
+
+        // the chain of 0x10 grows to two blocks
+        let (chain, single) = {
+            let mut db = BinacleFile::create("test32.db", 5, 6, 16).unwrap();
+            helper_insert(&mut db, 1, 0x10, 100);
+            helper_insert(&mut db, 300, 0x20, 3);
+            db.insert_ngram(100000, 0x20).unwrap();
+            db.flush().unwrap();
+            let check = db.check_lists();
+            assert!(check.is_sound(), "{:?}", check);
+            assert_eq!(2, check.lists);
+            assert!(check.blocks >= 3);
+            (db.ngram_list_ptr(0x10), db.ngram_list_ptr(0x20))
+        };
+        let write_at = |offset: u64, bytes: &[u8]| {
+            OpenOptions::new().write(true).open("test32.db").unwrap().write_all_at(bytes, offset).unwrap();
+        };
+        let problems = || {
+            let check = BinacleFile::open_read("test32.db").unwrap().check_lists();
+            assert_eq!(check.bad_lists as usize, check.problems.len());
+            check.problems
+        };
+        let original = fs::read("test32.db").unwrap();
+
+        // the oldest block of the chain points back to the newest one
+        let older = BinacleFile::open_read("test32.db").unwrap().get_list_meta(chain).3;
+        write_at(older + 5, &(chain >> 6).to_le_bytes()[.. 5]);
+        assert_eq!(vec![format!("n-gram 0x10: the chain loops back to the block at {}", chain)], problems());
+        write_at(0, &original);
+
+        // a header entry out of the file
+        write_at(0x20 * 5, &[0xff, 0xff, 0xff, 0, 0]);
+        assert!(problems()[0].starts_with("n-gram 0x20: block at 1073741760 out of the lists"));
+        write_at(0, &original);
+
+        // counts not matching the bytes, then the bytes not matching the ids
+        write_at(single + 1, &[1, 0]);
+        assert_eq!(vec![format!("n-gram 0x20: block at {}: nb_bytes 13 for nb_elem 1, 4 to 4 expected", single)], problems());
+        write_at(0, &original);
+        write_at(single + 10 + 4 + 3, &[0x01]);
+        assert!(problems()[0].ends_with("the differences take 4 of the 5 bytes between the first and the last id"), "{:?}", problems());
+        write_at(0, &original);
+        write_at(single + 10 + 13 - 4, &1234u32.to_le_bytes());
+        assert!(problems()[0].ends_with("last id 1234 stored, 100000 decoded"));
+        write_at(0, &original);
+        write_at(single, &[40]);
+        assert!(problems()[0].ends_with("size_log 40 out of 6 to 15"));
+        write_at(0, &original);
+        assert!(problems().is_empty());
+
+        // a meta whose header does not fit the file
+        fs::write("test32.db", &original[.. 1000]).unwrap();
+        let check = BinacleFile::open_read("test32.db").unwrap().check_lists();
+        assert!(!check.is_sound());
+        assert!(check.header.unwrap().starts_with("header of 327744 bytes for offset_size 5 and ngram_size 16, the file has 1000"));
+
+        let _ = remove_file("test32.db");
+        let _ = remove_file("test32.meta");
+        let _ = remove_file("test32.bitmap");
+    }
+
+    #[test]
+    fn compact() {
+
+        let postings = |db: &BinacleFile| db.iter_postings().collect::<Vec<(u32, Vec<u32>)>>();
+        let before = {
+            let mut db = BinacleFile::create("test33.db", 5, 6, 16).unwrap();
+            helper_insert(&mut db, 1, 0x10, 10000);
+            helper_insert(&mut db, 5, 0x20, 300);
+            helper_insert(&mut db, 7, 0x30, 2);
+            db.compact_ngram(0x20).unwrap();
+            db.flush().unwrap();
+            assert!(db.nb_free_blocks() > 0);
+            postings(&db)
+        };
+
+        let report = BinacleFile::open_write("test33.db").unwrap().compact().unwrap();
+        assert_eq!(3, report.lists);
+        assert!(report.blocks_before > 8);
+        assert_eq!(3, report.blocks_after);
+        assert!(report.size_after < report.size_before);
+        assert_eq!(report.size_before - report.size_after, report.reclaimed());
+        assert!(!PathBuf::from("test33.db.compact.tmp").exists());
+        assert!(!PathBuf::from("test33.db.compact.meta").exists());
+
+        let db = BinacleFile::open_read("test33.db").unwrap();
+        assert_eq!(before, postings(&db));
+        assert_eq!(report.size_after, db.file_size());
+        assert_eq!(0, db.nb_free_blocks());
+        let mut longest = 0;
+        db.for_each_chain(|_, len| longest = max(longest, len));
+        assert_eq!(1, longest);
+        assert!(db.check_lists().is_sound());
+        assert_eq!(BitmapCheck::InSync { slots: 3 }, db.check_bitmap());
+        drop(db);
+
+        // the insertions go on after it
+        let mut db = BinacleFile::open_write("test33.db").unwrap();
+        db.insert_ngram(20000, 0x10).unwrap();
+        assert_eq!(10001, db.get_ids_by_ngram(0x10).len());
+        drop(db);
+
+        let _ = remove_file("test33.db");
+        let _ = remove_file("test33.meta");
+        let _ = remove_file("test33.bitmap");
+    }
 
+    // a crash between the renames: the data is the new one, the meta the
+    // old one, the next open finishes them
+    #[test]
+    fn compact_interrupted() {
+
+        let postings = |db: &BinacleFile| db.iter_postings().collect::<Vec<(u32, Vec<u32>)>>();
+        let before = {
+            let mut db = BinacleFile::create("test36.db", 5, 6, 16).unwrap();
+            helper_insert(&mut db, 1, 0x10, 3000);
+            helper_insert(&mut db, 5, 0x20, 300);
+            postings(&db)
+        };
+        // the files of the compaction, made by the one of a copy
+        for (from, to) in BinacleFile::shard_files("test36.db").into_iter().zip(BinacleFile::shard_files("test37.db")) {
+            fs::copy(from, to).unwrap();
+        }
+        let report = BinacleFile::open_write("test37.db").unwrap().compact().unwrap();
+        for (from, to) in BinacleFile::shard_files("test37.db").into_iter().zip(BinacleFile::shard_files("test36.db.compact.tmp")) {
+            fs::rename(from, to).unwrap();
+        }
+
+        // not marked complete, the shard is as it was
+        let db = BinacleFile::open_read("test36.db").unwrap();
+        assert_eq!(report.size_before, db.file_size());
+        drop(db);
+
+        File::create("test36.db.compact.done").unwrap();
+        fs::rename("test36.db.compact.tmp", "test36.db").unwrap();
+        let db = BinacleFile::open_read("test36.db").unwrap();
+        assert_eq!(report.size_after, db.file_size());
+        assert_eq!(before, postings(&db));
+        assert!(db.check_lists().is_sound());
+        drop(db);
+        for path in BinacleFile::shard_files("test36.db.compact.tmp").into_iter().chain(vec![PathBuf::from("test36.db.compact.done")]) {
+            assert!(!path.exists(), "{}", path.display());
+        }
+
+        for path in BinacleFile::shard_files("test36.db") {
+            let _ = remove_file(path);
+        }
     }
 
 }
@@ -2,25 +2,267 @@ extern crate rustc_serialize;
 extern crate fs2;
 extern crate walkdir;
 
-use std::fs::{File, read_dir, metadata};
-use std::path::PathBuf;
+use std::fs::{File, read_dir, metadata, remove_file, create_dir, remove_dir_all, copy, rename};
+use std::path::{Path, PathBuf};
 use std::fs::OpenOptions;
-use self::fs2::FileExt;
+use self::fs2::{FileExt, lock_contended_error};
 use std::io::*;
 use std::ptr;
-use std::collections::{HashSet, HashMap};
+use std::borrow::Cow;
+use std::collections::{HashSet, HashMap, BTreeMap, BTreeSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, process};
 use rustc_serialize::json;
 use walkdir::WalkDir;
 
-use binacle::BinacleFile;
+use binacle::{self, BinacleFile, Compacted, FileIdentity};
+use hash::{self, HashReader};
+use search::{self, SearchRequest, SearchResponse};
+use magic::{MagicTable, HeadReader};
+use cache::{NegativeCache, CacheStats, NEGATIVE_CACHE_SIZE};
+use durability::{Durability, FlushEvent, SyncState, sync_path};
+use lazy_map::{self, MAP_MEMORY_FACTOR};
+use provenance::Provenance;
+use watchdog::{DeadlineReader, Quarantined, panic_message};
+use translate::{Translation, TranslateReader};
+use collection::Collections;
+use library::Library;
+use chains::DEFAULT_RECHAIN_THRESHOLD;
+use finalize::{self, ShardSummary};
+use reindex;
+use sidecar::Sidecars;
+use planner::{Plan, DEFAULT_PRESELECT};
+use lock::{self, LockStatus};
+use space::SpaceCheck;
+use compat::{self, Unknown};
+use dedup::DedupStats;
+use reads::ReadPath;
+use prefetch::{Prefetch, Next, DEFAULT_CHUNK_SIZE};
+use ingest_filter::{IngestFilter, FilteredRun};
+use occupancy;
+use dirty::Snapshot;
+
+
+// result of the insertion of one file
+#[derive(Debug)]
+pub enum InsertOutcome {
+    Inserted(u32),
+    Skipped(String),
+    Failed(Error),
+}
+
+impl InsertOutcome {
+    // the id inserted, a skipped file is an error
+    pub fn into_result(self) -> Result<u32> {
+        match self {
+            InsertOutcome::Inserted(id) => Ok(id),
+            InsertOutcome::Skipped(reason) => Err(Error::new(ErrorKind::Other, reason)),
+            InsertOutcome::Failed(e) => Err(e),
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        match *self {
+            InsertOutcome::Inserted(_) => "inserted",
+            InsertOutcome::Skipped(_) => "skipped",
+            InsertOutcome::Failed(_) => "failed",
+        }
+    }
+
+    // why the file was not inserted
+    pub fn error(&self) -> Option<String> {
+        match *self {
+            InsertOutcome::Inserted(_) => None,
+            InsertOutcome::Skipped(ref reason) => Some(reason.clone()),
+            InsertOutcome::Failed(ref e) => Some(e.to_string()),
+        }
+    }
+}
+
+// a file of an insertion of several files and what became of it
+#[derive(Debug)]
+pub struct FileInsertion {
+    pub id: u32,
+    pub path: String,
+    pub outcome: InsertOutcome,
+    // bytes of the file when its metadata was read before the insertion
+    pub size: Option<u64>,
+}
 
+// what insert_dir_with tells while it runs, the caller shows it
+pub enum InsertEvent<'a> {
+    // the files to insert and their bytes, listed before the first one,
+    // and the ones already in the map
+    Listed { files: usize, bytes: u64, known: usize },
+    // a file already in the map, skipped
+    Known(&'a FileInsertion),
+    // a file inserted, skipped or failed
+    Done(&'a FileInsertion),
+}
 
 // Used to maintain the Binacle Files
 pub struct BinacleManager {
 	pub db_path: String,
     cur_index: Option<(usize, BinacleFile)>,
 	meta: BinacleMeta,
-    map: Option<HashMap<u32, String>>,
+    map: Option<HashMap<u32, MapEntry>>,
+    // sha256 (hex) -> ids, for map databases
+    hashes: HashMap<String, Vec<u32>>,
+    // label -> id, built from the map
+    labels: HashMap<String, u32>,
+    // reproducible insertions: sorted walks, no wall-clock fields
+    deterministic: bool,
+    // content types recognized at insertion
+    magic: MagicTable,
+    // called before a shard is searched, tests use it to slow the search down
+    shard_hook: Option<Box<dyn Fn(usize) + Send>>,
+    // metadata files next to the samples of a recursive insertion
+    sidecars: Option<Sidecars>,
+    // n-grams absent from the full shards, by shard number
+    negative_cache: HashMap<usize, NegativeCache>,
+    // of the full shards when their cache was started, and the caches
+    // dropped since because the shard was replaced
+    shard_identity: HashMap<usize, FileIdentity>,
+    replaced_shards: u64,
+    // when the insertions are synced to the disk
+    durability: Durability,
+    sync_state: SyncState,
+    // told of each sync step, tests use it to count them
+    flush_hook: Option<Box<dyn Fn(FlushEvent) + Send>>,
+    // the map was too big to be loaded, only the entries resolved are
+    // in memory and the database is read only
+    lazy_map: bool,
+    // shards that failed during the last search
+    shard_warnings: Vec<ShardWarning>,
+    // modification time and size of the meta when it was read
+    meta_stamp: Option<(SystemTime, u64)>,
+    last_reload: Option<SystemTime>,
+    // time a file can take to be indexed
+    file_budget: Option<Duration>,
+    retry_quarantined: bool,
+    // the label of the file insert_labeled inserts, in its entry before the
+    // sync of the insertion
+    next_label: Option<String>,
+    // insert_dir_recursive inserts again the paths already in the map
+    reinsert_known: bool,
+    // the database file opened by create or open, not locked between two
+    // writes of the meta: a reader and a writer may share the database
+    file: File,
+    // the meta as it was last read or written, it is not written again
+    // when unchanged
+    meta_saved: String,
+    // fields of the meta written by a newer build, see compat.rs
+    meta_unknown: Unknown,
+    // time waited for the lock of the database before writing the meta
+    lock_wait: Duration,
+    // persisted by close(), nothing is left for the Drop
+    closed: bool,
+    // directory of a temporary database, removed with it
+    temporary: Option<PathBuf>,
+    finalize_on_full: bool,
+    preselect: usize,
+    // the hash of the shards with a checksum is checked before a search
+    verify_hashes: bool,
+    // how the shards opened for searching are read, see reads.rs
+    read_path: ReadPath,
+    // the free disk space checked between two files of an insertion
+    space_check: Option<SpaceCheck>,
+    // size of the chunks read ahead of the indexing, 0 for none, see prefetch.rs
+    prefetch_chunk: usize,
+    // the files of insert_dir_recursive kept by their time, owner and size
+    ingest_filter: Option<IngestFilter>,
+    // the shards not finalized are opened without their lock, see dirty.rs
+    dirty_read: bool,
+    // the ones opened so by the last open_shards
+    dirty_shards: Vec<(usize, Snapshot)>,
+}
+
+// time waited for the lock of the database by default
+pub const LOCK_WAIT: Duration = Duration::from_secs(10);
+
+// a shard that could not be searched, the others were
+#[derive(Clone, Debug)]
+pub struct ShardWarning {
+    pub shard: usize,
+    pub path: String,
+    pub error: String,
+}
+
+// what merge_from added
+#[derive(Clone, Debug, PartialEq)]
+pub struct Merge {
+    pub shards: usize,
+    pub files: u32,
+    // the first and the last id of the other database, shifted
+    pub ids: Option<(u32, u32)>,
+}
+
+// what a deletion changed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deletion {
+    // lists the id was dropped from, by shard
+    pub lists: Vec<u64>,
+    // only the lists of the n-grams of the file were read, not all of them
+    pub from_file: bool,
+    // the id had an entry in the map
+    pub mapped: bool,
+}
+
+// how much memory the map can use when the database is opened
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapPolicy {
+    // in bytes, the available memory when None
+    pub cap: Option<u64>,
+    // read the map lazily instead of failing when it is over the cap
+    pub lazy: bool,
+}
+
+// what the map knows about an id
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct MapEntry {
+    pub path: String,
+    pub sha256: Option<String>,
+    // insertion time, in seconds since the epoch
+    pub date: Option<u64>,
+    // user identifier, unique in the database
+    pub label: Option<String>,
+    // content type guessed at insertion (pe, elf, text, data...)
+    pub file_type: Option<String>,
+    // free user tags
+    pub tags: Option<Vec<String>>,
+    // transformations and regions not indexed
+    pub provenance: Option<Provenance>,
+    // left by the analysts, oldest first
+    pub notes: Option<Vec<Note>>,
+}
+
+// free text attached to an id, any line or character
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Note {
+    // in seconds since the epoch, None for a deterministic database
+    pub date: Option<u64>,
+    pub text: String,
+}
+
+impl MapEntry {
+    pub fn new(path: &str) -> MapEntry {
+        MapEntry {
+            path: String::from(path),
+            sha256: None,
+            date: None,
+            label: None,
+            file_type: None,
+            tags: None,
+            provenance: None,
+            notes: None,
+        }
+    }
+
+    pub fn has_note_containing(&self, substr: &str) -> bool {
+        self.notes.iter().flat_map(|n| n.iter()).any(|n| n.text.contains(substr))
+    }
 }
 
 #[derive(RustcDecodable, RustcEncodable)]
@@ -36,12 +278,44 @@ struct BinacleMeta {
     // size of a ngram
     ngram_size: u8, // in bits, in [12..32], 28 is OK.
 	index: Vec<BinacleIndex>,
+    // ids removed from the results, sorted
+    tombstones: Option<Vec<u32>>,
+    // files abandoned by the watchdog, skipped by the next insertions
+    quarantine: Option<Vec<Quarantined>>,
+    // applied to the contents and the patterns before the n-grams are formed
+    translate: Option<Translation>,
+    // finalized, checksummed and read only until it is unsealed
+    sealed: Option<bool>,
+    // the recursive insertions that left files out, see ingest_filter.rs
+    filtered: Option<Vec<FilteredRun>>,
 }
 
 #[derive(RustcDecodable, RustcEncodable, Clone)]
 struct BinacleIndex {
 	path: String,
 	is_full: bool,
+    // compacted and summarized once full (see finalize.rs)
+    finalized: Option<bool>,
+    // recorded when the shard stops changing: finalized, compacted or merged
+    checksum: Option<ShardChecksum>,
+}
+
+// size and sha256 of a shard that does not change anymore. A copy that
+// truncated or modified it is detected before it is searched: its size is
+// checked each time it is opened, its hash by verify_shards
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct ShardChecksum {
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl ShardChecksum {
+
+    pub fn of(path: &str) -> Result<ShardChecksum> {
+        let file = try!(OpenOptions::new().read(true).open(path));
+        let (sha256, size) = try!(HashReader::new(file).finish_len());
+        Ok(ShardChecksum { size: size, sha256: hash::to_hex(&sha256) })
+    }
 }
 
 
@@ -51,6 +325,15 @@ impl BinacleManager {
     // create a new manager, max_index_size should be 80% of the available RAM
 	pub fn create(path: &str, use_map: bool, max_index_size: u64, alignment: u8, ngram_size: u8) -> Result<BinacleManager> {
 
+        // a shard grows past max_index_size by the last file inserted, the
+        // insertion fails when it is over the limit
+        let addressable = binacle::addressable_size(5, alignment);
+        if max_index_size > addressable {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("max_index_size {} exceeds the maximum addressable size of a shard with offset_size 5 and alignment {} ({} bytes)",
+                        max_index_size, alignment, addressable)));
+        }
+
         let mut file = try!(OpenOptions::new()
                     .read(true)
                     .write(true)
@@ -58,6 +341,7 @@ impl BinacleManager {
                     .open(path));
 
         try!(file.lock_exclusive());
+        lock::acquired(path);
 
 		let meta = BinacleMeta {
             is_map: use_map,
@@ -68,6 +352,11 @@ impl BinacleManager {
             alignment: alignment,
             ngram_size: ngram_size,
 		    index: Vec::new(),
+            tombstones: None,
+            quarantine: None,
+            translate: None,
+            sealed: None,
+            filtered: None,
 		};
 
         let map = match use_map {
@@ -75,36 +364,247 @@ impl BinacleManager {
             false => None,
         };
 
-        BinacleManager::write_meta(&mut file, &meta);
+        let encoded = try!(BinacleManager::encode_meta(&meta, &Unknown::default()));
+        try!(BinacleManager::write_meta(&mut file, &encoded));
+        try!(file.unlock());
+        lock::released(path);
 
 		Ok(BinacleManager { 
             db_path: String::from(path),
             cur_index: None,
             meta: meta,
             map: map,
+            hashes: HashMap::new(),
+            labels: HashMap::new(),
+            deterministic: false,
+            magic: MagicTable::builtin(),
+            shard_hook: None,
+            sidecars: None,
+            negative_cache: HashMap::new(),
+            shard_identity: HashMap::new(),
+            replaced_shards: 0,
+            durability: Durability::None,
+            sync_state: SyncState::new(),
+            flush_hook: None,
+            lazy_map: false,
+            shard_warnings: Vec::new(),
+            meta_stamp: None,
+            last_reload: None,
+            file_budget: None,
+            retry_quarantined: false,
+            next_label: None,
+            reinsert_known: false,
+            file: file,
+            meta_saved: encoded,
+            meta_unknown: Unknown::default(),
+            lock_wait: LOCK_WAIT,
+            closed: false,
+            temporary: None,
+            finalize_on_full: true,
+            preselect: DEFAULT_PRESELECT,
+            verify_hashes: false,
+            read_path: ReadPath::Mmap,
+            space_check: None,
+            prefetch_chunk: DEFAULT_CHUNK_SIZE,
+            ingest_filter: None,
+            dirty_read: false,
+            dirty_shards: Vec::new(),
         })
 	}
 
+    // a throwaway database, in a directory of its own under the temporary
+    // directory: it is removed when the manager is dropped or closed, unless
+    // persist() copied it to a normal database before
+    pub fn create_in_memory(use_map: bool, max_index_size: u64, alignment: u8, ngram_size: u8) -> Result<BinacleManager> {
+
+        let dir = try!(BinacleManager::temporary_dir());
+        let path = dir.join("tmp.db");
+        match BinacleManager::create(path.to_str().unwrap(), use_map, max_index_size, alignment, ngram_size) {
+            Ok(mut db) => {
+                db.temporary = Some(dir);
+                Ok(db)
+            },
+            Err(e) => {
+                let _ = remove_dir_all(&dir);
+                Err(e)
+            },
+        }
+    }
+
+    fn temporary_dir() -> Result<PathBuf> {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let n = CREATED.fetch_add(1, Ordering::SeqCst);
+            let dir = env::temp_dir().join(format!("binacle-{}-{}", process::id(), n));
+            match create_dir(&dir) {
+                Ok(()) => return Ok(dir),
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn is_temporary(&self) -> bool {
+        self.temporary.is_some()
+    }
+
+    // copy the database to a new one at path: its shards, its map, its
+    // collections and its saved queries. The manager keeps using its own files
+    pub fn persist(&mut self, path: &str) -> Result<()> {
+
+        if metadata(path).is_ok() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", path)));
+        }
+        if self.lazy_map {
+            return Err(Error::new(ErrorKind::Other, "a map read lazily can not be copied"));
+        }
+        if let Some((_, ref mut db)) = self.cur_index {
+            try!(db.flush());
+        }
+
+        let (mut meta, unknown): (BinacleMeta, Unknown) =
+            try!(compat::decode(&try!(BinacleManager::encode_meta(&self.meta, &self.meta_unknown))));
+        for (n, index) in meta.index.iter_mut().enumerate() {
+            let dst = format!("{}_index{}.db", path, n);
+            try!(copy(&index.path, &dst));
+            let mut src_meta = PathBuf::from(&index.path);
+            src_meta.set_extension("meta");
+            let mut dst_meta = PathBuf::from(&dst);
+            dst_meta.set_extension("meta");
+            try!(copy(&src_meta, &dst_meta));
+            // written by the flush above, absent for the shards without one
+            if let Err(e) = copy(occupancy::bitmap_path(&index.path), occupancy::bitmap_path(&dst)) {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+            index.path = dst;
+        }
+        if self.meta.is_map {
+            try!(self.write_map_to(path));
+        }
+        try!(try!(Collections::open(&self.db_path)).copy_to(path));
+        try!(try!(Library::open(&self.db_path)).copy_to(path));
+
+        // the database exists once its meta is written
+        let mut file = try!(OpenOptions::new().write(true).create_new(true).open(path));
+        try!(BinacleManager::write_meta(&mut file, &try!(BinacleManager::encode_meta(&meta, &unknown))));
+        file.sync_all()
+    }
+
+    // the shard in use is closed first, it writes its meta when dropped
+    fn remove_temporary(&mut self) -> Result<()> {
+        match self.temporary.take() {
+            Some(dir) => {
+                self.cur_index = None;
+                remove_dir_all(&dir)
+            },
+            None => Ok(()),
+        }
+    }
+
     // open a BinacleManager File
 	pub fn open(path: &str) -> Result<BinacleManager> {
+        BinacleManager::open_with(path, MapPolicy::default())
+    }
 
-        let mut file = try!(OpenOptions::new()
-                    .read(true)
-                    .open(path));
+    // open with a limit on the memory used by the map
+    pub fn open_with(path: &str, policy: MapPolicy) -> Result<BinacleManager> {
 
-        try!(file.lock_exclusive());
+        // the errors name the file of the database or of its map
+        let named = |file: &Path, e: Error| Error::new(e.kind(), format!("{}: {}", file.display(), e));
+
+        // kept for the writes of the meta, read only when it can not be written
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Err(ref e) if e.kind() == ErrorKind::PermissionDenied => OpenOptions::new().read(true).open(path),
+            file => file,
+        };
+        let mut file = try!(file.map_err(|e| named(Path::new(path), e)));
+
+        // the meta is read under a shared lock, kept while a sealed
+        // database is open: it is not unsealed under its readers
+        try!(file.lock_shared());
+        let stamp = BinacleManager::meta_stamp(path);
+        let meta = BinacleManager::read_meta(&mut file);
+        if !meta.as_ref().map_or(false, |m| m.0.sealed == Some(true)) {
+            let _ = file.unlock();
+        }
+        let (meta, meta_unknown) = try!(meta.map_err(|e| named(Path::new(path), e)));
+        let meta_saved = try!(BinacleManager::encode_meta(&meta, &meta_unknown));
+
+        // the map must fit in memory, or be read lazily
+        let mut lazy_map = false;
+        if meta.is_map {
+            let mut map_path = PathBuf::from(&path);
+            map_path.set_extension("map");
+            let size = try!(metadata(&map_path).map_err(|e| named(&map_path, e))).len();
+            let needed = size * MAP_MEMORY_FACTOR;
+            if let Some(cap) = policy.cap.or_else(lazy_map::available_memory) {
+                if needed > cap && !policy.lazy {
+                    return Err(Error::new(ErrorKind::Other, format!(
+                        "the map ({} bytes) needs about {} bytes of memory, {} allowed: \
+                         open it with --lazy-map (slow, read only) or raise --map-cap", size, needed, cap)));
+                }
+                lazy_map = needed > cap;
+            }
+        }
 
-        let meta = try!(BinacleManager::read_meta(&mut file));
         let map = match meta.is_map {
             false => None,
-            true => Some(try!(BinacleManager::read_map(path))),
+            true if lazy_map => Some(HashMap::new()),
+            true => Some(try!(BinacleManager::read_map(path).map_err(|e| named(&Path::new(path).with_extension("map"), e)))),
         }; 
 
+        let hashes = match map {
+            Some(ref map) if !lazy_map => try!(BinacleManager::read_hashes(path, map)),
+            _ => HashMap::new(),
+        };
+
+        let labels = match map {
+            Some(ref map) => BinacleManager::build_labels(map),
+            None => HashMap::new(),
+        };
+
         Ok(BinacleManager {
             db_path: String::from(path),
             cur_index: None,
             meta: meta,
             map: map,
+            hashes: hashes,
+            labels: labels,
+            deterministic: false,
+            magic: MagicTable::builtin(),
+            shard_hook: None,
+            sidecars: None,
+            negative_cache: HashMap::new(),
+            shard_identity: HashMap::new(),
+            replaced_shards: 0,
+            durability: Durability::None,
+            sync_state: SyncState::new(),
+            flush_hook: None,
+            lazy_map: lazy_map,
+            shard_warnings: Vec::new(),
+            meta_stamp: stamp,
+            last_reload: None,
+            file_budget: None,
+            retry_quarantined: false,
+            next_label: None,
+            reinsert_known: false,
+            file: file,
+            meta_saved: meta_saved,
+            meta_unknown: meta_unknown,
+            lock_wait: LOCK_WAIT,
+            closed: false,
+            temporary: None,
+            finalize_on_full: true,
+            preselect: DEFAULT_PRESELECT,
+            verify_hashes: false,
+            read_path: ReadPath::Mmap,
+            space_check: None,
+            prefetch_chunk: DEFAULT_CHUNK_SIZE,
+            ingest_filter: None,
+            dirty_read: false,
+            dirty_shards: Vec::new(),
         })
     }
 
@@ -112,59 +612,258 @@ impl BinacleManager {
     // do NOT use this when you want to insert several files
     pub fn insert_file(&mut self, filepath: &str, id: u32, update_map: bool) -> Result<u32> {
 
-        match self.cur_index {
-            Some((i, ref mut db)) if !self.meta.index[i].is_full => {
-                try!(db.insert_file(filepath, id));
-                
-                self.meta.nb_file += 1;
-                self.meta.last_id = id;
+        self.insert(filepath, id, update_map).into_result()
+    }
 
-                if db.get_size() > self.meta.max_index_size {
-                    self.meta.index[i].is_full = true;
-                    db.fix_size();
-                }
+    // insert one file under the id after the last one, returned
+    pub fn insert_file_auto(&mut self, filepath: &str) -> Result<u32> {
 
-                if self.meta.is_map {
-                    if let Some(ref mut h) = self.map {
-                        h.insert(id, String::from(filepath));
-                    }
-                };
-            },
+        let id = try!(self.next_id());
+        self.insert_file(filepath, id, true)
+    }
 
-            Some(_) | None => {
-                try!(self.set_cur_index());
-                try!(self.insert_file(filepath, id, update_map));
+    // the id after the last one inserted, given up or absorbed
+    pub fn next_id(&self) -> Result<u32> {
+        self.meta.last_id.checked_add(1).ok_or_else(|| {
+            Error::new(ErrorKind::Other, format!("no id left after {}", self.meta.last_id))
+        })
+    }
+
+    // insert one file, and tell if it was inserted, skipped or failed
+    pub fn insert(&mut self, filepath: &str, id: u32, update_map: bool) -> InsertOutcome {
+
+        let mut files = self.prefetch_files(vec![String::from(filepath)]);
+        self.insert_prefetched(&mut files, filepath, id, update_map)
+    }
+
+    // the files of an insertion, read ahead of their indexing in this order
+    pub fn prefetch_files(&self, paths: Vec<String>) -> Prefetch {
+        Prefetch::start(paths, self.prefetch_chunk)
+    }
+
+    // insert the next file of a prefetch, filepath is its path
+    pub fn insert_prefetched(&mut self, files: &mut Prefetch, filepath: &str, id: u32, update_map: bool) -> InsertOutcome {
+
+        // taken even when quarantined, the next file is the next path
+        let file = match files.next() {
+            Next::File(file) => file,
+            Next::Skipped(reason) => return InsertOutcome::Skipped(reason),
+            Next::Failed(e) => return InsertOutcome::Failed(e),
+        };
+
+        if !self.retry_quarantined {
+            if let Some(q) = self.quarantined(filepath) {
+                return InsertOutcome::Skipped(format!("quarantined: {}", q.reason));
+            }
+        }
+
+        self.insert_reader(file, filepath, id, update_map)
+    }
+
+    // insert one file with a label, refused if the label is already used
+    pub fn insert_labeled(&mut self, filepath: &str, id: u32, label: &str, update_map: bool) -> InsertOutcome {
+
+        if let Some(other) = self.id_of_label(label) {
+            return InsertOutcome::Failed(BinacleManager::label_conflict(label, other));
+        }
+        if let Err(e) = BinacleManager::check_label(label) {
+            return InsertOutcome::Failed(e);
+        }
+        if !self.meta.is_map {
+            return InsertOutcome::Failed(Error::new(ErrorKind::Other, "labels need a map database"));
+        }
+
+        // synced once, with the entry
+        self.next_label = Some(String::from(label));
+        let outcome = self.insert(filepath, id, update_map);
+        self.next_label = None;
+        outcome
+    }
+
+    // insert the content of a reader under the given id and path
+    // counters and map are left untouched if the index insertion or its sync failed
+    pub fn insert_reader<R: Read>(&mut self, reader: R, filepath: &str, id: u32, update_map: bool) -> InsertOutcome {
+        self.insert_checked(reader, filepath, id, None, update_map)
+    }
+
+    // insert a file only if its content has the expected sha256
+    // on a mismatch the id is tombstoned (its n-grams are already in the
+    // index) and the error kind is InvalidData
+    pub fn insert_verified(&mut self, filepath: &str, id: u32, sha256: &[u8; 32], update_map: bool) -> InsertOutcome {
+
+        match OpenOptions::new().read(true).open(filepath) {
+            Ok(file) => self.insert_checked(file, filepath, id, Some(sha256), update_map),
+            Err(e) => InsertOutcome::Failed(e),
+        }
+    }
+
+    fn insert_checked<R: Read>(&mut self, reader: R, filepath: &str, id: u32,
+                               expected: Option<&[u8; 32]>, update_map: bool) -> InsertOutcome {
+
+        if let Err(e) = self.check_writable() {
+            return InsertOutcome::Failed(e);
+        }
+
+        // the content is hashed and its type guessed while it is indexed
+        let mut reader = HashReader::new(HeadReader::new(DeadlineReader::new(reader, self.file_budget)));
+
+        // a panic is confined to the file, the index may keep some of its
+        // n-grams so the id is tombstoned like for a timeout
+        let mut provenance = Provenance::default();
+        let translation = self.meta.translate.clone();
+        let indexed = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.insert_index(TranslateReader::new(&mut reader, translation.as_ref()), id)
+        }));
+        let res = match indexed {
+            Ok(res) => res,
+            Err(payload) => {
+                let e = Error::new(ErrorKind::Other, format!("extraction panicked: {}", panic_message(&payload)));
+                self.quarantine_file(filepath, id, &e);
+                return InsertOutcome::Failed(e);
+            },
+        };
+        match res {
+            Ok(dedup) => provenance.set_dedup(dedup),
+            Err(e) => {
+                if e.kind() == ErrorKind::TimedOut {
+                    self.quarantine_file(filepath, id, &e);
+                }
+                return InsertOutcome::Failed(e);
+            },
+        }
+        let file_type = String::from(self.magic.detect(reader.get_ref().head()));
+        let sha256 = match reader.finish_len() {
+            Ok((h, size)) => {
+                provenance.set_size(size);
+                h
+            },
+            Err(e) => {
+                if e.kind() == ErrorKind::TimedOut {
+                    self.quarantine_file(filepath, id, &e);
+                }
+                return InsertOutcome::Failed(e);
             },
         };
 
-        if self.meta.is_map && update_map {
-            self.write_map();
+        if let Some(expected) = expected {
+            if *expected != sha256 {
+                self.tombstone(id);
+                if id > self.meta.last_id {
+                    self.meta.last_id = id;
+                }
+                return InsertOutcome::Failed(Error::new(ErrorKind::InvalidData,
+                    format!("sha256 mismatch: expected {}, got {}", hash::to_hex(expected), hash::to_hex(&sha256))));
+            }
+        }
+        let sha256 = hash::to_hex(&sha256);
+
+        // counted from now on, undone below if the sync fails
+        self.meta.nb_file += 1;
+        self.meta.last_id = id;
+        if self.retry_quarantined {
+            if let Some(ref mut q) = self.meta.quarantine {
+                q.retain(|q| q.path != filepath);
+            }
+        }
+
+        if self.meta.is_map {
+            if let Some(ref mut h) = self.map {
+                let date = match self.deterministic {
+                    true => None,
+                    false => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok(),
+                };
+                let mut entry = MapEntry::new(filepath);
+                entry.sha256 = Some(sha256.clone());
+                entry.date = date;
+                entry.file_type = Some(file_type);
+                entry.provenance = Some(provenance);
+                entry.label = self.next_label.take();
+                if let Some(ref label) = entry.label {
+                    self.labels.insert(label.clone(), id);
+                }
+                h.insert(id, entry);
+            }
+            let ids = self.hashes.entry(sha256).or_insert(Vec::new());
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        let written = match self.sync_state.inserted(self.durability) {
+            true => self.sync(),
+            false if self.meta.is_map && update_map => self.write_map(),
+            false => Ok(()),
+        };
+        if let Err(e) = written {
+            // indexed but maybe not on the disk, the caller must not rely on
+            // it: not counted nor in the map, and its id is tombstoned (its
+            // n-grams are in the index) and not given again
+            self.meta.nb_file -= 1;
+            self.remove_entry(id);
+            self.tombstone(id);
+            return InsertOutcome::Failed(e);
         }
-        
-        Ok(0)
+
+        InsertOutcome::Inserted(id)
     }
 
-    // insert several files in the database
-    pub fn insert_files(&mut self, files: Vec<(u32, &str)>) -> Result<()> {
+    // insert several files in the database, a file that can not be
+    // inserted does not stop the next ones
+    pub fn insert_files(&mut self, files: Vec<(u32, &str)>) -> Result<Vec<FileInsertion>> {
     
+        try!(self.check_writable());
+        let mut prefetch = self.prefetch_files(files.iter().map(|f| String::from(f.1)).collect());
+        let mut inserted = Vec::with_capacity(files.len());
         for file in &files {
-            let _ = try!(self.insert_file(file.1, file.0, false));
+            inserted.push(FileInsertion {
+                id: file.0,
+                path: String::from(file.1),
+                outcome: self.insert_prefetched(&mut prefetch, file.1, file.0, false),
+                size: None,
+            });
         }
 
-        if self.meta.is_map {
-            self.write_map();
-        }
-        
-        Ok(())
+        try!(self.commit());
+        Ok(inserted)
     }
 
-    // insert all files in a directory, recursively
-    pub fn insert_dir_recursive(&mut self, dir: &str) -> Result<()> {
+    // insert all files in a directory, recursively. In a map database the
+    // paths already there are skipped under their id, an interrupted
+    // insertion run again goes on with the files it had not reached
+    pub fn insert_dir_recursive(&mut self, dir: &str) -> Result<Vec<FileInsertion>> {
+        self.insert_dir_with(dir, |_| {})
+    }
+
+    // same, each step told to report as it happens
+    pub fn insert_dir_with<F: FnMut(InsertEvent)>(&mut self, dir: &str, mut report: F) -> Result<Vec<FileInsertion>> {
 
+        try!(self.check_writable());
         let _ = try!(read_dir(dir));
         let mut id = self.meta.last_id + 1;
 
-        for entry in WalkDir::new(dir) {
+        // built once, a lookup a file
+        let known: HashMap<String, u32> = match self.reinsert_known {
+            true => HashMap::new(),
+            false => self.entries()
+                         .filter(|&(id, _)| !self.is_tombstoned(id))
+                         .map(|(id, e)| (e.path.clone(), id))
+                         .collect(),
+        };
+
+        let mut walker = WalkDir::new(dir);
+        if self.deterministic {
+            walker = walker.sort_by_file_name();
+        }
+
+        // listed first, the files are read ahead while the previous ones
+        // are indexed
+        let mut files = Vec::new();
+        let mut sizes = Vec::new();
+        // the id of the files already in the map
+        let mut indexed = Vec::new();
+        let mut excluded = 0;
+        let mut excluded_bytes = 0;
+        for entry in walker {
             
             let entry = match entry {
                 Ok(e) => e,
@@ -181,60 +880,226 @@ impl BinacleManager {
                 continue;
             }
 
-            let size = meta.len();
-
             match p.to_str() {
                 Some(file) => {
-                    let _ = self.insert_file(file, id, false);
-                    id += 1;                
+                    if self.sidecars.as_ref().map_or(false, |s| s.is_sidecar(file)) {
+                        continue;
+                    }
+                    if self.ingest_filter.as_ref().map_or(false, |f| f.excludes(&meta).is_some()) {
+                        excluded += 1;
+                        excluded_bytes += meta.len();
+                        continue;
+                    }
+                    indexed.push(known.get(file).cloned());
+                    files.push(String::from(file));
+                    sizes.push(meta.len());
                 },
                 None => continue,
             }
+        }
+
+        // recorded before the files, an exclusion is explained even when
+        // the insertion stops on the space
+        if let Some(ref filter) = self.ingest_filter {
+            let date = match self.deterministic {
+                true => None,
+                false => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok(),
+            };
+            self.meta.filtered.get_or_insert(Vec::new()).push(FilteredRun {
+                dir: String::from(dir),
+                filter: filter.clone(),
+                excluded: excluded,
+                excluded_bytes: Some(excluded_bytes),
+                date: date,
+            });
+        }
+
+        let new_files = indexed.iter().filter(|known| known.is_none()).count();
+        report(InsertEvent::Listed {
+            files: new_files,
+            bytes: sizes.iter().zip(&indexed).filter(|&(_, known)| known.is_none()).map(|(size, _)| *size).sum(),
+            known: files.len() - new_files,
+        });
+
+        // only the files to insert are read ahead
+        let mut prefetch = self.prefetch_files(files.iter().zip(&indexed).filter(|f| f.1.is_none()).map(|f| f.0.clone()).collect());
+        let mut inserted = Vec::with_capacity(files.len());
+        for ((file, size), known) in files.iter().zip(sizes).zip(indexed) {
+
+            if let Some(known) = known {
+                let outcome = InsertOutcome::Skipped(format!("already indexed as id {}", known));
+                inserted.push(FileInsertion { id: known, path: file.clone(), outcome: outcome, size: Some(size) });
+                report(InsertEvent::Known(&inserted[inserted.len() - 1]));
+                continue;
+            }
 
-            if id % 100 == 0 {
-                println!("Inserting file {} (size {}) {:?}", id, size, p);
+            // stopped cleanly, the files inserted are kept
+            if let Err(e) = self.check_space() {
+                try!(self.commit());
+                return Err(Error::new(e.kind(), format!("{}: {:?} and the next files not inserted", e, file)));
             }
+
+            let outcome = self.insert_prefetched(&mut prefetch, file, id, false);
+            // a skipped file leaves its id to the next one, a failed one
+            // keeps it: some of its n-grams may be in the index
+            let next = match outcome {
+                InsertOutcome::Inserted(_) => {
+                    self.import_sidecar(file, id);
+                    id + 1
+                },
+                InsertOutcome::Skipped(_) => id,
+                InsertOutcome::Failed(_) => id + 1,
+            };
+            inserted.push(FileInsertion { id: id, path: file.clone(), outcome: outcome, size: Some(size) });
+            report(InsertEvent::Done(&inserted[inserted.len() - 1]));
+            id = next;
         }
 
-        if self.meta.is_map {
-            self.write_map();
+        try!(self.commit());
+        Ok(inserted)
+    }
+
+    // the files of the next insert_dir_recursive kept by their time, owner
+    // and size, the insertion is recorded with the files excluded
+    pub fn set_ingest_filter(&mut self, filter: Option<IngestFilter>) {
+        self.ingest_filter = filter.filter(|f| !f.is_empty());
+    }
+
+    // the recursive insertions with filters, the oldest first
+    pub fn filtered_runs(&self) -> &[FilteredRun] {
+        match self.meta.filtered {
+            Some(ref runs) => runs,
+            None => &[],
         }
+    }
 
-        Ok(())
+    // read the files of an insertion ahead of their indexing in chunks of
+    // this size, the default; 0 reads each file when it is indexed
+    pub fn set_prefetch(&mut self, chunk_size: usize) {
+        self.prefetch_chunk = chunk_size;
+    }
+
+    // the metadata next to the files of insert_dir_recursive, see sidecar.rs
+    pub fn set_sidecars(&mut self, sidecars: Option<Sidecars>) {
+        self.sidecars = sidecars;
+    }
+
+    // the tags of the sidecar of a file just inserted, a sidecar that can
+    // not be read is reported and the file kept without them
+    fn import_sidecar(&mut self, file: &str, id: u32) {
+        let res = match self.sidecars {
+            Some(ref s) if self.meta.is_map => s.tags_of(file),
+            _ => return,
+        };
+        match res {
+            Ok(Some(tags)) => {
+                let _ = self.set_tags(id, tags);
+            },
+            Ok(None) => {},
+            Err(e) => eprintln!("Warning: metadata of {} not imported: {}", file, e),
+        }
     }
 
     // search all files that match the pattern
     pub fn search(&mut self, pattern: &[u8]) -> Result<HashSet<u32>> {
 
-        // close the cur_index in order to open all index in read only
-        self.cur_index = None;
+        // refused by every shard, the request and not the shards is wrong
+        if pattern.len() < 4 {
+            return Err(binacle::short_pattern());
+        }
 
-        // search on all index and make the union
-        let mut set_ids = HashSet::new();
+        // open all index in read only
+        let shards = self.open_shards();
 
-        for index in &self.meta.index {
+        // search on all index and make the union, a damaged shard
+        // is reported and the others are still searched
+        let mut set_ids = HashSet::new();
+        self.shard_warnings.clear();
 
-            let db = try!(BinacleFile::open_read(&index.path));
-            let ids = try!(db.search(pattern));
-            set_ids.extend(ids);
+        for (n, db) in shards.iter().enumerate() {
+            let res = match *db {
+                Ok(ref db) => self.search_shard(n, db, pattern),
+                Err(ref e) => Err(Error::new(e.kind(), e.to_string())),
+            };
+            match res {
+                Ok(ids) => set_ids.extend(ids),
+                Err(e) if e.kind() == ErrorKind::InvalidInput => return Err(e),
+                Err(e) => {
+                    let warning = self.shard_warning(n, &e);
+                    self.shard_warnings.push(warning);
+                },
+            }
         }
 
+        set_ids.retain(|id| !self.is_tombstoned(*id));
         Ok(set_ids)
     }
 
+    // the ids holding at least one of the patterns, each one with the
+    // indexes of the patterns it holds, ascending. A shard that can not be
+    // searched is reported as by search
+    pub fn search_any(&mut self, patterns: &[Vec<u8>]) -> Result<HashMap<u32, Vec<usize>>> {
+
+        if let Some(i) = patterns.iter().position(|p| p.len() < 4) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("pattern {} has {} byte(s), a pattern has at least 4", i, patterns[i].len())));
+        }
+
+        let shards = self.open_shards();
+        let mut found: HashMap<u32, Vec<usize>> = HashMap::new();
+        self.shard_warnings.clear();
+
+        for (n, db) in shards.iter().enumerate() {
+            let db = match *db {
+                Ok(ref db) => db,
+                Err(ref e) => {
+                    let warning = self.shard_warning(n, e);
+                    self.shard_warnings.push(warning);
+                    continue;
+                },
+            };
+            // the ids of a shard are kept once it is searched whole
+            let mut in_shard = Vec::with_capacity(patterns.len());
+            let mut failed = None;
+            for (i, pattern) in patterns.iter().enumerate() {
+                match self.search_shard(n, db, pattern) {
+                    Ok(ids) => in_shard.push((i, ids)),
+                    Err(e) => { failed = Some(e); break; },
+                }
+            }
+            if let Some(e) = failed {
+                let warning = self.shard_warning(n, &e);
+                self.shard_warnings.push(warning);
+                continue;
+            }
+            for (i, ids) in in_shard {
+                for id in ids {
+                    found.entry(id).or_insert(Vec::new()).push(i);
+                }
+            }
+        }
+
+        found.retain(|id, _| !self.is_tombstoned(*id));
+        for indexes in found.values_mut() {
+            indexes.sort();
+            indexes.dedup();
+        }
+        Ok(found)
+    }
+
     pub fn search_multi(&mut self, patterns: & [Vec<u8>]) -> Result<HashSet<u32>> {
 
         // transform all patterns in a set of ngrams
         let mut ngram_set = HashSet::new();
         for p in patterns {
 
+            let p = self.normalize(p);
             if p.len() < 4 {
-                return Err(Error::new(ErrorKind::Other, "pattern size is < 4"));
+                return Err(binacle::short_pattern());
             }
 
             for i in 0 .. p.len()-3 {
                 let ptr_read = (&p).as_ptr() as u64 + i as u64;
-                let ngram: u32 = unsafe { ptr::read(ptr_read as *const u32)};
+                let ngram: u32 = unsafe { ptr::read_unaligned(ptr_read as *const u32)};
                 ngram_set.insert(ngram);
             }
         }
@@ -251,53 +1116,1180 @@ impl BinacleManager {
             let ids = try!(db.search_ngrams(&ngram_set));
             set_ids.extend(ids);
         }
+        set_ids.retain(|id| !self.is_tombstoned(*id));
         Ok(set_ids)
     }
 
-    pub fn to_map(&self, ids: &HashSet<u32>) -> Result<Vec<String>> {
+    // every id present in the indexes, tombstoned ones included
+    // slow: walks all the posting lists
+    pub fn index_ids(&mut self) -> Result<HashSet<u32>> {
 
-        assert!(self.meta.is_map);
-        let mut res = Vec::with_capacity(ids.len());
+        self.cur_index = None;
 
-        if let Some(ref map) = self.map {
-            for id in ids {
-                match map.get(id) {
-                    None => panic!("Not found in map"),
-                    Some(file) => res.push(file.to_owned()),
-                }
-            }
+        let mut set_ids = HashSet::new();
+        for index in &self.meta.index {
+            let db = try!(BinacleFile::open_read(&index.path));
+            set_ids.extend(db.all_ids());
         }
-        Ok(res)
+        Ok(set_ids)
     }
 
-    pub fn is_map(&self) -> bool {
-        return self.meta.is_map;
+    // hide an id from every search, the index itself is not modified
+    pub fn tombstone(&mut self, id: u32) {
+        let tombstones = self.meta.tombstones.get_or_insert(Vec::new());
+        if let Err(pos) = tombstones.binary_search(&id) {
+            tombstones.insert(pos, id);
+        }
     }
 
-    /*********************/
-    /*  Private methods  */
-    /*********************/
+    pub fn set_file_budget(&mut self, budget: Option<Duration>) {
+        self.file_budget = budget;
+    }
 
-    fn set_cur_index(&mut self) -> Result<()> {
+    // insert again the quarantined files instead of skipping them
+    pub fn set_retry_quarantined(&mut self, retry: bool) {
+        self.retry_quarantined = retry;
+    }
 
-        let free_index = self.meta.index.iter().cloned().enumerate().find(|x| !x.1.is_full);
+    // insert_dir_recursive inserts again the paths already in the map,
+    // under new ids, instead of skipping them
+    pub fn set_reinsert_known(&mut self, reinsert: bool) {
+        self.reinsert_known = reinsert;
+    }
 
-        match free_index {
-            Some((i, index)) => {
-                self.cur_index = Some((i, try!(BinacleFile::open_write(&index.path))));
-            },
-            None => {
-                try!(self.add_index());
-            },
-        };
-        Ok(())
+    pub fn quarantine(&self) -> &[Quarantined] {
+        match self.meta.quarantine {
+            Some(ref q) => q,
+            None => &[],
+        }
     }
 
-    fn add_index(&mut self) -> Result<()> {
+    pub fn quarantined(&self, path: &str) -> Option<&Quarantined> {
+        self.quarantine().iter().find(|q| q.path == path)
+    }
 
-        let offset_size = self.meta.offset_size;
-        let alignment = self.meta.alignment;
-        let ngram_size = self.meta.ngram_size;
+    pub fn is_tombstoned(&self, id: u32) -> bool {
+        match self.meta.tombstones {
+            Some(ref t) => t.binary_search(&id).is_ok(),
+            None => false,
+        }
+    }
+
+    // all the map entries, for map databases
+    pub fn entries<'a>(&'a self) -> impl Iterator<Item=(u32, &'a MapEntry)> + 'a {
+        self.map.iter().flat_map(|map| map.iter()).map(|(id, e)| (*id, e))
+    }
+
+    // each entry of the map by ascending id, until f fails: only the ids
+    // are sorted aside. A map read lazily is streamed from its file, in the
+    // order of the file
+    pub fn for_each_entry<F: FnMut(u32, &MapEntry) -> Result<()>>(&self, mut f: F) -> Result<()> {
+
+        let map = match self.map {
+            Some(ref map) => map,
+            None => return Ok(()),
+        };
+        if self.lazy_map {
+            let mut failed = None;
+            try!(lazy_map::scan(self.map_path().to_str().unwrap(), |_| true, |id, entry| {
+                if failed.is_none() {
+                    failed = f(id, &entry).err();
+                }
+            }));
+            return failed.map_or(Ok(()), Err);
+        }
+
+        let mut ids: Vec<u32> = map.keys().cloned().collect();
+        ids.sort();
+        for id in ids {
+            try!(f(id, &map[&id]));
+        }
+        Ok(())
+    }
+
+    // give a unique label to an id of the map
+    pub fn set_label(&mut self, id: u32, label: &str) -> Result<()> {
+
+        try!(self.check_writable());
+        try!(BinacleManager::check_label(label));
+
+        if let Some(other) = self.id_of_label(label) {
+            if other != id {
+                return Err(BinacleManager::label_conflict(label, other));
+            }
+        }
+
+        let old = match self.map.as_mut().and_then(|m| m.get_mut(&id)) {
+            Some(entry) => entry.label.replace(String::from(label)),
+            None => return Err(Error::new(ErrorKind::NotFound, format!("id {} not found in map", id))),
+        };
+
+        if let Some(old) = old {
+            self.labels.remove(&old);
+        }
+        self.labels.insert(String::from(label), id);
+        Ok(())
+    }
+
+    pub fn set_tags(&mut self, id: u32, tags: Vec<String>) -> Result<()> {
+        try!(self.check_writable());
+        match self.map.as_mut().and_then(|m| m.get_mut(&id)) {
+            Some(entry) => {
+                entry.tags = if tags.is_empty() { None } else { Some(tags) };
+                Ok(())
+            },
+            None => Err(Error::new(ErrorKind::NotFound, format!("id {} not found in map", id))),
+        }
+    }
+
+    // append a note to the entry of an id, the map is written by flush_map
+    pub fn add_note(&mut self, id: u32, text: &str) -> Result<()> {
+
+        try!(self.check_writable());
+        let date = match self.deterministic {
+            true => None,
+            false => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok(),
+        };
+        match self.map.as_mut().and_then(|m| m.get_mut(&id)) {
+            Some(entry) => {
+                entry.notes.get_or_insert(Vec::new()).push(Note { date: date, text: String::from(text) });
+                Ok(())
+            },
+            None => Err(Error::new(ErrorKind::NotFound, format!("id {} not found in map", id))),
+        }
+    }
+
+    pub fn id_of_label(&self, label: &str) -> Option<u32> {
+        self.labels.get(label).cloned()
+    }
+
+    // id given either as a label or as a number, labels are tried first
+    pub fn resolve_id(&self, id_or_label: &str) -> Option<u32> {
+        match self.id_of_label(id_or_label) {
+            Some(id) => Some(id),
+            None => id_or_label.parse::<u32>().ok(),
+        }
+    }
+
+    // drop an id from the map (and the hash and label indexes)
+    pub fn remove_entry(&mut self, id: u32) -> Option<MapEntry> {
+
+        let entry = match self.map {
+            Some(ref mut map) => map.remove(&id),
+            None => None,
+        };
+
+        if let Some(MapEntry { label: Some(ref l), .. }) = entry {
+            self.labels.remove(l);
+        }
+
+        if let Some(MapEntry { sha256: Some(ref h), .. }) = entry {
+            let empty = match self.hashes.get_mut(h) {
+                Some(ids) => {
+                    ids.retain(|x| *x != id);
+                    ids.is_empty()
+                },
+                None => false,
+            };
+            if empty {
+                self.hashes.remove(h);
+            }
+        }
+        entry
+    }
+
+    // take a file out of the database: the id is dropped from the lists of
+    // every shard, from the map, and the count of files. Only the lists of
+    // the n-grams of the file are rewritten when it is still at its path
+    // with the content it was inserted with, all the lists otherwise
+    pub fn delete(&mut self, id: u32) -> Result<Deletion> {
+
+        try!(self.check_writable());
+        self.cur_index = None;
+
+        let ngrams = self.indexed_ngrams(id);
+        let mut deletion = Deletion { lists: Vec::new(), from_file: ngrams.is_some(), mapped: false };
+        for n in 0 .. self.meta.index.len() {
+            let mut shard = try!(self.open_shard_write(n));
+            let changed = try!(match ngrams {
+                Some(ref ngrams) => shard.remove_id_from(id, ngrams),
+                None => shard.remove_id(id),
+            });
+            if changed > 0 {
+                try!(shard.flush());
+                drop(shard);
+                if self.meta.index[n].checksum.is_some() {
+                    try!(self.record_checksum(n));
+                }
+            }
+            deletion.lists.push(changed);
+        }
+
+        deletion.mapped = self.remove_entry(id).is_some();
+        if !deletion.mapped && deletion.lists.iter().all(|n| *n == 0) {
+            return Err(Error::new(ErrorKind::NotFound, format!("id {} is not in the database", id)));
+        }
+        // an id tombstoned by the insertion (a mismatch, a failed sync, a
+        // quarantine) has no entry and was never counted
+        if deletion.mapped || !self.is_tombstoned(id) {
+            self.meta.nb_file = self.meta.nb_file.saturating_sub(1);
+        }
+        // not hidden anymore, it is gone
+        if let Some(ref mut tombstones) = self.meta.tombstones {
+            tombstones.retain(|t| *t != id);
+        }
+        try!(self.flush_map());
+        try!(self.store_meta());
+        Ok(deletion)
+    }
+
+    // the n-grams of the file of an id, read again when its path still has
+    // the content whose sha256 was recorded
+    fn indexed_ngrams(&self, id: u32) -> Option<HashSet<u32>> {
+
+        let (path, sha256) = match self.entry(id) {
+            Some(&MapEntry { ref path, sha256: Some(ref sha256), .. }) => (path.clone(), sha256.clone()),
+            _ => return None,
+        };
+        let file = match OpenOptions::new().read(true).open(&path) {
+            Ok(file) => file,
+            Err(_) => return None,
+        };
+        let mut reader = HashReader::new(file);
+        let mut ngrams = HashSet::new();
+        let mask = ((1u64 << self.meta.ngram_size) - 1) as u32;
+        let read = binacle::read_ngrams(TranslateReader::new(&mut reader, self.meta.translate.as_ref()), |ngram| {
+            ngrams.insert(ngram & mask);
+            Ok(())
+        });
+        match read.and_then(|_| reader.finish()) {
+            Ok(ref hash) if hash::to_hex(hash) == sha256 => Some(ngrams),
+            _ => None,
+        }
+    }
+
+    // the map of a sealed database is not written again
+    pub fn flush_map(&self) -> Result<()> {
+        match self.meta.is_map && !self.is_sealed() {
+            true => self.write_map(),
+            false => Ok(()),
+        }
+    }
+
+    pub fn to_map(&self, ids: &HashSet<u32>) -> Result<Vec<String>> {
+
+        assert!(self.meta.is_map);
+        let mut res = Vec::with_capacity(ids.len());
+
+        if let Some(ref map) = self.map {
+            for id in ids {
+                match map.get(id) {
+                    None => panic!("Not found in map"),
+                    Some(entry) => res.push(entry.path.to_owned()),
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // path of an id, for map databases
+    pub fn path_of(&self, id: u32) -> Option<&str> {
+        self.entry(id).map(|e| e.path.as_str())
+    }
+
+    pub fn entry(&self, id: u32) -> Option<&MapEntry> {
+        match self.map {
+            Some(ref map) => map.get(&id),
+            None => None,
+        }
+    }
+
+    // ids of the files with this sha256, for map databases
+    pub fn find_by_hash(&self, sha256: &[u8; 32]) -> Vec<u32> {
+        match self.hashes.get(&hash::to_hex(sha256)) {
+            Some(ids) => ids.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    // evaluate a search request, see search.rs
+    pub fn run(&mut self, request: &SearchRequest) -> Result<SearchResponse> {
+        search::run(self, request)
+    }
+
+    // same inputs give the same database, see set_deterministic
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    // search one shard opened by open_shards
+    // full shards do not change, their absent n-grams are remembered
+    pub fn search_shard(&mut self, shard: usize, db: &BinacleFile, pattern: &[u8]) -> Result<HashSet<u32>> {
+        self.search_shard_planned(shard, db, pattern, None).map(|(ids, _)| ids)
+    }
+
+    // same, with the plan of the lists read, TimedOut when the deadline
+    // passes in them
+    pub fn search_shard_planned(&mut self, shard: usize, db: &BinacleFile, pattern: &[u8],
+                                deadline: Option<Instant>) -> Result<(HashSet<u32>, Plan)> {
+
+        let pattern = self.normalize(pattern);
+        let preselect = self.preselect;
+        if self.meta.index[shard].is_full {
+            // a shard renamed over since the handle was opened is read again,
+            // a path that can not be checked fails the shard
+            let current = try!(FileIdentity::of_path(&db.path));
+            let reopened = match try!(db.identity()) == current {
+                true => None,
+                false => Some(try!(BinacleFile::open_read_with(&db.path, self.read_path))),
+            };
+            let db = reopened.as_ref().unwrap_or(db);
+            self.check_replaced(shard, current);
+            let cache = self.negative_cache.entry(shard).or_insert_with(|| NegativeCache::new(NEGATIVE_CACHE_SIZE));
+            db.search_cached(&pattern, cache, preselect, deadline)
+        } else {
+            self.negative_cache.remove(&shard);
+            db.search_planned_until(&pattern, preselect, deadline)
+        }
+    }
+
+    // the absent n-grams of a full shard are those of the file they were
+    // found in: a compaction by rename or another process replacing the file
+    // makes them stale
+    fn check_replaced(&mut self, shard: usize, current: FileIdentity) {
+        match self.shard_identity.insert(shard, current) {
+            Some(old) if old != current => {
+                if self.negative_cache.remove(&shard).is_some() {
+                    self.replaced_shards += 1;
+                    eprintln!("Warning: shard {} ({}) was replaced, its cache is dropped", shard, self.meta.index[shard].path);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // n-grams of a pattern probed on their score before all are, 0 probes
+    // them all (see planner.rs)
+    pub fn set_preselect(&mut self, preselect: usize) {
+        self.preselect = preselect;
+    }
+
+    pub fn preselect(&self) -> usize {
+        self.preselect
+    }
+
+    // the translation can only be chosen while the database is empty
+    pub fn set_translation(&mut self, translation: Option<Translation>) -> Result<()> {
+        if self.meta.nb_file != 0 || self.meta.last_id != 0 || !self.meta.index.is_empty() {
+            return Err(Error::new(ErrorKind::Other, "the translation is chosen when the database is created"));
+        }
+        self.meta.translate = translation;
+        Ok(())
+    }
+
+    pub fn translation(&self) -> Option<&Translation> {
+        self.meta.translate.as_ref()
+    }
+
+    // a content or a pattern as the index sees it
+    pub fn normalize<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.meta.translate {
+            Some(ref t) => Cow::Owned(t.apply(bytes)),
+            None => Cow::Borrowed(bytes),
+        }
+    }
+
+    // statistics of the negative cache, all shards summed
+    pub fn cache_stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for cache in self.negative_cache.values() {
+            stats.hits += cache.stats.hits;
+            stats.misses += cache.stats.misses;
+            stats.inserts += cache.stats.inserts;
+        }
+        stats.replaced = self.replaced_shards;
+        stats
+    }
+
+    // to call when the shards are rewritten
+    pub fn clear_negative_cache(&mut self) {
+        self.negative_cache.clear();
+    }
+
+    pub fn is_lazy_map(&self) -> bool {
+        self.lazy_map
+    }
+
+    // load the map entries of these ids when the map is read lazily
+    pub fn resolve(&mut self, ids: &HashSet<u32>) -> Result<()> {
+
+        if !self.lazy_map {
+            return Ok(());
+        }
+
+        let missing: HashSet<u32> = match self.map {
+            Some(ref map) => ids.iter().filter(|id| !map.contains_key(id)).cloned().collect(),
+            None => return Ok(()),
+        };
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let mut path = PathBuf::from(&self.db_path);
+        path.set_extension("map");
+        let found = try!(lazy_map::lookup(path.to_str().unwrap(), &missing));
+        if let Some(ref mut map) = self.map {
+            map.extend(found);
+        }
+        Ok(())
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn set_flush_hook(&mut self, hook: Box<dyn Fn(FlushEvent) + Send>) {
+        self.flush_hook = Some(hook);
+    }
+
+    // write the current index, the map and the meta to the disk, in this order
+    pub fn sync(&mut self) -> Result<()> {
+
+        if let Some((_, ref mut db)) = self.cur_index {
+            try!(db.flush());
+            if let Some(ref hook) = self.flush_hook {
+                hook(FlushEvent::Index);
+            }
+        }
+
+        if self.meta.is_map {
+            try!(self.write_map());
+            let mut path = PathBuf::from(&self.db_path);
+            path.set_extension("map");
+            try!(sync_path(path.to_str().unwrap()));
+            path.set_extension("hashes");
+            try!(sync_path(path.to_str().unwrap()));
+            if let Some(ref hook) = self.flush_hook {
+                hook(FlushEvent::Map);
+            }
+        }
+
+        try!(self.save_meta());
+        try!(self.file.sync_all());
+        if let Some(ref hook) = self.flush_hook {
+            hook(FlushEvent::Meta);
+        }
+
+        self.sync_state.synced();
+        Ok(())
+    }
+
+    // end of a batch of insertions
+    fn commit(&mut self) -> Result<()> {
+        match self.durability {
+            Durability::None => self.flush_map(),
+            _ => self.sync(),
+        }
+    }
+
+    pub fn set_shard_hook(&mut self, hook: Box<dyn Fn(usize) + Send>) {
+        self.shard_hook = Some(hook);
+    }
+
+    pub fn before_shard(&self, shard: usize) {
+        if let Some(ref hook) = self.shard_hook {
+            hook(shard);
+        }
+    }
+
+    pub fn magic(&self) -> &MagicTable {
+        &self.magic
+    }
+
+    // replace the builtin content types
+    pub fn set_magic(&mut self, magic: MagicTable) {
+        self.magic = magic;
+    }
+
+    pub fn ngram_size(&self) -> u8 {
+        self.meta.ngram_size
+    }
+
+    pub fn alignment(&self) -> u8 {
+        self.meta.alignment
+    }
+
+    // shortest pattern found in every file holding it: every 4-byte window
+    // of a file is indexed whatever the parameters, the n-gram size and the
+    // alignment only add candidates the exact check drops
+    pub fn min_reliable_pattern_len(&self) -> usize {
+        4
+    }
+
+    pub fn offset_size(&self) -> u8 {
+        self.meta.offset_size
+    }
+
+    pub fn max_index_size(&self) -> u64 {
+        self.meta.max_index_size
+    }
+
+    // size a shard can not grow past
+    pub fn max_addressable_size(&self) -> u64 {
+        binacle::addressable_size(self.meta.offset_size, self.meta.alignment)
+    }
+
+    // bytes of the header of each shard
+    pub fn header_size(&self) -> u64 {
+        binacle::header_size(self.meta.offset_size, self.meta.alignment, self.meta.ngram_size)
+    }
+
+    pub fn nb_index(&self) -> usize {
+        self.meta.index.len()
+    }
+
+    pub fn nb_file(&self) -> u32 {
+        self.meta.nb_file
+    }
+
+    pub fn last_id(&self) -> u32 {
+        self.meta.last_id
+    }
+
+    pub fn tombstones(&self) -> &[u32] {
+        match self.meta.tombstones {
+            Some(ref t) => t,
+            None => &[],
+        }
+    }
+
+    // open every index read only
+    pub fn open_indexes(&mut self) -> Result<Vec<BinacleFile>> {
+
+        self.cur_index = None;
+
+        let mut indexes = Vec::with_capacity(self.meta.index.len());
+        for index in &self.meta.index {
+            indexes.push(try!(BinacleFile::open_read(&index.path)));
+        }
+        Ok(indexes)
+    }
+
+    // open every index in read only, a shard that can not be opened
+    // does not prevent the others from being opened, neither does one
+    // failing its size check
+    pub fn open_shards(&mut self) -> Vec<Result<BinacleFile>> {
+
+        self.cur_index = None;
+        self.dirty_shards.clear();
+
+        let shards: Vec<Result<BinacleFile>> = (0 .. self.meta.index.len()).map(|n| {
+            let path = &self.meta.index[n].path;
+            let db = match self.dirty_read && !self.shard_is_finalized(n) {
+                true => try!(BinacleFile::open_dirty(path)),
+                false => try!(BinacleFile::open_read_with(path, self.read_path)),
+            };
+            try!(self.check_size(n, &db));
+            if self.verify_hashes {
+                try!(self.check_hash(n, &db));
+            }
+            Ok(db)
+        }).collect();
+        for (n, shard) in shards.iter().enumerate() {
+            if let Some(snapshot) = shard.as_ref().ok().and_then(|s| s.snapshot()) {
+                self.dirty_shards.push((n, snapshot));
+            }
+        }
+        shards
+    }
+
+    pub fn shard_warning(&self, shard: usize, error: &Error) -> ShardWarning {
+        ShardWarning {
+            shard: shard,
+            path: self.meta.index[shard].path.clone(),
+            error: error.to_string(),
+        }
+    }
+
+    // a new empty shard, filled by the caller (reindexing)
+    pub fn add_shard(&mut self, is_full: bool) -> Result<BinacleFile> {
+
+        try!(self.check_writable());
+        self.cur_index = None;
+        try!(self.add_index());
+        let n = self.meta.index.len() - 1;
+        self.meta.index[n].is_full = is_full;
+        match self.cur_index.take() {
+            Some((_, db)) => Ok(db),
+            None => Err(Error::new(ErrorKind::Other, "shard not created")),
+        }
+    }
+
+    // a shard opened for writing by the caller (compaction)
+    pub fn open_shard_write(&mut self, shard: usize) -> Result<BinacleFile> {
+        try!(self.check_writable());
+        self.cur_index = None;
+        BinacleFile::open_write(&self.meta.index[shard].path)
+    }
+
+    pub fn shard_is_full(&self, shard: usize) -> bool {
+        self.meta.index[shard].is_full
+    }
+
+    pub fn shard_is_finalized(&self, shard: usize) -> bool {
+        self.meta.index[shard].finalized == Some(true)
+    }
+
+    pub fn shard_summary(&self, shard: usize) -> Result<Option<ShardSummary>> {
+        finalize::read_summary(&self.meta.index[shard].path)
+    }
+
+    // finalize a full shard, the ones full before the finalization existed
+    // or whose finalization was interrupted
+    pub fn finalize_shard(&mut self, shard: usize) -> Result<ShardSummary> {
+
+        if !self.meta.index[shard].is_full {
+            return Err(Error::new(ErrorKind::Other, format!("shard {} is not full, it still changes", shard)));
+        }
+        let summary = try!(try!(self.open_shard_write(shard)).finalize(DEFAULT_RECHAIN_THRESHOLD, || true));
+        self.negative_cache.remove(&shard);
+        self.meta.index[shard].finalized = Some(true);
+        try!(self.record_checksum(shard));
+        Ok(summary)
+    }
+
+    // every shard rewritten by BinacleFile::compact, reported to done as
+    // it is. Stops at the first one failing, or between two shards when the
+    // disk space runs low: the ones done are kept
+    pub fn compact_shards<F: FnMut(usize, &Compacted)>(&mut self, mut done: F) -> Result<()> {
+
+        for n in 0 .. self.meta.index.len() {
+            try!(self.check_space().map_err(|e| Error::new(e.kind(), format!("{}, shard {} and the next ones not compacted", e, n))));
+            let report = try!(try!(self.open_shard_write(n)).compact());
+            // same ids, but read from another file
+            self.negative_cache.remove(&n);
+            if self.meta.index[n].checksum.is_some() {
+                try!(self.record_checksum(n));
+            }
+            done(n, &report);
+        }
+        Ok(())
+    }
+
+    // to call once a full shard was written: finalized, compacted or merged
+    pub fn record_checksum(&mut self, shard: usize) -> Result<()> {
+        let checksum = try!(ShardChecksum::of(&self.meta.index[shard].path));
+        self.meta.index[shard].checksum = Some(checksum);
+        Ok(())
+    }
+
+    pub fn shard_checksum(&self, shard: usize) -> Option<&ShardChecksum> {
+        self.meta.index[shard].checksum.as_ref()
+    }
+
+    // a shard opened for a search is first checked on its size: the one
+    // recorded by its checksum, or at least the size of its lists told by
+    // its own meta when it still changes
+    fn check_size(&self, shard: usize, db: &BinacleFile) -> Result<()> {
+        let actual = db.file_size();
+        match self.meta.index[shard].checksum {
+            Some(ref checksum) if checksum.size != actual => Err(Error::new(ErrorKind::InvalidData, format!(
+                "{} bytes instead of the {} recorded: truncated or modified", actual, checksum.size))),
+            None if actual < db.get_size() => Err(Error::new(ErrorKind::InvalidData, format!(
+                "{} bytes, its meta tells {}: truncated", actual, db.get_size()))),
+            _ => Ok(()),
+        }
+    }
+
+    // the hash of a shard with a checksum, Ok(false) when it has none
+    fn check_hash(&self, shard: usize, db: &BinacleFile) -> Result<bool> {
+        let expected = match self.meta.index[shard].checksum {
+            Some(ref checksum) => &checksum.sha256,
+            None => return Ok(false),
+        };
+        let actual = try!(ShardChecksum::of(&db.path));
+        match actual.sha256 == *expected {
+            true => Ok(true),
+            false => Err(Error::new(ErrorKind::InvalidData, format!(
+                "sha256 {} instead of the {} recorded: modified", actual.sha256, expected))),
+        }
+    }
+
+    // check the size of every shard and the hash of the ones with a
+    // checksum: Ok(true) when the hash was checked, Ok(false) when only the
+    // size could be
+    pub fn verify_shards(&mut self) -> Vec<Result<bool>> {
+        let shards = self.open_shards();
+        shards.into_iter().enumerate().map(|(n, db)| db.and_then(|db| self.check_hash(n, &db))).collect()
+    }
+
+    // the free space checked by the long insertions, see space.rs
+    pub fn set_space_check(&mut self, check: Option<SpaceCheck>) {
+        self.space_check = check;
+    }
+
+    pub fn space_check(&self) -> Option<SpaceCheck> {
+        self.space_check
+    }
+
+    // Ok when there is no check
+    pub fn check_space(&self) -> Result<()> {
+        match self.space_check {
+            Some(ref check) => check.checkpoint(&self.db_path),
+            None => Ok(()),
+        }
+    }
+
+    // read every shard with a checksum before searching it (slow)
+    pub fn set_verify_hashes(&mut self, verify: bool) {
+        self.verify_hashes = verify;
+    }
+
+    // the searches read the shards with positioned reads instead of
+    // mapping them: a read failing on network storage fails the shard
+    pub fn set_read_path(&mut self, read_path: ReadPath) {
+        self.read_path = read_path;
+    }
+
+    // search the shards another process is writing without waiting for
+    // it, as of their last meta (see dirty.rs)
+    pub fn set_dirty_read(&mut self, dirty_read: bool) {
+        self.dirty_read = dirty_read;
+    }
+
+    // the shards of the last search read so, with what was read of them
+    pub fn dirty_shards(&self) -> &[(usize, Snapshot)] {
+        &self.dirty_shards
+    }
+
+    // finalize the shards when they become full, the default
+    pub fn set_finalize_on_full(&mut self, finalize: bool) {
+        self.finalize_on_full = finalize;
+    }
+
+    // remove the shards from this one, left unfinished by an interrupted reindexing
+    pub fn truncate_shards(&mut self, from: usize) -> Result<()> {
+
+        self.cur_index = None;
+        self.negative_cache.clear();
+        while self.meta.index.len() > from {
+            let index = self.meta.index.pop().unwrap();
+            let mut meta_path = PathBuf::from(&index.path);
+            meta_path.set_extension("meta");
+            for path in &[PathBuf::from(&index.path), meta_path, occupancy::bitmap_path(&index.path)] {
+                match remove_file(path) {
+                    Err(ref e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(Error::new(e.kind(), format!("{:?}: {}", path, e)));
+                    },
+                    _ => {},
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // replay the postings of an index (of another database) into a new
+    // shard, written by the batch writer with the ids shifted by id_offset:
+    // its files are not read, they may be gone. Only the reduced n-grams are
+    // in an index, the ngram_size of this database can not be larger. The
+    // tombstones and the translation of the source are not in its index, in
+    // a map database the entries of the ids are added by the caller
+    // (add_entry, which counts them)
+    pub fn absorb_index(&mut self, src: &BinacleFile, id_offset: u32) -> Result<u64> {
+
+        let ngram_size = self.meta.ngram_size;
+        try!(reindex::check_feasible(src.ngram_size(), ngram_size));
+        let last_id = match src.last_id().checked_add(id_offset) {
+            Some(id) => id,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("id offset {} overflows the ids of {}", id_offset, src.path))),
+        };
+        if src.last_id() != 0 && id_offset < self.meta.last_id {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "id offset {} under the last id {}, the absorbed ids would be mixed with the existing ones", id_offset, self.meta.last_id)));
+        }
+
+        if !self.meta.is_map {
+            self.meta.nb_file += src.nb_file();
+        }
+        self.absorb(src, id_offset, last_id)
+    }
+
+    // the shard of absorb_index, the ids checked by the caller
+    fn absorb(&mut self, src: &BinacleFile, id_offset: u32, last_id: u32) -> Result<u64> {
+
+        let ngram_size = self.meta.ngram_size;
+        let mut writer = try!(try!(self.add_shard(false)).into_bulk_writer());
+        let shifted = |ids: Vec<u32>| ids.into_iter().map(|id| id + id_offset).collect::<Vec<u32>>();
+        if src.ngram_size() == ngram_size {
+            for (ngram, ids) in src.iter_postings() {
+                try!(writer.write_list(ngram, &shifted(ids)));
+            }
+        } else {
+            // the lists of the source n-grams reduced to the same one are merged
+            for (ngram, ids) in reindex::merged_lists(src, ngram_size) {
+                try!(writer.write_list(ngram, &shifted(ids)));
+            }
+        }
+        let size = try!(writer.finish(src.nb_file(), last_id, src.average_size()));
+
+        let shard = self.meta.index.len() - 1;
+        if size > self.meta.max_index_size {
+            self.mark_shard_full(shard);
+            try!(self.record_checksum(shard));
+        }
+        self.meta.last_id = self.meta.last_id.max(last_id);
+        try!(self.sync());
+        Ok(size)
+    }
+
+    // the ids of the database: of its map, or of its shards without one,
+    // and its tombstones
+    fn known_ids(&mut self) -> Result<BTreeSet<u32>> {
+        let mut ids: BTreeSet<u32> = self.meta.tombstones.iter().flat_map(|t| t.iter().cloned()).collect();
+        match self.map {
+            Some(ref map) => ids.extend(map.keys()),
+            None => for shard in try!(self.open_indexes()) {
+                ids.extend(shard.all_ids());
+            },
+        }
+        Ok(ids)
+    }
+
+    // another database built over other files (on another machine) added
+    // to this one: its shards are absorbed, its map entries, labels,
+    // tombstones and quarantine are added, its ids shifted by id_offset.
+    // Nothing is written when one of its ids or labels is already used
+    // here, the offset to merge with is told. Its collections and saved
+    // queries are not merged. The map and the counters are written once
+    // every shard is absorbed: an interrupted merge keeps the shards
+    // absorbed so far, without the entries of their ids
+    pub fn merge_from(&mut self, other_path: &str, id_offset: u32) -> Result<Merge> {
+
+        try!(self.check_writable());
+        let mut other = try!(BinacleManager::open(other_path));
+        if other.meta.is_map != self.meta.is_map {
+            let has = |db: &BinacleManager| if db.meta.is_map { "has a map" } else { "has no map" };
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} {} and {} {}", other_path, has(&other), self.db_path, has(self))));
+        }
+        if other.lazy_map {
+            return Err(Error::new(ErrorKind::Other, format!("the map of {} is read lazily, it can not be merged", other_path)));
+        }
+        if other.translation() != self.translation() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "{} and {} do not translate the bytes the same way (--translate), their n-grams differ", other_path, self.db_path)));
+        }
+        try!(reindex::check_feasible(other.ngram_size(), self.meta.ngram_size));
+
+        let shift = |id: u32| id.checked_add(id_offset).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, format!("id offset {} overflows the id {} of {}", id_offset, id, other_path))
+        });
+        let mut ids = BTreeSet::new();
+        for id in try!(other.known_ids()) {
+            ids.insert(try!(shift(id)));
+        }
+        let known = try!(self.known_ids());
+        let colliding: Vec<u32> = ids.intersection(&known).cloned().collect();
+        if !colliding.is_empty() {
+            let first: Vec<String> = colliding.iter().take(5).map(|id| id.to_string()).collect();
+            // the smallest id of the other one just after the last one here
+            let lowest = ids.iter().next().unwrap() - id_offset;
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "{} id(s) of {} shifted by {} are already in {} ({}{}): merge with an id offset of {} at least",
+                colliding.len(), other_path, id_offset, self.db_path, first.join(", "),
+                if colliding.len() > first.len() { ", ..." } else { "" }, self.meta.last_id.saturating_add(1).saturating_sub(lowest))));
+        }
+        if let Some(label) = other.labels.keys().find(|l| self.labels.contains_key(*l)) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("the label {} is in {} and in {}", label, other_path, self.db_path)));
+        }
+
+        // the shards first: interrupted, the ids absorbed have no entry yet
+        // and the ones of the next merge start after them
+        let shards = try!(other.open_indexes());
+        let last_id = try!(shift(other.meta.last_id));
+        for shard in &shards {
+            try!(self.absorb(shard, id_offset, shard.last_id().saturating_add(id_offset)));
+        }
+
+        if let Some(map) = other.map.take() {
+            for (id, entry) in map {
+                let id = id + id_offset;
+                if let Some(ref sha256) = entry.sha256 {
+                    let same = self.hashes.entry(sha256.clone()).or_insert(Vec::new());
+                    same.push(id);
+                    same.sort();
+                }
+                if let Some(ref label) = entry.label {
+                    self.labels.insert(label.clone(), id);
+                }
+                if let Some(ref mut map) = self.map {
+                    map.insert(id, entry);
+                }
+            }
+        }
+        if let Some(ref tombstones) = other.meta.tombstones {
+            let mut merged = self.meta.tombstones.take().unwrap_or(Vec::new());
+            merged.extend(tombstones.iter().map(|id| id + id_offset));
+            merged.sort();
+            self.meta.tombstones = Some(merged);
+        }
+        if let Some(ref quarantine) = other.meta.quarantine {
+            self.meta.quarantine.get_or_insert(Vec::new()).extend(quarantine.iter().cloned());
+        }
+        self.meta.nb_file += other.meta.nb_file;
+        self.meta.last_id = self.meta.last_id.max(last_id);
+        try!(self.sync());
+        Ok(Merge {
+            shards: shards.len(),
+            files: other.meta.nb_file,
+            ids: match (ids.iter().next(), ids.iter().next_back()) {
+                (Some(&first), Some(&last)) => Some((first, last)),
+                _ => None,
+            },
+        })
+    }
+
+    // map, counters and tombstones of another database holding the same files
+    pub fn copy_entries(&mut self, from: &BinacleManager) {
+        self.meta.nb_file = from.meta.nb_file;
+        self.meta.last_id = from.meta.last_id;
+        self.meta.tombstones = from.meta.tombstones.clone();
+        self.meta.quarantine = from.meta.quarantine.clone();
+        self.meta.filtered = from.meta.filtered.clone();
+        if self.meta.is_map {
+            self.map = from.map.clone();
+            self.hashes = from.hashes.clone();
+            self.labels = from.labels.clone();
+        }
+    }
+
+    // an entry of a file indexed by the caller (bulk build), the ids must
+    // be given in increasing order
+    pub fn add_entry(&mut self, id: u32, mut entry: MapEntry) {
+        self.meta.nb_file += 1;
+        self.meta.last_id = id;
+        if !self.meta.is_map {
+            return;
+        }
+        if !self.deterministic {
+            entry.date = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok();
+        }
+        if let Some(ref sha256) = entry.sha256 {
+            self.hashes.entry(sha256.clone()).or_insert(Vec::new()).push(id);
+        }
+        if let Some(ref label) = entry.label {
+            self.labels.insert(label.clone(), id);
+        }
+        if let Some(ref mut map) = self.map {
+            map.insert(id, entry);
+        }
+    }
+
+    pub fn mark_shard_full(&mut self, shard: usize) {
+        self.meta.index[shard].is_full = true;
+    }
+
+    // read again the meta and the map when another manager wrote them
+    // since they were read: the new shards are then searched. Tells if
+    // something was reloaded
+    pub fn reload(&mut self) -> Result<bool> {
+
+        let stamp = BinacleManager::meta_stamp(&self.db_path);
+        if stamp.is_some() && stamp == self.meta_stamp {
+            return Ok(false);
+        }
+
+        try!(self.file.lock_shared());
+        let stamp = BinacleManager::meta_stamp(&self.db_path);
+        let meta = BinacleManager::read_meta(&mut self.file);
+        if !meta.as_ref().map_or(false, |m| m.0.sealed == Some(true)) {
+            let _ = self.file.unlock();
+        }
+        let (meta, meta_unknown) = try!(meta);
+
+        if meta.is_map && !self.lazy_map {
+            let map = try!(BinacleManager::read_map(&self.db_path));
+            self.hashes = try!(BinacleManager::read_hashes(&self.db_path, &map));
+            self.labels = BinacleManager::build_labels(&map);
+            self.map = Some(map);
+        } else if meta.is_map {
+            // the entries are looked up again when needed
+            self.map = Some(HashMap::new());
+        }
+
+        self.meta_saved = try!(BinacleManager::encode_meta(&meta, &meta_unknown));
+        self.meta = meta;
+        self.meta_unknown = meta_unknown;
+        self.meta_stamp = stamp;
+        self.cur_index = None;
+        // a shard may have been rewritten
+        self.negative_cache.clear();
+        self.last_reload = Some(SystemTime::now());
+        Ok(true)
+    }
+
+    pub fn last_reload(&self) -> Option<SystemTime> {
+        self.last_reload
+    }
+
+    // shards skipped by the last call to search
+    pub fn shard_warnings(&self) -> &[ShardWarning] {
+        &self.shard_warnings
+    }
+
+    pub fn is_map(&self) -> bool {
+        return self.meta.is_map;
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.meta.sealed == Some(true)
+    }
+
+    // make the database read only for good: every shard is finalized and
+    // checksummed (the one in use too, the next insertions after an unseal
+    // go to a new shard), the map is written a last time, then the meta
+    // is sealed. Nothing is written to a sealed database, the inserts and
+    // the changes of the map fail until it is unsealed
+    pub fn seal(&mut self) -> Result<()> {
+
+        try!(self.check_writable());
+        self.cur_index = None;
+        for shard in 0 .. self.meta.index.len() {
+            if !self.meta.index[shard].is_full {
+                self.mark_shard_full(shard);
+            }
+            if !self.shard_is_finalized(shard) {
+                try!(self.finalize_shard(shard));
+            } else if self.meta.index[shard].checksum.is_none() {
+                try!(self.record_checksum(shard));
+            }
+        }
+        try!(self.flush_map());
+        self.meta.sealed = Some(true);
+        self.store_meta()
+    }
+
+    // writable again: refused while another process has the database
+    // open, it holds a shared lock on it. The unsealing is told on stderr
+    pub fn unseal(&mut self) -> Result<()> {
+
+        if !self.is_sealed() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not sealed", self.db_path)));
+        }
+        self.meta.sealed = None;
+        if let Err(e) = self.store_meta() {
+            self.meta.sealed = Some(true);
+            let _ = self.file.lock_shared();
+            return Err(e);
+        }
+        eprintln!("{} unsealed by pid {} on {}", self.db_path, process::id(), lock::local_host());
+        Ok(())
+    }
+
+    /*********************/
+    /*  Private methods  */
+    /*********************/
+
+    // the id is given up, its n-grams may be in the index
+    fn quarantine_file(&mut self, filepath: &str, id: u32, error: &Error) {
+
+        self.tombstone(id);
+        if id > self.meta.last_id {
+            self.meta.last_id = id;
+        }
+
+        let quarantine = self.meta.quarantine.get_or_insert(Vec::new());
+        quarantine.retain(|q| q.path != filepath);
+        quarantine.push(Quarantined {
+            path: String::from(filepath),
+            reason: error.to_string(),
+        });
+    }
+
+    // the error of the changes refused by a sealed database or a lazy map
+    fn check_writable(&self) -> Result<()> {
+        if self.is_sealed() {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!(
+                "{} is sealed, it is read only: --unseal it first", self.db_path)));
+        }
+        if self.lazy_map {
+            return Err(Error::new(ErrorKind::Other, "the map is read lazily, the database is read only"));
+        }
+        Ok(())
+    }
+
+    fn label_conflict(label: &str, id: u32) -> Error {
+        Error::new(ErrorKind::AlreadyExists, format!("label \"{}\" is already used by id {}", label, id))
+    }
+
+    // the labels are tried before the ids where either is accepted, a
+    // number would hide the id it reads as
+    fn check_label(label: &str) -> Result<()> {
+        match !label.is_empty() && label.bytes().all(|b| b.is_ascii_digit()) {
+            true => Err(Error::new(ErrorKind::InvalidInput, format!("label \"{}\" is a number, it would hide the id {}", label, label))),
+            false => Ok(()),
+        }
+    }
+
+    // the n-grams of the file read and written
+    fn insert_index<R: Read>(&mut self, reader: R, id: u32) -> Result<DedupStats> {
+
+        match self.cur_index {
+            Some((i, ref mut db)) if !self.meta.index[i].is_full => {
+                try!(db.insert_reader(reader, id));
+                let dedup = db.last_dedup();
+
+                if db.get_size() > self.meta.max_index_size {
+                    self.meta.index[i].is_full = true;
+                    try!(db.fix_size());
+                    if self.finalize_on_full {
+                        // the file is indexed, a shard left unfinalized is
+                        // finalized again by --finalize
+                        match db.finalize(DEFAULT_RECHAIN_THRESHOLD, || true).and_then(|_| ShardChecksum::of(&db.path)) {
+                            Ok(checksum) => {
+                                self.meta.index[i].finalized = Some(true);
+                                self.meta.index[i].checksum = Some(checksum);
+                            },
+                            Err(e) => eprintln!("Warning: shard {} not finalized: {}", i, e),
+                        }
+                    } else if self.durability != Durability::None {
+                        // the shard is closed by the next insertion, before any sync
+                        try!(db.flush());
+                    }
+                }
+                Ok(dedup)
+            },
+
+            Some(_) | None => {
+                try!(self.set_cur_index());
+                self.insert_index(reader, id)
+            },
+        }
+    }
+
+    fn set_cur_index(&mut self) -> Result<()> {
+
+        let free_index = self.meta.index.iter().cloned().enumerate().find(|x| !x.1.is_full);
+
+        match free_index {
+            Some((i, index)) => {
+                self.cur_index = Some((i, try!(BinacleFile::open_write(&index.path))));
+            },
+            None => {
+                try!(self.add_index());
+            },
+        };
+        Ok(())
+    }
+
+    fn add_index(&mut self) -> Result<()> {
+
+        let offset_size = self.meta.offset_size;
+        let alignment = self.meta.alignment;
+        let ngram_size = self.meta.ngram_size;
 
     	if offset_size < 4 || offset_size > 8 {
     		return Err(Error::new(ErrorKind::Other, "violation: 4 <= offset_size <= 8"));
@@ -313,6 +2305,8 @@ impl BinacleManager {
     	let index = BinacleIndex {
 			path: index_name,
 			is_full: false,
+            finalized: None,
+            checksum: None,
     	};
 
     	self.meta.index.push(index);
@@ -320,26 +2314,131 @@ impl BinacleManager {
     	Ok(())
     }
 
-    fn read_meta(file: &mut File) -> Result<BinacleMeta> {
+    fn meta_stamp(path: &str) -> Option<(SystemTime, u64)> {
+        metadata(path).and_then(|m| m.modified().map(|t| (t, m.len()))).ok()
+    }
+
+    fn build_labels(map: &HashMap<u32, MapEntry>) -> HashMap<String, u32> {
+        let mut labels = HashMap::new();
+        for (id, entry) in map {
+            if let Some(ref label) = entry.label {
+                labels.insert(label.clone(), *id);
+            }
+        }
+        labels
+    }
+
+    // the fields unknown to this build aside, to be written back
+    fn read_meta(file: &mut File) -> Result<(BinacleMeta, Unknown)> {
 
 	    let mut encoded = String::new();
 
 	    try!(file.seek(SeekFrom::Start(0)));
-	    file.read_to_string(&mut encoded).unwrap();
+	    try!(file.read_to_string(&mut encoded));
+
+	    compat::decode(&encoded)
+    }
+
+    fn encode_meta(meta: &BinacleMeta, unknown: &Unknown) -> Result<String> {
+        compat::encode(meta, unknown)
+    }
+
+    // a shorter meta than the previous one must not leave its end
+    fn write_meta(file: &mut File, encoded: &str) -> Result<()> {
+        try!(file.seek(SeekFrom::Start(0)));
+        try!(file.write_all(encoded.as_bytes()));
+        file.set_len(encoded.len() as u64)
+    }
+
+    // the lock of the database, waited for lock_wait at most
+    fn lock_meta(&self) -> Result<()> {
+        let start = SystemTime::now();
+        loop {
+            match self.file.try_lock_exclusive() {
+                Ok(()) => {
+                    lock::acquired(&self.db_path);
+                    return Ok(());
+                },
+                Err(ref e) if e.kind() == lock_contended_error().kind() => {},
+                Err(e) => return Err(e),
+            }
+            if start.elapsed().unwrap_or(Duration::from_secs(0)) >= self.lock_wait {
+                return Err(Error::new(ErrorKind::TimedOut, format!(
+                    "{} is locked by another process", self.db_path)));
+            }
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+    }
 
-	    let meta = json::decode(&encoded).unwrap();
+    // write the meta when it changed since it was read or written, a
+    // sealed meta only changes by seal() and unseal()
+    fn save_meta(&mut self) -> Result<()> {
+        if self.is_sealed() && try!(BinacleManager::encode_meta(&self.meta, &self.meta_unknown)) != self.meta_saved {
+            return self.check_writable();
+        }
+        self.store_meta()
+    }
+
+    fn store_meta(&mut self) -> Result<()> {
+
+        let encoded = try!(BinacleManager::encode_meta(&self.meta, &self.meta_unknown));
+        if encoded == self.meta_saved {
+            return Ok(());
+        }
+
+        try!(self.lock_meta());
+        let written = BinacleManager::write_meta(&mut self.file, &encoded);
+        let _ = self.file.unlock();
+        lock::released(&self.db_path);
+        try!(written);
+
+        self.meta_saved = encoded;
+        self.meta_stamp = BinacleManager::meta_stamp(&self.db_path);
+        Ok(())
+    }
+
+    pub fn set_lock_wait(&mut self, wait: Duration) {
+        self.lock_wait = wait;
+    }
+
+    // the locks held on the meta and on each shard, found without waiting
+    // for them: the database is not opened
+    pub fn lock_status(path: &str) -> Result<Vec<LockStatus>> {
 
-	    Ok(meta)
+        let (meta, _) = try!(BinacleManager::read_meta(&mut try!(OpenOptions::new().read(true).open(path))));
+        let mut report = vec![try!(lock::probe(path))];
+        for index in &meta.index {
+            report.push(try!(lock::probe(&index.path)));
+        }
+        Ok(report)
     }
 
-    fn write_meta(file: &mut File, meta: &BinacleMeta) {
-        let encoded = json::encode(meta).unwrap();
+    // remove the lock info of the holders gone and take the locks again,
+    // refused when a holder is alive on this host: the locks after it
+    pub fn force_unlock(path: &str) -> Result<Vec<LockStatus>> {
+
+        let report = try!(BinacleManager::lock_status(path));
+        for status in &report {
+            try!(lock::check_not_alive(status));
+        }
+        for status in &report {
+            try!(lock::force_unlock(&status.path));
+        }
+        BinacleManager::lock_status(path)
+    }
 
-        file.seek(SeekFrom::Start(0)).unwrap();
-        let _ = file.write_all(&encoded.into_bytes());    
+    // write the map and the meta, then release the database: the errors
+    // the Drop could only print are returned
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        if self.is_temporary() {
+            return self.remove_temporary();
+        }
+        try!(self.flush_map());
+        self.save_meta()
     }
 
-    fn read_map(path: &str) -> Result<HashMap<u32, String>> {
+    fn read_map(path: &str) -> Result<HashMap<u32, MapEntry>> {
 
         let mut meta_path = PathBuf::from(&path);
         meta_path.set_extension("map");
@@ -355,49 +2454,1260 @@ impl BinacleManager {
             Ok(mut file) => {
                 let mut encoded = String::new();
                 try!(file.read_to_string(&mut encoded));
-                return Ok(json::decode(&encoded).unwrap());
+                if let Ok(map) = json::decode(&encoded) {
+                    return Ok(map);
+                }
+
+                // maps written before the entries existed only hold paths
+                let legacy: HashMap<u32, String> = try!(json::decode(&encoded).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())));
+                return Ok(legacy.into_iter().map(|(id, path)| (id, MapEntry::new(&path))).collect());
             }
         }
     }
 
-    fn write_map(&self) {
+    // read the hash index, rebuild it from the map when it does not exist yet
+    fn read_hashes(path: &str, map: &HashMap<u32, MapEntry>) -> Result<HashMap<String, Vec<u32>>> {
+
+        let mut hashes_path = PathBuf::from(&path);
+        hashes_path.set_extension("hashes");
+
+        match OpenOptions::new().read(true).open(&hashes_path) {
+            Ok(mut file) => {
+                let mut encoded = String::new();
+                try!(file.read_to_string(&mut encoded));
+                match json::decode(&encoded) {
+                    Ok(hashes) => Ok(hashes),
+                    Err(e) => Err(Error::new(ErrorKind::InvalidData, format!("{:?}: {}", hashes_path, e))),
+                }
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let mut hashes = HashMap::new();
+                for (id, entry) in map {
+                    if let Some(ref h) = entry.sha256 {
+                        hashes.entry(h.clone()).or_insert(Vec::new()).push(*id);
+                    }
+                }
+                Ok(hashes)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_map(&self) -> Result<()> {
+
+        // only a part of the map is known
+        if self.lazy_map {
+            return Ok(());
+        }
+        self.write_map_to(&self.db_path)
+    }
+
+    // the map and the hashes of the database at path
+    fn write_map_to(&self, path: &str) -> Result<()> {
 
         assert!(self.meta.is_map);
 
-        let encoded = json::encode(&self.map).unwrap();
+        // sorted keys, the same map is always written the same way
+        let sorted: BTreeMap<&u32, &MapEntry> = self.map.iter().flat_map(|m| m.iter()).collect();
+        let encoded = try!(json::encode(&sorted).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())));
+
+        let mut path = PathBuf::from(path);
+        path.set_extension("map");
+        try!(BinacleManager::write_replacing(&path, encoded.as_bytes()));
+
+        // the hash index is kept next to the map
+        let sorted: BTreeMap<&String, &Vec<u32>> = self.hashes.iter().collect();
+        let encoded = try!(json::encode(&sorted).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())));
+        path.set_extension("hashes");
+        BinacleManager::write_replacing(&path, encoded.as_bytes())
+    }
+
+    // written aside and renamed, an interrupted write leaves the previous file
+    fn write_replacing(path: &PathBuf, content: &[u8]) -> Result<()> {
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(content));
+        }
+        rename(&tmp, path)
+    }
 
+    pub fn map_path(&self) -> PathBuf {
         let mut path = PathBuf::from(&self.db_path);
         path.set_extension("map");
+        path
+    }
 
-        let mut file = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(path.to_str().unwrap())
-                    .unwrap();
+    // replace the whole map (see map_rebuild.rs): the meta is written first,
+    // then the map under the lock of the database
+    pub fn replace_map(&mut self, map: HashMap<u32, MapEntry>) -> Result<()> {
 
-        let _ = file.write_all(&encoded.into_bytes());    
+        try!(self.check_writable());
+        if !self.meta.is_map || self.lazy_map {
+            return Err(Error::new(ErrorKind::Other, "the map is replaced in a map database loaded in memory"));
+        }
+        let mut hashes = HashMap::new();
+        for (id, entry) in &map {
+            if let Some(ref h) = entry.sha256 {
+                hashes.entry(h.clone()).or_insert(Vec::new()).push(*id);
+            }
+        }
+        for ids in hashes.values_mut() {
+            ids.sort();
+        }
+        self.labels = BinacleManager::build_labels(&map);
+        self.hashes = hashes;
+        self.map = Some(map);
+
+        try!(self.save_meta());
+        try!(self.lock_meta());
+        let written = self.write_map();
+        let _ = self.file.unlock();
+        lock::released(&self.db_path);
+        written
     }
 
 }
 
 impl Drop for BinacleManager {
 
+    // best effort, the errors are only printed: a panic here would abort
+    // the process when it unwinds from another error, hiding it
     fn drop(&mut self) {
 
-        let mut file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(false)
-                    .open(&self.db_path)
-                    .unwrap();
-
-        // lock the file to prevent parallel use
-        file.lock_exclusive().unwrap();
-        
-        BinacleManager::write_meta(&mut file, &self.meta);
-        if self.meta.is_map {
-            self.write_map();
+        if self.closed {
+            return;
+        }
+        if self.is_temporary() {
+            if let Err(e) = self.remove_temporary() {
+                eprintln!("Warning: the temporary database {} was not removed: {}", self.db_path, e);
+            }
+            return;
+        }
+        if let Err(e) = self.flush_map() {
+            eprintln!("Warning: the map of {} was not written: {}", self.db_path, e);
+        }
+        if let Err(e) = self.save_meta() {
+            eprintln!("Warning: the meta of {} was not written: {}", self.db_path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::fs::remove_file;
+    use binacle::tests::FaultyReader;
+
+    pub fn cleanup(name: &str) {
+        let _ = remove_file(format!("{}.db", name));
+        let _ = remove_file(format!("{}.map", name));
+        let _ = remove_file(format!("{}.hashes", name));
+        let _ = remove_file(format!("{}.db.lockinfo", name));
+        // every shard: the first 8 (a test may leave a hole), then until
+        // the first one missing
+        for i in 0 .. {
+            let found = remove_file(format!("{}.db_index{}.db", name, i)).is_ok();
+            let _ = remove_file(format!("{}.db_index{}.meta", name, i));
+            let _ = remove_file(format!("{}.db_index{}.summary", name, i));
+            let _ = remove_file(format!("{}.db_index{}.bitmap", name, i));
+            let _ = remove_file(format!("{}.db_index{}.db.lockinfo", name, i));
+            if !found && i >= 8 {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn insert_failure_keeps_counters() {
+        {
+            let mut db = BinacleManager::create("test_mgr1.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"first file"[..], "first", 1, true);
+
+            match db.insert_reader(FaultyReader { ok: 100 }, "faulty", 2, true) {
+                InsertOutcome::Failed(_) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(1, db.meta.nb_file);
+            assert_eq!(1, db.meta.last_id);
+            assert_eq!(None, db.path_of(2));
+            assert_eq!(1, BinacleManager::read_map("test_mgr1.db").unwrap().len());
+        }
+        cleanup("test_mgr1");
+    }
+
+    #[test]
+    fn insert_outcomes() {
+        {
+            let mut db = BinacleManager::create("test_mgr2.db", true, 1 << 30, 6, 16).unwrap();
+            match db.insert("src", 1, false) {
+                InsertOutcome::Skipped(_) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            match db.insert("does_not_exist", 1, false) {
+                InsertOutcome::Failed(_) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            match db.insert("Cargo.toml", 3, false) {
+                InsertOutcome::Inserted(3) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(1, db.meta.nb_file);
+            assert_eq!(Some("Cargo.toml"), db.path_of(3));
+        }
+        cleanup("test_mgr2");
+    }
+
+    #[test]
+    fn hash_index() {
+        let sha = hash::sha256_file("Cargo.toml").unwrap();
+        {
+            let mut db = BinacleManager::create("test_mgr3.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_file("Cargo.toml", 1, false).unwrap();
+            db.insert_file("Cargo.toml", 2, true).unwrap();
+            assert_eq!(vec![1, 2], db.find_by_hash(&sha));
+            assert_eq!(Some(hash::to_hex(&sha)), db.entry(1).unwrap().sha256);
+        }
+        {
+            // persisted next to the map
+            let db = BinacleManager::open("test_mgr3.db").unwrap();
+            let mut ids = db.find_by_hash(&sha);
+            ids.sort();
+            assert_eq!(vec![1, 2], ids);
+            assert!(db.find_by_hash(&[0u8; 32]).is_empty());
+        }
+        {
+            // rebuilt from the map when missing
+            let _ = remove_file("test_mgr3.hashes");
+            let db = BinacleManager::open("test_mgr3.db").unwrap();
+            assert_eq!(2, db.find_by_hash(&sha).len());
+        }
+        cleanup("test_mgr3");
+    }
+
+    #[test]
+    fn legacy_map() {
+        {
+            let _ = BinacleManager::create("test_mgr4.db", true, 1 << 30, 6, 16).unwrap();
+        }
+        {
+            let mut file = File::create("test_mgr4.map").unwrap();
+            file.write_all(b"{\"1\":\"/old/path\"}").unwrap();
+            let _ = remove_file("test_mgr4.hashes");
+        }
+        {
+            let db = BinacleManager::open("test_mgr4.db").unwrap();
+            assert_eq!(Some("/old/path"), db.path_of(1));
+            assert_eq!(None, db.entry(1).unwrap().sha256);
+        }
+        cleanup("test_mgr4");
+    }
+
+    // the errors of a database that can not be opened name the file
+    #[test]
+    fn open_errors() {
+        {
+            let mut db = BinacleManager::create("test_mgr30.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "a.exe", 1, false);
+        }
+        let err = BinacleManager::open("test_mgr30_none.db").err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().starts_with("test_mgr30_none.db: "), "{}", err);
+
+        // a shard whose meta is corrupt
+        let meta = std::fs::read("test_mgr30.db_index0.meta").unwrap();
+        File::create("test_mgr30.db_index0.meta").unwrap().write_all(b"{\"size\":").unwrap();
+        {
+            let mut db = BinacleManager::open("test_mgr30.db").unwrap();
+            let err = db.open_indexes().err().unwrap();
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+            assert!(err.to_string().starts_with("test_mgr30.db_index0.meta: "), "{}", err);
+        }
+        File::create("test_mgr30.db_index0.meta").unwrap().write_all(&meta).unwrap();
+
+        rename("test_mgr30.map", "test_mgr30.map.bak").unwrap();
+        let err = BinacleManager::open("test_mgr30.db").err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().starts_with("test_mgr30.map: "), "{}", err);
+        rename("test_mgr30.map.bak", "test_mgr30.map").unwrap();
+
+        let content = std::fs::read("test_mgr30.db").unwrap();
+        File::create("test_mgr30.db").unwrap().write_all(&content[.. content.len() / 2]).unwrap();
+        let err = BinacleManager::open("test_mgr30.db").err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().starts_with("test_mgr30.db: "), "{}", err);
+        cleanup("test_mgr30");
+    }
+
+    // the map listed entry by entry, read lazily or not
+    #[test]
+    fn each_entry() {
+        {
+            let mut db = BinacleManager::create("test_mgr31.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "c.exe", 3, false);
+            db.insert_reader(&b"LoadLibraryA"[..], "a.exe", 1, false);
+            db.insert_reader(&b"VirtualAlloc"[..], "b.exe", 2, true);
+        }
+
+        let listed = |db: &BinacleManager| {
+            let mut listed = Vec::new();
+            db.for_each_entry(|id, entry| { listed.push((id, entry.path.clone())); Ok(()) }).unwrap();
+            listed.sort();
+            listed
+        };
+        let all = vec![(1, String::from("a.exe")), (2, String::from("b.exe")), (3, String::from("c.exe"))];
+
+        let db = BinacleManager::open("test_mgr31.db").unwrap();
+        assert_eq!(all, listed(&db));
+        // in the order of the ids
+        let mut ids = Vec::new();
+        db.for_each_entry(|id, _| { ids.push(id); Ok(()) }).unwrap();
+        assert_eq!(vec![1, 2, 3], ids);
+
+        // the first error stops the listing
+        let mut seen = 0;
+        let err = db.for_each_entry(|_, _| {
+            seen += 1;
+            Err(Error::new(ErrorKind::BrokenPipe, "closed"))
+        }).err().unwrap();
+        assert_eq!(ErrorKind::BrokenPipe, err.kind());
+        assert_eq!(1, seen);
+        drop(db);
+
+        let lazy = BinacleManager::open_with("test_mgr31.db", MapPolicy { cap: Some(10), lazy: true }).unwrap();
+        assert!(lazy.is_lazy_map());
+        assert_eq!(all, listed(&lazy));
+        drop(lazy);
+        cleanup("test_mgr31");
+    }
+
+    // the ids after the last one, the given ones included
+    #[test]
+    fn auto_ids() {
+        {
+            let mut db = BinacleManager::create("test_mgr33.db", true, 1 << 30, 6, 16).unwrap();
+            assert_eq!(1, db.insert_file_auto("Cargo.toml").unwrap());
+            assert_eq!(7, db.insert_file("Cargo.lock", 7, true).unwrap());
+            assert_eq!(8, db.insert_file_auto("src/lib.rs").unwrap());
+            assert!(db.insert_file_auto("no_such_file.bin").is_err());
+            assert_eq!(9, db.next_id().unwrap());
+            assert_eq!(Some("src/lib.rs"), db.path_of(8));
+        }
+        // kept in the map
+        let db = BinacleManager::open("test_mgr33.db").unwrap();
+        assert_eq!(Some("src/lib.rs"), db.path_of(8));
+        assert_eq!(9, db.next_id().unwrap());
+        drop(db);
+        cleanup("test_mgr33");
+    }
+
+    // an insertion run again skips the paths already in the map
+    #[test]
+    fn resumed_insertion() {
+
+        let _ = remove_dir_all("test_mgr32_dir");
+        create_dir("test_mgr32_dir").unwrap();
+        create_dir("test_mgr32_dir/sub").unwrap();
+        for (name, content) in &[("a.bin", "GetProcAddress"), ("b.bin", "LoadLibraryA"), ("sub/c.bin", "VirtualAlloc")] {
+            File::create(format!("test_mgr32_dir/{}", name)).unwrap().write_all(content.as_bytes()).unwrap();
+        }
+        {
+            let mut db = BinacleManager::create("test_mgr32.db", true, 1 << 30, 6, 16).unwrap();
+            db.set_deterministic(true);
+            assert_eq!(3, db.insert_dir_recursive("test_mgr32_dir").unwrap().len());
+        }
+        File::create("test_mgr32_dir/d.bin").unwrap().write_all(b"CreateRemoteThread").unwrap();
+        {
+            let mut db = BinacleManager::open("test_mgr32.db").unwrap();
+            db.set_deterministic(true);
+            // the new file alone is counted
+            let mut events = Vec::new();
+            let insertions = db.insert_dir_with("test_mgr32_dir", |event| events.push(match event {
+                InsertEvent::Listed { files, bytes, known } => format!("listed {} {} {}", files, bytes, known),
+                InsertEvent::Known(i) => format!("known {}", i.id),
+                InsertEvent::Done(i) => format!("done {}", i.id),
+            })).unwrap();
+            assert_eq!(vec!["listed 1 18 3", "known 1", "known 2", "done 4", "known 3"], events);
+            let got: Vec<(u32, &str, Option<String>)> = insertions.iter().map(|i| (i.id, i.path.as_str(), i.outcome.error())).collect();
+            assert_eq!(vec![(1, "test_mgr32_dir/a.bin", Some(String::from("already indexed as id 1"))),
+                            (2, "test_mgr32_dir/b.bin", Some(String::from("already indexed as id 2"))),
+                            (4, "test_mgr32_dir/d.bin", None),
+                            (3, "test_mgr32_dir/sub/c.bin", Some(String::from("already indexed as id 3")))], got);
+            assert_eq!(4, db.nb_file());
+            assert_eq!(Some("test_mgr32_dir/d.bin"), db.path_of(4));
+
+            // inserted again under new ids
+            db.set_reinsert_known(true);
+            let insertions = db.insert_dir_recursive("test_mgr32_dir").unwrap();
+            assert_eq!(vec![5, 6, 7, 8], insertions.iter().map(|i| i.id).collect::<Vec<u32>>());
+            assert_eq!(8, db.nb_file());
+            let mut found: Vec<u32> = db.search(b"GetProcAddress").unwrap().into_iter().collect();
+            found.sort();
+            assert_eq!(vec![1, 5], found);
+        }
+        cleanup("test_mgr32");
+        let _ = remove_dir_all("test_mgr32_dir");
+    }
+
+    #[test]
+    fn labels() {
+        {
+            let mut db = BinacleManager::create("test_mgr5.db", true, 1 << 30, 6, 16).unwrap();
+            match db.insert_labeled("Cargo.toml", 1, "5f2b-uuid", true) {
+                InsertOutcome::Inserted(1) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            // a used label is refused before anything is indexed
+            match db.insert_labeled("Cargo.toml", 2, "5f2b-uuid", true) {
+                InsertOutcome::Failed(ref e) if e.kind() == ErrorKind::AlreadyExists => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(1, db.meta.nb_file);
+            assert!(db.path_of(2).is_none());
+
+            // a number would hide an id
+            match db.insert_labeled("Cargo.toml", 2, "12", true) {
+                InsertOutcome::Failed(ref e) if e.kind() == ErrorKind::InvalidInput => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(ErrorKind::InvalidInput, db.set_label(1, "0012").unwrap_err().kind());
+            assert!(db.set_label(1, "12a").is_ok());
+            assert!(db.set_label(1, "5f2b-uuid").is_ok());
+        }
+        {
+            let mut db = BinacleManager::open("test_mgr5.db").unwrap();
+            assert_eq!(Some(1), db.id_of_label("5f2b-uuid"));
+            assert_eq!(Some(1), db.resolve_id("5f2b-uuid"));
+            assert_eq!(Some(7), db.resolve_id("7"));
+            assert_eq!(None, db.resolve_id("unknown"));
+            assert_eq!(Some(String::from("5f2b-uuid")), db.entry(1).unwrap().label);
+
+            db.remove_entry(1);
+            assert_eq!(None, db.id_of_label("5f2b-uuid"));
+        }
+        cleanup("test_mgr5");
+    }
+
+    #[test]
+    fn file_types() {
+        {
+            let mut db = BinacleManager::create("test_mgr6.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"MZ\x90\x00 GetProcAddress"[..], "a.exe", 1, false);
+            db.insert_reader(&b"\x7fELF\x02 GetProcAddress"[..], "b.so", 2, false);
+            db.insert_reader(&b"call GetProcAddress\n"[..], "c.txt", 3, true);
+
+            assert_eq!(Some("pe"), db.entry(1).unwrap().file_type.as_ref().map(|t| t.as_str()));
+            assert_eq!(Some("text"), db.entry(3).unwrap().file_type.as_ref().map(|t| t.as_str()));
+
+            let request = SearchRequest::pattern(b"GetProcAddress").file_type("pe").file_type("elf");
+            assert_eq!(vec![1, 2], db.run(&request).unwrap().ids);
+            let request = SearchRequest::pattern(b"GetProcAddress").file_type("text");
+            assert_eq!(vec![3], db.run(&request).unwrap().ids);
         }
+        {
+            let db = BinacleManager::open("test_mgr6.db").unwrap();
+            assert_eq!(Some("elf"), db.entry(2).unwrap().file_type.as_ref().map(|t| t.as_str()));
+        }
+        cleanup("test_mgr6");
+    }
+
+    #[test]
+    fn negative_cache() {
+        {
+            // max index size of 1 byte: every shard is full after one file
+            let mut db = BinacleManager::create("test_mgr7.db", false, 1, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "", 1, false);
+
+            for _ in 0 .. 3 {
+                assert!(db.search(b"LoadLibrary").unwrap().is_empty());
+                assert!(db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap().ids.is_empty());
+            }
+            let stats = db.cache_stats();
+            assert_eq!(1, stats.inserts);
+            assert_eq!(5, stats.hits);
+
+            // a new shard, absent from the cache, gets the n-grams
+            db.insert_reader(&b"LoadLibraryA"[..], "", 2, false);
+            assert_eq!(vec![2], db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap().ids);
+        }
+        {
+            // the live shard is never cached
+            let mut db = BinacleManager::create("test_mgr8.db", false, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "", 1, false);
+            assert!(db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap().ids.is_empty());
+            db.insert_reader(&b"LoadLibraryA"[..], "", 2, false);
+            assert_eq!(vec![2], db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap().ids);
+            assert_eq!(CacheStats::default(), db.cache_stats());
+        }
+        cleanup("test_mgr7");
+        let _ = remove_file("test_mgr7.db_index1.db");
+        let _ = remove_file("test_mgr7.db_index1.meta");
+        let _ = remove_file("test_mgr7.db_index1.bitmap");
+        cleanup("test_mgr8");
+    }
+
+    #[test]
+    fn replaced_shard() {
+        {
+            let mut db = BinacleManager::create("test_mgr17.db", false, 1, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "", 1, false);
+            assert!(db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap().ids.is_empty());
+            assert_eq!(1, db.cache_stats().inserts);
+            let stale = db.open_shards().remove(0).unwrap();
+
+            // another shard renamed over the full one
+            {
+                let mut other = BinacleFile::create("test_mgr17.new", 5, 6, 16).unwrap();
+                other.insert_reader(&b"LoadLibraryA"[..], 1).unwrap();
+            }
+            ::std::fs::rename("test_mgr17.new", "test_mgr17.db_index0.db").unwrap();
+            // as a compaction does, or its size check fails the shard
+            db.record_checksum(0).unwrap();
+
+            // the cached absent n-grams are dropped, the handle opened
+            // before the rename is not read
+            assert_eq!(vec![1], db.search_shard(0, &stale, b"LoadLibrary").unwrap().into_iter().collect::<Vec<u32>>());
+            assert_eq!(1, db.cache_stats().replaced);
+            assert_eq!(vec![1], db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap().ids);
+            assert_eq!(1, db.cache_stats().replaced);
+
+            // a shard that can not be checked fails, it is reported by the search
+            remove_file("test_mgr17.db_index0.db").unwrap();
+            assert!(db.search_shard(0, &stale, b"LoadLibrary").is_err());
+        }
+        cleanup("test_mgr17");
+        let _ = remove_file("test_mgr17.meta");
+        let _ = remove_file("test_mgr17.bitmap");
+        let _ = remove_file("test_mgr17.new.lockinfo");
+    }
+
+    #[test]
+    fn min_reliable_pattern_len() {
+        for (n, &(alignment, ngram_size)) in [(6, 16), (4, 24)].iter().enumerate() {
+            let name = format!("test_mgr{}", 23 + n);
+            {
+                let mut db = BinacleManager::create(&format!("{}.db", name), false, 1 << 30, alignment, ngram_size).unwrap();
+                db.insert_reader(&b"call GetProcAddress"[..], "", 1, false);
+                let len = db.min_reliable_pattern_len();
+                assert_eq!(4, len);
+                // every window of this length is found
+                for window in b"call GetProcAddress".windows(len) {
+                    assert!(db.search(window).unwrap().contains(&1), "{:?}", window);
+                }
+                assert!(db.search(&b"call"[.. len - 1]).is_err());
+            }
+            cleanup(&name);
+        }
+    }
+
+    #[test]
+    fn shard_checksums() {
+        let checked = |db: &mut BinacleManager| db.verify_shards().into_iter().map(|c| c.ok()).collect::<Vec<_>>();
+        let truncate = |path: &str, by: u64| {
+            let file = OpenOptions::new().write(true).open(path).unwrap();
+            let len = file.metadata().unwrap().len();
+            file.set_len(len - by).unwrap();
+        };
+        {
+            let mut db = BinacleManager::create("test_mgr22.db", false, 1, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "", 1, false);
+            let size = metadata("test_mgr22.db_index0.db").unwrap().len();
+            assert_eq!(Some(size), db.shard_checksum(0).map(|c| c.size));
+            // a live shard has no checksum
+            drop(db.add_shard(false).unwrap());
+            assert_eq!(None, db.shard_checksum(1));
+            assert_eq!(vec![Some(true), Some(false)], checked(&mut db));
+
+            // same size, another content: only the hash tells
+            let mut content = Vec::new();
+            File::open("test_mgr22.db_index0.db").unwrap().read_to_end(&mut content).unwrap();
+            let last = content.len() - 1;
+            content[last] ^= 0xff;
+            File::create("test_mgr22.db_index0.db").unwrap().write_all(&content).unwrap();
+            assert_eq!(vec![None, Some(false)], checked(&mut db));
+            db.set_verify_hashes(true);
+            let response = db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap();
+            assert!(response.ids.is_empty());
+            assert!(response.warnings[0].error.contains("modified"), "{}", response.warnings[0].error);
+            db.set_verify_hashes(false);
+
+            truncate("test_mgr22.db_index0.db", 1);
+            truncate("test_mgr22.db_index1.db", 1);
+        }
+
+        // detected when the shards are opened, with the checksums read again
+        let mut db = BinacleManager::open("test_mgr22.db").unwrap();
+        let response = db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap();
+        assert!(response.ids.is_empty());
+        assert_eq!(2, response.warnings.len());
+        assert!(response.warnings[0].error.contains("recorded: truncated or modified"), "{}", response.warnings[0].error);
+        assert!(response.warnings[1].error.contains("its meta tells"), "{}", response.warnings[1].error);
+        assert!(db.run(&SearchRequest::pattern(b"GetProcAddress").strict()).is_err());
+        assert_eq!(vec![None, None], checked(&mut db));
+        drop(db);
+        cleanup("test_mgr22");
+    }
+
+    #[test]
+    fn sealed() {
+        let found = |db: &mut BinacleManager, pattern: &[u8]| {
+            let mut ids: Vec<u32> = db.search(pattern).unwrap().into_iter().collect();
+            ids.sort();
+            ids
+        };
+        {
+            let mut db = BinacleManager::create("test_mgr25.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+            db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "b.bin", 2, true);
+            db.seal().unwrap();
+            assert!(db.is_sealed());
+            assert!(db.shard_is_finalized(0));
+            assert!(db.shard_checksum(0).is_some());
+
+            let refused = |outcome: InsertOutcome| match outcome {
+                InsertOutcome::Failed(e) => e.kind() == ErrorKind::PermissionDenied,
+                _ => false,
+            };
+            assert!(refused(db.insert_reader(&b"LoadLibraryW"[..], "c.bin", 3, true)));
+            assert_eq!(ErrorKind::PermissionDenied, db.set_label(1, "first").unwrap_err().kind());
+            assert!(db.add_note(1, "sealed").is_err());
+            assert!(db.add_shard(false).is_err());
+            assert_eq!(vec![1, 2], found(&mut db, b"GetProcAddress"));
+            db.close().unwrap();
+        }
+        let map_written = metadata("test_mgr25.map").unwrap().modified().unwrap();
+
+        // opened by two readers, it is not unsealed under them
+        let mut db = BinacleManager::open("test_mgr25.db").unwrap();
+        assert!(db.is_sealed());
+        assert_eq!(vec![2], found(&mut db, b"LoadLibraryA"));
+        {
+            let mut reader = BinacleManager::open("test_mgr25.db").unwrap();
+            assert_eq!(vec![1, 2], found(&mut reader, b"GetProcAddress"));
+            db.set_lock_wait(Duration::from_millis(50));
+            assert_eq!(ErrorKind::TimedOut, db.unseal().unwrap_err().kind());
+            assert!(db.is_sealed());
+        }
+        assert_eq!(map_written, metadata("test_mgr25.map").unwrap().modified().unwrap());
+
+        db.unseal().unwrap();
+        assert!(db.unseal().is_err());
+        match db.insert_reader(&b"LoadLibraryW"[..], "c.bin", 3, true) {
+            InsertOutcome::Inserted(3) => {},
+            o => panic!("unexpected outcome {:?}", o),
+        }
+        // the sealed shard stays finalized, the insertions go to a new one
+        assert_eq!(2, db.nb_index());
+        db.close().unwrap();
+
+        let mut db = BinacleManager::open("test_mgr25.db").unwrap();
+        assert!(!db.is_sealed());
+        assert_eq!(vec![2, 3], found(&mut db, b"LoadLibrary"));
+        drop(db);
+        cleanup("test_mgr25");
+    }
+
+    // rewrite the fields of a JSON meta, as another version would
+    fn edit_meta<F: FnOnce(&mut BTreeMap<String, json::Json>)>(path: &str, f: F) {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        let mut fields = match json::Json::from_str(&content).unwrap() {
+            json::Json::Object(fields) => fields,
+            _ => panic!("{} is not an object", path),
+        };
+        f(&mut fields);
+        File::create(path).unwrap().write_all(json::Json::Object(fields).to_string().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn other_versions() {
+        let found = |db: &mut BinacleManager, pattern: &[u8]| {
+            let mut ids: Vec<u32> = db.search(pattern).unwrap().into_iter().collect();
+            ids.sort();
+            ids
+        };
+        {
+            let mut db = BinacleManager::create("test_mgr26.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+            db.close().unwrap();
+        }
+
+        // an older database: the fields added since are missing
+        edit_meta("test_mgr26.db", |meta| {
+            meta.remove("sealed");
+            meta.remove("translate");
+        });
+        edit_meta("test_mgr26.db_index0.meta", |meta| {
+            meta.remove("flags");
+        });
+        {
+            let mut db = BinacleManager::open("test_mgr26.db").unwrap();
+            db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "b.bin", 2, true);
+            assert_eq!(vec![1, 2], found(&mut db, b"GetProcAddress"));
+            db.close().unwrap();
+        }
+
+        // a newer database: unknown fields and an optional feature
+        edit_meta("test_mgr26.db", |meta| {
+            meta.insert(String::from("retention"), json::Json::from_str(r#"{"days":30}"#).unwrap());
+        });
+        edit_meta("test_mgr26.db_index0.meta", |meta| {
+            meta.insert(String::from("flags"), json::Json::U64(1 << 5));
+            meta.insert(String::from("zone_maps"), json::Json::U64(12));
+        });
+        {
+            let mut db = BinacleManager::open("test_mgr26.db").unwrap();
+            assert_eq!(vec![2], found(&mut db, b"LoadLibraryA"));
+            // read only: the shard is not written by this build
+            match db.insert_reader(&b"LoadLibraryW"[..], "c.bin", 3, true) {
+                InsertOutcome::Failed(ref e) => assert!(e.to_string().contains("optional flags 0x20"), "{}", e),
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            // the meta is written again, with the unknown field
+            db.tombstone(1);
+            db.close().unwrap();
+        }
+        let mut content = String::new();
+        File::open("test_mgr26.db").unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.contains(r#""retention":{"days":30}"#) && content.contains(r#""tombstones":[1]"#), "{}", content);
+        content.clear();
+        File::open("test_mgr26.db_index0.meta").unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.contains(r#""zone_maps":12"#) && content.contains(r#""flags":32"#), "{}", content);
+
+        // a required feature: the shard is refused, with the reason
+        edit_meta("test_mgr26.db_index0.meta", |meta| {
+            meta.insert(String::from("flags"), json::Json::U64(1 << 33));
+        });
+        let mut db = BinacleManager::open("test_mgr26.db").unwrap();
+        let err = db.open_shards().into_iter().next().unwrap().err().unwrap();
+        assert!(err.to_string().contains("needs features unknown to this version of Binacle"), "{}", err);
+        drop(db);
+        cleanup("test_mgr26");
+    }
+
+    #[test]
+    fn deleted_ids() {
+        let found = |db: &mut BinacleManager, pattern: &[u8]| {
+            let mut ids: Vec<u32> = db.search(pattern).unwrap().into_iter().collect();
+            ids.sort();
+            ids
+        };
+        File::create("test_mgr27.bin").unwrap().write_all(b"GetProcAddress kernel32.dll").unwrap();
+        {
+            let mut db = BinacleManager::create("test_mgr27.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+            assert_eq!(2, db.insert_file("test_mgr27.bin", 2, true).unwrap());
+            db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "c.bin", 3, true);
+            db.tombstone(3);
+
+            // the file is still there: only the lists of its n-grams
+            let deletion = db.delete(2).unwrap();
+            assert!(deletion.from_file && deletion.mapped);
+            assert!(deletion.lists[0] > 0);
+            // 3 is still indexed, but tombstoned
+            assert_eq!(vec![1], found(&mut db, b"GetProcAddress"));
+            assert!(found(&mut db, b"kernel32").is_empty());
+            // its content was not found again: all the lists
+            let deletion = db.delete(3).unwrap();
+            assert!(!deletion.from_file);
+            assert!(!db.is_tombstoned(3));
+            assert!(db.delete(3).unwrap_err().kind() == ErrorKind::NotFound);
+            assert_eq!(1, db.nb_file());
+            db.close().unwrap();
+        }
+        {
+            let mut db = BinacleManager::open("test_mgr27.db").unwrap();
+            assert!(db.entry(2).is_none() && db.entry(3).is_none());
+            assert_eq!(1, db.nb_file());
+            assert!(db.index_ids().unwrap().into_iter().eq(vec![1]));
+            // the lists rewritten take the next ids
+            db.insert_reader(&b"GetProcAddress LoadLibraryA kernel32.dll"[..], "d.bin", 4, true);
+            assert_eq!(vec![1, 4], found(&mut db, b"GetProcAddress"));
+            assert_eq!(vec![4], found(&mut db, b"LoadLibraryA"));
+            drop(db);
+        }
+        cleanup("test_mgr27");
+        let _ = remove_file("test_mgr27.bin");
+    }
+
+    #[test]
+    fn pread_shards() {
+        let found = |db: &mut BinacleManager, pattern: &[u8]| {
+            let mut ids: Vec<u32> = db.search(pattern).unwrap().into_iter().collect();
+            ids.sort();
+            ids
+        };
+        {
+            let mut db = BinacleManager::create("test_mgr28.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+            db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "b.bin", 2, true);
+            db.insert_reader(&b"kernel32.dll LoadLibraryA"[..], "c.bin", 3, true);
+            let patterns: [&[u8]; 3] = [b"GetProcAddress", b"LoadLibraryA", b"absent"];
+            let mapped: Vec<Vec<u32>> = patterns.iter().map(|p| found(&mut db, p)).collect();
+            db.set_read_path(ReadPath::Pread);
+            let read: Vec<Vec<u32>> = patterns.iter().map(|p| found(&mut db, p)).collect();
+            assert_eq!(mapped, read);
+            assert_eq!(vec![2, 3], read[1]);
+
+            // a read failing in the middle of the query fails the shard only
+            let shard = db.open_shards().remove(0).unwrap();
+            OpenOptions::new().write(true).open(&shard.path).unwrap().set_len(shard.header_len()).unwrap();
+            assert!(db.search_shard(0, &shard, b"LoadLibraryA").is_err());
+            drop(shard);
+            drop(db);
+        }
+        cleanup("test_mgr28");
+    }
+
+    #[test]
+    fn any_pattern() {
+        {
+            let mut db = BinacleManager::create("test_mgr29.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+            db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "b.bin", 2, true);
+            db.insert_reader(&b"kernel32.dll LoadLibraryA"[..], "c.bin", 3, true);
+            let patterns = vec![b"LoadLibraryA".to_vec(), b"absent".to_vec(), b"GetProcAddress".to_vec()];
+            let found = db.search_any(&patterns).unwrap();
+            assert_eq!(3, found.len());
+            assert_eq!(Some(&vec![2]), found.get(&1));
+            assert_eq!(Some(&vec![0, 2]), found.get(&2));
+            assert_eq!(Some(&vec![0]), found.get(&3));
+            assert!(db.shard_warnings().is_empty());
+
+            db.tombstone(2);
+            assert!(!db.search_any(&patterns).unwrap().contains_key(&2));
+            let err = db.search_any(&[b"kernel32".to_vec(), b"dll".to_vec()]).unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+        cleanup("test_mgr29");
+    }
+
+    #[test]
+    fn absorbed_index() {
+        let contents: [&[u8]; 4] = [b"GetProcAddress LoadLibraryA", b"LoadLibraryW kernel32.dll",
+                                     b"\xc0\xa8\x01\x01 GetProcAddress", b"This program cannot be run in DOS mode"];
+        let patterns: [&[u8]; 6] = [b"GetProc", b"LoadLibrary", b"kernel32", b"\xc0\xa8\x01\x01", b"DOS mode", b"absent"];
+        {
+            let mut src = BinacleManager::create("test_mgr18.db", false, 1 << 30, 6, 20).unwrap();
+            for (i, content) in contents.iter().enumerate() {
+                src.insert_reader(*content, "", i as u32 + 1, false);
+            }
+            let shard = src.open_indexes().unwrap().remove(0);
+            assert_eq!(shard.iter_postings().count(), {
+                let mut n = 0;
+                shard.for_each_chain(|_, _| n += 1);
+                n
+            });
+
+            let mut same = BinacleManager::create("test_mgr19.db", false, 1 << 30, 4, 20).unwrap();
+            let mut coarser = BinacleManager::create("test_mgr20.db", false, 1 << 30, 6, 16).unwrap();
+            same.absorb_index(&shard, 0).unwrap();
+            coarser.absorb_index(&shard, 0).unwrap();
+            assert_eq!(4, same.nb_file());
+            assert_eq!(4, same.last_id());
+            for pattern in patterns.iter() {
+                let expected = src.search(pattern).unwrap();
+                assert_eq!(expected, same.search(pattern).unwrap());
+                assert!(coarser.search(pattern).unwrap().is_superset(&expected));
+            }
+
+            // the finer n-grams can not be recovered
+            let mut finer = BinacleManager::create("test_mgr21.db", false, 1 << 30, 6, 24).unwrap();
+            assert_eq!(ErrorKind::InvalidInput, finer.absorb_index(&shard, 0).unwrap_err().kind());
+
+            // after the ids of the destination
+            assert_eq!(ErrorKind::InvalidInput, same.absorb_index(&shard, 2).unwrap_err().kind());
+            same.absorb_index(&shard, 4).unwrap();
+            let mut ids: Vec<u32> = same.search(b"GetProc").unwrap().into_iter().collect();
+            ids.sort();
+            assert_eq!(vec![1, 3, 5, 7], ids);
+            assert_eq!(8, same.last_id());
+
+            // insertions go on after it
+            same.insert_reader(&b"GetProcAddress"[..], "", 9, false);
+            assert!(same.search(b"GetProc").unwrap().contains(&9));
+        }
+        for name in &["test_mgr18", "test_mgr19", "test_mgr20", "test_mgr21"] {
+            cleanup(name);
+        }
+    }
+
+    // the map, labels and shards of another database, its ids shifted
+    #[test]
+    fn merged_databases() {
+        {
+            let mut dst = BinacleManager::create("test_mgr34.db", true, 1 << 30, 6, 16).unwrap();
+            dst.insert_file("Cargo.toml", 1, true).unwrap();
+            dst.insert_file("Cargo.lock", 2, true).unwrap();
+            dst.set_label(2, "lock").unwrap();
+            let mut src = BinacleManager::create("test_mgr35.db", true, 1 << 30, 6, 16).unwrap();
+            src.insert_file("src/lib.rs", 1, true).unwrap();
+            src.insert_file("src/main.rs", 3, true).unwrap();
+            src.set_label(3, "main").unwrap();
+            drop(src);
+
+            // the ids collide without an offset, nothing is written, the
+            // smallest offset putting 1 after 2 is told
+            let e = dst.merge_from("test_mgr35.db", 0).unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, e.kind());
+            assert!(e.to_string().ends_with("merge with an id offset of 2 at least"), "{}", e);
+            let e = dst.merge_from("test_mgr35.db", 1).unwrap_err();
+            assert!(e.to_string().ends_with("merge with an id offset of 2 at least"), "{}", e);
+            assert_eq!(1, dst.nb_index());
+            assert_eq!(Some("Cargo.toml"), dst.path_of(1));
+
+            let merge = dst.merge_from("test_mgr35.db", 2).unwrap();
+            assert_eq!(Merge { shards: 1, files: 2, ids: Some((3, 5)) }, merge);
+            assert_eq!(4, dst.nb_file());
+            assert_eq!(5, dst.last_id());
+            assert_eq!(Some("src/lib.rs"), dst.path_of(3));
+            assert_eq!(Some(5), dst.id_of_label("main"));
+            let ids: HashSet<u32> = dst.search(b"fn main").unwrap().into_iter().collect();
+            assert!(ids.contains(&5));
+            assert!(!ids.contains(&1));
+
+            // the label is now taken
+            let mut again = BinacleManager::create("test_mgr36.db", true, 1 << 30, 6, 16).unwrap();
+            again.insert_file("src/main.rs", 10, true).unwrap();
+            again.set_label(10, "main").unwrap();
+            drop(again);
+            assert_eq!(ErrorKind::InvalidInput, dst.merge_from("test_mgr36.db", 0).unwrap_err().kind());
+        }
+        // kept in the map and the meta
+        let db = BinacleManager::open("test_mgr34.db").unwrap();
+        assert_eq!(4, db.nb_file());
+        assert_eq!(Some("src/main.rs"), db.path_of(5));
+        assert_eq!(Some(2), db.id_of_label("lock"));
+        drop(db);
+        for name in &["test_mgr34", "test_mgr35", "test_mgr36"] {
+            cleanup(name);
+        }
+    }
+
+    #[test]
+    fn durability_flushes() {
+
+        use std::sync::{Arc, Mutex};
+
+        let policies = vec![
+            (Durability::None, 0),
+            (Durability::Periodic { files: 2, seconds: 0 }, 1),
+            (Durability::PerFile, 3),
+        ];
+
+        for (policy, nb_sync) in policies {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            {
+                let mut db = BinacleManager::create("test_mgr9.db", true, 1 << 30, 6, 16).unwrap();
+                db.set_durability(policy);
+                let e = events.clone();
+                db.set_flush_hook(Box::new(move |event| e.lock().unwrap().push(event)));
+
+                for id in 1 .. 4 {
+                    db.insert_reader(&b"GetProcAddress"[..], "sample", id, false);
+                }
+            }
+            let mut expected: Vec<FlushEvent> = Vec::new();
+            for _ in 0 .. nb_sync {
+                expected.extend(&[FlushEvent::Index, FlushEvent::Map, FlushEvent::Meta]);
+            }
+            assert_eq!(expected, *events.lock().unwrap(), "{}", policy);
+            cleanup("test_mgr9");
+        }
+    }
+
+    #[test]
+    fn failed_sync_not_counted() {
+
+        use std::fs::{create_dir, remove_dir};
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let mut db = BinacleManager::create("test_mgr37.db", true, 1 << 30, 6, 16).unwrap();
+            db.set_durability(Durability::PerFile);
+            let e = events.clone();
+            db.set_flush_hook(Box::new(move |event| e.lock().unwrap().push(event)));
+
+            // the label is synced with the insertion
+            match db.insert_labeled("Cargo.toml", 1, "5f2b-uuid", false) {
+                InsertOutcome::Inserted(1) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(vec![FlushEvent::Index, FlushEvent::Map, FlushEvent::Meta], *events.lock().unwrap());
+
+            // the map can not be written
+            create_dir("test_mgr37.map.tmp").unwrap();
+            match db.insert_reader(&b"GetProcAddress"[..], "b.bin", 2, false) {
+                InsertOutcome::Failed(_) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(1, db.nb_file());
+            assert!(db.path_of(2).is_none());
+            assert!(db.is_tombstoned(2));
+            assert_eq!(2, db.last_id());
+            remove_dir("test_mgr37.map.tmp").unwrap();
+
+            match db.insert_reader(&b"GetProcAddress"[..], "c.bin", 3, false) {
+                InsertOutcome::Inserted(3) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(2, db.nb_file());
+        }
+        {
+            let mut db = BinacleManager::open("test_mgr37.db").unwrap();
+            assert_eq!(Some(1), db.id_of_label("5f2b-uuid"));
+            assert_eq!(vec![3], db.search(b"GetProcAddress").unwrap().into_iter().collect::<Vec<u32>>());
+        }
+        cleanup("test_mgr37");
+    }
+
+    #[test]
+    fn deleted_tombstoned_id() {
+        File::create("test_mgr38.bin").unwrap().write_all(b"GetProcAddress kernel32.dll").unwrap();
+        let mut db = BinacleManager::create("test_mgr38.db", true, 1 << 30, 6, 16).unwrap();
+        db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+        // its n-grams are in the index, but it was never counted
+        match db.insert_verified("test_mgr38.bin", 2, &[0; 32], true) {
+            InsertOutcome::Failed(_) => {},
+            o => panic!("unexpected outcome {:?}", o),
+        }
+        assert!(db.is_tombstoned(2));
+        assert_eq!(1, db.nb_file());
+
+        let deletion = db.delete(2).unwrap();
+        assert!(!deletion.mapped && deletion.lists[0] > 0);
+        assert!(!db.is_tombstoned(2));
+        assert_eq!(1, db.nb_file());
+        db.close().unwrap();
+        cleanup("test_mgr38");
+        let _ = remove_file("test_mgr38.bin");
+    }
+
+    #[test]
+    fn map_memory_cap() {
+        {
+            let mut db = BinacleManager::create("test_mgr10.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "a.exe", 1, false);
+            db.insert_reader(&b"LoadLibraryA"[..], "b.exe", 2, true);
+        }
+
+        let low = MapPolicy { cap: Some(10), lazy: false };
+        assert!(BinacleManager::open_with("test_mgr10.db", low).is_err());
+
+        let high = MapPolicy { cap: Some(1 << 30), lazy: true };
+        assert!(!BinacleManager::open_with("test_mgr10.db", high).unwrap().is_lazy_map());
+
+        {
+            let lazy = MapPolicy { cap: Some(10), lazy: true };
+            let mut db = BinacleManager::open_with("test_mgr10.db", lazy).unwrap();
+            assert!(db.is_lazy_map());
+            assert_eq!(None, db.path_of(2));
+
+            let response = db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap();
+            assert_eq!(vec!["b.exe"], response.entries);
+            assert_eq!(Some("b.exe"), db.path_of(2));
+            assert_eq!(None, db.path_of(1));
+
+            match db.insert_reader(&b"other"[..], "c.exe", 3, true) {
+                InsertOutcome::Failed(_) => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+        }
+
+        // the map was not overwritten by the partial one
+        let db = BinacleManager::open("test_mgr10.db").unwrap();
+        assert_eq!(Some("a.exe"), db.path_of(1));
+        drop(db);
+        cleanup("test_mgr10");
+    }
+
+    #[test]
+    fn notes() {
+        {
+            let mut db = BinacleManager::create("test_mgr16.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "a.exe", 1, false);
+            db.insert_reader(&b"LoadLibraryA"[..], "b.exe", 2, true);
+            db.add_note(1, "confirmed Emotet dropper\nsee \"case 42\"").unwrap();
+            db.add_note(1, "faux positif pour la r\u{e8}gle X").unwrap();
+            assert_eq!(ErrorKind::NotFound, db.add_note(3, "nope").unwrap_err().kind());
+            db.flush_map().unwrap();
+        }
+
+        let mut db = BinacleManager::open("test_mgr16.db").unwrap();
+        let notes = db.entry(1).unwrap().notes.clone().unwrap();
+        assert_eq!(2, notes.len());
+        assert_eq!("confirmed Emotet dropper\nsee \"case 42\"", notes[0].text);
+        assert!(notes[0].date.is_some());
+        assert!(db.entry(1).unwrap().has_note_containing("r\u{e8}gle"));
+        assert!(!db.entry(2).unwrap().has_note_containing(""));
+        let noted: Vec<u32> = db.entries().filter(|&(_, e)| e.has_note_containing("Emotet")).map(|(id, _)| id).collect();
+        assert_eq!(vec![1], noted);
+
+        // no date in a deterministic database
+        db.set_deterministic(true);
+        db.add_note(2, "later").unwrap();
+        assert_eq!(None, db.entry(2).unwrap().notes.as_ref().unwrap()[0].date);
+        db.close().unwrap();
+
+        // and read by the lazy map
+        let lazy = MapPolicy { cap: Some(10), lazy: true };
+        let mut db = BinacleManager::open_with("test_mgr16.db", lazy).unwrap();
+        let response = db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap();
+        assert_eq!(vec!["a.exe"], response.entries);
+        assert_eq!(Some(&notes), db.entry(1).unwrap().notes.as_ref());
+        assert!(db.add_note(1, "read only").is_err());
+        drop(db);
+        cleanup("test_mgr16");
+    }
+
+    #[test]
+    fn reload_new_shards() {
+        {
+            let mut db = BinacleManager::create("test_mgr11.db", true, 1, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "a.exe", 1, true);
+        }
+
+        let mut reader = BinacleManager::open("test_mgr11.db").unwrap();
+        assert!(!reader.reload().unwrap());
+        assert!(reader.search(b"LoadLibraryA").unwrap().is_empty());
+
+        {
+            let mut writer = BinacleManager::open("test_mgr11.db").unwrap();
+            writer.insert_reader(&b"LoadLibraryA"[..], "b.exe", 2, true);
+        }
+
+        assert!(reader.reload().unwrap());
+        assert!(reader.last_reload().is_some());
+        assert_eq!(2, reader.nb_index());
+        assert_eq!(vec![2], reader.search(b"LoadLibraryA").unwrap().into_iter().collect::<Vec<u32>>());
+        assert_eq!(Some("b.exe"), reader.path_of(2));
+        assert!(!reader.reload().unwrap());
+
+        drop(reader);
+        cleanup("test_mgr11");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn contended_lock() {
+        {
+            let mut db = BinacleManager::create("test_mgr12.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "a.exe", 1, true);
+            db.close().unwrap();
+        }
+
+        let mut db = BinacleManager::open("test_mgr12.db").unwrap();
+        let mut unwound = BinacleManager::open("test_mgr12.db").unwrap();
+
+        // another process holds the database
+        let holder = OpenOptions::new().read(true).open("test_mgr12.db").unwrap();
+        holder.lock_exclusive().unwrap();
+
+        db.set_lock_wait(Duration::from_millis(50));
+        db.insert_reader(&b"LoadLibraryA"[..], "b.exe", 2, true);
+        assert_eq!(ErrorKind::TimedOut, db.close().unwrap_err().kind());
+
+        // the error unwound is the one seen, the Drop only prints its own
+        unwound.set_lock_wait(Duration::from_millis(50));
+        let res = panic::catch_unwind(AssertUnwindSafe(move || {
+            unwound.insert_reader(&b"VirtualAlloc"[..], "c.exe", 3, true);
+            panic!("primary error");
+        }));
+        assert_eq!("primary error", panic_message(&res.unwrap_err()));
+
+        holder.unlock().unwrap();
+        let mut db = BinacleManager::open("test_mgr12.db").unwrap();
+        assert_eq!(1, db.nb_index());
+        assert!(db.search(b"GetProcAddress").unwrap().contains(&1));
+        db.insert_reader(&b"LoadLibraryA"[..], "b.exe", 2, true);
+        db.close().unwrap();
+
+        drop(holder);
+        cleanup("test_mgr12");
+    }
+
+    #[test]
+    fn in_memory() {
+
+        let mut db = BinacleManager::create_in_memory(true, 1 << 30, 6, 16).unwrap();
+        assert!(db.is_temporary());
+        let dir = PathBuf::from(&db.db_path).parent().unwrap().to_path_buf();
+        for id in 1 .. 200 {
+            let content = format!("artifact {} GetProcAddress {}", id, if id % 2 == 0 { "LoadLibraryA" } else { "" });
+            db.insert_reader(content.as_bytes(), &format!("ci/artifact{}.bin", id), id, true);
+        }
+        let even = db.run(&SearchRequest::pattern(b"LoadLibraryA")).unwrap();
+        assert_eq!(99, even.total);
+        assert_eq!(199, db.search(b"GetProcAddress").unwrap().len());
+        assert_eq!(Some("ci/artifact3.bin"), db.path_of(3));
+
+        // nothing was written out of the temporary directory
+        assert!(metadata("tmp.db").is_err());
+
+        db.persist("test_mgr13.db").unwrap();
+        assert_eq!(ErrorKind::AlreadyExists, db.persist("test_mgr13.db").unwrap_err().kind());
+        // still usable
+        db.insert_reader(&b"late LoadLibraryA"[..], "ci/late.bin", 500, true);
+        db.close().unwrap();
+        assert!(metadata(&dir).is_err());
+
+        let mut persisted = BinacleManager::open("test_mgr13.db").unwrap();
+        assert!(!persisted.is_temporary());
+        assert_eq!(even.ids, persisted.run(&SearchRequest::pattern(b"LoadLibraryA")).unwrap().ids);
+        assert_eq!(Some("ci/artifact3.bin"), persisted.path_of(3));
+        persisted.insert_reader(&b"more LoadLibraryA"[..], "b.bin", 600, true);
+        assert!(persisted.search(b"LoadLibraryA").unwrap().contains(&600));
+        drop(persisted);
+
+        // removed by the Drop too
+        let db = BinacleManager::create_in_memory(false, 1 << 30, 6, 16).unwrap();
+        let dir = PathBuf::from(&db.db_path).parent().unwrap().to_path_buf();
+        assert!(metadata(&dir).is_ok());
+        drop(db);
+        assert!(metadata(&dir).is_err());
+
+        cleanup("test_mgr13");
+    }
+
+    #[test]
+    fn max_index_size_addressable() {
+        // 2^44 bytes with offset_size 5 and alignment 4
+        let e = BinacleManager::create("test_mgr15.db", true, (1 << 44) + 1, 4, 16).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+        assert!(metadata("test_mgr15.db").is_err());
+
+        let db = BinacleManager::create("test_mgr15.db", true, 1 << 44, 4, 16).unwrap();
+        assert_eq!(1 << 44, db.max_addressable_size());
+        drop(db);
+        cleanup("test_mgr15");
+    }
+
+    // reads 4 GB, cargo test --release -- --ignored over_4gb
+    #[test]
+    #[ignore]
+    fn over_4gb() {
+
+        let size = (1u64 << 32) + 4096;
+        {
+            // sparse, the content is at the end
+            let mut file = File::create("test_mgr14.bin").unwrap();
+            file.seek(SeekFrom::Start(size - 14)).unwrap();
+            file.write_all(b"GetProcAddress").unwrap();
+            assert_eq!(size, file.metadata().unwrap().len());
+        }
+        {
+            let mut db = BinacleManager::create("test_mgr14.db", true, 1 << 30, 6, 16).unwrap();
+            assert_eq!(1, db.insert_file("test_mgr14.bin", 1, true).unwrap());
+        }
+
+        let mut db = BinacleManager::open("test_mgr14.db").unwrap();
+        assert_eq!(size, db.entry(1).unwrap().provenance.as_ref().unwrap().size);
+        assert_eq!(size as f64, db.open_shards().remove(0).unwrap().average_size());
+        assert_eq!(vec![1], db.search(b"GetProcAddress").unwrap().into_iter().collect::<Vec<u32>>());
+        drop(db);
+        cleanup("test_mgr14");
+        let _ = remove_file("test_mgr14.bin");
+    }
+}
@@ -0,0 +1,370 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::{self, File, OpenOptions, metadata};
+use std::io::*;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use walkdir::WalkDir;
+
+use binacle::read_ngrams;
+use binacle_manager::{BinacleManager, MapEntry};
+use hash::{self, HashReader};
+use magic::HeadReader;
+use manifest;
+use provenance::Provenance;
+use translate::{Translation, TranslateReader};
+
+// Initial build of a database over a known corpus, without the incremental
+// allocator. The (ngram, id) pairs of all the files are collected, sorted in
+// runs of the memory given and spilled to temporary files, then merged: the
+// merge gives each n-gram with all its ids, in header order, and its list is
+// written once at its final size. The index has no chain (but for lists over
+// BULK_LIST_BYTES) and is a normal index, the insertions can go on after it.
+// The files can be read by several threads, the ids still follow the sources.
+
+
+// memory used by the pairs before a run is spilled
+pub const DEFAULT_BULK_MEMORY: u64 = 512 << 20;
+
+// a file to index, with what the manifest tells of it
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub path: String,
+    pub sha256: Option<[u8; 32]>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BulkReport {
+    pub files: u32,
+    // path and reason
+    pub skipped: Vec<(String, String)>,
+    // sorted runs spilled to the disk
+    pub runs: usize,
+    pub postings: u64,
+    // bytes of the index
+    pub size: u64,
+}
+
+// the files of a directory (walked in name order) or of a manifest
+pub fn sources(from: &str) -> Result<Vec<Source>> {
+
+    let meta = try!(metadata(from));
+    if meta.is_dir() {
+        let mut sources = Vec::new();
+        for entry in WalkDir::new(from).sort_by_file_name() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            match metadata(entry.path()) {
+                Ok(ref m) if m.is_file() => {},
+                _ => continue,
+            }
+            if let Some(path) = entry.path().to_str() {
+                sources.push(Source { path: String::from(path), sha256: None, tags: None });
+            }
+        }
+        return Ok(sources);
+    }
+
+    let mut sources = Vec::new();
+    for entry in try!(manifest::read_entries(from)) {
+        sources.push(Source {
+            sha256: Some(try!(hash::parse_sha256(&entry.sha256))),
+            path: entry.path,
+            tags: entry.tags,
+        });
+    }
+    Ok(sources)
+}
+
+// the distinct reduced n-grams of a content
+fn content_ngrams<R: Read>(reader: R, ngram_size: u8, ngrams: &mut HashSet<u32>) -> Result<()> {
+
+    let mask = ((1u64 << ngram_size) - 1) as u32;
+    try!(read_ngrams(reader, |ngram| {
+        ngrams.insert(ngram & mask);
+        Ok(())
+    }));
+    Ok(())
+}
+
+// what pass one keeps of a file
+struct Extracted {
+    ngrams: HashSet<u32>,
+    head: Vec<u8>,
+    sha256: [u8; 32],
+    size: u64,
+}
+
+fn extract(path: &str, ngram_size: u8, translation: Option<&Translation>) -> Result<Extracted> {
+
+    let file = try!(OpenOptions::new().read(true).open(path));
+    let mut reader = HashReader::new(HeadReader::new(file));
+    let mut ngrams = HashSet::new();
+    try!(content_ngrams(TranslateReader::new(&mut reader, translation), ngram_size, &mut ngrams));
+    let head = reader.get_ref().head().to_vec();
+    let (sha256, size) = try!(reader.finish_len());
+    Ok(Extracted { ngrams: ngrams, head: head, sha256: sha256, size: size })
+}
+
+// the files of the sources, extracted by jobs threads, in the order of the
+// sources: a batch of a few files per thread is in memory at a time
+fn extract_all<F>(sources: &[Source], ngram_size: u8, translation: Option<&Translation>, jobs: usize, mut f: F) -> Result<()>
+    where F: FnMut(&Source, Result<Extracted>) -> Result<()> {
+
+    if jobs <= 1 {
+        for source in sources {
+            try!(f(source, extract(&source.path, ngram_size, translation)));
+        }
+        return Ok(());
+    }
+
+    for batch in sources.chunks(jobs * 4) {
+        let next = AtomicUsize::new(0);
+        let done: Mutex<Vec<Option<Result<Extracted>>>> = Mutex::new((0 .. batch.len()).map(|_| None).collect());
+        thread::scope(|scope| {
+            for _ in 0 .. jobs {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= batch.len() {
+                        return;
+                    }
+                    let extracted = extract(&batch[i].path, ngram_size, translation);
+                    done.lock().unwrap()[i] = Some(extracted);
+                });
+            }
+        });
+        for (source, extracted) in batch.iter().zip(done.into_inner().unwrap()) {
+            try!(f(source, extracted.unwrap()));
+        }
+    }
+    Ok(())
+}
+
+// a sorted run, a pair is (ngram << 32 | id)
+fn spill(run: &mut Vec<u64>, dir: &Path, nb: usize) -> Result<File> {
+
+    run.sort();
+    let path = dir.join(format!("run{}", nb));
+    {
+        let mut out = BufWriter::new(try!(File::create(&path)));
+        for pair in run.iter() {
+            try!(out.write_all(&pair.to_ne_bytes()));
+        }
+        try!(out.flush());
+    }
+    run.clear();
+    OpenOptions::new().read(true).open(&path)
+}
+
+struct RunReader(BufReader<File>);
+
+impl Iterator for RunReader {
+    type Item = Result<u64>;
+
+    fn next(&mut self) -> Option<Result<u64>> {
+        let mut bytes = [0u8; 8];
+        match self.0.read_exact(&mut bytes) {
+            Ok(()) => Some(Ok(u64::from_ne_bytes(bytes))),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// index the sources in the first shard of an empty database, the temporary
+// runs are written in tmp_dir and removed, the files are read by jobs threads
+pub fn bulk_build(db: &mut BinacleManager, sources: &[Source], memory: u64, tmp_dir: &str, jobs: usize) -> Result<BulkReport> {
+
+    if db.nb_index() != 0 || db.last_id() != 0 {
+        return Err(Error::new(ErrorKind::Other, format!("{} is not empty", db.db_path)));
+    }
+    if !db.is_map() {
+        return Err(Error::new(ErrorKind::Other, "bulk build needs a map database"));
+    }
+
+    try!(fs::create_dir(tmp_dir));
+    let res = build(db, sources, memory, Path::new(tmp_dir), jobs);
+    let _ = fs::remove_dir_all(tmp_dir);
+    res
+}
+
+fn build(db: &mut BinacleManager, sources: &[Source], memory: u64, tmp_dir: &Path, jobs: usize) -> Result<BulkReport> {
+
+    let ngram_size = db.ngram_size();
+    let translation = db.translation().cloned();
+    let run_len = (memory / 8).max(1) as usize;
+
+    let mut report = BulkReport::default();
+    let mut run: Vec<u64> = Vec::new();
+    let mut runs: Vec<File> = Vec::new();
+    let mut total_size = 0u64;
+
+    // pass one: the pairs, the map entries
+    try!(extract_all(sources, ngram_size, translation.as_ref(), jobs, |source, extracted| {
+
+        let id = db.last_id() + 1;
+        let extracted = match extracted {
+            Ok(e) => e,
+            Err(e) => {
+                report.skipped.push((source.path.clone(), e.to_string()));
+                return Ok(());
+            },
+        };
+        if let Some(expected) = source.sha256 {
+            if expected != extracted.sha256 {
+                report.skipped.push((source.path.clone(), format!("sha256 mismatch: expected {}, got {}",
+                                                                  hash::to_hex(&expected), hash::to_hex(&extracted.sha256))));
+                return Ok(());
+            }
+        }
+
+        for ngram in extracted.ngrams {
+            run.push((ngram as u64) << 32 | id as u64);
+        }
+        if run.len() >= run_len {
+            try!(db.check_space());
+            runs.push(try!(spill(&mut run, tmp_dir, runs.len())));
+        }
+
+        let mut provenance = Provenance::default();
+        provenance.set_size(extracted.size);
+        let mut entry = MapEntry::new(&source.path);
+        entry.sha256 = Some(hash::to_hex(&extracted.sha256));
+        entry.file_type = Some(String::from(db.magic().detect(&extracted.head)));
+        entry.tags = source.tags.clone();
+        entry.provenance = Some(provenance);
+        db.add_entry(id, entry);
+
+        total_size += extracted.size;
+        report.files += 1;
+        Ok(())
+    }));
+    report.runs = runs.len();
+
+    // pass two: the runs merged, the lists written in header order
+    run.sort();
+    let mut inputs: Vec<Box<dyn Iterator<Item = Result<u64>>>> = Vec::with_capacity(runs.len() + 1);
+    for file in runs {
+        inputs.push(Box::new(RunReader(BufReader::new(file))));
+    }
+    inputs.push(Box::new(run.into_iter().map(Ok)));
+
+    let mut heap = BinaryHeap::new();
+    for (n, input) in inputs.iter_mut().enumerate() {
+        if let Some(pair) = input.next() {
+            heap.push(Reverse((try!(pair), n)));
+        }
+    }
+
+    try!(db.check_space());
+    let mut writer = try!(try!(db.add_shard(false)).into_bulk_writer());
+    let mut ngram = None;
+    let mut ids: Vec<u32> = Vec::new();
+    while let Some(Reverse((pair, n))) = heap.pop() {
+
+        if let Some(next) = inputs[n].next() {
+            heap.push(Reverse((try!(next), n)));
+        }
+
+        let (pair_ngram, id) = ((pair >> 32) as u32, pair as u32);
+        if ngram != Some(pair_ngram) {
+            if let Some(ngram) = ngram {
+                try!(writer.write_list(ngram, &ids));
+            }
+            ngram = Some(pair_ngram);
+            ids.clear();
+        }
+        if ids.last() != Some(&id) {
+            ids.push(id);
+            report.postings += 1;
+        }
+    }
+    if let Some(ngram) = ngram {
+        try!(writer.write_list(ngram, &ids));
+    }
+
+    let average_size = match report.files {
+        0 => 0.0,
+        n => total_size as f64 / n as f64,
+    };
+    report.size = try!(writer.finish(db.nb_file(), db.last_id(), average_size));
+    if report.size > db.max_index_size() {
+        db.mark_shard_full(0);
+        try!(db.record_checksum(0));
+    }
+    try!(db.sync());
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle_manager::tests::cleanup;
+    use corpus::{self, Plant, SizeDist};
+    use fingerprint::fingerprint;
+
+    #[test]
+    fn same_results_as_insertions() {
+
+        let _ = fs::remove_dir_all("test_bulk_corpus");
+        let plants = Plant::parse_list("4765745072@30,c0a80101@3").unwrap();
+        corpus::generate("test_bulk_corpus", 80, SizeDist::parse("lognormal:11,0.3").unwrap(), 3, &plants).unwrap();
+
+        let mut incremental = BinacleManager::create("test_bulk_a.db", true, 1 << 30, 4, 16).unwrap();
+        incremental.set_deterministic(true);
+        incremental.insert_dir_recursive("test_bulk_corpus").unwrap();
+
+        // runs of 128k pairs, several are spilled
+        let mut bulk = BinacleManager::create("test_bulk_b.db", true, 1 << 30, 4, 16).unwrap();
+        bulk.set_deterministic(true);
+        let sources = sources("test_bulk_corpus").unwrap();
+        let report = bulk_build(&mut bulk, &sources, 1 << 20, "test_bulk_b.tmp", 1).unwrap();
+        assert_eq!(80, report.files);
+        assert!(report.runs > 1);
+        assert!(metadata("test_bulk_b.tmp").is_err());
+        assert!(bulk_build(&mut bulk, &sources, 1 << 20, "test_bulk_b.tmp", 1).is_err());
+
+        assert_eq!(fingerprint(&mut incremental).unwrap(), fingerprint(&mut bulk).unwrap());
+
+        // the same ids when the files are read by several threads
+        let mut threaded = BinacleManager::create("test_bulk_c.db", true, 1 << 30, 4, 16).unwrap();
+        threaded.set_deterministic(true);
+        assert_eq!(80, bulk_build(&mut threaded, &sources, 1 << 20, "test_bulk_c.tmp", 3).unwrap().files);
+        assert_eq!(fingerprint(&mut bulk).unwrap(), fingerprint(&mut threaded).unwrap());
+        drop(threaded);
+        cleanup("test_bulk_c");
+        for pattern in &[&b"GetPr"[..], &b"\xc0\xa8\x01\x01"[..]] {
+            let mut a: Vec<u32> = incremental.search(pattern).unwrap().into_iter().collect();
+            let mut b: Vec<u32> = bulk.search(pattern).unwrap().into_iter().collect();
+            a.sort();
+            b.sort();
+            assert_eq!(a, b);
+        }
+
+        // lists of about 50 ids: the blocks grown by the insertions take
+        // at least half more than the exact ones
+        let header = 5 << 16;
+        let a = incremental.open_indexes().unwrap()[0].get_size() - header;
+        let b = bulk.open_indexes().unwrap()[0].get_size() - header;
+        assert!(b * 3 < a * 2, "bulk {} bytes, incremental {} bytes", b, a);
+
+        // the index takes insertions afterwards
+        drop(bulk);
+        let mut bulk = BinacleManager::open("test_bulk_b.db").unwrap();
+        assert_eq!(81, bulk.insert_file("test_bulk_corpus/000001.bin", 81, true).unwrap());
+        assert!(bulk.search(b"GetPr").unwrap().contains(&81));
+
+        drop(bulk);
+        drop(incremental);
+        cleanup("test_bulk_a");
+        cleanup("test_bulk_b");
+        let _ = fs::remove_dir_all("test_bulk_corpus");
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+// Negative cache: n-grams recently found absent from a shard.
+// Only valid for a shard that does not change anymore (a full shard),
+// the live shard is never cached.
+//
+// Posting cache: the lists decoded by the requests of the server (see
+// server.rs), shared by them and bounded by the ids they hold, the least
+// recently used forgotten first. The same rule holds, the live shard is
+// read each time.
+
+
+// n-grams kept per shard, the oldest ones are forgotten first
+pub const NEGATIVE_CACHE_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    // lookups answered by the cache
+    pub hits: u64,
+    // lookups that had to read the shard
+    pub misses: u64,
+    // absent n-grams added to the cache
+    pub inserts: u64,
+    // caches dropped because their shard was replaced
+    pub replaced: u64,
+}
+
+pub struct NegativeCache {
+    set: HashSet<u32>,
+    order: VecDeque<u32>,
+    capacity: usize,
+    pub stats: CacheStats,
+}
+
+impl NegativeCache {
+
+    pub fn new(capacity: usize) -> NegativeCache {
+        NegativeCache {
+            set: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // true when the n-gram is known to be absent
+    pub fn lookup(&mut self, ngram: u32) -> bool {
+        if self.set.contains(&ngram) {
+            self.stats.hits += 1;
+            true
+        } else {
+            self.stats.misses += 1;
+            false
+        }
+    }
+
+    pub fn insert(&mut self, ngram: u32) {
+
+        if self.capacity == 0 || !self.set.insert(ngram) {
+            return;
+        }
+        self.order.push_back(ngram);
+        self.stats.inserts += 1;
+
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.set.remove(&old);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PostingStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    // requests over their budget, their lists not inserted
+    pub bypassed: u64,
+}
+
+// a list by its shard and its n-gram
+pub struct PostingCache {
+    lists: HashMap<(usize, u32), (u64, Arc<HashSet<u32>>)>,
+    // the keys by their last use
+    order: BTreeMap<u64, (usize, u32)>,
+    tick: u64,
+    ids: u64,
+    capacity: u64,
+    pub stats: PostingStats,
+}
+
+impl PostingCache {
+
+    // at most capacity ids held
+    pub fn new(capacity: u64) -> PostingCache {
+        PostingCache {
+            lists: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            ids: 0,
+            capacity: capacity,
+            stats: PostingStats::default(),
+        }
+    }
+
+    // the list, now the most recently used
+    pub fn get(&mut self, shard: usize, ngram: u32) -> Option<Arc<HashSet<u32>>> {
+        self.tick += 1;
+        match self.lists.get_mut(&(shard, ngram)) {
+            Some(entry) => {
+                self.order.remove(&entry.0);
+                self.order.insert(self.tick, (shard, ngram));
+                entry.0 = self.tick;
+                self.stats.hits += 1;
+                Some(entry.1.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    // a list larger than the whole cache is not kept
+    pub fn insert(&mut self, shard: usize, ngram: u32, list: Arc<HashSet<u32>>) {
+
+        let size = list.len() as u64;
+        if size > self.capacity || self.lists.contains_key(&(shard, ngram)) {
+            return;
+        }
+        while self.ids + size > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&tick) => self.order.remove(&tick).unwrap(),
+                None => break,
+            };
+            if let Some((_, old)) = self.lists.remove(&oldest) {
+                self.ids -= old.len() as u64;
+                self.stats.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, (shard, ngram));
+        self.lists.insert((shard, ngram), (self.tick, list));
+        self.ids += size;
+        self.stats.inserts += 1;
+    }
+
+    // held, without it being used
+    pub fn contains(&self, shard: usize, ngram: u32) -> bool {
+        self.lists.contains_key(&(shard, ngram))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lists.len()
+    }
+
+    pub fn ids(&self) -> u64 {
+        self.ids
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let mut cache = NegativeCache::new(2);
+        cache.insert(1);
+        cache.insert(2);
+        cache.insert(2);
+        cache.insert(3);
+        assert_eq!(2, cache.len());
+        assert!(!cache.lookup(1));
+        assert!(cache.lookup(3));
+        assert_eq!(CacheStats { hits: 1, misses: 1, inserts: 3, replaced: 0 }, cache.stats);
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let list = |ids: &[u32]| Arc::new(ids.iter().cloned().collect::<HashSet<u32>>());
+        let mut cache = PostingCache::new(5);
+        cache.insert(0, 1, list(&[1, 2]));
+        cache.insert(0, 2, list(&[3, 4]));
+        assert_eq!(4, cache.ids());
+        // used again, the other one is the oldest
+        assert!(cache.get(0, 1).is_some());
+        cache.insert(1, 2, list(&[5, 6]));
+        assert!(cache.contains(0, 1));
+        assert!(!cache.contains(0, 2));
+        assert!(cache.contains(1, 2));
+        assert_eq!(4, cache.ids());
+
+        cache.insert(0, 3, list(&[1, 2, 3, 4, 5, 6]));
+        assert!(!cache.contains(0, 3));
+        assert!(cache.get(0, 3).is_none());
+        assert_eq!(PostingStats { hits: 1, misses: 1, inserts: 3, evictions: 1, bypassed: 0 }, cache.stats);
+    }
+}
@@ -0,0 +1,211 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::*;
+
+use binacle::BinacleFile;
+
+// Length of the chains of blocks of the lists. A list grown by insertions is
+// a chain of blocks, each one read by a search of its n-gram: long chains
+// slow the searches down. The compaction rewrites the longest ones in a
+// single block.
+
+
+// p95 of the chain lengths of a shard over which a compaction is recommended
+pub const DEFAULT_RECHAIN_THRESHOLD: u32 = 8;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChainStats {
+    // n-grams with a list
+    pub lists: u64,
+    pub mean: f64,
+    pub p50: u32,
+    pub p95: u32,
+    pub max: u32,
+    // lists whose chain is longer than the threshold given
+    pub over: u64,
+}
+
+impl ChainStats {
+
+    // number of lists of each chain length
+    pub fn from_histogram(histogram: &[u64], threshold: u32) -> ChainStats {
+
+        let lists: u64 = histogram.iter().sum();
+        if lists == 0 {
+            return ChainStats::default();
+        }
+        let blocks: u64 = histogram.iter().enumerate().map(|(len, n)| len as u64 * n).sum();
+
+        // smallest length covering the part of the lists
+        let percentile = |part: f64| {
+            let rank = (part * lists as f64).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            for (len, n) in histogram.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return len as u32;
+                }
+            }
+            histogram.len() as u32 - 1
+        };
+
+        ChainStats {
+            lists: lists,
+            mean: blocks as f64 / lists as f64,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            max: histogram.iter().rposition(|n| *n != 0).unwrap_or(0) as u32,
+            over: histogram.iter().skip(threshold as usize + 1).sum(),
+        }
+    }
+
+    pub fn needs_compaction(&self, threshold: u32) -> bool {
+        self.p95 > threshold
+    }
+}
+
+pub fn chain_stats(db: &BinacleFile, threshold: u32) -> ChainStats {
+    let mut histogram = Vec::new();
+    db.for_each_chain(|_, len| {
+        if histogram.len() <= len as usize {
+            histogram.resize(len as usize + 1, 0);
+        }
+        histogram[len as usize] += 1;
+    });
+    ChainStats::from_histogram(&histogram, threshold)
+}
+
+// the n n-grams with the longest chains, the longest first
+pub fn longest_chains(db: &BinacleFile, n: usize) -> Vec<(u32, u32)> {
+
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    db.for_each_chain(|ngram, len| {
+        if len > 1 {
+            heap.push(Reverse((len, ngram)));
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+    });
+    let mut longest: Vec<(u32, u32)> = heap.into_iter().map(|Reverse((len, ngram))| (ngram, len)).collect();
+    longest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    longest
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Compaction {
+    pub ngrams: usize,
+    pub blocks_freed: usize,
+    // longest chain before the compaction
+    pub longest_before: u32,
+    // header slots the search of the longest chains skipped, None without
+    // an occupancy bitmap
+    pub header_skipped: Option<u64>,
+}
+
+// rewrite the top longest chains of a shard, the other lists are untouched
+pub fn compact_ngrams(db: &mut BinacleFile, top: usize) -> Result<Compaction> {
+
+    let longest = longest_chains(db, top);
+    let mut report = Compaction {
+        ngrams: 0,
+        blocks_freed: 0,
+        longest_before: longest.first().map_or(0, |l| l.1),
+        header_skipped: db.header_skipped(),
+    };
+    for (ngram, _) in longest {
+        report.blocks_freed += try!(db.compact_ngram(ngram));
+        report.ngrams += 1;
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs::remove_file;
+
+    #[test]
+    fn percentiles() {
+        // 90 lists of 1 block, 8 of 3, 2 of 10
+        let mut histogram = vec![0; 11];
+        histogram[1] = 90;
+        histogram[3] = 8;
+        histogram[10] = 2;
+        let stats = ChainStats::from_histogram(&histogram, 2);
+        assert_eq!(100, stats.lists);
+        assert_eq!(1, stats.p50);
+        assert_eq!(3, stats.p95);
+        assert_eq!(10, stats.max);
+        assert_eq!(10, stats.over);
+        assert!((stats.mean - 1.34).abs() < 1e-9);
+        assert!(stats.needs_compaction(2));
+        assert_eq!(ChainStats::default(), ChainStats::from_histogram(&[], 2));
+    }
+
+    #[test]
+    fn compaction_keeps_postings() {
+        {
+            let mut db = BinacleFile::create("test_chains.db", 5, 4, 16).unwrap();
+            // long chains for the first n-grams, single blocks for the others
+            for id in 1 .. 400u32 {
+                for ngram in 0 .. 40u32 {
+                    if ngram < 3 || id % (ngram + 1) == 0 {
+                        db.insert_postings(ngram, &[id * 3]).unwrap();
+                    }
+                }
+            }
+            let mut before = HashMap::new();
+            for ngram in 0 .. 40u32 {
+                before.insert(ngram, db.get_ids_by_ngram(ngram));
+            }
+            let stats = chain_stats(&db, 3);
+            assert!(stats.max > 3);
+
+            let longest = longest_chains(&db, 3);
+            assert_eq!(vec![0, 1, 2], longest.iter().map(|l| l.0).collect::<Vec<u32>>());
+            let untouched = chain_lengths(&db, 39);
+
+            let report = compact_ngrams(&mut db, 3).unwrap();
+            assert_eq!(3, report.ngrams);
+            assert_eq!(stats.max, report.longest_before);
+            assert!(report.blocks_freed > 3);
+            assert_eq!(report.blocks_freed, db.nb_free_blocks());
+
+            for ngram in 0 .. 40u32 {
+                assert_eq!(before[&ngram], db.get_ids_by_ngram(ngram));
+            }
+            for ngram in 0 .. 3 {
+                assert_eq!(1, chain_lengths(&db, ngram));
+            }
+            assert_eq!(untouched, chain_lengths(&db, 39));
+
+            // the freed blocks are used by the next allocations
+            let size = db.get_size();
+            for ngram in 1000 .. 1003u32 {
+                db.insert_postings(ngram, &[1]).unwrap();
+            }
+            assert_eq!(size, db.get_size());
+            db.insert_postings(0, &[2000]).unwrap();
+            assert!(db.get_ids_by_ngram(0).contains(&2000));
+            assert_eq!(before[&1], db.get_ids_by_ngram(1));
+        }
+        {
+            // kept in the meta
+            let db = BinacleFile::open_read("test_chains.db").unwrap();
+            assert!(db.nb_free_blocks() > 0);
+        }
+        let _ = remove_file("test_chains.db");
+        let _ = remove_file("test_chains.meta");
+        let _ = remove_file("test_chains.bitmap");
+    }
+
+    fn chain_lengths(db: &BinacleFile, ngram: u32) -> u32 {
+        let mut found = 0;
+        db.for_each_chain(|n, len| if n == ngram { found = len });
+        found
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::BTreeMap;
+use docopt::{ArgvMap, Value};
+use regex::Regex;
+
+// Check of the command line against what each command reads. Docopt lets
+// the usage lines ending by [options] take any option, most of them are
+// only read by one command and were silently ignored by the others: the
+// options a command does not use, the ones hiding each other and the ones
+// needing another are rejected, unless --permissive.
+
+
+// read by all the commands opening a database
+const OPEN: &'static [&'static str] = &["--map-cap", "--lazy-map", "--permissive", "--config"];
+
+const INSERT: &'static [&'static str] = &["--deterministic", "--magic", "--durability", "--file-budget-ms", "--retry-quarantined",
+                                          "--ignore-space-check", "--no-prefetch", "--quiet"];
+
+const SEARCH: &'static [&'static str] = &[
+    "--and", "--exclude", "--id-range", "--limit", "--sample", "--seed", "--explain", "--why-not",
+    "--deadline-ms", "--strict", "--context", "--group-by-dir", "--show-files", "--json", "--output", "--format",
+    "--labels", "--type", "--show-type", "--tag", "--in-collection", "--save-as", "--path-match", "--path-contains",
+    "--endian", "--endian-unit", "--variant", "--variant-cap", "--preselect", "--show-notes", "--aggregate",
+    "--search-file", "--keep-line-endings", "--binary-ok", "--max-bytes", "--literal-newlines", "--verify-shards",
+    "--exec", "--exec-jobs", "--exec-timeout-ms", "--exec-batch", "--exec-shell", "--color", "--no-truncate",
+    "--no-mmap-reads", "--copy-to", "--hardlink", "--symlink", "--max-copy-bytes", "--any", "--wide", "--both",
+    "--dirty-read", "--verify",
+];
+
+// the options of -s a saved query keeps, see library.rs
+const SAVED: &'static [&'static str] = &[
+    "--and", "--exclude", "--id-range", "--limit", "--sample", "--seed", "--deadline-ms", "--strict", "--json",
+    "--labels", "--type", "--show-type", "--tag", "--path-match", "--path-contains", "--endian", "--endian-unit",
+    "--search-file", "--keep-line-endings", "--binary-ok", "--max-bytes", "--literal-newlines", "--wide", "--query-tag",
+];
+
+// the second option is ignored when the first one is given
+const HIDES: &'static [(&'static str, &'static str)] = &[
+    ("--output", "--json"),
+    ("--output", "--labels"),
+    ("--output", "--context"),
+    ("--output", "--group-by-dir"),
+    ("--output", "--show-type"),
+    ("--labels", "--context"),
+    ("--labels", "--group-by-dir"),
+    ("--labels", "--show-type"),
+    ("--context", "--group-by-dir"),
+    ("--context", "--show-type"),
+    ("--group-by-dir", "--show-type"),
+    ("--output", "--show-notes"),
+    ("--json", "--show-notes"),
+    ("--labels", "--show-notes"),
+    ("--context", "--show-notes"),
+    ("--group-by-dir", "--show-notes"),
+    ("--output", "--aggregate"),
+    ("--aggregate", "--labels"),
+    ("--aggregate", "--context"),
+    ("--aggregate", "--group-by-dir"),
+    ("--aggregate", "--show-type"),
+    ("--aggregate", "--show-notes"),
+    ("--both", "--wide"),
+];
+
+// the first option is only read with the second one
+const NEEDS: &'static [(&'static str, &'static str)] = &[
+    ("--show-files", "--group-by-dir"),
+    ("--endian-unit", "--endian"),
+    ("--seed", "--sample"),
+    ("--format", "--output"),
+    ("--variant-cap", "--variant"),
+    ("--sidecar-keys", "--sidecar-meta"),
+    ("--keep-line-endings", "--search-file"),
+    ("--binary-ok", "--search-file"),
+    ("--max-bytes", "--search-file"),
+    ("--exec-jobs", "--exec"),
+    ("--exec-timeout-ms", "--exec"),
+    ("--exec-batch", "--exec"),
+    ("--exec-shell", "--exec"),
+    ("--hardlink", "--copy-to"),
+    ("--symlink", "--copy-to"),
+    ("--max-copy-bytes", "--copy-to"),
+];
+
+// the options whose value can be left out, and the value they then take:
+// docopt wants the value of an option declared with one
+const OPTIONAL_VALUES: &'static [(&'static str, &'static str)] = &[
+    ("--group-by-dir", "1"),
+];
+
+// the command line with the values left out given, before docopt: only an
+// attached value (--group-by-dir=2) is taken, the argument following a bare
+// option is never its value (-s --group-by-dir 1234 searches 1234)
+pub fn fill_values<I: Iterator<Item=String>>(argv: I) -> Vec<String> {
+    argv.map(|arg| match OPTIONAL_VALUES.iter().find(|&&(option, _)| option == arg) {
+        Some(&(option, value)) => format!("{}={}", option, value),
+        None => arg,
+    }).collect()
+}
+
+// the command of the usage lines taking [options], the other lines take
+// only the options they list
+pub fn command(args: &ArgvMap) -> Option<&'static str> {
+    // their --queries line lists their options
+    if args.get_bool("--ab-test") || args.get_bool("--scan") || args.get_bool("--warmup") {
+        return None;
+    }
+    for c in &["--tmp", "-f", "--rec", "--watch", "--ingest-manifest", "--save-query", "-s", "--collection", "--queries"] {
+        if args.get_bool(c) {
+            return Some(c);
+        }
+    }
+    if args.get_bool("--files") && !args.get_bool("--gen-corpus") {
+        return Some("--files");
+    }
+    None
+}
+
+// the options of the usage and their default value
+pub fn options(usage: &str) -> BTreeMap<String, Option<String>> {
+
+    // exact and hex are words of the usage lines, not options
+    let option = Regex::new(r"^\s+(--[\w-]+)").unwrap();
+    let default = Regex::new(r"\[default: ([^\]]*)\]").unwrap();
+
+    let mut options = BTreeMap::new();
+    let mut current = None;
+    let section = usage.splitn(2, "Options:").nth(1).unwrap_or("");
+    for line in section.lines() {
+        if let Some(cap) = option.captures(line) {
+            current = Some(String::from(&cap[1]));
+            options.insert(String::from(&cap[1]), None);
+        }
+        if let (Some(ref name), Some(cap)) = (&current, default.captures(line)) {
+            options.insert(name.clone(), Some(String::from(&cap[1])));
+        }
+    }
+    options
+}
+
+pub fn given(args: &ArgvMap, name: &str, default: &Option<String>) -> bool {
+    match args.map.find(&String::from(name)) {
+        Some(&Value::Switch(b)) => b,
+        Some(&Value::Counted(n)) => n > 0,
+        Some(&Value::Plain(Some(ref v))) => default.as_ref() != Some(v),
+        Some(&Value::List(ref v)) => !v.is_empty(),
+        _ => false,
+    }
+}
+
+// the options read by a command of the usage lines taking [options]
+pub fn accepted(command: &str) -> Vec<&'static str> {
+
+    // the temporary database is created, not opened
+    let mut accepted: Vec<&str> = match command {
+        "--tmp" => vec!["--permissive", "--config"],
+        _ => OPEN.to_vec(),
+    };
+    match command {
+        "-f" => {
+            accepted.extend(INSERT);
+            accepted.extend(&["--label", "--json"]);
+        },
+        "--ingest-manifest" => {
+            accepted.extend(INSERT);
+            accepted.push("--result");
+        },
+        "--rec" => {
+            accepted.extend(INSERT);
+            accepted.extend(&["--sidecar-meta", "--sidecar-keys", "--modified-since", "--modified-before", "--owner", "--min-size",
+                              "--max-size", "--force", "--json", "--progress"]);
+        },
+        "--files" => {
+            accepted.extend(INSERT);
+            accepted.push("--json");
+        },
+        "--watch" => {
+            accepted.extend(INSERT);
+            accepted.extend(&["--interval", "--json"]);
+        },
+        "-s" => accepted.extend(SEARCH),
+        "--save-query" => accepted.extend(SAVED),
+        // the options of each query are on its line, the shards are read for all
+        "--queries" => accepted.push("--no-mmap-reads"),
+        "--tmp" => accepted.extend(&["--persist", "--deterministic", "--magic", "--ngram-size", "--alignment"]),
+        _ => accepted.extend(&["--ids", "--from-query", "--tag"]),
+    }
+    accepted
+}
+
+// what is wrong with the command line, each problem names the options
+pub fn check(usage: &str, args: &ArgvMap) -> Vec<String> {
+
+    let command = match command(args) {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+    let options = options(usage);
+    let present: Vec<&str> = options.iter()
+        .filter(|&(name, default)| given(args, name, default))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let has = |name: &str| present.contains(&name);
+    let accepted = accepted(command);
+
+    let mut problems = Vec::new();
+    for name in &present {
+        if !accepted.contains(name) {
+            problems.push(format!("{} is not used by {}", name, command));
+        }
+    }
+
+    if command == "-s" && has("--why-not") {
+        // the search is not run, only the pattern is read
+        let pattern = ["--why-not", "--search-file", "--keep-line-endings", "--binary-ok", "--max-bytes", "--literal-newlines", "--color",
+                       "--wide"];
+        for name in &present {
+            if !OPEN.contains(name) && !pattern.contains(name) && accepted.contains(name) {
+                problems.push(format!("{} is not used with --why-not", name));
+            }
+        }
+        if args.get_bool("exact") {
+            problems.push(String::from("exact is not used with --why-not"));
+        }
+        return problems;
+    }
+
+    if command == "-s" && has("--any") {
+        // each pattern is searched alone and the ids are printed with its index
+        let list = ["--any", "--search-file", "--no-mmap-reads", "--color"];
+        for name in &present {
+            if !OPEN.contains(name) && !list.contains(name) && accepted.contains(name) {
+                problems.push(format!("{} is not used with --any", name));
+            }
+        }
+        if args.get_bool("exact") {
+            problems.push(String::from("exact is not used with --any"));
+        }
+        return problems;
+    }
+
+    if command == "-s" || command == "--save-query" {
+        for &(first, second) in HIDES {
+            if has(first) && has(second) {
+                problems.push(format!("{} and {} can not be used together, {} would be ignored", first, second, second));
+            }
+        }
+    }
+    if command == "-s" && args.get_bool("exact") && has("--verify") {
+        problems.push(String::from("exact and --verify can not be used together, --verify would be ignored"));
+    }
+    for &(option, needed) in NEEDS {
+        if has(option) && !has(needed) {
+            problems.push(format!("{} needs {}", option, needed));
+        }
+    }
+
+    if command == "--collection" {
+        let action = args.get_str("<action>");
+        for &(option, needed) in &[("--ids", "add"), ("--from-query", "add"), ("--tag", "tag")] {
+            if has(option) && action != needed {
+                problems.push(format!("{} is not used by --collection {}", option, action));
+            }
+        }
+    }
+    problems
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use docopt::Docopt;
+
+    // the problems of a command line, None when docopt rejects it
+    fn run(argv: &str) -> Option<Vec<String>> {
+        let argv = format!("binacle {}", argv);
+        Docopt::new(::USAGE)
+            .and_then(|d| d.argv(argv.split(' ')).parse())
+            .ok()
+            .map(|args| if args.get_bool("--permissive") { Vec::new() } else { check(::USAGE, &args) })
+    }
+
+    #[test]
+    fn optional_values() {
+        let fill = |argv: &str| fill_values(argv.split(' ').map(String::from)).join(" ");
+        assert_eq!("t.db -s abc --group-by-dir=1", fill("t.db -s abc --group-by-dir"));
+        assert_eq!("t.db -s abc --group-by-dir=1 --show-files", fill("t.db -s abc --group-by-dir --show-files"));
+        assert_eq!("t.db -s abc --group-by-dir=1 3 --json", fill("t.db -s abc --group-by-dir 3 --json"));
+        assert_eq!("t.db -s --group-by-dir=1 1234", fill("t.db -s --group-by-dir 1234"));
+        assert_eq!("t.db -s abc --group-by-dir=2", fill("t.db -s abc --group-by-dir=2"));
+
+        // docopt alone wants the value
+        assert_eq!(None, run("t.db -s abc --group-by-dir"));
+        assert_eq!(Some(Vec::new()), run(&fill("t.db -s abc --group-by-dir")));
+        assert_eq!(Some(Vec::new()), run(&fill("t.db -s abc --group-by-dir --show-files")));
+        // the pattern, not the depth
+        assert_eq!(Some(Vec::new()), run(&fill("t.db -s --group-by-dir 1234")));
+    }
+
+    #[test]
+    fn accepted_matrix() {
+        let accepted = [
+            "-c t.db map 1000 6 16",
+            "t.db -f 1 a.bin --label=first --deterministic --durability=per-file --magic=m.json",
+            "t.db -f 1 a.bin --file-budget-ms=10 --retry-quarantined --map-cap=100 --lazy-map",
+            "t.db -f a.bin --label=first --json",
+            "t.db --files 1_a.bin --durability=none",
+            "t.db --files 1_a.bin --no-prefetch --json",
+            "t.db --rec dir --ignore-space-check",
+            "t.db --rec dir --deterministic --json",
+            "t.db --rec dir --sidecar-meta=.json --sidecar-keys=family,source",
+            "t.db --rec dir --modified-since=2024-01-01 --modified-before=2024-02-01 --owner=1000",
+            "t.db --rec dir --min-size=1 --max-size=2G --json",
+            "t.db --rec dir --force --deterministic",
+            "t.db --ingest-manifest m.jsonl --result=r.jsonl --magic=m.json",
+            "t.db -s exact hex 4142 --limit=3 --json --exclude=x --id-range=1-9 --type=pe --tag=a",
+            "t.db -s abc --sample=3 --seed=2 --explain --deadline-ms=10 --strict --save-as=c",
+            "t.db -s abc --no-mmap-reads --strict",
+            "t.db -s GetProcAddress --and=evil.example.com --and=kernel32 --exclude=x",
+            "t.db -s exact GetProcAddress --copy-to=case --hardlink --max-copy-bytes=1000",
+            "t.db -s abc --group-by-dir=2 --show-files --in-collection=c --path-contains=x",
+            "t.db -s abc --context=16 --json --path-match=x",
+            "t.db -s abc --labels --json",
+            "t.db -s abc --dirty-read --json --no-mmap-reads",
+            "t.db -s abc --show-type",
+            "t.db -s abc --show-type --show-notes",
+            "t.db -s abc --aggregate=count-by:dir:2 --json --tag=x",
+            "t.db -s hex --search-file=p.hex --explain",
+            "t.db -s abc --verify-shards --limit=3",
+            "t.db -s abc --verify --verify-shards --json",
+            "t.db -s abc --literal-newlines --why-not=3",
+            "t.db -s abc --exec=triage --exec-jobs=4 --exec-timeout-ms=1000 --exec-batch --exec-shell",
+            "t.db -s --search-file=p.txt --keep-line-endings --binary-ok --why-not=3",
+            "t.db -s --search-file=stub.bin --binary-ok --keep-line-endings --max-bytes=256",
+            "t.db -s abc --show-type --color=always --no-truncate",
+            "t.db -s abc --why-not=3 --color=never",
+            "t.db -s --any --search-file=iocs.txt --no-mmap-reads",
+            "t.db -s abc --config=team.toml --limit=3",
+            "t.db -f 1 a.bin --config=team.toml",
+            "--show-config --config=team.toml",
+            "t.db -s hex --any 4142434445 --map-cap=100",
+            "t.db --note 3 text",
+            "t.db --list --note-contains=emotet",
+            "t.db --list --color=never --no-truncate",
+            "t.db --list --filter=/samples/ --id=3",
+            "t.db --list --id=emotet-1 --note-contains=c2",
+            "t.db --save-query loaders -s hex 4c6f6164 --and=4142 --limit=10 --json --query-tag=hunting",
+            "t.db --save-query loaders -s exact --search-file=p.txt --binary-ok --endian=both --endian-unit=4",
+            "t.db --run-query loaders",
+            "t.db --run-all-queries --tag=hunting",
+            "t.db --list-queries --color=never",
+            "t.db --delete-query loaders",
+            "t.db --verify-shards --color=always",
+            "t.db --coverage-of a.exe --bar",
+            "t.db -s abc --endian=both --endian-unit=4",
+            "t.db -s abc --output=r.tsv --format=tsv --limit=10",
+            "t.db -s hex 6a40680030 --variant=4=30,40 --variant=2=68,69 --variant-cap=8 --preselect=0",
+            "t.db -s hex 4142 --why-not=3",
+            "t.db -s abc --why-not=first --map-cap=100",
+            "t.db -s cmd.exe --wide --why-not=3",
+            "t.db -s exact cmd.exe --both --and=/c --limit=3",
+            "t.db --collection list",
+            "t.db --queries q.txt --lazy-map",
+            "t.db --queries q.txt --no-mmap-reads",
+            "--tmp dir --queries q.txt --persist=t.db --ngram-size=20 --magic=m.json",
+            "t.db --collection add c --ids=1,2 --from-query=abc",
+            "t.db --collection tag c --tag=x",
+            "t.db --stats --durability=periodic:10:5 --rechain-threshold=4 --color=auto",
+            "t.db --stats --json --rechain-threshold=4",
+            "t.db --stats --shareable --k-floor=20 --bucket=50 --salt-top",
+            "t.db --why 3 hex 4142",
+            "t.db --delete 3",
+            "t.db --reindex t2.db --ngram-size=12 --alignment=4",
+            "t.db --verify-reindex t2.db --sample=10 --seed=1",
+            "t.db --merge t2.db --id-offset=100 --ignore-space-check",
+            "t.db --watch dir --interval=5 --json --durability=periodic:100:30",
+            "t.db --rec dir --progress --durability=per-file",
+            "t.db --files 1_a.bin --quiet --json",
+            "--bulk-build t.db --from dir 1000 6 16 --bulk-memory=100 --deterministic",
+            "--gen-corpus dir --files 10 --seed=3 --plant=41@2",
+            "--ab-test a.db b.db --queries q.txt --corpus-truth=t.json",
+            "--overlap a.db b.db --sample=100 --seed=2",
+            "--scan dir --queries q.txt --jobs=4",
+            "t.db --warmup --seconds=30 --queries q.txt --notify",
+            "t.db --serve 127.0.0.1:8700 --user=nobody --group=nogroup --chroot=/srv/binacle",
+            "t.db --serve 127.0.0.1:8700 --cache-ids=1000000 --request-budget=10000 --release-pages",
+            "t.db --dump-postings --min-df=2 --format=binary",
+            // the default values are not told from the absent options
+            "t.db -s abc --durability=none --endian-unit=pattern",
+            "t.db -f 1 a.bin --limit=3 --permissive",
+        ];
+        for argv in accepted.iter() {
+            assert_eq!(Some(Vec::new()), run(argv), "{}", argv);
+        }
+    }
+
+    #[test]
+    fn rejected_matrix() {
+        let rejected = [
+            ("t.db -f 1 a.bin --exclude=x", "--exclude is not used by -f"),
+            ("t.db -f 1 a.bin --limit=3", "--limit is not used by -f"),
+            ("t.db --rec dir --no-truncate", "--no-truncate is not used by --rec"),
+            ("t.db --files 1_a.bin --label=x", "--label is not used by --files"),
+            ("t.db --rec dir --labels", "--labels is not used by --rec"),
+            ("t.db --ingest-manifest m --json", "--json is not used by --ingest-manifest"),
+            ("t.db --rec dir --result=r", "--result is not used by --rec"),
+            ("t.db --rec dir --interval=5", "--interval is not used by --rec"),
+            ("t.db --files 1_a.bin --progress", "--progress is not used by --files"),
+            ("t.db -s abc --quiet", "--quiet is not used by -s"),
+            ("t.db -s abc --user=nobody", "--user is not used by -s"),
+            ("t.db -s abc --max-size=10M", "--max-size is not used by -s"),
+            ("t.db --files 1_a.bin --force", "--force is not used by --files"),
+            ("t.db --files 1_a.bin --sidecar-meta=.json", "--sidecar-meta is not used by --files"),
+            ("t.db --files 1_a.bin --modified-since=2024-01-01", "--modified-since is not used by --files"),
+            ("t.db --ingest-manifest m --label=x", "--label is not used by --ingest-manifest"),
+            ("t.db -s abc --deterministic", "--deterministic is not used by -s"),
+            ("t.db -s abc --no-prefetch", "--no-prefetch is not used by -s"),
+            ("t.db -s abc --durability=per-file", "--durability is not used by -s"),
+            ("t.db -s abc --label=x", "--label is not used by -s"),
+            ("t.db -s abc --ids=1", "--ids is not used by -s"),
+            ("t.db -s abc --output=r.tsv --json", "--output and --json can not be used together, --json would be ignored"),
+            ("t.db -s abc --labels --context=3", "--labels and --context can not be used together, --context would be ignored"),
+            ("t.db -s abc --context=3 --group-by-dir=1", "--context and --group-by-dir can not be used together, --group-by-dir would be ignored"),
+            ("t.db -s abc --group-by-dir=1 --show-type", "--group-by-dir and --show-type can not be used together, --show-type would be ignored"),
+            ("t.db -s abc --json --show-notes", "--json and --show-notes can not be used together, --show-notes would be ignored"),
+            ("t.db -s abc --aggregate=count-by:tag --group-by-dir=1", "--aggregate and --group-by-dir can not be used together, --group-by-dir would be ignored"),
+            ("t.db -s cmd.exe --both --wide", "--both and --wide can not be used together, --wide would be ignored"),
+            ("t.db -s cmd.exe --both --why-not=3", "--both is not used with --why-not"),
+            ("t.db -s abc --show-files", "--show-files needs --group-by-dir"),
+            ("t.db -s abc --endian-unit=4", "--endian-unit needs --endian"),
+            ("t.db -s abc --seed=3", "--seed needs --sample"),
+            ("t.db -s abc --format=tsv", "--format needs --output"),
+            ("t.db -s abc --variant-cap=8", "--variant-cap needs --variant"),
+            ("t.db --rec dir --sidecar-keys=family", "--sidecar-keys needs --sidecar-meta"),
+            ("t.db -s abc --binary-ok", "--binary-ok needs --search-file"),
+            ("t.db -s abc --max-bytes=4", "--max-bytes needs --search-file"),
+            ("t.db -s abc --symlink", "--symlink needs --copy-to"),
+            ("t.db -s abc --exec-jobs=2", "--exec-jobs needs --exec"),
+            ("t.db -s --search-file=p.txt --keep-line-endings --why-not=3 --limit=2", "--limit is not used with --why-not"),
+            ("t.db -s abcd --why-not=3 --variant=1=00", "--variant is not used with --why-not"),
+            ("t.db -s abc --why-not=3 --limit=2", "--limit is not used with --why-not"),
+            ("t.db -s exact abc --why-not=3", "exact is not used with --why-not"),
+            ("t.db -s --any --search-file=iocs.txt --limit=2", "--limit is not used with --any"),
+            ("t.db -s exact --any abc", "exact is not used with --any"),
+            ("t.db -s exact abc --verify", "exact and --verify can not be used together, --verify would be ignored"),
+            ("t.db -s --any abc --verify", "--verify is not used with --any"),
+            ("t.db --collection list --tag=x", "--tag is not used by --collection list"),
+            ("t.db --collection add c --tag=x", "--tag is not used by --collection add"),
+            ("t.db --collection show c --ids=1", "--ids is not used by --collection show"),
+            ("t.db --collection add c --limit=1", "--limit is not used by --collection"),
+            ("t.db --queries q.txt --limit=1", "--limit is not used by --queries"),
+            ("t.db --save-query q -s abc --context=3", "--context is not used by --save-query"),
+            ("t.db --save-query q -s abc --variant=1=00", "--variant is not used by --save-query"),
+            ("t.db --save-query q -s abc --labels --show-type", "--labels and --show-type can not be used together, --show-type would be ignored"),
+            ("t.db -s abc --query-tag=x", "--query-tag is not used by -s"),
+            ("--tmp dir --queries q.txt --lazy-map", "--lazy-map is not used by --tmp"),
+        ];
+        for &(argv, problem) in rejected.iter() {
+            assert_eq!(Some(vec![String::from(problem)]), run(argv), "{}", argv);
+        }
+
+        // every problem is told
+        assert_eq!(2, run("t.db -f 1 a.bin --exclude=x --labels").unwrap().len());
+        // docopt itself rejects the options out of the lines without [options]
+        assert_eq!(None, run("t.db -f 1 a.bin --rec dir"));
+        assert_eq!(None, run("t.db -f 1 a.bin hex"));
+        assert_eq!(None, run("t.db --stats hex"));
+        assert_eq!(None, run("t.db --have a.bin --limit=3"));
+    }
+
+    #[test]
+    fn usage_options() {
+        let options = options(::USAGE);
+        assert_eq!(Some(&None), options.get("--json"));
+        assert_eq!(None, options.get("--exact"));
+        assert_eq!(Some(&Some(String::from("none"))), options.get("--durability"));
+        assert_eq!(Some(&Some(String::from("pattern"))), options.get("--endian-unit"));
+        // every option read by a command is in the usage
+        for name in OPEN.iter().chain(INSERT).chain(SEARCH) {
+            assert!(options.contains_key(*name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::*;
+use std::path::PathBuf;
+use rustc_serialize::json;
+
+// Collections: named sets of ids (a case, a hunt), kept in a sidecar of the
+// database ({db}.collections) so the map format does not change. An id
+// removed from the database stays in its collections, the searches filtered
+// on a collection drop it like any tombstoned id.
+
+
+pub struct Collections {
+    path: PathBuf,
+    sets: BTreeMap<String, BTreeSet<u32>>,
+}
+
+fn sidecar(db_path: &str) -> PathBuf {
+    let mut path = PathBuf::from(db_path);
+    path.set_extension("collections");
+    path
+}
+
+impl Collections {
+
+    // the collections of a database, none when the sidecar does not exist
+    pub fn open(db_path: &str) -> Result<Collections> {
+
+        let path = sidecar(db_path);
+        let mut content = String::new();
+        let sets = match OpenOptions::new().read(true).open(&path) {
+            Ok(mut f) => {
+                try!(f.read_to_string(&mut content));
+                try!(json::decode(&content).map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+                }))
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Collections { path: path, sets: sets })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let encoded = try!(json::encode(&self.sets).map_err(|e| Error::new(ErrorKind::Other, e.to_string())));
+        try!(File::create(&self.path)).write_all(encoded.as_bytes())
+    }
+
+    // the same collections for another database
+    pub fn copy_to(&self, db_path: &str) -> Result<()> {
+        if self.sets.is_empty() {
+            return Ok(());
+        }
+        Collections { path: sidecar(db_path), sets: self.sets.clone() }.save()
+    }
+
+    pub fn create(&mut self, name: &str) -> Result<()> {
+        if self.sets.contains_key(name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("collection {} already exists", name)));
+        }
+        self.sets.insert(String::from(name), BTreeSet::new());
+        Ok(())
+    }
+
+    // number of ids that were not in the collection
+    pub fn add<I: IntoIterator<Item = u32>>(&mut self, name: &str, ids: I) -> Result<usize> {
+        match self.sets.get_mut(name) {
+            Some(set) => Ok(ids.into_iter().filter(|id| set.insert(*id)).count()),
+            None => Err(Collections::not_found(name)),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        match self.sets.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(Collections::not_found(name)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<&BTreeSet<u32>> {
+        self.sets.get(name).ok_or_else(|| Collections::not_found(name))
+    }
+
+    // names and sizes, sorted by name
+    pub fn list(&self) -> Vec<(&str, usize)> {
+        self.sets.iter().map(|(name, ids)| (name.as_str(), ids.len())).collect()
+    }
+
+    fn not_found(name: &str) -> Error {
+        Error::new(ErrorKind::NotFound, format!("no collection {}", name))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::remove_file;
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use search::SearchRequest;
+
+    #[test]
+    fn save_and_filter() {
+        {
+            let mut db = BinacleManager::create("test_coll.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "a.exe", 1, false);
+            db.insert_reader(&b"GetProcAddress"[..], "b.exe", 2, false);
+            db.insert_reader(&b"LoadLibraryA GetProcAddress"[..], "c.exe", 3, true);
+
+            let mut c = Collections::open("test_coll.db").unwrap();
+            c.create("case1").unwrap();
+            assert!(c.create("case1").is_err());
+            assert_eq!(2, c.add("case1", vec![1, 3, 3]).unwrap());
+            assert!(c.add("other", vec![1]).is_err());
+
+            // results saved as a collection
+            let response = db.run(&SearchRequest::pattern(b"LoadLibrary")).unwrap();
+            c.create("loaders").unwrap();
+            c.add("loaders", response.ids).unwrap();
+            c.save().unwrap();
+
+            let c = Collections::open("test_coll.db").unwrap();
+            assert_eq!(vec![("case1", 2), ("loaders", 1)], c.list());
+
+            let request = SearchRequest::pattern(b"GetProcAddress").within(c.get("case1").unwrap().clone());
+            assert_eq!(vec![1, 3], db.run(&request).unwrap().ids);
+
+            let mut c = c;
+            c.remove("case1").unwrap();
+            assert!(c.get("case1").is_err());
+        }
+        cleanup("test_coll");
+        let _ = remove_file("test_coll.collections");
+    }
+}
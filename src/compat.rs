@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::io::*;
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json::{self, Json};
+
+// Compatibility of the databases across the versions of Binacle. A fleet
+// mixes builds: a database written by a newer build is opened by an older
+// one, and the other way around.
+//
+// - the fields of a meta (the database meta, the meta of a shard) that a
+//   build does not know are kept as they were read and written back with
+//   the known ones: a field added by a newer build survives an older
+//   writer. Only the fields at the top of the meta are kept this way.
+// - the features of a shard an older build can not know about are
+//   announced in the flags word of its meta. The low half are optional: a
+//   reader ignoring them gives the same results, the shard is opened read
+//   only with the feature ignored. The high half are required: the shard
+//   can not be read without them and is refused. A shard is never opened
+//   for writing with a flag unknown to the build.
+//
+// A meta without flags, written before they existed, has none.
+
+
+// the flags whose feature must be understood to read the shard
+pub const REQUIRED_FLAGS: u64 = 0xffff_ffff_0000_0000;
+
+// the flags of this build, none yet: the features added from now on
+// take a bit, optional when an older reader can ignore them
+pub const KNOWN_FLAGS: u64 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// a shard with these flags can be opened by a build knowing known
+pub fn check_flags(path: &str, flags: u64, known: u64, access: Access) -> Result<()> {
+
+    let unknown = flags & !known;
+    if unknown & REQUIRED_FLAGS != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "{} needs features unknown to this version of Binacle (required flags {:#x}): \
+             upgrade Binacle to read it", path, unknown & REQUIRED_FLAGS)));
+    }
+    if unknown != 0 && access == Access::Write {
+        return Err(Error::new(ErrorKind::PermissionDenied, format!(
+            "{} uses features unknown to this version of Binacle (optional flags {:#x}): \
+             it can only be read, upgrade Binacle to write it", path, unknown)));
+    }
+    Ok(())
+}
+
+// the fields of a meta not known to the build, written back as read
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Unknown {
+    pub fields: BTreeMap<String, Json>,
+}
+
+impl Unknown {
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+fn invalid<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+// the known fields decoded, the other ones kept aside
+pub fn decode<T: Decodable + Encodable>(encoded: &str) -> Result<(T, Unknown)> {
+
+    let read = try!(Json::from_str(encoded).map_err(invalid));
+    let value: T = try!(Decodable::decode(&mut json::Decoder::new(read.clone())).map_err(invalid));
+
+    let mut unknown = Unknown::default();
+    let known = try!(Json::from_str(&try!(json::encode(&value).map_err(invalid))).map_err(invalid));
+    if let (Json::Object(read), Json::Object(known)) = (read, known) {
+        for (name, field) in read {
+            if !known.contains_key(&name) {
+                unknown.fields.insert(name, field);
+            }
+        }
+    }
+    Ok((value, unknown))
+}
+
+// the known fields encoded, the unknown ones added back. Without unknown
+// fields, the meta is the one json::encode gives
+pub fn encode<T: Encodable>(value: &T, unknown: &Unknown) -> Result<String> {
+
+    let encoded = try!(json::encode(value).map_err(invalid));
+    if unknown.is_empty() {
+        return Ok(encoded);
+    }
+    let mut fields = match try!(Json::from_str(&encoded).map_err(invalid)) {
+        Json::Object(fields) => fields,
+        _ => return Ok(encoded),
+    };
+    for (name, field) in &unknown.fields {
+        fields.entry(name.clone()).or_insert(field.clone());
+    }
+    Ok(Json::Object(fields).to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // a meta of this build
+    #[derive(RustcDecodable, RustcEncodable, Debug, PartialEq)]
+    struct Current {
+        nb_file: u32,
+        flags: Option<u64>,
+    }
+
+    // the same meta before flags existed
+    #[derive(RustcDecodable, RustcEncodable, Debug, PartialEq)]
+    struct Older {
+        nb_file: u32,
+    }
+
+    #[test]
+    fn unknown_fields_kept() {
+
+        // an older build rewrites the meta of a newer one
+        let newer = r#"{"nb_file":3,"flags":1,"zone_maps":{"bits":12}}"#;
+        let (mut meta, unknown) = decode::<Older>(newer).unwrap();
+        assert_eq!(Older { nb_file: 3 }, meta);
+        assert_eq!(2, unknown.fields.len());
+        meta.nb_file = 4;
+        let written = encode(&meta, &unknown).unwrap();
+        let (meta, unknown) = decode::<Current>(&written).unwrap();
+        assert_eq!(Current { nb_file: 4, flags: Some(1) }, meta);
+        assert_eq!(Some(&Json::from_str(r#"{"bits":12}"#).unwrap()), unknown.fields.get("zone_maps"));
+
+        // a newer build reads the meta of an older one, unchanged
+        let older = json::encode(&Older { nb_file: 2 }).unwrap();
+        let (meta, unknown) = decode::<Current>(&older).unwrap();
+        assert_eq!(Current { nb_file: 2, flags: None }, meta);
+        assert!(unknown.is_empty());
+        assert_eq!(json::encode(&meta).unwrap(), encode(&meta, &unknown).unwrap());
+
+        assert!(decode::<Current>("{\"flags\":1}").is_err());
+    }
+
+    #[test]
+    fn flags_matrix() {
+        let optional = 1u64 << 3;
+        let required = 1u64 << 40;
+        let cases = [
+            // flags, known, access, accepted
+            (0, 0, Access::Write, true),
+            (optional, optional, Access::Write, true),
+            (optional | required, optional | required, Access::Write, true),
+            // an older reader
+            (optional, 0, Access::Read, true),
+            (optional, 0, Access::Write, false),
+            (required, 0, Access::Read, false),
+            (required, optional, Access::Write, false),
+            // a newer reader of an older shard
+            (0, optional | required, Access::Read, true),
+            (0, optional | required, Access::Write, true),
+        ];
+        for &(flags, known, access, accepted) in cases.iter() {
+            assert_eq!(accepted, check_flags("t.db_index0.db", flags, known, access).is_ok(),
+                       "{:#x} {:#x} {:?}", flags, known, access);
+        }
+
+        let err = check_flags("t.db_index0.db", required | optional, 0, Access::Read).unwrap_err();
+        assert_eq!("t.db_index0.db needs features unknown to this version of Binacle (required flags 0x10000000000): \
+                    upgrade Binacle to read it", err.to_string());
+        let err = check_flags("t.db_index0.db", optional, 0, Access::Write).unwrap_err();
+        assert_eq!(ErrorKind::PermissionDenied, err.kind());
+    }
+}
@@ -0,0 +1,335 @@
+use std::env;
+use std::fs::File;
+use std::io::*;
+use std::path::PathBuf;
+use std::result;
+use docopt::{ArgvMap, Value};
+
+use cli;
+
+// Defaults of the options shared by the invocations, read from a TOML file:
+// --config=<file>, else ./binacle.toml, else
+// $XDG_CONFIG_HOME/binacle/config.toml ($HOME/.config without it). The first
+// file found is the only one read. Its keys are the long options without
+// their dashes, at the top of the file:
+//
+//   map-cap = 100000
+//   durability = "batch"
+//   color = "never"
+//   no-mmap-reads = true
+//
+// An option of the command line wins over the file, the file over the
+// default of the usage. A key is only applied to the commands reading its
+// option, the file is shared by all of them. Only the operational options
+// are read (KEYS): the ones fixing the format of a database stay explicit
+// parameters of its creation and are rejected (FORMAT), as are the options
+// of a query and the unknown keys.
+
+
+// the options a config file can give
+pub const KEYS: &'static [&'static str] = &[
+    "--map-cap", "--lazy-map", "--durability", "--file-budget-ms", "--ignore-space-check", "--no-prefetch",
+    "--bulk-memory",
+    "--color", "--no-truncate", "--json", "--format", "--preselect", "--variant-cap", "--deadline-ms", "--strict",
+    "--verify-shards", "--no-mmap-reads", "--rechain-threshold", "--exec-jobs", "--exec-timeout-ms",
+    "--max-copy-bytes", "--jobs",
+];
+
+// the parameters written in a database when it is created
+pub const FORMAT: &'static [&'static str] = &[
+    "--ngram-size", "--alignment", "--translate", "--deterministic", "--max-size", "--offset-size", "--map",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    Bool(bool),
+    // strings and numbers, given to the option as written
+    Text(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    // None when no file was found
+    pub path: Option<PathBuf>,
+    // the options and their value, in the order of the file
+    pub values: Vec<(String, ConfigValue)>,
+}
+
+// where the value of an option comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    CommandLine,
+    File(PathBuf),
+    Default,
+}
+
+impl Config {
+
+    // the file of --config, else the first one found
+    pub fn load(explicit: &str) -> Result<Config> {
+        let path = match explicit {
+            "" => match discover() {
+                Some(path) => path,
+                None => return Ok(Config::default()),
+            },
+            path => PathBuf::from(path),
+        };
+        let mut text = String::new();
+        try!(File::open(&path).and_then(|mut f| f.read_to_string(&mut text))
+             .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e))));
+        let values = try!(parse(&text).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))));
+        Ok(Config { path: Some(path), values: values })
+    }
+
+    // the values of the file given to the options the command reads and the
+    // command line left out, the names of these options
+    pub fn apply(&self, usage: &str, args: &mut ArgvMap) -> Result<Vec<String>> {
+
+        let options = cli::options(usage);
+        let accepted = cli::command(args).map(cli::accepted);
+        let mut applied = Vec::new();
+        for &(ref name, ref value) in &self.values {
+            if accepted.as_ref().map_or(false, |a| !a.contains(&name.as_str())) {
+                continue;
+            }
+            match options.get(name) {
+                Some(default) if !cli::given(args, name, default) => {},
+                _ => continue,
+            }
+            let given = match (args.map.find(name), value) {
+                (Some(&Value::Switch(_)), &ConfigValue::Bool(b)) => Value::Switch(b),
+                (Some(&Value::Plain(_)), &ConfigValue::Text(ref t)) => Value::Plain(Some(t.clone())),
+                (Some(&Value::Switch(_)), _) => return Err(self.invalid(name, "is a switch, true or false")),
+                (Some(&Value::Plain(_)), _) => return Err(self.invalid(name, "takes a value, not true or false")),
+                // not an option of the usage lines of the command
+                _ => continue,
+            };
+            *args.map.find_mut(name).unwrap() = given;
+            applied.push(name.clone());
+        }
+        Ok(applied)
+    }
+
+    fn invalid(&self, name: &str, what: &str) -> Error {
+        let path = self.path.as_ref().map_or(String::from("config"), |p| p.display().to_string());
+        Error::new(ErrorKind::InvalidData, format!("{}: {} {}", path, &name[2 ..], what))
+    }
+
+    // the options of the usage with a value once the file applied, the
+    // value and where it comes from
+    pub fn effective(&self, usage: &str, cli_args: &ArgvMap, args: &ArgvMap, applied: &[String]) -> Vec<(String, String, Source)> {
+        let mut effective = Vec::new();
+        for (name, default) in cli::options(usage) {
+            let value = match args.map.find(&name) {
+                Some(&Value::Switch(true)) => String::from("true"),
+                Some(&Value::Plain(Some(ref v))) => v.clone(),
+                _ => continue,
+            };
+            let source = if cli::given(cli_args, &name, &default) {
+                Source::CommandLine
+            } else if applied.contains(&name) {
+                Source::File(self.path.clone().unwrap_or_default())
+            } else {
+                Source::Default
+            };
+            effective.push((String::from(&name[2 ..]), value, source));
+        }
+        effective
+    }
+}
+
+// ./binacle.toml, then the one of the user
+pub fn discover() -> Option<PathBuf> {
+    let local = PathBuf::from("binacle.toml");
+    if local.is_file() {
+        return Some(local);
+    }
+    let base = match env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => return None,
+        },
+    };
+    let user = base.join("binacle").join("config.toml");
+    match user.is_file() {
+        true => Some(user),
+        false => None,
+    }
+}
+
+// the key of a line, checked against KEYS and FORMAT, as an option
+pub fn option_of(key: &str) -> result::Result<String, String> {
+    let name = format!("--{}", key.replace('_', "-"));
+    if FORMAT.contains(&name.as_str()) {
+        return Err(format!("{} sets the format of a database, it is a parameter of its creation and is not read from a config file", key));
+    }
+    if !KEYS.contains(&name.as_str()) {
+        return Err(format!("{} is not an option the config file sets", key));
+    }
+    Ok(name)
+}
+
+// the keys and values of a config file, the subset of TOML it needs: a key
+// and its value by line, the strings quoted, the numbers and the booleans
+// bare, the comments after a #
+pub fn parse(text: &str) -> result::Result<Vec<(String, ConfigValue)>, String> {
+
+    let mut values: Vec<(String, ConfigValue)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let at = |e: String| format!("line {}: {}", n + 1, e);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(at(String::from("tables are not read, the keys are at the top of the file")));
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let rest = match parts.next() {
+            Some(rest) => rest.trim(),
+            None => return Err(at(format!("{}: key = value expected", line))),
+        };
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(at(format!("invalid key: {}", key)));
+        }
+        let name = try!(option_of(key).map_err(&at));
+        let value = try!(parse_value(rest).map_err(|e| at(format!("{}: {}", key, e))));
+        if values.iter().any(|&(ref k, _)| *k == name) {
+            return Err(at(format!("{} is given twice", key)));
+        }
+        values.push((name, value));
+    }
+    Ok(values)
+}
+
+// a value and the comment after it
+fn parse_value(text: &str) -> result::Result<ConfigValue, String> {
+
+    let (value, rest) = match text.chars().next() {
+        Some('"') => {
+            let mut value = String::new();
+            let mut chars = text[1 ..].char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => { end = Some(i + 2); break; },
+                    '\\' => match chars.next().map(|(_, c)| c) {
+                        Some('"') => value.push('"'),
+                        Some('\\') => value.push('\\'),
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        other => return Err(format!("invalid escape \\{}", other.map_or(String::new(), |c| c.to_string()))),
+                    },
+                    c => value.push(c),
+                }
+            }
+            match end {
+                Some(end) => (ConfigValue::Text(value), &text[end ..]),
+                None => return Err(String::from("unterminated string")),
+            }
+        },
+        Some('\'') => match text[1 ..].find('\'') {
+            Some(end) => (ConfigValue::Text(String::from(&text[1 .. end + 1])), &text[end + 2 ..]),
+            None => return Err(String::from("unterminated string")),
+        },
+        _ => {
+            let end = text.find('#').unwrap_or(text.len());
+            let bare = text[.. end].trim();
+            let value = match bare {
+                "true" => ConfigValue::Bool(true),
+                "false" => ConfigValue::Bool(false),
+                _ if bare.parse::<f64>().is_ok() || bare.replace('_', "").parse::<i64>().is_ok() => {
+                    ConfigValue::Text(bare.replace('_', ""))
+                },
+                "" => return Err(String::from("no value")),
+                _ => return Err(format!("invalid value {}, a string is quoted", bare)),
+            };
+            (value, &text[end ..])
+        },
+    };
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected {} after the value", rest));
+    }
+    Ok(value)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use docopt::Docopt;
+
+    fn args(argv: &str) -> ArgvMap {
+        let argv = format!("binacle {}", argv);
+        Docopt::new(::USAGE).and_then(|d| d.argv(argv.split(' ')).parse()).unwrap()
+    }
+
+    fn config(text: &str) -> Config {
+        Config { path: Some(PathBuf::from("binacle.toml")), values: parse(text).unwrap() }
+    }
+
+    #[test]
+    fn parsed() {
+        let values = parse("# shared by the team\n\nmap-cap = 100_000\ndurability = \"batch\" # fsync by batch\n\
+                            color='never'\nno_mmap_reads = true\npreselect = 4\n").unwrap();
+        assert_eq!(vec![(String::from("--map-cap"), ConfigValue::Text(String::from("100000"))),
+                        (String::from("--durability"), ConfigValue::Text(String::from("batch"))),
+                        (String::from("--color"), ConfigValue::Text(String::from("never"))),
+                        (String::from("--no-mmap-reads"), ConfigValue::Bool(true)),
+                        (String::from("--preselect"), ConfigValue::Text(String::from("4")))], values);
+
+        assert_eq!("line 2: map-cap is given twice", parse("map-cap = 1\nmap-cap = 2").unwrap_err());
+        assert_eq!("line 1: tables are not read, the keys are at the top of the file", parse("[search]").unwrap_err());
+        assert_eq!("line 1: durability: unterminated string", parse("durability = \"batch").unwrap_err());
+        assert_eq!("line 1: durability: invalid value batch, a string is quoted", parse("durability = batch").unwrap_err());
+        assert_eq!("line 1: color: unexpected x after the value", parse("color = \"never\" x").unwrap_err());
+        assert_eq!("line 1: map-cap 1: key = value expected", parse("map-cap 1").unwrap_err());
+    }
+
+    #[test]
+    fn rejected_keys() {
+        // the format of a database is given when it is created
+        for key in &["ngram-size", "alignment", "translate", "ngram_size", "deterministic"] {
+            let e = parse(&format!("{} = 1", key)).unwrap_err();
+            assert!(e.contains("sets the format of a database"), "{}", e);
+        }
+        assert_eq!("line 1: limit is not an option the config file sets", parse("limit = 10").unwrap_err());
+        assert_eq!("line 1: threads is not an option the config file sets", parse("threads = 8").unwrap_err());
+    }
+
+    #[test]
+    fn precedence() {
+        let config = config("map-cap = 1000\npreselect = 4\nno-mmap-reads = true\nexec-jobs = 8\ncolor = \"never\"");
+
+        // the command line wins, the file fills what it leaves
+        let cli_args = args("t.db -s abc --preselect=2");
+        let mut merged = cli_args.clone();
+        let applied = config.apply(::USAGE, &mut merged).unwrap();
+        assert_eq!(vec!["--map-cap", "--no-mmap-reads", "--exec-jobs", "--color"], applied);
+        assert_eq!("2", merged.get_str("--preselect"));
+        assert_eq!("1000", merged.get_str("--map-cap"));
+        assert!(merged.get_bool("--no-mmap-reads"));
+        // a value of the file does not make a problem of the command line
+        assert!(cli::check(::USAGE, &cli_args).is_empty());
+
+        let effective = config.effective(::USAGE, &cli_args, &merged, &applied);
+        let source = |key: &str| effective.iter().find(|e| e.0 == key).map(|e| e.2.clone());
+        assert_eq!(Some(Source::CommandLine), source("preselect"));
+        assert_eq!(Some(Source::File(PathBuf::from("binacle.toml"))), source("map-cap"));
+        assert_eq!(Some(Source::Default), source("variant-cap"));
+
+        // only the options the command reads
+        let mut merged = args("t.db -f 1 a.bin");
+        let applied = config.apply(::USAGE, &mut merged).unwrap();
+        assert_eq!(vec!["--map-cap"], applied);
+        assert!(!merged.get_bool("--no-mmap-reads"));
+
+        let mut merged = args("t.db -s abc");
+        let wrong = Config { path: None, values: vec![(String::from("--lazy-map"), ConfigValue::Text(String::from("1")))] };
+        assert!(wrong.apply(::USAGE, &mut merged).unwrap_err().to_string().ends_with("lazy-map is a switch, true or false"));
+    }
+}
@@ -0,0 +1,169 @@
+use std::cmp;
+use std::fs::OpenOptions;
+use std::io::*;
+use twoway;
+
+// Bytes around the occurrences of a pattern in a file
+
+
+pub struct Context {
+    // offset of the match in the file
+    pub offset: u64,
+    // up to n bytes, less at the start of the file
+    pub before: Vec<u8>,
+    pub matched: Vec<u8>,
+    // up to n bytes, less at the end of the file
+    pub after: Vec<u8>,
+}
+
+// bytes of the file searched at once, the end of a chunk is kept at the
+// start of the next one for the occurrences across them
+const CHUNK_SIZE: usize = 1 << 20;
+
+// offsets of every occurrence of the pattern, overlapping ones included
+pub fn find_offsets(path: &str, pattern: &[u8]) -> Result<Vec<u64>> {
+    find_offsets_chunked(path, pattern, CHUNK_SIZE)
+}
+
+fn find_offsets_chunked(path: &str, pattern: &[u8], chunk_size: usize) -> Result<Vec<u64>> {
+
+    let mut offsets = Vec::new();
+    if pattern.is_empty() {
+        return Ok(offsets);
+    }
+    let mut file = try!(OpenOptions::new().read(true).open(path));
+    let kept = pattern.len() - 1;
+
+    // offset in the file of the first byte of the window
+    let mut base = 0u64;
+    let mut window = Vec::with_capacity(kept + chunk_size);
+    loop {
+        let filled = window.len();
+        window.resize(filled + chunk_size, 0);
+        let len = loop {
+            match file.read(&mut window[filled ..]) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                read => break try!(read),
+            }
+        };
+        window.truncate(filled + len);
+        if len == 0 {
+            return Ok(offsets);
+        }
+        // an occurrence found here starts before the bytes kept
+        let mut start = 0;
+        while let Some(pos) = twoway::find_bytes(&window[start ..], pattern) {
+            offsets.push(base + (start + pos) as u64);
+            start += pos + 1;
+        }
+        let end = window.len() - kept.min(window.len());
+        window.drain(.. end);
+        base += end as u64;
+    }
+}
+
+// context of n bytes around each occurrence (offsets are sorted)
+// nearby occurrences share their window, so each part of the file is read once
+pub fn read_contexts(path: &str, offsets: &[u64], len: usize, n: usize) -> Result<Vec<Context>> {
+
+    let mut file = try!(OpenOptions::new().read(true).open(path));
+    let file_len = try!(file.metadata()).len();
+
+    let mut contexts = Vec::with_capacity(offsets.len());
+    let mut i = 0;
+
+    while i < offsets.len() {
+
+        // merge the windows that overlap or touch
+        let start = offsets[i].saturating_sub(n as u64);
+        let mut end = cmp::min(offsets[i] + (len + n) as u64, file_len);
+        let mut j = i + 1;
+        while j < offsets.len() && offsets[j].saturating_sub(n as u64) <= end {
+            end = cmp::min(offsets[j] + (len + n) as u64, file_len);
+            j += 1;
+        }
+
+        try!(file.seek(SeekFrom::Start(start)));
+        let mut window = vec![0u8; (end - start) as usize];
+        try!(file.read_exact(&mut window));
+
+        for off in &offsets[i .. j] {
+            let m_start = (off - start) as usize;
+            let m_end = cmp::min(m_start + len, window.len());
+            let b_start = m_start.saturating_sub(n);
+            let a_end = cmp::min(m_end + n, window.len());
+            contexts.push(Context {
+                offset: *off,
+                before: Vec::from(&window[b_start .. m_start]),
+                matched: Vec::from(&window[m_start .. m_end]),
+                after: Vec::from(&window[m_end .. a_end]),
+            });
+        }
+        i = j;
+    }
+    Ok(contexts)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{File, remove_file};
+
+    #[test]
+    fn clipped_contexts() {
+
+        File::create("test_context.bin").unwrap().write_all(b"abcdXXefghijklmnopXX").unwrap();
+
+        let offsets = find_offsets("test_context.bin", b"XX").unwrap();
+        assert_eq!(vec![4, 18], offsets);
+        // the same across the chunks
+        for chunk_size in 1 .. 8 {
+            assert_eq!(offsets, find_offsets_chunked("test_context.bin", b"XX", chunk_size).unwrap());
+        }
+
+        let contexts = read_contexts("test_context.bin", &offsets, 2, 6).unwrap();
+        assert_eq!(2, contexts.len());
+
+        // start of the file
+        assert_eq!(b"abcd".to_vec(), contexts[0].before);
+        assert_eq!(b"XX".to_vec(), contexts[0].matched);
+        assert_eq!(b"efghij".to_vec(), contexts[0].after);
+
+        // end of the file
+        assert_eq!(b"klmnop".to_vec(), contexts[1].before);
+        assert_eq!(b"XX".to_vec(), contexts[1].matched);
+        assert!(contexts[1].after.is_empty());
+
+        let _ = remove_file("test_context.bin");
+    }
+
+    #[test]
+    fn coalesced_contexts() {
+
+        File::create("test_context2.bin").unwrap().write_all(b"..XX..XX..").unwrap();
+
+        let offsets = find_offsets("test_context2.bin", b"XX").unwrap();
+        assert_eq!(vec![2, 6], offsets);
+        let contexts = read_contexts("test_context2.bin", &offsets, 2, 3).unwrap();
+        assert_eq!(b"..".to_vec(), contexts[0].before);
+        assert_eq!(b"..X".to_vec(), contexts[0].after);
+        assert_eq!(b"X..".to_vec(), contexts[1].before);
+        assert_eq!(b"..".to_vec(), contexts[1].after);
+
+        let _ = remove_file("test_context2.bin");
+    }
+
+    #[test]
+    fn overlapping_offsets() {
+
+        File::create("test_context3.bin").unwrap().write_all(b"aXXXXbXX").unwrap();
+        for chunk_size in 1 .. 10 {
+            assert_eq!(vec![1, 2, 3, 6], find_offsets_chunked("test_context3.bin", b"XX", chunk_size).unwrap());
+        }
+        assert!(find_offsets("test_context3.bin", b"").unwrap().is_empty());
+
+        let _ = remove_file("test_context3.bin");
+    }
+}
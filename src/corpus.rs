@@ -0,0 +1,291 @@
+use std::collections::HashSet;
+use std::f64::consts::PI;
+use std::fs::{self, File, OpenOptions};
+use std::io::*;
+use std::path::Path;
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json;
+
+use binacle_manager::BinacleManager;
+use search::{Rng, SearchRequest, sample_ids};
+
+// Synthetic corpus: pseudorandom files of a given size distribution, some of
+// them holding planted patterns. The same seed gives the same corpus. The
+// ground truth tells which files hold which pattern, evaluate() searches the
+// patterns and compares the results to it.
+
+
+// the drawn sizes are clamped to this
+pub const MAX_FILE_SIZE: u64 = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SizeDist {
+    // the log of the size follows a normal law
+    LogNormal { mean: f64, sigma: f64 },
+}
+
+impl SizeDist {
+
+    // "lognormal:<mean>,<sigma>", of the log of the size
+    pub fn parse(s: &str) -> Result<SizeDist> {
+
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid size distribution: {}", s));
+
+        if !s.starts_with("lognormal:") {
+            return Err(invalid());
+        }
+        let fields: Vec<&str> = s["lognormal:".len() ..].split(',').collect();
+        if fields.len() != 2 {
+            return Err(invalid());
+        }
+        let mean = try!(fields[0].parse::<f64>().map_err(|_| invalid()));
+        let sigma = try!(fields[1].parse::<f64>().map_err(|_| invalid()));
+        if !mean.is_finite() || !sigma.is_finite() || sigma < 0.0 {
+            return Err(invalid());
+        }
+        Ok(SizeDist::LogNormal { mean: mean, sigma: sigma })
+    }
+
+    fn draw(&self, rng: &mut Rng) -> u64 {
+        match *self {
+            SizeDist::LogNormal { mean, sigma } => {
+                // Box-Muller, the first draw must not be 0
+                let u1 = 1.0 - rng.unit();
+                let u2 = rng.unit();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+                let size = (mean + sigma * z).exp();
+                if size >= MAX_FILE_SIZE as f64 { MAX_FILE_SIZE } else { size as u64 }
+            },
+        }
+    }
+}
+
+// a pattern written in count files
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plant {
+    pub pattern: Vec<u8>,
+    pub count: usize,
+}
+
+impl Plant {
+
+    // "<hex>@<count>", several of them separated by commas
+    pub fn parse_list(s: &str) -> Result<Vec<Plant>> {
+
+        let mut plants = Vec::new();
+        for spec in s.split(',').filter(|x| !x.is_empty()) {
+
+            let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid planted pattern: {}", spec));
+
+            let mut fields = spec.splitn(2, '@');
+            let pattern = try!(fields.next().unwrap().from_hex().map_err(|_| invalid()));
+            let count = try!(fields.next().ok_or_else(invalid)
+                                   .and_then(|c| c.parse::<usize>().map_err(|_| invalid())));
+            if pattern.len() < 4 {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("planted pattern shorter than 4 bytes: {}", spec)));
+            }
+            plants.push(Plant { pattern: pattern, count: count });
+        }
+        Ok(plants)
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct PlantedPattern {
+    // hexadecimal
+    pub pattern: String,
+    pub files: Vec<String>,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct Truth {
+    pub seed: u64,
+    pub files: Vec<String>,
+    pub patterns: Vec<PlantedPattern>,
+}
+
+impl Truth {
+
+    pub fn read(path: &str) -> Result<Truth> {
+        let mut content = String::new();
+        try!(try!(OpenOptions::new().read(true).open(path)).read_to_string(&mut content));
+        json::decode(&content).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e)))
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let content = try!(json::encode(self).map_err(|e| Error::new(ErrorKind::Other, e.to_string())));
+        try!(File::create(path)).write_all(content.as_bytes())
+    }
+}
+
+// write nb_files files in dir, they are named by their number
+pub fn generate(dir: &str, nb_files: usize, dist: SizeDist, seed: u64, plants: &[Plant]) -> Result<Truth> {
+
+    let mut rng = Rng(seed);
+
+    // files of each planted pattern
+    let all: Vec<u32> = (0 .. nb_files as u32).collect();
+    let mut planted: Vec<Vec<usize>> = vec![Vec::new(); nb_files];
+    for (p, plant) in plants.iter().enumerate() {
+        if plant.count > nb_files {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "{} planted in {} files, the corpus only has {}", plant.pattern.to_hex(), plant.count, nb_files)));
+        }
+        for n in sample_ids(&all, plant.count, rng.next()) {
+            planted[n as usize].push(p);
+        }
+    }
+
+    try!(fs::create_dir_all(dir));
+
+    let mut truth = Truth {
+        seed: seed,
+        files: Vec::with_capacity(nb_files),
+        patterns: plants.iter().map(|p| PlantedPattern { pattern: p.pattern.to_hex(), files: Vec::new() }).collect(),
+    };
+
+    for (n, patterns) in planted.iter().enumerate() {
+
+        let path = Path::new(dir).join(format!("{:06}.bin", n));
+        let path = String::from(path.to_str().unwrap());
+
+        // each pattern is written in its own slice of the file
+        let longest = patterns.iter().map(|p| plants[*p].pattern.len() as u64).max().unwrap_or(0);
+        let size = dist.draw(&mut rng).max(longest * patterns.len() as u64);
+
+        let mut content = Vec::with_capacity(size as usize);
+        while (content.len() as u64) < size {
+            let word = rng.next();
+            for i in 0 .. 8 {
+                content.push((word >> (8 * i)) as u8);
+            }
+        }
+        content.truncate(size as usize);
+
+        if !patterns.is_empty() {
+            let slice = size / patterns.len() as u64;
+            for (i, p) in patterns.iter().enumerate() {
+                let pattern = &plants[*p].pattern;
+                let offset = (i as u64 * slice + rng.below(slice - pattern.len() as u64 + 1)) as usize;
+                content[offset .. offset + pattern.len()].copy_from_slice(pattern);
+                truth.patterns[*p].files.push(path.clone());
+            }
+        }
+
+        try!(try!(File::create(&path)).write_all(&content));
+        truth.files.push(path);
+    }
+
+    Ok(truth)
+}
+
+// search results of a planted pattern against the truth
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    pub pattern: String,
+    pub expected: usize,
+    pub found: usize,
+    // results that are not planted files
+    pub false_positives: usize,
+    // planted files not found
+    pub missed: Vec<String>,
+}
+
+impl Evaluation {
+
+    pub fn recall(&self) -> f64 {
+        match self.expected {
+            0 => 1.0,
+            n => self.found as f64 / n as f64,
+        }
+    }
+}
+
+// paths are compared once made absolute, the corpus can be indexed from elsewhere
+pub fn normalize(path: &str) -> String {
+    match fs::canonicalize(path) {
+        Ok(p) => String::from(p.to_str().unwrap_or(path)),
+        Err(_) => String::from(path),
+    }
+}
+
+pub fn evaluate(db: &mut BinacleManager, truth: &Truth) -> Result<Vec<Evaluation>> {
+
+    if !db.is_map() {
+        return Err(Error::new(ErrorKind::Other, "evaluation needs a map database"));
+    }
+
+    let mut report = Vec::with_capacity(truth.patterns.len());
+    for planted in &truth.patterns {
+
+        let pattern = try!(planted.pattern.from_hex().map_err(|_| {
+            Error::new(ErrorKind::InvalidData, format!("invalid pattern in the truth: {}", planted.pattern))
+        }));
+        let response = try!(db.run(&SearchRequest::pattern(&pattern)));
+
+        let found: HashSet<String> = response.entries.iter().map(|p| normalize(p)).collect();
+        let expected: HashSet<String> = planted.files.iter().map(|p| normalize(p)).collect();
+
+        let mut missed: Vec<String> = planted.files.iter().filter(|p| !found.contains(&normalize(p))).cloned().collect();
+        missed.sort();
+
+        report.push(Evaluation {
+            pattern: planted.pattern.clone(),
+            expected: expected.len(),
+            found: expected.len() - missed.len(),
+            false_positives: found.difference(&expected).count(),
+            missed: missed,
+        });
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle_manager::tests::cleanup;
+
+    #[test]
+    fn generate_and_evaluate() {
+
+        let _ = fs::remove_dir_all("test_gen_corpus");
+        let plants = Plant::parse_list("c0a80101@5,4765745072@20").unwrap();
+        let dist = SizeDist::parse("lognormal:6,1").unwrap();
+
+        let truth = generate("test_gen_corpus", 20, dist, 42, &plants).unwrap();
+        assert_eq!(20, truth.files.len());
+        assert_eq!(5, truth.patterns[0].files.len());
+        assert_eq!(20, truth.patterns[1].files.len());
+
+        // same seed, same corpus
+        let first = fs::read("test_gen_corpus/000007.bin").unwrap();
+        assert_eq!(truth, generate("test_gen_corpus", 20, dist, 42, &plants).unwrap());
+        assert_eq!(first, fs::read("test_gen_corpus/000007.bin").unwrap());
+
+        truth.write("test_gen_corpus.json").unwrap();
+        let truth = Truth::read("test_gen_corpus.json").unwrap();
+        {
+            let mut db = BinacleManager::create("test_gen.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_dir_recursive("test_gen_corpus").unwrap();
+
+            let report = evaluate(&mut db, &truth).unwrap();
+            assert_eq!(2, report.len());
+            for e in &report {
+                assert_eq!(1.0, e.recall());
+                assert!(e.missed.is_empty());
+            }
+            assert_eq!(0, report[1].false_positives);
+        }
+
+        assert!(Plant::parse_list("c0a8@5").is_err());
+        assert!(Plant::parse_list("c0a80101").is_err());
+        assert!(SizeDist::parse("normal:1,2").is_err());
+        assert!(generate("test_gen_corpus", 2, dist, 1, &plants).is_err());
+
+        cleanup("test_gen");
+        let _ = fs::remove_file("test_gen_corpus.json");
+        let _ = fs::remove_dir_all("test_gen_corpus");
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::*;
+
+// Dates given on the command line and printed, in UTC: the days are
+// converted to and from the civil calendar without a date crate.
+
+
+// "YYYY-MM-DD HH:MM:SS UTC" from seconds since the epoch
+pub fn format_date(secs: u64) -> String {
+
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // civil date from the number of days since 1970-01-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, (rem / 60) % 60, rem % 60)
+}
+
+// number of days since 1970-01-01 of a civil date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// seconds since the epoch of YYYY-MM-DD, YYYY-MM-DD HH:MM[:SS] (a T
+// between them, a final Z or UTC are accepted) or @<seconds>
+pub fn parse_date(date: &str) -> Result<u64> {
+
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!(
+        "invalid date {}, YYYY-MM-DD[ HH:MM[:SS]] (UTC) or @<seconds> expected", date));
+
+    let date = date.trim();
+    if date.starts_with('@') {
+        return date[1 ..].parse::<u64>().map_err(|_| invalid());
+    }
+    let date = date.trim_end_matches("UTC").trim_end_matches('Z').trim();
+    let (day, time) = match date.find(|c| c == 'T' || c == ' ') {
+        Some(i) => (&date[.. i], date[i + 1 ..].trim()),
+        None => (date, ""),
+    };
+
+    let number = |s: &str, digits: usize| match s.len() == digits && s.bytes().all(|b| b.is_ascii_digit()) {
+        true => Ok(s.parse::<i64>().unwrap()),
+        false => Err(invalid()),
+    };
+    let day: Vec<&str> = day.split('-').collect();
+    if day.len() != 3 {
+        return Err(invalid());
+    }
+    let (y, m, d) = (try!(number(day[0], 4)), try!(number(day[1], 2)), try!(number(day[2], 2)));
+    let time: Vec<&str> = match time {
+        "" => Vec::new(),
+        t => t.split(':').collect(),
+    };
+    if time.len() == 1 || time.len() > 3 {
+        return Err(invalid());
+    }
+    let mut hms = [0i64; 3];
+    for (i, part) in time.iter().enumerate() {
+        hms[i] = try!(number(part, 2));
+    }
+
+    let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if y < 1970 || m < 1 || m > 12 || d < 1 || d > month_days[m as usize - 1] || hms[0] > 23 || hms[1] > 59 || hms[2] > 59 {
+        return Err(invalid());
+    }
+    Ok((days_from_civil(y, m, d) * 86400 + hms[0] * 3600 + hms[1] * 60 + hms[2]) as u64)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!("1970-01-01 00:00:00 UTC", format_date(0));
+        assert_eq!("2017-06-07 13:30:05 UTC", format_date(1496842205));
+        assert_eq!("2024-02-29 23:59:59 UTC", format_date(1709251199));
+    }
+
+    #[test]
+    fn parsed() {
+        assert_eq!(0, parse_date("1970-01-01").unwrap());
+        assert_eq!(1496842205, parse_date("2017-06-07 13:30:05").unwrap());
+        assert_eq!(1496842205, parse_date("2017-06-07T13:30:05Z").unwrap());
+        assert_eq!(1496842200, parse_date("2017-06-07 13:30 UTC").unwrap());
+        assert_eq!(1709251199, parse_date("2024-02-29 23:59:59").unwrap());
+        assert_eq!(1709251199, parse_date("@1709251199").unwrap());
+        for secs in &[0, 951782400, 1496842205, 4102444800] {
+            assert_eq!(*secs, parse_date(&format_date(*secs)).unwrap());
+        }
+        for bad in &["", "2017", "2017-6-07", "2023-02-29", "2017-06-07 25:00", "2017-06-07 13", "1969-12-31", "@x", "2017-06-07x"] {
+            assert!(parse_date(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+
+// The n-grams already written for the file being inserted. A file repeats
+// the same n-grams many times (padding, tables, repeated structures): only
+// the first occurrence of a reduced n-gram is written, the next ones would
+// find the id already at the end of the list and leave it unchanged. The
+// results of the searches are the same.
+//
+// The reduced n-grams seen are kept in a hash set, bounded: past SEEN_CAP
+// of them it spills into a bitset of all the reduced n-grams (2^ngram_size
+// bits, 32 MB for 28 bits), allocated once and kept for the next files.
+
+
+// distinct n-grams of a file held in the hash set before the bitset is used
+pub const SEEN_CAP: usize = 1 << 18;
+
+pub struct SeenSet {
+    ngram_size: u8,
+    small: HashSet<u32>,
+    // empty until a file has more than SEEN_CAP distinct n-grams
+    bits: Vec<u64>,
+    spilled: bool,
+}
+
+impl SeenSet {
+
+    pub fn new(ngram_size: u8) -> SeenSet {
+        SeenSet {
+            ngram_size: ngram_size,
+            small: HashSet::new(),
+            bits: Vec::new(),
+            spilled: false,
+        }
+    }
+
+    // true the first time this reduced n-gram is seen since the last clear
+    pub fn insert(&mut self, ngram: u32) -> bool {
+        if !self.spilled {
+            if self.small.len() < SEEN_CAP {
+                return self.small.insert(ngram);
+            }
+            if self.small.contains(&ngram) {
+                return false;
+            }
+            self.spill();
+        }
+        let (word, bit) = ((ngram >> 6) as usize, 1u64 << (ngram & 63));
+        let first = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        first
+    }
+
+    fn spill(&mut self) {
+        if self.bits.is_empty() {
+            self.bits = vec![0; ((1u64 << self.ngram_size) as usize + 63) / 64];
+        }
+        for ngram in self.small.drain() {
+            self.bits[(ngram >> 6) as usize] |= 1u64 << (ngram & 63);
+        }
+        self.spilled = true;
+    }
+
+    // for the next file, the memory is kept
+    pub fn clear(&mut self) {
+        if self.spilled {
+            for word in self.bits.iter_mut() {
+                *word = 0;
+            }
+            self.spilled = false;
+        }
+        self.small.clear();
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.spilled
+    }
+}
+
+// n-grams of a file and the ones written to the index
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DedupStats {
+    pub ngrams: u64,
+    pub writes: u64,
+}
+
+impl DedupStats {
+
+    // n-grams read for each one written, 1 when nothing was suppressed
+    pub fn ratio(&self) -> f64 {
+        match self.writes {
+            0 => 1.0,
+            writes => self.ngrams as f64 / writes as f64,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn spilled_into_the_bitset() {
+        let mut seen = SeenSet::new(20);
+        for ngram in 0 .. SEEN_CAP as u32 {
+            assert!(seen.insert(ngram));
+        }
+        assert!(!seen.is_spilled());
+        assert!(!seen.insert(5));
+        // past the cap: the n-grams of the hash set are still seen
+        assert!(seen.insert(1 << 19));
+        assert!(seen.is_spilled());
+        assert!(!seen.insert(5) && !seen.insert(1 << 19));
+        assert!(seen.insert((1 << 20) - 1));
+
+        seen.clear();
+        assert!(!seen.is_spilled());
+        assert!(seen.insert(5) && seen.insert(1 << 19));
+        assert!(!seen.insert(5));
+
+        assert_eq!(4.0, DedupStats { ngrams: 8, writes: 2 }.ratio());
+        assert_eq!(1.0, DedupStats::default().ratio());
+    }
+}
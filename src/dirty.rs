@@ -0,0 +1,51 @@
+// Dirty reads: the search of a shard still written by another process,
+// without waiting for its lock. The writer holds the exclusive lock of its
+// shard for the whole insertion, a reader taking the shared lock waits for
+// its end; with --dirty-read the finalized shards are opened as usual and
+// the live ones without their lock.
+//
+// The live shard is read as of its meta when it is opened: the size and the
+// last id of the meta are snapshotted and the reader never follows what
+// was written after them. The writer grows the shard under the reader,
+// so:
+//
+// - the lookups use positioned reads (see reads.rs), a page the writer
+//   changes is read as it is at that moment and a read past the end is an
+//   error, never a fault of the map;
+// - a header entry or a previous block out of the snapshot is an empty
+//   list: the block was allocated after the meta was written, or the
+//   entry is being written. The blocks of a chain are allocated one after
+//   the other, the previous block of a chain starts before it: one that
+//   does not is the end of the chain, a torn read can not make it loop;
+// - a block whose content would end out of the snapshot is read as empty,
+//   the ids after the last id of the snapshot are dropped: they are the
+//   ones of the files being inserted, possibly partly.
+//
+// A dirty read can only miss the files inserted since the meta was written
+// (the writer writes it when it flushes the shard), not return ids the
+// shard does not have: the results are labeled as such.
+
+
+// reads of the meta of a live shard, the writer may be rewriting it
+pub const META_TRIES: u32 = 5;
+
+// the part of a live shard a dirty reader follows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Snapshot {
+    pub size: u64,
+    pub last_id: u32,
+}
+
+impl Snapshot {
+
+    // the len bytes at offset were written before the snapshot
+    pub fn holds(&self, offset: u64, len: u64) -> bool {
+        offset.checked_add(len).map_or(false, |end| end <= self.size)
+    }
+}
+
+// the note of the results of a dirty read of a shard
+pub fn describe(shard: usize, snapshot: &Snapshot) -> String {
+    format!("dirty read: shard {} read without its lock as of {} bytes and id {}, \
+             the files inserted since may be missing", shard, snapshot.size, snapshot.last_id)
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::*;
+use std::time::{Duration, Instant};
+
+// When the inserted data is written and synced to the disk
+//
+// None      nothing is synced while inserting, the meta and the map are
+//           written when the manager is dropped. After a crash the database
+//           can be in any state, including unreadable: rebuild it.
+// Periodic  every `files` inserted files or `seconds` seconds (the first
+//           reached, 0 disables a limit), the index, the map and the meta
+//           are synced. After a crash the database is the one of the last
+//           sync: the files inserted since then must be inserted again.
+// PerFile   everything is synced after each file. After a crash every file
+//           whose insertion returned is in the database.
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    None,
+    Periodic { files: u32, seconds: u64 },
+    PerFile,
+}
+
+// what is synced, in this order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushEvent {
+    Index,
+    Map,
+    Meta,
+}
+
+impl Durability {
+
+    // "none", "per-file" or "periodic:<files>:<seconds>"
+    pub fn parse(s: &str) -> Result<Durability> {
+
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid durability: {}", s));
+
+        match s {
+            "none" => Ok(Durability::None),
+            "per-file" => Ok(Durability::PerFile),
+            _ if s.starts_with("periodic:") => {
+                let fields: Vec<&str> = s["periodic:".len() ..].split(':').collect();
+                if fields.len() != 2 {
+                    return Err(invalid());
+                }
+                let files = try!(fields[0].parse::<u32>().map_err(|_| invalid()));
+                let seconds = try!(fields[1].parse::<u64>().map_err(|_| invalid()));
+                Ok(Durability::Periodic { files: files, seconds: seconds })
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Durability::None => write!(f, "none"),
+            Durability::Periodic { files, seconds } => write!(f, "periodic:{}:{}", files, seconds),
+            Durability::PerFile => write!(f, "per-file"),
+        }
+    }
+}
+
+// files inserted and time elapsed since the last sync
+pub struct SyncState {
+    files: u32,
+    last: Instant,
+}
+
+impl SyncState {
+
+    pub fn new() -> SyncState {
+        SyncState {
+            files: 0,
+            last: Instant::now(),
+        }
+    }
+
+    // one more file inserted, tells if it is time to sync
+    pub fn inserted(&mut self, policy: Durability) -> bool {
+        self.files += 1;
+        match policy {
+            Durability::None => false,
+            Durability::PerFile => true,
+            Durability::Periodic { files, seconds } => {
+                (files > 0 && self.files >= files) ||
+                (seconds > 0 && self.last.elapsed() >= Duration::from_secs(seconds))
+            },
+        }
+    }
+
+    // files waiting for a sync
+    pub fn pending(&self) -> u32 {
+        self.files
+    }
+
+    pub fn synced(&mut self) {
+        self.files = 0;
+        self.last = Instant::now();
+    }
+}
+
+// flush a file written by another handle to the disk
+pub fn sync_path(path: &str) -> Result<()> {
+    try!(OpenOptions::new().read(true).open(path)).sync_all()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_policies() {
+        assert_eq!(Durability::None, Durability::parse("none").unwrap());
+        assert_eq!(Durability::PerFile, Durability::parse("per-file").unwrap());
+        assert_eq!(Durability::Periodic { files: 100, seconds: 5 }, Durability::parse("periodic:100:5").unwrap());
+        assert_eq!("periodic:100:5", Durability::parse("periodic:100:5").unwrap().to_string());
+        assert!(Durability::parse("periodic:100").is_err());
+        assert!(Durability::parse("always").is_err());
+    }
+
+    #[test]
+    fn periodic_by_files() {
+        let policy = Durability::Periodic { files: 2, seconds: 0 };
+        let mut state = SyncState::new();
+        assert!(!state.inserted(policy));
+        assert!(state.inserted(policy));
+        state.synced();
+        assert!(!state.inserted(policy));
+    }
+}
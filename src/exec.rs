@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::io::*;
+use std::process::{Command, Stdio, ExitStatus};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use binacle_manager::BinacleManager;
+
+// Command run on the results of a search (--exec), to hand them to the
+// triage tools. The command is split into words like a shell would, but
+// without its expansions: a path is given to the program as one argument
+// whatever it holds, in place of the words {} or in the environment
+// (BINACLE_ID, BINACLE_PATH, BINACLE_SHA256, BINACLE_QUERY). The shell only
+// runs the command with --exec-shell, the paths are then single-quoted.
+//
+// A command is run per hit, or per batch of hits, on jobs threads. Its exit
+// code is counted, a failure does not stop the others.
+
+
+// paths given to one invocation of a batch command
+pub const BATCH_MAX: usize = 256;
+
+// a result of the search, the path is the id when the database has no map
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hit {
+    pub id: u32,
+    pub path: String,
+    pub sha256: String,
+}
+
+pub fn hits(db: &BinacleManager, ids: &[u32]) -> Vec<Hit> {
+    ids.iter().map(|id| {
+        let entry = db.entry(*id);
+        Hit {
+            id: *id,
+            path: entry.map_or_else(|| id.to_string(), |e| e.path.clone()),
+            sha256: entry.and_then(|e| e.sha256.clone()).unwrap_or_default(),
+        }
+    }).collect()
+}
+
+// the words of a command: separated by blanks, '...' taken as is, "..."
+// and the blanks with \ escapes
+pub fn split_words(command: &str) -> Result<Vec<String>> {
+
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\'' => loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => word.get_or_insert_with(String::new).push(c),
+                    None => return Err(Error::new(ErrorKind::InvalidInput, format!("unterminated ' in {}", command))),
+                }
+            },
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if c == '"' || c == '\\' => w.push(c),
+                            Some(c) => {
+                                w.push('\\');
+                                w.push(c);
+                            },
+                            None => return Err(Error::new(ErrorKind::InvalidInput, format!("unterminated \" in {}", command))),
+                        },
+                        Some(c) => w.push(c),
+                        None => return Err(Error::new(ErrorKind::InvalidInput, format!("unterminated \" in {}", command))),
+                    }
+                }
+            },
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(Error::new(ErrorKind::InvalidInput, format!("nothing escaped at the end of {}", command))),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    match words.is_empty() {
+        true => Err(Error::new(ErrorKind::InvalidInput, "empty command")),
+        false => Ok(words),
+    }
+}
+
+// a word of the shell, whatever it holds
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// exit codes of the runs of the command
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecSummary {
+    pub runs: usize,
+    pub codes: BTreeMap<i32, usize>,
+    // killed by a signal
+    pub signaled: usize,
+    pub timed_out: usize,
+    // the command could not be started
+    pub not_started: usize,
+}
+
+impl ExecSummary {
+
+    pub fn failed(&self) -> usize {
+        self.runs - self.codes.get(&0).cloned().unwrap_or(0)
+    }
+
+    fn add(&mut self, other: &ExecSummary) {
+        self.runs += other.runs;
+        for (code, n) in &other.codes {
+            *self.codes.entry(*code).or_insert(0) += *n;
+        }
+        self.signaled += other.signaled;
+        self.timed_out += other.timed_out;
+        self.not_started += other.not_started;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Hook {
+    command: String,
+    words: Vec<String>,
+    query: String,
+    shell: bool,
+    batch: bool,
+    jobs: usize,
+    timeout: Option<Duration>,
+}
+
+impl Hook {
+
+    // the command run on the hits of query
+    pub fn new(command: &str, query: &str) -> Result<Hook> {
+        Ok(Hook {
+            command: String::from(command),
+            words: try!(split_words(command)),
+            query: String::from(query),
+            shell: false,
+            batch: false,
+            jobs: 1,
+            timeout: None,
+        })
+    }
+
+    // run by the shell, the paths quoted
+    pub fn shell(mut self) -> Hook {
+        self.shell = true;
+        self
+    }
+
+    // the paths of BATCH_MAX hits given to one invocation, in place of a
+    // word {} or after the last word
+    pub fn batch(mut self) -> Hook {
+        self.batch = true;
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Hook {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    // an invocation still running is killed after it
+    pub fn timeout(mut self, timeout: Duration) -> Hook {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // the command of these hits, one unless batch
+    pub fn command(&self, hits: &[Hit]) -> Command {
+
+        let paths: Vec<&str> = hits.iter().map(|h| h.path.as_str()).collect();
+        let mut command = match self.shell {
+            true => {
+                let quoted = paths.iter().map(|p| shell_quote(p)).collect::<Vec<String>>().join(" ");
+                let mut line = self.command.replace("{}", &quoted);
+                if self.batch && !self.command.contains("{}") {
+                    line = format!("{} {}", line, quoted);
+                }
+                let mut command = match cfg!(windows) {
+                    true => Command::new("cmd"),
+                    false => Command::new("sh"),
+                };
+                command.arg(if cfg!(windows) { "/C" } else { "-c" }).arg(line);
+                command
+            },
+            false => {
+                let mut command = Command::new(&self.words[0]);
+                for word in &self.words[1 ..] {
+                    match (self.batch, word.as_str()) {
+                        (true, "{}") => { command.args(&paths); },
+                        (_, word) => { command.arg(word.replace("{}", &paths.join(" "))); },
+                    }
+                }
+                if self.batch && !self.words.iter().any(|w| w.contains("{}")) {
+                    command.args(&paths);
+                }
+                command
+            },
+        };
+
+        command.env("BINACLE_QUERY", &self.query).stdin(Stdio::null());
+        match hits {
+            [hit] => command.env("BINACLE_ID", hit.id.to_string())
+                            .env("BINACLE_PATH", &hit.path)
+                            .env("BINACLE_SHA256", &hit.sha256),
+            _ => command.env("BINACLE_IDS", hits.iter().map(|h| h.id.to_string()).collect::<Vec<String>>().join(" ")),
+        };
+        command
+    }
+
+    // run the command of one group of hits, until it ends or times out
+    fn run_one(&self, hits: &[Hit]) -> ExecSummary {
+
+        let mut summary = ExecSummary { runs: 1, ..ExecSummary::default() };
+        let mut child = match self.command(hits).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Warning: {} not started: {}", self.words[0], e);
+                summary.not_started += 1;
+                return summary;
+            },
+        };
+
+        let start = Instant::now();
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) => {},
+                Err(_) => break None,
+            }
+            if self.timeout.map_or(false, |t| start.elapsed() > t) {
+                let _ = child.kill();
+                let _ = child.wait();
+                summary.timed_out += 1;
+                return summary;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        match status.as_ref().and_then(ExitStatus::code) {
+            Some(code) => { summary.codes.insert(code, 1); },
+            None => summary.signaled += 1,
+        }
+        summary
+    }
+
+    // run the command on all the hits, jobs invocations at a time
+    pub fn run(&self, hits: &[Hit]) -> ExecSummary {
+
+        let groups: Vec<&[Hit]> = match self.batch {
+            true => hits.chunks(BATCH_MAX).collect(),
+            false => hits.chunks(1).collect(),
+        };
+        let next = AtomicUsize::new(0);
+        let summary = Mutex::new(ExecSummary::default());
+        thread::scope(|scope| {
+            for _ in 0 .. self.jobs.min(groups.len()) {
+                scope.spawn(|| loop {
+                    let n = next.fetch_add(1, Ordering::SeqCst);
+                    if n >= groups.len() {
+                        break;
+                    }
+                    let one = self.run_one(groups[n]);
+                    summary.lock().unwrap().add(&one);
+                });
+            }
+        });
+        summary.into_inner().unwrap()
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+
+    fn hit(id: u32, path: &str) -> Hit {
+        Hit { id: id, path: String::from(path), sha256: format!("{:064x}", id) }
+    }
+
+    fn read(path: &str) -> String {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(vec!["cp", "{}", "out dir/"], split_words("cp {}  'out dir/'").unwrap());
+        assert_eq!(vec!["a\"b", "c d", "e\\f", "g h"], split_words(r#"a\"b "c d" "e\f" g\ h"#).unwrap());
+        assert_eq!(vec!["it's"], split_words(r#""it's""#).unwrap());
+        assert!(split_words("echo 'abc").is_err());
+        assert!(split_words("  ").is_err());
+        assert_eq!("'it'\\''s a file'", shell_quote("it's a file"));
+    }
+
+    #[test]
+    fn paths_with_spaces_and_quotes() {
+
+        let _ = fs::remove_dir_all("test_exec");
+        fs::create_dir_all("test_exec/in dir").unwrap();
+        fs::create_dir_all("test_exec/out").unwrap();
+        let names = ["test_exec/in dir/it's a \"sample\".bin", "test_exec/in dir/$(touch test_exec_pwned);.bin", "test_exec/in dir/plain.bin"];
+        for (i, name) in names.iter().enumerate() {
+            File::create(name).unwrap().write_all(format!("sample {}", i).as_bytes()).unwrap();
+        }
+        let hits: Vec<Hit> = names.iter().enumerate().map(|(i, n)| hit(i as u32 + 1, n)).collect();
+
+        // one argument per path, nothing interpreted
+        let summary = Hook::new("cp {} test_exec/out/", "GetProcAddress").unwrap().jobs(2).run(&hits);
+        assert_eq!(3, summary.runs);
+        assert_eq!(0, summary.failed());
+        for (i, name) in names.iter().enumerate() {
+            let copy = format!("test_exec/out/{}", name.rsplit('/').next().unwrap());
+            assert_eq!(format!("sample {}", i), read(&copy));
+        }
+        assert!(fs::metadata("test_exec_pwned").is_err());
+
+        // the environment, the shell only runs the command
+        let summary = Hook::new("printf '%s|%s|%s|%s\\n' \"$BINACLE_ID\" \"$BINACLE_PATH\" \"$BINACLE_QUERY\" {} >> test_exec/env.txt",
+                                "GetProcAddress").unwrap().shell().run(&hits[.. 2]);
+        assert_eq!(0, summary.failed());
+        assert_eq!(format!("1|{0}|GetProcAddress|{0}\n2|{1}|GetProcAddress|{1}\n", names[0], names[1]), read("test_exec/env.txt"));
+        assert!(fs::metadata("test_exec_pwned").is_err());
+
+        // all the paths to one invocation
+        fs::remove_dir_all("test_exec/out").unwrap();
+        fs::create_dir_all("test_exec/out").unwrap();
+        let summary = Hook::new("cp {} test_exec/out", "").unwrap().batch().run(&hits);
+        assert_eq!(1, summary.runs);
+        assert_eq!(0, summary.failed());
+        assert_eq!(3, fs::read_dir("test_exec/out").unwrap().count());
+
+        fs::remove_dir_all("test_exec").unwrap();
+    }
+
+    #[test]
+    fn exit_codes_counted() {
+
+        let hits: Vec<Hit> = (1 .. 5).map(|i| hit(i, &format!("{}.bin", i))).collect();
+        let summary = Hook::new("sh -c 'exit $(( BINACLE_ID % 2 * 3 ))'", "").unwrap().jobs(4).run(&hits);
+        assert_eq!(4, summary.runs);
+        assert_eq!(Some(&2), summary.codes.get(&0));
+        assert_eq!(Some(&2), summary.codes.get(&3));
+        assert_eq!(2, summary.failed());
+
+        let summary = Hook::new("sleep 5", "").unwrap().timeout(Duration::from_millis(50)).run(&hits[.. 1]);
+        assert_eq!(1, summary.timed_out);
+        assert_eq!(1, summary.failed());
+
+        let summary = Hook::new("test_exec_no_such_program {}", "").unwrap().run(&hits[.. 1]);
+        assert_eq!(1, summary.not_started);
+        assert_eq!(1, summary.failed());
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt::Display;
+use std::io;
+use std::process;
+use std::str::FromStr;
+
+use docopt::ArgvMap;
+
+use style;
+
+// The exit codes of the commands, for the scripts. A search or a lookup
+// without results exits 1, as grep does; the comparisons that differ
+// (--ab-test, --verify-reindex, --coverage-of, --evaluate) 2 and the
+// searches some shards failed 3. The errors are told apart from them and
+// from each other, by the codes of sysexits.h: the command line (an
+// argument that does not parse, options that do not go together) is 64,
+// the files (a database, a meta or a map missing, unreadable or corrupt)
+// 74.
+//
+// An error is one line on stderr naming the argument or the file.
+
+
+pub const NO_RESULTS: i32 = 1;
+pub const DIFFER: i32 = 2;
+pub const PARTIAL: i32 = 3;
+pub const USAGE: i32 = 64;
+pub const IO: i32 = 74;
+
+// the invalid inputs of the library are the requests of the command line
+pub fn code(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::InvalidInput => USAGE,
+        _ => IO,
+    }
+}
+
+// the database must be dropped before, its meta is written by its Drop
+pub fn fail(e: io::Error) -> ! {
+    style::error(&e);
+    process::exit(code(&e))
+}
+
+pub fn usage<T: Display>(message: T) -> ! {
+    style::error(message);
+    process::exit(USAGE)
+}
+
+// the value of an argument, the command fails naming it when it does not
+// parse
+pub fn arg<T: FromStr>(args: &ArgvMap, name: &str) -> T where T::Err: Display {
+    let value = args.get_str(name);
+    value.parse::<T>().unwrap_or_else(|e| usage(invalid(name, value, e)))
+}
+
+fn invalid<E: Display>(name: &str, value: &str, e: E) -> String {
+    format!("invalid {}: {:?} ({})", name, value, e)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use docopt::Docopt;
+
+    #[test]
+    fn codes() {
+        assert_eq!(USAGE, code(&io::Error::new(io::ErrorKind::InvalidInput, "pattern size is < 4")));
+        assert_eq!(IO, code(&io::Error::new(io::ErrorKind::NotFound, "test.db.meta: No such file or directory")));
+        assert_eq!(IO, code(&io::Error::new(io::ErrorKind::InvalidData, "test.db.meta: EOF while parsing")));
+
+        let args = Docopt::new("Usage: binacle -f <id> <file>").unwrap()
+                       .argv(vec!["binacle", "-f", "12", "a.bin"]).parse().unwrap();
+        assert_eq!(12, arg::<u32>(&args, "<id>"));
+        assert_eq!("invalid <id>: \"a.bin\" (invalid digit found in string)",
+                   invalid("<id>", "a.bin", "a.bin".parse::<u32>().unwrap_err()));
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs::OpenOptions;
+use std::io::*;
+
+// The ids and the paths of the files of --files. The list is read from a
+// file (@list.txt), from stdin (-) or from the argument itself, one
+// <id><TAB><path> or <id> <path> a line: the line is split on its first
+// space or tab, the rest of it is the path as it is (spaces, parentheses,
+// dashes, non-ASCII and Windows paths).
+//
+// A line that can not be read is told with its number, the other files are
+// still inserted.
+
+
+#[derive(Debug, Default, PartialEq)]
+pub struct FileList {
+    pub files: Vec<(u32, String)>,
+    // a message for each line left out, with its number
+    pub errors: Vec<String>,
+}
+
+// the list of the argument of --files
+pub fn read(arg: &str) -> Result<FileList> {
+
+    let mut content = String::new();
+    let source = match arg {
+        "-" => {
+            try!(stdin().read_to_string(&mut content));
+            "stdin"
+        },
+        _ if arg.starts_with('@') => {
+            let path = &arg[1 ..];
+            let read = OpenOptions::new().read(true).open(path).and_then(|mut f| f.read_to_string(&mut content));
+            try!(read.map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
+            path
+        },
+        _ => return Ok(parse_lines(arg, "<files_and_ids>")),
+    };
+    Ok(parse_lines(&content, source))
+}
+
+// one file a line, the blank lines skipped
+pub fn parse_lines(content: &str, source: &str) -> FileList {
+
+    let mut list = FileList::default();
+    for (nb, line) in content.lines().enumerate() {
+
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, path) = match line.find(|c| c == ' ' || c == '\t') {
+            Some(at) => (&line[.. at], &line[at + 1 ..]),
+            None => (line, ""),
+        };
+        match id.parse::<u32>() {
+            _ if path.is_empty() => list.errors.push(format!("{}:{}: {:?} is not <id> <path>", source, nb + 1, line)),
+            Ok(id) => list.files.push((id, String::from(path))),
+            Err(e) => list.errors.push(format!("{}:{}: invalid id {:?} ({})", source, nb + 1, id, e)),
+        }
+    }
+    list
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+
+    #[test]
+    fn lines() {
+
+        let content = "1\tcases/a b.exe\r\n2 dump/x.bin\n\n   \nthree c.bin\n4\n5\t c.bin  \n99999999999 d.bin\n";
+        let list = parse_lines(content, "list.txt");
+        assert_eq!(vec![(1, String::from("cases/a b.exe")), (2, String::from("dump/x.bin")), (5, String::from(" c.bin  "))],
+                   list.files);
+        assert_eq!(3, list.errors.len());
+        assert!(list.errors[0].starts_with("list.txt:5: invalid id \"three\""), "{}", list.errors[0]);
+        assert_eq!("list.txt:6: \"4\" is not <id> <path>", list.errors[1]);
+        assert!(list.errors[2].starts_with("list.txt:8: invalid id \"99999999999\""), "{}", list.errors[2]);
+    }
+
+    #[test]
+    fn arguments() {
+
+        File::create("test_file_list.txt").unwrap().write_all(b"7 a.bin\n8\tb.bin\n").unwrap();
+        let list = read("@test_file_list.txt").unwrap();
+        assert_eq!(vec![(7, String::from("a.bin")), (8, String::from("b.bin"))], list.files);
+        assert!(list.errors.is_empty());
+        fs::remove_file("test_file_list.txt").unwrap();
+
+        let err = read("@test_file_list.txt").unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().starts_with("test_file_list.txt: "));
+
+        // in the argument, a line each
+        let list = read("1 a.bin\n2 b.bin").unwrap();
+        assert_eq!(vec![(1, String::from("a.bin")), (2, String::from("b.bin"))], list.files);
+        assert_eq!(vec!["<files_and_ids>:1: invalid id \"99999999999\" (number too large to fit in target type)"],
+                   read("99999999999 a.bin").unwrap().errors);
+    }
+
+    #[test]
+    fn verbatim_paths() {
+
+        let paths = ["C:\\Program Files (x86)\\évil sample-1.bin", "/tmp/dir with spaces/a.so", "cases/[2024] dump; #1.bin",
+                     "名前/ファイル.exe"];
+        let argument: Vec<String> = paths.iter().enumerate().map(|(i, p)| format!("{} {}", i + 1, p)).collect();
+        let list = read(&argument.join("\n")).unwrap();
+        assert_eq!(paths.iter().enumerate().map(|(i, p)| (i as u32 + 1, String::from(*p))).collect::<Vec<_>>(), list.files);
+        assert!(list.errors.is_empty());
+
+        // a token that is not a pair is told, not ignored
+        let list = read("1 a.bin\n/tmp/b.bin\n-3 c.bin").unwrap();
+        assert_eq!(vec![(1, String::from("a.bin"))], list.files);
+        assert_eq!(vec!["<files_and_ids>:2: \"/tmp/b.bin\" is not <id> <path>",
+                        "<files_and_ids>:3: invalid id \"-3\" (invalid digit found in string)"], list.errors);
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::*;
+use std::path::PathBuf;
+use rustc_serialize::json;
+
+use binacle::BinacleFile;
+use chains::ChainStats;
+
+// Finalization of a full shard. A full shard does not change anymore: the
+// read-optimizations the live shard can not afford are run once, when it
+// becomes full. The chains longer than the threshold are compacted, the
+// shard is synced and its statistics are frozen in a summary written next
+// to it (<shard>.summary).
+//
+// Each step can be run again: an interrupted finalization is resumed by the
+// next one, the manager only marks the shard finalized once it completed.
+// The lists keep the same ids, the results of the searches do not change.
+
+
+// most frequent n-grams kept in the summary
+pub const FREQUENT_NGRAMS: usize = 256;
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct ShardSummary {
+    pub lists: u64,
+    // ids in all the lists
+    pub postings: u64,
+    // chain lengths, after the compaction
+    pub chain_mean: f64,
+    pub chain_p95: u32,
+    pub chain_max: u32,
+    pub compacted: usize,
+    // (n-gram, number of ids) of the most frequent n-grams, the most frequent first
+    pub frequent: Vec<(u32, u32)>,
+}
+
+pub fn summary_path(shard_path: &str) -> PathBuf {
+    let mut path = PathBuf::from(shard_path);
+    path.set_extension("summary");
+    path
+}
+
+pub fn read_summary(shard_path: &str) -> Result<Option<ShardSummary>> {
+    let path = summary_path(shard_path);
+    let mut content = String::new();
+    match OpenOptions::new().read(true).open(&path) {
+        Ok(mut f) => try!(f.read_to_string(&mut content)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    json::decode(&content).map(Some).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+}
+
+// written aside and renamed, an interrupted write leaves the previous one
+fn write_summary(shard_path: &str, summary: &ShardSummary) -> Result<()> {
+    let path = summary_path(shard_path);
+    let mut tmp = path.clone();
+    tmp.set_extension("summary.tmp");
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(json::encode(summary).unwrap().as_bytes()));
+        try!(file.sync_all());
+    }
+    fs::rename(&tmp, &path)
+}
+
+impl BinacleFile {
+
+    // compact, sync and summarize a full shard. keep_going is asked before
+    // each n-gram compacted, the finalization stops with ErrorKind::Interrupted
+    // when it returns false
+    pub fn finalize<F: FnMut() -> bool>(&mut self, threshold: u32, mut keep_going: F) -> Result<ShardSummary> {
+
+        let mut long = Vec::new();
+        self.for_each_chain(|ngram, len| {
+            if len > threshold {
+                long.push(ngram);
+            }
+        });
+
+        let mut compacted = 0;
+        for ngram in long {
+            if !keep_going() {
+                // what was compacted is kept, the size of a full shard too
+                try!(self.fix_size());
+                try!(self.flush());
+                return Err(Error::new(ErrorKind::Interrupted, format!("finalization of {} interrupted", self.path)));
+            }
+            try!(self.compact_ngram(ngram));
+            compacted += 1;
+        }
+        try!(self.fix_size());
+        try!(self.flush());
+
+        let mut histogram = Vec::new();
+        let mut frequent = Vec::new();
+        let mut postings = 0;
+        self.for_each_chain(|ngram, len| {
+            if histogram.len() <= len as usize {
+                histogram.resize(len as usize + 1, 0);
+            }
+            histogram[len as usize] += 1;
+            frequent.push((ngram, 0));
+        });
+        for f in frequent.iter_mut() {
+            f.1 = self.get_ids_size_by_ngram(f.0);
+            postings += f.1 as u64;
+        }
+        frequent.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        frequent.truncate(FREQUENT_NGRAMS);
+
+        let stats = ChainStats::from_histogram(&histogram, threshold);
+        let summary = ShardSummary {
+            lists: stats.lists,
+            postings: postings,
+            chain_mean: stats.mean,
+            chain_p95: stats.p95,
+            chain_max: stats.max,
+            compacted: compacted,
+            frequent: frequent,
+        };
+        try!(write_summary(&self.path, &summary));
+        Ok(summary)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs::remove_file;
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use search::SearchRequest;
+
+    fn postings(db: &BinacleFile) -> HashMap<u32, Vec<u32>> {
+        let mut lists = HashMap::new();
+        db.for_each_chain(|ngram, _| { lists.insert(ngram, Vec::new()); });
+        for (ngram, ids) in lists.iter_mut() {
+            *ids = db.get_ids_by_ngram(*ngram).into_iter().collect();
+            ids.sort();
+        }
+        lists
+    }
+
+    #[test]
+    fn same_lists_interrupted_or_not() {
+        let files = ["test_finalize.db", "test_finalize.meta", "test_finalize.summary", "test_finalize.bitmap"];
+        for f in &files {
+            let _ = remove_file(f);
+        }
+        {
+            let mut db = BinacleFile::create("test_finalize.db", 5, 4, 16).unwrap();
+            for id in 1 .. 300u32 {
+                for ngram in 0 .. 30u32 {
+                    if ngram < 5 || id % (ngram + 1) == 0 {
+                        db.insert_postings(ngram, &[id]).unwrap();
+                    }
+                }
+            }
+            let before = postings(&db);
+
+            // stopped after 2 n-grams compacted
+            let mut allowed = 2;
+            let e = db.finalize(3, || { allowed -= 1; allowed >= 0 }).unwrap_err();
+            assert_eq!(ErrorKind::Interrupted, e.kind());
+            assert_eq!(before, postings(&db));
+            assert!(read_summary("test_finalize.db").unwrap().is_none());
+
+            // resumed
+            let summary = db.finalize(3, || true).unwrap();
+            assert_eq!(before, postings(&db));
+            assert!(summary.compacted >= 3);
+            assert!(summary.chain_max <= 3);
+            assert_eq!(30, summary.lists);
+            assert_eq!((0, 299), summary.frequent[0]);
+            assert_eq!(before.values().map(|l| l.len() as u64).sum::<u64>(), summary.postings);
+
+            // nothing left to do
+            assert_eq!(0, db.finalize(3, || true).unwrap().compacted);
+        }
+        assert!(read_summary("test_finalize.db").unwrap().is_some());
+        for f in &files {
+            let _ = remove_file(f);
+        }
+    }
+
+    #[test]
+    fn finalized_when_full() {
+
+        let contents: Vec<String> = (1 .. 300).map(|id| {
+            format!("sample {} GetProcAddress ioc{:03} {}{}", id % 5, id % 100, "LoadLibraryA ".repeat(id % 4), "x".repeat(id % 50))
+        }).collect();
+        let patterns: &[&[u8]] = &[b"GetProcAddress", b"sample 3", b"LoadLibraryA LoadLibraryA", b"absent pattern"];
+
+        // the same files, the shards of the first database are not finalized
+        let mut expected = Vec::new();
+        {
+            let mut plain = BinacleManager::create("test_finalize2.db", true, 340_000, 6, 16).unwrap();
+            plain.set_finalize_on_full(false);
+            let mut db = BinacleManager::create("test_finalize3.db", true, 340_000, 6, 16).unwrap();
+            for (i, content) in contents.iter().enumerate() {
+                plain.insert_reader(content.as_bytes(), &format!("{}.bin", i + 1), i as u32 + 1, true);
+                db.insert_reader(content.as_bytes(), &format!("{}.bin", i + 1), i as u32 + 1, true);
+            }
+            assert!(db.nb_index() > 1);
+            for n in 0 .. db.nb_index() {
+                assert_eq!(db.shard_is_full(n), db.shard_is_finalized(n), "shard {}", n);
+                assert!(!plain.shard_is_finalized(n));
+            }
+            assert!(db.shard_summary(0).unwrap().is_some());
+            assert!(plain.shard_summary(0).unwrap().is_none());
+            for p in patterns {
+                let ids = plain.run(&SearchRequest::pattern(p)).unwrap().ids;
+                assert_eq!(ids, db.run(&SearchRequest::pattern(p)).unwrap().ids);
+                expected.push(ids);
+            }
+
+            // the shards full before the finalization existed
+            plain.finalize_shard(0).unwrap();
+            assert!(plain.shard_is_finalized(0));
+            assert!(plain.finalize_shard(plain.nb_index() - 1).is_err());
+        }
+
+        let mut plain = BinacleManager::open("test_finalize2.db").unwrap();
+        assert!(plain.shard_is_finalized(0));
+        for (p, ids) in patterns.iter().zip(&expected) {
+            assert_eq!(*ids, plain.run(&SearchRequest::pattern(p)).unwrap().ids);
+        }
+        drop(plain);
+        cleanup("test_finalize2");
+        cleanup("test_finalize3");
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeSet;
+use std::io::*;
+
+use binacle_manager::BinacleManager;
+use hash::{Sha256, Digest};
+
+// Hash of the logical content of a database: parameters, map and postings.
+// The physical layout (alignment, shard boundaries, list chaining, dates)
+// is left out so two databases built from the same corpus compare equal.
+
+
+// integers are hashed in little endian, strings and lists are length prefixed
+fn put_u32(hasher: &mut Sha256, v: u32) {
+    hasher.update(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+fn put_str(hasher: &mut Sha256, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            hasher.update(&[1]);
+            put_u32(hasher, s.len() as u32);
+            hasher.update(s.as_bytes());
+        },
+        None => hasher.update(&[0]),
+    }
+}
+
+pub fn fingerprint(db: &mut BinacleManager) -> Result<[u8; 32]> {
+
+    let mut hasher = Sha256::new();
+
+    // parameters
+    hasher.update(b"binacle fingerprint 1");
+    hasher.update(&[db.ngram_size(), db.is_map() as u8]);
+    put_u32(&mut hasher, db.nb_file());
+    put_u32(&mut hasher, db.last_id());
+
+    let tombstones = db.tombstones();
+    put_u32(&mut hasher, tombstones.len() as u32);
+    for id in tombstones {
+        put_u32(&mut hasher, *id);
+    }
+
+    // map, sorted by id
+    let mut entries: Vec<_> = db.entries().collect();
+    entries.sort_by_key(|e| e.0);
+    put_u32(&mut hasher, entries.len() as u32);
+    for (id, entry) in entries {
+        put_u32(&mut hasher, id);
+        put_str(&mut hasher, Some(&entry.path));
+        put_str(&mut hasher, entry.sha256.as_ref().map(|s| s.as_str()));
+        put_str(&mut hasher, entry.label.as_ref().map(|s| s.as_str()));
+        put_str(&mut hasher, entry.file_type.as_ref().map(|s| s.as_str()));
+        let tags = entry.tags.as_ref().map(|t| t.join("\n"));
+        put_str(&mut hasher, tags.as_ref().map(|s| s.as_str()));
+    }
+
+    // postings of every n-gram, merged over the shards
+    let indexes = try!(db.open_indexes());
+    for ngram in 0 .. (1u64 << db.ngram_size()) {
+        let mut ids = BTreeSet::new();
+        for index in &indexes {
+            ids.extend(index.get_ids_by_ngram(ngram as u32));
+        }
+        if ids.is_empty() {
+            continue;
+        }
+        put_u32(&mut hasher, ngram as u32);
+        put_u32(&mut hasher, ids.len() as u32);
+        for id in ids {
+            put_u32(&mut hasher, id);
+        }
+    }
+
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.finalize());
+    Ok(hash)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::tests::cleanup;
+
+    fn build(name: &str, alignment: u8) -> [u8; 32] {
+        let mut db = BinacleManager::create(name, true, 1 << 30, alignment, 16).unwrap();
+        db.set_deterministic(true);
+        db.insert_dir_recursive("test_fp_corpus").unwrap();
+        fingerprint(&mut db).unwrap()
+    }
+
+    #[test]
+    fn rebuild_same_fingerprint() {
+
+        let _ = fs::remove_dir_all("test_fp_corpus");
+        fs::create_dir_all("test_fp_corpus/sub").unwrap();
+        File::create("test_fp_corpus/b.bin").unwrap().write_all(b"GetProcAddress").unwrap();
+        File::create("test_fp_corpus/a.bin").unwrap().write_all(b"LoadLibraryA").unwrap();
+        File::create("test_fp_corpus/sub/c.bin").unwrap().write_all(b"VirtualAlloc").unwrap();
+
+        let first = build("test_fp1.db", 6);
+        let second = build("test_fp2.db", 6);
+        assert_eq!(first, second);
+        assert_eq!(fs::read("test_fp1.map").unwrap(), fs::read("test_fp2.map").unwrap());
+
+        // the alignment only changes the layout
+        assert_eq!(first, build("test_fp3.db", 8));
+
+        File::create("test_fp_corpus/a.bin").unwrap().write_all(b"LoadLibraryW").unwrap();
+        assert!(first != build("test_fp4.db", 6));
+
+        for name in &["test_fp1", "test_fp2", "test_fp3", "test_fp4"] {
+            cleanup(name);
+        }
+        let _ = fs::remove_dir_all("test_fp_corpus");
+    }
+}
@@ -0,0 +1,102 @@
+extern crate sha2;
+
+use std::fs::OpenOptions;
+use std::io::*;
+use rustc_serialize::hex::{FromHex, ToHex};
+
+pub use self::sha2::{Sha256, Digest};
+
+// Reader computing the sha256 of everything read through it,
+// used to hash a file in the same pass as the n-gram extraction
+pub struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> HashReader<R> {
+
+    pub fn new(inner: R) -> HashReader<R> {
+        HashReader {
+            inner: inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    // read what is left, then return the hash of the whole content
+    pub fn finish(self) -> Result<[u8; 32]> {
+        self.finish_len().map(|(hash, _)| hash)
+    }
+
+    // same, with the length of the whole content
+    pub fn finish_len(mut self) -> Result<([u8; 32], u64)> {
+        try!(copy(&mut self, &mut sink()));
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&self.hasher.finalize());
+        Ok((hash, self.len))
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = try!(self.inner.read(buf));
+        self.hasher.update(&buf[.. len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+pub fn sha256_file(path: &str) -> Result<[u8; 32]> {
+    let file = try!(OpenOptions::new().read(true).open(path));
+    HashReader::new(file).finish()
+}
+
+pub fn to_hex(hash: &[u8; 32]) -> String {
+    hash.to_hex()
+}
+
+pub fn parse_sha256(hex: &str) -> Result<[u8; 32]> {
+    match hex.from_hex() {
+        Ok(ref bytes) if bytes.len() == 32 => {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(bytes);
+            Ok(hash)
+        },
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("invalid sha256: {}", hex))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn hash_known_value() {
+        let hash = HashReader::new(&b"abc"[..]).finish().unwrap();
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", to_hex(&hash));
+    }
+
+    #[test]
+    fn hash_rest_of_reader() {
+        // bytes not consumed by the caller are hashed by finish
+        let mut reader = HashReader::new(&b"abc"[..]);
+        let mut buf = [0u8; 1];
+        reader.read(&mut buf).unwrap();
+        let hash = reader.finish().unwrap();
+        assert_eq!(parse_sha256("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").unwrap(), hash);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse_sha256("abcd").is_err());
+        assert!(parse_sha256("zz").is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use std::fs::{self, Metadata};
+use std::io::*;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use binacle_manager::BinacleManager;
+use dates::format_date;
+
+// The files of a recursive insertion kept by their modification time,
+// their owner and their size: the ones of an incident window or of a
+// compromised account, without the memory dumps and the empty placeholders.
+// The walk already reads the metadata of each file, the filters cost
+// nothing more.
+//
+// The filters of each filtered insertion are kept in the meta with the
+// number of files they excluded: a file missing from the results can be
+// told excluded rather than never seen, its metadata checked again against
+// the filters of the insertions of its directory.
+
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, Default, PartialEq)]
+pub struct IngestFilter {
+    // seconds since the epoch, the bounds of the modification time
+    pub modified_since: Option<u64>,
+    pub modified_before: Option<u64>,
+    // uid of the owner (Unix only)
+    pub owner: Option<u32>,
+    // bounds of the size in bytes, both included
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+// a filtered insertion, in the meta
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct FilteredRun {
+    pub dir: String,
+    pub filter: IngestFilter,
+    pub excluded: u64,
+    // bytes of the files excluded, None for the insertions recorded before
+    pub excluded_bytes: Option<u64>,
+    // None for the deterministic insertions
+    pub date: Option<u64>,
+}
+
+impl IngestFilter {
+
+    pub fn is_empty(&self) -> bool {
+        *self == IngestFilter::default()
+    }
+
+    // why a file with this metadata is excluded, None when it is kept
+    pub fn excludes(&self, meta: &Metadata) -> Option<String> {
+
+        if self.modified_since.is_some() || self.modified_before.is_some() {
+            let modified = match meta.modified().ok().and_then(|m| m.duration_since(UNIX_EPOCH).ok()) {
+                Some(d) => d.as_secs(),
+                None => return Some(String::from("modification time unknown")),
+            };
+            if let Some(since) = self.modified_since {
+                if modified < since {
+                    return Some(format!("modified {}, before --modified-since {}", format_date(modified), format_date(since)));
+                }
+            }
+            if let Some(before) = self.modified_before {
+                if modified >= before {
+                    return Some(format!("modified {}, not before --modified-before {}", format_date(modified), format_date(before)));
+                }
+            }
+        }
+        if let Some(owner) = self.owner {
+            match uid(meta) {
+                Some(uid) if uid != owner => return Some(format!("owned by uid {}, not by --owner {}", uid, owner)),
+                Some(_) => {},
+                None => return Some(String::from("owner unknown on this platform")),
+            }
+        }
+        if let Some(min) = self.min_size {
+            if meta.len() < min {
+                return Some(format!("{} byte(s), under --min-size {}", meta.len(), min));
+            }
+        }
+        if let Some(max) = self.max_size {
+            if meta.len() > max {
+                return Some(format!("{} byte(s), over --max-size {}", meta.len(), max));
+            }
+        }
+        None
+    }
+
+    // the options given, as on the command line
+    pub fn describe(&self) -> String {
+        let mut options = Vec::new();
+        if let Some(since) = self.modified_since {
+            options.push(format!("--modified-since={}", format_date(since)));
+        }
+        if let Some(before) = self.modified_before {
+            options.push(format!("--modified-before={}", format_date(before)));
+        }
+        if let Some(owner) = self.owner {
+            options.push(format!("--owner={}", owner));
+        }
+        if let Some(min) = self.min_size {
+            options.push(format!("--min-size={}", min));
+        }
+        if let Some(max) = self.max_size {
+            options.push(format!("--max-size={}", max));
+        }
+        options.join(" ")
+    }
+}
+
+#[cfg(unix)]
+fn uid(meta: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.uid())
+}
+
+#[cfg(not(unix))]
+fn uid(_meta: &Metadata) -> Option<u32> {
+    None
+}
+
+// a uid, or the name of a user of /etc/passwd
+pub fn parse_owner(owner: &str) -> Result<u32> {
+
+    if let Ok(uid) = owner.parse::<u32>() {
+        return Ok(uid);
+    }
+    if cfg!(not(unix)) {
+        return Err(Error::new(ErrorKind::InvalidInput, "--owner is only supported on Unix"));
+    }
+    let passwd = try!(fs::read_to_string("/etc/passwd"));
+    passwd.lines()
+        .map(|l| l.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 2 && fields[0] == owner)
+        .and_then(|fields| fields[2].parse::<u32>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown user {}", owner)))
+}
+
+// bytes, with a unit or not: 4096, 512k, 10M, 2G, 1.5T (powers of 1024)
+pub fn parse_size(size: &str) -> Result<u64> {
+
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid size: {}, <bytes> or <n><unit> (k, M, G, T) expected", size));
+
+    let lower = size.trim().to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_alphabetic());
+    let unit: u64 = match lower[digits.len() ..].trim_end_matches('b') {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+    if unit == 1 {
+        return digits.parse::<u64>().map_err(|_| invalid());
+    }
+    match digits.parse::<f64>() {
+        Ok(value) if value >= 0.0 => Ok((value * unit as f64) as u64),
+        _ => Err(invalid()),
+    }
+}
+
+// the file was left out by the filters of an insertion of its directory,
+// the last one first. None when no filter excludes it or it is gone
+pub fn why_excluded(db: &BinacleManager, path: &str) -> Option<String> {
+
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return None,
+    };
+    for run in db.filtered_runs().iter().rev() {
+        if !Path::new(path).starts_with(&run.dir) {
+            continue;
+        }
+        let reason = match run.filter.excludes(&meta) {
+            Some(reason) => reason,
+            None => continue,
+        };
+        let date = run.date.map(|d| format!(" on {}", format_date(d))).unwrap_or(String::new());
+        return Some(format!("{}: excluded by the filters of the insertion of {}{} ({}), {} file(s) excluded",
+                            reason, run.dir, date, run.filter.describe(), run.excluded));
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::time::Duration;
+    use binacle_manager::tests::cleanup;
+    use dates::parse_date;
+
+    // the modification time of a file set to the date
+    fn touch(path: &str, date: &str) {
+        File::create(path).unwrap().write_all(path.as_bytes()).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(parse_date(date).unwrap());
+        OpenOptions::new().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn incident_window() {
+
+        let _ = fs::remove_dir_all("test_filter_dir");
+        fs::create_dir_all("test_filter_dir/sub").unwrap();
+        touch("test_filter_dir/before.bin", "2023-12-31 23:59:59");
+        touch("test_filter_dir/first.bin", "2024-01-01");
+        touch("test_filter_dir/sub/inside.bin", "2024-01-15 12:00");
+        touch("test_filter_dir/sub/end.bin", "2024-02-01");
+
+        let filter = IngestFilter {
+            modified_since: Some(parse_date("2024-01-01").unwrap()),
+            modified_before: Some(parse_date("2024-02-01").unwrap()),
+            ..IngestFilter::default()
+        };
+        {
+            let mut db = BinacleManager::create("test_filter.db", true, 1 << 30, 6, 16).unwrap();
+            db.set_deterministic(true);
+            db.set_ingest_filter(Some(filter.clone()));
+            db.insert_dir_recursive("test_filter_dir").unwrap();
+
+            let mut paths: Vec<String> = db.entries().map(|e| e.1.path.clone()).collect();
+            paths.sort();
+            assert_eq!(vec!["test_filter_dir/first.bin", "test_filter_dir/sub/inside.bin"], paths);
+            let excluded_bytes = "test_filter_dir/before.bin".len() + "test_filter_dir/sub/end.bin".len();
+            assert_eq!(vec![FilteredRun { dir: String::from("test_filter_dir"), filter: filter.clone(), excluded: 2,
+                                          excluded_bytes: Some(excluded_bytes as u64), date: None }],
+                       db.filtered_runs());
+        }
+
+        // recorded in the meta, the exclusion is explained
+        let db = BinacleManager::open("test_filter.db").unwrap();
+        assert_eq!(1, db.filtered_runs().len());
+        let reason = why_excluded(&db, "test_filter_dir/before.bin").unwrap();
+        assert!(reason.starts_with("modified 2023-12-31 23:59:59 UTC, before --modified-since 2024-01-01 00:00:00 UTC: \
+                                    excluded by the filters of the insertion of test_filter_dir"), "{}", reason);
+        assert!(reason.ends_with("2 file(s) excluded"), "{}", reason);
+        assert!(why_excluded(&db, "test_filter_dir/sub/end.bin").unwrap().contains("not before --modified-before"));
+        assert_eq!(None, why_excluded(&db, "test_filter_dir/first.bin"));
+        assert_eq!(None, why_excluded(&db, "elsewhere/before.bin"));
+        drop(db);
+
+        cleanup("test_filter");
+        let _ = fs::remove_dir_all("test_filter_dir");
+    }
+
+    #[test]
+    fn owner() {
+
+        let meta = fs::metadata("Cargo.toml").unwrap();
+        let filter = |owner| IngestFilter { owner: Some(owner), ..IngestFilter::default() };
+        match uid(&meta) {
+            Some(uid) => {
+                assert_eq!(None, filter(uid).excludes(&meta));
+                assert_eq!(Some(format!("owned by uid {}, not by --owner {}", uid, uid + 1)), filter(uid + 1).excludes(&meta));
+            },
+            // no owner to check on this platform
+            None => assert!(filter(0).excludes(&meta).is_some()),
+        }
+        assert_eq!(1000, parse_owner("1000").unwrap());
+        if cfg!(unix) && fs::metadata("/etc/passwd").is_ok() {
+            assert_eq!(0, parse_owner("root").unwrap());
+        }
+        assert!(parse_owner("no such user here").is_err());
+
+        assert_eq!(None, IngestFilter::default().excludes(&meta));
+        let since = IngestFilter { modified_since: Some(parse_date("2024-01-01").unwrap()), ..IngestFilter::default() };
+        assert_eq!("--modified-since=2024-01-01 00:00:00 UTC", since.describe());
+    }
+
+    #[test]
+    fn sizes() {
+
+        use binacle_manager::InsertOutcome;
+
+        assert_eq!(4096, parse_size("4096").unwrap());
+        assert_eq!(512 << 10, parse_size("512k").unwrap());
+        assert_eq!(10 << 20, parse_size("10M").unwrap());
+        assert_eq!(10 << 20, parse_size("10MB").unwrap());
+        assert_eq!(2 << 30, parse_size("2G").unwrap());
+        assert_eq!(1536, parse_size("1.5k").unwrap());
+        for invalid in &["", "x", "-1", "1.5", "-2G", "3P"] {
+            assert_eq!(ErrorKind::InvalidInput, parse_size(invalid).unwrap_err().kind(), "{}", invalid);
+        }
+
+        let _ = fs::remove_dir_all("test_size_dir");
+        fs::create_dir_all("test_size_dir").unwrap();
+        for &(name, len) in &[("a.bin", 0), ("b.bin", 100), ("c.bin", 2000), ("d.bin", 5000), ("e.bin", 300)] {
+            File::create(format!("test_size_dir/{}", name)).unwrap().write_all(&vec![b'x'; len]).unwrap();
+        }
+
+        let filter = IngestFilter { min_size: Some(1), max_size: Some(parse_size("4k").unwrap()), ..IngestFilter::default() };
+        assert_eq!("--min-size=1 --max-size=4096", filter.describe());
+        {
+            let mut db = BinacleManager::create("test_size.db", true, 1 << 30, 6, 16).unwrap();
+            db.set_deterministic(true);
+            // timed out, c.bin is quarantined under the id 1
+            db.set_file_budget(Some(Duration::from_secs(0)));
+            match db.insert("test_size_dir/c.bin", 1, true) {
+                InsertOutcome::Failed(ref e) if e.kind() == ErrorKind::TimedOut => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            db.set_file_budget(None);
+
+            db.set_ingest_filter(Some(filter.clone()));
+            let insertions = db.insert_dir_recursive("test_size_dir").unwrap();
+            // the quarantined file leaves its id to the next one
+            let got: Vec<(u32, &str, &str, Option<u64>)> = insertions.iter()
+                .map(|i| (i.id, i.path.as_str(), i.outcome.status(), i.size))
+                .collect();
+            assert_eq!(vec![(2, "test_size_dir/b.bin", "inserted", Some(100)),
+                            (3, "test_size_dir/c.bin", "skipped", Some(2000)),
+                            (3, "test_size_dir/e.bin", "inserted", Some(300))], got);
+            assert_eq!(3, db.last_id());
+
+            let run = &db.filtered_runs()[0];
+            assert_eq!((2, Some(5000)), (run.excluded, run.excluded_bytes));
+        }
+
+        let db = BinacleManager::open("test_size.db").unwrap();
+        let reason = why_excluded(&db, "test_size_dir/d.bin").unwrap();
+        assert!(reason.starts_with("5000 byte(s), over --max-size 4096: excluded"), "{}", reason);
+        assert!(why_excluded(&db, "test_size_dir/a.bin").unwrap().starts_with("0 byte(s), under --min-size 1"));
+        assert_eq!(None, why_excluded(&db, "test_size_dir/e.bin"));
+        drop(db);
+
+        cleanup("test_size");
+        let _ = fs::remove_dir_all("test_size_dir");
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::*;
+use std::str;
+use rustc_serialize::json::{JsonEvent, Parser, StackElement};
+
+use binacle_manager::{MapEntry, Note};
+
+// Map lookups without loading the map: the JSON file is parsed as a stream
+// and only the wanted entries are kept. Slow (the whole file is read at
+// each lookup) but the memory used does not depend on the map size.
+
+
+// the decoded map takes a few times the size of its JSON file
+pub const MAP_MEMORY_FACTOR: u64 = 3;
+
+// memory available on the host, None when it can not be known
+pub fn available_memory() -> Option<u64> {
+
+    let mut content = String::new();
+    let mut file = match OpenOptions::new().read(true).open("/proc/meminfo") {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+    if file.read_to_string(&mut content).is_err() {
+        return None;
+    }
+
+    for line in content.lines() {
+        if line.starts_with("MemAvailable:") {
+            // "MemAvailable:   123456 kB"
+            return line.split_whitespace().nth(1)
+                       .and_then(|kb| kb.parse::<u64>().ok())
+                       .map(|kb| kb * 1024);
+        }
+    }
+    None
+}
+
+// UTF-8 characters of a reader, for the streaming parser
+struct Chars<R> {
+    inner: R,
+}
+
+impl<R: BufRead> Iterator for Chars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+
+        let mut buf = [0u8; 4];
+        if self.inner.read_exact(&mut buf[.. 1]).is_err() {
+            return None;
+        }
+        let width = match buf[0] {
+            0x00 ..= 0x7f => 1,
+            0xc0 ..= 0xdf => 2,
+            0xe0 ..= 0xef => 3,
+            _ => 4,
+        };
+        if self.inner.read_exact(&mut buf[1 .. width]).is_err() {
+            return None;
+        }
+        // an invalid sequence ends the stream, the parser reports the error
+        str::from_utf8(&buf[.. width]).ok().and_then(|s| s.chars().next())
+    }
+}
+
+// entries of the map file for the given ids
+pub fn lookup(map_path: &str, ids: &HashSet<u32>) -> Result<HashMap<u32, MapEntry>> {
+    let mut found = HashMap::new();
+    try!(scan(map_path, |id| ids.contains(&id), |id, entry| {
+        found.insert(id, entry);
+    }));
+    Ok(found)
+}
+
+// each entry of the map file in the order of the file, an id given twice
+// by a map edited by hand is seen twice (the map loaded keeps the last one).
+// The provenance of the entries is not read
+pub fn scan<W, F>(map_path: &str, wanted: W, mut f: F) -> Result<()>
+    where W: Fn(u32) -> bool, F: FnMut(u32, MapEntry) {
+
+    let file = try!(OpenOptions::new().read(true).open(map_path));
+    let mut parser = Parser::new(Chars { inner: BufReader::new(file) });
+    let mut current: Option<(u32, MapEntry)> = None;
+
+    loop {
+        let event = match parser.next() {
+            Some(e) => e,
+            None => break,
+        };
+
+        let stack = parser.stack();
+
+        // the id is the key of the first level
+        let id = match stack.len() {
+            0 => None,
+            _ => match stack.get(0) {
+                StackElement::Key(k) => k.parse::<u32>().ok(),
+                _ => None,
+            },
+        };
+        let id = match id {
+            Some(id) if wanted(id) => id,
+            _ => {
+                if let JsonEvent::Error(e) = event {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("{}: {:?}", map_path, e)));
+                }
+                continue;
+            },
+        };
+
+        let field = match stack.len() {
+            2 | 3 | 4 => match stack.get(1) {
+                StackElement::Key(k) => Some(k),
+                _ => None,
+            },
+            _ => None,
+        };
+        let entry = current.as_mut().map(|c| &mut c.1);
+
+        match (stack.len(), field, event) {
+            // legacy map, id -> path
+            (1, _, JsonEvent::StringValue(path)) => {
+                if let Some((id, entry)) = current.take() {
+                    f(id, entry);
+                }
+                f(id, MapEntry::new(&path));
+            },
+            (1, _, JsonEvent::ObjectStart) => {
+                if let Some((id, entry)) = current.replace((id, MapEntry::new(""))) {
+                    f(id, entry);
+                }
+            },
+            (2, Some(field), JsonEvent::StringValue(value)) => {
+                if let Some(entry) = entry {
+                    match field {
+                        "path" => entry.path = value,
+                        "sha256" => entry.sha256 = Some(value),
+                        "label" => entry.label = Some(value),
+                        "file_type" => entry.file_type = Some(value),
+                        _ => {},
+                    }
+                }
+            },
+            (2, Some("date"), JsonEvent::U64Value(date)) => {
+                if let Some(entry) = entry {
+                    entry.date = Some(date);
+                }
+            },
+            (3, Some("tags"), JsonEvent::StringValue(tag)) => {
+                if let Some(entry) = entry {
+                    entry.tags.get_or_insert(Vec::new()).push(tag);
+                }
+            },
+            // the notes are objects of an array
+            (3, Some("notes"), JsonEvent::ObjectStart) => {
+                if let Some(entry) = entry {
+                    entry.notes.get_or_insert(Vec::new()).push(Note { date: None, text: String::new() });
+                }
+            },
+            (4, Some("notes"), JsonEvent::StringValue(text)) if stack.ends_with(&[StackElement::Key("text")]) => {
+                if let Some(note) = entry.and_then(|e| e.notes.as_mut()).and_then(|n| n.last_mut()) {
+                    note.text = text;
+                }
+            },
+            (4, Some("notes"), JsonEvent::U64Value(date)) if stack.ends_with(&[StackElement::Key("date")]) => {
+                if let Some(note) = entry.and_then(|e| e.notes.as_mut()).and_then(|n| n.last_mut()) {
+                    note.date = Some(date);
+                }
+            },
+            (_, _, JsonEvent::Error(e)) => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{}: {:?}", map_path, e)));
+            },
+            _ => {},
+        }
+    }
+    if let Some((id, entry)) = current {
+        f(id, entry);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{File, remove_file};
+
+    #[test]
+    fn stream_lookup() {
+
+        File::create("test_lazy.map").unwrap().write_all("{\"1\":{\"path\":\"a\",\"sha256\":null,\"date\":12,\
+            \"label\":\"l\",\"file_type\":\"pe\",\"tags\":[\"x\",\"y\"],\"notes\":[{\"date\":5,\"text\":\"two\\nlines\"},\
+            {\"date\":null,\"text\":\"d\u{e9}j\u{e0}\"}]},\"2\":{\"path\":\"caf\u{e9}\"},\"3\":{\"path\":\"c\"}}"
+            .as_bytes()).unwrap();
+
+        let ids = [1, 2, 4].iter().cloned().collect();
+        let found = lookup("test_lazy.map", &ids).unwrap();
+        assert_eq!(2, found.len());
+        assert_eq!("a", found[&1].path);
+        assert_eq!(Some(12), found[&1].date);
+        assert_eq!(Some(vec![String::from("x"), String::from("y")]), found[&1].tags);
+        assert_eq!("caf\u{e9}", found[&2].path);
+        assert_eq!(Some(vec![Note { date: Some(5), text: String::from("two\nlines") },
+                             Note { date: None, text: String::from("d\u{e9}j\u{e0}") }]), found[&1].notes);
+        assert_eq!(None, found[&2].notes);
+
+        // legacy format
+        File::create("test_lazy.map").unwrap().write_all(b"{\"7\":\"old\"}").unwrap();
+        let ids = [7].iter().cloned().collect();
+        assert_eq!("old", lookup("test_lazy.map", &ids).unwrap()[&7].path);
+
+        // an id given twice is seen twice, in the order of the file
+        File::create("test_lazy.map").unwrap().write_all(b"{\"7\":{\"path\":\"a\"},\"8\":\"b\",\"7\":{\"path\":\"c\"}}").unwrap();
+        let mut seen = Vec::new();
+        scan("test_lazy.map", |_| true, |id, entry| seen.push((id, entry.path))).unwrap();
+        assert_eq!(vec![(7, String::from("a")), (8, String::from("b")), (7, String::from("c"))], seen);
+        let ids = [7].iter().cloned().collect();
+        assert_eq!("c", lookup("test_lazy.map", &ids).unwrap()[&7].path);
+
+        let _ = remove_file("test_lazy.map");
+    }
+}
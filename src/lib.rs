@@ -0,0 +1,60 @@
+extern crate rustc_serialize;
+extern crate walkdir;
+extern crate twoway;
+extern crate regex;
+
+pub mod binacle;
+pub mod binacle_manager;
+pub mod resize;
+pub mod search;
+pub mod hash;
+pub mod audit;
+pub mod fingerprint;
+pub mod context;
+pub mod magic;
+pub mod cache;
+pub mod durability;
+pub mod manifest;
+pub mod lazy_map;
+pub mod corpus;
+pub mod provenance;
+pub mod reindex;
+pub mod collection;
+pub mod watchdog;
+pub mod bulk;
+pub mod chains;
+pub mod translate;
+pub mod queries;
+pub mod variants;
+pub mod finalize;
+pub mod planner;
+pub mod lock;
+pub mod params;
+pub mod abtest;
+pub mod scan;
+pub mod sidecar;
+pub mod aggregate;
+pub mod pattern_file;
+pub mod exec;
+pub mod map_rebuild;
+pub mod space;
+pub mod compat;
+pub mod warmup;
+pub mod postings;
+pub mod shareable;
+pub mod dedup;
+pub mod stats;
+pub mod reads;
+pub mod materialize;
+pub mod prefetch;
+pub mod dates;
+pub mod ingest_filter;
+pub mod occupancy;
+pub mod overlap;
+pub mod dirty;
+pub mod library;
+pub mod file_list;
+pub mod yara;
+pub mod server;
+pub mod sandbox;
+pub mod watch;
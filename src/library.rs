@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::*;
+use std::path::PathBuf;
+use regex::Regex;
+use rustc_serialize::json::{Json, ToJson};
+use rustc_serialize::hex::ToHex;
+
+use dates::format_date;
+use queries::{self, Query};
+
+// The query library: searches saved by name with the database, in a
+// sidecar ({db}.library) like the collections, to be run again by name or
+// all together. A saved query is a line of the --queries files (see
+// queries.rs), its patterns written as hex: terms: the bytes searched are
+// kept, not the file or the escapes they were read from. The output options
+// of the search are kept with it, and the number of hits of its last run,
+// the next run is compared to it.
+
+
+// the outputs of the search a saved query keeps
+pub const OUTPUTS: &'static [&'static str] = &["json", "labels", "show-type"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedQuery {
+    // terms and options
+    pub query: String,
+    pub output: Vec<String>,
+    pub tags: Vec<String>,
+    pub last_run: Option<LastRun>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LastRun {
+    // in seconds since the epoch
+    pub date: u64,
+    pub hits: usize,
+}
+
+impl SavedQuery {
+
+    // the patterns, the excluded ones and the options of a query line
+    // (limit=3, tag=x...), checked by reading the line back
+    pub fn new(patterns: &[Vec<u8>], excludes: &[Vec<u8>], options: &[String]) -> Result<SavedQuery> {
+
+        let invalid = |what: String| Error::new(ErrorKind::InvalidInput, what);
+
+        if patterns.is_empty() {
+            return Err(invalid(String::from("no pattern to look for")));
+        }
+        // the options are separated by commas, after the last |
+        if let Some(option) = options.iter().find(|o| o.contains(',') || o.contains('|')) {
+            return Err(invalid(format!("{} can not be saved, a saved query has no , or | in its options", option)));
+        }
+        let mut terms: Vec<String> = patterns.iter().map(|p| format!("hex:{}", p.to_hex())).collect();
+        terms.extend(excludes.iter().map(|p| format!("!hex:{}", p.to_hex())));
+        let mut query = terms.join(" ");
+        if !options.is_empty() {
+            query = format!("{} | {}", query, options.join(", "));
+        }
+
+        let saved = SavedQuery { query: query, output: Vec::new(), tags: Vec::new(), last_run: None };
+        try!(saved.parse("saved").map_err(|e| invalid(e.to_string())));
+        Ok(saved)
+    }
+
+    // the query of the --queries files, named
+    pub fn parse(&self, name: &str) -> Result<Query> {
+        match try!(queries::parse_query(&format!("{}: {}", name, self.query), 1)) {
+            Some(query) => Ok(query),
+            None => Err(Error::new(ErrorKind::InvalidData, format!("query {} is empty", name))),
+        }
+    }
+
+    pub fn outputs(&self, output: &str) -> bool {
+        self.output.iter().any(|o| o == output)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    fn from_json(json: &Json) -> Option<SavedQuery> {
+        let strings = |key: &str| -> Option<Vec<String>> {
+            json.find(key).and_then(|a| a.as_array())
+                .and_then(|a| a.iter().map(|s| s.as_string().map(String::from)).collect())
+        };
+        let number = |run: &Json, key: &str| run.find(key).and_then(|n| n.as_u64());
+        let last_run = match json.find("last_run") {
+            None | Some(&Json::Null) => None,
+            Some(run) => match (number(run, "date"), number(run, "hits")) {
+                (Some(date), Some(hits)) => Some(LastRun { date: date, hits: hits as usize }),
+                _ => return None,
+            },
+        };
+        match (json.find("query").and_then(|q| q.as_string()), strings("output"), strings("tags")) {
+            (Some(query), Some(output), Some(tags)) => {
+                Some(SavedQuery { query: String::from(query), output: output, tags: tags, last_run: last_run })
+            },
+            _ => None,
+        }
+    }
+}
+
+impl ToJson for SavedQuery {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("query"), self.query.to_json());
+        obj.insert(String::from("output"), self.output.to_json());
+        obj.insert(String::from("tags"), self.tags.to_json());
+        obj.insert(String::from("last_run"), match self.last_run {
+            Some(run) => {
+                let mut last = BTreeMap::new();
+                last.insert(String::from("date"), run.date.to_json());
+                last.insert(String::from("hits"), run.hits.to_json());
+                Json::Object(last)
+            },
+            None => Json::Null,
+        });
+        Json::Object(obj)
+    }
+}
+
+// the hits of a run against the ones of the previous run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delta {
+    pub hits: usize,
+    pub previous: Option<LastRun>,
+}
+
+impl Delta {
+
+    pub fn changed(&self) -> bool {
+        self.previous.map_or(false, |p| p.hits != self.hits)
+    }
+
+    pub fn describe(&self) -> String {
+        match self.previous {
+            None => format!("{} hit(s), first run", self.hits),
+            Some(p) if p.hits == self.hits => format!("{} hit(s), as on {}", self.hits, format_date(p.date)),
+            Some(p) if p.hits < self.hits => format!("{} hit(s), {} new since {}", self.hits, self.hits - p.hits, format_date(p.date)),
+            Some(p) => format!("{} hit(s), {} fewer than on {}", self.hits, p.hits - self.hits, format_date(p.date)),
+        }
+    }
+}
+
+
+pub struct Library {
+    path: PathBuf,
+    queries: BTreeMap<String, SavedQuery>,
+}
+
+fn sidecar(db_path: &str) -> PathBuf {
+    let mut path = PathBuf::from(db_path);
+    path.set_extension("library");
+    path
+}
+
+impl Library {
+
+    // the library of a database, empty when the sidecar does not exist
+    pub fn open(db_path: &str) -> Result<Library> {
+
+        let path = sidecar(db_path);
+        let mut content = String::new();
+        let invalid = |what: String| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, what));
+        let queries = match OpenOptions::new().read(true).open(&path) {
+            Ok(mut f) => {
+                try!(f.read_to_string(&mut content));
+                let json = try!(Json::from_str(&content).map_err(|e| invalid(e.to_string())));
+                let mut queries = BTreeMap::new();
+                for (name, query) in try!(json.as_object().ok_or_else(|| invalid(String::from("not an object")))) {
+                    let query = try!(SavedQuery::from_json(query).ok_or_else(|| invalid(format!("invalid query {}", name))));
+                    queries.insert(name.clone(), query);
+                }
+                queries
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Library { path: path, queries: queries })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let encoded = self.queries.to_json().to_string();
+        try!(File::create(&self.path)).write_all(encoded.as_bytes())
+    }
+
+    // the queries added to the library of another database, the ones it
+    // already has under the same name are kept as they are there
+    pub fn copy_to(&self, db_path: &str) -> Result<()> {
+        if self.queries.is_empty() {
+            return Ok(());
+        }
+        let mut other = try!(Library::open(db_path));
+        for (name, query) in &self.queries {
+            other.queries.entry(name.clone()).or_insert_with(|| query.clone());
+        }
+        other.save()
+    }
+
+    // the query saved before under this name is replaced and returned
+    pub fn insert(&mut self, name: &str, query: SavedQuery) -> Result<Option<SavedQuery>> {
+        if !Regex::new(r"^[\w.-]+$").unwrap().is_match(name) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("invalid query name {:?}: letters, digits, _ . and - only", name)));
+        }
+        Ok(self.queries.insert(String::from(name), query))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<SavedQuery> {
+        self.queries.remove(name).ok_or_else(|| Library::not_found(name))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&SavedQuery> {
+        self.queries.get(name).ok_or_else(|| Library::not_found(name))
+    }
+
+    // the saved queries sorted by name, the ones with the tag when given
+    pub fn list(&self, tag: Option<&str>) -> Vec<(&str, &SavedQuery)> {
+        self.queries.iter()
+            .filter(|&(_, q)| tag.map_or(true, |t| q.has_tag(t)))
+            .map(|(name, q)| (name.as_str(), q))
+            .collect()
+    }
+
+    // the hits of a run kept as the last run of the query, compared to the
+    // previous one
+    pub fn record(&mut self, name: &str, hits: usize, date: u64) -> Result<Delta> {
+        let query = try!(self.queries.get_mut(name).ok_or_else(|| Library::not_found(name)));
+        let previous = query.last_run.take();
+        query.last_run = Some(LastRun { date: date, hits: hits });
+        Ok(Delta { hits: hits, previous: previous })
+    }
+
+    fn not_found(name: &str) -> Error {
+        Error::new(ErrorKind::NotFound, format!("no saved query {}", name))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::remove_file;
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use search::run_batch;
+
+    #[test]
+    fn save_and_run() {
+        {
+            let mut db = BinacleManager::create("test_library.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "a.exe", 1, false);
+            db.insert_reader(&b"LoadLibraryA GetProcAddress"[..], "b.exe", 2, true);
+
+            let mut library = Library::open("test_library.db").unwrap();
+            assert!(library.list(None).is_empty());
+
+            let mut loaders = SavedQuery::new(&[b"LoadLibrary".to_vec()], &[], &[String::from("limit=10")]).unwrap();
+            assert_eq!("hex:4c6f61644c696272617279 | limit=10", loaders.query);
+            loaders.tags.push(String::from("hunting"));
+            loaders.output.push(String::from("json"));
+            assert_eq!(None, library.insert("loaders", loaders).unwrap());
+
+            let resolvers = SavedQuery::new(&[b"GetProcAddress".to_vec()], &[b"VirtualAlloc".to_vec()], &[]).unwrap();
+            assert_eq!("hex:47657450726f6341646472657373 !hex:5669727475616c416c6c6f63", resolvers.query);
+            library.insert("resolvers", resolvers.clone()).unwrap();
+            assert!(library.insert("two words", resolvers.clone()).is_err());
+            assert!(SavedQuery::new(&[b"abcd".to_vec()], &[], &[String::from("path-match=a|b")]).is_err());
+            assert!(SavedQuery::new(&[b"abcd".to_vec()], &[], &[String::from("limit=x")]).is_err());
+            library.save().unwrap();
+
+            // read back
+            let mut library = Library::open("test_library.db").unwrap();
+            let names: Vec<&str> = library.list(None).iter().map(|&(name, _)| name).collect();
+            assert_eq!(vec!["loaders", "resolvers"], names);
+            assert_eq!(1, library.list(Some("hunting")).len());
+            assert!(library.get("loaders").unwrap().outputs("json"));
+
+            let requests: Vec<_> = library.list(None).iter().map(|&(name, q)| q.parse(name).unwrap().request).collect();
+            let report = run_batch(&mut db, &requests);
+            let hits: Vec<usize> = report.results.iter().map(|r| r.as_ref().unwrap().total).collect();
+            assert_eq!(vec![1, 2], hits);
+
+            // replaced, then removed
+            assert!(library.insert("resolvers", resolvers).unwrap().is_some());
+            library.remove("resolvers").unwrap();
+            assert_eq!(ErrorKind::NotFound, library.get("resolvers").unwrap_err().kind());
+            assert_eq!(ErrorKind::NotFound, library.remove("resolvers").unwrap_err().kind());
+            library.save().unwrap();
+        }
+        cleanup("test_library");
+        let _ = remove_file("test_library.library");
+    }
+
+    #[test]
+    fn delta_of_the_last_run() {
+        let mut library = Library::open("test_library_delta.db").unwrap();
+        library.insert("q", SavedQuery::new(&[b"abcd".to_vec()], &[], &[]).unwrap()).unwrap();
+
+        let first = library.record("q", 3, 86400).unwrap();
+        assert!(!first.changed());
+        assert_eq!("3 hit(s), first run", first.describe());
+        let same = library.record("q", 3, 2 * 86400).unwrap();
+        assert!(!same.changed());
+        assert_eq!("3 hit(s), as on 1970-01-02 00:00:00 UTC", same.describe());
+        let more = library.record("q", 5, 3 * 86400).unwrap();
+        assert!(more.changed());
+        assert_eq!("5 hit(s), 2 new since 1970-01-03 00:00:00 UTC", more.describe());
+        let fewer = library.record("q", 1, 4 * 86400).unwrap();
+        assert_eq!("1 hit(s), 4 fewer than on 1970-01-04 00:00:00 UTC", fewer.describe());
+        assert_eq!(Some(LastRun { date: 4 * 86400, hits: 1 }), library.get("q").unwrap().last_run);
+        assert!(library.record("other", 1, 0).is_err());
+
+        // carried to another database, its own queries kept
+        let mut other = Library::open("test_library_other.db").unwrap();
+        other.insert("q", SavedQuery::new(&[b"efgh".to_vec()], &[], &[]).unwrap()).unwrap();
+        other.save().unwrap();
+        library.insert("r", SavedQuery::new(&[b"ijkl".to_vec()], &[], &[]).unwrap()).unwrap();
+        library.copy_to("test_library_other.db").unwrap();
+        let other = Library::open("test_library_other.db").unwrap();
+        assert_eq!(2, other.list(None).len());
+        assert_eq!("hex:65666768", other.get("q").unwrap().query);
+        let _ = remove_file("test_library_other.library");
+    }
+}
@@ -0,0 +1,247 @@
+extern crate fs2;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::*;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rustc_serialize::json;
+use self::fs2::{FileExt, lock_contended_error};
+
+// Who holds the exclusive lock of a file. The locks are advisory locks
+// (flock): a process killed on another host of an NFS share can leave one
+// held with no way to tell who took it. Each exclusive lock is recorded
+// in a sidecar, <file>.lockinfo, removed when it is released: its pid and
+// host tell a live holder from a stale one. The shared locks of the
+// readers are not recorded, they are many and never block for long.
+//
+// The recording is best effort, a database on a read only share is still
+// locked without it.
+
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub host: String,
+    // in seconds since the epoch
+    pub since: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockState {
+    Free,
+    Shared,
+    Exclusive,
+}
+
+impl LockState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LockState::Free => "free",
+            LockState::Shared => "shared",
+            LockState::Exclusive => "exclusive",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LockStatus {
+    pub path: String,
+    pub state: LockState,
+    pub info: Option<LockInfo>,
+}
+
+impl LockStatus {
+
+    // the recorded holder is a process of this host that is gone
+    pub fn is_stale(&self) -> bool {
+        match self.info {
+            Some(ref info) => holder_alive(info) == Some(false),
+            None => false,
+        }
+    }
+}
+
+pub fn info_path(path: &str) -> String {
+    format!("{}.lockinfo", path)
+}
+
+pub fn local_host() -> String {
+    let mut host = String::new();
+    match File::open("/proc/sys/kernel/hostname").and_then(|mut f| f.read_to_string(&mut host)) {
+        Ok(_) if !host.trim().is_empty() => String::from(host.trim()),
+        _ => env::var("HOSTNAME").unwrap_or(String::from("unknown")),
+    }
+}
+
+// Some(true) when the holder runs on this host, Some(false) when it is gone,
+// None when it can not be told (another host, no /proc)
+pub fn holder_alive(info: &LockInfo) -> Option<bool> {
+    if info.host != local_host() || !Path::new("/proc/self").exists() {
+        return None;
+    }
+    Some(Path::new(&format!("/proc/{}", info.pid)).exists())
+}
+
+// record this process as the holder of the exclusive lock of path
+pub fn acquired(path: &str) {
+    let info = LockInfo {
+        pid: process::id(),
+        host: local_host(),
+        since: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    };
+    let _ = File::create(info_path(path)).and_then(|mut f| f.write_all(json::encode(&info).unwrap().as_bytes()));
+}
+
+// the lock of path is released, only by the process that recorded it
+pub fn released(path: &str) {
+    if let Ok(Some(info)) = read_info(path) {
+        if info.pid == process::id() && info.host == local_host() {
+            let _ = fs::remove_file(info_path(path));
+        }
+    }
+}
+
+pub fn read_info(path: &str) -> Result<Option<LockInfo>> {
+    let mut content = String::new();
+    match File::open(info_path(path)) {
+        Ok(mut f) => try!(f.read_to_string(&mut content)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    json::decode(&content).map(Some).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", info_path(path), e)))
+}
+
+// the lock held on path, found without waiting: another handle of this
+// process counts as another holder
+pub fn probe(path: &str) -> Result<LockStatus> {
+
+    let file = try!(OpenOptions::new().read(true).open(path));
+    let contended = |r: Result<()>| match r {
+        Ok(()) => Ok(false),
+        Err(ref e) if e.kind() == lock_contended_error().kind() => Ok(true),
+        Err(e) => Err(e),
+    };
+
+    let state = if !try!(contended(file.try_lock_exclusive())) {
+        LockState::Free
+    } else if !try!(contended(FileExt::try_lock_shared(&file))) {
+        LockState::Shared
+    } else {
+        LockState::Exclusive
+    };
+    let _ = file.unlock();
+
+    Ok(LockStatus {
+        path: String::from(path),
+        state: state,
+        info: try!(read_info(path)),
+    })
+}
+
+// a holder alive on this host is not forced
+pub fn check_not_alive(status: &LockStatus) -> Result<()> {
+    match status.info {
+        Some(ref info) if holder_alive(info) == Some(true) && info.pid != process::id() => {
+            Err(Error::new(ErrorKind::Other, format!(
+                "{} is locked by pid {} on {}, which is alive", status.path, info.pid, info.host)))
+        },
+        _ => Ok(()),
+    }
+}
+
+// remove the lock info of path when its holder is not alive on this host,
+// then try to take the lock: tells if the file is free now
+pub fn force_unlock(path: &str) -> Result<bool> {
+
+    let status = try!(probe(path));
+    try!(check_not_alive(&status));
+    if status.info.is_some() {
+        try!(fs::remove_file(info_path(path)));
+    }
+    Ok(try!(probe(path)).state == LockState::Free)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::remove_file;
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+
+    fn record(path: &str, pid: u32) {
+        let info = LockInfo { pid: pid, host: local_host(), since: 0 };
+        File::create(info_path(path)).unwrap().write_all(json::encode(&info).unwrap().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn stale_or_live() {
+        File::create("test_lock.db").unwrap();
+
+        let holder = OpenOptions::new().read(true).open("test_lock.db").unwrap();
+        holder.lock_exclusive().unwrap();
+        acquired("test_lock.db");
+        let status = probe("test_lock.db").unwrap();
+        assert_eq!(LockState::Exclusive, status.state);
+        assert_eq!(process::id(), status.info.unwrap().pid);
+        // a live holder is refused
+        record("test_lock.db", 1);
+        assert!(Path::new("/proc/1").exists());
+        assert!(force_unlock("test_lock.db").is_err());
+        assert!(read_info("test_lock.db").unwrap().is_some());
+
+        // recorded by this process, released
+        record("test_lock.db", process::id());
+        holder.unlock().unwrap();
+        released("test_lock.db");
+        assert!(read_info("test_lock.db").unwrap().is_none());
+
+        // a pid that is gone: stale, removed and the lock taken again
+        let gone = u32::max_value() - 1;
+        record("test_lock.db", gone);
+        let status = probe("test_lock.db").unwrap();
+        assert_eq!(LockState::Free, status.state);
+        assert!(status.is_stale());
+        assert!(force_unlock("test_lock.db").unwrap());
+        assert!(read_info("test_lock.db").unwrap().is_none());
+
+        // a reader
+        holder.lock_shared().unwrap();
+        assert_eq!(LockState::Shared, probe("test_lock.db").unwrap().state);
+        assert!(!force_unlock("test_lock.db").unwrap());
+        holder.unlock().unwrap();
+
+        // another host can not be told
+        File::create(info_path("test_lock.db")).unwrap()
+            .write_all(json::encode(&LockInfo { pid: 1, host: String::from("elsewhere"), since: 0 }).unwrap().as_bytes()).unwrap();
+        assert!(!probe("test_lock.db").unwrap().is_stale());
+        assert!(force_unlock("test_lock.db").unwrap());
+
+        let _ = remove_file("test_lock.db");
+        let _ = remove_file(info_path("test_lock.db"));
+    }
+
+    #[test]
+    fn recorded_by_the_writers() {
+        let mut db = BinacleManager::create("test_lock2.db", true, 1 << 30, 6, 16).unwrap();
+        db.insert_reader(&b"GetProcAddress"[..], "a.exe", 1, true);
+        db.sync().unwrap();
+
+        let report = BinacleManager::lock_status("test_lock2.db").unwrap();
+        assert_eq!(2, report.len());
+        // the meta is only locked while it is written
+        assert_eq!(LockState::Free, report[0].state);
+        assert!(report[0].info.is_none());
+        // the shard being filled is held
+        assert_eq!(LockState::Exclusive, report[1].state);
+        assert_eq!(process::id(), report[1].info.as_ref().unwrap().pid);
+
+        db.close().unwrap();
+        let report = BinacleManager::lock_status("test_lock2.db").unwrap();
+        assert!(report.iter().all(|s| s.state == LockState::Free && s.info.is_none()));
+        cleanup("test_lock2");
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs::OpenOptions;
+use std::io::*;
+use rustc_serialize::hex::FromHex;
+
+// Content type of the files, guessed from their first bytes
+
+
+// number of bytes kept to guess the type
+pub const HEAD_SIZE: usize = 512;
+
+struct Magic {
+    name: String,
+    offset: usize,
+    bytes: Vec<u8>,
+}
+
+pub struct MagicTable {
+    rules: Vec<Magic>,
+}
+
+impl MagicTable {
+
+    pub fn builtin() -> MagicTable {
+
+        let rules: &[(&str, usize, &[u8])] = &[
+            ("pe", 0, b"MZ"),
+            ("elf", 0, b"\x7fELF"),
+            ("macho", 0, b"\xfe\xed\xfa\xce"),
+            ("macho", 0, b"\xfe\xed\xfa\xcf"),
+            ("macho", 0, b"\xce\xfa\xed\xfe"),
+            ("macho", 0, b"\xcf\xfa\xed\xfe"),
+            ("pdf", 0, b"%PDF-"),
+            ("zip", 0, b"PK\x03\x04"),
+            ("gzip", 0, b"\x1f\x8b"),
+            ("7z", 0, b"7z\xbc\xaf\x27\x1c"),
+            ("rar", 0, b"Rar!\x1a\x07"),
+            ("ole", 0, b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1"),
+        ];
+
+        MagicTable {
+            rules: rules.iter().map(|&(name, offset, bytes)| Magic {
+                name: String::from(name),
+                offset: offset,
+                bytes: Vec::from(bytes),
+            }).collect(),
+        }
+    }
+
+    // builtin table, with the rules of a definitions file checked first
+    // one rule a line: <name> <offset> <hex bytes>, '#' starts a comment
+    pub fn load(path: &str) -> Result<MagicTable> {
+
+        let mut content = String::new();
+        try!(try!(OpenOptions::new().read(true).open(path)).read_to_string(&mut content));
+
+        let mut rules = Vec::new();
+        for (nb, line) in content.lines().enumerate() {
+
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("{}:{}: invalid magic rule", path, nb + 1));
+
+            if fields.len() != 3 {
+                return Err(invalid());
+            }
+            let offset = try!(fields[1].parse::<usize>().map_err(|_| invalid()));
+            let bytes = try!(fields[2].from_hex().map_err(|_| invalid()));
+            if bytes.is_empty() || offset + bytes.len() > HEAD_SIZE {
+                return Err(invalid());
+            }
+
+            rules.push(Magic {
+                name: String::from(fields[0]),
+                offset: offset,
+                bytes: bytes,
+            });
+        }
+
+        rules.extend(MagicTable::builtin().rules);
+        Ok(MagicTable { rules: rules })
+    }
+
+    // type of a file from its first bytes, "data" when unknown
+    pub fn detect(&self, head: &[u8]) -> &str {
+
+        for rule in &self.rules {
+            if head.len() >= rule.offset + rule.bytes.len() &&
+               &head[rule.offset .. rule.offset + rule.bytes.len()] == &rule.bytes[..] {
+                return &rule.name;
+            }
+        }
+
+        if is_text(head) {
+            "text"
+        } else {
+            "data"
+        }
+    }
+}
+
+// UTF-8 without control characters, the head may cut the last character
+fn is_text(head: &[u8]) -> bool {
+
+    if head.is_empty() {
+        return false;
+    }
+
+    let valid = match ::std::str::from_utf8(head) {
+        Ok(s) => s,
+        Err(ref e) if e.error_len().is_none() => ::std::str::from_utf8(&head[.. e.valid_up_to()]).unwrap(),
+        Err(_) => return false,
+    };
+
+    valid.chars().all(|c| !c.is_control() || c == '\n' || c == '\r' || c == '\t' || c == '\x0c')
+}
+
+// Reader keeping the first bytes read through it
+pub struct HeadReader<R> {
+    inner: R,
+    head: Vec<u8>,
+}
+
+impl<R: Read> HeadReader<R> {
+
+    pub fn new(inner: R) -> HeadReader<R> {
+        HeadReader {
+            inner: inner,
+            head: Vec::with_capacity(HEAD_SIZE),
+        }
+    }
+
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+}
+
+impl<R: Read> Read for HeadReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = try!(self.inner.read(buf));
+        if self.head.len() < HEAD_SIZE {
+            let missing = ::std::cmp::min(HEAD_SIZE - self.head.len(), len);
+            self.head.extend_from_slice(&buf[.. missing]);
+        }
+        Ok(len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{File, remove_file};
+
+    #[test]
+    fn detect_builtin() {
+        let table = MagicTable::builtin();
+        assert_eq!("pe", table.detect(b"MZ\x90\x00\x03\x00\x00\x00"));
+        assert_eq!("elf", table.detect(b"\x7fELF\x02\x01\x01"));
+        assert_eq!("pdf", table.detect(b"%PDF-1.7\n"));
+        assert_eq!("zip", table.detect(b"PK\x03\x04\x14\x00"));
+        assert_eq!("text", table.detect(b"hello world\n"));
+        assert_eq!("text", table.detect("caf\u{e9}".as_bytes()));
+        assert_eq!("data", table.detect(b"\x00\x01\x02\x03"));
+        assert_eq!("data", table.detect(b""));
+    }
+
+    #[test]
+    fn user_definitions() {
+        File::create("test_magic.txt").unwrap()
+            .write_all(b"# custom types\nclass 0 cafebabe\nmzlike 0 4d5a9000 # before pe\n").unwrap();
+
+        let table = MagicTable::load("test_magic.txt").unwrap();
+        assert_eq!("class", table.detect(b"\xca\xfe\xba\xbe\x00"));
+        assert_eq!("mzlike", table.detect(b"MZ\x90\x00"));
+        assert_eq!("pe", table.detect(b"MZ\x00\x00"));
+
+        File::create("test_magic.txt").unwrap().write_all(b"broken 0 zz\n").unwrap();
+        assert!(MagicTable::load("test_magic.txt").is_err());
+
+        let _ = remove_file("test_magic.txt");
+    }
+
+    #[test]
+    fn head_kept() {
+        let content = vec![0x41u8; HEAD_SIZE * 2];
+        let mut reader = HeadReader::new(&content[..]);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(HEAD_SIZE, reader.head().len());
+        assert_eq!(content, out);
+    }
+}
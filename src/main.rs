@@ -1,118 +1,2708 @@
 extern crate rustc_serialize;
 extern crate docopt;
-extern crate walkdir;
-extern crate time;
 extern crate regex;
-extern crate twoway;
+extern crate binacle;
 
 use std::env;
-use std::fs::OpenOptions;
-use std::io::Read;
-use rustc_serialize::hex::FromHex;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::thread;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rustc_serialize::hex::{FromHex, ToHex};
 use rustc_serialize::json;
-use docopt::Docopt;
-use regex::Regex;
+use docopt::{Docopt, ArgvMap};
 
-mod binacle_manager;
-mod binacle;
+use binacle::binacle::Compacted;
+use binacle::binacle_manager;
+use binacle::search::{self, SearchRequest};
+use binacle::hash;
+use binacle::audit;
+use binacle::fingerprint;
+use binacle::context;
+use binacle::magic;
+use binacle::durability::Durability;
+use binacle::corpus;
+use binacle::provenance;
+use binacle::reindex;
+use binacle::bulk;
+use binacle::chains;
+use binacle::translate;
+use binacle::queries;
+use binacle::variants::{self, ByteSwap};
+use binacle::params;
+use binacle::abtest;
+use binacle::overlap;
+use binacle::dirty;
+use binacle::scan;
+use binacle::sidecar::Sidecars;
+use binacle::aggregate;
+use binacle::pattern_file;
+use binacle::exec;
+use binacle::map_rebuild;
+use binacle::warmup;
+use binacle::postings;
+use binacle::shareable;
+use binacle::stats;
+use binacle::reads::ReadPath;
+use binacle::materialize;
+use binacle::dates;
+use binacle::ingest_filter::{self, IngestFilter};
+use binacle::occupancy::{self, BitmapCheck};
+use binacle::space::{self, SpaceCheck};
+use binacle::collection::Collections;
+use binacle::library::{self, Library};
+use binacle::file_list::{self, FileList};
+use binacle::yara;
+use binacle::server;
+use binacle::sandbox::{self, Sandbox};
+use binacle::watch;
+
+mod cli;
+mod config;
+mod exit;
+mod output;
+mod sink;
+mod style;
 
 // Command line arguments are explained in readme
 
 const USAGE: &'static str = "
 Usage: 
-       binacle -c <db_name> [map] <max_size> <alignment> <ngram_size>
-       binacle <db_name> -f <id> <file>
-       binacle <db_name> --files <files_and_ids>
-       binacle <db_name> --rec <dir>
-       binacle <db_name> -s [exact] [hex] <string>
+       binacle -c <db_name> [map] <max_size> <alignment> <ngram_size> [--translate=<file>]
+       binacle <db_name> -f <id> <file> [--label=<label>] [options]
+       binacle <db_name> -f <file> [--label=<label>] [options]
+       binacle <db_name> --files <files_and_ids> [options]
+       binacle <db_name> --rec <dir> [options]
+       binacle <db_name> --watch <dir> [options]
+       binacle <db_name> --ingest-manifest <manifest> [--result=<file>] [options]
+       binacle <db_name> -s [exact] [hex] (<string> | --search-file=<file>) [--variant=<alt>...] [--and=<string>...] [options]
+       binacle <db_name> --queries <file> [options]
+       binacle <db_name> --save-query <name> -s [exact] [hex] (<string> | --search-file=<file>) [--and=<string>...] [--query-tag=<tag>...] [options]
+       binacle <db_name> --run-query <name>
+       binacle <db_name> --run-all-queries [--tag=<tag>]
+       binacle <db_name> --yara <rules>
+       binacle <db_name> --list-queries [--color=<when>] [--no-truncate]
+       binacle <db_name> --delete-query <name>
+       binacle <db_name> --inetd [--timing]
+       binacle <db_name> --serve <addr> [--user=<name>] [--group=<name>] [--chroot=<dir>] [--cache-ids=<n>] [--request-budget=<n>] [--release-pages]
+       binacle <db_name> --warmup [--seconds=<n>] [--queries <file>] [--notify]
+       binacle --tmp <dir> --queries <file> [--persist=<db_name>] [options]
+       binacle --scan <dir> --queries <file> [--jobs=<n>]
+       binacle <db_name> --have <file>
+       binacle <db_name> --lock-status
+       binacle <db_name> --force-unlock [--yes]
+       binacle <db_name> --have-hash <sha256>
+       binacle <db_name> --audit-ids [--apply]
+       binacle <db_name> --rebuild-map
+       binacle <db_name> (--seal | --unseal)
+       binacle <db_name> --id-of <label>
+       binacle <db_name> --info <id>
+       binacle <db_name> --note <id> <text>
+       binacle <db_name> --delete <id>
+       binacle <db_name> --list [--note-contains=<substr>] [--filter=<substr>] [--id=<id>] [--color=<when>] [--no-truncate]
+       binacle <db_name> --why <id> [hex] <string>
+       binacle <db_name> --coverage-of <id> [--bar]
+       binacle <db_name> --fingerprint
+       binacle <db_name> --dump-postings [--min-df=<n>] [--format=<fmt>]
+       binacle <db_name> --verify-shards [--color=<when>]
+       binacle <db_name> --check [--rebuild-bitmap]
+       binacle <db_name> --stats [--json] [--durability=<policy>] [--rechain-threshold=<len>] [--color=<when>]
+       binacle <db_name> --stats --shareable [--k-floor=<n>] [--bucket=<n>] [--salt-top]
+       binacle <db_name> --compact-ngrams <top_n> [--ignore-space-check]
+       binacle <db_name> --compact [--ignore-space-check]
+       binacle <db_name> --finalize <shard>
+       binacle <db_name> --evaluate <truth>
+       binacle <db_name> --collection <action> [<name>] [options]
+       binacle <db_name> --reindex <dst_db> [--ngram-size=<bits>] [--alignment=<a>] [--ignore-space-check]
+       binacle <db_name> --verify-reindex <dst_db> [--sample=<n>] [--seed=<s>]
+       binacle <db_name> --merge <src_db> [--id-offset=<n>] [--ignore-space-check]
+       binacle --bulk-build <db_name> --from <source> <max_size> <alignment> <ngram_size> [--bulk-memory=<bytes>] [--deterministic] [--magic=<file>] [--translate=<file>] [--ignore-space-check]
+       binacle --gen-corpus <dir> --files <nb_files> [--size-dist=<dist>] [--seed=<s>] [--plant=<patterns>] [--truth=<file>]
+       binacle --suggest-params <sample_dir> --ram=<gb> --disk=<gb> --expected-total=<total>
+       binacle --ab-test <db_a> <db_b> --queries <file> [--corpus-truth=<json>]
+       binacle --overlap <db_a> <db_b> [--sample=<n>] [--seed=<s>]
+       binacle --show-config [--config=<file>]
 
 Options:
     exact, --exact  Show exact matches
-    hex, --hex      Provide hexa string.
+    --verify        Read the files of the results again and only show the ones
+                    having the patterns (map databases): the false positives
+                    are counted, the files that can not be read are unverifiable.
+    hex, --hex      Provide hexa string, ?? for a byte that can be any.
+    --wide          Search the UTF-16LE form of the patterns, the strings of
+                    the PE files.
+    --both          Search the patterns as given and in UTF-16LE, a file
+                    having either form is found.
+    --search-file=<file>  Search the content of this file: its UTF-8 byte order
+                    mark and its final line endings are stripped.
+    --keep-line-endings  Search the final CR and LF of --search-file too.
+    --literal-newlines  Search the line breaks of <string>, instead of each
+                    line as a pattern.
+    --binary-ok     No warning for the bytes of --search-file out of printable ASCII.
+    --max-bytes=<n>  Only search the first n bytes of --search-file.
+    --and=<string>  Only keep the files that also contain this pattern, hex with hex.
+    --any           Search each line of <string> or --search-file as a pattern and
+                    print the ids found with the index of each of their patterns.
+    --exclude=<string>  Drop the files that also contain this pattern.
+    --id-range=<min-max>  Only keep ids in this range.
+    --limit=<n>     Show at most n results.
+    --sample=<n>    Show n results drawn at random.
+    --seed=<s>      Seed of --sample, to draw the same results again.
+    --explain       Print the evaluation steps on stderr.
+    --why-not=<id>  Tell which n-grams of the pattern miss this id (or label) and why,
+                    or why a file of --rec was left out by its filters.
+    --deadline-ms=<ms>  Stop the search after ms milliseconds, the shards not
+                    searched by then are skipped and the result is partial.
+    --strict        Fail when a shard can not be searched (the other shards are
+                    searched and the exit code is 3 otherwise).
+    --context=<n>   Hex dump of n bytes around each occurrence (reads the files).
+    --group-by-dir=<depth>  Count results by their first <depth> directories, 1 when
+                    the depth is left out. The depth is only read attached,
+                    after the '=' sign.
+    --aggregate=<spec>  Only count the results by group: count-by:tag,
+                    count-by:type or count-by:dir:<depth>.
+    --show-files    List the files of each group.
+    --json          JSON output: {ids, paths, count} for a search, and an object
+                    {id, path, status, error} a line for each file inserted.
+    --color=<when>  Colors of the output and of the errors: always, never or
+                    auto, on a terminal only [default: auto].
+    --no-truncate   Print the whole paths on a terminal, not cut to its width.
+    --output=<path>  Write the results to this file: .txt, .tsv, .json, or a
+                    .hits table the results of each search are appended to.
+    --format=<fmt>  Format of --output instead of its extension: plain, tsv, json or table.
+                    Of --dump-postings: tsv (by default) or binary.
+    --apply         Perform the recommended actions (dry run otherwise).
+    --rebuild-bitmap  Rebuild from their header the occupancy bitmaps found
+                    missing or out of sync by --check.
+    --yes           Do not ask for a confirmation.
+    --ram=<gb>      RAM of the host of the database, in GB.
+    --disk=<gb>     Disk space for the database, in GB.
+    --expected-total=<total>  Expected size of the corpus: a number of files or
+                    of bytes with a unit (ex: 500GB).
+    --corpus-truth=<json>  Ground truth of --gen-corpus, the recall and the
+                    precision of both databases are reported.
+    --label=<label>  Unique name given to the file, not a number.
+    --labels        Show labels instead of paths.
+    --deterministic  Reproducible insertion: sorted walk, no dates.
+    --magic=<file>  Content types definitions, added to the builtin ones.
+    --file-budget-ms=<ms>  Abandon and quarantine a file not indexed after ms milliseconds.
+    --retry-quarantined  Insert the quarantined files again instead of skipping them.
+    --no-prefetch   Read each file when it is indexed instead of reading the
+                    next ones ahead in a thread.
+    --ignore-space-check  Start an insertion, a bulk build, a compaction, a
+                    reindexing or a merge without checking the free disk
+                    space, and do not stop it when the space runs low.
+    --sidecar-meta=<suffix>  Import the JSON object of the file with this suffix
+                    next to each file of --rec as tags key:value, the
+                    sidecars are not indexed.
+    --sidecar-keys=<keys>  Keys of the sidecars imported: family,source (all by default).
+    --modified-since=<date>  Only insert the files of --rec modified at this date
+                    or after: YYYY-MM-DD[ HH:MM[:SS]] (UTC) or @<seconds>.
+    --modified-before=<date>  Only insert the files of --rec modified before this date.
+    --owner=<user>  Only insert the files of --rec owned by this uid or user (Unix).
+    --min-size=<size>  Only insert the files of --rec of this size or more: bytes
+                    or with a unit, 512k, 10M, 2G.
+    --max-size=<size>  Only insert the files of --rec of this size or less.
+    --force         Insert again under new ids the files of --rec already in
+                    the map, they are skipped otherwise.
+    --durability=<policy>  When insertions are synced: none, per-file or
+                    periodic:<files>:<seconds> [default: none].
+    --interval=<s>  Seconds between two scans of --watch [default: 10].
+    --progress      Count the files of --rec first, then draw the files and
+                    the bytes done, their rates and the time left on stderr.
+    --quiet         Only the errors of an insertion on stderr, no counts.
+    --type=<types>  Only keep these content types (ex: pe,elf).
+    --show-type     Show the content type of each result.
+    --show-notes    Show the notes of each result.
+    --bar           Draw the n-gram positions covered.
+    --note-contains=<substr>  Only list the ids with a note containing substr.
+    --filter=<substr>  Only list the ids with a path containing substr.
+    --id=<id>       Only list this id or label.
+    --tag=<tag>     Only keep the files with this tag, or the saved queries with
+                    this tag for --run-all-queries.
+    --query-tag=<tag>  Tag of a saved query, to run it with the others of the tag.
+    --in-collection=<name>  Only keep the files of this collection.
+    --save-as=<name>  Add the results to this collection.
+    --ids=<ids>     Ids added to a collection: 1,2,3.
+    --from-query=<string>  Add the results of this search to a collection.
+    --path-match=<regex>  Only keep the files whose path or label matches.
+    --path-contains=<substr>  Only keep the files whose path or label contains it.
+    --endian=<order>  both: also look for the byte-reversed pattern.
+    --endian-unit=<unit>  Reverse the whole pattern or each 4-byte word:
+                    pattern or 4 [default: pattern].
+    --variant=<alt>  Bytes the pattern byte at this offset can take, as in
+                    8=00,10,40: any variant of the pattern is found.
+    --variant-cap=<n>  Offsets are matched by any byte over n variants [default: 64].
+    --exec=<command>  Run this command on each result: {} is replaced by its
+                    path, BINACLE_ID, BINACLE_PATH, BINACLE_SHA256 and
+                    BINACLE_QUERY are set. Not run by a shell.
+    --exec-jobs=<n>  Commands run at the same time [default: 1].
+    --exec-timeout-ms=<ms>  Kill a command still running after ms milliseconds.
+    --exec-batch    Run the command on many results at once, {} or its end
+                    is replaced by their paths.
+    --exec-shell    Run the command by the shell, the paths quoted.
+    --copy-to=<dir>  Copy the results in this directory, named by their sha256,
+                    with a manifest of the copy.
+    --hardlink      Hard links in --copy-to instead of copies.
+    --symlink       Symbolic links in --copy-to instead of copies.
+    --max-copy-bytes=<n>  Stop copying when n bytes are copied by --copy-to.
+    --timing        Print the time from the opening of the database to the
+                    answer of --inetd.
+    --seconds=<n>   Time budget of --warmup, none by default.
+    --notify        Tell systemd ($NOTIFY_SOCKET) the warm-up is done.
+    --user=<name>   User --serve runs as once its socket is bound, a name or
+                    an uid. Its primary group unless --group.
+    --group=<name>  Group --serve runs as, a name or a gid.
+    --chroot=<dir>  Directory --serve is confined to, holding the database
+                    and the current directory.
+    --cache-ids=<n>  Ids of the decoded lists --serve keeps for the next
+                    requests, 2^24 by default.
+    --request-budget=<n>  Ids of the lists of a request above which they are
+                    not cached, 2^20 by default.
+    --release-pages  Give back the pages read by a request over its budget.
+    --min-df=<n>    Only dump the lists of at least n ids.
+    --shareable     Statistics for partners, the counts from which a file
+                    could be told in the corpus suppressed or rounded.
+    --k-floor=<n>   Counts under n are suppressed from --shareable [default: 10].
+    --bucket=<n>    Counts of --shareable rounded down to a multiple of n [default: 10].
+    --salt-top      Give the most frequent n-grams in --shareable, hashed
+                    with a salt drawn for the export (omitted otherwise).
+    --verify-shards  Check the sha256 of the shards recorded when they were
+                    finalized before searching them (reads them all).
+    --preselect=<k>  N-grams of the pattern probed on their score before all
+                    are, 0 probes them all [default: 8].
+    --no-mmap-reads  Read the shards searched with positioned reads: a read
+                    failing on network storage fails the shard, not the process.
+    --dirty-read    Search the shards another process is writing without waiting
+                    for it, as of their last meta: the files it inserted since
+                    may be missing.
+    --result=<file>  Status of each manifest line [default: <manifest>.result].
+    --size-dist=<dist>  Size of the generated files, lognormal:<mean>,<sigma>
+                    of the log of the size [default: lognormal:10,1.5].
+    --plant=<patterns>  Patterns written in the generated files: <hex>@<count>,...
+    --truth=<file>  Ground truth of the generated corpus [default: <dir>.truth.json].
+    --ngram-size=<bits>  ngram_size of the reindexed database, at most the current one.
+    --alignment=<a>  Alignment of the reindexed database.
+    --id-offset=<n>  Added to the ids of the merged database [default: 0].
+    --bulk-memory=<bytes>  Memory of the sorted runs of a bulk build, spilled to
+                    <db_name>.bulk when full [default: 536870912].
+    --rechain-threshold=<len>  Recommend a compaction when the p95 of the chain
+                    lengths of a shard is over len [default: 8].
+    --translate=<file>  Byte translation of the contents and the patterns, kept
+                    in the database: a 256-byte table or rules (see readme).
+    --persist=<db_name>  Keep the temporary database of --tmp as this database.
+    --jobs=<n>      Threads reading the files of --scan, one per CPU by default.
+    --map-cap=<bytes>  Memory the map may use, the available memory by default.
+    --lazy-map      Read a map over the cap from the disk at each search (read only).
+    --permissive    Warn about the options the command does not use instead of failing.
+    --config=<file>  Defaults of the options, instead of ./binacle.toml or
+                    $XDG_CONFIG_HOME/binacle/config.toml.
 ";
 
 fn main() {
 
-    let args = Docopt::new(USAGE)
-                  .and_then(|d| d.argv(env::args()).parse())
-                  .unwrap_or_else(|e| e.exit());
+    let cli_args = Docopt::new(USAGE)
+                  .and_then(|d| d.argv(cli::fill_values(env::args())).parse())
+                  .unwrap_or_else(|e| match e.fatal() {
+                      true => {
+                          eprintln!("{}", e);
+                          process::exit(exit::USAGE);
+                      },
+                      // --help and --version
+                      false => e.exit(),
+                  });
+
+    // the defaults of the config file, under the options of the command line
+    let mut args = cli_args.clone();
+    let applied = config::Config::load(cli_args.get_str("--config"))
+                      .and_then(|config| config.apply(USAGE, &mut args).map(|applied| (config, applied)));
+    let (config, applied) = match applied {
+        Ok(applied) => applied,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(exit::code(&e));
+        },
+    };
 
+    let color = match style::ColorChoice::parse(args.get_str("--color")) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(exit::USAGE);
+        },
+    };
+    style::init(color);
+    // aligned and colored on a terminal, plain when piped or redirected
+    let out = style::Style::stdout(color, !args.get_bool("--no-truncate"));
+
+    // options given but not used by the command
+    let problems = cli::check(USAGE, &cli_args);
+    if args.get_bool("--permissive") {
+        for p in &problems {
+            style::warning(p);
+        }
+    } else if !problems.is_empty() {
+        for p in &problems {
+            style::error(p);
+        }
+        eprintln!("(--permissive to run anyway)");
+        process::exit(exit::USAGE);
+    }
 
     if args.get_bool("-c") {
         let db_name = args.get_str("<db_name>");
         let is_map = args.get_bool("map");
-        let max_size = args.get_str("<max_size>").parse::<u64>().unwrap();
-        let alignment = args.get_str("<alignment>").parse::<u8>().unwrap();
-        let ngram_size = args.get_str("<ngram_size>").parse::<u8>().unwrap();
-        binacle_manager::BinacleManager::create(db_name, is_map, max_size, alignment, ngram_size).unwrap();
+        let max_size = exit::arg::<u64>(&args, "<max_size>");
+        let alignment = exit::arg::<u8>(&args, "<alignment>");
+        let ngram_size = exit::arg::<u8>(&args, "<ngram_size>");
+        let mut db = binacle_manager::BinacleManager::create(db_name, is_map, max_size, alignment, ngram_size)
+                         .unwrap_or_else(|e| exit::fail(e));
+        if args.get_str("--translate") != "" {
+            let res = translate::Translation::load(args.get_str("--translate"))
+                          .and_then(|translation| db.set_translation(Some(translation)));
+            if let Err(e) = res {
+                drop(db);
+                exit::fail(e);
+            }
+        }
+    }
+
+    if args.get_bool("--gen-corpus") {
+        let dir = args.get_str("<dir>");
+        let nb_files = exit::arg::<usize>(&args, "<nb_files>");
+        let dist = corpus::SizeDist::parse(args.get_str("--size-dist")).unwrap_or_else(|e| exit::fail(e));
+        let seed = match args.get_str("--seed") {
+            "" => 0,
+            _ => exit::arg::<u64>(&args, "--seed"),
+        };
+        let plants = corpus::Plant::parse_list(args.get_str("--plant")).unwrap_or_else(|e| exit::fail(e));
+        let truth_path = match args.get_str("--truth") {
+            "<dir>.truth.json" => format!("{}.truth.json", dir.trim_end_matches('/')),
+            path => String::from(path),
+        };
+
+        let truth = corpus::generate(dir, nb_files, dist, seed, &plants).unwrap_or_else(|e| exit::fail(e));
+        if let Err(e) = truth.write(&truth_path) {
+            exit::fail(e);
+        }
+        println!("{} file(s) written in {}, ground truth in {}", truth.files.len(), dir, truth_path);
+        return;
     }
 
-    let mut db = binacle_manager::BinacleManager::open(args.get_str("<db_name>")).unwrap();
+    if args.get_bool("--bulk-build") {
+        let db_name = args.get_str("<db_name>");
+        let max_size = exit::arg::<u64>(&args, "<max_size>");
+        let alignment = exit::arg::<u8>(&args, "<alignment>");
+        let ngram_size = exit::arg::<u8>(&args, "<ngram_size>");
+        let memory = exit::arg::<u64>(&args, "--bulk-memory");
+
+        let sources = match bulk::sources(args.get_str("<source>")) {
+            Ok(s) => s,
+            Err(e) => exit::fail(e),
+        };
+        let checked = !args.get_bool("--ignore-space-check");
+        if checked {
+            let (files, bytes) = space::files_content(sources.iter().map(|s| s.path.as_str()));
+            let need = space::bulk_need(files, bytes, memory, ngram_size, alignment, max_size);
+            if let Err(e) = SpaceCheck::new().require(db_name, &need) {
+                exit::fail(e);
+            }
+        }
+        let mut db = binacle_manager::BinacleManager::create(db_name, true, max_size, alignment, ngram_size)
+                         .unwrap_or_else(|e| exit::fail(e));
+        if checked {
+            db.set_space_check(Some(SpaceCheck::new()));
+        }
+        db.set_deterministic(args.get_bool("--deterministic"));
+        if args.get_str("--magic") != "" {
+            match magic::MagicTable::load(args.get_str("--magic")) {
+                Ok(table) => db.set_magic(table),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        }
+        if args.get_str("--translate") != "" {
+            let res = translate::Translation::load(args.get_str("--translate"))
+                          .and_then(|translation| db.set_translation(Some(translation)));
+            if let Err(e) = res {
+                drop(db);
+                exit::fail(e);
+            }
+        }
+
+        match bulk::bulk_build(&mut db, &sources, memory, &format!("{}.bulk", db_name), 1) {
+            Ok(report) => {
+                for &(ref path, ref reason) in &report.skipped {
+                    eprintln!("skipped {}: {}", path, reason);
+                }
+                println!("{} file(s), {} postings, {} sorted run(s) spilled, index of {} bytes",
+                         report.files, report.postings, report.runs, report.size);
+            },
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+        return;
+    }
+
+    // same, with a bulk build sized from the files of the directory
+    if args.get_bool("--scan") {
+        let jobs = match args.get_str("--jobs") {
+            "" => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => match n.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    exit::usage(format!("invalid --jobs: {}", n));
+                },
+            },
+        };
+        let res = scan::scan_dir(args.get_str("<dir>"), jobs).and_then(|(mut db, report)| {
+            for &(ref path, ref reason) in &report.skipped {
+                eprintln!("skipped {}: {}", path, reason);
+            }
+            eprintln!("{} file(s) indexed, ngram_size {}", report.files, db.ngram_size());
+            let res = run_queries(&mut db, args.get_str("<file>"));
+            res.and_then(|_| db.close())
+        });
+        if let Err(e) = res {
+            exit::fail(e);
+        }
+        return;
+    }
+
+    // index a directory in a temporary database, search it and remove it
+    if args.get_bool("--tmp") {
+        let ngram_size = match args.get_str("--ngram-size") {
+            "" => 24,
+            _ => exit::arg::<u8>(&args, "--ngram-size"),
+        };
+        let alignment = match args.get_str("--alignment") {
+            "" => 6,
+            _ => exit::arg::<u8>(&args, "--alignment"),
+        };
+        let mut db = binacle_manager::BinacleManager::create_in_memory(true, 2000000000, alignment, ngram_size)
+                         .unwrap_or_else(|e| exit::fail(e));
+        db.set_deterministic(args.get_bool("--deterministic"));
+        if args.get_str("--magic") != "" {
+            match magic::MagicTable::load(args.get_str("--magic")) {
+                Ok(table) => db.set_magic(table),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        }
+        let res = db.insert_dir_recursive(args.get_str("<dir>"))
+                    .and_then(|_| match args.get_str("--persist") {
+                        "" => Ok(()),
+                        path => db.persist(path),
+                    })
+                    .and_then(|_| run_queries(&mut db, args.get_str("<file>")));
+        let res = res.and_then(|_| db.close());
+        if let Err(e) = res {
+            exit::fail(e);
+        }
+        return;
+    }
+
+    // the options once the config file applied, and where they come from
+    if args.get_bool("--show-config") {
+        output::print_config(&config, &config.effective(USAGE, &cli_args, &args, &applied));
+        return;
+    }
+
+    // parameters of a new database from a sample of its corpus
+    if args.get_bool("--suggest-params") {
+        let gb = |name: &str| match args.get_str(name).parse::<f64>() {
+            Ok(v) if v > 0.0 => (v * (1u64 << 30) as f64) as u64,
+            _ => {
+                exit::usage(format!("invalid {}: {}", name, args.get_str(name)));
+            },
+        };
+        let res = params::Expected::parse(args.get_str("--expected-total"))
+                    .and_then(|expected| {
+                        let budget = params::Budget { ram: gb("--ram"), disk: gb("--disk"), expected: expected };
+                        params::suggest(args.get_str("<sample_dir>"), &budget)
+                    });
+        let (predictions, best) = match res {
+            Ok(r) => r,
+            Err(e) => exit::fail(e),
+        };
+        for (i, p) in predictions.iter().enumerate() {
+            println!("ngram_size {} alignment {}: {} shard(s) of at most {} bytes, {} bytes on disk, {:.1} ids read per n-gram of a query{}",
+                     p.ngram_size, p.alignment, p.shards, p.max_index_size, p.disk, p.query_ids,
+                     if i == best { " (suggested)" } else { "" });
+            println!("    header {} bytes, {} postings, {:.2} bytes per posting, n-gram sharing {:.2}, {:.1} ids per list",
+                     p.header, p.postings, p.bytes_per_posting, p.sharing, p.mean_list);
+            for reason in &p.reasons {
+                println!("    does not fit: {}", reason);
+            }
+        }
+        let p = &predictions[best];
+        if !p.fits {
+            eprintln!("Warning: no candidate fits the budget, the smallest one on disk is suggested");
+        }
+        println!("offset_size is {}: a shard can address up to {} bytes", p.offset_size, binacle::binacle::addressable_size(p.offset_size, p.alignment));
+        println!("binacle -c <db_name> map {} {} {}", p.max_index_size, p.alignment, p.ngram_size);
+        return;
+    }
+
+    // the same queries against two databases, exit code 2 when they disagree
+    if args.get_bool("--ab-test") {
+        let res = ab_test(args.get_str("<db_a>"), args.get_str("<db_b>"), args.get_str("<file>"), args.get_str("--corpus-truth"));
+        match res {
+            Ok(true) => return,
+            Ok(false) => process::exit(exit::DIFFER),
+            Err(e) => exit::fail(e),
+        }
+    }
+
+    // how much of the n-grams of two databases are the same, from a sample
+    if args.get_bool("--overlap") {
+        let samples = match args.get_str("--sample") {
+            "" => 10000,
+            _ => exit::arg::<usize>(&args, "--sample"),
+        };
+        let seed = match args.get_str("--seed") {
+            "" => 0,
+            _ => exit::arg::<u64>(&args, "--seed"),
+        };
+        if let Err(e) = estimate_overlap(args.get_str("<db_a>"), args.get_str("<db_b>"), samples, seed) {
+            exit::fail(e);
+        }
+        return;
+    }
+
+    // the database is not opened, its locks may be the ones stuck
+    if args.get_bool("--lock-status") || args.get_bool("--force-unlock") {
+        let path = args.get_str("<db_name>");
+        if args.get_bool("--force-unlock") && !args.get_bool("--yes") {
+            eprint!("Remove the lock info of the holders of {} that are gone and take the locks again? [y/N] ", path);
+            let _ = io::stderr().flush();
+            let mut answer = String::new();
+            let _ = io::stdin().lock().read_line(&mut answer);
+            if answer.trim() != "y" && answer.trim() != "yes" {
+                exit::usage("not confirmed");
+            }
+        }
+        let report = match args.get_bool("--force-unlock") {
+            true => binacle_manager::BinacleManager::force_unlock(path),
+            false => binacle_manager::BinacleManager::lock_status(path),
+        };
+        let report = match report {
+            Ok(r) => r,
+            Err(e) => exit::fail(e),
+        };
+        let mut held = false;
+        for status in &report {
+            let holder = match status.info {
+                Some(ref info) => format!(", pid {} on {} since {}{}", info.pid, info.host, info.since,
+                                          if status.is_stale() { " (gone)" } else { "" }),
+                None => String::new(),
+            };
+            println!("{}\t{}{}", status.path, status.state.name(), holder);
+            held |= status.state != binacle::lock::LockState::Free;
+        }
+        if args.get_bool("--force-unlock") && held {
+            style::error("some locks are still held, by a live process or one of another host");
+            process::exit(exit::IO);
+        }
+        return;
+    }
+
+    // a process a query: the map is not loaded, only the entries of the
+    // results are read
+    let opened = Instant::now();
+    let policy = match args.get_bool("--inetd") || args.get_bool("--warmup") {
+        true => binacle_manager::MapPolicy { cap: Some(0), lazy: true },
+        false => binacle_manager::MapPolicy {
+            cap: match args.get_str("--map-cap") {
+                "" => None,
+                _ => Some(exit::arg::<u64>(&args, "--map-cap")),
+            },
+            lazy: args.get_bool("--lazy-map"),
+        },
+    };
+    // a server does not keep the descriptors of its parent
+    if args.get_bool("--serve") {
+        sandbox::close_inherited();
+    }
+    let mut db = match binacle_manager::BinacleManager::open_with(args.get_str("<db_name>"), policy) {
+        Ok(db) => db,
+        Err(e) => exit::fail(e),
+    };
+    if db.is_lazy_map() && !args.get_bool("--inetd") {
+        eprintln!("map: over the memory cap, read lazily (read only)");
+    }
+    db.set_deterministic(args.get_bool("--deterministic"));
+    match Durability::parse(args.get_str("--durability")) {
+        Ok(durability) => db.set_durability(durability),
+        Err(e) => {
+            drop(db);
+            exit::fail(e);
+        },
+    }
+    if args.get_bool("--no-mmap-reads") {
+        db.set_read_path(ReadPath::Pread);
+    }
+    db.set_dirty_read(args.get_bool("--dirty-read"));
+    if args.get_str("--file-budget-ms") != "" {
+        let ms = exit::arg::<u64>(&args, "--file-budget-ms");
+        db.set_file_budget(Some(Duration::from_millis(ms)));
+    }
+    db.set_retry_quarantined(args.get_bool("--retry-quarantined"));
+    if args.get_bool("--no-prefetch") {
+        db.set_prefetch(0);
+    }
+    let inserting = args.get_bool("-f") || args.get_bool("--files") || args.get_bool("--rec") || args.get_bool("--ingest-manifest")
+        || args.get_bool("--watch");
+    if inserting && db.is_sealed() {
+        style::error(format!("{} is sealed, it is read only: --unseal it first", db.db_path));
+        drop(db);
+        process::exit(exit::USAGE);
+    }
+    let quiet = args.get_bool("--quiet");
+    if inserting && !quiet {
+        eprintln!("durability: {}", db.durability());
+    }
+    // read once, stdin can not be read again
+    let file_list = match args.get_bool("--files") {
+        true => match file_list::read(args.get_str("<files_and_ids>")) {
+            Ok(list) => list,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        },
+        false => FileList::default(),
+    };
+    if !args.get_bool("--ignore-space-check") {
+        let need = match space_need(&args, &mut db, &file_list) {
+            Ok(need) => need,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+        if let Some((path, need)) = need {
+            if let Err(e) = SpaceCheck::new().require(&path, &need) {
+                drop(db);
+                exit::fail(e);
+            }
+            db.set_space_check(Some(SpaceCheck::new()));
+        }
+    }
+    let quarantined = db.quarantine().len();
+    // a search without results, its exit code once the database closed
+    let mut found = true;
+    if args.get_str("--magic") != "" {
+        match magic::MagicTable::load(args.get_str("--magic")) {
+            Ok(table) => db.set_magic(table),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
 
     if args.get_bool("-f") {
-        let id = args.get_str("<id>").parse::<u32>().unwrap();
+        // without an id, the one after the last one, printed
+        let auto = args.get_str("<id>") == "";
+        let id = match auto {
+            true => match db.next_id() {
+                Ok(id) => id,
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            },
+            false => exit::arg::<u32>(&args, "<id>"),
+        };
         let file = args.get_str("<file>");
-        db.insert_file(file, id, true).unwrap();
+        let label = args.get_str("--label");
+        let outcome = match label {
+            "" => db.insert(file, id, true),
+            label => db.insert_labeled(file, id, label, true),
+        };
+        let failed = match outcome {
+            binacle_manager::InsertOutcome::Failed(_) => true,
+            _ => false,
+        };
+        if args.get_bool("--json") {
+            let insertion = binacle_manager::FileInsertion { id: id, path: String::from(file), outcome: outcome, size: None };
+            println!("{}", output::insertion_json(&insertion));
+        } else if let binacle_manager::InsertOutcome::Failed(e) = outcome {
+            style::error(e);
+        } else if let binacle_manager::InsertOutcome::Skipped(reason) = outcome {
+            if !quiet {
+                style::warning(format!("{} not inserted: {}", file, reason));
+            }
+        } else if auto {
+            println!("{}", id);
+        }
+        if failed {
+            drop(db);
+            process::exit(exit::IO);
+        }
     }
 
     else if args.get_bool("--rec") {
         let dir = args.get_str("<dir>");
-        db.insert_dir_recursive(dir).unwrap();
+        if args.get_str("--sidecar-meta") != "" {
+            db.set_sidecars(Some(Sidecars::new(args.get_str("--sidecar-meta"), args.get_str("--sidecar-keys"))));
+        }
+        match rec_filter(&args) {
+            Ok(filter) => db.set_ingest_filter(Some(filter)),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+        db.set_reinsert_known(args.get_bool("--force"));
+        let runs = db.filtered_runs().len();
+        let inserted = match args.get_bool("--progress") && !quiet {
+            true => {
+                let mut progress = output::Progress::new();
+                let inserted = db.insert_dir_with(dir, |event| progress.event(&event));
+                progress.finish();
+                inserted
+            },
+            false => db.insert_dir_recursive(dir),
+        };
+        match inserted {
+            Ok(insertions) => output::print_insertions(&insertions, args.get_bool("--json"), quiet),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+        if let Some(run) = db.filtered_runs().get(runs).filter(|_| !quiet) {
+            eprintln!("{} file(s) of {} byte(s) excluded by {}", run.excluded, run.excluded_bytes.unwrap_or(0), run.filter.describe());
+        }
+    }
+
+    else if args.get_bool("--watch") {
+        let dir = args.get_str("<dir>");
+        let interval = Duration::from_secs(exit::arg::<u64>(&args, "--interval"));
+        let as_json = args.get_bool("--json");
+        let watched = watch::Watch::new(&db, dir).and_then(|mut watch| {
+            if !quiet {
+                eprintln!("watching {} every {} s, Ctrl-C to stop", dir, interval.as_secs());
+            }
+            watch.run(&mut db, interval, watch::stop_on_interrupt(), |insertion| output::print_insertion(insertion, as_json))
+        });
+        match watched {
+            Ok(_) if quiet => {},
+            Ok(summary) => eprintln!("{} scan(s): {} file(s) inserted, {} skipped, {} failed",
+                                     summary.scans, summary.inserted, summary.skipped, summary.failed),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
     }
 
     else if args.get_bool("--files") {
-        let files = args.get_str("<files_and_ids>");
-        let re = Regex::new(r"(\d{1,10}) ([\w:\\ \._]+)").unwrap();
+        // the lines that can not be read are told, the others inserted
+        for error in &file_list.errors {
+            style::error(error);
+        }
+        match db.insert_files(file_list.files.iter().map(|f| (f.0, f.1.as_str())).collect()) {
+            Ok(insertions) => output::print_insertions(&insertions, args.get_bool("--json"), quiet),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+        if !file_list.errors.is_empty() {
+            eprintln!("{} line(s) of the list not read", file_list.errors.len());
+            drop(db);
+            process::exit(exit::USAGE);
+        }
+    }
 
-        for cap in re.captures_iter(files) {
+    else if args.get_bool("--ingest-manifest") {
+        let manifest = args.get_str("<manifest>");
+        let result = match args.get_str("--result") {
+            "<manifest>.result" => format!("{}.result", manifest),
+            r => String::from(r),
+        };
+        let report = match binacle::manifest::ingest_manifest(&mut db, manifest, &result) {
+            Ok(report) => report,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
 
-            let id = cap[1].parse::<u32>().unwrap();
-            let file = &cap[2];
+        let mut counts = BTreeMap::new();
+        for r in &report {
+            *counts.entry(r.status.name()).or_insert(0) += 1;
+        }
+        for (name, nb) in &counts {
+            println!("{} {}", nb, name);
+        }
+        println!("status of each line in {}", result);
+    }
 
-            let res = db.insert_file(file, id, false);
-            if let Err(e) = res {
-                println!("{}:\"Error {}\"", id, e);
+    // before --queries, its file is replayed
+    else if args.get_bool("--warmup") {
+        let budget = match args.get_str("--seconds") {
+            "" => None,
+            _ => Some(Duration::from_secs(exit::arg::<u64>(&args, "--seconds"))),
+        };
+        let warmup = warmup::Warmup::new(budget);
+        let report = match args.get_bool("--queries") {
+            true => queries::read_queries(args.get_str("<file>")).and_then(|queries| {
+                let requests: Vec<SearchRequest> = queries.into_iter().map(|q| q.request).collect();
+                warmup.replay(&mut db, &requests)
+            }),
+            false => warmup.headers(&mut db),
+        };
+        match report {
+            Ok(report) => {
+                for w in &report.warnings {
+                    style::warning(format!("shard {} not warmed up: {}", w.shard, w.error));
+                }
+                println!("{}", report.describe());
+            },
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+        if args.get_bool("--notify") {
+            if let Err(e) = warmup::notify_ready() {
+                style::warning(format!("readiness not notified: {}", e));
             }
         }
     }
 
-    else if args.get_bool("-s") {
+    else if args.get_bool("--inetd") {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let answered = queries::answer_one(&mut db, stdin.lock(), &mut stdout.lock());
+        if args.get_bool("--timing") {
+            eprintln!("open to answer: {:.1} ms", opened.elapsed().as_secs_f64() * 1000.0);
+        }
+        // the error was the answer
+        if let Err(e) = answered {
+            drop(db);
+            process::exit(exit::code(&e));
+        }
+    }
+
+    else if args.get_bool("--serve") {
+        // the server reloads the database until the process is killed
+        let given = |option| match args.get_str(option) {
+            "" => None,
+            value => Some(String::from(value)),
+        };
+        let sandbox = Sandbox { user: given("--user"), group: given("--group"), chroot: given("--chroot") };
+        let cache = server::CacheConfig {
+            capacity: match args.get_str("--cache-ids") {
+                "" => server::CACHE_IDS,
+                _ => exit::arg::<u64>(&args, "--cache-ids"),
+            },
+            budget: match args.get_str("--request-budget") {
+                "" => server::REQUEST_BUDGET,
+                _ => exit::arg::<u64>(&args, "--request-budget"),
+            },
+            release_pages: args.get_bool("--release-pages"),
+        };
+        if let Err(e) = server::serve(server::Server::with_cache(db, cache), args.get_str("<addr>"), &sandbox) {
+            exit::fail(e);
+        }
+        return;
+    }
+
+    else if args.get_bool("--queries") {
+        if let Err(e) = run_queries(&mut db, args.get_str("<file>")) {
+            drop(db);
+            exit::fail(e);
+        }
+    }
 
-        let result_id = if args.get_bool("hex") {
-            let pattern = args.get_str("<string>").from_hex().unwrap();
-            db.search(&pattern).unwrap()
+    else if args.get_bool("--have") || args.get_bool("--have-hash") {
 
+        let sha256 = if args.get_bool("--have") {
+            let path = args.get_str("<file>");
+            hash::sha256_file(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
         } else {
-            let pattern = args.get_str("<string>").as_bytes();
-            db.search(pattern).unwrap()
-        };
-
-        if db.is_map() {
-            let res = db.to_map(&result_id).unwrap();
-            let mut nb = 0;
-            for f in &res {
-                if args.get_bool("exact") {
-                    let mut file = OpenOptions::new().read(true).open(f).unwrap();
-                    let mut content = Vec::new();
-                    file.read_to_end(&mut content).unwrap();
-                    let pattern = if args.get_bool("hex") {
-                        args.get_str("<string>").from_hex().unwrap()
-                    } else {
-                        Vec::from(args.get_str("<string>").as_bytes())
+            hash::parse_sha256(args.get_str("<sha256>"))
+        };
+        let sha256 = match sha256 {
+            Ok(sha256) => sha256,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+
+        let mut ids = db.find_by_hash(&sha256);
+        ids.sort();
+        for id in &ids {
+            match db.entry(*id) {
+                Some(entry) => {
+                    let date = entry.date.map(output::format_date).unwrap_or(String::from("-"));
+                    println!("{}\t{}\t{}", id, entry.path, date);
+                },
+                None => println!("{}", id),
+            }
+        }
+
+        drop(db);
+        process::exit(if ids.is_empty() { exit::NO_RESULTS } else { 0 });
+    }
+
+    else if args.get_bool("--id-of") {
+        match db.id_of_label(args.get_str("<label>")) {
+            Some(id) => println!("{}", id),
+            None => {
+                eprintln!("label not found");
+                drop(db);
+                process::exit(exit::NO_RESULTS);
+            },
+        }
+    }
+
+    else if args.get_bool("--info") {
+        let id = db.resolve_id(args.get_str("<id>"));
+        match id.and_then(|id| db.entry(id).map(|e| (id, e))) {
+            Some((id, entry)) => output::print_info(id, entry, db.is_tombstoned(id)),
+            None => {
+                eprintln!("id not found in map");
+                drop(db);
+                process::exit(exit::NO_RESULTS);
+            },
+        }
+    }
+
+    else if args.get_bool("--delete") {
+        let id = match db.resolve_id(args.get_str("<id>")) {
+            Some(id) => id,
+            None => {
+                drop(db);
+                exit::usage(format!("{} is neither an id nor a label", args.get_str("<id>")));
+            },
+        };
+        match db.delete(id) {
+            Ok(deletion) => {
+                let lists: u64 = deletion.lists.iter().sum();
+                println!("id {} deleted: dropped from {} list(s){}{}", id, lists,
+                         if deletion.from_file { " of the n-grams of its file" } else { ", all the lists read" },
+                         if deletion.mapped { ", removed from the map" } else { "" });
+            },
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--coverage-of") {
+        // an id, a label or a path of the map
+        let arg = args.get_str("<id>");
+        let id = db.resolve_id(arg).or_else(|| db.entries().find(|&(_, e)| e.path == arg).map(|(id, _)| id));
+        match id.map(|id| provenance::coverage_of(&db, id)) {
+            Some(Ok(coverage)) => {
+                output::print_coverage(&coverage, args.get_bool("--bar"));
+                if coverage.changed() {
+                    drop(db);
+                    process::exit(exit::DIFFER);
+                }
+            },
+            Some(Err(e)) => {
+                drop(db);
+                exit::fail(e);
+            },
+            None => {
+                drop(db);
+                exit::usage(format!("no id, label or path {}", arg));
+            },
+        }
+    }
+
+    else if args.get_bool("--note") {
+        let res = match db.resolve_id(args.get_str("<id>")) {
+            Some(id) => db.add_note(id, args.get_str("<text>")).and_then(|_| db.flush_map()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no id or label {}", args.get_str("<id>")))),
+        };
+        if let Err(e) = res {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    else if args.get_bool("--list") {
+        // the ids of a database without a map are all that is known
+        if !db.is_map() {
+            println!("no map: ids 0..{}, {} file(s)", db.last_id(), db.nb_file());
+            drop(db);
+            process::exit(0);
+        }
+
+        let substr = args.get_str("--note-contains");
+        let filter = args.get_str("--filter");
+        let wanted = |entry: &binacle_manager::MapEntry| {
+            entry.path.contains(filter) && (substr == "" || entry.has_note_containing(substr))
+        };
+        // the rows are printed as they are read, the ids as wide as the last one
+        let widths = [db.last_id().to_string().len(), 0];
+        let stdout = io::stdout();
+        let mut writer = io::BufWriter::new(stdout.lock());
+        let mut found = false;
+        let res = {
+            let mut print = |id: u32, entry: &binacle_manager::MapEntry| -> io::Result<()> {
+                if !wanted(entry) {
+                    return Ok(());
+                }
+                found = true;
+                try!(writeln!(writer, "{}", out.row(&[id.to_string(), entry.path.clone()], &widths, 1)));
+                if substr != "" {
+                    for note in entry.notes.iter().flat_map(|n| n.iter()).filter(|n| n.text.contains(substr)) {
+                        try!(writeln!(writer, "    note: {}", output::format_note(note)));
+                    }
+                }
+                Ok(())
+            };
+            match args.get_str("--id") {
+                "" => db.for_each_entry(print),
+                arg => match db.resolve_id(arg) {
+                    Some(id) => {
+                        let set: HashSet<u32> = [id].iter().cloned().collect();
+                        db.resolve(&set).and_then(|_| match db.entry(id) {
+                            Some(entry) => print(id, entry),
+                            None => Ok(()),
+                        })
+                    },
+                    None => Ok(()),
+                },
+            }
+        };
+        match res.and_then(|_| writer.flush()) {
+            // the reader of a pipe is gone, as head
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+            // an empty map is listed as such, a filter that keeps nothing is a lookup without results
+            Ok(_) if !found && (filter != "" || substr != "" || args.get_str("--id") != "") => {
+                drop(db);
+                process::exit(exit::NO_RESULTS);
+            },
+            Ok(_) => (),
+        }
+    }
+
+    // also an option of -s
+    else if args.get_bool("--verify-shards") && !args.get_bool("-s") {
+        let mut failed = 0;
+        for (n, check) in db.verify_shards().into_iter().enumerate() {
+            match check {
+                Ok(true) => println!("shard {}: ok", n),
+                Ok(false) => println!("shard {}: size ok, no checksum (not finalized)", n),
+                Err(e) => {
+                    println!("shard {}: {}", n, out.error(&format!("FAILED: {}", e)));
+                    failed += 1;
+                },
+            }
+        }
+        if failed > 0 {
+            eprintln!("{} shard(s) truncated or modified, copy them again or --reindex from a sound copy", failed);
+            drop(db);
+            process::exit(exit::IO);
+        }
+    }
+
+    else if args.get_bool("--check") {
+        let rebuild = args.get_bool("--rebuild-bitmap");
+        let (mut stale, mut failed) = (0, 0);
+        for (n, shard) in db.open_shards().into_iter().enumerate() {
+            let shard = match shard {
+                Ok(shard) => shard,
+                Err(e) => {
+                    println!("shard {}: {}", n, out.error(&e.to_string()));
+                    failed += 1;
+                    continue;
+                },
+            };
+            let lists = shard.check_lists();
+            if let Some(ref header) = lists.header {
+                // the slots of the header can not be read
+                println!("shard {}: {}", n, out.error(header));
+                failed += 1;
+                continue;
+            }
+            match lists.bad_lists {
+                0 => println!("shard {}: {} list(s) of {} block(s), all sound", n, lists.lists, lists.blocks),
+                bad => {
+                    println!("shard {}: {}", n, out.error(&format!("{} bad list(s) of {}, {} block(s) read", bad, lists.lists, lists.blocks)));
+                    for problem in &lists.problems {
+                        println!("shard {}:   {}", n, problem);
+                    }
+                    if bad as usize > lists.problems.len() {
+                        println!("shard {}:   and {} more", n, bad as usize - lists.problems.len());
+                    }
+                    failed += 1;
+                },
+            }
+            let check = shard.check_bitmap();
+            match check {
+                BitmapCheck::InSync { slots } => println!("shard {}: occupancy bitmap in sync, {} of {} slot(s) set", n, slots, shard.header_slots()),
+                BitmapCheck::Missing => println!("shard {}: no occupancy bitmap, the scans read the whole header", n),
+                BitmapCheck::Stale => println!("shard {}: occupancy bitmap out of sync with the shard, not used", n),
+                BitmapCheck::Lacking(lists) => println!("shard {}: {}", n, out.error(&format!(
+                    "occupancy bitmap lacks {} slot(s) having a list, the scans miss them", lists))),
+            }
+            match check {
+                BitmapCheck::InSync { .. } => {},
+                _ if rebuild => match shard.rebuild_bitmap() {
+                    Ok(slots) => println!("shard {}: occupancy bitmap rebuilt, {} slot(s) set", n, slots),
+                    Err(e) => {
+                        println!("shard {}: {}", n, out.error(&format!("bitmap not rebuilt: {}", e)));
+                        failed += 1;
+                    },
+                },
+                BitmapCheck::Lacking(_) => failed += 1,
+                _ => stale += 1,
+            }
+        }
+        if stale > 0 {
+            style::warning_line(format!("WARNING: {} shard(s) scanned without an occupancy bitmap: run --check --rebuild-bitmap", stale));
+        }
+        if failed > 0 {
+            drop(db);
+            process::exit(exit::IO);
+        }
+    }
+
+    // the statistics shared, before the other ones
+    else if args.get_bool("--shareable") {
+        let floor = exit::arg::<u64>(&args, "--k-floor");
+        let bucket = exit::arg::<u64>(&args, "--bucket");
+        let exported = shareable::ShareParams::new(floor, bucket, args.get_bool("--salt-top")).and_then(|params| {
+            shareable::collect(&mut db).map(|stats| shareable::shareable(&stats, &params, &shareable::draw_salt()))
+        });
+        match exported {
+            Ok(export) => println!("{}", export),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--stats") && args.get_bool("--json") {
+        let threshold = exit::arg::<u32>(&args, "--rechain-threshold");
+        let shards = stats::shard_stats(&mut db, threshold);
+        println!("{}", stats::to_json(&db, &shards));
+    }
+
+    else if args.get_bool("--stats") {
+        let threshold = exit::arg::<u32>(&args, "--rechain-threshold");
+        let shards = stats::shard_stats(&mut db, threshold);
+        let translation = db.translation().map(|t| t.describe()).unwrap_or(String::from("none"));
+        let pairs = [
+            ("map", String::from(if db.is_map() { "yes" } else { "no" })),
+            ("sealed", String::from(if db.is_sealed() { "yes" } else { "no" })),
+            ("ngram_size", db.ngram_size().to_string()),
+            ("alignment", db.alignment().to_string()),
+            ("offset_size", db.offset_size().to_string()),
+            ("max_index_size", db.max_index_size().to_string()),
+            ("max_addressable_size", db.max_addressable_size().to_string()),
+            ("header_size", db.header_size().to_string()),
+            ("min_reliable_pattern_len", db.min_reliable_pattern_len().to_string()),
+            ("nb_file", db.nb_file().to_string()),
+            ("last_id", db.last_id().to_string()),
+            ("nb_index", db.nb_index().to_string()),
+            ("durability", db.durability().to_string()),
+            ("translation", translation),
+            ("quarantined", db.quarantine().len().to_string()),
+        ];
+        for line in out.pairs(&pairs) {
+            println!("{}", line);
+        }
+        let collections = match Collections::open(&db.db_path) {
+            Ok(collections) => collections,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+        for (name, count) in collections.list() {
+            println!("collection {}: {} id(s)", name, count);
+        }
+        let mut over = 0;
+        for (n, shard) in shards.iter().enumerate() {
+            match *shard {
+                Ok(ref shard) => {
+                    let chains = &shard.chains;
+                    println!("shard {}: ok{}, {} bytes on disk, {} used, {} list(s), header {:.2}% full, \
+                              chain length mean {:.2} p50 {} p95 {} max {}, {}",
+                             n, if shard.finalized { ", finalized" } else { "" }, shard.file_size, shard.size,
+                             shard.lists, shard.header_fill * 100.0, chains.mean, chains.p50, chains.p95, chains.max,
+                             occupancy::describe_skipped(shard.header_skipped, shard.header_slots));
+                    if chains.needs_compaction(threshold) {
+                        over += chains.over;
+                    }
+                },
+                Err(ref e) => println!("shard {}: {}", n, out.error(&e.to_string())),
+            }
+        }
+        if over > 0 {
+            style::warning_line(format!("WARNING: the chains of some shards are long (p95 over {}), the searches are slowed down: \
+                       run --compact-ngrams {}", threshold, over));
+        }
+    }
+
+    else if args.get_bool("--compact-ngrams") {
+        let top = exit::arg::<usize>(&args, "<top_n>");
+        for n in 0 .. db.nb_index() {
+            if let Err(e) = db.check_space() {
+                style::error(format!("{}, shard {} and the next ones not compacted", e, n));
+                drop(db);
+                process::exit(exit::code(&e));
+            }
+            let compacted = db.open_shard_write(n).and_then(|mut shard| {
+                let report = try!(chains::compact_ngrams(&mut shard, top));
+                // a full shard keeps its size, the blocks come from the end of the file
+                if db.shard_is_full(n) {
+                    try!(shard.fix_size());
+                }
+                try!(shard.flush());
+                Ok(report)
+            });
+            let compacted = compacted.and_then(|report| match db.shard_is_full(n) {
+                true => db.record_checksum(n).map(|_| report),
+                false => Ok(report),
+            });
+            let report = match compacted {
+                Ok(report) => report,
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            };
+            println!("shard {}: {} n-gram(s) compacted, longest chain was {}, {} block(s) freed, {}",
+                     n, report.ngrams, report.longest_before, report.blocks_freed,
+                     occupancy::describe_skipped(report.header_skipped, 1u64 << db.ngram_size()));
+        }
+    }
+
+    else if args.get_bool("--compact") {
+        let mut total = Compacted::default();
+        let compacted = db.compact_shards(|n, report| {
+            println!("shard {}: {} list(s) in {} block(s), {} before, {} byte(s), {} reclaimed",
+                     n, report.lists, report.blocks_after, report.blocks_before, report.size_after, report.reclaimed());
+            total.size_before += report.size_before;
+            total.size_after += report.size_after;
+        });
+        println!("{} byte(s) reclaimed, {} to {}", total.reclaimed(), total.size_before, total.size_after);
+        if let Err(e) = compacted {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    else if args.get_bool("--merge") {
+        let src = args.get_str("<src_db>");
+        match db.merge_from(src, exit::arg::<u32>(&args, "--id-offset")) {
+            Ok(merge) => match merge.ids {
+                Some((first, last)) => println!("{}: {} file(s) and {} shard(s) merged, ids {} to {}",
+                                                src, merge.files, merge.shards, first, last),
+                None => println!("{}: {} shard(s) merged, no file", src, merge.shards),
+            },
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--finalize") {
+        let shard = exit::arg::<usize>(&args, "<shard>");
+        if shard >= db.nb_index() {
+            style::error(format!("no shard {}, the database has {}", shard, db.nb_index()));
+            drop(db);
+            process::exit(exit::USAGE);
+        }
+        match db.finalize_shard(shard) {
+            Ok(summary) => println!("shard {}: finalized, {} n-gram(s) compacted, {} list(s), chain length mean {:.2} p95 {} max {}",
+                                    shard, summary.compacted, summary.lists, summary.chain_mean, summary.chain_p95, summary.chain_max),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--evaluate") {
+        let report = match corpus::Truth::read(args.get_str("<truth>")).and_then(|truth| corpus::evaluate(&mut db, &truth)) {
+            Ok(report) => report,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+
+        let mut complete = true;
+        for e in &report {
+            println!("{}\trecall {}/{} ({:.1}%)\t{} false positive(s)",
+                     e.pattern, e.found, e.expected, 100.0 * e.recall(), e.false_positives);
+            for path in &e.missed {
+                println!("    missed: {}", path);
+            }
+            complete &= e.missed.is_empty();
+        }
+        if !complete {
+            drop(db);
+            process::exit(exit::DIFFER);
+        }
+    }
+
+    else if args.get_bool("--why") {
+        let id = exit::arg::<u32>(&args, "<id>");
+        let pattern = match parse_pattern(&args, args.get_str("<string>")) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+        match provenance::explain_miss(&mut db, id, &pattern) {
+            Ok(None) => println!("found by the index"),
+            Ok(Some(reason)) => println!("{}", reason),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--collection") {
+        if let Err(e) = collection_command(&args, &mut db) {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    else if args.get_bool("--reindex") {
+        let ngram_size = match args.get_str("--ngram-size") {
+            "" => db.ngram_size(),
+            _ => exit::arg::<u8>(&args, "--ngram-size"),
+        };
+        let alignment = match args.get_str("--alignment") {
+            "" => db.alignment(),
+            _ => exit::arg::<u8>(&args, "--alignment"),
+        };
+        let dst = args.get_str("<dst_db>");
+        if let Err(e) = reindex::reindex(&mut db, dst, ngram_size, alignment, |done, total| {
+            eprintln!("shard {}/{} rebuilt", done, total);
+        }) {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    else if args.get_bool("--verify-reindex") {
+        let mut dst = match binacle_manager::BinacleManager::open(args.get_str("<dst_db>")) {
+            Ok(dst) => dst,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+        let samples = match args.get_str("--sample") {
+            "" => 1000,
+            _ => exit::arg::<usize>(&args, "--sample"),
+        };
+        let seed = match args.get_str("--seed") {
+            "" => 0,
+            _ => exit::arg::<u64>(&args, "--seed"),
+        };
+        let report = match reindex::verify(&mut db, &mut dst, samples, seed) {
+            Ok(report) => report,
+            Err(e) => {
+                drop(dst);
+                drop(db);
+                exit::fail(e);
+            },
+        };
+        for ngram in &report.mismatches {
+            println!("mismatch: n-gram {:08x}", ngram);
+        }
+        println!("{} n-gram(s) compared, {} mismatch(es)", report.samples, report.mismatches.len());
+        if !report.mismatches.is_empty() {
+            drop(dst);
+            drop(db);
+            process::exit(exit::DIFFER);
+        }
+    }
+
+    else if args.get_bool("--fingerprint") {
+        let fp = match fingerprint::fingerprint(&mut db) {
+            Ok(fp) => fp,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+        println!("{}", hash::to_hex(&fp));
+    }
+
+    else if args.get_bool("--dump-postings") {
+        let format = postings::DumpFormat::parse(args.get_str("--format"));
+        let min_df = match args.get_str("--min-df") {
+            "" => 0,
+            _ => exit::arg::<u32>(&args, "--min-df"),
+        };
+        let stdout = io::stdout();
+        let dumped = format.and_then(|format| postings::dump(&mut db, io::BufWriter::new(stdout.lock()), format, min_df));
+        match dumped {
+            Ok(report) => {
+                for w in &report.warnings {
+                    style::warning(format!("shard {} not dumped: {}", w.shard, w.error));
+                }
+                eprintln!("{} list(s) dumped, {} of {} header slot(s) skipped{}", report.lists, report.header_skipped, report.header_slots,
+                          match report.without_bitmap {
+                              0 => String::new(),
+                              n => format!(", {} shard(s) without an occupancy bitmap read whole", n),
+                          });
+            },
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--audit-ids") {
+
+        let report = match audit::audit_ids(&mut db) {
+            Ok(report) => report,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+
+        let mut counts = BTreeMap::new();
+        for entry in &report {
+            let detail = match entry.issue {
+                audit::AuditIssue::Superseded(by) => format!("by {}", by),
+                _ => String::from("-"),
+            };
+            println!("{}\t{}\t{}\t{}\t{}", entry.id, entry.issue.name(),
+                     entry.path.as_ref().map(|p| p.as_str()).unwrap_or("-"), detail, entry.issue.action());
+            *counts.entry(entry.issue.name()).or_insert(0) += 1;
+        }
+
+        for (name, nb) in &counts {
+            println!("{} {}", nb, name);
+        }
+
+        if args.get_bool("--apply") {
+            if let Err(e) = audit::apply(&mut db, &report) {
+                drop(db);
+                exit::fail(e);
+            }
+            println!("{} id(s) fixed", report.len());
+        } else if !report.is_empty() {
+            println!("dry run, use --apply to perform the actions");
+        }
+    }
+
+    else if args.get_bool("--rebuild-map") {
+        match map_rebuild::rebuild_map(&mut db) {
+            Ok(report) => {
+                for change in &report.changes {
+                    println!("{}", change.describe());
+                }
+                println!("{} change(s), {} entries written", report.changes.len(), report.entries);
+                if report.changes.iter().any(|c| match *c { map_rebuild::Change::Canonical { .. } => false, _ => true }) {
+                    println!("entries dropped archived in {}", map_rebuild::archive_path(&db).display());
+                }
+            },
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--seal") {
+        match db.seal() {
+            Ok(()) => println!("{} sealed: {} shard(s) finalized and checksummed, read only until --unseal", db.db_path, db.nb_index()),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--unseal") {
+        if let Err(e) = db.unseal() {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    // before -s, the search of the line is saved and not run
+    else if args.get_bool("--save-query") {
+        let name = args.get_str("<name>");
+        let saved = saved_query(&args).and_then(|saved| {
+            let mut library = try!(Library::open(&db.db_path));
+            let line = saved.query.clone();
+            let replaced = try!(library.insert(name, saved)).is_some();
+            try!(library.save());
+            Ok((line, replaced))
+        });
+        match saved {
+            Ok((line, replaced)) => println!("query {} {}: {}", name, if replaced { "replaced" } else { "saved" }, line),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--run-query") {
+        match run_saved_query(&mut db, args.get_str("<name>"), &out) {
+            Ok(0) => {
+                drop(db);
+                process::exit(exit::NO_RESULTS);
+            },
+            Ok(_) => (),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--run-all-queries") {
+        let tag = match args.get_str("--tag") {
+            "" => None,
+            tag => Some(tag),
+        };
+        if let Err(e) = run_all_saved_queries(&mut db, tag) {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    else if args.get_bool("--yara") {
+        if let Err(e) = prefilter_yara(&mut db, args.get_str("<rules>")) {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    else if args.get_bool("--list-queries") {
+        match Library::open(&db.db_path) {
+            Ok(library) => {
+                let rows: Vec<Vec<String>> = library.list(None).iter().map(|&(name, query)| {
+                    let tags = match query.tags.is_empty() {
+                        true => String::from("-"),
+                        false => query.tags.join(","),
                     };
-                    if twoway::find_bytes(&content, &pattern) != None {
-                        println!("{}", f);
-                        nb += 1;
+                    let last = query.last_run.map(|r| format!("{} on {}", r.hits, output::format_date(r.date)));
+                    vec![String::from(name), tags, last.unwrap_or(String::from("-")), query.query.clone()]
+                }).collect();
+                for line in out.table(&rows, 3) {
+                    println!("{}", line);
+                }
+            },
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+    }
+
+    else if args.get_bool("--delete-query") {
+        let deleted = Library::open(&db.db_path).and_then(|mut library| {
+            try!(library.remove(args.get_str("<name>")));
+            library.save()
+        });
+        if let Err(e) = deleted {
+            drop(db);
+            exit::fail(e);
+        }
+    }
+
+    else if args.get_bool("-s") && args.get_bool("--any") {
+
+        // a list of patterns, one a line: each id found with the indexes of its patterns
+        let content = match args.get_str("--search-file") {
+            "" => Ok(args.get_str("<string>").as_bytes().to_vec()),
+            path => std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e))),
+        };
+        let found = content.and_then(|c| pattern_file::pattern_list(&c, args.get_bool("hex")))
+                           .and_then(|patterns| db.search_any(&patterns));
+        match found {
+            Ok(found) => output::print_any(&found),
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        }
+        if !db.shard_warnings().is_empty() {
+            for warning in db.shard_warnings() {
+                eprintln!("{}", output::shard_warning_note(warning));
+            }
+            drop(db);
+            process::exit(exit::PARTIAL);
+        }
+    }
+    else if args.get_bool("-s") {
+
+        // the offsets of the ?? of a hex pattern, any byte matches there
+        let mut wildcards = Vec::new();
+        let pattern = match args.get_str("--search-file") {
+            "" if args.get_bool("hex") => match variants::parse_masked(args.get_str("<string>")) {
+                Ok((bytes, masked)) => {
+                    wildcards = masked;
+                    bytes
+                },
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            },
+            "" => match parse_pattern(&args, args.get_str("<string>")) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            },
+            path => {
+                let options = pattern_file::TextOptions {
+                    keep_line_endings: args.get_bool("--keep-line-endings"),
+                    binary_ok: args.get_bool("--binary-ok"),
+                    hex: args.get_bool("hex"),
+                    max_bytes: match args.get_str("--max-bytes") {
+                        "" => None,
+                        _ => Some(exit::arg::<u64>(&args, "--max-bytes")),
+                    },
+                };
+                match pattern_file::read_pattern(path, options) {
+                    Ok(normalized) => {
+                        for warning in &normalized.warnings {
+                            style::warning(warning);
+                        }
+                        if args.get_bool("--explain") {
+                            for note in &normalized.notes {
+                                eprintln!("{}: {}", path, note);
+                            }
+                            eprintln!("{}: searching {} byte(s): {}", path, normalized.bytes.len(), normalized.bytes.to_hex());
+                        }
+                        normalized.bytes
+                    },
+                    Err(e) => {
+                        drop(db);
+                        exit::fail(e);
+                    },
+                }
+            },
+        };
+
+        // a list of indicators pasted as one argument, one pattern a line
+        let lines = match args.get_str("--search-file") == "" && !args.get_bool("hex") && !args.get_bool("--literal-newlines") {
+            true => pattern_file::split_lines(&pattern),
+            false => None,
+        };
+        let patterns = match lines {
+            Some(ref lines) if lines.is_empty() => {
+                drop(db);
+                exit::usage("the pattern only has blank lines");
+            },
+            Some(lines) => {
+                if lines.len() > 1 {
+                    eprintln!("notice: the pattern has {} lines, each one is searched and a file with any of them is a result \
+                               (--literal-newlines to search the line breaks)", lines.len());
+                } else {
+                    eprintln!("notice: the line breaks around the pattern are not searched (--literal-newlines to search them)");
+                }
+                lines
+            },
+            None => vec![pattern],
+        };
+        if patterns.len() > 1 && (args.get_str("--why-not") != "" || args.get_str("--context") != "") {
+            drop(db);
+            exit::usage(format!("--why-not and --context take a single pattern, not {} lines", patterns.len()));
+        }
+        if !wildcards.is_empty() && (args.get_str("--why-not") != "" || args.get_str("--context") != "") {
+            drop(db);
+            exit::usage("--why-not and --context take a pattern without ?? wildcards");
+        }
+        if args.get_bool("--both") && args.get_str("--context") != "" {
+            drop(db);
+            exit::usage("--context takes a single form of the pattern, --wide or not, not --both");
+        }
+        // the bytes of the files, for --why-not and --context
+        let pattern = match args.get_bool("--wide") {
+            true => variants::to_wide(&patterns[0]),
+            false => patterns[0].clone(),
+        };
+
+        if args.get_str("--why-not") != "" {
+            let id = match db.resolve_id(args.get_str("--why-not")) {
+                Some(id) => id,
+                None => {
+                    // a file left out of the database by the filters of --rec
+                    if let Some(reason) = ingest_filter::why_excluded(&db, args.get_str("--why-not")) {
+                        println!("{}", reason);
+                        return;
                     }
+                    drop(db);
+                    exit::usage(format!("no id or label {}", args.get_str("--why-not")));
+                },
+            };
+            match provenance::why_not(&mut db, id, &pattern) {
+                Ok(report) => output::print_why_not(id, &report),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+            return;
+        }
+
+        let mut request = SearchRequest::any_of(&patterns);
+        for offset in &wildcards {
+            request = request.wildcard(*offset);
+        }
+
+        if args.get_bool("exact") {
+            request = request.exact();
+        }
+        if args.get_bool("--verify") {
+            request = request.verify();
+        }
+        if args.get_bool("--wide") {
+            request = request.wide();
+        }
+        if args.get_bool("--both") {
+            request = request.both_widths();
+        }
+        for pattern in args.get_vec("--and") {
+            match parse_pattern(&args, pattern) {
+                Ok(bytes) => request = request.and(&bytes),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        }
+        if args.get_str("--exclude") != "" {
+            match parse_pattern(&args, args.get_str("--exclude")) {
+                Ok(bytes) => request = request.exclude(&bytes),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        }
+        match args.get_str("--endian") {
+            "" => {},
+            "both" => match ByteSwap::parse(args.get_str("--endian-unit")) {
+                Ok(swap) => request = request.both_endian(swap),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            },
+            other => {
+                drop(db);
+                exit::fail(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid --endian value: {}, the order is both", other)));
+            },
+        }
+        for spec in args.get_vec("--variant") {
+            match variants::parse_alternative(spec) {
+                Ok((offset, values)) => request = request.alternative(offset, &values),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        }
+        request = request.variant_cap(exit::arg::<usize>(&args, "--variant-cap"));
+        db.set_preselect(exit::arg::<usize>(&args, "--preselect"));
+        db.set_verify_hashes(args.get_bool("--verify-shards"));
+        if args.get_str("--tag") != "" {
+            request = request.tag(args.get_str("--tag"));
+        }
+        if args.get_str("--path-match") != "" {
+            request = match request.path_match(args.get_str("--path-match")) {
+                Ok(r) => r,
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            };
+        }
+        if args.get_str("--in-collection") != "" {
+            let ids = Collections::open(&db.db_path)
+                          .and_then(|collections| collections.get(args.get_str("--in-collection")).map(|ids| ids.clone()));
+            match ids {
+                Ok(ids) => request = request.within(ids),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        }
+        if args.get_str("--path-contains") != "" {
+            request = request.path_contains(args.get_str("--path-contains"));
+        }
+        if args.get_str("--type") != "" {
+            for t in args.get_str("--type").split(',') {
+                request = request.file_type(t);
+            }
+        }
+        if args.get_str("--id-range") != "" {
+            let range = args.get_str("--id-range");
+            let mut bounds = range.splitn(2, '-').map(|x| x.parse::<u32>().ok());
+            match (bounds.next().and_then(|min| min), bounds.next().and_then(|max| max)) {
+                (Some(min), Some(max)) => request = request.id_range(min..=max),
+                _ => {
+                    drop(db);
+                    exit::usage(format!("invalid --id-range: {:?} (<min>-<max> expected)", range));
+                },
+            }
+        }
+        if args.get_str("--limit") != "" {
+            request = request.limit(exit::arg::<usize>(&args, "--limit"));
+        }
+        if args.get_str("--sample") != "" {
+            request = request.sample(exit::arg::<usize>(&args, "--sample"));
+        }
+        if args.get_str("--seed") != "" {
+            request = request.seed(exit::arg::<u64>(&args, "--seed"));
+        }
+        if args.get_str("--deadline-ms") != "" {
+            let ms = exit::arg::<u64>(&args, "--deadline-ms");
+            request = request.deadline(Duration::from_millis(ms));
+        }
+        if args.get_bool("--explain") {
+            request = request.explain();
+        }
+        if args.get_bool("--strict") {
+            request = request.strict();
+        }
+
+        let response = match db.run(&request) {
+            Ok(r) => r,
+            Err(e) => {
+                drop(db);
+                exit::fail(e);
+            },
+        };
+
+        if let Some(ref warning) = response.variant_warning {
+            style::warning(warning);
+        }
+
+        if args.get_str("--save-as") != "" {
+            let name = args.get_str("--save-as");
+            let saved = Collections::open(&db.db_path).and_then(|mut collections| {
+                if collections.get(name).is_err() {
+                    try!(collections.create(name));
+                }
+                let added = try!(collections.add(name, response.ids.iter().cloned()));
+                collections.save().map(|_| added)
+            });
+            match saved {
+                Ok(added) => eprintln!("{} id(s) added to {}", added, name),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        }
+
+        if let Some(ref trace) = response.explain {
+            for step in trace {
+                eprintln!("{}", step);
+            }
+        }
+
+        if args.get_str("--output") != "" {
+            let path = args.get_str("--output");
+            let query = sink::QueryRecord::new(args.get_str("<string>"), env::args().skip(2).collect());
+            let written = sink::open(path, args.get_str("--format")).and_then(|mut out| {
+                let n = try!(sink::write_response(&db, &response, &mut *out, &query));
+                out.finish().map(|_| n)
+            });
+            match written {
+                Ok(n) => eprintln!("{} result(s) written to {}", n, path),
+                Err(e) => {
+                    style::error(format!("{}: {}", path, e));
+                    drop(db);
+                    process::exit(exit::code(&e));
+                },
+            }
+        } else if args.get_str("--aggregate") != "" {
+            let counts = aggregate::Aggregation::parse(args.get_str("--aggregate"))
+                            .and_then(|by| aggregate::count_by(&db, &response.ids, by));
+            match counts {
+                Ok(counts) => output::print_counts(&counts, args.get_bool("--json"), &response),
+                Err(e) => {
+                    drop(db);
+                    exit::fail(e);
+                },
+            }
+        } else if db.is_map() && args.get_bool("--labels") {
+            let labels: Vec<String> = response.ids.iter().map(|id| {
+                match db.entry(*id).and_then(|e| e.label.clone()) {
+                    Some(label) => label,
+                    None => id.to_string(),
+                }
+            }).collect();
+            if args.get_bool("--json") {
+                println!("{}", json::encode(&labels).unwrap());
+            } else {
+                for l in &labels {
+                    println!("{}", l);
+                }
+                println!("{} result(s)", response.total);
+            }
+        } else if db.is_map() {
+            let hits = response.entries.iter().map(|x| x.as_str());
+
+            if args.get_str("--context") != "" {
+                let n = exit::arg::<usize>(&args, "--context");
+                let mut contexts = Vec::with_capacity(response.entries.len());
+                for path in hits {
+                    let read = context::find_offsets(path, &pattern)
+                                   .and_then(|offsets| context::read_contexts(path, &offsets, pattern.len(), n));
+                    match read {
+                        Ok(read) => contexts.push((path, read)),
+                        Err(e) => {
+                            drop(db);
+                            exit::fail(io::Error::new(e.kind(), format!("{}: {}", path, e)));
+                        },
+                    }
+                }
+                output::print_contexts(&contexts, args.get_bool("--json"), &out);
+            } else if args.get_str("--group-by-dir") != "" {
+                let depth = exit::arg::<usize>(&args, "--group-by-dir");
+                let show_files = args.get_bool("--show-files");
+                let groups = output::group_by_dir(hits, depth, show_files);
+                output::print_groups(&groups, show_files, args.get_bool("--json"), &response);
+            } else if args.get_bool("--json") && (args.get_str("--endian") != "" || !response.variants.is_empty()) {
+                output::print_orientations(&response, true);
+            } else if args.get_bool("--json") {
+                println!("{}", output::hits_json(&response));
+            } else {
+                // the id and the size first on a terminal
+                let show_type = args.get_bool("--show-type");
+                let path_column = (if out.aligned { 2 } else { 0 }) + (if show_type { 1 } else { 0 });
+                let rows: Vec<Vec<String>> = response.ids.iter().zip(hits).map(|(id, f)| {
+                    let mut row = Vec::new();
+                    if out.aligned {
+                        let size = db.entry(*id).and_then(|e| e.provenance.as_ref()).map(|p| p.size.to_string());
+                        row.push(id.to_string());
+                        row.push(size.unwrap_or(String::from("-")));
+                    }
+                    if show_type {
+                        let file_type = db.entry(*id).and_then(|e| e.file_type.clone());
+                        row.push(file_type.unwrap_or(String::from("-")));
+                    }
+                    row.push(String::from(f));
+                    if let (false, Some(v)) = (show_type, response.variants.get(id)) {
+                        row.push(v.join(" | "));
+                    }
+                    row
+                }).collect();
+                for (line, id) in out.table(&rows, path_column).into_iter().zip(&response.ids) {
+                    println!("{}", line);
+                    if args.get_bool("--show-notes") {
+                        for note in db.entry(*id).and_then(|e| e.notes.as_ref()).iter().flat_map(|n| n.iter()) {
+                            println!("    note: {}", output::format_note(note));
+                        }
+                    }
+                }
+                if response.truncated && args.get_str("--sample") != "" {
+                    println!("{} result(s), {} sampled", response.total, response.ids.len());
+                } else if response.truncated {
+                    println!("{} result(s), first {} shown", response.total, response.ids.len());
                 } else {
-                    println!("{}", f);
-                    nb += 1;
+                    println!("{} result(s)", response.total);
                 }
             }
-            println!("{} result(s)", nb);
+        } else if args.get_str("--endian") != "" || !response.variants.is_empty() {
+            output::print_orientations(&response, false);
+        } else if args.get_bool("--json") {
+            println!("{}", output::hits_json(&response));
         } else {
-            println!("{}", json::encode(&result_id).unwrap());    
+            println!("{}", json::encode(&response.ids).unwrap());
+        }
+
+        if let Some(n) = response.unfiltered {
+            let note = format!("{} match(es) before the path filter", n);
+            if args.get_bool("--json") {
+                eprintln!("{}", note);
+            } else {
+                println!("{}", note);
+            }
+        }
+
+        // JSON arrays have no room for it, the note goes to stderr
+        if response.partial {
+            let note = output::partial_note(&response.skipped_shards);
+            if args.get_bool("--json") || !db.is_map() {
+                eprintln!("{}", note);
+            } else {
+                println!("{}", note);
+            }
+        }
+        for warning in &response.warnings {
+            let note = output::shard_warning_note(warning);
+            if args.get_bool("--json") || !db.is_map() {
+                eprintln!("{}", note);
+            } else {
+                println!("{}", note);
+            }
+        }
+        for &(shard, ref snapshot) in &response.dirty_shards {
+            let note = dirty::describe(shard, snapshot);
+            if args.get_bool("--json") || !db.is_map() {
+                eprintln!("{}", note);
+            } else {
+                println!("{}", note);
+            }
+        }
+        // the results are the verified ones, the others are told apart
+        if args.get_bool("--verify") {
+            let mut notes = vec![format!("{} false positive(s) filtered by --verify", response.false_positives)];
+            for &(id, ref error) in &response.unverifiable {
+                notes.push(format!("unverifiable: id {}: {}", id, error));
+            }
+            for note in &notes {
+                if args.get_bool("--json") {
+                    eprintln!("{}", note);
+                } else {
+                    println!("{}", note);
+                }
+            }
+        }
+
+        if args.get_str("--exec") != "" {
+            let query = match args.get_str("--search-file") {
+                "" => args.get_str("<string>"),
+                path => path,
+            };
+            let hook = exec::Hook::new(args.get_str("--exec"), query).map(|hook| {
+                let mut hook = hook.jobs(exit::arg::<usize>(&args, "--exec-jobs"));
+                if args.get_str("--exec-timeout-ms") != "" {
+                    hook = hook.timeout(Duration::from_millis(exit::arg::<u64>(&args, "--exec-timeout-ms")));
+                }
+                if args.get_bool("--exec-batch") {
+                    hook = hook.batch();
+                }
+                if args.get_bool("--exec-shell") {
+                    hook = hook.shell();
+                }
+                hook
+            });
+            match hook {
+                Ok(hook) => output::print_exec_summary(&hook.run(&exec::hits(&db, &response.ids))),
+                Err(e) => {
+                    style::error(format!("--exec: {}", e));
+                    drop(db);
+                    process::exit(exit::code(&e));
+                },
+            }
+        }
+
+        if args.get_str("--copy-to") != "" {
+            let mode = match (args.get_bool("--hardlink"), args.get_bool("--symlink")) {
+                (true, true) => {
+                    drop(db);
+                    exit::usage("--hardlink and --symlink can not be both given");
+                },
+                (true, false) => materialize::Mode::Hardlink,
+                (false, true) => materialize::Mode::Symlink,
+                (false, false) => materialize::Mode::Copy,
+            };
+            if !db.is_map() {
+                drop(db);
+                exit::usage("--copy-to needs a map database, the paths of the results");
+            }
+            let query = match args.get_str("--search-file") {
+                "" => args.get_str("<string>"),
+                path => path,
+            };
+            let max_bytes = match args.get_str("--max-copy-bytes") {
+                "" => None,
+                _ => Some(exit::arg::<u64>(&args, "--max-copy-bytes")),
+            };
+            match materialize::copy_hits(&exec::hits(&db, &response.ids), args.get_str("--copy-to"), mode, max_bytes, query) {
+                Ok(report) => output::print_copy_report(&report),
+                Err(e) => {
+                    style::error(format!("--copy-to: {}", e));
+                    drop(db);
+                    process::exit(exit::code(&e));
+                },
+            }
+        }
+
+        if !response.warnings.is_empty() || !response.unverifiable.is_empty() {
+            drop(db);
+            process::exit(exit::PARTIAL);
+        }
+        found = response.total > 0;
+    }
+
+    if inserting && db.quarantine().len() > quarantined {
+        eprintln!("{} file(s) quarantined, skipped by the next runs unless --retry-quarantined",
+                  db.quarantine().len() - quarantined);
+    }
+
+    // the Drop could only print why the meta was not written
+    if let Err(e) = db.close() {
+        exit::fail(e);
+    }
+    if !found {
+        process::exit(exit::NO_RESULTS);
+    }
+}
+
+// create, add, list, show, remove, and the bulk actions on the ids of
+// a collection: delete, tag, export
+fn collection_command(args: &ArgvMap, db: &mut binacle_manager::BinacleManager) -> std::io::Result<()> {
+
+    let mut collections = try!(Collections::open(&db.db_path));
+    let name = args.get_str("<name>");
+    let action = args.get_str("<action>");
+
+    if action != "list" && name == "" {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: a collection name is needed", action)));
+    }
+
+    match action {
+        "create" => {
+            try!(collections.create(name));
+            try!(collections.save());
+        },
+        "add" => {
+            let mut ids = Vec::new();
+            for id in args.get_str("--ids").split(',').filter(|x| !x.is_empty()) {
+                ids.push(try!(id.parse::<u32>().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid id: {}", id))
+                })));
+            }
+            if args.get_str("--from-query") != "" {
+                let pattern = try!(parse_pattern(args, args.get_str("--from-query")));
+                ids.extend(try!(db.run(&SearchRequest::pattern(&pattern))).ids);
+            }
+            let added = try!(collections.add(name, ids));
+            try!(collections.save());
+            println!("{} id(s) added", added);
+        },
+        "list" => {
+            for (name, count) in collections.list() {
+                println!("{}\t{}", count, name);
+            }
+        },
+        "show" => {
+            for id in try!(collections.get(name)) {
+                match db.path_of(*id) {
+                    Some(path) => println!("{}\t{}", id, path),
+                    None => println!("{}", id),
+                }
+            }
+        },
+        "remove" => {
+            try!(collections.remove(name));
+            try!(collections.save());
+        },
+        "delete" => {
+            let ids = try!(collections.get(name)).clone();
+            for id in &ids {
+                db.tombstone(*id);
+            }
+            println!("{} id(s) deleted", ids.len());
+        },
+        "tag" => {
+            let tag = args.get_str("--tag");
+            if tag == "" {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "tag: --tag is needed"));
+            }
+            for id in try!(collections.get(name)).clone() {
+                let mut tags = db.entry(id).and_then(|e| e.tags.clone()).unwrap_or(Vec::new());
+                if !tags.iter().any(|t| t == tag) {
+                    tags.push(String::from(tag));
+                    try!(db.set_tags(id, tags));
+                }
+            }
+            try!(db.flush_map());
+        },
+        "export" => {
+            let entries: BTreeMap<String, Option<binacle_manager::MapEntry>> = try!(collections.get(name)).iter()
+                .map(|id| (id.to_string(), db.entry(*id).cloned()))
+                .collect();
+            println!("{}", json::encode(&entries).unwrap());
+        },
+        _ => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unknown collection action: {}", action)));
+        },
+    }
+    Ok(())
+}
+
+// the results of a file of queries, labeled by query, the summary on stderr
+fn run_queries(db: &mut binacle_manager::BinacleManager, path: &str) -> std::io::Result<()> {
+
+    let queries = try!(queries::read_queries(path));
+    let requests: Vec<SearchRequest> = queries.iter().map(|q| q.request.clone()).collect();
+    let start = Instant::now();
+    let report = search::run_batch(db, &requests);
+    let total = start.elapsed();
+
+    let mut failed = 0;
+    for (query, (result, elapsed)) in queries.iter().zip(report.results.iter().zip(&report.elapsed)) {
+        let label = match query.name == format!("line {}", query.line) {
+            true => query.name.clone(),
+            false => format!("{} (line {})", query.name, query.line),
+        };
+        for note in &query.notes {
+            eprintln!("{}: {}", label, note);
+        }
+        for warning in &query.warnings {
+            eprintln!("{}: warning: {}", label, warning);
+        }
+        match *result {
+            Ok(ref response) => {
+                if db.is_map() {
+                    for path in &response.entries {
+                        println!("{}\t{}", query.name, path);
+                    }
+                } else {
+                    for id in &response.ids {
+                        println!("{}\t{}", query.name, id);
+                    }
+                }
+                eprintln!("{}: {} result(s) in {:.1} ms", label, response.total, elapsed.as_secs_f64() * 1000.0);
+            },
+            Err(ref e) => {
+                eprintln!("{}: Error: {}", label, e);
+                failed += 1;
+            },
+        }
+    }
+    eprintln!("{} queries in {:.1} ms, {} n-gram list(s) read in {:.1} ms for all of them",
+              queries.len(), total.as_secs_f64() * 1000.0, report.lists_read, report.shared.as_secs_f64() * 1000.0);
+    match failed {
+        0 => Ok(()),
+        n => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{} of the queries failed", n))),
+    }
+}
+
+// the query of the -s options of --save-query: its patterns as the bytes
+// searched, the options a line of --queries takes and the outputs
+fn saved_query(args: &ArgvMap) -> io::Result<library::SavedQuery> {
+
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, what.to_string());
+
+    let pattern = match args.get_str("--search-file") {
+        "" if args.get_bool("hex") => {
+            let (bytes, masked) = try!(variants::parse_masked(args.get_str("<string>")));
+            if !masked.is_empty() {
+                return Err(invalid("a saved query takes a pattern without ?? wildcards"));
+            }
+            bytes
+        },
+        "" => try!(parse_pattern(args, args.get_str("<string>"))),
+        path => {
+            let options = pattern_file::TextOptions {
+                keep_line_endings: args.get_bool("--keep-line-endings"),
+                binary_ok: args.get_bool("--binary-ok"),
+                hex: args.get_bool("hex"),
+                max_bytes: match args.get_str("--max-bytes") {
+                    "" => None,
+                    _ => Some(exit::arg::<u64>(args, "--max-bytes")),
+                },
+            };
+            let normalized = try!(pattern_file::read_pattern(path, options));
+            for warning in &normalized.warnings {
+                style::warning(warning);
+            }
+            normalized.bytes
+        },
+    };
+    // as -s, the line breaks around a string are not searched
+    let pattern = match args.get_str("--search-file") == "" && !args.get_bool("hex") && !args.get_bool("--literal-newlines") {
+        true => match pattern_file::split_lines(&pattern) {
+            Some(ref lines) if lines.len() != 1 => {
+                return Err(invalid(&format!("a saved query takes a single pattern, not {} lines", lines.len())));
+            },
+            Some(mut lines) => lines.remove(0),
+            None => pattern,
+        },
+        false => pattern,
+    };
+
+    let mut patterns = vec![pattern];
+    for pattern in args.get_vec("--and") {
+        patterns.push(try!(parse_pattern(args, pattern)));
+    }
+    let mut excludes = Vec::new();
+    if args.get_str("--exclude") != "" {
+        excludes.push(try!(parse_pattern(args, args.get_str("--exclude"))));
+    }
+
+    let mut options = Vec::new();
+    if args.get_bool("exact") {
+        options.push(String::from("exact"));
+    }
+    if args.get_bool("--wide") {
+        options.push(String::from("wide"));
+    }
+    match (args.get_str("--endian"), args.get_str("--endian-unit")) {
+        ("", _) => {},
+        ("both", unit) => options.push(String::from(match try!(ByteSwap::parse(unit)) {
+            ByteSwap::Words => "endian=words",
+            ByteSwap::Full => "endian=both",
+        })),
+        (other, _) => return Err(invalid(&format!("invalid --endian value: {}", other))),
+    }
+    for &(arg, option) in &[("--limit", "limit"), ("--sample", "sample"), ("--seed", "seed"), ("--deadline-ms", "deadline-ms"),
+                            ("--id-range", "id-range"), ("--tag", "tag"), ("--path-contains", "path-contains"),
+                            ("--path-match", "path-match")] {
+        if args.get_str(arg) != "" {
+            options.push(format!("{}={}", option, args.get_str(arg)));
+        }
+    }
+    for t in args.get_str("--type").split(',').filter(|t| !t.is_empty()) {
+        options.push(format!("type={}", t));
+    }
+    if args.get_bool("--strict") {
+        options.push(String::from("strict"));
+    }
+
+    let mut saved = try!(library::SavedQuery::new(&patterns, &excludes, &options));
+    saved.output = library::OUTPUTS.iter().filter(|o| args.get_bool(&format!("--{}", o))).map(|o| String::from(*o)).collect();
+    saved.tags = args.get_vec("--query-tag").into_iter().map(String::from).collect();
+    Ok(saved)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// a saved query run with its outputs, its hits compared to its last run on
+// stderr: the number of hits
+fn run_saved_query(db: &mut binacle_manager::BinacleManager, name: &str, out: &style::Style) -> std::io::Result<usize> {
+
+    let mut library = try!(Library::open(&db.db_path));
+    let saved = try!(library.get(name)).clone();
+    let query = try!(saved.parse(name));
+    let response = match search::run_batch(db, &[query.request]).results.pop() {
+        Some(response) => try!(response),
+        None => return Err(std::io::Error::new(std::io::ErrorKind::Other, "no response")),
+    };
+
+    let labels: Option<Vec<String>> = match db.is_map() && saved.outputs("labels") {
+        true => Some(response.ids.iter().map(|id| {
+            db.entry(*id).and_then(|e| e.label.clone()).unwrap_or(id.to_string())
+        }).collect()),
+        false => None,
+    };
+    match (labels, saved.outputs("json")) {
+        (Some(labels), true) => println!("{}", json::encode(&labels).unwrap()),
+        (Some(labels), false) => {
+            for l in &labels {
+                println!("{}", l);
+            }
+            println!("{} result(s)", response.total);
+        },
+        (None, true) => println!("{}", output::hits_json(&response)),
+        (None, false) if db.is_map() => {
+            let rows: Vec<Vec<String>> = response.ids.iter().zip(&response.entries).map(|(id, path)| {
+                match saved.outputs("show-type") {
+                    true => vec![db.entry(*id).and_then(|e| e.file_type.clone()).unwrap_or(String::from("-")), path.clone()],
+                    false => vec![path.clone()],
+                }
+            }).collect();
+            let path_column = if saved.outputs("show-type") { 1 } else { 0 };
+            for line in out.table(&rows, path_column) {
+                println!("{}", line);
+            }
+            match response.truncated {
+                true => println!("{} result(s), first {} shown", response.total, response.ids.len()),
+                false => println!("{} result(s)", response.total),
+            }
+        },
+        (None, false) => println!("{}", json::encode(&response.ids).unwrap()),
+    }
+
+    // a partial count is not the one of the next run to compare to
+    if response.partial {
+        eprintln!("{}", output::partial_note(&response.skipped_shards));
+        eprintln!("{}: {} hit(s), partial, not kept as its last run", name, response.total);
+        return Ok(response.total);
+    }
+    let delta = try!(library.record(name, response.total, now()));
+    try!(library.save());
+    match delta.changed() {
+        true => style::warning_line(format!("{}: {}", name, delta.describe())),
+        false => eprintln!("{}: {}", name, delta.describe()),
+    }
+    Ok(response.total)
+}
+
+// the saved queries (the ones with the tag) run together as --queries runs
+// a file, then the hits of each one against its last run, the changes
+// standing out
+fn run_all_saved_queries(db: &mut binacle_manager::BinacleManager, tag: Option<&str>) -> std::io::Result<()> {
+
+    let mut library = try!(Library::open(&db.db_path));
+    let mut names = Vec::new();
+    let mut requests = Vec::new();
+    for (name, saved) in library.list(tag) {
+        requests.push(try!(saved.parse(name)).request);
+        names.push(String::from(name));
+    }
+    if names.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, match tag {
+            Some(tag) => format!("no saved query with the tag {}", tag),
+            None => String::from("no saved query"),
+        }));
+    }
+
+    let start = Instant::now();
+    let report = search::run_batch(db, &requests);
+    let total = start.elapsed();
+
+    let date = now();
+    let mut summary = Vec::new();
+    let (mut changed, mut failed) = (0, 0);
+    for (name, result) in names.iter().zip(&report.results) {
+        match *result {
+            Ok(ref response) => {
+                if db.is_map() {
+                    for path in &response.entries {
+                        println!("{}\t{}", name, path);
+                    }
+                } else {
+                    for id in &response.ids {
+                        println!("{}\t{}", name, id);
+                    }
+                }
+                if response.partial {
+                    summary.push((true, format!("{}: {} hit(s), partial, not kept as its last run", name, response.total)));
+                    continue;
+                }
+                let delta = try!(library.record(name, response.total, date));
+                if delta.changed() {
+                    changed += 1;
+                }
+                summary.push((delta.changed(), format!("{}: {}", name, delta.describe())));
+            },
+            Err(ref e) => {
+                summary.push((true, format!("{}: Error: {}", name, e)));
+                failed += 1;
+            },
+        }
+    }
+    try!(library.save());
+
+    for (stands_out, line) in summary {
+        match stands_out {
+            true => style::warning_line(line),
+            false => eprintln!("{}", line),
+        }
+    }
+    eprintln!("{} saved queries in {:.1} ms, {} changed since their last run",
+              names.len(), total.as_secs_f64() * 1000.0, changed);
+    match failed {
+        0 => Ok(()),
+        n => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{} of the saved queries failed", n))),
+    }
+}
+
+// the comparison of each query, then the summary, true when all agree
+fn estimate_overlap(path_a: &str, path_b: &str, samples: usize, seed: u64) -> std::io::Result<()> {
+
+    let mut a = try!(binacle_manager::BinacleManager::open(path_a));
+    let mut b = try!(binacle_manager::BinacleManager::open(path_b));
+    let o = try!(overlap::estimate(&mut a, &mut b, samples, seed));
+
+    println!("vocabulary: a {} n-gram slot(s), b {}", o.vocabulary_a, o.vocabulary_b);
+    if o.scanned_shards > 0 {
+        println!("{} shard(s) without an occupancy bitmap, their header read whole", o.scanned_shards);
+    }
+    let sampled = o.sampled.name();
+    let other = if o.sampled == overlap::Side::A { "b" } else { "a" };
+    println!("{} slot(s) drawn from {}: {} occupied in {} ({:.1}%)",
+             o.samples, sampled, o.hits, other, 100.0 * o.containment());
+    println!("jaccard overlap: {:.4} (95% interval {:.4} .. {:.4})", o.jaccard, o.interval.0, o.interval.1);
+    println!("shared bytes: about {:.0} of the {:.0} indexed in {}", o.shared_bytes, o.sampled_bytes, sampled);
+    try!(a.close());
+    b.close()
+}
+
+fn ab_test(path_a: &str, path_b: &str, queries: &str, truth: &str) -> std::io::Result<bool> {
+
+    let queries = try!(queries::read_queries(queries));
+    let truth = match truth {
+        "" => None,
+        path => Some(try!(corpus::Truth::read(path))),
+    };
+    let mut a = try!(binacle_manager::BinacleManager::open(path_a));
+    let mut b = try!(binacle_manager::BinacleManager::open(path_b));
+    println!("results compared by {}", abtest::KeyMode::of(&a, &b).name());
+
+    let summary = try!(abtest::ab_test(&mut a, &mut b, &queries, truth.as_ref(), |c| {
+        let count = |r: &Result<usize, String>| match *r {
+            Ok(n) => n.to_string(),
+            Err(ref e) => format!("error ({})", e),
+        };
+        println!("{}	{}	a: {}	b: {}", c.name, if c.agree() { "agree" } else { "DIFFER" }, count(&c.a), count(&c.b));
+        for path in &c.examples_a {
+            println!("    only in a: {}", path);
+        }
+        if c.only_a > c.examples_a.len() {
+            println!("    ... {} more only in a", c.only_a - c.examples_a.len());
+        }
+        for path in &c.examples_b {
+            println!("    only in b: {}", path);
+        }
+        if c.only_b > c.examples_b.len() {
+            println!("    ... {} more only in b", c.only_b - c.examples_b.len());
+        }
+        if let Some((ref qa, ref qb)) = c.accuracy {
+            println!("    truth: a recall {:.1}% precision {:.1}%, b recall {:.1}% precision {:.1}%",
+                     100.0 * qa.recall(), 100.0 * qa.precision(), 100.0 * qb.recall(), 100.0 * qb.precision());
+        }
+    }));
+
+    println!("{} queries: {} agree, {} differ, {} failed on a side at least",
+             summary.queries, summary.agreed, summary.disagreed(), summary.failed);
+    if let Some((ref sa, ref sb)) = summary.accuracy {
+        for &(side, acc) in &[("a", sa), ("b", sb)] {
+            println!("{}: recall {}/{} ({:.1}%), precision {}/{} ({:.1}%)", side,
+                     acc.true_positives, acc.expected, 100.0 * acc.recall(),
+                     acc.true_positives, acc.found, 100.0 * acc.precision());
         }
     }
+    try!(a.close());
+    try!(b.close());
+    Ok(summary.disagreed() == 0)
+}
+
+// the candidates of each rule of a YARA file, a line <rule><TAB><path or id>
+fn prefilter_yara(db: &mut binacle_manager::BinacleManager, rules: &str) -> std::io::Result<()> {
 
-}
\ No newline at end of file
+    let rules = try!(yara::read_rules(rules));
+    let start = Instant::now();
+    let report = try!(yara::prefilter(db, &rules));
+    let total = start.elapsed();
+
+    for rule in &report {
+        let ids = match rule.ids {
+            Some(ref ids) => ids,
+            None => continue,
+        };
+        try!(db.resolve(&ids.iter().cloned().collect()));
+        for &id in ids {
+            match db.path_of(id) {
+                Some(path) => println!("{}\t{}", rule.rule, path),
+                None => println!("{}\t{}", rule.rule, id),
+            }
+        }
+    }
+
+    for rule in &report {
+        for &(ref name, ref reason) in &rule.ignored {
+            eprintln!("{}: {} ignored: {}", rule.rule, name, reason);
+        }
+        match rule.ids {
+            Some(ref ids) => eprintln!("{}: {} candidate(s), {} string(s) searched", rule.rule, ids.len(), rule.searched),
+            None => style::warning_line(format!("{}: not pre-filtered, all the files are candidates", rule.rule)),
+        }
+    }
+    eprintln!("{} rule(s) in {:.1} ms", report.len(), total.as_secs_f64() * 1000.0);
+    Ok(())
+}
+
+// the space needed by the commands growing a database, and the file on
+// the filesystem that must hold it
+fn space_need(args: &ArgvMap, db: &mut binacle_manager::BinacleManager, file_list: &FileList) -> std::io::Result<Option<(String, space::Need)>> {
+
+    let content = if args.get_bool("-f") {
+        space::files_content(Some(args.get_str("<file>")).into_iter())
+    } else if args.get_bool("--files") {
+        space::files_content(file_list.files.iter().map(|f| f.1.as_str()))
+    } else if args.get_bool("--rec") {
+        space::dir_content(args.get_str("<dir>"))
+    } else if args.get_bool("--ingest-manifest") {
+        let entries = try!(binacle::manifest::read_entries(args.get_str("<manifest>")));
+        space::files_content(entries.iter().map(|e| e.path.as_str()))
+    } else if args.get_bool("--compact-ngrams") {
+        let top = exit::arg::<usize>(&args, "<top_n>");
+        return Ok(Some((db.db_path.clone(), try!(space::compaction_need(db, top)))));
+    } else if args.get_bool("--compact") {
+        return Ok(Some((db.db_path.clone(), try!(space::rewrite_need(db)))));
+    } else if args.get_bool("--reindex") {
+        let alignment = match args.get_str("--alignment") {
+            "" => db.alignment(),
+            _ => exit::arg::<u8>(&args, "--alignment"),
+        };
+        return Ok(Some((String::from(args.get_str("<dst_db>")), try!(space::reindex_need(db, alignment)))));
+    } else if args.get_bool("--watch") {
+        // the files are not known yet, the floor is checked between them
+        return Ok(Some((db.db_path.clone(), space::Need::default())));
+    } else if args.get_bool("--merge") {
+        let mut other = try!(binacle_manager::BinacleManager::open(args.get_str("<src_db>")));
+        return Ok(Some((db.db_path.clone(), try!(space::merge_need(db, &mut other)))));
+    } else {
+        return Ok(None);
+    };
+    Ok(Some((db.db_path.clone(), space::ingest_need(db, content.0, content.1))))
+}
+
+// the filters of --rec on the time, the owner and the size of the files
+fn rec_filter(args: &ArgvMap) -> io::Result<IngestFilter> {
+    let date = |option: &str| match args.get_str(option) {
+        "" => Ok(None),
+        d => dates::parse_date(d).map(Some).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", option, e))),
+    };
+    let size = |option: &str| match args.get_str(option) {
+        "" => Ok(None),
+        s => ingest_filter::parse_size(s).map(Some).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", option, e))),
+    };
+    Ok(IngestFilter {
+        modified_since: try!(date("--modified-since")),
+        modified_before: try!(date("--modified-before")),
+        owner: match args.get_str("--owner") {
+            "" => None,
+            owner => Some(try!(ingest_filter::parse_owner(owner))),
+        },
+        min_size: try!(size("--min-size")),
+        max_size: try!(size("--max-size")),
+    }).and_then(|filter| match (filter.min_size, filter.max_size) {
+        (Some(min), Some(max)) if min > max => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                    format!("--min-size {} over --max-size {}, no file would be inserted", min, max))),
+        _ => Ok(filter),
+    })
+}
+
+// bytes of a pattern given on the command line
+fn parse_pattern(args: &ArgvMap, pattern: &str) -> io::Result<Vec<u8>> {
+    if args.get_bool("hex") {
+        pattern.from_hex().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid hex pattern {}: {}", pattern, e)))
+    } else {
+        Ok(Vec::from(pattern.as_bytes()))
+    }
+}
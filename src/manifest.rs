@@ -0,0 +1,278 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{OpenOptions, metadata};
+use std::io::*;
+use rustc_serialize::json::{self, Json, ToJson};
+
+use binacle_manager::{BinacleManager, InsertOutcome};
+use hash;
+
+// Insertion of the files listed in a manifest, one JSON object a line:
+//   {"path": "...", "sha256": "...", "size": 1234, "tags": ["..."]}
+// A file is only kept when its content matches the manifest. The status of
+// each line is appended to a result file, running again with the same
+// result file skips the lines already indexed and the ones whose content did
+// not match, their id is tombstoned already (the manifest must not be
+// reordered between the runs).
+
+
+#[derive(RustcDecodable)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub sha256: String,
+    pub size: Option<u64>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManifestStatus {
+    Indexed(u32),
+    // indexed by a previous run
+    AlreadyIndexed(u32),
+    HashMismatch,
+    // rejected by a previous run
+    AlreadyMismatched,
+    SizeMismatch,
+    Missing,
+    Error(String),
+}
+
+impl ManifestStatus {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ManifestStatus::Indexed(_) | ManifestStatus::AlreadyIndexed(_) => "indexed",
+            ManifestStatus::HashMismatch | ManifestStatus::AlreadyMismatched => "hash-mismatch",
+            ManifestStatus::SizeMismatch => "size-mismatch",
+            ManifestStatus::Missing => "missing",
+            ManifestStatus::Error(_) => "error",
+        }
+    }
+}
+
+pub struct ManifestResult {
+    // line number in the manifest, from 1
+    pub line: usize,
+    pub path: String,
+    pub status: ManifestStatus,
+}
+
+impl ManifestResult {
+
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("line"), self.line.to_json());
+        obj.insert(String::from("path"), self.path.to_json());
+        obj.insert(String::from("status"), self.status.name().to_json());
+        match self.status {
+            ManifestStatus::Indexed(id) | ManifestStatus::AlreadyIndexed(id) => {
+                obj.insert(String::from("id"), id.to_json());
+            },
+            ManifestStatus::Error(ref e) => {
+                obj.insert(String::from("error"), e.to_json());
+            },
+            _ => {},
+        }
+        Json::Object(obj)
+    }
+}
+
+// lines done by a previous run, with their path and the id indexed, none
+// for a content not matching the manifest
+fn read_results(path: &str) -> Result<HashMap<usize, (String, Option<u32>)>> {
+
+    let mut indexed = HashMap::new();
+
+    let file = match OpenOptions::new().read(true).open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(indexed),
+        Err(e) => return Err(e),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+        // a run interrupted while writing leaves a truncated last line
+        let obj = match Json::from_str(&line) {
+            Ok(Json::Object(obj)) => obj,
+            _ => continue,
+        };
+        let id = match obj.get("status").and_then(|s| s.as_string()) {
+            Some("indexed") => match obj.get("id").and_then(|i| i.as_u64()) {
+                Some(id) => Some(id as u32),
+                None => continue,
+            },
+            Some("hash-mismatch") => None,
+            _ => continue,
+        };
+        if let (Some(line), Some(path)) = (obj.get("line").and_then(|l| l.as_u64()),
+                                           obj.get("path").and_then(|p| p.as_string())) {
+            indexed.insert(line as usize, (String::from(path), id));
+        }
+    }
+    Ok(indexed)
+}
+
+fn ingest_entry(db: &mut BinacleManager, entry: &ManifestEntry, id: u32) -> ManifestStatus {
+
+    let expected = match hash::parse_sha256(&entry.sha256) {
+        Ok(h) => h,
+        Err(e) => return ManifestStatus::Error(e.to_string()),
+    };
+
+    match metadata(&entry.path) {
+        Ok(ref m) if !m.is_file() => return ManifestStatus::Error(String::from("not a regular file")),
+        Ok(ref m) if entry.size.map_or(false, |s| s != m.len()) => return ManifestStatus::SizeMismatch,
+        Ok(_) => {},
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return ManifestStatus::Missing,
+        Err(e) => return ManifestStatus::Error(e.to_string()),
+    }
+
+    match db.insert_verified(&entry.path, id, &expected, false) {
+        InsertOutcome::Inserted(id) => {
+            if let Some(ref tags) = entry.tags {
+                if let Err(e) = db.set_tags(id, tags.clone()) {
+                    return ManifestStatus::Error(e.to_string());
+                }
+            }
+            ManifestStatus::Indexed(id)
+        },
+        InsertOutcome::Failed(ref e) if e.kind() == ErrorKind::InvalidData => ManifestStatus::HashMismatch,
+        InsertOutcome::Failed(ref e) if e.kind() == ErrorKind::NotFound => ManifestStatus::Missing,
+        InsertOutcome::Failed(e) => ManifestStatus::Error(e.to_string()),
+        InsertOutcome::Skipped(reason) => ManifestStatus::Error(reason),
+    }
+}
+
+// all the entries of a manifest, an invalid line is an error
+pub fn read_entries(manifest: &str) -> Result<Vec<ManifestEntry>> {
+
+    let input = try!(OpenOptions::new().read(true).open(manifest));
+    let mut entries = Vec::new();
+    for (nb, line) in BufReader::new(input).lines().enumerate() {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(try!(json::decode::<ManifestEntry>(&line).map_err(|e| {
+            Error::new(ErrorKind::InvalidData, format!("{}:{}: invalid manifest line: {}", manifest, nb + 1, e))
+        })));
+    }
+    Ok(entries)
+}
+
+// insert the files of the manifest, new ids follow the last one
+pub fn ingest_manifest(db: &mut BinacleManager, manifest: &str, results: &str) -> Result<Vec<ManifestResult>> {
+
+    if !db.is_map() {
+        return Err(Error::new(ErrorKind::Other, "manifest ingestion needs a map database"));
+    }
+
+    let done = try!(read_results(results));
+    let input = try!(OpenOptions::new().read(true).open(manifest));
+    let mut output = try!(OpenOptions::new().create(true).append(true).open(results));
+
+    let mut report = Vec::new();
+
+    for (nb, line) in BufReader::new(input).lines().enumerate() {
+
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match json::decode::<ManifestEntry>(&line) {
+            Err(e) => ManifestResult {
+                line: nb + 1,
+                path: String::new(),
+                status: ManifestStatus::Error(format!("invalid manifest line: {}", e)),
+            },
+            Ok(entry) => {
+                // skipped only if the line did not change and the database still knows it,
+                // a mismatch is final: each try would tombstone one more id
+                let status = match done.get(&(nb + 1)) {
+                    Some(&(ref path, Some(id))) if *path == entry.path && db.path_of(id) == Some(path) => {
+                        ManifestStatus::AlreadyIndexed(id)
+                    },
+                    Some(&(ref path, None)) if *path == entry.path => ManifestStatus::AlreadyMismatched,
+                    _ => {
+                        // stopped cleanly, the next run resumes at this line
+                        if let Err(e) = db.check_space() {
+                            try!(db.flush_map());
+                            return Err(Error::new(e.kind(), format!("{}: manifest line {} and the next ones not ingested", e, nb + 1)));
+                        }
+                        let id = db.last_id() + 1;
+                        ingest_entry(db, &entry, id)
+                    },
+                };
+                ManifestResult {
+                    line: nb + 1,
+                    path: entry.path,
+                    status: status,
+                }
+            },
+        };
+
+        match result.status {
+            ManifestStatus::AlreadyIndexed(_) | ManifestStatus::AlreadyMismatched => {},
+            _ => try!(writeln!(output, "{}", result.to_json())),
+        }
+        report.push(result);
+    }
+
+    try!(db.flush_map());
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::tests::cleanup;
+
+    #[test]
+    fn manifest_statuses() {
+
+        let _ = fs::remove_dir_all("test_manifest");
+        fs::create_dir_all("test_manifest").unwrap();
+        File::create("test_manifest/good.bin").unwrap().write_all(b"abc GetProcAddress").unwrap();
+        File::create("test_manifest/bad.bin").unwrap().write_all(b"abc LoadLibraryA").unwrap();
+
+        let good = hash::to_hex(&hash::sha256_file("test_manifest/good.bin").unwrap());
+        File::create("test_manifest/manifest.jsonl").unwrap().write_all(format!(
+            "{{\"path\": \"test_manifest/good.bin\", \"sha256\": \"{}\", \"size\": 18, \"tags\": [\"case1\"]}}\n\
+             {{\"path\": \"test_manifest/bad.bin\", \"sha256\": \"{}\"}}\n\
+             {{\"path\": \"test_manifest/good.bin\", \"sha256\": \"{}\", \"size\": 3}}\n\
+             {{\"path\": \"test_manifest/none.bin\", \"sha256\": \"{}\"}}\n\
+             {{\"path\": \"test_manifest/good.bin\", \"sha256\": \"zz\"}}\n\
+             not json\n", good, good, good, good).as_bytes()).unwrap();
+
+        {
+            let mut db = BinacleManager::create("test_manifest.db", true, 1 << 30, 6, 16).unwrap();
+            let report = ingest_manifest(&mut db, "test_manifest/manifest.jsonl", "test_manifest/result.jsonl").unwrap();
+            let statuses: Vec<&str> = report.iter().map(|r| r.status.name()).collect();
+            assert_eq!(vec!["indexed", "hash-mismatch", "size-mismatch", "missing", "error", "error"], statuses);
+            assert_eq!(ManifestStatus::Indexed(1), report[0].status);
+
+            // the mismatching content is not found
+            assert_eq!(vec![1], db.search(b"GetProcAddress").unwrap().into_iter().collect::<Vec<u32>>());
+            assert!(db.search(b"LoadLibraryA").unwrap().is_empty());
+            assert_eq!(Some(&vec![String::from("case1")]), db.entry(1).unwrap().tags.as_ref());
+
+            // second run: the indexed and the mismatching entries are skipped, the others are tried again
+            let last_id = db.last_id();
+            let report = ingest_manifest(&mut db, "test_manifest/manifest.jsonl", "test_manifest/result.jsonl").unwrap();
+            assert_eq!(ManifestStatus::AlreadyIndexed(1), report[0].status);
+            assert_eq!(ManifestStatus::AlreadyMismatched, report[1].status);
+            assert_eq!("hash-mismatch", report[1].status.name());
+            assert_eq!(1, db.nb_file());
+            assert_eq!(last_id, db.last_id());
+        }
+
+        let results = fs::read_to_string("test_manifest/result.jsonl").unwrap();
+        assert_eq!(10, results.lines().count());
+
+        cleanup("test_manifest");
+        let _ = fs::remove_dir_all("test_manifest");
+    }
+}
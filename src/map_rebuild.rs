@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions, rename};
+use std::io::*;
+use std::path::{Component, Path, PathBuf};
+use rustc_serialize::json;
+
+use binacle_manager::{BinacleManager, MapEntry};
+use lazy_map;
+
+// Rewrite of the map of a database, cleaned of what years of partial
+// failures and edits by hand leave in it:
+//
+//   - an id given twice in the file: the entry the map loads, the last
+//     one, is kept
+//   - the paths are written in their canonical form, without ./, // and
+//     dir/.. (the files are not read, they may be gone)
+//   - the entries of a same canonical path with the same sha256: the last
+//     id is kept, the others are tombstoned
+//   - the entries of the tombstoned ids are dropped
+//
+// The entries dropped are archived in <db>.archive with the reason. The
+// tombstones are written first, then the map aside and renamed over the
+// old one in the current format (a legacy map id -> path becomes entries):
+// an interrupted rebuild is completed by the next one, a second rebuild
+// changes nothing.
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    // an entry of the id given again later in the file
+    Duplicate { id: u32, path: String },
+    Canonical { id: u32, from: String, to: String },
+    // the same file as the entry of the id of
+    Alias { id: u32, path: String, of: u32 },
+    Tombstoned { id: u32, path: String },
+}
+
+impl Change {
+
+    pub fn id(&self) -> u32 {
+        match *self {
+            Change::Duplicate { id, .. } | Change::Canonical { id, .. } |
+            Change::Alias { id, .. } | Change::Tombstoned { id, .. } => id,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match *self {
+            Change::Duplicate { id, ref path } => format!("{}\tduplicate entry dropped, the last one is kept\t{}", id, path),
+            Change::Canonical { id, ref from, ref to } => format!("{}\tpath canonicalized\t{} -> {}", id, from, to),
+            Change::Alias { id, ref path, of } => format!("{}\talias of {} tombstoned and dropped\t{}", id, of, path),
+            Change::Tombstoned { id, ref path } => format!("{}\ttombstoned, dropped\t{}", id, path),
+        }
+    }
+}
+
+// an entry dropped from the map, kept for the audits
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct Archived {
+    pub id: u32,
+    pub reason: String,
+    pub entry: MapEntry,
+}
+
+pub fn archive_path(db: &BinacleManager) -> PathBuf {
+    let mut path = PathBuf::from(&db.db_path);
+    path.set_extension("archive");
+    path
+}
+
+pub fn read_archive(path: &Path) -> Result<Vec<Archived>> {
+    let mut content = String::new();
+    match OpenOptions::new().read(true).open(path) {
+        Ok(mut f) => try!(f.read_to_string(&mut content)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    json::decode(&content).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+}
+
+// the archive with these entries, an entry archived again for the same
+// reason is not repeated
+fn append_archive(path: &Path, entries: Vec<Archived>) -> Result<()> {
+
+    let mut archive = try!(read_archive(path));
+    for entry in entries {
+        if !archive.iter().any(|a| a.id == entry.id && a.reason == entry.reason && a.entry.path == entry.entry.path) {
+            archive.push(entry);
+        }
+    }
+    let encoded = try!(json::encode(&archive).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())));
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    try!(try!(File::create(&tmp)).write_all(encoded.as_bytes()));
+    rename(&tmp, path)
+}
+
+// the path without its . components, its repeated separators and its
+// dir/.. pairs, lexically
+pub fn canonical_path(path: &str) -> String {
+
+    let mut parts: Vec<Component> = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => match parts.last() {
+                Some(&Component::Normal(_)) => { parts.pop(); },
+                // the parent of the root is the root
+                Some(&Component::RootDir) => {},
+                _ => parts.push(component),
+            },
+            c => parts.push(c),
+        }
+    }
+    match parts.is_empty() {
+        true => String::from("."),
+        false => parts.iter().collect::<PathBuf>().to_string_lossy().into_owned(),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MapRebuild {
+    pub changes: Vec<Change>,
+    // entries of the map written
+    pub entries: usize,
+}
+
+// rebuild the map of db, see above
+pub fn rebuild_map(db: &mut BinacleManager) -> Result<MapRebuild> {
+
+    if !db.is_map() {
+        return Err(Error::new(ErrorKind::Other, "the map is rebuilt in a map database"));
+    }
+    if db.is_lazy_map() {
+        return Err(Error::new(ErrorKind::Other, "the map is over the memory cap, it can not be rebuilt"));
+    }
+
+    let mut changes = Vec::new();
+    let mut archived = Vec::new();
+
+    // the occurrences of each id in the file, the last one was loaded
+    let mut seen: HashMap<u32, Vec<MapEntry>> = HashMap::new();
+    try!(lazy_map::scan(db.map_path().to_str().unwrap(), |_| true, |id, entry| {
+        seen.entry(id).or_insert_with(Vec::new).push(entry);
+    }));
+    let mut duplicated: Vec<(u32, Vec<MapEntry>)> = seen.into_iter().filter(|&(_, ref e)| e.len() > 1).collect();
+    duplicated.sort_by_key(|&(id, _)| id);
+    for (id, mut entries) in duplicated {
+        entries.pop();
+        for entry in entries {
+            changes.push(Change::Duplicate { id: id, path: entry.path.clone() });
+            archived.push(Archived { id: id, reason: String::from("duplicate"), entry: entry });
+        }
+    }
+
+    let mut map: BTreeMap<u32, MapEntry> = db.entries().map(|(id, e)| (id, e.clone())).collect();
+
+    let tombstoned: Vec<u32> = map.keys().cloned().filter(|id| db.is_tombstoned(*id)).collect();
+    for id in tombstoned {
+        let entry = map.remove(&id).unwrap();
+        changes.push(Change::Tombstoned { id: id, path: entry.path.clone() });
+        archived.push(Archived { id: id, reason: String::from("tombstoned"), entry: entry });
+    }
+
+    for (id, entry) in map.iter_mut() {
+        let canonical = canonical_path(&entry.path);
+        if canonical != entry.path {
+            changes.push(Change::Canonical { id: *id, from: entry.path.clone(), to: canonical.clone() });
+            entry.path = canonical;
+        }
+    }
+
+    // the ids are visited in order, the last of a file is kept
+    let mut files: HashMap<(String, String), u32> = HashMap::new();
+    let mut older = Vec::new();
+    for (id, entry) in &map {
+        if let Some(ref sha256) = entry.sha256 {
+            let file = (entry.path.clone(), sha256.clone());
+            if let Some(id) = files.insert(file.clone(), *id) {
+                older.push((id, file));
+            }
+        }
+    }
+    let aliases: Vec<(u32, u32)> = older.into_iter().map(|(id, file)| (id, files[&file])).collect();
+    for &(id, of) in &aliases {
+        let entry = map.remove(&id).unwrap();
+        changes.push(Change::Alias { id: id, path: entry.path.clone(), of: of });
+        archived.push(Archived { id: id, reason: format!("alias of {}", of), entry: entry });
+    }
+
+    // the labels must stay unique, nothing is written otherwise
+    let mut labels: HashMap<&str, u32> = HashMap::new();
+    for (id, entry) in &map {
+        if let Some(ref label) = entry.label {
+            if let Some(other) = labels.insert(label.as_str(), *id) {
+                return Err(Error::new(ErrorKind::InvalidData, format!(
+                    "label \"{}\" is given to ids {} and {}, fix it first (--audit-ids, --info)", label, other, id)));
+            }
+        }
+    }
+
+    let entries = map.len();
+    if changes.is_empty() {
+        return Ok(MapRebuild { changes: changes, entries: entries });
+    }
+    if !archived.is_empty() {
+        try!(append_archive(&archive_path(db), archived));
+    }
+    for &(id, _) in &aliases {
+        db.tombstone(id);
+    }
+    try!(db.replace_map(map.into_iter().collect()));
+
+    changes.sort_by_key(|c| c.id());
+    Ok(MapRebuild { changes: changes, entries: entries })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::tests::cleanup;
+
+    fn remove(name: &str) {
+        cleanup(name);
+        let _ = fs::remove_file(format!("{}.archive", name));
+    }
+
+    #[test]
+    fn canonical() {
+        assert_eq!("dir/a.bin", canonical_path("./dir//a.bin"));
+        assert_eq!("dir/b.bin", canonical_path("dir/sub/../b.bin"));
+        assert_eq!("../b.bin", canonical_path("../b.bin"));
+        assert_eq!("/b.bin", canonical_path("/../b.bin"));
+        assert_eq!(".", canonical_path("a/.."));
+    }
+
+    #[test]
+    fn messy_legacy_map() {
+
+        remove("test_rebuild");
+        {
+            let mut db = BinacleManager::create("test_rebuild.db", true, 1 << 30, 6, 16).unwrap();
+            for id in 1 .. 6 {
+                db.insert_reader(&b"call GetProcAddress"[..], &format!("{}.bin", id), id, true);
+            }
+            db.tombstone(4);
+        }
+        // edited by hand: 2 given twice, 5 under two spellings of one path
+        File::create("test_rebuild.map").unwrap().write_all(b"{\"1\":\"./samples//a.bin\",\"2\":\"samples/old.bin\",\
+            \"3\":\"samples/c.bin\",\"2\":\"samples/b.bin\",\"4\":\"samples/d.bin\",\"5\":\"samples/x/../e.bin\"}").unwrap();
+        let _ = fs::remove_file("test_rebuild.hashes");
+
+        {
+            let mut db = BinacleManager::open("test_rebuild.db").unwrap();
+            let report = rebuild_map(&mut db).unwrap();
+            assert_eq!(vec![
+                Change::Canonical { id: 1, from: String::from("./samples//a.bin"), to: String::from("samples/a.bin") },
+                Change::Duplicate { id: 2, path: String::from("samples/old.bin") },
+                Change::Tombstoned { id: 4, path: String::from("samples/d.bin") },
+                Change::Canonical { id: 5, from: String::from("samples/x/../e.bin"), to: String::from("samples/e.bin") },
+            ], report.changes);
+            assert_eq!(4, report.entries);
+            assert!(report.changes[0].describe().starts_with("1\tpath canonicalized"));
+        }
+
+        // the current format, the same map loaded again
+        let mut content = String::new();
+        File::open("test_rebuild.map").unwrap().read_to_string(&mut content).unwrap();
+        let map: BTreeMap<u32, MapEntry> = json::decode(&content).unwrap();
+        let paths: Vec<(u32, &str)> = map.iter().map(|(id, e)| (*id, e.path.as_str())).collect();
+        assert_eq!(vec![(1, "samples/a.bin"), (2, "samples/b.bin"), (3, "samples/c.bin"), (5, "samples/e.bin")], paths);
+
+        let archive = read_archive(Path::new("test_rebuild.archive")).unwrap();
+        let reasons: Vec<(u32, &str, &str)> = archive.iter().map(|a| (a.id, a.reason.as_str(), a.entry.path.as_str())).collect();
+        assert_eq!(vec![(2, "duplicate", "samples/old.bin"), (4, "tombstoned", "samples/d.bin")], reasons);
+
+        // nothing left to do
+        {
+            let mut db = BinacleManager::open("test_rebuild.db").unwrap();
+            assert!(rebuild_map(&mut db).unwrap().changes.is_empty());
+        }
+        assert_eq!(2, read_archive(Path::new("test_rebuild.archive")).unwrap().len());
+        remove("test_rebuild");
+    }
+
+    #[test]
+    fn aliases_and_labels() {
+
+        remove("test_rebuild2");
+        {
+            let mut db = BinacleManager::create("test_rebuild2.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "dir/a.bin", 1, true);
+            db.insert_reader(&b"call GetProcAddress"[..], "./dir/a.bin", 2, true);
+            // same path, another content: not an alias
+            db.insert_reader(&b"call LoadLibraryA"[..], "dir/a.bin", 3, true);
+            db.insert_reader(&b"call GetProcAddress"[..], "dir//b.bin", 4, true);
+
+            let report = rebuild_map(&mut db).unwrap();
+            assert_eq!(vec![
+                Change::Alias { id: 1, path: String::from("dir/a.bin"), of: 2 },
+                Change::Canonical { id: 2, from: String::from("./dir/a.bin"), to: String::from("dir/a.bin") },
+                Change::Canonical { id: 4, from: String::from("dir//b.bin"), to: String::from("dir/b.bin") },
+            ], report.changes);
+            assert!(db.is_tombstoned(1));
+            assert!(db.entry(1).is_none());
+            assert_eq!(vec![2, 4], db.find_by_hash(&::hash::parse_sha256(db.entry(2).unwrap().sha256.as_ref().unwrap()).unwrap()));
+            let mut ids: Vec<u32> = db.search(b"GetProcAddress").unwrap().into_iter().collect();
+            ids.sort();
+            assert_eq!(vec![2, 4], ids);
+
+            // two ids with a label: nothing is written
+            db.set_label(2, "sample").unwrap();
+            db.set_label(3, "other").unwrap();
+        }
+        let mut content = String::new();
+        File::open("test_rebuild2.map").unwrap().read_to_string(&mut content).unwrap();
+        File::create("test_rebuild2.map").unwrap().write_all(content.replace("\"other\"", "\"sample\"").replace("dir/b.bin", "dir/./b.bin").as_bytes()).unwrap();
+        {
+            let mut db = BinacleManager::open("test_rebuild2.db").unwrap();
+            let e = rebuild_map(&mut db).err().unwrap();
+            assert!(e.to_string().starts_with("label \"sample\" is given to ids 2 and 3"), "{}", e);
+        }
+        let mut unchanged = String::new();
+        File::open("test_rebuild2.map").unwrap().read_to_string(&mut unchanged).unwrap();
+        assert!(unchanged.contains("dir/./b.bin"));
+        remove("test_rebuild2");
+    }
+}
@@ -0,0 +1,342 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::*;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rustc_serialize::json::{Json, Object};
+
+use exec::Hit;
+use hash::{self, HashReader};
+
+// The results of a search put in a case directory (--copy-to), the end of
+// a hunt. Each hit is named by its sha256, by its id when the map has no
+// hash for it: a sample found by several searches is there once, a hit
+// already in the directory with the same content is left as it is.
+//
+// - copy, by default: the content is copied to a .part file renamed when
+//   complete, its sha256 checked against the one of the map (a file
+//   changed since it was indexed is not the sample found);
+// - hardlink or symlink: no space is taken, the directory on the same
+//   file system as the corpus for a hard link. The symlinks point to the
+//   absolute path of the hits.
+//
+// A hit that can not be materialized is reported and counted, the others
+// are still done. The bytes copied can be bounded, the hits past the budget
+// are left and told. A manifest of the run, the query, what was done for
+// each hit and why, is written next to them.
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Copy,
+    Hardlink,
+    Symlink,
+}
+
+impl Mode {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mode::Copy => "copy",
+            Mode::Hardlink => "hardlink",
+            Mode::Symlink => "symlink",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Done,
+    // the same content was already there
+    Present,
+    // left by the budget
+    OverBudget,
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub id: u32,
+    pub source: String,
+    // file name in the directory
+    pub name: String,
+    pub sha256: String,
+    pub size: u64,
+    pub status: Status,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CopyReport {
+    pub records: Vec<Record>,
+    pub done: usize,
+    pub present: usize,
+    pub failed: usize,
+    // bytes copied, the links take none
+    pub bytes: u64,
+    // why the hits left were not copied
+    pub stopped: Option<String>,
+    pub manifest: PathBuf,
+}
+
+// the name of a hit in the directory
+pub fn name_of(hit: &Hit) -> String {
+    match hit.sha256.is_empty() {
+        true => format!("id-{}", hit.id),
+        false => hit.sha256.clone(),
+    }
+}
+
+fn sha256_hex(path: &Path) -> Result<String> {
+    hash::sha256_file(path.to_str().unwrap_or("")).map(|h| hash::to_hex(&h))
+}
+
+// the content through a .part file, its hash the expected one when known
+fn copy_checked(source: &str, dest: &Path, expected: &str) -> Result<()> {
+    let part = dest.with_extension("part");
+    let res = (|| {
+        let mut reader = HashReader::new(try!(OpenOptions::new().read(true).open(source)));
+        let mut out = try!(File::create(&part));
+        try!(copy(&mut reader, &mut out));
+        try!(out.sync_all());
+        let found = hash::to_hex(&try!(reader.finish()));
+        if !expected.is_empty() && found != expected {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "sha256 {} instead of the {} indexed: changed since", found, expected)));
+        }
+        fs::rename(&part, dest)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    res
+}
+
+#[cfg(unix)]
+fn symlink(source: &Path, dest: &Path) -> Result<()> {
+    ::std::os::unix::fs::symlink(source, dest)
+}
+
+#[cfg(windows)]
+fn symlink(source: &Path, dest: &Path) -> Result<()> {
+    ::std::os::windows::fs::symlink_file(source, dest)
+}
+
+// one hit in the directory, Present when it already is
+fn materialize(hit: &Hit, dest: &Path, mode: Mode) -> Result<Status> {
+
+    if fs::symlink_metadata(dest).is_ok() {
+        let source_sha = match hit.sha256.is_empty() {
+            true => try!(sha256_hex(Path::new(&hit.path))),
+            false => hit.sha256.clone(),
+        };
+        return match try!(sha256_hex(dest)) == source_sha {
+            true => Ok(Status::Present),
+            false => Err(Error::new(ErrorKind::AlreadyExists, format!(
+                "{} is already there with another content", dest.display()))),
+        };
+    }
+    match mode {
+        Mode::Copy => try!(copy_checked(&hit.path, dest, &hit.sha256)),
+        Mode::Hardlink => try!(fs::hard_link(&hit.path, dest)),
+        Mode::Symlink => try!(symlink(&try!(fs::canonicalize(&hit.path)), dest)),
+    }
+    Ok(Status::Done)
+}
+
+// put the hits in dir, created when missing. Fails only when the directory
+// or the manifest can not be written
+pub fn copy_hits(hits: &[Hit], dir: &str, mode: Mode, max_bytes: Option<u64>, query: &str) -> Result<CopyReport> {
+
+    try!(fs::create_dir_all(dir));
+    let mut report = CopyReport::default();
+
+    for hit in hits {
+        let name = name_of(hit);
+        let dest = Path::new(dir).join(&name);
+        let size = fs::metadata(&hit.path).map(|m| m.len()).unwrap_or(0);
+        let mut record = Record {
+            id: hit.id,
+            source: hit.path.clone(),
+            name: name,
+            sha256: hit.sha256.clone(),
+            size: size,
+            status: Status::Done,
+        };
+
+        let over = mode == Mode::Copy && max_bytes.map_or(false, |max| report.bytes + size > max);
+        if report.stopped.is_some() || (over && fs::symlink_metadata(&dest).is_err()) {
+            if report.stopped.is_none() {
+                report.stopped = Some(format!("--max-copy-bytes {} reached after {} byte(s) copied, {} hit(s) left",
+                                              max_bytes.unwrap_or(0), report.bytes, hits.len() - report.records.len()));
+            }
+            record.status = Status::OverBudget;
+            report.records.push(record);
+            continue;
+        }
+
+        record.status = match materialize(hit, &dest, mode) {
+            Ok(status) => status,
+            Err(e) => Status::Failed(e.to_string()),
+        };
+        match record.status {
+            Status::Done => {
+                report.done += 1;
+                if mode == Mode::Copy {
+                    report.bytes += size;
+                }
+            },
+            Status::Present => report.present += 1,
+            Status::Failed(_) => report.failed += 1,
+            Status::OverBudget => {},
+        }
+        report.records.push(record);
+    }
+
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    report.manifest = Path::new(dir).join(format!("manifest-{}.json", secs));
+    let manifest = manifest(&report, mode, query);
+    try!(try!(File::create(&report.manifest)).write_all(manifest.as_bytes()));
+    Ok(report)
+}
+
+// what was done for each hit, and the query that found them
+pub fn manifest(report: &CopyReport, mode: Mode, query: &str) -> String {
+
+    let records = report.records.iter().map(|r| {
+        let (status, reason) = match r.status {
+            Status::Done => (mode.name(), None),
+            Status::Present => ("present", Some(String::from("the same content was already there"))),
+            Status::OverBudget => ("skipped", report.stopped.clone()),
+            Status::Failed(ref e) => ("failed", Some(e.clone())),
+        };
+        let mut record = Object::new();
+        record.insert(String::from("id"), Json::U64(r.id as u64));
+        record.insert(String::from("source"), Json::String(r.source.clone()));
+        record.insert(String::from("name"), Json::String(r.name.clone()));
+        record.insert(String::from("sha256"), match r.sha256.is_empty() {
+            true => Json::Null,
+            false => Json::String(r.sha256.clone()),
+        });
+        record.insert(String::from("size"), Json::U64(r.size));
+        record.insert(String::from("status"), Json::String(String::from(status)));
+        record.insert(String::from("reason"), reason.map_or(Json::Null, Json::String));
+        Json::Object(record)
+    }).collect();
+
+    let mut root = Object::new();
+    root.insert(String::from("query"), Json::String(String::from(query)));
+    root.insert(String::from("mode"), Json::String(String::from(mode.name())));
+    root.insert(String::from("done"), Json::U64(report.done as u64));
+    root.insert(String::from("present"), Json::U64(report.present as u64));
+    root.insert(String::from("failed"), Json::U64(report.failed as u64));
+    root.insert(String::from("bytes"), Json::U64(report.bytes));
+    root.insert(String::from("stopped"), report.stopped.clone().map_or(Json::Null, Json::String));
+    root.insert(String::from("hits"), Json::Array(records));
+    Json::Object(root).pretty().to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::remove_dir_all;
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use exec;
+    use search::SearchRequest;
+
+    fn corpus(name: &str) -> (BinacleManager, Vec<Hit>) {
+        let _ = fs::create_dir(name);
+        let mut db = BinacleManager::create(&format!("{}.db", name), true, 1 << 30, 6, 16).unwrap();
+        for id in 1 .. 4 {
+            let path = format!("{}/{}.bin", name, id);
+            File::create(&path).unwrap().write_all(format!("GetProcAddress sample {}", id).repeat(id as usize).as_bytes()).unwrap();
+            db.insert_file(&path, id, true).unwrap();
+        }
+        let ids = db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap().ids;
+        let hits = exec::hits(&db, &ids);
+        assert_eq!(3, hits.len());
+        (db, hits)
+    }
+
+    fn read_dir_names(dir: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|n| !n.starts_with("manifest-"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn three_modes() {
+        let (db, hits) = corpus("test_copy");
+        let mut expected: Vec<String> = hits.iter().map(|h| h.sha256.clone()).collect();
+        expected.sort();
+
+        for &mode in &[Mode::Copy, Mode::Hardlink, Mode::Symlink] {
+            let dir = format!("test_copy.{}", mode.name());
+            let report = copy_hits(&hits, &dir, mode, None, "GetProcAddress").unwrap();
+            assert_eq!((3, 0, 0), (report.done, report.present, report.failed));
+            assert_eq!(expected, read_dir_names(&dir));
+            for hit in &hits {
+                let dest = Path::new(&dir).join(&hit.sha256);
+                assert_eq!(fs::read(&hit.path).unwrap(), fs::read(&dest).unwrap());
+                assert_eq!(mode == Mode::Symlink, fs::symlink_metadata(&dest).unwrap().file_type().is_symlink());
+            }
+            assert_eq!(mode == Mode::Copy, report.bytes > 0);
+
+            // again: nothing to do
+            let again = copy_hits(&hits, &dir, mode, None, "GetProcAddress").unwrap();
+            assert_eq!((0, 3, 0), (again.done, again.present, again.failed));
+            let manifest = Json::from_str(&fs::read_to_string(&again.manifest).unwrap()).unwrap();
+            assert_eq!(Some("present"), manifest.find("hits").unwrap()[0].find("status").unwrap().as_string());
+            assert_eq!(Some("GetProcAddress"), manifest.find("query").unwrap().as_string());
+            remove_dir_all(&dir).unwrap();
+        }
+
+        // a hit gone is counted, the others are done
+        let mut gone = hits.clone();
+        gone[1].path = String::from("test_copy/missing.bin");
+        let report = copy_hits(&gone, "test_copy.gone", Mode::Copy, None, "GetProcAddress").unwrap();
+        assert_eq!((2, 1), (report.done, report.failed));
+        match report.records[1].status {
+            Status::Failed(_) => {},
+            ref other => panic!("{:?}", other),
+        }
+        remove_dir_all("test_copy.gone").unwrap();
+
+        drop(db);
+        cleanup("test_copy");
+        remove_dir_all("test_copy").unwrap();
+    }
+
+    #[test]
+    fn budget_stop() {
+        let (db, hits) = corpus("test_budget");
+        let sizes: Vec<u64> = hits.iter().map(|h| fs::metadata(&h.path).unwrap().len()).collect();
+
+        // room for the first two
+        let max = sizes[0] + sizes[1];
+        let report = copy_hits(&hits, "test_budget.case", Mode::Copy, Some(max), "GetProcAddress").unwrap();
+        assert_eq!(2, report.done);
+        assert_eq!(max, report.bytes);
+        assert_eq!(Status::OverBudget, report.records[2].status);
+        assert!(report.stopped.as_ref().unwrap().contains("--max-copy-bytes"));
+        assert_eq!(2, read_dir_names("test_budget.case").len());
+
+        // the links take no space
+        let links = copy_hits(&hits, "test_budget.links", Mode::Symlink, Some(1), "GetProcAddress").unwrap();
+        assert_eq!((3, None), (links.done, links.stopped));
+
+        // the ones present are not counted: the third one now fits
+        let next = copy_hits(&hits, "test_budget.case", Mode::Copy, Some(sizes[2]), "GetProcAddress").unwrap();
+        assert_eq!((1, 2), (next.done, next.present));
+
+        drop(db);
+        cleanup("test_budget");
+        for dir in &["test_budget", "test_budget.case", "test_budget.links"] {
+            remove_dir_all(dir).unwrap();
+        }
+    }
+}
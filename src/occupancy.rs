@@ -0,0 +1,261 @@
+use std::fs::{rename, File};
+use std::io::*;
+use std::path::PathBuf;
+use std::ops::Range;
+use std::sync::Arc;
+
+// Occupancy bitmap of the header of a shard: a bit for each header slot,
+// set when a list is allocated for its n-gram. The scans of the whole
+// header (statistics, dumps, compaction) read the slots whose bit is set
+// only, skipping the empty ones a word of 64 slots at a time: most of the
+// 2^ngram_size slots are empty for a small corpus.
+//
+// The bitmap is a sidecar of the shard (<shard>.bitmap), kept in memory by
+// the writer of the shard (it is removed when the shard is opened for
+// writing) and written when the shard is flushed or closed, with a stamp
+// of its meta. A missing bitmap or one whose stamp differs (the shard
+// written by an older build, or by a process killed before it closed the
+// shard) is not used: the scans read every slot until it is rebuilt from
+// the header (--check --rebuild-bitmap). A bit stays set when a list is
+// emptied, the bitmap may only be a superset of the header.
+
+
+const MAGIC: &'static [u8; 8] = b"BNOCCUP1";
+
+// magic, slots, and the stamp
+const HEADER_LEN: usize = 32;
+
+// the meta of the shard when the bitmap was written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stamp {
+    pub size: u64,
+    pub nb_file: u32,
+    pub last_id: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Occupancy {
+    slots: u64,
+    words: Vec<u64>,
+}
+
+// a bitmap compared with the header of its shard
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitmapCheck {
+    Missing,
+    // written for another state of the shard
+    Stale,
+    // slots having a list and not set, the scans would miss them
+    Lacking(u64),
+    InSync { slots: u64 },
+}
+
+// what a scan of a header of these slots skipped, as reported
+pub fn describe_skipped(skipped: Option<u64>, slots: u64) -> String {
+    match skipped {
+        Some(skipped) => format!("{} of {} header slot(s) skipped", skipped, slots),
+        None => format!("{} header slot(s) read, no occupancy bitmap", slots),
+    }
+}
+
+pub fn bitmap_path(shard_path: &str) -> PathBuf {
+    let mut path = PathBuf::from(shard_path);
+    path.set_extension("bitmap");
+    path
+}
+
+impl Occupancy {
+
+    pub fn new(slots: u64) -> Occupancy {
+        Occupancy {
+            slots: slots,
+            words: vec![0; ((slots + 63) / 64) as usize],
+        }
+    }
+
+    pub fn slots(&self) -> u64 {
+        self.slots
+    }
+
+    pub fn is_set(&self, slot: u32) -> bool {
+        self.words[slot as usize / 64] & (1 << (slot % 64)) != 0
+    }
+
+    pub fn set(&mut self, slot: u32) {
+        self.words[slot as usize / 64] |= 1 << (slot % 64);
+    }
+
+    // slots set
+    pub fn count(&self) -> u64 {
+        self.words.iter().map(|w| w.count_ones() as u64).sum()
+    }
+
+    // the slots set, ascending
+    pub fn iter<'a>(&'a self) -> Occupied<'a> {
+        Occupied { words: &self.words, index: 0, word: self.words.first().cloned().unwrap_or(0) }
+    }
+
+    // the bitmap of a shard, None when there is none or its stamp or its
+    // number of slots are not the ones expected
+    pub fn read(shard_path: &str, slots: u64, stamp: Stamp) -> Result<Option<Occupancy>> {
+
+        let mut file = match File::open(bitmap_path(shard_path)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut header = [0u8; HEADER_LEN];
+        if file.read_exact(&mut header).is_err() || &header[.. 8] != MAGIC {
+            return Ok(None);
+        }
+        let u64_at = |i: usize| u64::from_le_bytes([header[i], header[i+1], header[i+2], header[i+3],
+                                                    header[i+4], header[i+5], header[i+6], header[i+7]]);
+        let u32_at = |i: usize| u32::from_le_bytes([header[i], header[i+1], header[i+2], header[i+3]]);
+        let read = Stamp { size: u64_at(16), nb_file: u32_at(24), last_id: u32_at(28) };
+        if u64_at(8) != slots || read != stamp {
+            return Ok(None);
+        }
+
+        let mut occupancy = Occupancy::new(slots);
+        let mut bytes = Vec::with_capacity(occupancy.words.len() * 8);
+        try!(file.read_to_end(&mut bytes));
+        if bytes.len() != occupancy.words.len() * 8 {
+            return Ok(None);
+        }
+        for (word, b) in occupancy.words.iter_mut().zip(bytes.chunks(8)) {
+            *word = u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+        }
+        Ok(Some(occupancy))
+    }
+
+    // written aside and renamed, an interrupted write leaves the previous one
+    pub fn write(&self, shard_path: &str, stamp: Stamp) -> Result<()> {
+
+        let path = bitmap_path(shard_path);
+        let mut tmp = path.clone();
+        tmp.set_extension("bitmap.tmp");
+        {
+            let mut out = BufWriter::new(try!(File::create(&tmp)));
+            try!(out.write_all(&Occupancy::header(self.slots, stamp)));
+            for word in &self.words {
+                try!(out.write_all(&word.to_le_bytes()));
+            }
+            try!(out.flush());
+        }
+        rename(&tmp, &path)
+    }
+
+    fn header(slots: u64, stamp: Stamp) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&slots.to_le_bytes());
+        header.extend_from_slice(&stamp.size.to_le_bytes());
+        header.extend_from_slice(&stamp.nb_file.to_le_bytes());
+        header.extend_from_slice(&stamp.last_id.to_le_bytes());
+        header
+    }
+}
+
+pub struct Occupied<'a> {
+    words: &'a [u64],
+    index: usize,
+    // the bits of words[index] not returned yet
+    word: u64,
+}
+
+impl<'a> Iterator for Occupied<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        next_set(self.words, &mut self.index, &mut self.word)
+    }
+}
+
+// the slots a scan of the header reads: all of them, or the ones set in the
+// bitmap. It owns the bitmap, the shard can change while it is scanned
+pub enum Slots {
+    All(Range<u64>),
+    Occupied { occupancy: Arc<Occupancy>, index: usize, word: u64 },
+}
+
+impl Slots {
+    pub fn occupied(occupancy: Arc<Occupancy>) -> Slots {
+        let word = occupancy.words.first().cloned().unwrap_or(0);
+        Slots::Occupied { occupancy: occupancy, index: 0, word: word }
+    }
+}
+
+impl Iterator for Slots {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match *self {
+            Slots::All(ref mut range) => range.next().map(|slot| slot as u32),
+            Slots::Occupied { ref occupancy, ref mut index, ref mut word } => next_set(&occupancy.words, index, word),
+        }
+    }
+}
+
+// the next bit set from the word at index, the empty words skipped whole
+fn next_set(words: &[u64], index: &mut usize, word: &mut u64) -> Option<u32> {
+    while *word == 0 {
+        *index += 1;
+        if *index >= words.len() {
+            return None;
+        }
+        *word = words[*index];
+    }
+    let bit = word.trailing_zeros();
+    *word &= *word - 1;
+    Some(*index as u32 * 64 + bit)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::remove_file;
+
+    #[test]
+    fn set_and_iterated() {
+        let mut occupancy = Occupancy::new(1 << 10);
+        assert_eq!(None, occupancy.iter().next());
+        for slot in &[0, 1, 63, 64, 500, 1023] {
+            occupancy.set(*slot);
+        }
+        occupancy.set(500);
+        assert_eq!(vec![0, 1, 63, 64, 500, 1023], occupancy.iter().collect::<Vec<u32>>());
+        assert_eq!(6, occupancy.count());
+        assert!(occupancy.is_set(63) && !occupancy.is_set(62));
+
+        let slots: Vec<u32> = Slots::occupied(Arc::new(occupancy)).collect();
+        assert_eq!(vec![0, 1, 63, 64, 500, 1023], slots);
+        assert_eq!(3, Slots::All(4 .. 7).count());
+
+        // the last slots of a header of 2^32 slots
+        let top: Vec<u32> = Slots::All((1u64 << 32) - 2 .. 1u64 << 32).collect();
+        assert_eq!(vec![u32::max_value() - 1, u32::max_value()], top);
+        let mut widest = Occupancy::new(1u64 << 32);
+        widest.set(u32::max_value());
+        assert_eq!(vec![u32::max_value()], widest.iter().collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn stamped() {
+        let stamp = Stamp { size: 1 << 20, nb_file: 3, last_id: 7 };
+        let mut occupancy = Occupancy::new(1 << 12);
+        occupancy.set(42);
+        occupancy.write("test_occupancy.db", stamp).unwrap();
+
+        assert_eq!(Some(occupancy.clone()), Occupancy::read("test_occupancy.db", 1 << 12, stamp).unwrap());
+        // the shard changed since, or another size of header
+        let later = Stamp { nb_file: 4, ..stamp };
+        assert_eq!(None, Occupancy::read("test_occupancy.db", 1 << 12, later).unwrap());
+        assert_eq!(None, Occupancy::read("test_occupancy.db", 1 << 13, stamp).unwrap());
+
+        occupancy.write("test_occupancy.db", later).unwrap();
+        assert_eq!(Some(occupancy), Occupancy::read("test_occupancy.db", 1 << 12, later).unwrap());
+        assert_eq!(None, Occupancy::read("test_occupancy_none.db", 1 << 12, stamp).unwrap());
+        let _ = remove_file("test_occupancy.bitmap");
+    }
+}
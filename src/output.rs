@@ -0,0 +1,678 @@
+use std::collections::{HashMap, BTreeMap};
+use std::io::{self, IsTerminal};
+use std::time::{Duration, Instant};
+use rustc_serialize::json::{Json, ToJson};
+
+use rustc_serialize::hex::ToHex;
+
+use binacle::aggregate::dir_prefix;
+use binacle::binacle_manager::{FileInsertion, InsertEvent, InsertOutcome, MapEntry, Note, ShardWarning};
+use binacle::context::Context;
+use binacle::search::SearchResponse;
+use binacle::provenance::{Coverage, WhyNot};
+use binacle::exec::ExecSummary;
+use binacle::materialize::{CopyReport, Status};
+pub use binacle::dates::format_date;
+
+use style::{self, Style};
+use config::{Config, Source};
+
+// Search results printing helpers
+
+
+// hits sharing the same directory prefix
+pub struct DirGroup<'a> {
+    pub prefix: String,
+    pub count: usize,
+    pub files: Vec<&'a str>,
+}
+
+// group paths by directory prefix, the biggest groups first
+// paths are borrowed so large result sets are not copied
+pub fn group_by_dir<'a, I>(paths: I, depth: usize, keep_files: bool) -> Vec<DirGroup<'a>>
+    where I: IntoIterator<Item=&'a str> {
+
+    let mut groups: HashMap<String, DirGroup<'a>> = HashMap::new();
+
+    for path in paths {
+        let prefix = dir_prefix(path, depth);
+        let group = groups.entry(prefix.clone()).or_insert(DirGroup {
+            prefix: prefix,
+            count: 0,
+            files: Vec::new(),
+        });
+        group.count += 1;
+        if keep_files {
+            group.files.push(path);
+        }
+    }
+
+    let mut groups: Vec<DirGroup> = groups.into_iter().map(|x| x.1).collect();
+    groups.sort_by(|a, b| b.count.cmp(&a.count).then(a.prefix.cmp(&b.prefix)));
+    for g in &mut groups {
+        g.files.sort();
+    }
+    groups
+}
+
+pub fn print_groups(groups: &[DirGroup], show_files: bool, as_json: bool, response: &SearchResponse) {
+
+    let total: usize = groups.iter().map(|g| g.count).sum();
+
+    if as_json {
+        let mut obj = BTreeMap::new();
+        for g in groups {
+            let mut group = BTreeMap::new();
+            group.insert(String::from("count"), g.count.to_json());
+            if show_files {
+                let files = g.files.iter().map(|f| Json::String(f.to_string())).collect();
+                group.insert(String::from("files"), Json::Array(files));
+            }
+            obj.insert(g.prefix.clone(), Json::Object(group));
+        }
+        let mut root = BTreeMap::new();
+        root.insert(String::from("total"), total.to_json());
+        root.insert(String::from("groups"), Json::Object(obj));
+        if response.partial {
+            root.insert(String::from("partial"), true.to_json());
+            root.insert(String::from("skipped_shards"), response.skipped_shards.to_json());
+        }
+        if !response.warnings.is_empty() {
+            let failed = response.warnings.iter().map(|w| {
+                let mut obj = BTreeMap::new();
+                obj.insert(String::from("shard"), w.shard.to_json());
+                obj.insert(String::from("path"), w.path.to_json());
+                obj.insert(String::from("error"), w.error.to_json());
+                Json::Object(obj)
+            }).collect();
+            root.insert(String::from("failed_shards"), Json::Array(failed));
+        }
+        println!("{}", Json::Object(root));
+        return;
+    }
+
+    for g in groups {
+        println!("{}\t{}", g.count, g.prefix);
+        if show_files {
+            for f in &g.files {
+                println!("    {}", f);
+            }
+        }
+    }
+    println!("{} result(s) in {} group(s)", total, groups.len());
+}
+
+// group and count of --aggregate, the biggest groups first
+pub fn print_counts(counts: &[(String, usize)], as_json: bool, response: &SearchResponse) {
+
+    if as_json {
+        let mut groups = BTreeMap::new();
+        for &(ref group, count) in counts {
+            groups.insert(group.clone(), count.to_json());
+        }
+        let mut root = BTreeMap::new();
+        root.insert(String::from("total"), response.ids.len().to_json());
+        root.insert(String::from("groups"), Json::Object(groups));
+        if response.truncated {
+            root.insert(String::from("truncated"), true.to_json());
+        }
+        println!("{}", Json::Object(root));
+        return;
+    }
+
+    for &(ref group, count) in counts {
+        println!("{}\t{}", count, group);
+    }
+    match response.truncated {
+        true => println!("{} result(s) counted of {} in {} group(s)", response.ids.len(), response.total, counts.len()),
+        false => println!("{} result(s) in {} group(s)", response.ids.len(), counts.len()),
+    }
+}
+
+// JSON array of the hits with the byte order or the variants that matched
+pub fn print_orientations(response: &SearchResponse, with_paths: bool) {
+
+    let mut hits = Vec::with_capacity(response.ids.len());
+    for (i, id) in response.ids.iter().enumerate() {
+        let mut obj = BTreeMap::new();
+        if with_paths {
+            obj.insert(String::from("path"), response.entries[i].to_json());
+        } else {
+            obj.insert(String::from("id"), id.to_json());
+        }
+        if let Some(o) = response.orientations.get(id) {
+            obj.insert(String::from("orientation"), o.name().to_json());
+        }
+        if let Some(v) = response.variants.get(id) {
+            obj.insert(String::from("variants"), v.to_json());
+        }
+        hits.push(Json::Object(obj));
+    }
+    println!("{}", Json::Array(hits));
+}
+
+// the hits of a search with --json: their ids, their paths (none without
+// a map) and how many matched before the sample and the limit, with the
+// shards of a dirty read and the files a verified search could not read
+pub fn hits_json(response: &SearchResponse) -> Json {
+    let mut root = BTreeMap::new();
+    root.insert(String::from("ids"), response.ids.to_json());
+    root.insert(String::from("paths"), response.entries.to_json());
+    root.insert(String::from("count"), response.total.to_json());
+    if !response.dirty_shards.is_empty() {
+        let shards: Vec<usize> = response.dirty_shards.iter().map(|d| d.0).collect();
+        root.insert(String::from("dirty_shards"), shards.to_json());
+    }
+    if response.false_positives > 0 {
+        root.insert(String::from("false_positives"), response.false_positives.to_json());
+    }
+    if !response.unverifiable.is_empty() {
+        let unverifiable = response.unverifiable.iter().map(|&(id, ref error)| {
+            let mut obj = BTreeMap::new();
+            obj.insert(String::from("id"), id.to_json());
+            obj.insert(String::from("error"), error.to_json());
+            Json::Object(obj)
+        }).collect();
+        root.insert(String::from("unverifiable"), Json::Array(unverifiable));
+    }
+    Json::Object(root)
+}
+
+// a file of an insertion with --json, error is null when it was inserted
+pub fn insertion_json(insertion: &FileInsertion) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert(String::from("id"), insertion.id.to_json());
+    obj.insert(String::from("path"), insertion.path.to_json());
+    obj.insert(String::from("status"), insertion.outcome.status().to_json());
+    obj.insert(String::from("error"), insertion.outcome.error().to_json());
+    if let Some(size) = insertion.size {
+        obj.insert(String::from("size"), size.to_json());
+    }
+    Json::Object(obj)
+}
+
+// the files of an insertion: an object a line with --json, the ones not
+// inserted otherwise. The counts go to stderr, with the bytes when the
+// sizes of the files are known. Quiet, only the failed files are told
+pub fn print_insertions(insertions: &[FileInsertion], as_json: bool, quiet: bool) {
+    let mut counts = [0; 3];
+    let mut bytes = [0u64; 2];
+    for insertion in insertions {
+        match insertion.outcome {
+            InsertOutcome::Inserted(_) => counts[0] += 1,
+            InsertOutcome::Skipped(_) => counts[1] += 1,
+            InsertOutcome::Failed(_) => counts[2] += 1,
+        }
+        match insertion.outcome {
+            InsertOutcome::Inserted(_) => bytes[0] += insertion.size.unwrap_or(0),
+            _ => bytes[1] += insertion.size.unwrap_or(0),
+        }
+        if as_json {
+            println!("{}", insertion_json(insertion));
+        } else if let Some(error) = insertion.outcome.error() {
+            if !quiet || insertion.outcome.status() == "failed" {
+                println!("{}:\"Error {}\"", insertion.id, error);
+            }
+        }
+    }
+    if quiet {
+        return;
+    }
+    eprintln!("{} file(s) inserted, {} skipped, {} failed", counts[0], counts[1], counts[2]);
+    if insertions.iter().any(|i| i.size.is_some()) {
+        eprintln!("{} byte(s) indexed, {} byte(s) not inserted", bytes[0], bytes[1]);
+    }
+}
+
+// the line of an insertion on stderr (--progress): the files and the bytes
+// done of the ones listed, their rates and the time left at the rate of the
+// bytes. Redrawn in place on a terminal, a line every PROGRESS_LOG_STEP
+// otherwise (a log)
+pub struct Progress {
+    start: Instant,
+    drawn: Option<Instant>,
+    tty: bool,
+    files: usize,
+    bytes: u64,
+    done_files: usize,
+    done_bytes: u64,
+}
+
+const PROGRESS_STEP: Duration = Duration::from_millis(500);
+const PROGRESS_LOG_STEP: Duration = Duration::from_secs(10);
+
+impl Progress {
+
+    pub fn new() -> Progress {
+        Progress {
+            start: Instant::now(),
+            drawn: None,
+            tty: io::stderr().is_terminal(),
+            files: 0,
+            bytes: 0,
+            done_files: 0,
+            done_bytes: 0,
+        }
+    }
+
+    pub fn event(&mut self, event: &InsertEvent) {
+        match *event {
+            InsertEvent::Listed { files, bytes, .. } => {
+                self.files = files;
+                self.bytes = bytes;
+                // the time of the listing is not the one of the insertion
+                self.start = Instant::now();
+            },
+            InsertEvent::Known(_) => return,
+            InsertEvent::Done(insertion) => {
+                self.done_files += 1;
+                self.done_bytes += insertion.size.unwrap_or(0);
+            },
+        }
+        let step = if self.tty { PROGRESS_STEP } else { PROGRESS_LOG_STEP };
+        if self.drawn.map_or(true, |drawn| drawn.elapsed() >= step) {
+            self.draw();
+        }
+    }
+
+    fn draw(&mut self) {
+        let line = self.line(self.start.elapsed());
+        match self.tty {
+            true => eprint!("\r{}\x1b[K", line),
+            false => eprintln!("{}", line),
+        }
+        self.drawn = Some(Instant::now());
+    }
+
+    // the last state, the line ended
+    pub fn finish(&mut self) {
+        self.draw();
+        if self.tty {
+            eprintln!();
+        }
+    }
+
+    pub fn line(&self, elapsed: Duration) -> String {
+        let seconds = elapsed.as_secs_f64();
+        let mb = |bytes: u64| bytes as f64 / (1 << 20) as f64;
+        let (files_rate, bytes_rate) = match seconds > 0.0 {
+            true => (self.done_files as f64 / seconds, self.done_bytes as f64 / seconds),
+            false => (0.0, 0.0),
+        };
+        let left = match (self.bytes.saturating_sub(self.done_bytes), self.files.saturating_sub(self.done_files)) {
+            (_, 0) => Some(0.0),
+            (bytes, _) if bytes > 0 && bytes_rate > 0.0 => Some(bytes as f64 / bytes_rate),
+            (_, files) if files_rate > 0.0 => Some(files as f64 / files_rate),
+            _ => None,
+        };
+        format!("{}/{} file(s), {:.1}/{:.1} MB, {:.1} files/s, {:.1} MB/s, ETA {}",
+                self.done_files, self.files, mb(self.done_bytes), mb(self.bytes), files_rate, mb(bytes_rate as u64),
+                left.map_or(String::from("?"), |s| {
+                    let s = s.ceil() as u64;
+                    format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+                }))
+    }
+}
+
+// a file of --watch once inserted: an object with --json, or
+// <id>\t<status>\t<path>, the reason it was not inserted after
+pub fn print_insertion(insertion: &FileInsertion, as_json: bool) {
+    if as_json {
+        println!("{}", insertion_json(insertion));
+        return;
+    }
+    match insertion.outcome.error() {
+        Some(error) => println!("{}\t{}\t{}\t{}", insertion.id, insertion.outcome.status(), insertion.path, error),
+        None => println!("{}\t{}\t{}", insertion.id, insertion.outcome.status(), insertion.path),
+    }
+}
+
+// shards left unsearched by the deadline
+pub fn partial_note(skipped: &[usize]) -> String {
+    let shards: Vec<String> = skipped.iter().map(|s| s.to_string()).collect();
+    format!("partial result: shard(s) {} not searched (deadline)", shards.join(","))
+}
+
+// a shard that could not be searched
+pub fn shard_warning_note(warning: &ShardWarning) -> String {
+    format!("warning: shard {} ({}) not searched: {}", warning.shard, warning.path, warning.error)
+}
+
+// the ids of --any and the indexes of their patterns, a line for each pair
+pub fn print_any(found: &HashMap<u32, Vec<usize>>) {
+    let mut ids: Vec<&u32> = found.keys().collect();
+    ids.sort();
+    for id in ids {
+        for index in &found[id] {
+            println!("{}\t{}", id, index);
+        }
+    }
+}
+
+// the effective options in the syntax of the config file, the source of
+// each one in a comment
+pub fn print_config(config: &Config, effective: &[(String, String, Source)]) {
+    match config.path {
+        Some(ref path) => println!("# config file: {}", path.display()),
+        None => println!("# no config file"),
+    }
+    for &(ref key, ref value, ref source) in effective {
+        let value = match value.as_str() {
+            "true" => value.clone(),
+            _ if value.parse::<f64>().is_ok() => value.clone(),
+            _ => format!("{:?}", value),
+        };
+        let source = match *source {
+            Source::CommandLine => String::from("command line"),
+            Source::File(ref path) => path.display().to_string(),
+            Source::Default => String::from("default"),
+        };
+        println!("{} = {}  # {}", key, value, source);
+    }
+}
+
+// the runs of --exec and how they ended
+pub fn exec_summary_line(summary: &ExecSummary) -> String {
+    let mut ends: Vec<String> = summary.codes.iter().map(|(code, n)| format!("exit {}: {}", code, n)).collect();
+    for &(n, what) in &[(summary.timed_out, "timed out"), (summary.signaled, "killed"), (summary.not_started, "not started")] {
+        if n > 0 {
+            ends.push(format!("{}: {}", what, n));
+        }
+    }
+    format!("exec: {} run(s), {} failed ({})", summary.runs, summary.failed(), ends.join(", "))
+}
+
+pub fn print_exec_summary(summary: &ExecSummary) {
+    eprintln!("{}", exec_summary_line(summary));
+}
+
+// the failures of --copy-to, then what was done
+pub fn print_copy_report(report: &CopyReport) {
+    for record in &report.records {
+        if let Status::Failed(ref e) = record.status {
+            style::warning(format!("{} ({}) not copied: {}", record.id, record.source, e));
+        }
+    }
+    if let Some(ref stopped) = report.stopped {
+        style::warning(stopped);
+    }
+    eprintln!("copy: {} done, {} already there, {} failed, {} byte(s) copied, manifest {}",
+              report.done, report.present, report.failed, report.bytes, report.manifest.display());
+}
+
+// everything the map knows about an id
+// the n-grams of the pattern and the presence of the id in their list
+pub fn print_why_not(id: u32, report: &WhyNot) {
+    println!("bytes\tn-gram\tin list of {}", id);
+    for check in &report.checks {
+        println!("{}..{}\t{:#010x}\t{}", check.offset, check.offset + 4, check.ngram,
+                 if check.present { "yes" } else { "no" });
+    }
+    match report.first_missing() {
+        Some(check) => println!("first missing n-gram: pattern bytes {}..{}", check.offset, check.offset + 4),
+        None => println!("all the n-grams hold the id"),
+    }
+    match report.explanation {
+        Some(ref reason) => println!("{}", reason),
+        None => println!("found by the index"),
+    }
+}
+
+// one character per cell of the positions: # covered, . skipped, + both
+pub fn coverage_bar(covered: &[(u64, u64)], positions: u64, width: usize) -> String {
+    (0 .. width as u64).map(|i| {
+        let start = i * positions / width as u64;
+        let end = ((i + 1) * positions / width as u64).max(start + 1).min(positions);
+        let inside: u64 = covered.iter()
+            .map(|&(s, e)| e.min(end).saturating_sub(s.max(start)))
+            .sum();
+        match inside {
+            0 => '.',
+            n if n >= end.saturating_sub(start) => '#',
+            _ => '+',
+        }
+    }).collect()
+}
+
+pub fn print_coverage(coverage: &Coverage, bar: bool) {
+    println!("id: {}", coverage.id);
+    println!("path: {}", coverage.path);
+    if coverage.translated {
+        println!("the database is translated, the positions are the ones of the translated content");
+    }
+    let covered = coverage.covered();
+    let positions = coverage.positions();
+    let nb_covered: u64 = covered.iter().map(|&(s, e)| e - s).sum();
+    let percent = match positions {
+        0 => 100.0,
+        n => 100.0 * nb_covered as f64 / n as f64,
+    };
+    println!("coverage: {:.2}% ({} of {} n-gram position(s))", percent, nb_covered, positions);
+    for &(start, end) in &covered {
+        println!("covered: {}..{}", start, end);
+    }
+    for r in &coverage.current.skipped {
+        println!("skipped: {}..{} ({})", r.offset, r.offset + r.len, r.reason);
+    }
+    if bar && positions > 0 {
+        println!("[{}]", coverage_bar(&covered, positions, 64));
+    }
+    if coverage.stored.is_none() {
+        println!("no provenance recorded when indexed, only the sha256 is compared");
+    }
+    for change in &coverage.changes {
+        println!("changed: {}", change);
+    }
+}
+
+pub fn print_info(id: u32, entry: &MapEntry, tombstoned: bool) {
+    println!("id: {}", id);
+    if let Some(ref label) = entry.label {
+        println!("label: {}", label);
+    }
+    println!("path: {}", entry.path);
+    if let Some(ref file_type) = entry.file_type {
+        println!("type: {}", file_type);
+    }
+    if let Some(ref tags) = entry.tags {
+        println!("tags: {}", tags.join(","));
+    }
+    if let Some(ref sha256) = entry.sha256 {
+        println!("sha256: {}", sha256);
+    }
+    if let Some(date) = entry.date {
+        println!("date: {}", format_date(date));
+    }
+    if let Some(ref p) = entry.provenance {
+        if !p.transforms.is_empty() {
+            println!("transforms: {}", p.transforms.join(","));
+        }
+        println!("coverage: {:.2}% ({} of {} byte(s) skipped)", 100.0 * p.coverage(), p.skipped_bytes(), p.size);
+        if let (Some(ngrams), Some(writes), Some(ratio)) = (p.ngrams, p.writes, p.dedup_ratio()) {
+            println!("n-grams: {} read, {} written (dedup ratio {:.1})", ngrams, writes, ratio);
+        }
+        for r in &p.skipped {
+            println!("skipped: {}..{} ({})", r.offset, r.offset + r.len, r.reason);
+        }
+    }
+    for note in entry.notes.iter().flat_map(|n| n.iter()) {
+        println!("note: {}", format_note(note));
+    }
+    if tombstoned {
+        println!("tombstoned: yes");
+    }
+}
+
+// date and text of a note, its next lines indented
+pub fn format_note(note: &Note) -> String {
+    let date = note.date.map(format_date).unwrap_or(String::from("-"));
+    format!("{} {}", date, note.text.trim_end().replace('\n', "\n    "))
+}
+
+// classic hex + ASCII dump of a context, 16 bytes a line,
+// the matched bytes are put between brackets, highlighted in color
+pub fn hex_dump(ctx: &Context, style: &Style) -> Vec<String> {
+
+    let start = ctx.offset - ctx.before.len() as u64;
+    let m_start = ctx.before.len();
+    let m_end = m_start + ctx.matched.len();
+    let bytes: Vec<u8> = ctx.before.iter().chain(&ctx.matched).chain(&ctx.after).cloned().collect();
+
+    let mut lines = Vec::new();
+    for (l, chunk) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        // the colors take no room, the hex column is padded on its width
+        let mut width = 0;
+        for (i, b) in chunk.iter().enumerate() {
+            let pos = l * 16 + i;
+            let matched = pos >= m_start && pos < m_end;
+            let c = if *b >= 0x20 && *b < 0x7f { *b as char } else { '.' };
+            // a match ending with the previous line was closed by it
+            hex.push(if pos == m_start { '[' } else if pos == m_end && i > 0 { ']' } else { ' ' });
+            match matched {
+                true => {
+                    hex.push_str(&style.highlight(&format!("{:02x}", b)));
+                    ascii.push_str(&style.highlight(&c.to_string()));
+                },
+                false => {
+                    hex.push_str(&format!("{:02x}", b));
+                    ascii.push(c);
+                },
+            }
+            width += 3;
+        }
+        let last = l * 16 + chunk.len();
+        hex.push(if last == m_end { ']' } else { ' ' });
+        width += 1;
+        hex.push_str(&" ".repeat(49usize.saturating_sub(width)));
+        lines.push(format!("{:08x} {} |{}|", start + (l * 16) as u64, hex, ascii));
+    }
+    lines
+}
+
+// contexts of the hits, hex strings in JSON
+pub fn print_contexts(hits: &[(&str, Vec<Context>)], as_json: bool, style: &Style) {
+
+    if as_json {
+        let mut files = Vec::with_capacity(hits.len());
+        for &(path, ref contexts) in hits {
+            let occurrences = contexts.iter().map(|c| {
+                let mut obj = BTreeMap::new();
+                obj.insert(String::from("offset"), c.offset.to_json());
+                obj.insert(String::from("before"), c.before.to_hex().to_json());
+                obj.insert(String::from("match"), c.matched.to_hex().to_json());
+                obj.insert(String::from("after"), c.after.to_hex().to_json());
+                Json::Object(obj)
+            }).collect();
+            let mut obj = BTreeMap::new();
+            obj.insert(String::from("path"), path.to_json());
+            obj.insert(String::from("occurrences"), Json::Array(occurrences));
+            files.push(Json::Object(obj));
+        }
+        println!("{}", Json::Array(files));
+        return;
+    }
+
+    for &(path, ref contexts) in hits {
+        println!("{}", path);
+        for c in contexts {
+            println!("  offset 0x{:x}", c.offset);
+            for line in hex_dump(c, style) {
+                println!("    {}", line);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn progress_line() {
+        let mut progress = Progress::new();
+        progress.files = 4;
+        progress.bytes = 4 << 20;
+        assert_eq!("0/4 file(s), 0.0/4.0 MB, 0.0 files/s, 0.0 MB/s, ETA ?", progress.line(Duration::from_secs(0)));
+        progress.done_files = 1;
+        progress.done_bytes = 1 << 20;
+        assert_eq!("1/4 file(s), 1.0/4.0 MB, 0.5 files/s, 0.5 MB/s, ETA 0:00:06", progress.line(Duration::from_secs(2)));
+        progress.done_files = 4;
+        progress.done_bytes = 4 << 20;
+        assert_eq!("4/4 file(s), 4.0/4.0 MB, 0.0 files/s, 0.0 MB/s, ETA 0:00:00", progress.line(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn exec_summary() {
+        let mut summary = ExecSummary { runs: 6, timed_out: 1, ..ExecSummary::default() };
+        summary.codes.insert(0, 3);
+        summary.codes.insert(2, 2);
+        assert_eq!("exec: 6 run(s), 3 failed (exit 0: 3, exit 2: 2, timed out: 1)", exec_summary_line(&summary));
+    }
+
+    #[test]
+    fn bar_cells() {
+        assert_eq!("####", coverage_bar(&[(0, 8)], 8, 4));
+        assert_eq!("#+..", coverage_bar(&[(0, 3)], 8, 4));
+        assert_eq!(".##.", coverage_bar(&[(2, 6)], 8, 4));
+        // fewer positions than cells
+        assert_eq!("#.", coverage_bar(&[(0, 1)], 2, 2));
+        assert_eq!("##", coverage_bar(&[(0, 1)], 1, 2));
+    }
+
+    #[test]
+    fn dump_delimits_match() {
+        let ctx = Context {
+            offset: 2,
+            before: b"ab".to_vec(),
+            matched: b"XX".to_vec(),
+            after: vec![0; 14],
+        };
+        let lines = hex_dump(&ctx, &Style::plain());
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("00000000  61 62[58 58]00 00"));
+        assert!(lines[0].ends_with("|abXX............|"));
+        assert!(lines[1].starts_with("00000010  00 00 "));
+
+        // on a terminal, the same dump once the colors are taken out
+        let style = Style { color: true, aligned: true, width: Some(80) };
+        let colored = hex_dump(&ctx, &style);
+        assert!(colored[0].contains("[\x1b[1;7m58\x1b[0m \x1b[1;7m58\x1b[0m]00"));
+        assert_eq!(lines, colored.iter().map(|l| l.replace("\x1b[1;7m", "").replace("\x1b[0m", "")).collect::<Vec<_>>());
+
+        // closed once when it ends a line
+        let ctx = Context { offset: 14, before: b"ab".to_vec(), matched: vec![0x58; 14], after: b"cd".to_vec() };
+        let lines = hex_dump(&ctx, &Style::plain());
+        assert!(lines[0].ends_with("58 58] |abXXXXXXXXXXXXXX|"), "{}", lines[0]);
+        assert!(lines[1].starts_with("0000001c  63 64 "), "{}", lines[1]);
+        assert_eq!(1, lines.iter().map(|l| l.matches(']').count()).sum::<usize>());
+    }
+
+    #[test]
+    fn insertion_objects() {
+        let inserted = FileInsertion { id: 3, path: String::from("a.bin"), outcome: InsertOutcome::Inserted(3), size: None };
+        assert_eq!(r#"{"error":null,"id":3,"path":"a.bin","status":"inserted"}"#, insertion_json(&inserted).to_string());
+        let skipped = FileInsertion { id: 4, path: String::from("b.bin"), outcome: InsertOutcome::Skipped(String::from("quarantined")), size: Some(12) };
+        assert_eq!(r#"{"error":"quarantined","id":4,"path":"b.bin","size":12,"status":"skipped"}"#, insertion_json(&skipped).to_string());
+    }
+
+    #[test]
+    fn groups_sorted() {
+        let paths = vec!["/i/2024/a", "/c/p/b", "/c/p/c", "/c/q/d"];
+        let groups = group_by_dir(paths.iter().cloned(), 1, true);
+        assert_eq!(2, groups.len());
+        assert_eq!("/c/", groups[0].prefix);
+        assert_eq!(3, groups[0].count);
+        assert_eq!(vec!["/c/p/b", "/c/p/c", "/c/q/d"], groups[0].files);
+        assert_eq!("/i/", groups[1].prefix);
+
+        let groups = group_by_dir(paths.iter().cloned(), 2, false);
+        assert_eq!(3, groups.len());
+        assert_eq!("/c/p/", groups[0].prefix);
+        assert_eq!(2, groups[0].count);
+        assert!(groups[0].files.is_empty());
+    }
+}
@@ -0,0 +1,350 @@
+use std::io::*;
+
+use binacle::BinacleFile;
+use binacle_manager::BinacleManager;
+use occupancy::Occupancy;
+use search::Rng;
+
+// Estimated overlap of the n-gram vocabularies of two databases: how much
+// of two collections is the same data, before merging or deduplicating
+// them. The vocabulary of a database is the set of its occupied header
+// slots (the reduced n-grams having a list in a shard at least), counted
+// from the occupancy bitmaps of its shards (a scan of the header for the
+// shards without one). Slots are drawn from the smaller vocabulary and
+// looked up in the other database: the share found estimates their
+// containment, turned into a Jaccard index by the sizes of both.
+//
+// The interval is the Wilson score interval of the containment at 95%,
+// with the correction of a finite population: the whole vocabulary drawn
+// gives the exact value. Its bounds are mapped to the Jaccard index, which
+// grows with the containment. The shared bytes are the containment times
+// the bytes indexed in the smaller database, a rough figure: the n-grams
+// of a file are not all shared or all distinct.
+
+
+// z of the two-sided 95% interval
+const Z: f64 = 1.96;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Side::A => "a",
+            Side::B => "b",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overlap {
+    // occupied slots of each database
+    pub vocabulary_a: u64,
+    pub vocabulary_b: u64,
+    // the database drawn from, the smaller vocabulary
+    pub sampled: Side,
+    pub samples: u64,
+    // drawn slots occupied in the other database
+    pub hits: u64,
+    pub jaccard: f64,
+    pub interval: (f64, f64),
+    pub shared_bytes: f64,
+    // bytes indexed in the sampled database
+    pub sampled_bytes: f64,
+    // shards without an occupancy bitmap, their header read whole
+    pub scanned_shards: usize,
+}
+
+impl Overlap {
+    pub fn containment(&self) -> f64 {
+        match self.samples {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+// the slots of a database and of another one name the same n-grams
+pub fn check_comparable(a: &BinacleManager, b: &BinacleManager) -> Result<()> {
+
+    let (size_a, size_b) = (a.ngram_size(), b.ngram_size());
+    if size_a != size_b {
+        return Err(Error::new(ErrorKind::InvalidInput, format!(
+            "the n-grams are reduced to {} bits in a and to {} bits in b, a slot does not name the same \
+             n-grams in both: reindex the one of {} bits to {} (--reindex <dst_db> --ngram-size={}) and compare it",
+            size_a, size_b, size_a.max(size_b), size_a.min(size_b), size_a.min(size_b))));
+    }
+    if a.translation() != b.translation() {
+        let describe = |db: &BinacleManager| match db.translation() {
+            Some(_) => "translated (--translate)",
+            None => "not translated",
+        };
+        let how = match (a.translation().is_some(), b.translation().is_some()) {
+            (true, true) => String::from("translated by different tables"),
+            _ => format!("{} in a, {} in b", describe(a), describe(b)),
+        };
+        return Err(Error::new(ErrorKind::InvalidInput, format!(
+            "the bytes are {} before they are cut in n-grams: the same data gives other n-grams, \
+             build both databases with the same --translate", how)));
+    }
+    Ok(())
+}
+
+// the slots having a list in a shard at least, and the number of shards
+// read without a bitmap
+pub fn vocabulary(shards: &[BinacleFile], ngram_size: u8) -> (Occupancy, usize) {
+
+    let mut occupancy = Occupancy::new(1u64 << ngram_size);
+    let mut scanned = 0;
+    for shard in shards {
+        if shard.header_skipped().is_none() {
+            scanned += 1;
+        }
+        for slot in shard.scan_slots() {
+            if !occupancy.is_set(slot) && shard.has_list(slot) {
+                occupancy.set(slot);
+            }
+        }
+    }
+    (occupancy, scanned)
+}
+
+// n slots set drawn at random (all of them when there are fewer), by
+// reservoir sampling, sorted
+pub fn sample_slots(occupancy: &Occupancy, n: usize, rng: &mut Rng) -> Vec<u32> {
+
+    let mut sample = Vec::with_capacity(n);
+    for (i, slot) in occupancy.iter().enumerate() {
+        if i < n {
+            sample.push(slot);
+            continue;
+        }
+        let j = rng.below(i as u64 + 1) as usize;
+        if j < n {
+            sample[j] = slot;
+        }
+    }
+    sample.sort();
+    sample
+}
+
+// Wilson score interval of a proportion of hits in a sample drawn without
+// replacement from a population, at 95%
+pub fn wilson(hits: u64, samples: u64, population: u64) -> (f64, f64) {
+
+    if samples == 0 {
+        return (0.0, 1.0);
+    }
+    let p = hits as f64 / samples as f64;
+    if samples >= population {
+        return (p, p);
+    }
+    // the sample size giving the same variance with replacement
+    let n = samples as f64 * (population as f64 - 1.0) / (population - samples) as f64;
+    let z2 = Z * Z;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half = Z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / (1.0 + z2 / n);
+    ((center - half).max(0.0), (center + half).min(1.0))
+}
+
+// Jaccard index of two sets from the share of the first found in the second
+pub fn jaccard(containment: f64, size_small: u64, size_large: u64) -> f64 {
+
+    let shared = containment * size_small as f64;
+    let union = size_small as f64 + size_large as f64 - shared;
+    match union > 0.0 {
+        true => shared / union,
+        false => 0.0,
+    }
+}
+
+// bytes of the files indexed in the shards
+fn indexed_bytes(shards: &[BinacleFile]) -> f64 {
+    shards.iter().map(|s| s.average_size() * s.nb_file() as f64).sum()
+}
+
+pub fn estimate(a: &mut BinacleManager, b: &mut BinacleManager, samples: usize, seed: u64) -> Result<Overlap> {
+
+    try!(check_comparable(a, b));
+    let ngram_size = a.ngram_size();
+    let shards_a = try!(a.open_indexes());
+    let shards_b = try!(b.open_indexes());
+
+    let (vocabulary_a, scanned_a) = vocabulary(&shards_a, ngram_size);
+    let (vocabulary_b, scanned_b) = vocabulary(&shards_b, ngram_size);
+    let (count_a, count_b) = (vocabulary_a.count(), vocabulary_b.count());
+    if count_a == 0 && count_b == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "no n-gram is indexed in either database"));
+    }
+
+    let (sampled, small, other, small_shards) = match count_a <= count_b {
+        true => (Side::A, vocabulary_a, &shards_b, &shards_a),
+        false => (Side::B, vocabulary_b, &shards_a, &shards_b),
+    };
+    let sample = sample_slots(&small, samples, &mut Rng(seed));
+    let hits = sample.iter().filter(|&&slot| other.iter().any(|shard| shard.has_list(slot))).count() as u64;
+
+    let (size_small, size_large) = (count_a.min(count_b), count_a.max(count_b));
+    let containment = match sample.len() {
+        0 => 0.0,
+        n => hits as f64 / n as f64,
+    };
+    let (low, high) = wilson(hits, sample.len() as u64, size_small);
+    let sampled_bytes = indexed_bytes(small_shards);
+    Ok(Overlap {
+        vocabulary_a: count_a,
+        vocabulary_b: count_b,
+        sampled: sampled,
+        samples: sample.len() as u64,
+        hits: hits,
+        jaccard: jaccard(containment, size_small, size_large),
+        interval: (jaccard(low, size_small, size_large), jaccard(high, size_small, size_large)),
+        shared_bytes: containment * sampled_bytes,
+        sampled_bytes: sampled_bytes,
+        scanned_shards: scanned_a + scanned_b,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle_manager::tests::cleanup;
+
+    // the overlap computed from every slot of the header
+    fn exact_jaccard(a: &mut BinacleManager, b: &mut BinacleManager) -> f64 {
+        let shards_a = a.open_indexes().unwrap();
+        let shards_b = b.open_indexes().unwrap();
+        let (mut shared, mut union) = (0u64, 0u64);
+        for slot in (0 .. 1u64 << a.ngram_size()).map(|s| s as u32) {
+            let in_a = shards_a.iter().any(|s| s.has_list(slot));
+            let in_b = shards_b.iter().any(|s| s.has_list(slot));
+            shared += (in_a && in_b) as u64;
+            union += (in_a || in_b) as u64;
+        }
+        shared as f64 / union as f64
+    }
+
+    // a few files of pseudo-random bytes, the common ones shared by both
+    fn fixtures(name_a: &str, name_b: &str, max_size: u64) -> (BinacleManager, BinacleManager) {
+        let mut rng = Rng(7);
+        let mut file = |len: usize| (0 .. len).map(|_| rng.next() as u8).collect::<Vec<u8>>();
+        let common: Vec<Vec<u8>> = (0 .. 3).map(|_| file(300)).collect();
+        let only_a: Vec<Vec<u8>> = (0 .. 2).map(|_| file(200)).collect();
+        let only_b: Vec<Vec<u8>> = (0 .. 5).map(|_| file(400)).collect();
+
+        let mut a = BinacleManager::create(&format!("{}.db", name_a), false, max_size, 6, 16).unwrap();
+        let mut b = BinacleManager::create(&format!("{}.db", name_b), false, 1 << 30, 6, 16).unwrap();
+        for (id, content) in common.iter().chain(&only_a).enumerate() {
+            a.insert_reader(&content[..], "a.bin", id as u32 + 1, false).into_result().unwrap();
+        }
+        for (id, content) in common.iter().chain(&only_b).enumerate() {
+            b.insert_reader(&content[..], "b.bin", id as u32 + 1, false).into_result().unwrap();
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn whole_vocabulary_is_exact() {
+
+        let (mut a, mut b) = fixtures("test_overlap_a", "test_overlap_b", 1 << 30);
+        let exact = exact_jaccard(&mut a, &mut b);
+        assert!(exact > 0.2 && exact < 0.6, "{}", exact);
+
+        let overlap = estimate(&mut a, &mut b, 1 << 20, 1).unwrap();
+        assert_eq!(Side::A, overlap.sampled);
+        assert_eq!(overlap.vocabulary_a, overlap.samples);
+        assert!(overlap.vocabulary_a < overlap.vocabulary_b);
+        assert!((overlap.jaccard - exact).abs() < 1e-9, "{} {}", overlap.jaccard, exact);
+        assert_eq!(overlap.interval.0, overlap.interval.1);
+        // the first 3 of the 5 files of a are in b, most of their bytes
+        let bytes = overlap.shared_bytes / overlap.sampled_bytes;
+        assert!(bytes > 0.6 && bytes < 0.9, "{}", bytes);
+
+        // the same the other way
+        let reverse = estimate(&mut b, &mut a, 1 << 20, 1).unwrap();
+        assert_eq!(Side::B, reverse.sampled);
+        assert!((reverse.jaccard - exact).abs() < 1e-9);
+
+        drop(a);
+        drop(b);
+        cleanup("test_overlap_a");
+        cleanup("test_overlap_b");
+    }
+
+    #[test]
+    fn sampled_interval() {
+
+        // a in a shard per file, their vocabularies are merged
+        let (mut a, mut b) = fixtures("test_overlap_c", "test_overlap_d", 1);
+        assert!(a.open_indexes().unwrap().len() > 1);
+        let exact = exact_jaccard(&mut a, &mut b);
+
+        for seed in 0 .. 5 {
+            let overlap = estimate(&mut a, &mut b, 200, seed).unwrap();
+            assert_eq!(200, overlap.samples);
+            let (low, high) = overlap.interval;
+            assert!(low < overlap.jaccard && overlap.jaccard < high);
+            assert!(low <= exact && exact <= high, "{} not in {} .. {}", exact, low, high);
+            assert!(high - low < 0.2);
+        }
+
+        drop(a);
+        drop(b);
+        cleanup("test_overlap_c");
+        cleanup("test_overlap_d");
+    }
+
+    #[test]
+    fn incomparable() {
+
+        let a = BinacleManager::create("test_overlap_e.db", false, 1 << 30, 6, 16).unwrap();
+        let b = BinacleManager::create("test_overlap_f.db", false, 1 << 30, 6, 20).unwrap();
+        let error = check_comparable(&a, &b).unwrap_err().to_string();
+        assert!(error.contains("reduced to 16 bits in a and to 20 bits in b"), "{}", error);
+        assert!(error.contains("--ngram-size=16"), "{}", error);
+
+        let mut c = BinacleManager::create("test_overlap_g.db", false, 1 << 30, 6, 16).unwrap();
+        assert!(check_comparable(&a, &c).is_ok());
+        c.set_translation(Some(::translate::Translation::identity())).unwrap();
+        let error = check_comparable(&a, &c).unwrap_err().to_string();
+        assert!(error.contains("not translated in a, translated (--translate) in b"), "{}", error);
+
+        drop(a);
+        drop(b);
+        drop(c);
+        cleanup("test_overlap_e");
+        cleanup("test_overlap_f");
+        cleanup("test_overlap_g");
+    }
+
+    #[test]
+    fn statistics() {
+
+        assert_eq!((0.5, 0.5), wilson(5, 10, 10));
+        let (low, high) = wilson(50, 100, 1 << 20);
+        assert!((low - 0.404).abs() < 0.001 && (high - 0.596).abs() < 0.001, "{} {}", low, high);
+        // narrower when the sample is most of the population
+        let (l, h) = wilson(50, 100, 120);
+        assert!(l > low && h < high);
+        assert_eq!((0.0, 1.0), wilson(0, 0, 10));
+
+        assert_eq!(1.0, jaccard(1.0, 10, 10));
+        assert_eq!(0.0, jaccard(0.0, 10, 30));
+        assert!((jaccard(0.5, 10, 30) - 5.0 / 35.0).abs() < 1e-12);
+
+        let mut occupancy = Occupancy::new(1 << 10);
+        for slot in (0 .. 1 << 10).step_by(3) {
+            occupancy.set(slot);
+        }
+        let sample = sample_slots(&occupancy, 50, &mut Rng(3));
+        assert_eq!(50, sample.len());
+        assert!(sample.iter().all(|&s| s % 3 == 0) && sample.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(occupancy.count() as usize, sample_slots(&occupancy, 5000, &mut Rng(3)).len());
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+use std::io::*;
+
+use binacle::{self, addressable_size};
+use binacle_manager::BinacleManager;
+use bulk;
+
+// Parameters of a new database, suggested from a sample of the corpus.
+// The sample is indexed in a temporary database for each candidate
+// (ngram_size, alignment), twice: with half of its files then all of them.
+// The model of a shard fitted on it:
+//
+//   size = header + sum of the bytes of its lists
+//
+// where header is offset_size * 2^ngram_size and a list of L ids takes a
+// chain of blocks of 2^alignment bytes, doubling up to 4 KB, each with its
+// list header and the ids packed in the bytes of their differences (the
+// size of a packed id is fitted on the data of the sample). A shard holds
+// a part of the files: its lists are the ones of the sample, each id kept
+// with the ratio of the postings of the shard to the ones of the sample
+// (the lengths binomial), or lengthened by it when the shard holds more.
+// The lists of P postings follow the occupancy of the 2^ngram_size lists,
+// N * (1 - exp(-k * P / N)), k being fitted on the sample (k < 1 when the
+// files share n-grams). The postings grow with the bytes of the corpus, a
+// shard is full once over max_index_size, by its last file.
+
+
+// (ngram_size, alignment) measured on the sample
+pub const CANDIDATES: &'static [(u8, u8)] = &[(24, 6), (28, 6), (28, 4)];
+
+// the offset_size of the databases created by the manager
+pub const OFFSET_SIZE: u8 = 5;
+
+// max_index_size is this part of the RAM, the shard being filled is mapped
+pub const RAM_PART: f64 = 0.8;
+
+// bytes before the ids of a block: size, ids, bytes and previous block
+const LIST_HEADER: u64 = 5 + OFFSET_SIZE as u64;
+
+// the blocks of a list stop doubling at 2^12 bytes
+const MAX_BLOCK_LOG: u8 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expected {
+    Files(u64),
+    Bytes(u64),
+}
+
+impl Expected {
+
+    // a number of files, or of bytes with a unit: 500GB, 2T, 10000000b
+    pub fn parse(s: &str) -> Result<Expected> {
+
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid expected total: {}, <count> or <bytes><unit> expected", s));
+
+        let lower = s.trim().to_lowercase();
+        let digits = lower.trim_end_matches(|c: char| c.is_alphabetic());
+        let value = try!(digits.parse::<f64>().map_err(|_| invalid()));
+        let unit: u64 = match lower[digits.len() ..].trim_end_matches('b') {
+            "" if lower.ends_with('b') => 1,
+            "" => return Ok(Expected::Files(value as u64)),
+            "k" => 1 << 10,
+            "m" => 1 << 20,
+            "g" => 1 << 30,
+            "t" => 1 << 40,
+            _ => return Err(invalid()),
+        };
+        Ok(Expected::Bytes((value * unit as f64) as u64))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    pub ram: u64,
+    pub disk: u64,
+    pub expected: Expected,
+}
+
+// what a sample gave in a single shard
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Measure {
+    pub files: u64,
+    pub bytes: u64,
+    pub postings: u64,
+    pub lists: u64,
+    // bytes after the header
+    pub data: u64,
+    // (ids of a list, number of lists), by length
+    pub lengths: Vec<(u64, u64)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub ngram_size: u8,
+    pub alignment: u8,
+    pub half: Measure,
+    pub all: Measure,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prediction {
+    pub ngram_size: u8,
+    pub alignment: u8,
+    pub offset_size: u8,
+    pub max_index_size: u64,
+    pub header: u64,
+    pub bytes_per_posting: f64,
+    // fitted on the sample, 1 when all the postings are distinct n-grams
+    pub sharing: f64,
+    pub postings: u64,
+    pub shards: u64,
+    // logical size of all the shards
+    pub disk: u64,
+    // ids of a list of a shard, on average
+    pub mean_list: f64,
+    // ids read for an n-gram of a query, all shards summed
+    pub query_ids: f64,
+    // the header fits max_index_size and the shards the disk
+    pub fits: bool,
+    pub reasons: Vec<String>,
+}
+
+pub fn header_size(ngram_size: u8, alignment: u8) -> u64 {
+    binacle::header_size(OFFSET_SIZE, alignment, ngram_size)
+}
+
+// index bytes for a byte of content, when none was indexed yet: a 4-byte
+// id for each byte, as if the files shared no n-gram
+pub const WORST_GROWTH: f64 = 4.0;
+
+// margin on the growth measured, the next files may be less alike
+pub const GROWTH_MARGIN: f64 = 1.5;
+
+// index bytes for a byte of content, as measured on the shards of a
+// database: their data after the header over the content indexed in them.
+// None when nothing was indexed
+pub fn measured_growth(db: &mut BinacleManager) -> Option<f64> {
+
+    let header = header_size(db.ngram_size(), db.alignment());
+    let mut data = 0u64;
+    let mut content = 0.0;
+    for shard in db.open_shards() {
+        if let Ok(shard) = shard {
+            data += shard.get_size().saturating_sub(header);
+            content += shard.average_size() * shard.nb_file() as f64;
+        }
+    }
+    match content >= 1.0 {
+        true => Some(data as f64 / content),
+        false => None,
+    }
+}
+
+// the index bytes the content adds, a header for each shard it fills
+pub fn projected_index(content: u64, growth: Option<f64>, ngram_size: u8, alignment: u8, max_index_size: u64) -> u64 {
+    let data = content as f64 * growth.map_or(WORST_GROWTH, |g| g * GROWTH_MARGIN);
+    let shards = (data / max_index_size.max(1) as f64).ceil() as u64;
+    data as u64 + shards * header_size(ngram_size, alignment)
+}
+
+// postings and lists of the shards of a temporary database
+fn measure_db(db: &mut BinacleManager, files: u64, bytes: u64) -> Result<Measure> {
+
+    let mut m = Measure { files: files, bytes: bytes, ..Measure::default() };
+    let mut lengths = BTreeMap::new();
+    for shard in db.open_shards() {
+        let shard = try!(shard);
+        let mut ngrams = Vec::new();
+        shard.for_each_chain(|ngram, _| ngrams.push(ngram));
+        m.lists += ngrams.len() as u64;
+        for ngram in ngrams {
+            let len = shard.get_ids_size_by_ngram(ngram) as u64;
+            m.postings += len;
+            *lengths.entry(len).or_insert(0) += 1;
+        }
+        m.data += shard.get_size() - header_size(db.ngram_size(), db.alignment());
+    }
+    m.lengths = lengths.into_iter().collect();
+    Ok(m)
+}
+
+// index the files of dir in a temporary database, half of them then all
+pub fn measure(dir: &str, ngram_size: u8, alignment: u8) -> Result<Sample> {
+
+    let sources = try!(bulk::sources(dir));
+    if sources.len() < 2 {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{}: at least 2 files are needed in the sample", dir)));
+    }
+
+    // a single shard, as large as it can address
+    let mut db = try!(BinacleManager::create_in_memory(false, addressable_size(OFFSET_SIZE, alignment), alignment, ngram_size));
+    db.set_deterministic(true);
+    let mut bytes = 0;
+    let mut half = Measure::default();
+    for (i, source) in sources.iter().enumerate() {
+        bytes += try!(::std::fs::metadata(&source.path)).len();
+        try!(db.insert_file(&source.path, i as u32 + 1, false));
+        if i + 1 == sources.len() / 2 {
+            half = try!(measure_db(&mut db, i as u64 + 1, bytes));
+        }
+    }
+    let all = try!(measure_db(&mut db, sources.len() as u64, bytes));
+    try!(db.close());
+
+    Ok(Sample { ngram_size: ngram_size, alignment: alignment, half: half, all: all })
+}
+
+// lists of postings spread over n lists, k fitted on the sample
+fn occupancy(n: f64, k: f64, postings: f64) -> f64 {
+    n * (1.0 - (-k * postings / n).exp())
+}
+
+// bytes of the chain of a list of len ids, an id after the first one
+// packed in packed bytes and the last one also kept whole in its block
+fn list_bytes(len: u64, alignment: u8, packed: f64) -> f64 {
+
+    let mut bytes = 0;
+    let mut left = len;
+    let mut log = alignment;
+    while left > 0 {
+        let block = 1u64 << log;
+        // one more id fits when 4 bytes and the header are left after the ids
+        let ids = match block {
+            b if b < LIST_HEADER + 8 => 1,
+            b if b < LIST_HEADER + 12 => 2,
+            b => ((b - LIST_HEADER - 12) as f64 / packed) as u64 + 2,
+        };
+        bytes += block;
+        left = left.saturating_sub(ids);
+        if log == MAX_BLOCK_LOG && left > 0 {
+            return (bytes + (left + ids - 1) / ids * block) as f64;
+        }
+        log = (log + 1).min(MAX_BLOCK_LOG);
+    }
+    bytes as f64
+}
+
+// size of a packed id, the one giving the data of the sample
+fn fit_packed(m: &Measure, alignment: u8) -> f64 {
+    let data = |packed: f64| m.lengths.iter().map(|&(len, lists)| lists as f64 * list_bytes(len, alignment, packed)).sum::<f64>();
+    let (mut low, mut high) = (1.0, 4.0);
+    for _ in 0 .. 30 {
+        let mid = (low + high) / 2.0;
+        match data(mid) > m.data as f64 {
+            true => high = mid,
+            false => low = mid,
+        }
+    }
+    low
+}
+
+// lists and bytes after the header of a shard holding ratio times the
+// postings of the sample: each id of a list of the sample kept with this
+// probability, the lists of the sample lengthened and new ones of one id
+// when the shard holds more
+fn thinned(m: &Measure, ratio: f64, alignment: u8, packed: f64, more_lists: f64) -> (f64, f64) {
+
+    if ratio >= 1.0 {
+        let (lists, bytes) = m.lengths.iter().fold((0.0, 0.0), |(lists, bytes), &(len, n)| {
+            let len = ((len as f64 * ratio).round() as u64).max(1);
+            (lists + n as f64, bytes + n as f64 * list_bytes(len, alignment, packed))
+        });
+        return (lists + more_lists, bytes + more_lists * list_bytes(1, alignment, packed));
+    }
+
+    let (mut lists, mut bytes) = (0.0, 0.0);
+    if ratio <= 0.0 {
+        return (lists, bytes);
+    }
+    for &(len, n) in &m.lengths {
+        // binomial probabilities by their logarithm, up to far past the mean
+        let l = len as f64;
+        let last = (l * ratio + 10.0 * (l * ratio * (1.0 - ratio)).sqrt() + 1.0).min(l) as u64;
+        let step = (ratio / (1.0 - ratio)).ln();
+        let mut log_p = l * (1.0 - ratio).ln();
+        for kept in 1 ..= last {
+            log_p += ((len - kept + 1) as f64 / kept as f64).ln() + step;
+            let p = log_p.exp();
+            lists += n as f64 * p;
+            bytes += n as f64 * p * list_bytes(kept, alignment, packed);
+        }
+    }
+    (lists, bytes)
+}
+
+// the model, for the expected corpus
+pub fn predict(sample: &Sample, budget: &Budget) -> Prediction {
+
+    let all = &sample.all;
+    let n = (1u64 << sample.ngram_size) as f64;
+    let header = header_size(sample.ngram_size, sample.alignment);
+    let block = (1u64 << sample.alignment) as f64;
+    let max_index_size = (budget.ram as f64 * RAM_PART) as u64;
+
+    // k from the lists of the whole sample, saturated lists tell nothing
+    let occupied = (all.lists as f64 / n).min(1.0 - 1e-9);
+    let sharing = match all.postings {
+        0 => 1.0,
+        p => (-(1.0 - occupied).ln() * n / p as f64).min(1.0),
+    };
+    let packed = fit_packed(all, sample.alignment);
+
+    let total_bytes = match budget.expected {
+        Expected::Bytes(b) => b as f64,
+        Expected::Files(f) => f as f64 * all.bytes as f64 / all.files.max(1) as f64,
+    };
+    let postings = all.postings as f64 * total_bytes / all.bytes.max(1) as f64;
+
+    // lists and size of a shard of p postings
+    let shard = |p: f64| {
+        let more_lists = (occupancy(n, sharing, p) - all.lists as f64).max(0.0);
+        let (lists, bytes) = thinned(all, p / all.postings.max(1) as f64, sample.alignment, packed, more_lists);
+        (lists, header as f64 + bytes)
+    };
+    let shard_size = |shards: f64| shard(postings / shards).1;
+    // a shard is full by the last file inserted, over max_index_size
+    let file_postings = all.postings as f64 / all.files.max(1) as f64;
+    let full = |shards: f64| shard((postings / shards - file_postings).max(0.0)).1 > max_index_size as f64;
+
+    let mut reasons = Vec::new();
+    let mut fits = true;
+    let shards = if header >= max_index_size / 2 {
+        fits = false;
+        reasons.push(format!("the header ({} bytes) takes half of max_index_size or more", header));
+        1
+    } else {
+        // the size of a shard decreases with their number
+        let mut high = 1u64;
+        while full(high as f64) {
+            high *= 2;
+        }
+        let mut low = high / 2 + 1;
+        while low < high {
+            let mid = (low + high) / 2;
+            if full(mid as f64) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        high.max(1)
+    };
+
+    if max_index_size > addressable_size(OFFSET_SIZE, sample.alignment) {
+        fits = false;
+        reasons.push(format!("max_index_size is over the {} bytes a shard can address", addressable_size(OFFSET_SIZE, sample.alignment)));
+    }
+
+    let disk = (shard_size(shards as f64) * shards as f64) as u64;
+    if disk > budget.disk {
+        fits = false;
+        reasons.push(format!("{} bytes over the disk budget", disk - budget.disk));
+    }
+
+    let (lists, size) = shard(postings / shards as f64);
+    let mean_list = match lists {
+        0.0 => 0.0,
+        l => postings / shards as f64 / l,
+    };
+    // the blocks past the first one of each list, by posting
+    let bytes_per_posting = match postings {
+        0.0 => 0.0,
+        p => ((size - header as f64 - lists * block) * shards as f64 / p).max(0.0),
+    };
+
+    Prediction {
+        ngram_size: sample.ngram_size,
+        alignment: sample.alignment,
+        offset_size: OFFSET_SIZE,
+        max_index_size: max_index_size,
+        header: header,
+        bytes_per_posting: bytes_per_posting,
+        sharing: sharing,
+        postings: postings as u64,
+        shards: shards,
+        disk: disk,
+        mean_list: mean_list,
+        query_ids: mean_list * shards as f64,
+        fits: fits,
+        reasons: reasons,
+    }
+}
+
+// the candidates, and the one suggested: the fewest ids read by a query
+// among the ones that fit, the smallest disk when none does
+pub fn suggest(dir: &str, budget: &Budget) -> Result<(Vec<Prediction>, usize)> {
+
+    let mut predictions = Vec::new();
+    for &(ngram_size, alignment) in CANDIDATES {
+        predictions.push(predict(&try!(measure(dir, ngram_size, alignment)), budget));
+    }
+
+    let best = match predictions.iter().any(|p| p.fits) {
+        true => (0 .. predictions.len()).filter(|i| predictions[*i].fits)
+                    .min_by(|a, b| predictions[*a].query_ids.partial_cmp(&predictions[*b].query_ids).unwrap()
+                                   .then(predictions[*a].disk.cmp(&predictions[*b].disk))).unwrap(),
+        false => (0 .. predictions.len()).min_by_key(|i| predictions[*i].disk).unwrap(),
+    };
+    Ok((predictions, best))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+    use binacle_manager::tests::cleanup;
+    use corpus::{self, SizeDist};
+
+    #[test]
+    fn totals() {
+        assert_eq!(Expected::Files(100000), Expected::parse("100000").unwrap());
+        assert_eq!(Expected::Bytes(500 << 30), Expected::parse("500GB").unwrap());
+        assert_eq!(Expected::Bytes(2 << 40), Expected::parse("2T").unwrap());
+        assert_eq!(Expected::Bytes(1536), Expected::parse("1.5kb").unwrap());
+        assert_eq!(Expected::Bytes(12), Expected::parse("12b").unwrap());
+        assert!(Expected::parse("12x").is_err());
+        assert!(Expected::parse("GB").is_err());
+    }
+
+    #[test]
+    fn predicted_like_built() {
+
+        let dist = SizeDist::LogNormal { mean: 9.0, sigma: 0.5 };
+        let _ = fs::remove_dir_all("test_params_sample");
+        let _ = fs::remove_dir_all("test_params_full");
+        corpus::generate("test_params_sample", 40, dist, 1, &[]).unwrap();
+        let full = corpus::generate("test_params_full", 200, dist, 2, &[]).unwrap();
+
+        // shards of 1.6 MB, the header of a 16-bit database is 320 KB
+        let budget = Budget { ram: 2 << 20, disk: 1 << 40, expected: Expected::Files(200) };
+        let sample = measure("test_params_sample", 16, 4).unwrap();
+        assert_eq!(20, sample.half.files);
+        assert!(sample.half.postings < sample.all.postings);
+        let p = predict(&sample, &budget);
+        assert!(p.fits, "{:?}", p.reasons);
+        assert!(p.shards > 1);
+
+        {
+            let mut db = BinacleManager::create("test_params.db", false, p.max_index_size, 4, 16).unwrap();
+            for (i, path) in full.files.iter().enumerate() {
+                db.insert_file(path, i as u32 + 1, false).unwrap();
+            }
+            let disk: u64 = db.open_shards().into_iter().map(|s| s.unwrap().get_size()).sum();
+            let shards = db.nb_index() as u64;
+            assert!(shards + 1 >= p.shards && shards <= p.shards + 1, "built {} shard(s), predicted {}", shards, p.shards);
+            let error = (disk as f64 - p.disk as f64).abs() / disk as f64;
+            assert!(error < 0.25, "built {} bytes, predicted {}", disk, p.disk);
+        }
+
+        // the header of a 28-bit database does not fit 800 MB
+        let budget = Budget { ram: 1 << 30, disk: 1 << 40, expected: Expected::Bytes(1 << 30) };
+        let (predictions, best) = suggest("test_params_sample", &budget).unwrap();
+        assert_eq!(CANDIDATES.len(), predictions.len());
+        assert!(!predictions[1].fits && !predictions[2].fits);
+        assert_eq!((24, 6), (predictions[best].ngram_size, predictions[best].alignment));
+        // nothing fits a tiny disk, the smallest is suggested
+        let budget = Budget { disk: 1 << 20, ..budget };
+        let (predictions, best) = suggest("test_params_sample", &budget).unwrap();
+        assert!(predictions.iter().all(|p| !p.fits));
+        assert!(predictions.iter().all(|p| p.disk >= predictions[best].disk));
+
+        cleanup("test_params");
+        let _ = fs::remove_dir_all("test_params_sample");
+        let _ = fs::remove_dir_all("test_params_full");
+    }
+}
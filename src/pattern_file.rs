@@ -0,0 +1,292 @@
+use std::fs::OpenOptions;
+use std::io::*;
+use rustc_serialize::hex::{FromHex, ToHex};
+
+// Patterns read from files saved by an editor: the UTF-8 byte order mark of
+// the Windows editors and the line endings (CRLF) would silently become
+// bytes of the pattern, and a search for them finds nothing. They are
+// stripped and what was done is told, so the bytes searched can be checked.
+// A text pattern with bytes out of printable ASCII is warned about unless
+// they are expected (binary_ok, or a hex pattern).
+//
+// A text pattern given with several lines is most often a list of
+// indicators pasted at once, its newlines found in no file: split_lines
+// makes it one pattern a line.
+
+
+pub const BOM: &'static [u8] = b"\xef\xbb\xbf";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TextOptions {
+    // keep the CR and LF at the end of the file
+    pub keep_line_endings: bool,
+    // no warning for the bytes out of printable ASCII
+    pub binary_ok: bool,
+    // the file holds the pattern in hexadecimal
+    pub hex: bool,
+    // only the first bytes of the file are read
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Normalized {
+    pub bytes: Vec<u8>,
+    // what was stripped
+    pub notes: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+// the content without its byte order mark
+pub fn strip_bom(content: &[u8]) -> Option<&[u8]> {
+    match content.starts_with(BOM) {
+        true => Some(&content[BOM.len() ..]),
+        false => None,
+    }
+}
+
+pub fn is_printable(b: u8) -> bool {
+    b == b' ' || b.is_ascii_graphic()
+}
+
+// the bytes of a text out of printable ASCII, None when there are none
+pub fn unprintable_warning(bytes: &[u8]) -> Option<String> {
+    let mut bad = bytes.iter().enumerate().filter(|&(_, b)| !is_printable(*b));
+    let (offset, first) = match bad.next() {
+        Some(b) => b,
+        None => return None,
+    };
+    Some(format!("{} byte(s) out of printable ASCII, the first one is 0x{:02x} at offset {}",
+                 bad.count() + 1, first, offset))
+}
+
+// the pattern of a file, all of it
+pub fn normalize(content: &[u8], options: TextOptions) -> Result<Normalized> {
+
+    let mut normalized = Normalized::default();
+    let mut bytes = match strip_bom(content) {
+        Some(rest) => {
+            normalized.notes.push(String::from("stripped the UTF-8 byte order mark"));
+            rest
+        },
+        None => content,
+    };
+
+    if !options.keep_line_endings {
+        let end = bytes.iter().rposition(|b| *b != b'\r' && *b != b'\n').map_or(0, |i| i + 1);
+        if end < bytes.len() {
+            normalized.notes.push(format!("stripped {} line ending byte(s): {}", bytes.len() - end, bytes[end ..].to_hex()));
+            bytes = &bytes[.. end];
+        }
+    }
+
+    if options.hex {
+        let text = String::from_utf8_lossy(bytes);
+        normalized.bytes = try!(text.trim().from_hex().map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())));
+        return Ok(normalized);
+    }
+    if !options.binary_ok {
+        let warning = unprintable_warning(bytes);
+        normalized.warnings.extend(warning.map(|w| format!("the pattern has {}: use --binary-ok if they are expected, or hex", w)));
+    }
+    normalized.bytes = bytes.to_vec();
+    Ok(normalized)
+}
+
+// the lines of a text pattern with newlines, None when it has none. The
+// lines are trimmed of their CR and of the blanks around them, the empty
+// ones and the repeated ones are dropped
+pub fn split_lines(pattern: &[u8]) -> Option<Vec<Vec<u8>>> {
+
+    if !pattern.contains(&b'\n') {
+        return None;
+    }
+    let blank = |b: &u8| *b == b' ' || *b == b'\t' || *b == b'\r';
+    let mut lines: Vec<Vec<u8>> = Vec::new();
+    for line in pattern.split(|b| *b == b'\n') {
+        let start = line.iter().position(|b| !blank(b)).unwrap_or(line.len());
+        let end = line.iter().rposition(|b| !blank(b)).map_or(start, |i| i + 1);
+        let line = &line[start .. end];
+        if !line.is_empty() && !lines.iter().any(|l| &l[..] == line) {
+            lines.push(line.to_vec());
+        }
+    }
+    Some(lines)
+}
+
+// the patterns of a list (--any), one a line, trimmed like split_lines.
+// The empty lines are dropped but not the repeated ones: a pattern is told
+// by its index, its rank among the lines not empty
+pub fn pattern_list(content: &[u8], hex: bool) -> Result<Vec<Vec<u8>>> {
+
+    let content = strip_bom(content).unwrap_or(content);
+    let blank = |b: &u8| *b == b' ' || *b == b'\t' || *b == b'\r';
+    let mut patterns = Vec::new();
+    for line in content.split(|b| *b == b'\n') {
+        let start = line.iter().position(|b| !blank(b)).unwrap_or(line.len());
+        let end = line.iter().rposition(|b| !blank(b)).map_or(start, |i| i + 1);
+        let line = &line[start .. end];
+        if line.is_empty() {
+            continue;
+        }
+        let index = patterns.len();
+        patterns.push(match hex {
+            true => try!(String::from_utf8_lossy(line).from_hex().map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("pattern {}: {}", index, e))
+            })),
+            false => line.to_vec(),
+        });
+    }
+    Ok(patterns)
+}
+
+// the pattern of a file, an error when it is too short to be searched
+pub fn read_pattern(path: &str, options: TextOptions) -> Result<Normalized> {
+    let file = try!(OpenOptions::new().read(true).open(path));
+    let len = try!(file.metadata()).len();
+    let mut content = Vec::new();
+    try!(file.take(options.max_bytes.unwrap_or(len)).read_to_end(&mut content));
+
+    let mut normalized = try!(normalize(&content, options).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
+    if (content.len() as u64) < len {
+        normalized.notes.insert(0, format!("read the first {} of its {} bytes", content.len(), len));
+    }
+    if normalized.bytes.len() < 4 {
+        return Err(Error::new(ErrorKind::InvalidInput, match content.is_empty() {
+            true => format!("{}: the file is empty, there is no pattern to search", path),
+            false => format!("{}: {} byte(s) to search, a pattern has at least 4", path, normalized.bytes.len()),
+        }));
+    }
+    Ok(normalized)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use queries::read_queries;
+    use search::{run_batch, SearchRequest};
+
+    #[test]
+    fn stripped() {
+        let n = normalize(b"\xef\xbb\xbfGetProcAddress\r\n", TextOptions::default()).unwrap();
+        assert_eq!(b"GetProcAddress".to_vec(), n.bytes);
+        assert_eq!(2, n.notes.len());
+        assert!(n.notes[1].ends_with("0d0a"));
+        assert!(n.warnings.is_empty());
+
+        // the line endings inside the pattern are kept
+        let n = normalize(b"a\r\nb\n", TextOptions::default()).unwrap();
+        assert_eq!(b"a\r\nb".to_vec(), n.bytes);
+        assert_eq!(1, n.warnings.len());
+
+        let hex = TextOptions { hex: true, ..TextOptions::default() };
+        assert_eq!(vec![0x4d, 0x5a, 0x90], normalize(b"\xef\xbb\xbf4d5a90\r\n", hex).unwrap().bytes);
+        assert!(normalize(b"4d5g", hex).is_err());
+
+        let n = normalize("d\u{e9}j\u{e0}".as_bytes(), TextOptions::default()).unwrap();
+        assert!(n.warnings[0].starts_with("the pattern has 4 byte(s)"));
+        let binary = TextOptions { binary_ok: true, ..TextOptions::default() };
+        assert!(normalize("d\u{e9}j\u{e0}".as_bytes(), binary).unwrap().warnings.is_empty());
+        let w = unprintable_warning("d\u{e9}j\u{e0}".as_bytes()).unwrap();
+        assert_eq!("4 byte(s) out of printable ASCII, the first one is 0xc3 at offset 1", w);
+    }
+
+    #[test]
+    fn split() {
+        let lines = |pattern: &[u8]| split_lines(pattern).map(|l| l.iter().map(|l| String::from_utf8(l.clone()).unwrap()).collect::<Vec<_>>());
+
+        assert_eq!(None, lines(b"GetProcAddress"));
+        assert_eq!(None, lines(b"  GetProcAddress\r"));
+        assert_eq!(Some(vec![String::from("GetProcAddress")]), lines(b"GetProcAddress\n"));
+        assert_eq!(Some(vec![String::from("evil.example.com"), String::from("10.0.0.1"), String::from("a b")]),
+                   lines(b"evil.example.com\r\n\r\n  10.0.0.1\t\r\n\n a b \r\nevil.example.com\r\n"));
+        assert_eq!(Some(Vec::new()), lines(b"\r\n \n"));
+
+        // a list keeps the repeated lines, the indexes are the ranks of the lines
+        let list = pattern_list(b"\xef\xbb\xbfkernel32\r\n\r\n  10.0.0.1\nkernel32\n", false).unwrap();
+        assert_eq!(vec![b"kernel32".to_vec(), b"10.0.0.1".to_vec(), b"kernel32".to_vec()], list);
+        assert_eq!(vec![vec![0x4d, 0x5a, 0x90, 0x00]], pattern_list(b"4d5a9000\r\n", true).unwrap());
+        assert!(pattern_list(b"4d5a\nzz\n", true).unwrap_err().to_string().starts_with("pattern 1:"));
+    }
+
+    #[test]
+    fn windows_files() {
+
+        File::create("test_pattern_file.txt").unwrap().write_all(b"\xef\xbb\xbfGetProcAddress\r\n").unwrap();
+        File::create("test_pattern_file.queries").unwrap()
+            .write_all(b"\xef\xbb\xbfproc: GetProcAddress\r\n\"LoadLibrary\"\r\n").unwrap();
+        File::create("test_pattern_file.bin").unwrap().write_all(b"call GetProcAddress, LoadLibraryA").unwrap();
+        {
+            let mut db = BinacleManager::create("test_pattern_file.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_file("test_pattern_file.bin", 1, false).unwrap();
+            // the n-grams of 16 bits do not tell the CR LF from ", ", the file does
+            let exact = |db: &mut BinacleManager, pattern: &[u8]| db.run(&SearchRequest::pattern(pattern).exact()).unwrap().ids;
+
+            let n = read_pattern("test_pattern_file.txt", TextOptions::default()).unwrap();
+            assert_eq!(vec![1], exact(&mut db, &n.bytes));
+
+            // the CR LF searched, with a warning
+            let kept = TextOptions { keep_line_endings: true, ..TextOptions::default() };
+            let n = read_pattern("test_pattern_file.txt", kept).unwrap();
+            assert_eq!(1, n.warnings.len());
+            assert!(exact(&mut db, &n.bytes).is_empty());
+
+            let queries = read_queries("test_pattern_file.queries").unwrap();
+            assert_eq!("proc", queries[0].name);
+            assert_eq!(vec!["stripped the UTF-8 byte order mark"], queries[0].notes);
+            assert!(queries[1].notes.is_empty());
+            assert!(queries.iter().all(|q| q.warnings.is_empty()));
+            let requests: Vec<_> = queries.iter().map(|q| q.request.clone()).collect();
+            for result in run_batch(&mut db, &requests).results {
+                assert_eq!(vec![1], result.unwrap().ids);
+            }
+        }
+        cleanup("test_pattern_file");
+        let _ = fs::remove_file("test_pattern_file.txt");
+        let _ = fs::remove_file("test_pattern_file.queries");
+        let _ = fs::remove_file("test_pattern_file.bin");
+    }
+
+    #[test]
+    fn binary_files() {
+
+        // a stub with the bytes of a line ending and of a byte order mark inside
+        let blob: Vec<u8> = (0 .. 300u32).map(|i| (i * 7 + 0xef) as u8).collect();
+        File::create("test_pattern_blob.bin").unwrap().write_all(&blob).unwrap();
+        File::create("test_pattern_short.bin").unwrap().write_all(b"\x00\x01\x02").unwrap();
+        File::create("test_pattern_empty.bin").unwrap();
+        {
+            let mut db = BinacleManager::create("test_pattern_blob.db", false, 1 << 30, 6, 16).unwrap();
+            let mut content = b"MZ header".to_vec();
+            content.extend_from_slice(&blob);
+            db.insert_reader(&content[..], "", 1, false);
+
+            let raw = TextOptions { binary_ok: true, keep_line_endings: true, ..TextOptions::default() };
+            let n = read_pattern("test_pattern_blob.bin", raw).unwrap();
+            assert_eq!(blob, n.bytes);
+            assert!(n.notes.is_empty() && n.warnings.is_empty());
+            assert!(db.search(&n.bytes).unwrap().contains(&1));
+
+            let first = TextOptions { max_bytes: Some(16), ..raw };
+            let n = read_pattern("test_pattern_blob.bin", first).unwrap();
+            assert_eq!(&blob[.. 16], &n.bytes[..]);
+            assert_eq!("read the first 16 of its 300 bytes", n.notes[0]);
+            assert!(db.search(&n.bytes).unwrap().contains(&1));
+
+            // too short: an error naming the file, not a panic
+            let e = read_pattern("test_pattern_short.bin", raw).unwrap_err();
+            assert_eq!("test_pattern_short.bin: 3 byte(s) to search, a pattern has at least 4", e.to_string());
+            let e = read_pattern("test_pattern_empty.bin", raw).unwrap_err();
+            assert!(e.to_string().contains("the file is empty"));
+            assert!(read_pattern("test_pattern_blob.bin", TextOptions { max_bytes: Some(3), ..raw }).is_err());
+        }
+        cleanup("test_pattern_blob");
+        for name in &["test_pattern_blob.bin", "test_pattern_short.bin", "test_pattern_empty.bin"] {
+            let _ = fs::remove_file(name);
+        }
+    }
+}
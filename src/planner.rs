@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::io::*;
+use std::time::Instant;
+use rustc_serialize::hex::ToHex;
+
+use binacle::{short_pattern, BinacleFile};
+
+// Order of the lists intersected by a search. The smallest list is read
+// first, knowing it takes the size of every list of the pattern: hundreds of
+// header and chain lookups for a long pattern. The n-grams of the ASCII or
+// repetitive regions of a pattern are the common ones, so the n-grams are
+// first ranked by the diversity of the bytes around them and only the best
+// ones are probed. When none of them is selective, all are probed as before.
+//
+// All the lists are intersected whatever the order, the results do not
+// change, only the number of lists probed.
+
+
+// n-grams probed on their score alone
+pub const DEFAULT_PRESELECT: usize = 8;
+
+// a list of at most this many ids is selective enough to start with
+pub const SELECTIVE_IDS: u32 = 1024;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Plan {
+    // n-grams in the order their lists are intersected
+    pub order: Vec<u32>,
+    // n-grams probed on their score, and their number of ids
+    pub preselected: Vec<(u32, u32)>,
+    // none of the preselected n-grams was selective, all were probed
+    pub fallback: bool,
+    // lists whose size was read
+    pub probed: usize,
+}
+
+impl Plan {
+
+    // one line of the explain output
+    pub fn describe(&self) -> String {
+        if self.order.is_empty() {
+            return String::from("an n-gram is absent, no list read");
+        }
+        if self.preselected.is_empty() {
+            return format!("{} n-gram(s), all probed", self.order.len());
+        }
+        let picks: Vec<String> = self.preselected.iter().map(|&(n, nb)| format!("{} ({})", n.to_ne_bytes().to_hex(), nb)).collect();
+        format!("{} of {} n-gram(s) probed, pre-selected {}{}", self.probed, self.order.len(), picks.join(" "),
+                if self.fallback { ", none selective: fallback to all" } else { "" })
+    }
+}
+
+// diversity of the bytes around the n-gram at i: the distinct bytes of the
+// window, and twice the bytes of the n-gram neither ASCII nor padding
+pub fn score(pattern: &[u8], i: usize) -> u32 {
+
+    let window = &pattern[i.saturating_sub(2) .. (i + 6).min(pattern.len())];
+    let mut seen = [false; 256];
+    let mut distinct = 0;
+    for b in window {
+        if !seen[*b as usize] {
+            seen[*b as usize] = true;
+            distinct += 1;
+        }
+    }
+    let rare = pattern[i .. i + 4].iter().filter(|b| **b != 0 && **b != 0xff && !(0x20 ..= 0x7e).contains(*b)).count() as u32;
+    distinct + 2 * rare
+}
+
+// the distinct n-grams of a pattern, the best scored first
+pub fn ranked_ngrams(pattern: &[u8]) -> Result<Vec<u32>> {
+
+    if pattern.len() < 4 {
+        return Err(short_pattern());
+    }
+
+    let mut seen = HashSet::with_capacity(pattern.len() - 3);
+    let mut ranked = Vec::with_capacity(pattern.len() - 3);
+    for i in 0 .. pattern.len() - 3 {
+        let ngram = u32::from_ne_bytes([pattern[i], pattern[i + 1], pattern[i + 2], pattern[i + 3]]);
+        if seen.insert(ngram) {
+            ranked.push((ngram, i, score(pattern, i)));
+        }
+    }
+    // stable, the first ones of the pattern first on a tie
+    ranked.sort_by(|a, b| b.2.cmp(&a.2));
+    Ok(ranked.into_iter().map(|r| r.0).collect())
+}
+
+impl BinacleFile {
+
+    // the order of the lists of a pattern, preselect n-grams are probed
+    // before all are (0 probes them all)
+    pub fn plan(&self, pattern: &[u8], preselect: usize) -> Result<Plan> {
+
+        let ranked = try!(ranked_ngrams(pattern));
+        let mut plan = Plan::default();
+
+        let probe = |ngrams: &[u32]| -> Vec<(u32, u32)> {
+            ngrams.iter().map(|n| (*n, self.get_ids_size_by_ngram(*n))).collect()
+        };
+        let by_size = |mut sized: Vec<(u32, u32)>| -> Vec<u32> {
+            sized.sort_by(|a, b| a.1.cmp(&b.1));
+            sized.into_iter().map(|s| s.0).collect()
+        };
+
+        if preselect == 0 || ranked.len() <= preselect {
+            plan.order = by_size(probe(&ranked));
+            plan.probed = ranked.len();
+            return Ok(plan);
+        }
+
+        let mut picks = probe(&ranked[.. preselect]);
+        picks.sort_by(|a, b| a.1.cmp(&b.1));
+        plan.preselected = picks.clone();
+        if picks[0].1 <= SELECTIVE_IDS {
+            // the others in the order of their score, unprobed
+            plan.probed = preselect;
+            plan.order = picks.into_iter().map(|s| s.0).chain(ranked[preselect ..].iter().cloned()).collect();
+        } else {
+            plan.fallback = true;
+            plan.probed = ranked.len();
+            picks.extend(probe(&ranked[preselect ..]));
+            plan.order = by_size(picks);
+        }
+        Ok(plan)
+    }
+
+    // the ids of the lists of a plan
+    pub fn search_plan(&self, plan: &Plan) -> HashSet<u32> {
+        self.search_plan_until(plan, None).unwrap_or_default()
+    }
+
+    // same as search_plan, TimedOut when the deadline passes in the lists
+    pub fn search_plan_until(&self, plan: &Plan, deadline: Option<Instant>) -> Result<HashSet<u32>> {
+
+        let mut set = match plan.order.first() {
+            Some(ngram) => try!(self.get_ids_until(*ngram, deadline)),
+            None => return Ok(HashSet::new()),
+        };
+        for ngram in &plan.order[1 ..] {
+            if set.is_empty() {
+                break;
+            }
+            set = try!(self.intersect_ids_until(set, *ngram, deadline));
+        }
+        Ok(set)
+    }
+
+    // same as search, the lists probed are chosen by plan
+    pub fn search_planned(&self, pattern: &[u8], preselect: usize) -> Result<(HashSet<u32>, Plan)> {
+        self.search_planned_until(pattern, preselect, None)
+    }
+
+    pub fn search_planned_until(&self, pattern: &[u8], preselect: usize, deadline: Option<Instant>) -> Result<(HashSet<u32>, Plan)> {
+        let plan = try!(self.plan(pattern, preselect));
+        let ids = try!(self.search_plan_until(&plan, deadline));
+        try!(self.check_reads());
+        Ok((ids, plan))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, remove_file};
+    use std::time::{Duration, Instant};
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use corpus::{self, Plant, SizeDist};
+    use search::SearchRequest;
+
+    #[test]
+    fn random_bytes_first() {
+        let pattern = b"GetProcAddress\x8b\x4d\xf3\x1c\x00\x00\x00\x00\x00";
+        let ranked = ranked_ngrams(pattern).unwrap();
+        // the n-grams over the binary bytes
+        assert_eq!(u32::from_ne_bytes([0x8b, 0x4d, 0xf3, 0x1c]), ranked[0]);
+        assert!(ranked[.. 4].iter().all(|n| n.to_ne_bytes().iter().any(|b| *b > 0x7e)));
+        // the repeated n-gram is kept once
+        assert_eq!(pattern.len() - 4, ranked.len());
+        assert!(ranked_ngrams(b"abc").is_err());
+    }
+
+    #[test]
+    fn same_ids_as_probing_all() {
+        {
+            let mut db = BinacleFile::create("test_planner.db", 5, 6, 28).unwrap();
+            for id in 1 .. 5000u32 {
+                let content = format!("common text {} {}", id % 3, if id % 500 == 0 { "\u{1f600}\u{1f4a9}rare" } else { "" });
+                db.insert_reader(content.as_bytes(), id).unwrap();
+            }
+
+            let rare = "common text 2 \u{1f600}\u{1f4a9}rare".as_bytes();
+            let (ids, plan) = db.search_planned(rare, 4).unwrap();
+            assert_eq!(db.search(rare).unwrap(), ids);
+            assert_eq!(3, ids.len());
+            assert!(!plan.fallback);
+            assert_eq!(4, plan.probed);
+            assert!(plan.describe().contains("pre-selected"), "{}", plan.describe());
+            let pick = plan.preselected[0].0.to_ne_bytes();
+            assert!(rare.windows(4).any(|w| w == pick));
+            assert!(plan.describe().contains(&pick.to_hex()));
+
+            // all common: the picks are not selective
+            let (ids, plan) = db.search_planned(b"common text 1 ", 4).unwrap();
+            assert_eq!(db.search(b"common text 1 ").unwrap(), ids);
+            assert_eq!(1667, ids.len());
+            assert!(plan.fallback);
+            assert!(plan.describe().ends_with("fallback to all"));
+
+            let (ids, plan) = db.search_planned(rare, 0).unwrap();
+            assert_eq!(3, ids.len());
+            assert!(plan.preselected.is_empty());
+            assert_eq!(plan.order.len(), plan.probed);
+        }
+        let _ = remove_file("test_planner.db");
+        let _ = remove_file("test_planner.meta");
+        let _ = remove_file("test_planner.bitmap");
+    }
+
+    #[test]
+    fn explained() {
+        let mut db = BinacleManager::create("test_planner2.db", true, 1 << 30, 6, 16).unwrap();
+        db.insert_reader(&b"call GetProcAddress \x8b\x4d\xf3\x1c\xe8 jmp"[..], "a.exe", 1, true);
+        let request = SearchRequest::pattern(b"call GetProcAddress \x8b\x4d\xf3\x1c\xe8").explain();
+        let steps = db.run(&request).unwrap().explain.unwrap();
+        assert!(steps.iter().any(|s| s.starts_with("shard 0: ") && s.contains("pre-selected")), "{:?}", steps);
+        drop(db);
+        cleanup("test_planner2");
+    }
+
+    // cargo test --release -- --ignored planner_benchmark --nocapture
+    #[test]
+    #[ignore]
+    fn planner_benchmark() {
+
+        let _ = fs::remove_dir_all("test_planner_corpus");
+        // the planted patterns are long, half text half binary
+        let plants: Vec<Plant> = (0 .. 20u8).map(|i| {
+            let mut pattern = format!("kernel32.dll GetProcAddress LoadLibraryA {:02} ", i).into_bytes();
+            pattern.extend_from_slice(&[0x8b, 0x4d, 0xf3 ^ i, 0x1c, 0xe8, i, 0x90, 0x55]);
+            Plant { pattern: pattern, count: 20 }
+        }).collect();
+        let truth = corpus::generate("test_planner_corpus", 2000, SizeDist::LogNormal { mean: 9.0, sigma: 1.0 }, 7, &plants).unwrap();
+        {
+            let mut db = BinacleManager::create("test_planner_bench.db", true, 1 << 30, 6, 24).unwrap();
+            for (id, path) in truth.files.iter().enumerate() {
+                db.insert_file(path, id as u32 + 1, false).unwrap();
+            }
+        }
+
+        let shards: Vec<BinacleFile> = BinacleManager::open("test_planner_bench.db").unwrap()
+            .open_shards().into_iter().map(|s| s.unwrap()).collect();
+        let run = |preselect: usize| {
+            let mut planning = Duration::new(0, 0);
+            let mut probed = 0;
+            let mut found = Vec::new();
+            for plant in &plants {
+                let mut ids = HashSet::new();
+                for shard in &shards {
+                    let start = Instant::now();
+                    let plan = shard.plan(&plant.pattern, preselect).unwrap();
+                    planning += start.elapsed();
+                    probed += plan.probed;
+                    ids.extend(shard.search_plan(&plan));
+                }
+                found.push(ids);
+            }
+            (planning, probed, found)
+        };
+        let (all_time, all_probed, all_found) = run(0);
+        let (pre_time, pre_probed, pre_found) = run(DEFAULT_PRESELECT);
+
+        println!("{} patterns: probing all {:?} ({} lists), pre-selected {:?} ({} lists), {:.1}x",
+                 plants.len(), all_time, all_probed, pre_time, pre_probed,
+                 all_time.as_secs_f64() / pre_time.as_secs_f64());
+        // no recall loss
+        assert_eq!(all_found, pre_found);
+        assert!(all_found.iter().all(|ids| ids.len() >= 20));
+        assert!(pre_probed * 4 < all_probed);
+
+        drop(shards);
+        cleanup("test_planner_bench");
+        let _ = fs::remove_dir_all("test_planner_corpus");
+        let _ = remove_file("test_planner_corpus.truth.json");
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::*;
+
+use binacle_manager::{BinacleManager, ShardWarning};
+
+// Dump of the posting lists of a database, for the statistics computed
+// outside of Binacle (document frequencies of the n-grams, co-occurrences):
+// the lists of each shard in header order, each one decoded as it is
+// written (BinacleFile::postings), the memory used does not depend on their
+// size. The n-gram of a list is the reduced one, its ngram_size low bits
+// of the 4 bytes read as a u32 on the host indexing the files (little
+// endian on x86). The ids are the ones of the lists: a tombstoned id is
+// dumped until the shards are rebuilt.
+//
+// tsv, a line by list:
+//
+//     <shard> TAB <n-gram, 8 hex digits> TAB <df> TAB <id>,<id>,...
+//
+// binary, stable (a new layout takes a new version), all the integers
+// unsigned and little endian:
+//
+//     header   "BNPD", version: u32 (1), ngram_size: u32
+//     list     shard: u32, n-gram: u32, df: u32, df ids: u32 ascending
+//
+// the lists follow the header up to the end of the stream, sorted by shard
+// then by n-gram. df is the number of ids of the list.
+
+
+pub const MAGIC: &'static [u8; 4] = b"BNPD";
+
+pub const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    Tsv,
+    Binary,
+}
+
+impl DumpFormat {
+
+    // tsv when not given
+    pub fn parse(s: &str) -> Result<DumpFormat> {
+        match s {
+            "" | "tsv" => Ok(DumpFormat::Tsv),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown dump format {}: tsv or binary", s))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DumpReport {
+    pub shards: usize,
+    pub lists: u64,
+    pub ids: u64,
+    // header slots of the shards dumped, the ones skipped by their
+    // occupancy bitmaps and the shards without one, read whole
+    pub header_slots: u64,
+    pub header_skipped: u64,
+    pub without_bitmap: usize,
+    // shards that could not be opened, not dumped
+    pub warnings: Vec<ShardWarning>,
+}
+
+fn write_u32<W: Write>(out: &mut W, n: u32) -> Result<()> {
+    out.write_all(&n.to_le_bytes())
+}
+
+// the lists of at least min_df ids
+pub fn dump<W: Write>(db: &mut BinacleManager, mut out: W, format: DumpFormat, min_df: u32) -> Result<DumpReport> {
+
+    let mut report = DumpReport::default();
+    if format == DumpFormat::Binary {
+        try!(out.write_all(MAGIC));
+        try!(write_u32(&mut out, VERSION));
+        try!(write_u32(&mut out, db.ngram_size() as u32));
+    }
+
+    for (n, shard) in db.open_shards().into_iter().enumerate() {
+        let shard = match shard {
+            Ok(shard) => shard,
+            Err(e) => {
+                report.warnings.push(db.shard_warning(n, &e));
+                continue;
+            },
+        };
+        report.shards += 1;
+        report.header_slots += shard.header_slots();
+        match shard.header_skipped() {
+            Some(skipped) => report.header_skipped += skipped,
+            None => report.without_bitmap += 1,
+        }
+        for (ngram, ids) in shard.postings() {
+            // the ids of the blocks are counted before the list is decoded twice
+            if ids.size_hint().1.map_or(false, |upper| upper < min_df as usize) {
+                continue;
+            }
+            let df = ids.clone().count() as u32;
+            if df < min_df {
+                continue;
+            }
+            match format {
+                DumpFormat::Tsv => {
+                    try!(write!(out, "{}\t{:08x}\t{}\t", n, ngram, df));
+                    for (i, id) in ids.enumerate() {
+                        try!(write!(out, "{}{}", if i == 0 { "" } else { "," }, id));
+                    }
+                    try!(out.write_all(b"\n"));
+                },
+                DumpFormat::Binary => {
+                    try!(write_u32(&mut out, n as u32));
+                    try!(write_u32(&mut out, ngram));
+                    try!(write_u32(&mut out, df));
+                    for id in ids {
+                        try!(write_u32(&mut out, id));
+                    }
+                },
+            }
+            report.lists += 1;
+            report.ids += df as u64;
+        }
+    }
+    try!(out.flush());
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle::BinacleFile;
+    use binacle_manager::tests::cleanup;
+
+    fn read_u32(bytes: &[u8], pos: &mut usize) -> u32 {
+        let n = u32::from_le_bytes([bytes[*pos], bytes[*pos + 1], bytes[*pos + 2], bytes[*pos + 3]]);
+        *pos += 4;
+        n
+    }
+
+    #[test]
+    fn dump_formats() {
+        let mut db = BinacleManager::create("test_dump.db", true, 1 << 30, 6, 16).unwrap();
+        db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+        db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "b.bin", 2, true);
+        db.insert_reader(&b"kernel32.dll GetProcAddress"[..], "c.bin", 3, true);
+
+        let mut binary = Vec::new();
+        let report = dump(&mut db, &mut binary, DumpFormat::Binary, 0).unwrap();
+        assert_eq!(1, report.shards);
+
+        // parsed as an external tool would, against the lists of the shard
+        let shard = db.open_shards().remove(0).unwrap();
+        assert_eq!(&MAGIC[..], &binary[.. 4]);
+        let mut pos = 4;
+        assert_eq!(VERSION, read_u32(&binary, &mut pos));
+        assert_eq!(16, read_u32(&binary, &mut pos));
+        let mut lists = 0;
+        while pos < binary.len() {
+            assert_eq!(0, read_u32(&binary, &mut pos));
+            let ngram = read_u32(&binary, &mut pos);
+            let df = read_u32(&binary, &mut pos);
+            let ids: Vec<u32> = (0 .. df).map(|_| read_u32(&binary, &mut pos)).collect();
+            let mut expected: Vec<u32> = shard.get_ids_by_ngram(ngram).into_iter().collect();
+            expected.sort();
+            assert_eq!(expected, ids);
+            lists += 1;
+        }
+        assert_eq!(report.lists, lists);
+        assert_eq!(pos, binary.len());
+
+        // the n-grams of the three files only
+        let mut tsv = Vec::new();
+        let frequent = dump(&mut db, &mut tsv, DumpFormat::Tsv, 3).unwrap();
+        assert!(frequent.lists > 0 && frequent.lists < report.lists);
+        let tsv = String::from_utf8(tsv).unwrap();
+        assert_eq!(frequent.lists as usize, tsv.lines().count());
+        let ngram = BinacleFile::split_ngrams(b"GetP").unwrap().into_iter().next().unwrap() & 0xffff;
+        assert!(tsv.lines().any(|l| l == format!("0\t{:08x}\t3\t1,2,3", ngram)));
+        assert!(tsv.lines().all(|l| l.ends_with("\t3\t1,2,3")));
+
+        assert_eq!(DumpFormat::Tsv, DumpFormat::parse("").unwrap());
+        assert!(DumpFormat::parse("json").is_err());
+        drop(shard);
+        drop(db);
+        cleanup("test_dump");
+    }
+}
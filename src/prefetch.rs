@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::fs::{metadata, File};
+use std::io::*;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+
+// Reading of the files of an insertion ahead of their indexing. A thread
+// reads the files in their order into chunks sent on a bounded queue, the
+// writer (the manager, alone to touch the shards) indexes a chunk while the
+// next ones are read: the waits on the disk overlap the updates of the
+// lists. The buffers come back to the thread once drained, a few of them
+// are allocated for the whole insertion.
+//
+// The writer sees each file as a reader of the same bytes as the file, the
+// n-grams indexed are the ones of the synchronous path (chunk size 0: each
+// file read by the writer when it is indexed). A file the writer does not
+// read to its end (an error, a timeout) is drained when its reader is
+// dropped, the next one starts at its first chunk.
+
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+// chunks on the queue, read but not yet indexed
+pub const QUEUE_DEPTH: usize = 8;
+
+enum Chunk {
+    // its content follows
+    Opened,
+    Skipped(String),
+    // a buffer and its bytes filled
+    Data(Vec<u8>, usize),
+    End,
+    Failed(Error),
+}
+
+// the next file of a prefetch
+pub enum Next<'a> {
+    File(FileReader<'a>),
+    Skipped(String),
+    Failed(Error),
+}
+
+enum Mode {
+    // the paths left, opened by the writer
+    Direct(VecDeque<String>),
+    Queued {
+        chunks: Receiver<Chunk>,
+        pool: Sender<Vec<u8>>,
+    },
+}
+
+pub struct Prefetch {
+    mode: Mode,
+}
+
+impl Prefetch {
+
+    // the files of paths, read by a thread in chunks of chunk_size bytes,
+    // or by the writer with 0. The thread stops when the Prefetch is dropped
+    pub fn start(paths: Vec<String>, chunk_size: usize) -> Prefetch {
+
+        if chunk_size == 0 {
+            return Prefetch { mode: Mode::Direct(paths.into_iter().collect()) };
+        }
+        let (chunks_tx, chunks) = sync_channel(QUEUE_DEPTH);
+        let (pool, pool_rx) = channel();
+        thread::spawn(move || read_ahead(paths, chunk_size, chunks_tx, pool_rx));
+        Prefetch { mode: Mode::Queued { chunks: chunks, pool: pool } }
+    }
+
+    // the next file, in the order of the paths
+    pub fn next<'a>(&'a mut self) -> Next<'a> {
+        match self.mode {
+            Mode::Direct(ref mut paths) => match paths.pop_front() {
+                Some(path) => match open(&path) {
+                    Ok(Some(file)) => Next::File(FileReader::Direct(file)),
+                    Ok(None) => Next::Skipped(String::from("not a regular file")),
+                    Err(e) => Next::Failed(e),
+                },
+                None => Next::Failed(stopped()),
+            },
+            Mode::Queued { ref chunks, ref pool } => match chunks.recv() {
+                Ok(Chunk::Opened) => Next::File(FileReader::Queued(Queued {
+                    chunks: chunks,
+                    pool: pool,
+                    buf: Vec::new(),
+                    len: 0,
+                    pos: 0,
+                    done: false,
+                })),
+                Ok(Chunk::Skipped(reason)) => Next::Skipped(reason),
+                Ok(Chunk::Failed(e)) => Next::Failed(e),
+                _ => Next::Failed(stopped()),
+            },
+        }
+    }
+}
+
+fn stopped() -> Error {
+    Error::new(ErrorKind::Other, "no more files prefetched")
+}
+
+// a regular file opened, None for the other ones
+fn open(path: &str) -> Result<Option<File>> {
+    if !try!(metadata(path)).is_file() {
+        return Ok(None);
+    }
+    File::open(path).map(Some)
+}
+
+// the bytes read, up to the size of buf: less at the end of the file only
+fn fill(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len ..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+// the thread: the chunks of each file, then its end or its error
+fn read_ahead(paths: Vec<String>, chunk_size: usize, chunks: SyncSender<Chunk>, pool: Receiver<Vec<u8>>) {
+
+    // the queue, the writer and the thread each hold buffers
+    let mut allocated = 0;
+    let mut spare: Option<Vec<u8>> = None;
+    for path in paths {
+        let mut file = match open(&path) {
+            Ok(Some(file)) => file,
+            Ok(None) => {
+                if chunks.send(Chunk::Skipped(String::from("not a regular file"))).is_err() {
+                    return;
+                }
+                continue;
+            },
+            Err(e) => {
+                if chunks.send(Chunk::Failed(e)).is_err() {
+                    return;
+                }
+                continue;
+            },
+        };
+        if chunks.send(Chunk::Opened).is_err() {
+            return;
+        }
+        loop {
+            let mut buf = match spare.take().or_else(|| pool.try_recv().ok()) {
+                Some(buf) => buf,
+                None if allocated < QUEUE_DEPTH + 2 => {
+                    allocated += 1;
+                    vec![0; chunk_size]
+                },
+                None => match pool.recv() {
+                    Ok(buf) => buf,
+                    Err(_) => return,
+                },
+            };
+            let (chunk, last) = match fill(&mut file, &mut buf) {
+                Ok(0) => {
+                    spare = Some(buf);
+                    (Chunk::End, true)
+                },
+                Ok(len) => (Chunk::Data(buf, len), len < chunk_size),
+                Err(e) => {
+                    spare = Some(buf);
+                    (Chunk::Failed(e), true)
+                },
+            };
+            let end = match chunk {
+                Chunk::Data(_, _) if last => true,
+                _ => false,
+            };
+            if chunks.send(chunk).is_err() {
+                return;
+            }
+            // a short chunk is the last one of the file
+            if end && chunks.send(Chunk::End).is_err() {
+                return;
+            }
+            if last {
+                break;
+            }
+        }
+    }
+}
+
+// the content of a file, read from it or from the queue
+pub enum FileReader<'a> {
+    Direct(File),
+    Queued(Queued<'a>),
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        match *self {
+            FileReader::Direct(ref mut file) => file.read(out),
+            FileReader::Queued(ref mut queued) => queued.read(out),
+        }
+    }
+}
+
+pub struct Queued<'a> {
+    chunks: &'a Receiver<Chunk>,
+    pool: &'a Sender<Vec<u8>>,
+    buf: Vec<u8>,
+    len: usize,
+    pos: usize,
+    // the end or the error of the file was received
+    done: bool,
+}
+
+impl<'a> Queued<'a> {
+
+    // the next chunk of the file, false at its end
+    fn next_chunk(&mut self) -> Result<bool> {
+        if !self.buf.is_empty() {
+            let buf = ::std::mem::replace(&mut self.buf, Vec::new());
+            let _ = self.pool.send(buf);
+        }
+        self.len = 0;
+        self.pos = 0;
+        if self.done {
+            return Ok(false);
+        }
+        match self.chunks.recv() {
+            Ok(Chunk::Data(buf, len)) => {
+                self.buf = buf;
+                self.len = len;
+                Ok(true)
+            },
+            Ok(Chunk::End) => {
+                self.done = true;
+                Ok(false)
+            },
+            Ok(Chunk::Failed(e)) => {
+                self.done = true;
+                Err(e)
+            },
+            _ => {
+                self.done = true;
+                Err(stopped())
+            },
+        }
+    }
+}
+
+impl<'a> Read for Queued<'a> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        while self.pos == self.len {
+            if !try!(self.next_chunk()) {
+                return Ok(0);
+            }
+        }
+        let n = out.len().min(self.len - self.pos);
+        out[.. n].copy_from_slice(&self.buf[self.pos .. self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<'a> Drop for Queued<'a> {
+    // the rest of the file is left on the queue otherwise
+    fn drop(&mut self) {
+        while let Ok(true) = self.next_chunk() {}
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use std::time::Instant;
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use corpus::{self, Plant, SizeDist};
+    use fingerprint::fingerprint;
+
+    #[test]
+    fn same_bytes_as_the_files() {
+
+        let contents: Vec<Vec<u8>> = vec![
+            b"call GetProcAddress".to_vec(),
+            Vec::new(),
+            (0 .. 100u32).map(|i| (i * 7) as u8).collect(),
+            // exactly two chunks of 50 bytes
+            (0 .. 100u32).map(|i| (i * 13) as u8).collect(),
+        ];
+        let mut paths = Vec::new();
+        for (i, content) in contents.iter().enumerate() {
+            let path = format!("test_prefetch_{}.bin", i);
+            File::create(&path).unwrap().write_all(content).unwrap();
+            paths.push(path);
+        }
+        paths.insert(1, String::from("test_prefetch_missing.bin"));
+        paths.insert(2, String::from("."));
+
+        for chunk_size in &[0, 1, 7, 50, DEFAULT_CHUNK_SIZE] {
+            let mut prefetch = Prefetch::start(paths.clone(), *chunk_size);
+            for path in &paths {
+                match (prefetch.next(), path.as_str()) {
+                    (Next::Failed(e), "test_prefetch_missing.bin") => assert_eq!(ErrorKind::NotFound, e.kind()),
+                    (Next::Skipped(reason), ".") => assert_eq!("not a regular file", reason),
+                    (Next::File(mut reader), path) => {
+                        let mut read = Vec::new();
+                        if path == "test_prefetch_2.bin" {
+                            // left half read, the rest is drained
+                            let mut half = [0u8; 40];
+                            reader.read_exact(&mut half).unwrap();
+                            read.extend_from_slice(&half);
+                            drop(reader);
+                            assert_eq!(&contents[2][.. 40], &read[..], "chunk size {}", chunk_size);
+                            continue;
+                        }
+                        reader.read_to_end(&mut read).unwrap();
+                        assert_eq!(fs::read(path).unwrap(), read, "{} chunk size {}", path, chunk_size);
+                    },
+                    _ => panic!("{} chunk size {}", path, chunk_size),
+                }
+            }
+            assert!(match prefetch.next() { Next::Failed(_) => true, _ => false });
+        }
+        for path in &paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn same_index_as_synchronous_reads() {
+
+        let _ = fs::remove_dir_all("test_prefetch_corpus");
+        let plants = Plant::parse_list("4765745072@20,c0a80101@3").unwrap();
+        corpus::generate("test_prefetch_corpus", 40, SizeDist::parse("lognormal:10,0.5").unwrap(), 5, &plants).unwrap();
+
+        let mut fingerprints = Vec::new();
+        for (i, chunk_size) in [0, 7, DEFAULT_CHUNK_SIZE].iter().enumerate() {
+            let name = format!("test_prefetch_db{}", i);
+            let mut db = BinacleManager::create(&format!("{}.db", name), true, 1 << 30, 4, 16).unwrap();
+            db.set_deterministic(true);
+            db.set_prefetch(*chunk_size);
+            db.insert_dir_recursive("test_prefetch_corpus").unwrap();
+            assert_eq!(40, db.nb_file());
+            fingerprints.push(fingerprint(&mut db).unwrap());
+            drop(db);
+            cleanup(&name);
+        }
+        assert_eq!(fingerprints[0], fingerprints[1]);
+        assert_eq!(fingerprints[0], fingerprints[2]);
+        let _ = fs::remove_dir_all("test_prefetch_corpus");
+    }
+
+    // cargo test --release -- --ignored prefetch_benchmark --nocapture
+    #[test]
+    #[ignore]
+    fn prefetch_benchmark() {
+
+        let _ = fs::remove_dir_all("test_prefetch_bench_corpus");
+        let truth = corpus::generate("test_prefetch_bench_corpus", 2000, SizeDist::LogNormal { mean: 11.0, sigma: 1.0 }, 7, &[]).unwrap();
+        let bytes: u64 = truth.files.iter().map(|f| fs::metadata(f).unwrap().len()).sum();
+
+        let run = |chunk_size: usize| {
+            let mut db = BinacleManager::create("test_prefetch_bench.db", true, 1 << 30, 4, 16).unwrap();
+            db.set_prefetch(chunk_size);
+            let start = Instant::now();
+            db.insert_dir_recursive("test_prefetch_bench_corpus").unwrap();
+            let elapsed = start.elapsed();
+            drop(db);
+            cleanup("test_prefetch_bench");
+            elapsed
+        };
+        let sync = run(0);
+        let prefetched = run(DEFAULT_CHUNK_SIZE);
+
+        let mb = bytes as f64 / (1 << 20) as f64;
+        println!("{} files, {:.1} MB: synchronous {:?} ({:.1} MB/s), prefetched {:?} ({:.1} MB/s)",
+                 truth.files.len(), mb, sync, mb / sync.as_secs_f64(), prefetched, mb / prefetched.as_secs_f64());
+        let _ = fs::remove_dir_all("test_prefetch_bench_corpus");
+    }
+}
@@ -0,0 +1,450 @@
+use std::io::*;
+
+use binacle::{read_ngrams, short_pattern};
+use binacle_manager::BinacleManager;
+use context;
+use dedup::{DedupStats, SeenSet};
+use hash::{self, HashReader};
+use translate::{Translation, TranslateReader};
+
+// What was done to a file before its n-grams were indexed: the
+// transformations applied and the regions whose n-grams are not in the index,
+// with the reason. Kept in the map so a missing hit can be explained.
+
+
+// regions kept per entry, the next ones are merged into the last one
+pub const MAX_SKIPPED_RANGES: usize = 32;
+
+// n-grams starting in [offset .. offset + len[ were not indexed
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct SkippedRange {
+    pub offset: u64,
+    pub len: u64,
+    pub reason: String,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, Default, PartialEq)]
+pub struct Provenance {
+    // applied before indexing, in order
+    pub transforms: Vec<String>,
+    // of the indexed content, after the transformations
+    pub size: u64,
+    pub skipped: Vec<SkippedRange>,
+    // n-grams read and written to the index, the repeated ones are not
+    // (None for the entries of older versions)
+    pub ngrams: Option<u64>,
+    pub writes: Option<u64>,
+}
+
+impl Provenance {
+
+    pub fn skip(&mut self, offset: u64, len: u64, reason: &str) {
+
+        if len == 0 {
+            return;
+        }
+        let full = self.skipped.len() >= MAX_SKIPPED_RANGES;
+        if let Some(last) = self.skipped.last_mut() {
+            let contiguous = last.offset.saturating_add(last.len) == offset && last.reason == reason;
+            if contiguous || full {
+                // over the limit the regions get coarser, never smaller
+                last.len = offset.saturating_add(len) - last.offset;
+                if last.reason != reason && !last.reason.ends_with(", ...") {
+                    last.reason.push_str(", ...");
+                }
+                return;
+            }
+        }
+        self.skipped.push(SkippedRange { offset: offset, len: len, reason: String::from(reason) });
+    }
+
+    // the size is known once the whole content was read, the regions
+    // running to the end are cut there
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size;
+        self.skipped.retain(|r| r.offset < size);
+        for r in &mut self.skipped {
+            r.len = r.len.min(size - r.offset);
+        }
+    }
+
+    pub fn set_dedup(&mut self, dedup: DedupStats) {
+        self.ngrams = Some(dedup.ngrams);
+        self.writes = Some(dedup.writes);
+    }
+
+    // n-grams read for each one written
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        match (self.ngrams, self.writes) {
+            (Some(ngrams), Some(writes)) => Some(DedupStats { ngrams: ngrams, writes: writes }.ratio()),
+            _ => None,
+        }
+    }
+
+    // n-gram positions not indexed
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped.iter().map(|r| r.len).sum()
+    }
+
+    // part of the n-grams in the index
+    pub fn coverage(&self) -> f64 {
+        let positions = self.size.saturating_sub(3);
+        match positions {
+            0 => 1.0,
+            n => n.saturating_sub(self.skipped_bytes()) as f64 / n as f64,
+        }
+    }
+
+    // [start, end[ of the n-gram positions indexed, between the skipped regions
+    pub fn covered(&self) -> Vec<(u64, u64)> {
+        let positions = self.size.saturating_sub(3);
+        let mut covered = Vec::new();
+        let mut start = 0;
+        for r in &self.skipped {
+            if start < r.offset && start < positions {
+                covered.push((start, r.offset.min(positions)));
+            }
+            start = start.max(r.offset.saturating_add(r.len));
+        }
+        if start < positions {
+            covered.push((start, positions));
+        }
+        covered
+    }
+
+    // why a pattern of len bytes at this offset may be missing from the index
+    pub fn explain(&self, offset: u64, len: u64) -> Option<String> {
+        let last = offset + len.saturating_sub(4);
+        self.skipped.iter()
+            .find(|r| r.offset <= last && offset < r.offset + r.len)
+            .map(|r| format!("pattern occurs at offset {} which was in a skipped {} region ({}..{})",
+                             offset, r.reason, r.offset, r.offset + r.len))
+    }
+}
+
+// why the index does not return this file for the pattern, None when it does
+pub fn explain_miss(db: &mut BinacleManager, id: u32, pattern: &[u8]) -> Result<Option<String>> {
+
+    if try!(db.search(pattern)).contains(&id) {
+        return Ok(None);
+    }
+
+    let entry = match db.entry(id) {
+        Some(e) => e.clone(),
+        None => return Err(Error::new(ErrorKind::NotFound, format!("id {} not found in map", id))),
+    };
+    if db.translation().is_some() {
+        return Ok(Some(String::from("the database is translated, the offsets in the file can not be checked")));
+    }
+
+    let offsets = try!(context::find_offsets(&entry.path, pattern));
+    if offsets.is_empty() {
+        return Ok(Some(String::from("the pattern is not in the file")));
+    }
+
+    let provenance = match entry.provenance {
+        Some(p) => p,
+        None => return Ok(Some(String::from("the pattern is in the file, the entry has no provenance record"))),
+    };
+    for offset in &offsets {
+        if let Some(reason) = provenance.explain(*offset, pattern.len() as u64) {
+            return Ok(Some(reason));
+        }
+    }
+    Ok(Some(format!("pattern occurs at offset {}, no skipped region explains the miss", offsets[0])))
+}
+
+// the extraction of an insertion replayed over a content, nothing is
+// written: the n-grams given are counted, the positions without one are
+// skipped, and the reduced n-grams of ngram_size bits the insertion would
+// write are counted once. Returns the provenance and the sha256 the
+// insertion would record.
+pub fn replay<R: Read>(reader: R, translation: Option<&Translation>, ngram_size: u8) -> Result<(Provenance, [u8; 32], u64)> {
+
+    let mut reader = HashReader::new(reader);
+    let mut seen = SeenSet::new(ngram_size);
+    let mask = ((1u64 << ngram_size) - 1) as u32;
+    let mut dedup = DedupStats::default();
+    let read = try!(read_ngrams(TranslateReader::new(&mut reader, translation), |ngram| {
+        dedup.ngrams += 1;
+        if seen.insert(ngram & mask) {
+            dedup.writes += 1;
+        }
+        Ok(())
+    }));
+    let (sha256, size) = try!(reader.finish_len());
+
+    let mut provenance = Provenance::default();
+    let positions = read.saturating_sub(3);
+    provenance.skip(dedup.ngrams, positions.saturating_sub(dedup.ngrams), "not extracted");
+    provenance.set_size(size);
+    provenance.set_dedup(dedup);
+    Ok((provenance, sha256, dedup.ngrams))
+}
+
+// what the index can see of a file, as it is now
+#[derive(Clone, Debug)]
+pub struct Coverage {
+    pub id: u32,
+    pub path: String,
+    pub current: Provenance,
+    // recorded when the file was indexed, None for the older entries
+    pub stored: Option<Provenance>,
+    // n-grams given to the index: with a translation the positions are the
+    // ones of the translated content
+    pub ngrams: u64,
+    pub translated: bool,
+    // how the file differs from the indexed one, empty when it does not
+    pub changes: Vec<String>,
+}
+
+impl Coverage {
+
+    pub fn changed(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    // n-gram positions of the ranges below
+    pub fn positions(&self) -> u64 {
+        match self.translated {
+            true => self.ngrams,
+            false => self.current.size.saturating_sub(3),
+        }
+    }
+
+    // [start, end[ of the positions giving an n-gram
+    pub fn covered(&self) -> Vec<(u64, u64)> {
+        match (self.translated, self.ngrams) {
+            (true, 0) => Vec::new(),
+            (true, n) => vec![(0, n)],
+            (false, _) => self.current.covered(),
+        }
+    }
+}
+
+// replay the insertion of the file of an id over its current content, and
+// compare it with what was recorded when it was indexed
+pub fn coverage_of(db: &BinacleManager, id: u32) -> Result<Coverage> {
+
+    let entry = match db.entry(id) {
+        Some(e) => e.clone(),
+        None => return Err(Error::new(ErrorKind::NotFound, format!("id {} not found in map", id))),
+    };
+    let file = try!(::std::fs::File::open(&entry.path));
+    let (current, sha256, ngrams) = try!(replay(file, db.translation(), db.ngram_size()));
+
+    let mut changes = Vec::new();
+    if let Some(ref indexed) = entry.sha256 {
+        let now = hash::to_hex(&sha256);
+        if *indexed != now {
+            changes.push(format!("sha256 {} when indexed, {} now", indexed, now));
+        }
+    }
+    if let Some(ref stored) = entry.provenance {
+        if stored.size != current.size {
+            changes.push(format!("size {} when indexed, {} now", stored.size, current.size));
+        }
+        if stored.skipped != current.skipped {
+            changes.push(format!("{} byte(s) skipped when indexed, {} now", stored.skipped_bytes(), current.skipped_bytes()));
+        }
+    }
+
+    Ok(Coverage {
+        id: id,
+        path: entry.path,
+        current: current,
+        stored: entry.provenance,
+        ngrams: ngrams,
+        translated: db.translation().is_some(),
+        changes: changes,
+    })
+}
+
+// presence of the id in the list of one n-gram of a pattern
+#[derive(Clone, Debug, PartialEq)]
+pub struct NgramCheck {
+    // the n-gram is pattern[offset .. offset + 4], of the translated
+    // pattern when the database has a translation
+    pub offset: usize,
+    // reduced to the ngram_size of the database
+    pub ngram: u32,
+    pub present: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct WhyNot {
+    pub checks: Vec<NgramCheck>,
+    // None when the index returns the id
+    pub explanation: Option<String>,
+}
+
+impl WhyNot {
+
+    pub fn first_missing(&self) -> Option<&NgramCheck> {
+        self.checks.iter().find(|c| !c.present)
+    }
+}
+
+// the n-grams of the pattern whose list misses the id, and why when the
+// map tells it
+pub fn why_not(db: &mut BinacleManager, id: u32, pattern: &[u8]) -> Result<WhyNot> {
+
+    let translated = db.normalize(pattern).into_owned();
+    if translated.len() < 4 {
+        return Err(short_pattern());
+    }
+
+    let mask = ((1u64 << db.ngram_size()) - 1) as u32;
+    let checks = {
+        let shards = try!(db.open_indexes());
+        translated.windows(4).enumerate().map(|(offset, w)| {
+            let ngram = u32::from_ne_bytes([w[0], w[1], w[2], w[3]]);
+            NgramCheck {
+                offset: offset,
+                ngram: ngram & mask,
+                present: shards.iter().any(|s| s.has_id(ngram, id)),
+            }
+        }).collect()
+    };
+
+    let explanation = match db.is_map() {
+        true => try!(explain_miss(db, id, pattern)),
+        false if db.search(pattern).map(|ids| ids.contains(&id)).unwrap_or(false) => None,
+        false => Some(String::from("the database has no map, the file can not be checked")),
+    };
+    Ok(WhyNot { checks: checks, explanation: explanation })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::tests::cleanup;
+
+    // returns the content a few bytes at a time
+    struct Chunked<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Chunked<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let len = self.1.min(self.0.len()).min(buf.len());
+            buf[.. len].copy_from_slice(&self.0[.. len]);
+            self.0 = &self.0[len ..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let mut p = Provenance::default();
+        p.skip(5, 3, "read boundary");
+        p.skip(8, 2, "read boundary");
+        p.skip(20, u64::max_value() - 20, "short read");
+        p.set_size(24);
+        assert_eq!(2, p.skipped.len());
+        assert_eq!(9, p.skipped_bytes());
+        assert!(p.explain(0, 4).is_none());
+        assert!(p.explain(3, 6).is_some());
+        assert!(p.explain(10, 10).is_none());
+        assert_eq!(vec![(0, 5), (10, 20)], p.covered());
+        assert_eq!(vec![(0, 7)], Provenance { size: 10, ..Provenance::default() }.covered());
+        assert!(Provenance::default().covered().is_empty());
+    }
+
+    #[test]
+    fn coverage_of_changed_file() {
+
+        // text, then pseudo-random bytes, then a run of zeros
+        let mut content = b"This program cannot be run in DOS mode.".to_vec();
+        let mut x = 0x2545f491u32;
+        for _ in 0 .. 4096 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            content.push(x as u8);
+        }
+        content.extend(vec![0u8; 1024]);
+        File::create("test_coverage.bin").unwrap().write_all(&content).unwrap();
+        {
+            let mut db = BinacleManager::create("test_coverage.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_file("test_coverage.bin", 1, true).unwrap();
+
+            let c = coverage_of(&db, 1).unwrap();
+            assert!(!c.changed());
+            assert_eq!(c.stored.as_ref(), Some(&c.current));
+            assert_eq!(content.len() as u64 - 3, c.ngrams);
+            assert_eq!(vec![(0, content.len() as u64 - 3)], c.covered());
+            assert_eq!(1.0, c.current.coverage());
+
+            // the replay matches the insertion, whatever the reads
+            let (p, _, ngrams) = replay(Chunked(&content, 7), None, 16).unwrap();
+            assert_eq!(c.current, p);
+            assert_eq!(c.ngrams, ngrams);
+
+            content.truncate(100);
+            File::create("test_coverage.bin").unwrap().write_all(&content).unwrap();
+            let c = coverage_of(&db, 1).unwrap();
+            assert_eq!(2, c.changes.len());
+            assert!(c.changes[1].starts_with("size 5159 when indexed, 100 now"));
+            assert_eq!(vec![(0, 97)], c.covered());
+
+            assert_eq!(ErrorKind::NotFound, coverage_of(&db, 2).unwrap_err().kind());
+        }
+        cleanup("test_coverage");
+        let _ = fs::remove_file("test_coverage.bin");
+    }
+
+    #[test]
+    fn read_boundaries_indexed() {
+
+        let content = b"AAAAGetProcAddressBBBB";
+        File::create("test_provenance.bin").unwrap().write_all(content).unwrap();
+        {
+            let mut db = BinacleManager::create("test_prov.db", true, 1 << 30, 6, 16).unwrap();
+            let file = fs::File::open("test_provenance.bin").unwrap();
+            db.insert_reader(file, "test_provenance.bin", 1, false);
+            db.insert_reader(Chunked(content, 8), "test_provenance.bin", 2, true);
+
+            // the 4-grams across the reads are indexed
+            let p = db.entry(2).unwrap().provenance.clone().unwrap();
+            assert_eq!(22, p.size);
+            assert_eq!(1.0, p.coverage());
+            assert_eq!(None, explain_miss(&mut db, 1, b"GetProcAddress").unwrap());
+            assert_eq!(None, explain_miss(&mut db, 2, b"GetProcAddress").unwrap());
+            assert_eq!(Some(String::from("the pattern is not in the file")),
+                       explain_miss(&mut db, 2, b"LoadLibrary").unwrap());
+        }
+        cleanup("test_prov");
+        let _ = fs::remove_file("test_provenance.bin");
+    }
+
+    #[test]
+    fn why_not_absent_or_changed() {
+
+        File::create("test_why_not.bin").unwrap().write_all(b"AAAAGetProcAddressBBBB").unwrap();
+        {
+            let mut db = BinacleManager::create("test_why_not.db", true, 1 << 30, 6, 16).unwrap();
+            // the file changed since it was indexed
+            db.insert_reader(Chunked(b"AAAAGetProcXXXXXXXBBBB", 8), "test_why_not.bin", 1, true);
+
+            // 16-bit n-grams: the ones starting in "cAddr" are missing
+            let lost = why_not(&mut db, 1, b"GetProcAddress").unwrap();
+            assert_eq!(11, lost.checks.len());
+            let missing: Vec<usize> = lost.checks.iter().filter(|c| !c.present).map(|c| c.offset).collect();
+            assert_eq!(vec![6, 7, 8, 9, 10], missing);
+            assert!(lost.explanation.as_ref().unwrap().contains("no skipped region explains the miss"));
+
+            // really absent
+            let absent = why_not(&mut db, 1, b"GetModuleHandle").unwrap();
+            assert!(absent.first_missing().is_some());
+            assert_eq!(Some(String::from("the pattern is not in the file")), absent.explanation);
+
+            let found = why_not(&mut db, 1, b"AAAAGet").unwrap();
+            assert!(found.first_missing().is_none());
+            assert!(found.explanation.is_none());
+        }
+        cleanup("test_why_not");
+        let _ = fs::remove_file("test_why_not.bin");
+    }
+}
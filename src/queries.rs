@@ -0,0 +1,416 @@
+use std::fs::OpenOptions;
+use std::io::*;
+use std::time::Duration;
+use regex::Regex;
+use rustc_serialize::hex::FromHex;
+
+use search::SearchRequest;
+use variants::ByteSwap;
+use binacle_manager::BinacleManager;
+use pattern_file::{strip_bom, unprintable_warning};
+
+// A file of queries run together by search::run_batch, one a line:
+//
+//   [<name>: ] <term> [<term>...] [| <option>, <option>...]
+//
+// All the terms must be found, but the ones starting by '!' which must not.
+// A term is a word, a "quoted string" (\" \\ and \xNN escapes) or hex:<bytes>.
+// The options are limit=<n>, sample=<n>, seed=<s>, tag=<tag>, type=<type>,
+// id-range=<min>-<max>, path-contains=<s>, path-match=<regex>,
+// deadline-ms=<ms>, endian=both|words, exact, wide and strict. A query
+// without a name is named by its line number, '#' starts a comment line.
+// The UTF-8 byte order mark of the first line is stripped, the raw bytes out
+// of printable ASCII are warned about: they are written by \xNN or hex:.
+
+
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub name: String,
+    // line of the file, from 1
+    pub line: usize,
+    pub request: SearchRequest,
+    // what was stripped of the line, see pattern_file
+    pub notes: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+// a term: excluded or not, and its bytes
+fn parse_term(term: &str) -> Option<(bool, Vec<u8>)> {
+
+    let (excluded, term) = match term.starts_with('!') {
+        true => (true, &term[1 ..]),
+        false => (false, term),
+    };
+
+    if term.starts_with("hex:") {
+        return term[4 ..].from_hex().ok().map(|b| (excluded, b));
+    }
+    if !term.starts_with('"') {
+        return Some((excluded, Vec::from(term.as_bytes())));
+    }
+    if term.len() < 2 || !term.ends_with('"') {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    let mut chars = term[1 .. term.len() - 1].bytes();
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(b'x') => {
+                let hex: Vec<u8> = chars.by_ref().take(2).collect();
+                match String::from_utf8(hex).ok().and_then(|h| h.from_hex().ok()) {
+                    Some(ref b) if b.len() == 1 => bytes.push(b[0]),
+                    _ => return None,
+                }
+            },
+            Some(c) => bytes.push(c),
+            None => return None,
+        }
+    }
+    Some((excluded, bytes))
+}
+
+// the terms of an expression, a quoted string may hold spaces
+fn split_terms(expr: &str) -> Option<Vec<String>> {
+
+    let mut terms = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in expr.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    terms.push(current.clone());
+                    current.clear();
+                }
+                continue;
+            },
+            _ => {},
+        }
+        current.push(c);
+    }
+    if quoted {
+        return None;
+    }
+    if !current.is_empty() {
+        terms.push(current);
+    }
+    Some(terms)
+}
+
+fn apply_option(request: SearchRequest, option: &str) -> Option<SearchRequest> {
+
+    let mut kv = option.splitn(2, '=');
+    let key = kv.next().unwrap().trim();
+    let value = kv.next().map(|v| v.trim());
+
+    let number = value.and_then(|v| v.parse::<u64>().ok());
+
+    Some(match (key, value, number) {
+        ("limit", _, Some(n)) => request.limit(n as usize),
+        ("sample", _, Some(n)) => request.sample(n as usize),
+        ("seed", _, Some(n)) => request.seed(n),
+        ("deadline-ms", _, Some(n)) => request.deadline(Duration::from_millis(n)),
+        ("tag", Some(v), _) => request.tag(v),
+        ("type", Some(v), _) => request.file_type(v),
+        ("path-contains", Some(v), _) => request.path_contains(v),
+        ("path-match", Some(v), _) => match request.path_match(v) {
+            Ok(r) => r,
+            Err(_) => return None,
+        },
+        ("id-range", Some(v), _) => {
+            let mut bounds = v.splitn(2, '-').map(|x| x.parse::<u32>().ok());
+            match (bounds.next(), bounds.next()) {
+                (Some(Some(min)), Some(Some(max))) => request.id_range(min ..= max),
+                _ => return None,
+            }
+        },
+        ("endian", Some("both"), _) => request.both_endian(ByteSwap::Full),
+        ("endian", Some("words"), _) => request.both_endian(ByteSwap::Words),
+        ("exact", None, _) => request.exact(),
+        ("wide", None, _) => request.wide(),
+        ("strict", None, _) => request.strict(),
+        _ => return None,
+    })
+}
+
+// a line of a queries file, None for a comment or an empty line
+pub fn parse_query(line: &str, nb: usize) -> Result<Option<Query>> {
+
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("line {}: {}", nb, what));
+
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(None);
+    }
+
+    let named = Regex::new(r"^([\w.-]+):\s+(.*)$").unwrap();
+    let (name, rest) = match named.captures(trimmed) {
+        Some(cap) => (String::from(&cap[1]), String::from(&cap[2])),
+        None => (format!("line {}", nb), String::from(trimmed)),
+    };
+
+    // the options follow the last '|' out of a quoted string
+    let terms_end = {
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut end = None;
+        for (i, c) in rest.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                '|' if !quoted => end = Some(i),
+                _ => {},
+            }
+        }
+        end
+    };
+    let (expr, options) = match terms_end {
+        Some(i) => (&rest[.. i], Some(&rest[i + 1 ..])),
+        None => (&rest[..], None),
+    };
+
+    let terms = try!(split_terms(expr).ok_or_else(|| invalid("unterminated string")));
+    let mut patterns = Vec::new();
+    let mut excludes = Vec::new();
+    for term in &terms {
+        match parse_term(term) {
+            Some((true, bytes)) => excludes.push(bytes),
+            Some((false, bytes)) => patterns.push(bytes),
+            None => return Err(invalid(&format!("invalid term {}", term))),
+        }
+    }
+    if patterns.is_empty() {
+        return Err(invalid("no pattern to look for"));
+    }
+
+    let mut request = SearchRequest::pattern(&patterns[0]);
+    for p in &patterns[1 ..] {
+        request = request.and(p);
+    }
+    for p in &excludes {
+        request = request.exclude(p);
+    }
+    for option in options.iter().flat_map(|o| o.split(',')).filter(|o| !o.trim().is_empty()) {
+        request = try!(apply_option(request, option).ok_or_else(|| invalid(&format!("invalid option {}", option.trim()))));
+    }
+
+    Ok(Some(Query { name: name, line: nb, request: request, notes: Vec::new(), warnings: Vec::new() }))
+}
+
+pub fn read_queries(path: &str) -> Result<Vec<Query>> {
+
+    let file = try!(OpenOptions::new().read(true).open(path));
+    let mut queries = Vec::new();
+    for (nb, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = try!(line);
+        let (line, bom) = match nb == 0 {
+            true => strip_bom(&line).map_or((&line[..], false), |rest| (rest, true)),
+            false => (&line[..], false),
+        };
+        let line = try!(String::from_utf8(line.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e)));
+        match parse_query(line.trim_end_matches('\r'), nb + 1) {
+            Ok(Some(mut q)) => {
+                if bom {
+                    q.notes.push(String::from("stripped the UTF-8 byte order mark"));
+                }
+                let raw = line.trim().replace('\t', " ");
+                q.warnings.extend(unprintable_warning(raw.as_bytes()).map(|w| format!("the line has {}", w)));
+                queries.push(q)
+            },
+            Ok(None) => {},
+            Err(e) => return Err(Error::new(e.kind(), format!("{}: {}", path, e))),
+        }
+    }
+    Ok(queries)
+}
+
+// the query of the first line of input, its results written to out the
+// way --queries prints them: one <name>\t<path> (or id) a line, then
+// "ok <n>" or "error <message>". A process started for a connection
+// (--inetd) answers one query and exits
+pub fn answer_one<R: BufRead, W: Write>(db: &mut BinacleManager, mut input: R, out: &mut W) -> Result<usize> {
+
+    let mut line = String::new();
+    let answer = input.read_line(&mut line)
+        .and_then(|_| parse_query(line.trim_start_matches('\u{feff}'), 1))
+        .and_then(|q| q.ok_or_else(|| Error::new(ErrorKind::InvalidData, "no query")))
+        .and_then(|q| db.run(&q.request).map(|response| (q, response)));
+
+    let (query, response) = match answer {
+        Ok(answer) => answer,
+        Err(e) => {
+            try!(writeln!(out, "error {}", e));
+            return Err(e);
+        },
+    };
+    match db.is_map() {
+        true => for path in &response.entries {
+            try!(writeln!(out, "{}\t{}", query.name, path));
+        },
+        false => for id in &response.ids {
+            try!(writeln!(out, "{}\t{}", query.name, id));
+        },
+    }
+    try!(writeln!(out, "ok {}", response.total));
+    Ok(response.total)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Instant;
+    use binacle_manager::{BinacleManager, MapPolicy};
+    use binacle_manager::tests::cleanup;
+    use search::run_batch;
+
+    #[test]
+    fn lines() {
+        assert!(parse_query("  # comment", 1).unwrap().is_none());
+        assert!(parse_query("", 2).unwrap().is_none());
+
+        let q = parse_query("feed-12: GetProcAddress \"a b|\\x00\\\"\" hex:4d5a !Load | limit=10, tag=feed", 3).unwrap().unwrap();
+        assert_eq!("feed-12", q.name);
+        assert_eq!(3, q.line);
+        let r = format!("{:?}", q.request);
+        assert!(r.contains("limit: Some(10)"), "{}", r);
+        assert!(r.contains("tags: [\"feed\"]"), "{}", r);
+        assert!(r.contains(&format!("{:?}", b"a b|\x00\"".to_vec())), "{}", r);
+        assert!(r.contains(&format!("{:?}", vec![0x4d, 0x5a])), "{}", r);
+        assert!(r.contains(&format!("excludes: [{:?}]", b"Load".to_vec())), "{}", r);
+
+        assert_eq!("line 4", parse_query("GetProcAddress", 4).unwrap().unwrap().name);
+        for bad in &["!only", "\"open", "hex:4g", "abcd | limit=x", "abcd | unknown", "abcd | id-range=3"] {
+            let e = parse_query(bad, 7).unwrap_err();
+            assert!(e.to_string().starts_with("line 7: "), "{}", e);
+        }
+    }
+
+    // a database of several shards and the queries of a sweep
+    fn fixture(name: &str, files: u32, max_index_size: u64) -> BinacleManager {
+        let mut db = BinacleManager::create(name, true, max_index_size, 6, 16).unwrap();
+        for id in 1 ..= files {
+            let content = format!("header {} call GetProcAddress ioc{:03} LoadLibraryA {}", id % 7, id % 100, "x".repeat(id as usize % 50));
+            db.insert_reader(content.as_bytes(), &format!("file{}.bin", id), id, false);
+        }
+        db
+    }
+
+    fn sweep() -> Vec<SearchRequest> {
+        (0 .. 100).map(|i| {
+            let request = SearchRequest::pattern(format!("ioc{:03}", i).as_bytes());
+            match i % 4 {
+                0 => request.and(b"GetProcAddress"),
+                1 => request.exclude(b"header 3"),
+                2 => request.limit(2),
+                _ => request,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn one_answer() {
+
+        drop(fixture("test_queries3.db", 20, 1 << 30));
+        {
+            // as --inetd opens it: the map is not loaded
+            let mut db = BinacleManager::open_with("test_queries3.db", MapPolicy { cap: Some(0), lazy: true }).unwrap();
+            assert!(db.is_lazy_map());
+
+            let mut out = Vec::new();
+            let total = answer_one(&mut db, &b"hit: ioc01 | limit=3\nignored\n"[..], &mut out).unwrap();
+            // ioc010 to ioc019, and the n-gram collisions of 16 bits
+            assert!(total >= 10);
+            let out = String::from_utf8(out).unwrap();
+            let lines: Vec<&str> = out.lines().collect();
+            assert_eq!(4, lines.len());
+            assert!(lines[.. 3].iter().all(|l| l.starts_with("hit\tfile")), "{}", out);
+            assert_eq!(format!("ok {}", total), lines[3]);
+
+            let mut out = Vec::new();
+            assert!(answer_one(&mut db, &b"\"open\n"[..], &mut out).is_err());
+            assert!(String::from_utf8(out).unwrap().starts_with("error line 1: "));
+            let mut out = Vec::new();
+            assert!(answer_one(&mut db, &b""[..], &mut out).is_err());
+            assert_eq!("error no query\n", String::from_utf8(out).unwrap());
+        }
+        cleanup("test_queries3");
+    }
+
+    #[test]
+    fn batch_same_results() {
+
+        let mut db = fixture("test_queries.db", 300, 340_000);
+        assert!(db.nb_index() > 2 && db.nb_index() < 8, "{} shard(s)", db.nb_index());
+
+        let mut requests = sweep();
+        requests.push(SearchRequest::pattern(b"abc"));
+        requests.push(SearchRequest::pattern(b"GetProcAddress"));
+        // the files do not exist, the request fails alone
+        requests.push(SearchRequest::pattern(b"GetProcAddress").exact());
+
+        let report = run_batch(&mut db, &requests);
+        assert_eq!(requests.len(), report.results.len());
+        assert_eq!(requests.len(), report.elapsed.len());
+        for (request, result) in requests.iter().zip(&report.results) {
+            match (db.run(request), result) {
+                (Ok(a), &Ok(ref b)) => {
+                    assert_eq!(a.ids, b.ids);
+                    assert_eq!(a.total, b.total);
+                    assert_eq!(a.warnings.len(), b.warnings.len());
+                },
+                (Err(_), &Err(_)) => {},
+                (a, b) => panic!("{:?} {:?}", a.map(|r| r.ids), b.as_ref().map(|r| r.ids.clone())),
+            }
+        }
+        // too short, the request fails alone like a single search does
+        assert_eq!(ErrorKind::InvalidInput, report.results[100].as_ref().err().unwrap().kind());
+        assert_eq!(300, report.results[101].as_ref().unwrap().total);
+        assert!(report.results[102].is_err());
+
+        drop(db);
+        cleanup("test_queries");
+    }
+
+    // cargo test --release -- --ignored batch_benchmark --nocapture
+    #[test]
+    #[ignore]
+    fn batch_benchmark() {
+
+        drop(fixture("test_queries_bench.db", 3000, 400_000));
+        let requests = sweep();
+
+        // one open a query, as separate invocations do
+        let sequential = Instant::now();
+        for request in &requests {
+            let mut db = BinacleManager::open("test_queries_bench.db").unwrap();
+            db.run(request).unwrap();
+        }
+        let sequential = sequential.elapsed();
+
+        let batch = Instant::now();
+        let mut db = BinacleManager::open("test_queries_bench.db").unwrap();
+        let report = run_batch(&mut db, &requests);
+        let batch = batch.elapsed();
+        assert!(report.results.iter().all(|r| r.is_ok()));
+
+        println!("{} shard(s), {} queries: sequential {:?}, batch {:?} ({:.1}x)",
+                 db.nb_index(), requests.len(), sequential, batch,
+                 sequential.as_secs_f64() / batch.as_secs_f64());
+        assert!(batch * 3 < sequential);
+
+        drop(db);
+        cleanup("test_queries_bench");
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::*;
+use std::sync::Mutex;
+
+// How the searches read the header and the blocks of a shard, chosen when
+// it is opened.
+//
+// - Mmap, by default: the shard is mapped and read as memory. A read that
+//   fails under the map (a stale NFS handle, a short outage of the network
+//   storage) is a SIGBUS, the process is killed in the middle of the query.
+// - Pread (--no-mmap-reads): the header entries and the blocks read by a
+//   search are positioned reads of the file. A failed read is an io error:
+//   the lookup sees an empty list, the error is kept and the search of the
+//   shard returns it, the shard is reported with the other shard warnings
+//   and the other shards complete the query. The shard is still mapped for
+//   the other uses (dumps, deletions), only the searches read through it.
+//
+// Performance: on local disk, the shard in the page cache, a lookup is a
+// system call and a copy by header entry and by block instead of a memory
+// access, so the searches reading many blocks (common patterns, long
+// chains) are several times slower than over the map; a cold shard is
+// bound by the disk and both paths are close. Pread is for the shards on
+// network storage, mmap stays the default.
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadPath {
+    Mmap,
+    Pread,
+}
+
+// positioned reads of a shard, the first error kept for its search (for
+// the next search to return it when several threads share the shard)
+pub struct Pread {
+    file: File,
+    error: Mutex<Option<Error>>,
+}
+
+impl Pread {
+
+    pub fn new(file: File) -> Pread {
+        Pread { file: file, error: Mutex::new(None) }
+    }
+
+    // the bytes at offset, zeros when they could not be read: the error is
+    // kept, the next reads still run
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) {
+        if let Err(e) = read_exact_at(&self.file, offset, buf) {
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            let mut error = self.error.lock().unwrap();
+            if error.is_none() {
+                *error = Some(Error::new(e.kind(), format!("read of {} bytes at {} failed: {}", buf.len(), offset, e)));
+            }
+        }
+    }
+
+    pub fn take_error(&self) -> Option<Error> {
+        self.error.lock().unwrap().take()
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut offset: u64, mut buf: &mut [u8]) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            Ok(n) => {
+                let tmp = buf;
+                buf = &mut tmp[n ..];
+                offset += n as u64;
+            },
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
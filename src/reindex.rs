@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, metadata, remove_file};
+use std::io::*;
+use rustc_serialize::json;
+
+use binacle::BinacleFile;
+use binacle_manager::BinacleManager;
+use collection::Collections;
+use library::Library;
+use search::Rng;
+
+// Rebuild of a database with other parameters from its index only, the
+// files are not read again. A n-gram is stored reduced to its ngram_size
+// low bits: the n-grams of the new index must be a further reduction of the
+// old ones, so the ngram_size can only stay or decrease (any ngram_size from
+// 32). The alignment only changes the layout and can take any value.
+//
+// Each shard is rebuilt into its own shard, the ids, the map, the
+// collections and the saved queries are kept. A checkpoint file next to the destination records
+// the shards done, running again after an interruption continues from there.
+
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+struct Checkpoint {
+    // shards of the source completely rebuilt
+    shards_done: usize,
+}
+
+fn checkpoint_path(dst: &str) -> String {
+    format!("{}.reindex", dst)
+}
+
+fn write_checkpoint(path: &str, checkpoint: &Checkpoint) -> Result<()> {
+    let encoded = try!(json::encode(checkpoint).map_err(|e| Error::new(ErrorKind::Other, e.to_string())));
+    let mut file = try!(File::create(path));
+    try!(file.write_all(encoded.as_bytes()));
+    file.sync_all()
+}
+
+fn read_checkpoint(path: &str) -> Result<Option<Checkpoint>> {
+    let mut content = String::new();
+    match OpenOptions::new().read(true).open(path) {
+        Ok(mut f) => try!(f.read_to_string(&mut content)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    json::decode(&content).map(Some).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e)))
+}
+
+// tells why the n-grams can not be converted
+pub fn check_feasible(src_ngram_size: u8, dst_ngram_size: u8) -> Result<()> {
+    if dst_ngram_size > src_ngram_size {
+        return Err(Error::new(ErrorKind::InvalidInput, format!(
+            "the n-grams are stored reduced to {} bits, the {} bits ones can not be recovered: \
+             choose a ngram_size of at most {}, or insert the files again in a new database",
+            src_ngram_size, dst_ngram_size, src_ngram_size)));
+    }
+    Ok(())
+}
+
+// the lists of the n-grams of the destination: the union of the lists of
+// the source n-grams reduced to each, sorted as the lists need them. The
+// lists of the shard are read once, in header order, and held in memory
+pub fn merged_lists(src: &BinacleFile, dst_ngram_size: u8) -> BTreeMap<u32, Vec<u32>> {
+    let mask = ((1u64 << dst_ngram_size) - 1) as u32;
+    let mut lists: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for (ngram, ids) in src.postings() {
+        lists.entry(ngram & mask).or_insert_with(Vec::new).extend(ids);
+    }
+    for ids in lists.values_mut() {
+        ids.sort();
+        ids.dedup();
+    }
+    lists
+}
+
+// rebuild src into dst, the progress is told after each shard
+pub fn reindex<F>(src: &mut BinacleManager, dst_path: &str, ngram_size: u8, alignment: u8, mut progress: F) -> Result<()>
+    where F: FnMut(usize, usize) {
+
+    try!(check_feasible(src.ngram_size(), ngram_size));
+
+    let checkpoint_path = checkpoint_path(dst_path);
+    let (mut dst, done) = match try!(read_checkpoint(&checkpoint_path)) {
+        Some(checkpoint) => {
+            let mut dst = try!(BinacleManager::open(dst_path));
+            if dst.ngram_size() != ngram_size || dst.alignment() != alignment {
+                return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "{} was started with ngram_size {} and alignment {}", dst_path, dst.ngram_size(), dst.alignment())));
+            }
+            // the shard in progress when interrupted is rebuilt
+            try!(dst.truncate_shards(checkpoint.shards_done));
+            (dst, checkpoint.shards_done)
+        },
+        None => {
+            if metadata(dst_path).is_ok() {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", dst_path)));
+            }
+            let mut dst = try!(BinacleManager::create(dst_path, src.is_map(), src.max_index_size(), alignment, ngram_size));
+            try!(dst.set_translation(src.translation().cloned()));
+            dst.copy_entries(src);
+            try!(try!(Collections::open(&src.db_path)).copy_to(dst_path));
+            try!(try!(Library::open(&src.db_path)).copy_to(dst_path));
+            (dst, 0)
+        },
+    };
+
+    dst.set_space_check(src.space_check());
+    let shards = try!(src.open_indexes());
+    for (n, shard) in shards.iter().enumerate().skip(done) {
+
+        // the shards rebuilt are kept, the next run resumes here
+        try!(dst.check_space());
+        {
+            let mut out = try!(dst.add_shard(src.shard_is_full(n)));
+            for (ngram, ids) in merged_lists(shard, ngram_size) {
+                try!(out.insert_postings(ngram, &ids));
+            }
+            out.copy_stats(shard);
+            if src.shard_is_full(n) {
+                try!(out.fix_size());
+            }
+            try!(out.flush());
+        }
+        if src.shard_is_full(n) {
+            let shard = dst.nb_index() - 1;
+            try!(dst.record_checksum(shard));
+        }
+
+        // the meta of the destination lists the shard before it is marked done
+        try!(dst.sync());
+        try!(write_checkpoint(&checkpoint_path, &Checkpoint { shards_done: n + 1 }));
+        progress(n + 1, shards.len());
+    }
+
+    drop(dst);
+    match remove_file(&checkpoint_path) {
+        Err(ref e) if e.kind() != ErrorKind::NotFound => Err(Error::new(e.kind(), e.to_string())),
+        _ => Ok(()),
+    }
+}
+
+// n-grams of a source and a destination compared
+#[derive(Clone, Debug, Default)]
+pub struct Verification {
+    pub samples: usize,
+    pub mismatches: Vec<u32>,
+}
+
+// draw random n-grams and check the destination returns the union of the
+// source n-grams reduced to them, shard by shard
+pub fn verify(src: &mut BinacleManager, dst: &mut BinacleManager, samples: usize, seed: u64) -> Result<Verification> {
+
+    let dst_ngram_size = dst.ngram_size();
+    try!(check_feasible(src.ngram_size(), dst_ngram_size));
+
+    let src_shards = try!(src.open_indexes());
+    let dst_shards = try!(dst.open_indexes());
+    if src_shards.len() != dst_shards.len() {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "{} shard(s) in the source, {} in the destination", src_shards.len(), dst_shards.len())));
+    }
+
+    let mut rng = Rng(seed);
+    let ngrams: Vec<u32> = (0 .. samples).map(|_| rng.next() as u32).collect();
+    let mask = ((1u64 << dst_ngram_size) - 1) as u32;
+    let mut differ = vec![false; samples];
+    for (s, d) in src_shards.iter().zip(&dst_shards) {
+        let expected = merged_lists(s, dst_ngram_size);
+        for (i, ngram) in ngrams.iter().enumerate() {
+            let mut found: Vec<u32> = d.get_ids_by_ngram(*ngram).into_iter().collect();
+            found.sort();
+            if expected.get(&(ngram & mask)).map_or(&[][..], |ids| &ids[..]) != &found[..] {
+                differ[i] = true;
+            }
+        }
+    }
+    let mismatches = ngrams.into_iter().zip(differ).filter(|&(_, d)| d).map(|(n, _)| n).collect();
+    Ok(Verification { samples: samples, mismatches: mismatches })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle_manager::tests::cleanup;
+
+    #[test]
+    fn reindex_smaller_ngrams() {
+        {
+            let mut src = BinacleManager::create("test_reindex_src.db", true, 1, 6, 18).unwrap();
+            src.insert_reader(&b"call GetProcAddress"[..], "a.exe", 1, false);
+            src.insert_reader(&b"LoadLibraryA"[..], "b.exe", 2, false);
+            src.insert_reader(&b"GetProcAddress again"[..], "c.exe", 5, true);
+            src.set_label(5, "third").unwrap();
+        }
+
+        let mut src = BinacleManager::open("test_reindex_src.db").unwrap();
+        assert!(reindex(&mut src, "test_reindex_dst.db", 20, 6, |_, _| {}).is_err());
+
+        // interrupted after the first shard
+        {
+            let mut dst = BinacleManager::create("test_reindex_dst.db", true, 1, 8, 16).unwrap();
+            dst.copy_entries(&src);
+            dst.add_shard(true).unwrap();
+            dst.add_shard(true).unwrap();
+        }
+        write_checkpoint("test_reindex_dst.db.reindex", &Checkpoint { shards_done: 0 }).unwrap();
+
+        let mut steps = Vec::new();
+        reindex(&mut src, "test_reindex_dst.db", 16, 8, |done, total| steps.push((done, total))).unwrap();
+        assert_eq!(vec![(1, 3), (2, 3), (3, 3)], steps);
+        assert!(metadata("test_reindex_dst.db.reindex").is_err());
+
+        let mut dst = BinacleManager::open("test_reindex_dst.db").unwrap();
+        assert_eq!(3, dst.nb_index());
+        assert_eq!(16, dst.ngram_size());
+        let mut ids: Vec<u32> = dst.search(b"GetProcAddress").unwrap().into_iter().collect();
+        ids.sort();
+        assert_eq!(vec![1, 5], ids);
+        assert_eq!(Some("b.exe"), dst.path_of(2));
+        assert_eq!(Some(5), dst.id_of_label("third"));
+
+        let report = verify(&mut src, &mut dst, 2000, 7).unwrap();
+        assert!(report.mismatches.is_empty());
+
+        drop(dst);
+        drop(src);
+        cleanup("test_reindex_src");
+        cleanup("test_reindex_dst");
+    }
+}
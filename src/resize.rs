@@ -0,0 +1,148 @@
+use std::io::*;
+
+// Growing (or shrinking) an index file that is mapped in memory.
+//
+// Lock levels required by the index operations, on every platform:
+//   create, open_write, insert, incr_size, fix_size: exclusive lock on the index file
+//   open_read, search: shared lock on the index file
+//   manager open / drop: exclusive lock on the manager file
+//
+// On Linux, set_len can be called while the file is mapped: the old mapping
+// stays valid (over the old length) until the file is mapped again.
+// On Windows, set_len fails on a file that still has a mapped view
+// (ERROR_USER_MAPPED_FILE), so the view has to be dropped before the file is
+// extended, then mapped again. The exclusive lock is held during the whole
+// sequence so no reader can see the unmapped window.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResizeStrategy {
+    // set_len on the mapped file, then map again (Unix)
+    RemapAfter,
+    // drop the map, set_len, then map again (Windows)
+    UnmapFirst,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResizeStep {
+    Unmap,
+    SetLen,
+    Remap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapState {
+    // the map covers the whole file
+    Mapped,
+    // no map on the file
+    Unmapped,
+    // the file was resized under the map, the map must not be used
+    Stale,
+}
+
+impl ResizeStrategy {
+
+    pub fn native() -> ResizeStrategy {
+        if cfg!(windows) {
+            ResizeStrategy::UnmapFirst
+        } else {
+            ResizeStrategy::RemapAfter
+        }
+    }
+
+    pub fn steps(&self) -> &'static [ResizeStep] {
+        match *self {
+            ResizeStrategy::RemapAfter => &[ResizeStep::SetLen, ResizeStep::Remap],
+            ResizeStrategy::UnmapFirst => &[ResizeStep::Unmap, ResizeStep::SetLen, ResizeStep::Remap],
+        }
+    }
+
+    // steps to run when a step failed, to go back to a mapped file
+    pub fn recover(&self, state: MapState) -> &'static [ResizeStep] {
+        match state {
+            MapState::Mapped => &[],
+            MapState::Unmapped | MapState::Stale => &[ResizeStep::Remap],
+        }
+    }
+}
+
+impl MapState {
+
+    // state after running a step, set_len is refused on a mapped file
+    // when the strategy does not allow it
+    pub fn apply(self, strategy: ResizeStrategy, step: ResizeStep) -> Result<MapState> {
+        match (self, step) {
+            (MapState::Mapped, ResizeStep::Unmap) => Ok(MapState::Unmapped),
+            (MapState::Mapped, ResizeStep::SetLen) if strategy == ResizeStrategy::RemapAfter => Ok(MapState::Stale),
+            (MapState::Unmapped, ResizeStep::SetLen) => Ok(MapState::Unmapped),
+            (MapState::Unmapped, ResizeStep::Remap) | (MapState::Stale, ResizeStep::Remap) => Ok(MapState::Mapped),
+            (state, step) => Err(Error::new(ErrorKind::Other,
+                format!("resize: {:?} not allowed in state {:?} with {:?}", step, state, strategy))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn run(strategy: ResizeStrategy) -> MapState {
+        let mut state = MapState::Mapped;
+        for step in strategy.steps() {
+            state = state.apply(strategy, *step).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn strategies_end_mapped() {
+        assert_eq!(MapState::Mapped, run(ResizeStrategy::RemapAfter));
+        assert_eq!(MapState::Mapped, run(ResizeStrategy::UnmapFirst));
+    }
+
+    #[test]
+    fn unmap_first_never_resizes_mapped() {
+        let strategy = ResizeStrategy::UnmapFirst;
+        let mut state = MapState::Mapped;
+        for step in strategy.steps() {
+            if *step == ResizeStep::SetLen {
+                assert_eq!(MapState::Unmapped, state);
+            }
+            state = state.apply(strategy, *step).unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_transitions() {
+        assert!(MapState::Mapped.apply(ResizeStrategy::UnmapFirst, ResizeStep::SetLen).is_err());
+        assert!(MapState::Mapped.apply(ResizeStrategy::RemapAfter, ResizeStep::Remap).is_err());
+        assert!(MapState::Unmapped.apply(ResizeStrategy::UnmapFirst, ResizeStep::Unmap).is_err());
+        assert!(MapState::Stale.apply(ResizeStrategy::RemapAfter, ResizeStep::SetLen).is_err());
+    }
+
+    #[test]
+    fn recover_after_failure() {
+        for strategy in &[ResizeStrategy::RemapAfter, ResizeStrategy::UnmapFirst] {
+            // every intermediate state must go back to mapped
+            let mut state = MapState::Mapped;
+            for step in strategy.steps() {
+                let mut rec = state;
+                for r in strategy.recover(state) {
+                    rec = rec.apply(*strategy, *r).unwrap();
+                }
+                assert_eq!(MapState::Mapped, rec);
+                state = state.apply(*strategy, *step).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn native_strategy() {
+        if cfg!(windows) {
+            assert_eq!(ResizeStrategy::UnmapFirst, ResizeStrategy::native());
+        } else {
+            assert_eq!(ResizeStrategy::RemapAfter, ResizeStrategy::native());
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::env;
+use std::fs;
+use std::io::*;
+use std::path::{Path, PathBuf};
+
+// Least privilege for --serve: once the socket is bound and the database
+// opened, the server can be confined to a directory (--chroot) and run as
+// another user (--user, --group), the setuid binaries it could still reach
+// giving no privilege back (no_new_privs). The descriptors inherited from
+// the parent are closed before the database is opened.
+//
+// The reloads open the meta, the map and the shards again inside the
+// sandbox, by the paths the meta gives: the database and the current
+// directory must be in the --chroot directory and the paths of the
+// database relative, the server then runs from the same place inside it.
+// They must also be readable by the --user.
+
+
+// what --serve drops, nothing by default
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sandbox {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<String>,
+}
+
+impl Sandbox {
+
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none() && self.group.is_none() && self.chroot.is_none()
+    }
+
+    // confine the process, the names of the user and of the group are
+    // looked up before the chroot. Fails when the privileges are not enough
+    // to drop them
+    #[cfg(unix)]
+    pub fn enter(&self) -> Result<()> {
+
+        let user = match self.user {
+            Some(ref name) => Some(try!(lookup_user(name))),
+            None => None,
+        };
+        let gid = match (self.group.as_ref(), user) {
+            (Some(name), _) => Some(try!(lookup_group(name))),
+            (None, Some((_, Some(gid)))) => Some(gid),
+            (None, Some((uid, None))) => {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("--user {}: no primary group, give --group", uid)));
+            },
+            (None, None) => None,
+        };
+
+        if let Some(ref root) = self.chroot {
+            let root = try!(fs::canonicalize(root).map_err(|e| Error::new(e.kind(), format!("--chroot {}: {}", root, e))));
+            let inner = try!(inner_dir(&root, &try!(env::current_dir())));
+            if unsafe { ffi::chroot(try!(c_path(&root)).as_ptr()) } != 0 {
+                return Err(denied("--chroot", &root.display(), "root or CAP_SYS_CHROOT"));
+            }
+            if unsafe { ffi::chdir(try!(c_path(&inner)).as_ptr()) } != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        if let Some(gid) = gid {
+            if unsafe { ffi::setgroups(0, ::std::ptr::null()) } != 0 || unsafe { ffi::setgid(gid) } != 0 {
+                return Err(denied("--group", &gid, "root or CAP_SETGID"));
+            }
+        }
+        if let Some((uid, _)) = user {
+            if unsafe { ffi::setuid(uid) } != 0 {
+                return Err(denied("--user", &uid, "root or CAP_SETUID"));
+            }
+            // dropped for good
+            if uid != 0 && unsafe { ffi::setuid(0) } == 0 {
+                return Err(Error::new(ErrorKind::PermissionDenied, format!("--user {}: root could be regained", uid)));
+            }
+        }
+
+        no_new_privs()
+    }
+
+    #[cfg(not(unix))]
+    pub fn enter(&self) -> Result<()> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(Error::new(ErrorKind::InvalidInput, "--user, --group and --chroot are only supported on Unix")),
+        }
+    }
+}
+
+// the current directory as seen from the root, it must be in it
+pub fn inner_dir(root: &Path, cwd: &Path) -> Result<PathBuf> {
+    let cwd = try!(fs::canonicalize(cwd));
+    match cwd.strip_prefix(root) {
+        Ok(relative) => Ok(Path::new("/").join(relative)),
+        Err(_) => Err(Error::new(ErrorKind::InvalidInput, format!(
+            "--chroot {}: the current directory {} is not in it, the paths of the database would not be found",
+            root.display(), cwd.display()))),
+    }
+}
+
+// the descriptors above stderr the process was started with, the
+// database is not open yet. Only Linux lists them
+pub fn close_inherited() {
+    let fds: Vec<i32> = match fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries.filter_map(|e| e.ok().and_then(|e| e.file_name().to_str().and_then(|n| n.parse().ok()))).collect(),
+        Err(_) => return,
+    };
+    // the one of read_dir is closed already
+    for fd in fds.into_iter().filter(|&fd| fd > 2) {
+        #[cfg(unix)]
+        unsafe {
+            ffi::close(fd);
+        }
+    }
+}
+
+fn denied<T: ::std::fmt::Display>(option: &str, value: &T, needed: &str) -> Error {
+    let e = Error::last_os_error();
+    Error::new(e.kind(), format!("{} {}: {} (needs {})", option, value, e, needed))
+}
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> Result<::std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    ::std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+// the uid, and the primary group when the user is known: a number is
+// taken as it is
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<(u32, Option<u32>)> {
+    let entry = match name.parse::<u32>() {
+        Ok(uid) => unsafe { ffi::getpwuid(uid) },
+        Err(_) => unsafe { ffi::getpwnam(try!(c_name(name)).as_ptr()) },
+    };
+    match (entry.is_null(), name.parse::<u32>()) {
+        (false, _) => Ok(unsafe { ((*entry).pw_uid, Some((*entry).pw_gid)) }),
+        (true, Ok(uid)) => Ok((uid, None)),
+        (true, Err(_)) => Err(Error::new(ErrorKind::InvalidInput, format!("--user {}: no such user", name))),
+    }
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(gid);
+    }
+    let entry = unsafe { ffi::getgrnam(try!(c_name(name)).as_ptr()) };
+    match entry.is_null() {
+        false => Ok(unsafe { (*entry).gr_gid }),
+        true => Err(Error::new(ErrorKind::InvalidInput, format!("--group {}: no such group", name))),
+    }
+}
+
+#[cfg(unix)]
+fn c_name(name: &str) -> Result<::std::ffi::CString> {
+    ::std::ffi::CString::new(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+// the execve of the server can not give privileges (setuid binaries,
+// file capabilities)
+#[cfg(target_os = "linux")]
+fn no_new_privs() -> Result<()> {
+    const PR_SET_NO_NEW_PRIVS: i32 = 38;
+    match unsafe { ffi::prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn no_new_privs() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+mod ffi {
+    use std::os::raw::c_char;
+
+    // the leading fields, the same on Linux and the BSDs
+    #[repr(C)]
+    pub struct Passwd {
+        pub pw_name: *mut c_char,
+        pub pw_passwd: *mut c_char,
+        pub pw_uid: u32,
+        pub pw_gid: u32,
+    }
+
+    #[repr(C)]
+    pub struct Group {
+        pub gr_name: *mut c_char,
+        pub gr_passwd: *mut c_char,
+        pub gr_gid: u32,
+    }
+
+    extern "C" {
+        pub fn getpwnam(name: *const c_char) -> *mut Passwd;
+        pub fn getpwuid(uid: u32) -> *mut Passwd;
+        pub fn getgrnam(name: *const c_char) -> *mut Group;
+        pub fn chroot(path: *const c_char) -> i32;
+        pub fn chdir(path: *const c_char) -> i32;
+        pub fn setgroups(size: usize, list: *const u32) -> i32;
+        pub fn setgid(gid: u32) -> i32;
+        pub fn setuid(uid: u32) -> i32;
+        pub fn close(fd: i32) -> i32;
+        #[cfg(target_os = "linux")]
+        pub fn prctl(option: i32, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> i32;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn inside_root() {
+        let root = fs::canonicalize(env::temp_dir()).unwrap();
+        let dir = root.join("test_sandbox1");
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(PathBuf::from("/"), inner_dir(&root, &root).unwrap());
+        assert_eq!(PathBuf::from("/test_sandbox1"), inner_dir(&root, &dir).unwrap());
+        assert_eq!(ErrorKind::InvalidInput, inner_dir(&dir, &root).unwrap_err().kind());
+
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    // nothing is dropped before every name is known
+    #[cfg(unix)]
+    #[test]
+    fn unknown_names() {
+        assert!(Sandbox::default().is_empty());
+
+        let sandbox = Sandbox { user: Some(String::from("no-such-user-here")), ..Sandbox::default() };
+        assert!(!sandbox.is_empty());
+        let e = sandbox.enter().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+        assert!(e.to_string().contains("no such user"), "{}", e);
+
+        let sandbox = Sandbox { group: Some(String::from("no-such-group-here")), ..Sandbox::default() };
+        assert!(sandbox.enter().unwrap_err().to_string().contains("no such group"));
+
+        let sandbox = Sandbox { chroot: Some(String::from("no_such_dir_here")), ..Sandbox::default() };
+        assert_eq!(ErrorKind::NotFound, sandbox.enter().unwrap_err().kind());
+    }
+}
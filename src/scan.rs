@@ -0,0 +1,84 @@
+use std::fs::metadata;
+use std::io::*;
+
+use binacle_manager::BinacleManager;
+use bulk::{self, BulkReport, DEFAULT_BULK_MEMORY};
+
+// One-shot search of a directory: its files are indexed by a bulk build in a
+// temporary database, the queries are run over it and the database is
+// removed with the manager. The files are read once for all the queries.
+
+
+pub const SCAN_ALIGNMENT: u8 = 6;
+pub const SCAN_MAX_INDEX_SIZE: u64 = 2000000000;
+
+// the header (5 bytes per n-gram) about the size of the files, without
+// going under 16 bits or over the 24 bits of --tmp
+pub fn ngram_size_for(total: u64) -> u8 {
+    let bits = 64 - total.leading_zeros() as u8;
+    bits.saturating_sub(2).max(16).min(24)
+}
+
+// the temporary database of the files of dir, read by jobs threads
+pub fn scan_dir(dir: &str, jobs: usize) -> Result<(BinacleManager, BulkReport)> {
+
+    if !try!(metadata(dir)).is_dir() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a directory", dir)));
+    }
+    let sources = try!(bulk::sources(dir));
+    let total: u64 = sources.iter().filter_map(|s| metadata(&s.path).ok()).map(|m| m.len()).sum();
+
+    let mut db = try!(BinacleManager::create_in_memory(true, SCAN_MAX_INDEX_SIZE, SCAN_ALIGNMENT, ngram_size_for(total)));
+    db.set_deterministic(true);
+    // in the directory of the temporary database
+    let tmp_dir = format!("{}.bulk", db.db_path);
+    let report = try!(bulk::bulk_build(&mut db, &sources, DEFAULT_BULK_MEMORY, &tmp_dir, jobs));
+    Ok((db, report))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::Path;
+    use rustc_serialize::hex::FromHex;
+    use corpus::{self, Plant, SizeDist};
+    use search::SearchRequest;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(16, ngram_size_for(0));
+        assert_eq!(19, ngram_size_for(1 << 20));
+        assert_eq!(24, ngram_size_for(1 << 40));
+    }
+
+    #[test]
+    fn planted_patterns_found() {
+
+        let _ = fs::remove_dir_all("test_scan_corpus");
+        let plants = Plant::parse_list("4765745072@7,c0a80101@2").unwrap();
+        let truth = corpus::generate("test_scan_corpus", 40, SizeDist::parse("lognormal:9,0.5").unwrap(), 11, &plants).unwrap();
+
+        let db_path = {
+            let (mut db, report) = scan_dir("test_scan_corpus", 3).unwrap();
+            assert_eq!(40, report.files);
+            assert!(db.is_temporary());
+
+            for planted in &truth.patterns {
+                let pattern = planted.pattern.from_hex().unwrap();
+                let response = db.run(&SearchRequest::pattern(&pattern)).unwrap();
+                let found: HashSet<String> = response.entries.iter().map(|p| corpus::normalize(p)).collect();
+                let expected: HashSet<String> = planted.files.iter().map(|p| corpus::normalize(p)).collect();
+                assert!(expected.is_subset(&found));
+            }
+            db.db_path.clone()
+        };
+        // removed with the manager
+        assert!(!Path::new(&db_path).exists());
+        assert!(scan_dir("test_scan_corpus/000001.bin", 1).is_err());
+        let _ = fs::remove_dir_all("test_scan_corpus");
+    }
+}
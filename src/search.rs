@@ -0,0 +1,1671 @@
+use std::borrow::Cow;
+use std::collections::{HashSet, HashMap, BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::*;
+use std::ops::RangeInclusive;
+use std::slice;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use twoway;
+use regex::Regex;
+
+use binacle::{short_pattern, BinacleFile};
+use binacle_manager::{BinacleManager, ShardWarning};
+use dirty::Snapshot;
+use translate::TranslateReader;
+use variants::{self, to_wide, ByteSwap, DEFAULT_VARIANT_CAP};
+
+// patterns of any_of, the ones found are a bit each
+pub const MAX_ANY: usize = 64;
+
+/// A search query, built step by step and evaluated by `BinacleManager::run`.
+/// The command line builds the same request from its arguments.
+///
+/// ```
+/// use std::fs::{File, remove_file};
+/// use std::io::Write;
+/// use binacle::binacle_manager::BinacleManager;
+/// use binacle::search::SearchRequest;
+///
+/// File::create("doc_sample.bin").unwrap().write_all(b"call GetProcAddress").unwrap();
+/// {
+///     let mut db = BinacleManager::create("doc_search.db", true, 1 << 30, 6, 16).unwrap();
+///     db.insert_file("doc_sample.bin", 1, true).unwrap();
+///
+///     let request = SearchRequest::pattern(b"GetProcAddress")
+///                     .exclude(b"LoadLibrary")
+///                     .id_range(1..=100)
+///                     .limit(10)
+///                     .explain();
+///     let response = db.run(&request).unwrap();
+///
+///     assert_eq!(vec![1], response.ids);
+///     assert_eq!(vec!["doc_sample.bin"], response.entries);
+///     assert!(!response.truncated);
+///     assert!(response.explain.is_some());
+/// }
+/// # for f in &["doc_sample.bin", "doc_search.db", "doc_search.db_index0.db",
+/// #            "doc_search.db_index0.meta", "doc_search.db_index0.bitmap", "doc_search.map",
+/// #            "doc_search.hashes"] {
+/// #     let _ = remove_file(f);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SearchRequest {
+    // all of them must be found
+    patterns: Vec<Vec<u8>>,
+    // none of them must be found
+    excludes: Vec<Vec<u8>>,
+    wide: bool,
+    // also look for the UTF-16LE form of the patterns
+    both_widths: bool,
+    // also look for the byte-reversed patterns
+    swap: Option<ByteSwap>,
+    // offset of the first pattern -> the bytes it can take
+    alternatives: BTreeMap<usize, Vec<u8>>,
+    // the first pattern is any of these ones
+    any: Vec<Vec<u8>>,
+    // offsets of the first pattern where any byte matches
+    wildcards: Vec<usize>,
+    variant_cap: usize,
+    exact: bool,
+    // like exact, the files that can not be read are set apart
+    verify: bool,
+    // content types to keep, all when empty (map databases only)
+    types: Vec<String>,
+    // tags that must all be set (map databases only)
+    tags: Vec<String>,
+    id_range: Option<RangeInclusive<u32>>,
+    // ids to keep, a collection
+    within: Option<HashSet<u32>>,
+    // the path or the label must match them (map databases only)
+    path_match: Option<Regex>,
+    path_contains: Vec<String>,
+    limit: Option<usize>,
+    // number of ids drawn at random, and the seed of the draw
+    sample: Option<usize>,
+    seed: Option<u64>,
+    // no shard is started after it, the shard in progress is finished
+    deadline: Option<Duration>,
+    // a shard that can not be searched fails the request
+    strict: bool,
+    explain: bool,
+}
+
+/// Result of a `SearchRequest`.
+pub struct SearchResponse {
+    /// matching ids, sorted
+    pub ids: Vec<u32>,
+    /// paths of the matching ids, empty when the database has no map
+    pub entries: Vec<String>,
+    /// number of matching ids before the sample and the limit
+    pub total: usize,
+    /// true when the sample or the limit dropped some ids
+    pub truncated: bool,
+    /// evaluation steps, when requested
+    pub explain: Option<Vec<String>>,
+    /// orientation found for each returned id, when searching both byte orders
+    pub orientations: HashMap<u32, Orientation>,
+    /// variants of the first pattern found for each returned id, when it has
+    /// alternatives, or the patterns of `any_of` found
+    pub variants: HashMap<u32, Vec<String>>,
+    /// offsets demoted to wildcards, the variants being over the cap
+    pub variant_warning: Option<String>,
+    /// number of matching ids before the path filters, when there are some
+    pub unfiltered: Option<usize>,
+    /// true when the deadline left some shards unsearched
+    pub partial: bool,
+    /// shards searched, by number
+    pub searched_shards: Vec<usize>,
+    /// shards not searched because of the deadline
+    pub skipped_shards: Vec<usize>,
+    /// shards that could not be opened or searched
+    pub warnings: Vec<ShardWarning>,
+    /// shards read without their lock (dirty read), as of their snapshot:
+    /// the files inserted since may be missing
+    pub dirty_shards: Vec<(usize, Snapshot)>,
+    /// candidates whose file did not have the patterns, when verified
+    pub false_positives: usize,
+    /// candidates whose file could not be read when verified, sorted by id,
+    /// with their path and the error
+    pub unverifiable: Vec<(u32, String)>,
+}
+
+impl SearchRequest {
+
+    pub fn pattern(pattern: &[u8]) -> SearchRequest {
+        SearchRequest {
+            patterns: vec![Vec::from(pattern)],
+            excludes: Vec::new(),
+            wide: false,
+            both_widths: false,
+            swap: None,
+            alternatives: BTreeMap::new(),
+            any: Vec::new(),
+            wildcards: Vec::new(),
+            variant_cap: DEFAULT_VARIANT_CAP,
+            exact: false,
+            verify: false,
+            types: Vec::new(),
+            tags: Vec::new(),
+            id_range: None,
+            within: None,
+            path_match: None,
+            path_contains: Vec::new(),
+            limit: None,
+            sample: None,
+            seed: None,
+            deadline: None,
+            strict: false,
+            explain: false,
+        }
+    }
+
+    // one of the patterns must be found, the response tells which ones
+    // were (like the variants). Panics when there are none
+    pub fn any_of(patterns: &[Vec<u8>]) -> SearchRequest {
+        let mut request = SearchRequest::pattern(&patterns[0]);
+        if patterns.len() > 1 {
+            request.any = patterns.to_vec();
+        }
+        request
+    }
+
+    // another pattern that must also be found
+    pub fn and(mut self, pattern: &[u8]) -> SearchRequest {
+        self.patterns.push(Vec::from(pattern));
+        self
+    }
+
+    // drop the ids that match this pattern
+    pub fn exclude(mut self, pattern: &[u8]) -> SearchRequest {
+        self.excludes.push(Vec::from(pattern));
+        self
+    }
+
+    // search the UTF-16LE form of the patterns
+    pub fn wide(mut self) -> SearchRequest {
+        self.wide = true;
+        self
+    }
+
+    // search the patterns as given and in UTF-16LE, a file having either form
+    // is found: the response tells which forms of the first pattern (or of
+    // the patterns of any_of) were found
+    pub fn both_widths(mut self) -> SearchRequest {
+        self.both_widths = true;
+        self
+    }
+
+    // match the patterns in both byte orders, the response tells which one matched
+    pub fn both_endian(mut self, swap: ByteSwap) -> SearchRequest {
+        self.swap = Some(swap);
+        self
+    }
+
+    // the byte at this offset of the first pattern can be any of these ones,
+    // the response tells which variants were found
+    pub fn alternative(mut self, offset: usize, values: &[u8]) -> SearchRequest {
+        let alternatives = self.alternatives.entry(offset).or_insert(Vec::new());
+        for v in values {
+            if !alternatives.contains(v) {
+                alternatives.push(*v);
+            }
+        }
+        self
+    }
+
+    // any byte matches at this offset of the first pattern, the runs
+    // between the wildcards are looked up, the ones of 4 bytes at least
+    pub fn wildcard(mut self, offset: usize) -> SearchRequest {
+        if !self.wildcards.contains(&offset) {
+            self.wildcards.push(offset);
+            self.wildcards.sort();
+        }
+        self
+    }
+
+    // number of variants over which offsets are demoted to wildcards
+    pub fn variant_cap(mut self, cap: usize) -> SearchRequest {
+        self.variant_cap = cap;
+        self
+    }
+
+    // confirm the candidates by reading the files (map databases only)
+    pub fn exact(mut self) -> SearchRequest {
+        self.exact = true;
+        self
+    }
+
+    // confirm the candidates by reading the files, the ones that are missing
+    // or unreadable are left out as unverifiable instead of failing the
+    // search, the response counts the false positives (map databases only)
+    pub fn verify(mut self) -> SearchRequest {
+        self.verify = true;
+        self
+    }
+
+    // only keep the files of this content type, can be given several times
+    pub fn file_type(mut self, file_type: &str) -> SearchRequest {
+        self.types.push(String::from(file_type));
+        self
+    }
+
+    // only keep the files with this tag, can be given several times
+    pub fn tag(mut self, tag: &str) -> SearchRequest {
+        self.tags.push(String::from(tag));
+        self
+    }
+
+    pub fn id_range(mut self, range: RangeInclusive<u32>) -> SearchRequest {
+        self.id_range = Some(range);
+        self
+    }
+
+    // only keep these ids
+    pub fn within<I: IntoIterator<Item = u32>>(mut self, ids: I) -> SearchRequest {
+        self.within = Some(ids.into_iter().collect());
+        self
+    }
+
+    // the path or the label of the file must match this regex
+    pub fn path_match(mut self, regex: &str) -> Result<SearchRequest> {
+        let re = try!(Regex::new(regex).map_err(|e| {
+            Error::new(ErrorKind::InvalidInput, format!("invalid path regex: {}", e))
+        }));
+        self.path_match = Some(re);
+        Ok(self)
+    }
+
+    // the path or the label of the file must contain this string
+    pub fn path_contains(mut self, substr: &str) -> SearchRequest {
+        self.path_contains.push(String::from(substr));
+        self
+    }
+
+    fn has_path_filter(&self) -> bool {
+        self.path_match.is_some() || !self.path_contains.is_empty()
+    }
+
+    fn path_matches(&self, path: &str) -> bool {
+        self.path_match.as_ref().map_or(true, |re| re.is_match(path)) &&
+        self.path_contains.iter().all(|s| path.contains(s.as_str()))
+    }
+
+    pub fn limit(mut self, limit: usize) -> SearchRequest {
+        self.limit = Some(limit);
+        self
+    }
+
+    // keep n ids drawn uniformly from the matches, the total is still reported
+    pub fn sample(mut self, n: usize) -> SearchRequest {
+        self.sample = Some(n);
+        self
+    }
+
+    // same seed, same sample
+    pub fn seed(mut self, seed: u64) -> SearchRequest {
+        self.seed = Some(seed);
+        self
+    }
+
+    // answer with the shards searched in time, the response is marked partial
+    pub fn deadline(mut self, deadline: Duration) -> SearchRequest {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // fail instead of skipping the damaged shards
+    pub fn strict(mut self) -> SearchRequest {
+        self.strict = true;
+        self
+    }
+
+    pub fn explain(mut self) -> SearchRequest {
+        self.explain = true;
+        self
+    }
+
+    // the patterns that must all be found, as given
+    pub fn patterns(&self) -> &[Vec<u8>] {
+        &self.patterns
+    }
+
+    // the bytes looked up in the index by a search of db, the variants,
+    // the reversed forms and the excluded patterns included
+    pub fn looked_up(&self, db: &BinacleManager) -> Result<Vec<Vec<u8>>> {
+        let pass = try!(Pass::new(db, self));
+        let looked_up = pass.looked_up().into_iter().map(Vec::from).collect();
+        Ok(looked_up)
+    }
+
+    // the response tells the variants or the patterns of any_of found
+    fn labeled(&self) -> bool {
+        !self.alternatives.is_empty() || !self.any.is_empty() || self.both_widths
+    }
+
+    // bytes actually looked for in the index
+    fn encode(&self, pattern: &[u8]) -> Vec<u8> {
+        if self.wide {
+            to_wide(pattern)
+        } else {
+            Vec::from(pattern)
+        }
+    }
+
+    // forms of a pattern looked for in the index, one of them must be found
+    fn variants(&self, pattern: &[u8]) -> Result<Vec<Vec<u8>>> {
+        variants::forms(pattern, self.wide, self.both_widths, self.swap)
+    }
+}
+
+// orientation of the patterns found in a file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Orientation {
+    AsIs,
+    Reversed,
+    Both,
+}
+
+impl Orientation {
+
+    fn from_bits(bits: u64) -> Orientation {
+        match bits {
+            1 => Orientation::AsIs,
+            2 => Orientation::Reversed,
+            _ => Orientation::Both,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Orientation::AsIs => "as-is",
+            Orientation::Reversed => "reversed",
+            Orientation::Both => "both",
+        }
+    }
+}
+
+// splitmix64, enough to draw a sample without another dependency
+pub struct Rng(pub u64);
+
+impl Rng {
+
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // in [0 .. bound[
+    pub fn below(&mut self, bound: u64) -> u64 {
+        ((self.next() as u128 * bound as u128) >> 64) as u64
+    }
+
+    // in [0 .. 1[
+    pub fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// reservoir sampling of n ids, the sample is returned sorted
+pub fn sample_ids(ids: &[u32], n: usize, seed: u64) -> Vec<u32> {
+
+    let mut rng = Rng(seed);
+    let mut reservoir: Vec<u32> = ids.iter().take(n).cloned().collect();
+
+    for i in n .. ids.len() {
+        let j = rng.below(i as u64 + 1) as usize;
+        if j < n {
+            reservoir[j] = ids[i];
+        }
+    }
+    reservoir.sort();
+    reservoir
+}
+
+// check that a file really contains the pattern
+pub fn file_contains(path: &str, pattern: &[u8]) -> Result<bool> {
+
+    let mut file = try!(OpenOptions::new().read(true).open(path));
+    let mut content = Vec::new();
+    try!(file.read_to_end(&mut content));
+
+    Ok(twoway::find_bytes(&content, pattern) != None)
+}
+
+// bytes of a file compared at once, the end of a chunk is kept at the start
+// of the next one for the matches across them
+const CHUNK_SIZE: usize = 1 << 20;
+
+// the variants found in the file (bit i for variant i), compared as the
+// index saw them: the file is read in chunks, never whole
+fn scan_file(db: &BinacleManager, path: &str, variants: &[Vec<u8>], wildcards: &[usize]) -> Result<u64> {
+
+    let file = try!(OpenOptions::new().read(true).open(path));
+    let mut reader = TranslateReader::new(file, db.translation());
+    let patterns: Vec<Cow<[u8]>> = variants.iter().map(|v| db.normalize(v)).collect();
+    let kept = patterns.iter().map(|p| p.len()).max().unwrap_or(0).saturating_sub(1);
+    let all = (0 .. patterns.len()).fold(0u64, |bits, v| bits | 1 << v);
+
+    let mut bits = 0u64;
+    let mut window = Vec::with_capacity(kept + CHUNK_SIZE);
+    loop {
+        let start = window.len();
+        window.resize(start + CHUNK_SIZE, 0);
+        let len = loop {
+            match reader.read(&mut window[start ..]) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                read => break try!(read),
+            }
+        };
+        window.truncate(start + len);
+        if len == 0 {
+            return Ok(bits);
+        }
+        for (v, pattern) in patterns.iter().enumerate() {
+            if bits & (1 << v) == 0 && variants::find_masked(&window, pattern, wildcards) {
+                bits |= 1 << v;
+            }
+        }
+        if bits == all {
+            return Ok(bits);
+        }
+        let end = window.len() - kept.min(window.len());
+        window.drain(.. end);
+    }
+}
+
+// ids of the set whose file contains one of the variants, each file is read
+// once, with the variants found in it (bit i for variant i)
+fn confirm(db: &BinacleManager, ids: &HashSet<u32>, variants: &[Vec<u8>], wildcards: &[usize]) -> Result<HashMap<u32, u64>> {
+
+    let mut confirmed = HashMap::with_capacity(ids.len());
+    for id in ids {
+        let bits = try!(scan_file(db, try!(path_of(db, *id)), variants, wildcards));
+        if bits != 0 {
+            confirmed.insert(*id, bits);
+        }
+    }
+    Ok(confirmed)
+}
+
+// confirm, the ids whose file could not be read being set apart with
+// their path and the error
+fn confirm_readable(db: &BinacleManager, ids: &HashSet<u32>, variants: &[Vec<u8>], wildcards: &[usize],
+                    unverifiable: &mut BTreeMap<u32, String>) -> HashMap<u32, u64> {
+
+    let mut confirmed = HashMap::with_capacity(ids.len());
+    for id in ids {
+        let scanned = path_of(db, *id).and_then(|path| {
+            scan_file(db, path, variants, wildcards).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))
+        });
+        match scanned {
+            Ok(0) => {},
+            Ok(bits) => {
+                confirmed.insert(*id, bits);
+            },
+            Err(e) => {
+                unverifiable.insert(*id, e.to_string());
+            },
+        }
+    }
+    confirmed
+}
+
+fn path_of(db: &BinacleManager, id: u32) -> Result<&str> {
+    match db.path_of(id) {
+        Some(path) => Ok(path),
+        None => Err(Error::new(ErrorKind::Other, format!("id {} not found in map", id))),
+    }
+}
+
+// a request under evaluation: its pattern variants, and what the shards
+// gave so far
+struct Pass<'r> {
+    request: &'r SearchRequest,
+    patterns: Vec<Vec<Vec<u8>>>,
+    excludes: Vec<Vec<Vec<u8>>>,
+    ids: HashSet<u32>,
+    trace: Vec<String>,
+    searched: Vec<usize>,
+    skipped: Vec<usize>,
+    warnings: Vec<ShardWarning>,
+    candidates: Vec<usize>,
+    removed: Vec<usize>,
+    // variants found for each id, bit i for variant i
+    variant_bits: HashMap<u32, u64>,
+    // offsets of the first pattern demoted to wildcards, the variants of
+    // its alternatives and why they were demoted
+    wildcards: Vec<usize>,
+    labels: Vec<String>,
+    variant_warning: Option<String>,
+    // ids whose file could not be read by a verified search, with the error
+    unverifiable: BTreeMap<u32, String>,
+}
+
+impl<'r> Pass<'r> {
+
+    fn new(db: &BinacleManager, request: &'r SearchRequest) -> Result<Pass<'r>> {
+
+        if request.exact && !db.is_map() {
+            return Err(Error::new(ErrorKind::Other, "exact search needs a map database"));
+        }
+        if request.verify && !db.is_map() {
+            return Err(Error::new(ErrorKind::Other, "verified search needs a map database"));
+        }
+        if !request.types.is_empty() && !db.is_map() {
+            return Err(Error::new(ErrorKind::Other, "type filter needs a map database"));
+        }
+        if !request.tags.is_empty() && !db.is_map() {
+            return Err(Error::new(ErrorKind::Other, "tag filter needs a map database"));
+        }
+        if request.has_path_filter() && !db.is_map() {
+            return Err(Error::new(ErrorKind::Other, "path filter needs a map database"));
+        }
+
+        let mut patterns = Vec::with_capacity(request.patterns.len());
+        for p in &request.patterns {
+            patterns.push(try!(request.variants(p)));
+        }
+
+        let mut expansion = None;
+        if !request.alternatives.is_empty() {
+            let pattern = &request.patterns[0];
+            if request.wide || request.swap.is_some() {
+                return Err(Error::new(ErrorKind::InvalidInput, "variants can not be combined with a wide or byte-reversed search"));
+            }
+            if let Some(offset) = request.alternatives.keys().find(|o| **o >= pattern.len()) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "variant offset {} out of the pattern ({} bytes)", offset, pattern.len())));
+            }
+            let e = variants::expand(pattern, &request.alternatives, request.variant_cap);
+            patterns[0] = e.variants.clone();
+            expansion = Some(e);
+        }
+        let (mut wildcards, mut labels, variant_warning) = match expansion {
+            Some(e) => (e.wildcards, e.labels, e.warning),
+            None => (Vec::new(), Vec::new(), None),
+        };
+        if !request.wildcards.is_empty() {
+            let pattern = &request.patterns[0];
+            if request.wide || request.swap.is_some() || !request.any.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "wildcards can not be combined with a wide, byte-reversed or several patterns search"));
+            }
+            if let Some(offset) = request.wildcards.iter().find(|o| **o >= pattern.len()) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "wildcard offset {} out of the pattern ({} bytes)", offset, pattern.len())));
+            }
+            wildcards.extend(request.wildcards.iter().cloned());
+            wildcards.sort();
+            wildcards.dedup();
+        }
+        // only wildcards and short runs would match any file
+        if !wildcards.is_empty() && variants::pieces(&patterns[0][0], &wildcards).is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "no run of 4 bytes between the wildcards, the pattern would match any file"));
+        }
+        if !request.any.is_empty() {
+            if !request.alternatives.is_empty() || request.swap.is_some() {
+                return Err(Error::new(ErrorKind::InvalidInput, "several patterns can not be combined with variants or a byte-reversed search"));
+            }
+            if request.any.len() > MAX_ANY {
+                return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "{} patterns, at most {} can be searched at once", request.any.len(), MAX_ANY)));
+            }
+            patterns[0] = request.any.iter().map(|p| request.encode(p)).collect();
+            labels = request.any.iter().map(|p| String::from_utf8_lossy(p).into_owned()).collect();
+        }
+        if request.both_widths {
+            if request.wide || request.swap.is_some() || !request.alternatives.is_empty() || !wildcards.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "both widths can not be combined with a wide, byte-reversed, variants or wildcards search"));
+            }
+            // the patterns as given, then their wide forms
+            let firsts = match request.any.is_empty() {
+                true => vec![request.patterns[0].clone()],
+                false => request.any.clone(),
+            };
+            if firsts.len() * 2 > MAX_ANY {
+                return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "{} patterns in both widths, at most {} can be searched at once", firsts.len(), MAX_ANY / 2)));
+            }
+            patterns[0] = firsts.iter().cloned().chain(firsts.iter().map(|p| to_wide(p))).collect();
+            let text: Vec<String> = firsts.iter().map(|p| String::from_utf8_lossy(p).into_owned()).collect();
+            labels = text.iter().cloned().chain(text.iter().map(|t| format!("{} (wide)", t))).collect();
+        }
+
+        let mut excludes = Vec::with_capacity(request.excludes.len());
+        for p in &request.excludes {
+            excludes.push(try!(request.variants(p)));
+        }
+        // refused by every shard, the pieces between the wildcards are checked above
+        let searched = patterns.iter().enumerate().filter(|&(i, _)| i > 0 || wildcards.is_empty()).map(|(_, v)| v);
+        if searched.chain(&excludes).flat_map(|v| v.iter()).any(|p| p.len() < 4) {
+            return Err(short_pattern());
+        }
+
+        Ok(Pass {
+            request: request,
+            patterns: patterns,
+            excludes: excludes,
+            ids: HashSet::new(),
+            trace: Vec::new(),
+            searched: Vec::new(),
+            skipped: Vec::new(),
+            warnings: Vec::new(),
+            candidates: vec![0; request.patterns.len()],
+            removed: vec![0; request.excludes.len()],
+            variant_bits: HashMap::new(),
+            wildcards: wildcards,
+            labels: labels,
+            variant_warning: variant_warning,
+            unverifiable: BTreeMap::new(),
+        })
+    }
+
+    // the wildcards of a pattern, only the first one has some
+    fn wildcards_of(&self, i: usize) -> &[usize] {
+        match i {
+            0 => &self.wildcards,
+            _ => &[],
+        }
+    }
+
+    // the bytes given to the lookups, the pieces of the variants with wildcards
+    fn looked_up(&self) -> Vec<&[u8]> {
+        let mut looked_up = Vec::new();
+        for (i, variants) in self.patterns.iter().enumerate() {
+            for variant in variants {
+                match self.wildcards_of(i) {
+                    [] => looked_up.push(&variant[..]),
+                    wildcards => looked_up.extend(variants::pieces(variant, wildcards)),
+                }
+            }
+        }
+        looked_up.extend(self.excludes.iter().flat_map(|v| v.iter()).map(|v| &v[..]));
+        looked_up
+    }
+
+    // no shard is started after the deadline
+    fn past_deadline(&mut self, n: usize, start: Instant) -> bool {
+        match self.request.deadline {
+            Some(deadline) if start.elapsed() >= deadline => {
+                self.skipped.push(n);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // evaluate a shard, lookup gives the ids of a pattern in it
+    fn eval_shard<F>(&mut self, db: &mut BinacleManager, n: usize, shard: &Result<BinacleFile>, lookup: F) -> Result<()>
+        where F: FnMut(&mut BinacleManager, &[u8]) -> Result<HashSet<u32>> {
+
+        let res = match *shard {
+            Ok(_) => self.eval_lists(db, lookup),
+            Err(ref e) => Err(Error::new(e.kind(), e.to_string())),
+        };
+        match res {
+            Ok(found) => {
+                self.ids.extend(found);
+                self.searched.push(n);
+            },
+            // an invalid request, not a damaged shard
+            Err(e) if e.kind() == ErrorKind::InvalidInput => return Err(e),
+            // stopped in its lists, what was read of them is dropped
+            Err(ref e) if e.kind() == ErrorKind::TimedOut && self.request.deadline.is_some() => {
+                self.trace.push(format!("shard {}: deadline passed in its lists", n));
+                self.skipped.push(n);
+            },
+            Err(e) => {
+                let warning = db.shard_warning(n, &e);
+                if self.request.strict {
+                    return Err(Error::new(e.kind(), format!("shard {} ({}): {}", n, warning.path, e)));
+                }
+                self.trace.push(format!("shard {} ({}) failed: {}", n, warning.path, e));
+                self.warnings.push(warning);
+            },
+        }
+        Ok(())
+    }
+
+    // ids of a shard matching the patterns and none of the excludes
+    fn eval_lists<F>(&mut self, db: &mut BinacleManager, mut lookup: F) -> Result<HashSet<u32>>
+        where F: FnMut(&mut BinacleManager, &[u8]) -> Result<HashSet<u32>> {
+
+        let request = self.request;
+
+        // intersection of the candidates of every pattern
+        let mut found: Option<HashSet<u32>> = None;
+        for (i, variants) in self.patterns.iter().enumerate() {
+            let tracked = request.swap.is_some() || (i == 0 && request.labeled());
+            let wildcards = match i {
+                0 => &self.wildcards[..],
+                _ => &[],
+            };
+            let mut set = HashSet::new();
+            for (v, variant) in variants.iter().enumerate() {
+                for id in try!(lookup_variant(db, &mut lookup, variant, wildcards)) {
+                    if tracked {
+                        *self.variant_bits.entry(id).or_insert(0) |= 1 << v;
+                    }
+                    set.insert(id);
+                }
+            }
+            self.candidates[i] += set.len();
+            if let Some(prev) = found {
+                set = prev.intersection(&set).cloned().collect();
+            }
+            found = Some(set);
+        }
+        let mut found = found.unwrap_or(HashSet::new());
+        found.retain(|id| !db.is_tombstoned(*id));
+
+        for (i, variants) in self.excludes.iter().enumerate() {
+            let mut excluded = HashSet::new();
+            for variant in variants {
+                excluded.extend(try!(lookup(db, variant)).intersection(&found));
+            }
+            // the false positives of the index would be removed with the
+            // others, the excluded ids are always confirmed on the files
+            if request.exact {
+                excluded = try!(confirm(db, &excluded, variants, &[])).keys().cloned().collect();
+            } else {
+                // the unreadable ones (all of them without a map) stay
+                // excluded, as the index says
+                let mut unreadable = BTreeMap::new();
+                let mut confirmed: HashSet<u32> = confirm_readable(db, &excluded, variants, &[], &mut unreadable).keys().cloned().collect();
+                confirmed.extend(unreadable.keys());
+                excluded = confirmed;
+                if request.verify {
+                    self.unverifiable.extend(unreadable);
+                }
+            }
+            self.removed[i] += excluded.len();
+            found = found.difference(&excluded).cloned().collect();
+        }
+        Ok(found)
+    }
+
+    // the filters on the map and the files, then the sample and the limit
+    fn finish(self, db: &mut BinacleManager) -> Result<SearchResponse> {
+
+        let Pass { request, patterns, mut ids, mut trace, searched, skipped, warnings,
+                   candidates, removed, mut variant_bits, wildcards, labels, variant_warning, mut unverifiable, .. } = self;
+
+        if let Some(ref within) = request.within {
+            ids.retain(|id| within.contains(id));
+            trace.push(format!("within {} id(s): {} left", within.len(), ids.len()));
+        }
+
+        // the paths of the candidates are needed from now on
+        try!(db.resolve(&ids));
+
+        // a dirty read may find files the map read at the opening lacks
+        if !db.dirty_shards().is_empty() && db.is_map() {
+            ids.retain(|id| db.path_of(*id).is_some());
+        }
+
+        if let Some(ref warning) = variant_warning {
+            trace.push(format!("pattern 0: {}", warning));
+        }
+        if !wildcards.is_empty() {
+            let runs: Vec<String> = variants::pieces(&patterns[0][0], &wildcards).iter().map(|r| r.len().to_string()).collect();
+            trace.push(format!("pattern 0: {} wildcard(s), runs of {} byte(s) looked up", wildcards.len(), runs.join(", ")));
+        }
+        for (i, variants) in patterns.iter().enumerate() {
+            trace.push(format!("pattern {}: {} byte(s), {} variant(s), {} candidate(s)",
+                               i, variants[0].len(), variants.len(), candidates[i]));
+        }
+        for (i, nb) in removed.iter().enumerate() {
+            trace.push(format!("exclude {}: {} removed", i, nb));
+        }
+        trace.push(format!("{} candidate(s) left", ids.len()));
+        if !skipped.is_empty() {
+            trace.push(format!("deadline: {} shard(s) searched, {} skipped", searched.len(), skipped.len()));
+        }
+
+        // filtered on the paths before the files are read
+        let mut unfiltered = None;
+        if request.has_path_filter() {
+            unfiltered = Some(ids.len());
+            ids.retain(|id| {
+                match db.entry(*id) {
+                    Some(e) => request.path_matches(&e.path) ||
+                               e.label.as_ref().map_or(false, |l| request.path_matches(l)),
+                    None => false,
+                }
+            });
+            trace.push(format!("path filter: {} left", ids.len()));
+        }
+
+        if request.exact {
+            // the variants really found in the files
+            variant_bits.clear();
+            for (i, variants) in patterns.iter().enumerate() {
+                let confirmed = try!(confirm(db, &ids, variants, if i == 0 { &wildcards } else { &[] }));
+                ids = confirmed.keys().cloned().collect();
+                if request.swap.is_some() || (i == 0 && request.labeled()) {
+                    for (id, bits) in confirmed {
+                        *variant_bits.entry(id).or_insert(0) |= bits;
+                    }
+                }
+                trace.push(format!("pattern {}: {} confirmed", i, ids.len()));
+            }
+        }
+
+        if !request.types.is_empty() {
+            ids.retain(|id| {
+                match db.entry(*id).and_then(|e| e.file_type.as_ref()) {
+                    Some(t) => request.types.contains(t),
+                    None => false,
+                }
+            });
+            trace.push(format!("type {}: {} left", request.types.join(","), ids.len()));
+        }
+
+        if !request.tags.is_empty() {
+            ids.retain(|id| {
+                match db.entry(*id).and_then(|e| e.tags.as_ref()) {
+                    Some(tags) => request.tags.iter().all(|t| tags.contains(t)),
+                    None => false,
+                }
+            });
+            trace.push(format!("tag {}: {} left", request.tags.join(","), ids.len()));
+        }
+
+        if let Some(ref range) = request.id_range {
+            ids.retain(|id| range.contains(id));
+            trace.push(format!("id range {}..={}: {} left", range.start(), range.end(), ids.len()));
+        }
+
+        // after the filters on the map, fewer files to read
+        let mut false_positives = 0;
+        if request.verify && !request.exact {
+            variant_bits.clear();
+            for (i, variants) in patterns.iter().enumerate() {
+                let before = unverifiable.len();
+                let confirmed = confirm_readable(db, &ids, variants, if i == 0 { &wildcards } else { &[] }, &mut unverifiable);
+                let unreadable = unverifiable.len() - before;
+                let dropped = ids.len() - confirmed.len() - unreadable;
+                false_positives += dropped;
+                ids = confirmed.keys().cloned().collect();
+                if request.swap.is_some() || (i == 0 && request.labeled()) {
+                    for (id, bits) in confirmed {
+                        *variant_bits.entry(id).or_insert(0) |= bits;
+                    }
+                }
+                trace.push(format!("pattern {}: {} verified, {} false positive(s), {} unverifiable",
+                                   i, ids.len(), dropped, unreadable));
+            }
+        }
+
+        let mut ids: Vec<u32> = ids.into_iter().collect();
+        ids.sort();
+
+        let total = ids.len();
+        let mut truncated = false;
+
+        // the paths are resolved above: only the ids kept by the sample and
+        // the limit get an entry in the answer
+        if let Some(n) = request.sample {
+            if n < total {
+                let seed = request.seed.unwrap_or_else(|| {
+                    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+                });
+                ids = sample_ids(&ids, n, seed);
+                truncated = true;
+                trace.push(format!("sample {} (seed {}): {} dropped", n, seed, total - n));
+            }
+        }
+        if let Some(limit) = request.limit {
+            if limit < ids.len() {
+                trace.push(format!("limit {}: {} dropped", limit, ids.len() - limit));
+                ids.truncate(limit);
+                truncated = true;
+            }
+        }
+
+        let mut entries = Vec::new();
+        if db.is_map() {
+            entries.reserve(ids.len());
+            for id in &ids {
+                entries.push(String::from(try!(path_of(db, *id))));
+            }
+        }
+
+        variant_bits.retain(|id, _| ids.binary_search(id).is_ok());
+        let orientations = match request.swap {
+            Some(_) => variant_bits.iter().map(|(id, bits)| (*id, Orientation::from_bits(*bits))).collect(),
+            None => HashMap::new(),
+        };
+        let variants = match request.labeled() {
+            false => HashMap::new(),
+            true => variant_bits.iter().map(|(id, bits)| {
+                (*id, (0 .. labels.len()).filter(|v| bits & (1 << v) != 0).map(|v| labels[v].clone()).collect())
+            }).collect(),
+        };
+
+        Ok(SearchResponse {
+            ids: ids,
+            entries: entries,
+            total: total,
+            truncated: truncated,
+            explain: if request.explain { Some(trace) } else { None },
+            orientations: orientations,
+            variants: variants,
+            variant_warning: variant_warning,
+            unfiltered: unfiltered,
+            partial: !skipped.is_empty(),
+            searched_shards: searched,
+            skipped_shards: skipped,
+            warnings: warnings,
+            dirty_shards: db.dirty_shards().to_vec(),
+            false_positives: false_positives,
+            unverifiable: unverifiable.into_iter().collect(),
+        })
+    }
+}
+
+// the ids of a variant, the ones of all its pieces when it has wildcards
+fn lookup_variant<F>(db: &mut BinacleManager, lookup: &mut F, variant: &[u8], wildcards: &[usize]) -> Result<HashSet<u32>>
+    where F: FnMut(&mut BinacleManager, &[u8]) -> Result<HashSet<u32>> {
+
+    if wildcards.is_empty() {
+        return lookup(db, variant);
+    }
+    let mut found: Option<HashSet<u32>> = None;
+    for piece in variants::pieces(variant, wildcards) {
+        let set = try!(lookup(db, piece));
+        found = Some(match found {
+            Some(prev) => prev.intersection(&set).cloned().collect(),
+            None => set,
+        });
+    }
+    Ok(found.unwrap_or(HashSet::new()))
+}
+
+pub fn run(db: &mut BinacleManager, request: &SearchRequest) -> Result<SearchResponse> {
+
+    // the variants share most of their n-grams, a batch reads each list once
+    if !request.alternatives.is_empty() {
+        return run_batch(db, slice::from_ref(request)).results.pop().unwrap();
+    }
+
+    let start = Instant::now();
+    let mut pass = try!(Pass::new(db, request));
+    let deadline = request.deadline.map(|d| start + d);
+
+    // a file is indexed in a single shard, so the request can be evaluated
+    // shard by shard, the shards left after the deadline skipped and the
+    // damaged ones reported. The deadline is also checked before each block
+    // of the lists, a shard it passes in is skipped
+    let shards = db.open_shards();
+    pass.searched.reserve(shards.len());
+
+    for (n, shard) in shards.iter().enumerate() {
+        if pass.past_deadline(n, start) {
+            continue;
+        }
+        if shard.is_ok() {
+            db.before_shard(n);
+        }
+        let mut plans = Vec::new();
+        try!(pass.eval_shard(db, n, shard, |db, p| match *shard {
+            Ok(ref shard) => db.search_shard_planned(n, shard, p, deadline).map(|(ids, plan)| {
+                plans.push(plan);
+                ids
+            }),
+            Err(ref e) => Err(Error::new(e.kind(), e.to_string())),
+        }));
+        if request.explain {
+            pass.trace.extend(plans.iter().map(|plan| format!("shard {}: {}", n, plan.describe())));
+        }
+    }
+    pass.finish(db)
+}
+
+
+/// Result of `run_batch`, in the order of the requests.
+pub struct BatchReport {
+    pub results: Vec<Result<SearchResponse>>,
+    /// time spent on each request, the shared reads of the lists apart
+    pub elapsed: Vec<Duration>,
+    /// time spent reading the lists of the batch from the shards
+    pub shared: Duration,
+    /// lists read, each n-gram of the batch once a shard
+    pub lists_read: usize,
+}
+
+// the lists of the n-grams of a batch in a shard
+struct ShardLists {
+    mask: u32,
+    lists: HashMap<u32, HashSet<u32>>,
+}
+
+impl ShardLists {
+
+    // the n-grams are reduced and sorted, the header is walked in order.
+    // TimedOut when the deadline passes in the lists
+    fn read(shard: &BinacleFile, ngrams: &BTreeSet<u32>, mask: u32, deadline: Option<Instant>) -> Result<ShardLists> {
+        let mut lists = HashMap::with_capacity(ngrams.len());
+        for n in ngrams {
+            lists.insert(*n, try!(shard.get_ids_until(*n, deadline)));
+        }
+        Ok(ShardLists { mask: mask, lists: lists })
+    }
+
+    // same as BinacleFile::search, for a pattern as the index sees it
+    fn search(&self, pattern: &[u8]) -> Result<HashSet<u32>> {
+
+        let empty = HashSet::new();
+        let ngrams = try!(BinacleFile::split_ngrams(pattern));
+        let mut lists: Vec<&HashSet<u32>> = ngrams.iter()
+            .map(|n| self.lists.get(&(n & self.mask)).unwrap_or(&empty))
+            .collect();
+        lists.sort_by_key(|l| l.len());
+
+        let mut set = lists[0].clone();
+        for list in &lists[1 ..] {
+            if set.is_empty() {
+                break;
+            }
+            set.retain(|id| list.contains(id));
+        }
+        Ok(set)
+    }
+}
+
+// evaluate several requests together: the shards are opened once and the
+// lists of all the n-grams of the batch are read once a shard, the requests
+// are then evaluated from them. A request failing does not stop the others.
+pub fn run_batch(db: &mut BinacleManager, requests: &[SearchRequest]) -> BatchReport {
+
+    let start = Instant::now();
+    let mut elapsed = vec![Duration::from_secs(0); requests.len()];
+    let mut passes: Vec<Result<Pass>> = requests.iter().map(|r| Pass::new(db, r)).collect();
+
+    // the n-grams of every variant, as the index sees them
+    let mask = ((1u64 << db.ngram_size()) - 1) as u32;
+    let mut ngrams = BTreeSet::new();
+    for pass in passes.iter().flat_map(|p| p.iter()) {
+        for variant in pass.looked_up() {
+            if let Ok(set) = BinacleFile::split_ngrams(&db.normalize(variant)) {
+                ngrams.extend(set.into_iter().map(|n| n & mask));
+            }
+        }
+    }
+
+    // the lists are read until the last deadline of the batch, when every
+    // request has one: past it, they all skip the shard
+    let deadlines: Vec<Option<Duration>> = passes.iter().filter_map(|p| p.as_ref().ok()).map(|p| p.request.deadline).collect();
+    let deadline = match deadlines.iter().all(|d| d.is_some()) {
+        true => deadlines.into_iter().filter_map(|d| d).max().map(|d| start + d),
+        false => None,
+    };
+
+    let shards = db.open_shards();
+    let mut shared = Duration::from_secs(0);
+    let mut lists_read = 0;
+    for (n, shard) in shards.iter().enumerate() {
+
+        let reading = Instant::now();
+        let lists = match *shard {
+            Ok(ref shard) => {
+                db.before_shard(n);
+                lists_read += ngrams.len();
+                ShardLists::read(shard, &ngrams, mask, deadline).ok()
+            },
+            Err(_) => None,
+        };
+        shared += reading.elapsed();
+
+        for (i, pass) in passes.iter_mut().enumerate() {
+            let failed = match *pass {
+                Ok(ref mut pass) => {
+                    if pass.past_deadline(n, start) {
+                        continue;
+                    }
+                    let evaluating = Instant::now();
+                    let res = pass.eval_shard(db, n, shard, |db, pattern| match lists {
+                        Some(ref lists) => lists.search(&db.normalize(pattern)),
+                        None => Err(Error::new(ErrorKind::Other, "shard not opened")),
+                    });
+                    elapsed[i] += evaluating.elapsed();
+                    res.err()
+                },
+                Err(_) => None,
+            };
+            if let Some(e) = failed {
+                *pass = Err(e);
+            }
+        }
+    }
+
+    let mut results = Vec::with_capacity(passes.len());
+    for (i, pass) in passes.into_iter().enumerate() {
+        let finishing = Instant::now();
+        results.push(pass.and_then(|pass| pass.finish(db)));
+        elapsed[i] += finishing.elapsed();
+    }
+
+    BatchReport {
+        results: results,
+        elapsed: elapsed,
+        shared: shared,
+        lists_read: lists_read,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn builder() {
+        let r = SearchRequest::pattern(b"abcd").and(b"efgh").exclude(b"ijkl").limit(3).wide();
+        assert_eq!(2, r.patterns.len());
+        assert_eq!(1, r.excludes.len());
+        assert_eq!(Some(3), r.limit);
+        assert_eq!(8, r.encode(b"abcd").len());
+    }
+
+    #[test]
+    fn sample_deterministic() {
+        let ids: Vec<u32> = (0 .. 1000).collect();
+        let a = sample_ids(&ids, 50, 42);
+        assert_eq!(50, a.len());
+        assert_eq!(a, sample_ids(&ids, 50, 42));
+        assert!(a != sample_ids(&ids, 50, 43));
+        assert_eq!(vec![1, 2], sample_ids(&[1, 2], 5, 42));
+    }
+
+    #[test]
+    fn sample_uniform() {
+        // every id of 10 is drawn about 3 times out of 10 for a sample of 3
+        let ids: Vec<u32> = (0 .. 10).collect();
+        let mut counts = [0; 10];
+        for seed in 0 .. 10000 {
+            for id in sample_ids(&ids, 3, seed) {
+                counts[id as usize] += 1;
+            }
+        }
+        for c in counts.iter() {
+            assert!(*c > 2800 && *c < 3200, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn deadline_skips_shards() {
+
+        use std::fs::remove_file;
+        use std::thread::sleep;
+
+        {
+            // max index size of 1 byte: one shard per file
+            let mut db = BinacleManager::create("test_search1.db", false, 1, 6, 16).unwrap();
+            for id in 1 .. 5 {
+                db.insert_reader(&b"call GetProcAddress"[..], "", id, false);
+            }
+
+            let complete = db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap();
+            assert!(!complete.partial);
+            assert_eq!(vec![0, 1, 2, 3], complete.searched_shards);
+            assert_eq!(vec![1, 2, 3, 4], complete.ids);
+
+            db.set_shard_hook(Box::new(|_| sleep(Duration::from_millis(100))));
+            // the deadline passes while the lists of the shard 2 are read
+            let request = SearchRequest::pattern(b"GetProcAddress").deadline(Duration::from_millis(250));
+            let partial = db.run(&request).unwrap();
+            assert!(partial.partial);
+            assert_eq!(vec![0, 1], partial.searched_shards);
+            assert_eq!(vec![2, 3], partial.skipped_shards);
+            assert_eq!(vec![1, 2], partial.ids);
+
+            // then in the first shard
+            let request = SearchRequest::pattern(b"GetProcAddress").deadline(Duration::from_millis(50)).explain();
+            let partial = db.run(&request).unwrap();
+            assert!(partial.partial);
+            assert!(partial.searched_shards.is_empty());
+            assert_eq!(vec![0, 1, 2, 3], partial.skipped_shards);
+            assert!(partial.ids.is_empty());
+            assert!(partial.explain.unwrap().contains(&String::from("shard 0: deadline passed in its lists")));
+
+            // the same for the lists read once for a batch
+            let report = run_batch(&mut db, &[SearchRequest::pattern(b"GetProcAddress").deadline(Duration::from_millis(50))]);
+            let partial = report.results.into_iter().next().unwrap().unwrap();
+            assert!(partial.searched_shards.is_empty());
+            assert_eq!(vec![0, 1, 2, 3], partial.skipped_shards);
+        }
+
+        let _ = remove_file("test_search1.db");
+        for i in 0 .. 4 {
+            let _ = remove_file(format!("test_search1.db_index{}.db", i));
+            let _ = remove_file(format!("test_search1.db_index{}.meta", i));
+            let _ = remove_file(format!("test_search1.db_index{}.bitmap", i));
+            let _ = remove_file(format!("test_search1.db_index{}.summary", i));
+        }
+    }
+
+    #[test]
+    fn damaged_shard_skipped() {
+
+        use std::fs::remove_file;
+
+        {
+            let mut db = BinacleManager::create("test_search4.db", false, 1, 6, 16).unwrap();
+            for id in 1 .. 4 {
+                db.insert_reader(&b"call GetProcAddress"[..], "", id, false);
+            }
+        }
+        remove_file("test_search4.db_index1.db").unwrap();
+        {
+            let mut db = BinacleManager::open("test_search4.db").unwrap();
+
+            let response = db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap();
+            assert_eq!(vec![1, 3], response.ids);
+            assert_eq!(vec![0, 2], response.searched_shards);
+            assert_eq!(1, response.warnings.len());
+            assert_eq!(1, response.warnings[0].shard);
+            assert_eq!("test_search4.db_index1.db", response.warnings[0].path);
+
+            assert!(db.run(&SearchRequest::pattern(b"GetProcAddress").strict()).is_err());
+
+            assert_eq!(2, db.search(b"GetProcAddress").unwrap().len());
+            assert_eq!(1, db.shard_warnings().len());
+
+            // a pattern too short is the request, not the shards
+            assert_eq!(ErrorKind::InvalidInput, db.run(&SearchRequest::pattern(b"ab")).err().unwrap().kind());
+            assert_eq!(ErrorKind::InvalidInput, db.run(&SearchRequest::pattern(b"GetProc").exclude(b"ab")).err().unwrap().kind());
+            assert_eq!(ErrorKind::InvalidInput, db.search(b"ab").unwrap_err().kind());
+        }
+
+        let _ = remove_file("test_search4.db");
+        for i in 0 .. 3 {
+            let _ = remove_file(format!("test_search4.db_index{}.db", i));
+            let _ = remove_file(format!("test_search4.db_index{}.meta", i));
+        }
+    }
+
+    #[test]
+    fn exclude_without_map() {
+
+        use binacle_manager::tests::cleanup;
+
+        {
+            // no path to read the files from: the index decides
+            let mut db = BinacleManager::create("test_search10.db", false, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"call GetProcAddress"[..], "", 1, false);
+            db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "", 2, false);
+
+            let response = db.run(&SearchRequest::pattern(b"GetProcAddress").exclude(b"LoadLibraryA")).unwrap();
+            assert_eq!(vec![1], response.ids);
+        }
+        cleanup("test_search10");
+    }
+
+    #[test]
+    fn reversed_patterns() {
+        let r = SearchRequest::pattern(&[1, 2, 3, 4]).both_endian(ByteSwap::Full).wide();
+        assert_eq!(vec![vec![1, 0, 2, 0, 3, 0, 4, 0], vec![4, 0, 3, 0, 2, 0, 1, 0]], r.variants(&[1, 2, 3, 4]).unwrap());
+    }
+
+    #[test]
+    fn both_endian_search() {
+
+        use std::fs::{File, remove_file};
+
+        // 0xc0a80101 stored little endian
+        File::create("test_endian.bin").unwrap().write_all(&[0x90, 0x01, 0x01, 0xa8, 0xc0, 0x90]).unwrap();
+        {
+            let mut db = BinacleManager::create("test_search2.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_file("test_endian.bin", 1, false).unwrap();
+
+            let big = [0xc0, 0xa8, 0x01, 0x01];
+            assert!(db.run(&SearchRequest::pattern(&big)).unwrap().ids.is_empty());
+
+            for exact in &[false, true] {
+                let mut request = SearchRequest::pattern(&big).both_endian(ByteSwap::Full);
+                if *exact {
+                    request = request.exact();
+                }
+                let response = db.run(&request).unwrap();
+                assert_eq!(vec![1], response.ids);
+                assert_eq!(Some(&Orientation::Reversed), response.orientations.get(&1));
+            }
+        }
+        for f in &["test_endian.bin", "test_search2.db", "test_search2.db_index0.db",
+                   "test_search2.db_index0.meta", "test_search2.db_index0.bitmap", "test_search2.map", "test_search2.hashes"] {
+            let _ = remove_file(f);
+        }
+    }
+
+    #[test]
+    fn path_filters() {
+
+        use binacle_manager::tests::cleanup;
+
+        {
+            let mut db = BinacleManager::create("test_search3.db", true, 1 << 30, 6, 16).unwrap();
+            db.insert_reader(&b"GetProcAddress"[..], "cases/a/drop.exe", 1, false);
+            db.insert_reader(&b"GetProcAddress"[..], "cases/b/loader.dll", 2, false);
+            db.insert_reader(&b"GetProcAddress"[..], "tmp/x.bin", 3, true);
+            db.set_label(3, "cases/c/renamed.exe").unwrap();
+
+            let request = SearchRequest::pattern(b"GetProcAddress").path_match(r"\.exe$").unwrap();
+            let response = db.run(&request).unwrap();
+            // the label matches, not the path
+            assert_eq!(vec![1, 3], response.ids);
+            assert_eq!(Some(3), response.unfiltered);
+            assert_eq!(2, response.total);
+
+            let request = SearchRequest::pattern(b"GetProcAddress").path_contains("cases/b/");
+            assert_eq!(vec![2], db.run(&request).unwrap().ids);
+
+            assert_eq!(None, db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap().unfiltered);
+
+            let err = SearchRequest::pattern(b"GetProcAddress").path_match("(cases").unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+        cleanup("test_search3");
+    }
+
+    #[test]
+    fn any_of_patterns() {
+
+        use std::fs::{File, remove_file};
+        use binacle_manager::tests::cleanup;
+
+        // on disk, read by the exact search
+        let files: [&[u8]; 4] = [b"call GetProcAddress", b"beacon to evil.example.com",
+                                 b"GetProcAddress evil.example.com", b"LoadLibraryA"];
+        {
+            let mut db = BinacleManager::create("test_search5.db", true, 1 << 30, 6, 16).unwrap();
+            for (i, content) in files.iter().enumerate() {
+                let path = format!("test_search5_{}.bin", i + 1);
+                File::create(&path).unwrap().write_all(content).unwrap();
+                db.insert_file(&path, i as u32 + 1, true).unwrap();
+            }
+
+            let patterns = vec![b"GetProcAddress".to_vec(), b"evil.example.com".to_vec()];
+            let response = db.run(&SearchRequest::any_of(&patterns)).unwrap();
+            assert_eq!(vec![1, 2, 3], response.ids);
+            assert_eq!(vec!["GetProcAddress"], response.variants[&1]);
+            assert_eq!(vec!["evil.example.com"], response.variants[&2]);
+            assert_eq!(vec!["GetProcAddress", "evil.example.com"], response.variants[&3]);
+
+            let response = db.run(&SearchRequest::any_of(&patterns).exact().exclude(b"call")).unwrap();
+            assert_eq!(vec![2, 3], response.ids);
+
+            // a single pattern is a plain search
+            let response = db.run(&SearchRequest::any_of(&patterns[.. 1])).unwrap();
+            assert_eq!(vec![1, 3], response.ids);
+            assert!(response.variants.is_empty());
+
+            let err = db.run(&SearchRequest::any_of(&patterns).both_endian(ByteSwap::Full)).err().unwrap();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+        cleanup("test_search5");
+        for i in 1 ..= files.len() {
+            let _ = remove_file(format!("test_search5_{}.bin", i));
+        }
+    }
+
+    #[test]
+    fn all_patterns() {
+
+        use std::fs::{File, remove_file};
+        use binacle_manager::tests::cleanup;
+
+        // the excludes are confirmed on the files
+        let files: [&[u8]; 4] = [b"GetProcAddress evil.example.com kernel32", b"call GetProcAddress",
+                                 b"beacon to evil.example.com", b"evil.example.com call GetProcAddress"];
+        {
+            // one shard per file: the ids of the patterns are gathered from all of them
+            let mut db = BinacleManager::create("test_search6.db", true, 1, 6, 16).unwrap();
+            for (i, content) in files.iter().enumerate() {
+                let path = format!("test_search6_{}.bin", i + 1);
+                File::create(&path).unwrap().write_all(content).unwrap();
+                db.insert_file(&path, i as u32 + 1, true).unwrap();
+            }
+            assert_eq!(4, db.nb_index());
+
+            let both = SearchRequest::pattern(b"GetProcAddress").and(b"evil.example.com");
+            assert_eq!(vec![1, 4], db.run(&both).unwrap().ids);
+            assert_eq!(vec![4], db.run(&both.clone().exclude(b"kernel32")).unwrap().ids);
+            assert_eq!(vec![4], db.run(&both.and(b"call")).unwrap().ids);
+            let none = SearchRequest::pattern(b"beacon").and(b"GetProcAddress");
+            assert!(db.run(&none).unwrap().ids.is_empty());
+        }
+        cleanup("test_search6");
+        for i in 1 ..= files.len() {
+            let _ = remove_file(format!("test_search6_{}.bin", i));
+        }
+    }
+
+    #[test]
+    fn relocated_variants() {
+
+        use std::fs::{File, remove_file};
+        use binacle_manager::tests::cleanup;
+
+        // push 0x40, push <address>: the address varies from a build to another
+        let build = |reloc: u8, low: u8| vec![0x6a, 0x40, 0x68, 0x00, reloc, 0x40, 0x00, 0x68, low, 0x10, 0x00, 0x00, 0xff, 0x15];
+        let files = [build(0x10, 0x00), build(0x30, 0x20), build(0x30, 0x21), build(0x50, 0x00)];
+        {
+            let mut db = BinacleManager::create("test_search11.db", true, 1 << 30, 6, 16).unwrap();
+            for (i, content) in files.iter().enumerate() {
+                let path = format!("test_search11_{}.bin", i + 1);
+                File::create(&path).unwrap().write_all(content).unwrap();
+                db.insert_file(&path, i as u32 + 1, true).unwrap();
+            }
+
+            let request = SearchRequest::pattern(&files[0]).alternative(4, &[0x10, 0x30]).alternative(8, &[0x00, 0x20]);
+            let response = db.run(&request).unwrap();
+            assert_eq!(vec![1, 2], response.ids);
+            assert_eq!(vec![String::from("4=10,8=00")], response.variants[&1]);
+            assert_eq!(vec![String::from("4=30,8=20")], response.variants[&2]);
+            assert_eq!(None, response.variant_warning);
+
+            // over the cap, the offset 8 matches any byte
+            let request = SearchRequest::pattern(&files[0]).alternative(4, &[0x10, 0x30]).alternative(8, &[0x00, 0x20, 0x21])
+                            .variant_cap(2).explain();
+            let response = db.run(&request).unwrap();
+            assert_eq!(vec![1, 2, 3], response.ids);
+            assert_eq!(vec![String::from("4=30")], response.variants[&3]);
+            assert!(response.variant_warning.as_ref().unwrap().contains("offset(s) 8 demoted"));
+            assert!(response.explain.unwrap().iter().any(|step| step.contains("2 variant(s)")));
+
+            // the files read confirm the variants
+            let request = SearchRequest::pattern(&files[0]).alternative(4, &[0x10, 0x30, 0x50]).alternative(8, &[0x00, 0x21]).exact();
+            let response = db.run(&request).unwrap();
+            assert_eq!(vec![1, 3, 4], response.ids);
+            assert_eq!(vec![String::from("4=50,8=00")], response.variants[&4]);
+
+            // the same as the search of each variant
+            let mut expected = HashSet::new();
+            for reloc in &[0x10, 0x30, 0x50] {
+                let mut variant = files[0].clone();
+                variant[4] = *reloc;
+                expected.extend(db.search(&variant).unwrap());
+            }
+            let response = db.run(&SearchRequest::pattern(&files[0]).alternative(4, &[0x10, 0x30, 0x50])).unwrap();
+            assert_eq!(expected, response.ids.iter().cloned().collect());
+
+            assert_eq!(ErrorKind::InvalidInput, db.run(&SearchRequest::pattern(&files[0]).alternative(14, &[0])).err().unwrap().kind());
+            assert_eq!(ErrorKind::InvalidInput, db.run(&SearchRequest::pattern(&files[0]).alternative(4, &[0]).wide()).err().unwrap().kind());
+        }
+        cleanup("test_search11");
+        for i in 1 ..= files.len() {
+            let _ = remove_file(format!("test_search11_{}.bin", i));
+        }
+    }
+
+    #[test]
+    fn wildcard_bytes() {
+
+        use std::fs::{File, remove_file};
+        use binacle_manager::tests::cleanup;
+
+        // push <address>; call eax, the address varies
+        let files = [
+            b"\x90\x68\x10\x20\x30\x00\xff\xd0\xc3".to_vec(),
+            b"\x90\x68\x44\x55\x66\x00\xff\xd0\xc3".to_vec(),
+            b"\x90\x68\x44\x55\x66\x01\xff\xd0\xc3".to_vec(),
+        ];
+        {
+            let mut db = BinacleManager::create("test_search7.db", true, 1 << 30, 6, 16).unwrap();
+            for (i, content) in files.iter().enumerate() {
+                let path = format!("test_search7_{}.bin", i + 1);
+                File::create(&path).unwrap().write_all(content).unwrap();
+                db.insert_file(&path, i as u32 + 1, true).unwrap();
+            }
+
+            // 68 ?? ?? ?? 00 ff d0 c3: the run 00 ff d0 c3 is looked up
+            let (pattern, wildcards) = variants::parse_masked("68 ?? ?? ?? 00 ff d0 c3").unwrap();
+            let masked = |exact: bool| {
+                let mut request = SearchRequest::pattern(&pattern).explain();
+                for w in &wildcards {
+                    request = request.wildcard(*w);
+                }
+                if exact { request.exact() } else { request }
+            };
+            let response = db.run(&masked(false)).unwrap();
+            assert_eq!(vec![1, 2], response.ids);
+            assert!(response.explain.unwrap().iter().any(|s| s == "pattern 0: 3 wildcard(s), runs of 4 byte(s) looked up"));
+            assert_eq!(vec![1, 2], db.run(&masked(true)).unwrap().ids);
+
+            // the same as the search of the runs
+            let expected: Vec<u32> = {
+                let mut ids: Vec<u32> = db.search(b"\x00\xff\xd0\xc3").unwrap().into_iter().collect();
+                ids.sort();
+                ids
+            };
+            assert_eq!(expected, db.run(&masked(false)).unwrap().ids);
+
+            // only runs under 4 bytes, any file would match
+            let (pattern, wildcards) = variants::parse_masked("68 ?? ?? ?? 00 ff d0").unwrap();
+            let request = wildcards.iter().fold(SearchRequest::pattern(&pattern), |r, w| r.wildcard(*w));
+            let e = db.run(&request).err().unwrap();
+            assert_eq!(ErrorKind::InvalidInput, e.kind());
+            assert!(e.to_string().contains("would match any file"), "{}", e);
+
+            assert_eq!(ErrorKind::InvalidInput, db.run(&SearchRequest::pattern(&files[0]).wildcard(9)).err().unwrap().kind());
+            assert_eq!(ErrorKind::InvalidInput, db.run(&SearchRequest::pattern(&files[0]).wildcard(2).wide()).err().unwrap().kind());
+        }
+        cleanup("test_search7");
+        for i in 1 ..= files.len() {
+            let _ = remove_file(format!("test_search7_{}.bin", i));
+        }
+    }
+
+    #[test]
+    fn ascii_and_wide() {
+
+        use std::fs::{File, remove_file};
+        use binacle_manager::tests::cleanup;
+
+        // on disk, read by the exact search
+        let files: Vec<Vec<u8>> = vec![
+            b"start cmd.exe /c".to_vec(),
+            [&b"MZ"[..], &to_wide(b"cmd.exe /c")[..]].concat(),
+            [&b"cmd.exe "[..], &to_wide("\u{e9}t\u{e9} cmd.exe".as_bytes())[..]].concat(),
+            b"powershell.exe".to_vec(),
+        ];
+        {
+            let mut db = BinacleManager::create("test_search8.db", true, 1 << 30, 6, 16).unwrap();
+            for (i, content) in files.iter().enumerate() {
+                let path = format!("test_search8_{}.bin", i + 1);
+                File::create(&path).unwrap().write_all(content).unwrap();
+                db.insert_file(&path, i as u32 + 1, true).unwrap();
+            }
+
+            assert_eq!(vec![1, 3], db.run(&SearchRequest::pattern(b"cmd.exe")).unwrap().ids);
+            assert_eq!(vec![2, 3], db.run(&SearchRequest::pattern(b"cmd.exe").wide()).unwrap().ids);
+            // the union, each file with the forms found
+            let response = db.run(&SearchRequest::pattern(b"cmd.exe").both_widths().exact()).unwrap();
+            assert_eq!(vec![1, 2, 3], response.ids);
+            assert_eq!(vec!["cmd.exe"], response.variants[&1]);
+            assert_eq!(vec!["cmd.exe (wide)"], response.variants[&2]);
+            assert_eq!(vec!["cmd.exe", "cmd.exe (wide)"], response.variants[&3]);
+
+            // the non-ASCII characters encoded, not widened byte by byte
+            let accented = "\u{e9}t\u{e9} cmd".as_bytes();
+            assert_eq!(vec![3], db.run(&SearchRequest::pattern(accented).both_widths().exact()).unwrap().ids);
+
+            // the other patterns in either width too
+            let response = db.run(&SearchRequest::pattern(b"cmd.exe").both_widths().and(b"exe /c").exclude(b"start").exact()).unwrap();
+            assert_eq!(vec![2], response.ids);
+            let patterns = vec![b"cmd.exe".to_vec(), b"powershell".to_vec()];
+            let response = db.run(&SearchRequest::any_of(&patterns).both_widths()).unwrap();
+            assert_eq!(vec![1, 2, 3, 4], response.ids);
+            assert_eq!(vec!["powershell"], response.variants[&4]);
+
+            let err = db.run(&SearchRequest::pattern(b"cmd.exe").both_widths().wide()).err().unwrap();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+            let err = db.run(&SearchRequest::pattern(b"cmd.exe").both_widths().both_endian(ByteSwap::Full)).err().unwrap();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+        cleanup("test_search8");
+        for i in 1 ..= files.len() {
+            let _ = remove_file(format!("test_search8_{}.bin", i));
+        }
+    }
+
+    #[test]
+    fn verified_candidates() {
+
+        use std::fs::{File, remove_file};
+        use binacle_manager::tests::cleanup;
+
+        // across two chunks, all the n-grams but not the pattern, then a
+        // file removed after its insertion
+        let mut big = vec![0u8; 3 * CHUNK_SIZE];
+        big[CHUNK_SIZE - 5 .. CHUNK_SIZE + 9].copy_from_slice(b"GetProcAddress");
+        let files: [&[u8]; 3] = [&big, b"GetProc rocAddress", b"call GetProcAddress"];
+        {
+            let mut db = BinacleManager::create("test_search9.db", true, 1 << 30, 6, 16).unwrap();
+            for (i, content) in files.iter().enumerate() {
+                let path = format!("test_search9_{}.bin", i + 1);
+                File::create(&path).unwrap().write_all(content).unwrap();
+                db.insert_file(&path, i as u32 + 1, true).unwrap();
+            }
+            remove_file("test_search9_3.bin").unwrap();
+
+            let response = db.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap();
+            assert_eq!(vec![1, 2, 3], response.ids);
+            assert_eq!(0, response.false_positives);
+
+            // without verification, only the files holding the exclude are
+            // dropped: 2 is a false positive of it, 3 can not be read and is
+            // excluded as the index says
+            let response = db.run(&SearchRequest::pattern(b"rocAddress").exclude(b"GetProcAddress")).unwrap();
+            assert_eq!(vec![2], response.ids);
+            assert!(response.unverifiable.is_empty());
+
+            let response = db.run(&SearchRequest::pattern(b"GetProcAddress").verify().explain()).unwrap();
+            assert_eq!(vec![1], response.ids);
+            assert_eq!(1, response.total);
+            assert_eq!(1, response.false_positives);
+            assert_eq!(1, response.unverifiable.len());
+            assert_eq!(3, response.unverifiable[0].0);
+            assert!(response.unverifiable[0].1.starts_with("test_search9_3.bin: "));
+            assert!(response.explain.unwrap().contains(&String::from("pattern 0: 1 verified, 1 false positive(s), 1 unverifiable")));
+
+            // the exact search fails on the missing file
+            assert!(db.run(&SearchRequest::pattern(b"GetProcAddress").exact()).is_err());
+
+            // the exclude is verified too: not in the file of 2, which is
+            // then a false positive of the pattern
+            let response = db.run(&SearchRequest::pattern(b"GetProcAddress").verify().exclude(b"ProcAddr")).unwrap();
+            assert!(response.ids.is_empty());
+            assert_eq!(1, response.false_positives);
+            assert_eq!(vec![3], response.unverifiable.iter().map(|u| u.0).collect::<Vec<u32>>());
+        }
+        cleanup("test_search9");
+        for i in 1 ..= files.len() {
+            let _ = remove_file(format!("test_search9_{}.bin", i));
+        }
+    }
+}
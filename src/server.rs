@@ -0,0 +1,861 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::*;
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::{Json, ToJson};
+
+use binacle::BinacleFile;
+use binacle_manager::{BinacleManager, ShardWarning};
+use cache::PostingCache;
+use planner::{Plan, DEFAULT_PRESELECT};
+use sandbox::Sandbox;
+use translate::Translation;
+
+// Query server (--serve): the shards of a database opened once, read only,
+// and searched by the requests of the clients, a pool of threads answering
+// the connections. A search does not pay the reading of the meta and the
+// map, nor the mapping of the shards. A minimal HTTP/1.0, one request a
+// connection:
+//
+//   POST /search        the body is the pattern, its bytes as they are
+//   POST /search?hex    the body is the pattern in hex
+//   POST /search?explain    (or ?hex&explain) the answer tells how the
+//                       lists were read
+//   POST /reload        the meta, the map and the shards opened again when
+//                       a writer changed the meta, as before each search
+//   GET /stats          the generation served, when it was loaded and the
+//                       health of the shards
+//
+// A search answers the JSON array of the ids found, ascending, or of
+// {"id": <id>, "path": <path>} when the map is loaded. A shard that can not
+// be opened or searched does not fail the search, as with -s: the answer is
+// then {"results": <array>, "partial": true, "failed_shards": [{"shard": <n>,
+// "path": <path>, "error": <message>}, ...]}. An error is {"error":
+// "<message>"}, with the status 400 for the request (a pattern under 4
+// bytes, bad hex) and 500 for the database.
+//
+// The threads share the shards, a BinacleFile only read is Sync. The live
+// shard is read without its lock, as of its meta (see dirty.rs), the
+// finalized ones keep their shared lock while they are served. A reload
+// (BinacleManager::reload, nothing is opened again when the meta did not
+// change) replaces them for the next requests, the searches running end on
+// the old ones: the files and the shards a writer added meanwhile are
+// searched after it. Each reload that changed something is a generation.
+// A search first compares the stamp of the meta (its time and size) with
+// the one loaded, a writer's changes are served without a POST /reload.
+//
+// The lists decoded by the searches are kept in a cache shared by the
+// requests (see cache.rs), a new one for each generation. A request whose
+// lists hold more ids than its budget, as told by the sizes of its plans
+// before any is read, does not insert them: a long pattern of common
+// n-grams leaves the lists of the small requests cached. With
+// release_pages it also gives back the pages it read. /stats counts the
+// requests over their budget.
+//
+// The privileges are dropped once the socket is bound (see sandbox.rs), the
+// files of the database must still be readable then, for the reloads.
+
+
+// bytes of the body of a request, a pattern
+pub const MAX_BODY: usize = 1 << 20;
+
+// a client that does not send its request in this time is dropped
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// threads answering the connections, the ones accepted beyond wait for
+// one of them
+pub const WORKERS: usize = 16;
+
+// ids of the lists kept by the cache, 64 MiB of them
+pub const CACHE_IDS: u64 = 1 << 24;
+
+// ids of the lists of a request above which they are not cached
+pub const REQUEST_BUDGET: u64 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheConfig {
+    pub capacity: u64,
+    pub budget: u64,
+    // the pages of a request over its budget given back
+    pub release_pages: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig { capacity: CACHE_IDS, budget: REQUEST_BUDGET, release_pages: false }
+    }
+}
+
+// how the lists of a request were read, for ?explain
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reading {
+    // of each shard searched
+    pub plans: Vec<(usize, Plan)>,
+    // ids of the lists of the plans
+    pub volume: u64,
+    pub from_cache: usize,
+    pub decoded: usize,
+    pub over_budget: bool,
+    pub released: bool,
+}
+
+// what the searches read, as of the last reload
+pub struct Served {
+    // the shards opened and their number in the meta
+    shards: Vec<(usize, BinacleFile)>,
+    // the ones that could not be opened
+    unopened: Vec<ShardWarning>,
+    nb_shard: usize,
+    // the paths of the ids, when the map is loaded
+    paths: Option<HashMap<u32, String>>,
+    tombstones: HashSet<u32>,
+    translation: Option<Translation>,
+    generation: u64,
+    loaded: Option<SystemTime>,
+    // the searches a shard failed since it was loaded, and the last error
+    failures: Mutex<BTreeMap<usize, (u64, String)>>,
+    cache: Mutex<PostingCache>,
+    config: CacheConfig,
+}
+
+impl Served {
+
+    // the live shard opened without its lock, as --dirty-read does: the
+    // writer goes on inserting in it
+    pub fn load(db: &mut BinacleManager, generation: u64, config: CacheConfig) -> Served {
+        db.set_dirty_read(true);
+        let mut shards = Vec::new();
+        let mut unopened = Vec::new();
+        for (n, shard) in db.open_shards().into_iter().enumerate() {
+            match shard {
+                Ok(shard) => shards.push((n, shard)),
+                Err(e) => unopened.push(db.shard_warning(n, &e)),
+            }
+        }
+        let paths = match db.is_map() && !db.is_lazy_map() {
+            true => Some(db.entries().map(|(id, entry)| (id, entry.path.clone())).collect()),
+            false => None,
+        };
+        Served {
+            shards: shards,
+            unopened: unopened,
+            nb_shard: db.nb_index(),
+            paths: paths,
+            tombstones: db.tombstones().iter().cloned().collect(),
+            translation: db.translation().cloned(),
+            generation: generation,
+            loaded: db.last_reload(),
+            failures: Mutex::new(BTreeMap::new()),
+            cache: Mutex::new(PostingCache::new(config.capacity)),
+            config: config,
+        }
+    }
+
+    pub fn nb_shard(&self) -> usize {
+        self.nb_shard
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // the ids holding the pattern, ascending, and the shards that could not
+    // be opened or searched
+    pub fn search(&self, pattern: &[u8]) -> Result<(Vec<u32>, Vec<ShardWarning>)> {
+        self.search_read(pattern).map(|(ids, warnings, _)| (ids, warnings))
+    }
+
+    // same, and how the lists were read
+    pub fn search_read(&self, pattern: &[u8]) -> Result<(Vec<u32>, Vec<ShardWarning>, Reading)> {
+
+        let translated = self.translation.as_ref().map(|t| t.apply(pattern));
+        let pattern = translated.as_ref().map_or(pattern, |p| &p[..]);
+        if pattern.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("pattern of {} byte(s), a pattern has at least 4", pattern.len())));
+        }
+
+        let mut warnings = self.unopened.clone();
+        let mut reading = Reading::default();
+        for &(n, ref shard) in &self.shards {
+            match shard.plan(pattern, DEFAULT_PRESELECT) {
+                Ok(plan) => {
+                    reading.volume += plan.order.iter().map(|&ngram| shard.get_ids_size_by_ngram(ngram) as u64).sum::<u64>();
+                    reading.plans.push((n, plan));
+                },
+                Err(e) => warnings.push(self.failed(n, shard, &e)),
+            }
+        }
+        reading.over_budget = reading.volume > self.config.budget;
+
+        let mut found = HashSet::new();
+        let mut decoded = Vec::new();
+        for &(n, ref plan) in &reading.plans {
+            let shard = &self.shards.iter().find(|s| s.0 == n).unwrap().1;
+            found.extend(self.search_plan(n, shard, plan, reading.over_budget, &mut decoded));
+            if let Err(e) = shard.check_reads() {
+                warnings.push(self.failed(n, shard, &e));
+            }
+        }
+        reading.decoded = decoded.len();
+        reading.from_cache = reading.plans.iter().map(|p| p.1.order.len()).sum::<usize>() - decoded.len();
+
+        if reading.over_budget {
+            self.cache.lock().unwrap().stats.bypassed += 1;
+            if self.config.release_pages {
+                for &(n, ngram) in &decoded {
+                    self.shards.iter().find(|s| s.0 == n).unwrap().1.release_list(ngram);
+                }
+                reading.released = true;
+            }
+        }
+
+        let mut ids: Vec<u32> = found.into_iter().filter(|id| !self.tombstones.contains(id)).collect();
+        ids.sort();
+        Ok((ids, warnings, reading))
+    }
+
+    // the lists of the plan intersected, read from the cache when there,
+    // inserted unless the request is over its budget or the shard is live
+    fn search_plan(&self, n: usize, shard: &BinacleFile, plan: &Plan, over_budget: bool, decoded: &mut Vec<(usize, u32)>) -> HashSet<u32> {
+
+        let cached = shard.snapshot().is_none();
+        let mut set: Option<HashSet<u32>> = None;
+        for &ngram in &plan.order {
+            let hit = match cached {
+                true => self.cache.lock().unwrap().get(n, ngram),
+                false => None,
+            };
+            let list = match hit {
+                Some(list) => list,
+                None => {
+                    let list = Arc::new(shard.get_ids_by_ngram(ngram));
+                    decoded.push((n, ngram));
+                    if cached && !over_budget {
+                        self.cache.lock().unwrap().insert(n, ngram, list.clone());
+                    }
+                    list
+                },
+            };
+            let next = match set {
+                Some(set) => set.intersection(&list).cloned().collect(),
+                None => (*list).clone(),
+            };
+            if next.is_empty() {
+                return next;
+            }
+            set = Some(next);
+        }
+        set.unwrap_or(HashSet::new())
+    }
+
+    // a search the shard failed, counted for /stats
+    fn failed(&self, n: usize, shard: &BinacleFile, e: &Error) -> ShardWarning {
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures.entry(n).or_insert((0, String::new()));
+        failure.0 += 1;
+        failure.1 = e.to_string();
+        ShardWarning { shard: n, path: shard.path.clone(), error: e.to_string() }
+    }
+
+    pub fn to_json(&self, ids: &[u32], warnings: &[ShardWarning]) -> Json {
+        let results = Json::Array(ids.iter().map(|&id| match self.paths {
+            Some(ref paths) => {
+                let mut entry = BTreeMap::new();
+                entry.insert(String::from("id"), Json::U64(id as u64));
+                entry.insert(String::from("path"), paths.get(&id).map_or(Json::Null, |p| Json::String(p.clone())));
+                Json::Object(entry)
+            },
+            None => Json::U64(id as u64),
+        }).collect());
+        if warnings.is_empty() {
+            return results;
+        }
+        let mut answer = BTreeMap::new();
+        answer.insert(String::from("results"), results);
+        answer.insert(String::from("partial"), true.to_json());
+        answer.insert(String::from("failed_shards"), Json::Array(warnings.iter().map(|w| {
+            let mut failed = BTreeMap::new();
+            failed.insert(String::from("shard"), w.shard.to_json());
+            failed.insert(String::from("path"), w.path.to_json());
+            failed.insert(String::from("error"), w.error.to_json());
+            Json::Object(failed)
+        }).collect()));
+        Json::Object(answer)
+    }
+
+    // the answer of ?explain: the results and how they were read
+    pub fn explain_json(&self, ids: &[u32], warnings: &[ShardWarning], reading: &Reading) -> Json {
+        let mut answer = match self.to_json(ids, warnings) {
+            Json::Object(answer) => answer,
+            results => {
+                let mut answer = BTreeMap::new();
+                answer.insert(String::from("results"), results);
+                answer
+            },
+        };
+        let mut explain = BTreeMap::new();
+        explain.insert(String::from("plans"), Json::Array(reading.plans.iter().map(|&(n, ref plan)| {
+            Json::String(format!("shard {}: {}", n, plan.describe()))
+        }).collect()));
+        explain.insert(String::from("ids"), reading.volume.to_json());
+        explain.insert(String::from("budget"), self.config.budget.to_json());
+        explain.insert(String::from("cached"), (!reading.over_budget).to_json());
+        explain.insert(String::from("from_cache"), reading.from_cache.to_json());
+        explain.insert(String::from("decoded"), reading.decoded.to_json());
+        explain.insert(String::from("released"), reading.released.to_json());
+        answer.insert(String::from("explain"), Json::Object(explain));
+        Json::Object(answer)
+    }
+
+    // the generation, its load time in seconds since the epoch (none for
+    // the first one) and a line for each shard of the meta
+    pub fn stats(&self) -> Json {
+        let failures = self.failures.lock().unwrap();
+        let mut shards = Vec::with_capacity(self.nb_shard);
+        for n in 0 .. self.nb_shard {
+            let mut shard = BTreeMap::new();
+            shard.insert(String::from("shard"), n.to_json());
+            let (status, error, failed) = match self.unopened.iter().find(|w| w.shard == n) {
+                Some(w) => ("unopened", Some(w.error.clone()), 0),
+                None => match failures.get(&n) {
+                    Some(&(count, ref error)) => ("failing", Some(error.clone()), count),
+                    None => ("ok", None, 0),
+                },
+            };
+            shard.insert(String::from("status"), status.to_json());
+            shard.insert(String::from("failed_searches"), failed.to_json());
+            if let Some(error) = error {
+                shard.insert(String::from("error"), error.to_json());
+            }
+            shards.push(Json::Object(shard));
+        }
+        let mut stats = BTreeMap::new();
+        stats.insert(String::from("generation"), self.generation.to_json());
+        stats.insert(String::from("last_reload"), self.loaded.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                                                             .map_or(Json::Null, |d| d.as_secs().to_json()));
+        stats.insert(String::from("shards"), Json::Array(shards));
+        let cache = self.cache.lock().unwrap();
+        let mut counts = BTreeMap::new();
+        counts.insert(String::from("capacity"), cache.capacity().to_json());
+        counts.insert(String::from("budget"), self.config.budget.to_json());
+        counts.insert(String::from("ids"), cache.ids().to_json());
+        counts.insert(String::from("lists"), cache.len().to_json());
+        counts.insert(String::from("hits"), cache.stats.hits.to_json());
+        counts.insert(String::from("misses"), cache.stats.misses.to_json());
+        counts.insert(String::from("inserts"), cache.stats.inserts.to_json());
+        counts.insert(String::from("evictions"), cache.stats.evictions.to_json());
+        counts.insert(String::from("over_budget"), cache.stats.bypassed.to_json());
+        stats.insert(String::from("cache"), Json::Object(counts));
+        Json::Object(stats)
+    }
+}
+
+// a request as read from the connection
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+}
+
+pub struct Server {
+    // read again by the reloads
+    db: Mutex<BinacleManager>,
+    served: RwLock<Arc<Served>>,
+    config: CacheConfig,
+}
+
+impl Server {
+
+    // the database as opened by the caller, the server reloads it
+    pub fn new(db: BinacleManager) -> Server {
+        Server::with_cache(db, CacheConfig::default())
+    }
+
+    pub fn with_cache(mut db: BinacleManager, config: CacheConfig) -> Server {
+        let served = Served::load(&mut db, 0, config);
+        Server {
+            db: Mutex::new(db),
+            served: RwLock::new(Arc::new(served)),
+            config: config,
+        }
+    }
+
+    pub fn served(&self) -> Arc<Served> {
+        self.served.read().unwrap().clone()
+    }
+
+    // the files the reloads open again: the database and its shards
+    pub fn paths(&self) -> Vec<String> {
+        let served = self.served();
+        let mut paths = vec![self.db.lock().unwrap().db_path.clone()];
+        paths.extend(served.shards.iter().map(|&(_, ref shard)| shard.path.clone()));
+        paths.extend(served.unopened.iter().map(|w| w.path.clone()));
+        paths
+    }
+
+    // the shards of the next requests opened again when the meta changed
+    // since the last load: the served ones are kept when the database can
+    // not be read. Tells if a new generation is served
+    pub fn reload(&self) -> Result<bool> {
+        let mut db = self.db.lock().unwrap();
+        self.reload_locked(&mut db)
+    }
+
+    // the stamp of the meta checked before a search, a reload when a writer
+    // changed it: no POST /reload needed. Left to the request already
+    // reloading, and the served shards kept when the database can not be
+    // read (the next /reload tells why)
+    fn refresh(&self) {
+        if let Ok(mut db) = self.db.try_lock() {
+            let _ = self.reload_locked(&mut db);
+        }
+    }
+
+    fn reload_locked(&self, db: &mut BinacleManager) -> Result<bool> {
+        if !try!(db.reload()) {
+            return Ok(false);
+        }
+        let served = Served::load(db, self.served().generation + 1, self.config);
+        *self.served.write().unwrap() = Arc::new(served);
+        Ok(true)
+    }
+
+    // the request read from input, its response written to out
+    pub fn answer<R: BufRead, W: Write>(&self, input: R, out: &mut W) -> Result<()> {
+        let (status, body) = match read_request(input) {
+            Ok(request) => self.route(&request),
+            Err(e) => (status_of(&e), error_json(&e)),
+        };
+        write_response(out, status, &body)
+    }
+
+    fn route(&self, request: &Request) -> (u16, String) {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/search") => {
+                self.refresh();
+                let searched = pattern_of(request).and_then(|(pattern, explain)| {
+                    let served = self.served();
+                    served.search_read(&pattern).map(|(ids, warnings, reading)| match explain {
+                        true => served.explain_json(&ids, &warnings, &reading),
+                        false => served.to_json(&ids, &warnings),
+                    })
+                });
+                match searched {
+                    Ok(json) => (200, json.to_string()),
+                    Err(e) => (status_of(&e), error_json(&e)),
+                }
+            },
+            ("POST", "/reload") => match self.reload() {
+                Ok(reloaded) => {
+                    let served = self.served();
+                    let mut answer = BTreeMap::new();
+                    answer.insert(String::from("reloaded"), reloaded.to_json());
+                    answer.insert(String::from("generation"), served.generation.to_json());
+                    answer.insert(String::from("shards"), served.nb_shard.to_json());
+                    (200, Json::Object(answer).to_string())
+                },
+                Err(e) => (500, error_json(&e)),
+            },
+            ("GET", "/stats") => (200, self.served().stats().to_string()),
+            (_, "/search") | (_, "/reload") => (405, error_json(&format!("{} {}: only POST", request.method, request.path))),
+            (_, "/stats") => (405, error_json(&format!("{} {}: only GET", request.method, request.path))),
+            _ => (404, error_json(&format!("no {}, /search, /reload or /stats", request.path))),
+        }
+    }
+
+    // a connection answered, its error printed
+    fn handle(&self, mut stream: TcpStream) {
+        let answered = stream.set_read_timeout(Some(READ_TIMEOUT))
+            .and_then(|_| stream.try_clone())
+            .and_then(|input| self.answer(BufReader::new(input), &mut stream));
+        if let Err(e) = answered {
+            eprintln!("Warning: request of {} not answered: {}", stream.peer_addr().map_or(String::from("?"), |a| a.to_string()), e);
+        }
+    }
+}
+
+// the server on addr until the process is killed, in the sandbox once
+// the socket is bound
+pub fn serve(server: Server, addr: &str, sandbox: &Sandbox) -> Result<()> {
+
+    let listener = try!(TcpListener::bind(addr).map_err(|e| Error::new(e.kind(), format!("{}: {}", addr, e))));
+    try!(sandbox.enter());
+    for path in server.paths() {
+        try!(File::open(&path).map_err(|e| Error::new(e.kind(), format!("{}: {}, the reloads could not open it", path, e))));
+    }
+    eprintln!("{} shard(s) served on {}", server.served().nb_shard(), try!(listener.local_addr()));
+
+    // the accepted connections wait in the queue for a worker, the accept
+    // loop waits when it is full
+    let server = Arc::new(server);
+    let (queue, connections) = sync_channel::<TcpStream>(WORKERS);
+    let connections = Arc::new(Mutex::new(connections));
+    for _ in 0 .. WORKERS {
+        let server = server.clone();
+        let connections = connections.clone();
+        thread::spawn(move || loop {
+            let stream = connections.lock().unwrap().recv();
+            match stream {
+                Ok(stream) => server.handle(stream),
+                Err(_) => return,
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => try!(queue.send(stream).map_err(|_| Error::new(ErrorKind::Other, "no worker left"))),
+            Err(e) => eprintln!("Warning: connection not accepted: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn read_request<R: BufRead>(mut input: R) -> Result<Request> {
+
+    let mut line = String::new();
+    try!(input.read_line(&mut line));
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("bad request line {:?}", line.trim_end()))),
+    };
+
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if try!(input.read_line(&mut header)) == 0 {
+            break;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(colon) = header.find(':') {
+            if header[.. colon].trim().eq_ignore_ascii_case("content-length") {
+                length = try!(header[colon + 1 ..].trim().parse::<usize>()
+                              .map_err(|e| Error::new(ErrorKind::InvalidData, format!("bad Content-Length: {}", e))));
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(Error::new(ErrorKind::InvalidData, format!("body of {} bytes, at most {}", length, MAX_BODY)));
+    }
+    let mut body = vec![0; length];
+    try!(input.read_exact(&mut body));
+
+    let (path, query) = match target.find('?') {
+        Some(mark) => (&target[.. mark], Some(String::from(&target[mark + 1 ..]))),
+        None => (target, None),
+    };
+    Ok(Request { method: String::from(method), path: String::from(path), query: query, body: body })
+}
+
+// the bytes searched, the whitespace around hex ignored, and if the
+// reading is explained
+fn pattern_of(request: &Request) -> Result<(Vec<u8>, bool)> {
+    let (mut hex, mut explain) = (false, false);
+    for word in request.query.as_ref().map_or("", String::as_str).split('&').filter(|w| !w.is_empty()) {
+        match word {
+            "hex" => hex = true,
+            "explain" => explain = true,
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown query {:?}, only hex and explain", word))),
+        }
+    }
+    if !hex {
+        return Ok((request.body.clone(), explain));
+    }
+    str::from_utf8(&request.body)
+        .map_err(|e| e.to_string())
+        .and_then(|hex| hex.split_whitespace().collect::<String>().from_hex().map_err(|e| e.to_string()))
+        .map(|pattern| (pattern, explain))
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid hex pattern: {}", e)))
+}
+
+// the client is at fault for what it sent, the server for the rest
+fn status_of(e: &Error) -> u16 {
+    match e.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::UnexpectedEof => 400,
+        _ => 500,
+    }
+}
+
+fn error_json<E: ToString>(e: &E) -> String {
+    let mut answer = BTreeMap::new();
+    answer.insert(String::from("error"), Json::String(e.to_string()));
+    Json::Object(answer).to_string()
+}
+
+fn write_response<W: Write>(out: &mut W, status: u16, body: &str) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    try!(write!(out, "HTTP/1.0 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, reason, body.len(), body));
+    out.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle_manager::tests::cleanup;
+
+    fn shared<T: Send + Sync>() {}
+
+    fn post(server: &Server, target: &str, body: &[u8]) -> (String, Json) {
+        let mut request = format!("POST {} HTTP/1.0\r\nHost: x\r\nContent-Length: {}\r\n\r\n", target, body.len()).into_bytes();
+        request.extend_from_slice(body);
+        let mut out = Vec::new();
+        server.answer(&request[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let split = out.find("\r\n\r\n").unwrap();
+        (String::from(out.lines().next().unwrap()), Json::from_str(&out[split + 4 ..]).unwrap())
+    }
+
+    // closed and opened again, the meta of its shard written
+    fn fixture(name: &str, files: u32) -> BinacleManager {
+        let mut db = BinacleManager::create(name, true, 1 << 30, 6, 20).unwrap();
+        for id in 1 ..= files {
+            let content = format!("header {} call GetProcAddress {:03}ioc", id % 3, id);
+            db.insert_reader(content.as_bytes(), &format!("file{}.bin", id), id, true);
+        }
+        drop(db);
+        BinacleManager::open(name).unwrap()
+    }
+
+    #[test]
+    fn answers() {
+        {
+            let server = Server::new(fixture("test_server1.db", 6));
+
+            let (status, json) = post(&server, "/search", b"004ioc");
+            assert_eq!("HTTP/1.0 200 OK", status);
+            assert_eq!(Json::from_str(r#"[{"id": 4, "path": "file4.bin"}]"#).unwrap(), json);
+            let (_, json) = post(&server, "/search?hex", b"68 65 61 64 65 72 20 31 20 63 61 6c 6c\n");
+            let ids: Vec<u64> = json.as_array().unwrap().iter().map(|e| e["id"].as_u64().unwrap()).collect();
+            assert_eq!(vec![1, 4], ids);
+
+            for &(target, body, expected) in &[("/search", &b"ioc"[..], "400"), ("/search?hex", &b"4g"[..], "400"),
+                                               ("/search?raw", &b"004ioc"[..], "400"), ("/nothing", &b""[..], "404")] {
+                let (status, json) = post(&server, target, body);
+                assert!(status.starts_with(&format!("HTTP/1.0 {}", expected)), "{} {}", target, status);
+                assert!(json.find("error").is_some());
+            }
+            let mut out = Vec::new();
+            server.answer(&b"GET /search HTTP/1.0\r\n\r\n"[..], &mut out).unwrap();
+            assert!(String::from_utf8(out).unwrap().starts_with("HTTP/1.0 405"));
+            let (status, _) = post(&server, "/stats", b"");
+            assert!(status.starts_with("HTTP/1.0 405"));
+            let mut out = Vec::new();
+            server.answer(&b"POST /search HTTP/1.0\r\nContent-Length: 10\r\n\r\nioc"[..], &mut out).unwrap();
+            assert!(String::from_utf8(out).unwrap().starts_with("HTTP/1.0 400"));
+        }
+        cleanup("test_server1");
+    }
+
+    // the threads search the same shards
+    #[test]
+    fn shared_shards() {
+
+        shared::<BinacleFile>();
+        shared::<Served>();
+        {
+            let mut db = fixture("test_server2.db", 40);
+            let served = Arc::new(Served::load(&mut db, 0, CacheConfig::default()));
+            let threads: Vec<_> = (0 .. 4).map(|t| {
+                let served = served.clone();
+                thread::spawn(move || (1 .. 41).filter(|id| id % 4 == t).map(|id| {
+                    (id, served.search(format!("{:03}ioc", id).as_bytes()).unwrap().0)
+                }).collect::<Vec<_>>())
+            }).collect();
+            for thread in threads {
+                for (id, ids) in thread.join().unwrap() {
+                    assert_eq!(vec![id], ids);
+                }
+            }
+        }
+        cleanup("test_server2");
+    }
+
+    fn get(server: &Server, target: &str) -> (String, Json) {
+        let mut out = Vec::new();
+        server.answer(format!("GET {} HTTP/1.0\r\n\r\n", target).as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let split = out.find("\r\n\r\n").unwrap();
+        (String::from(out.lines().next().unwrap()), Json::from_str(&out[split + 4 ..]).unwrap())
+    }
+
+    // the files inserted by another manager are found after a reload
+    #[test]
+    fn reloads() {
+        {
+            let server = Server::new(fixture("test_server3.db", 3));
+            let (_, stats) = get(&server, "/stats");
+            assert_eq!(Some(0), stats["generation"].as_u64());
+            assert!(stats["last_reload"].is_null());
+
+            // nothing changed, nothing opened again
+            let (_, json) = post(&server, "/reload", b"");
+            assert_eq!(Some(false), json["reloaded"].as_boolean());
+            assert_eq!(Some(0), json["generation"].as_u64());
+
+            {
+                let mut writer = BinacleManager::open("test_server3.db").unwrap();
+                writer.insert_reader(&b"a late GetProcAddress"[..], "late.bin", 4, true);
+                writer.delete(2).unwrap();
+            }
+
+            // read as of the meta when the server started
+            let (ids, _) = server.served().search(b"GetProcAddress").unwrap();
+            assert!(!ids.contains(&4), "{:?}", ids);
+            let (status, json) = post(&server, "/reload", b"");
+            assert_eq!("HTTP/1.0 200 OK", status);
+            assert_eq!(Some(true), json["reloaded"].as_boolean());
+            assert_eq!(Some(1), json["generation"].as_u64());
+            assert_eq!(Some(1), json["shards"].as_u64());
+            let (_, json) = post(&server, "/search", b"GetProcAddress");
+            let paths: Vec<&str> = json.as_array().unwrap().iter().map(|e| e["path"].as_string().unwrap()).collect();
+            assert_eq!(vec!["file1.bin", "file3.bin", "late.bin"], paths);
+            let (_, stats) = get(&server, "/stats");
+            assert_eq!(Some(1), stats["generation"].as_u64());
+            assert!(stats["last_reload"].as_u64().is_some());
+
+            // the shards served are kept when the database can not be read
+            cleanup("test_server3");
+            let (status, _) = post(&server, "/reload", b"");
+            assert!(status.starts_with("HTTP/1.0 500"), "{}", status);
+            assert_eq!(3, server.served().search(b"GetProcAddress").unwrap().0.len());
+        }
+        cleanup("test_server3");
+    }
+
+    // the changes of a writer found by the next search, without /reload
+    #[test]
+    fn reloads_on_search() {
+        {
+            let server = Server::new(fixture("test_server6.db", 3));
+            {
+                let mut writer = BinacleManager::open("test_server6.db").unwrap();
+                writer.insert_reader(&b"a late GetProcAddress"[..], "late.bin", 4, true);
+            }
+            let (status, json) = post(&server, "/search", b"GetProcAddress");
+            assert_eq!("HTTP/1.0 200 OK", status);
+            let paths: Vec<&str> = json.as_array().unwrap().iter().map(|e| e["path"].as_string().unwrap()).collect();
+            assert_eq!(vec!["file1.bin", "file2.bin", "file3.bin", "late.bin"], paths);
+            let (_, stats) = get(&server, "/stats");
+            assert_eq!(Some(1), stats["generation"].as_u64());
+
+            // nothing changed since
+            let (_, json) = post(&server, "/reload", b"");
+            assert_eq!(Some(false), json["reloaded"].as_boolean());
+            post(&server, "/search", b"GetProcAddress");
+            assert_eq!(1, server.served().generation);
+
+            // the served shards are still searched
+            cleanup("test_server6");
+            let (_, json) = post(&server, "/search", b"GetProcAddress");
+            assert_eq!(4, json.as_array().unwrap().len());
+        }
+        cleanup("test_server6");
+    }
+
+    // a shard that can not be opened leaves the others searched
+    #[test]
+    fn partial_results() {
+        {
+            let mut db = BinacleManager::create("test_server4.db", true, 1, 6, 16).unwrap();
+            for id in 1 .. 4 {
+                db.insert_reader(&b"call GetProcAddress"[..], &format!("file{}.bin", id), id, true);
+            }
+        }
+        ::std::fs::remove_file("test_server4.db_index1.db").unwrap();
+        {
+            let server = Server::new(BinacleManager::open("test_server4.db").unwrap());
+            let (status, json) = post(&server, "/search", b"GetProcAddress");
+            assert_eq!("HTTP/1.0 200 OK", status);
+            assert_eq!(Some(true), json["partial"].as_boolean());
+            let ids: Vec<u64> = json["results"].as_array().unwrap().iter().map(|e| e["id"].as_u64().unwrap()).collect();
+            assert_eq!(vec![1, 3], ids);
+            let failed = json["failed_shards"].as_array().unwrap();
+            assert_eq!(1, failed.len());
+            assert_eq!(Some(1), failed[0]["shard"].as_u64());
+            assert_eq!(Some("test_server4.db_index1.db"), failed[0]["path"].as_string());
+
+            // still a request error
+            let (status, _) = post(&server, "/search", b"ioc");
+            assert!(status.starts_with("HTTP/1.0 400"));
+
+            let (status, stats) = get(&server, "/stats");
+            assert_eq!("HTTP/1.0 200 OK", status);
+            let health: Vec<&str> = stats["shards"].as_array().unwrap().iter().map(|s| s["status"].as_string().unwrap()).collect();
+            assert_eq!(vec!["ok", "unopened", "ok"], health);
+            assert!(stats["shards"][1]["error"].is_string());
+        }
+        cleanup("test_server4");
+    }
+
+    // the lists of a request over its budget are read, not cached: the
+    // ones of the small requests stay
+    #[test]
+    fn request_budget() {
+        {
+            let mut db = fixture("test_server5.db", 40);
+            db.mark_shard_full(0);
+            db.finalize_shard(0).unwrap();
+            let config = CacheConfig { capacity: 1000, budget: 100, release_pages: true };
+            let server = Server::with_cache(db, config);
+            let served = server.served();
+
+            for id in 1 .. 6 {
+                let (ids, _) = served.search(format!("{:03}ioc", id).as_bytes()).unwrap();
+                assert_eq!(vec![id], ids);
+            }
+            let small = served.cache.lock().unwrap().len();
+            let held = served.cache.lock().unwrap().ids();
+            assert!(small > 0);
+
+            // the same lists again, from the cache
+            let (status, json) = post(&server, "/search?explain", b"003ioc");
+            assert_eq!("HTTP/1.0 200 OK", status);
+            assert_eq!(Some(3), json["results"][0]["id"].as_u64());
+            assert_eq!(Some(true), json["explain"]["cached"].as_boolean());
+            assert_eq!(Some(0), json["explain"]["decoded"].as_u64());
+
+            // every file has it
+            let (status, json) = post(&server, "/search?explain", b"call GetProcAddress");
+            assert_eq!("HTTP/1.0 200 OK", status);
+            assert_eq!(40, json["results"].as_array().unwrap().len());
+            assert_eq!(Some(false), json["explain"]["cached"].as_boolean());
+            assert_eq!(Some(true), json["explain"]["released"].as_boolean());
+            assert!(json["explain"]["ids"].as_u64().unwrap() > 100);
+
+            let cache = served.cache.lock().unwrap();
+            assert_eq!(small, cache.len());
+            assert_eq!(held, cache.ids());
+            assert_eq!(0, cache.stats.evictions);
+            assert_eq!(1, cache.stats.bypassed);
+            drop(cache);
+            assert_eq!(vec![7], served.search(b"007ioc").unwrap().0);
+
+            let (_, stats) = get(&server, "/stats");
+            assert_eq!(Some(1), stats["cache"]["over_budget"].as_u64());
+            let (status, _) = post(&server, "/search?explain&raw", b"003ioc");
+            assert!(status.starts_with("HTTP/1.0 400"));
+        }
+        cleanup("test_server5");
+    }
+}
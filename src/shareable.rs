@@ -0,0 +1,281 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, BTreeMap, HashMap};
+use std::fs::File;
+use std::io::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rustc_serialize::hex::ToHex;
+
+use aggregate::{self, Aggregation};
+use binacle_manager::BinacleManager;
+use hash::{Sha256, Digest};
+
+// Statistics of a database shared with partners (--stats --shareable):
+// only aggregates, from which the presence of a given file in the corpus
+// can not be told.
+//
+// - a count under the floor (k-anonymity) is suppressed, null in the
+//   export: the bins of the histograms are kept, the types and the tags
+//   are merged in "other", their name is not given;
+// - the counts over it are rounded down to a multiple of the bucket, so
+//   that two exports do not tell a file added between them;
+// - the most frequent n-grams are omitted, or with --salt-top hashed with a
+//   salt drawn for the export and not written in it: the n-grams are too
+//   few for a hash without salt to hide them.
+//
+// The floor and the bucket are written in the export.
+
+
+pub const DEFAULT_FLOOR: u64 = 10;
+
+pub const DEFAULT_BUCKET: u64 = 10;
+
+// n-grams of each shard kept for the most frequent ones
+pub const TOP_NGRAMS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShareParams {
+    // the counts under it are suppressed
+    pub floor: u64,
+    // the counts are rounded down to a multiple of it
+    pub bucket: u64,
+    // the most frequent n-grams salted and hashed instead of omitted
+    pub salt_top: bool,
+}
+
+impl ShareParams {
+
+    pub fn new(floor: u64, bucket: u64, salt_top: bool) -> Result<ShareParams> {
+        if floor == 0 || bucket == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "the floor and the bucket of a shareable export are at least 1"));
+        }
+        Ok(ShareParams { floor: floor, bucket: bucket, salt_top: salt_top })
+    }
+
+    // None when suppressed
+    pub fn count(&self, count: u64) -> Option<u64> {
+        let rounded = count - count % self.bucket;
+        match count < self.floor || rounded < self.floor {
+            true => None,
+            false => Some(rounded),
+        }
+    }
+}
+
+// the statistics as collected, not to be shared
+#[derive(Clone, Debug, Default)]
+pub struct RawStats {
+    pub ngram_size: u8,
+    pub nb_index: usize,
+    pub nb_file: u64,
+    // lists by their number of ids, in bins of powers of 2: bin b holds
+    // the lists of 2^b to 2^(b+1) - 1 ids
+    pub df_bins: BTreeMap<u32, u64>,
+    pub types: Vec<(String, u64)>,
+    pub tags: Vec<(String, u64)>,
+    // (n-gram, number of ids), the most frequent first
+    pub top: Vec<(u32, u64)>,
+}
+
+// the statistics of all the shards and of the map
+pub fn collect(db: &mut BinacleManager) -> Result<RawStats> {
+
+    let mut stats = RawStats {
+        ngram_size: db.ngram_size(),
+        nb_index: db.nb_index(),
+        nb_file: db.nb_file() as u64,
+        ..RawStats::default()
+    };
+
+    let mut top: HashMap<u32, u64> = HashMap::new();
+    for shard in db.open_shards() {
+        let shard = try!(shard);
+        let mut frequent = BinaryHeap::new();
+        for (ngram, ids) in shard.postings() {
+            // the ids of the blocks, the list is not decoded
+            let df = ids.size_hint().1.unwrap_or(0) as u64;
+            *stats.df_bins.entry(63 - df.leading_zeros()).or_insert(0) += 1;
+            frequent.push(Reverse((df, ngram)));
+            if frequent.len() > TOP_NGRAMS {
+                frequent.pop();
+            }
+        }
+        for Reverse((df, ngram)) in frequent {
+            *top.entry(ngram).or_insert(0) += df;
+        }
+    }
+    stats.top = top.into_iter().collect();
+    stats.top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    stats.top.truncate(TOP_NGRAMS);
+
+    if db.is_map() {
+        let ids: Vec<u32> = db.entries().map(|(id, _)| id).filter(|id| !db.is_tombstoned(*id)).collect();
+        let count_by = |by| aggregate::count_by(db, &ids, by)
+            .map(|counts| counts.into_iter().map(|(group, n)| (group, n as u64)).collect::<Vec<(String, u64)>>());
+        stats.types = try!(count_by(Aggregation::Type));
+        stats.tags = try!(count_by(Aggregation::Tag));
+    }
+    Ok(stats)
+}
+
+// a salt for one export, never written
+pub fn draw_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; 16];
+    if File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut salt)).is_ok() {
+        return salt;
+    }
+    let mut hasher = Sha256::new();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    hasher.update(&now.to_le_bytes());
+    hasher.update(&::std::process::id().to_le_bytes());
+    hasher.finalize()[.. 16].to_vec()
+}
+
+fn salted(salt: &[u8], ngram: u32) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(&ngram.to_le_bytes());
+    hasher.finalize()[.. 8].to_hex()
+}
+
+// the groups over the floor, the other ones merged
+fn groups(counts: &[(String, u64)], params: &ShareParams) -> BTreeMap<String, Option<u64>> {
+    let mut groups = BTreeMap::new();
+    let mut other = 0;
+    for &(ref name, count) in counts {
+        match params.count(count) {
+            Some(count) => { groups.insert(name.clone(), Some(count)); },
+            None => other += count,
+        }
+    }
+    if other > 0 {
+        groups.insert(String::from("other"), params.count(other));
+    }
+    groups
+}
+
+// the export, a JSON object
+pub fn shareable(stats: &RawStats, params: &ShareParams, salt: &[u8]) -> String {
+
+    use rustc_serialize::json::{Json, Object};
+
+    let count = |count: u64| params.count(count).map_or(Json::Null, Json::U64);
+    let named = |counts: &[(String, u64)]| {
+        Json::Object(groups(counts, params).into_iter()
+            .map(|(name, count)| (name, count.map_or(Json::Null, Json::U64))).collect())
+    };
+
+    let mut export = Object::new();
+    let mut recorded = Object::new();
+    recorded.insert(String::from("floor"), Json::U64(params.floor));
+    recorded.insert(String::from("bucket"), Json::U64(params.bucket));
+    recorded.insert(String::from("top_ngrams"), Json::String(String::from(if params.salt_top { "salted" } else { "omitted" })));
+    export.insert(String::from("params"), Json::Object(recorded));
+    export.insert(String::from("ngram_size"), Json::U64(stats.ngram_size as u64));
+    export.insert(String::from("nb_index"), Json::U64(stats.nb_index as u64));
+    export.insert(String::from("nb_file"), count(stats.nb_file));
+
+    let bins = stats.df_bins.iter().map(|(&bin, &lists)| {
+        let mut b = Object::new();
+        b.insert(String::from("min_df"), Json::U64(1 << bin));
+        b.insert(String::from("max_df"), Json::U64((1 << (bin + 1)) - 1));
+        b.insert(String::from("lists"), count(lists));
+        Json::Object(b)
+    }).collect();
+    export.insert(String::from("df_histogram"), Json::Array(bins));
+    export.insert(String::from("types"), named(&stats.types));
+    export.insert(String::from("tags"), named(&stats.tags));
+
+    if params.salt_top {
+        let top = stats.top.iter().filter_map(|&(ngram, df)| params.count(df).map(|df| {
+            Json::Array(vec![Json::String(salted(salt, ngram)), Json::U64(df)])
+        })).collect();
+        export.insert(String::from("top_ngrams"), Json::Array(top));
+    }
+    Json::Object(export).to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rustc_serialize::json::Json;
+    use binacle_manager::tests::cleanup;
+
+    #[test]
+    fn rounded_counts() {
+        let params = ShareParams::new(10, 5, false).unwrap();
+        let cases = [(0, None), (1, None), (9, None), (10, Some(10)), (14, Some(10)), (15, Some(15)), (123, Some(120))];
+        for &(count, shared) in cases.iter() {
+            assert_eq!(shared, params.count(count), "{}", count);
+        }
+        // a bucket over the floor: rounded under it, suppressed
+        let params = ShareParams::new(3, 10, false).unwrap();
+        assert_eq!(None, params.count(7));
+        assert_eq!(Some(10), params.count(19));
+        assert!(ShareParams::new(0, 10, false).is_err());
+    }
+
+    // the counts of an export, the suppressed ones null
+    fn shared_counts(json: &Json) -> Vec<&Json> {
+        let mut counts = vec![json.find("nb_file").unwrap()];
+        for group in &["types", "tags"] {
+            counts.extend(json.find(group).unwrap().as_object().unwrap().values());
+        }
+        for bin in json.find("df_histogram").unwrap().as_array().unwrap() {
+            counts.push(bin.find("lists").unwrap());
+        }
+        for top in json.find("top_ngrams").and_then(|t| t.as_array()).into_iter().flat_map(|t| t.iter()) {
+            counts.push(&top.as_array().unwrap()[1]);
+        }
+        counts
+    }
+
+    #[test]
+    fn nothing_low_nothing_reversible() {
+        let mut db = BinacleManager::create("test_share.db", true, 1 << 30, 6, 16).unwrap();
+        for id in 1 .. 26 {
+            let content = format!("GetProcAddress LoadLibraryA {}", id);
+            db.insert_reader(content.as_bytes(), &format!("{}.bin", id), id, true);
+            db.set_tags(id, vec![String::from(if id == 7 { "case-1337" } else { "family:emotet" })]).unwrap();
+        }
+        let stats = collect(&mut db).unwrap();
+        assert_eq!(25, stats.nb_file);
+        assert!(stats.tags.contains(&(String::from("case-1337"), 1)));
+        assert!(!stats.top.is_empty());
+
+        let params = ShareParams::new(10, 10, false).unwrap();
+        let salt = draw_salt();
+        let export = shareable(&stats, &params, &salt);
+        let json = Json::from_str(&export).unwrap();
+        assert_eq!(Some(&Json::U64(20)), json.find("nb_file"));
+        assert_eq!(Some(&Json::U64(10)), json.find_path(&["params", "floor"]));
+        assert_eq!(Some(&Json::U64(20)), json.find_path(&["tags", "family:emotet"]));
+        // the rare tag is neither named nor counted
+        assert!(!export.contains("case-1337"));
+        assert_eq!(Some(&Json::Null), json.find_path(&["tags", "other"]));
+        assert!(json.find("top_ngrams").is_none());
+
+        for count in shared_counts(&json) {
+            assert!(count.is_null() || count.as_u64().map_or(false, |n| n >= 10 && n % 10 == 0), "{} in {}", count, export);
+        }
+
+        // the top n-grams hashed: not the n-grams, not the same in another export
+        let params = ShareParams::new(10, 10, true).unwrap();
+        let export = shareable(&stats, &params, &salt);
+        let json = Json::from_str(&export).unwrap();
+        let top = json.find("top_ngrams").unwrap().as_array().unwrap();
+        assert!(!top.is_empty());
+        for count in shared_counts(&json) {
+            assert!(count.is_null() || count.as_u64().map_or(false, |n| n >= 10 && n % 10 == 0), "{} in {}", count, export);
+        }
+        let hashed: Vec<&str> = top.iter().map(|t| t.as_array().unwrap()[0].as_string().unwrap()).collect();
+        for &(ngram, _) in &stats.top {
+            assert!(!hashed.contains(&format!("{:x}", ngram).as_str()) && !hashed.contains(&ngram.to_string().as_str()));
+        }
+        assert!(export != shareable(&stats, &params, &draw_salt()));
+        assert!(!export.contains(&salt.to_hex()));
+        drop(db);
+        cleanup("test_share");
+    }
+}
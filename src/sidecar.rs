@@ -0,0 +1,136 @@
+use std::fs::OpenOptions;
+use std::io::*;
+use rustc_serialize::json::Json;
+
+// Metadata of a sample kept next to it: sample.bin and sample.bin.json, a
+// flat JSON object (source, family, first seen...). The allowed keys are
+// stored as tags key:value of the map entry, shown by --info and matched by
+// --tag. The sidecars are not indexed as content.
+
+
+#[derive(Clone, Debug)]
+pub struct Sidecars {
+    // appended to the path of a sample, ex: .json
+    pub suffix: String,
+    // the keys kept, all of them when None
+    pub keys: Option<Vec<String>>,
+}
+
+impl Sidecars {
+
+    // keys given as key1,key2, empty for all of them
+    pub fn new(suffix: &str, keys: &str) -> Sidecars {
+        Sidecars {
+            suffix: String::from(suffix),
+            keys: match keys {
+                "" => None,
+                keys => Some(keys.split(',').map(|k| String::from(k.trim())).filter(|k| !k.is_empty()).collect()),
+            },
+        }
+    }
+
+    pub fn is_sidecar(&self, path: &str) -> bool {
+        !self.suffix.is_empty() && path.ends_with(&self.suffix)
+    }
+
+    fn allowed(&self, key: &str) -> bool {
+        match self.keys {
+            Some(ref keys) => keys.iter().any(|k| k == key),
+            None => true,
+        }
+    }
+
+    // the tags of a sample, None when it has no sidecar: a sidecar that can
+    // not be read or is not a flat object is an error
+    pub fn tags_of(&self, sample: &str) -> Result<Option<Vec<String>>> {
+
+        let path = format!("{}{}", sample, self.suffix);
+        let mut content = String::new();
+        match OpenOptions::new().read(true).open(&path) {
+            Ok(mut f) => try!(f.read_to_string(&mut content)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let object = match Json::from_str(&content) {
+            Ok(Json::Object(o)) => o,
+            Ok(_) => return Err(Error::new(ErrorKind::InvalidData, format!("{}: not a JSON object", path))),
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e))),
+        };
+
+        let mut tags = Vec::new();
+        for (key, value) in object {
+            if !self.allowed(&key) {
+                continue;
+            }
+            let value = match value {
+                Json::String(s) => s,
+                Json::I64(n) => n.to_string(),
+                Json::U64(n) => n.to_string(),
+                Json::F64(n) => n.to_string(),
+                Json::Boolean(b) => b.to_string(),
+                Json::Null => continue,
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("{}: {} is not a flat value", path, key))),
+            };
+            tags.push(format!("{}:{}", key, value));
+        }
+        Ok(Some(tags))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use search::SearchRequest;
+
+    fn write(path: &str, content: &[u8]) {
+        File::create(path).unwrap().write_all(content).unwrap();
+    }
+
+    #[test]
+    fn present_absent_malformed() {
+
+        let _ = fs::remove_dir_all("test_sidecar");
+        fs::create_dir("test_sidecar").unwrap();
+        write("test_sidecar/a.bin", b"GetProcAddress");
+        write("test_sidecar/a.bin.json", b"{\"family\": \"emotet\", \"source\": \"vt\", \"first_seen\": 1496842205, \"size\": 14}");
+        write("test_sidecar/b.bin", b"GetProcAddress LoadLibraryA");
+        write("test_sidecar/c.bin", b"LoadLibraryW");
+        write("test_sidecar/c.bin.json", b"{\"family\": [\"a\", \"b\"]}");
+        write("test_sidecar/d.bin", b"kernel32.dll");
+        write("test_sidecar/d.bin.json", b"{\"family\": ");
+
+        let sidecars = Sidecars::new(".json", "family,source,first_seen");
+        assert_eq!(vec!["family:emotet", "first_seen:1496842205", "source:vt"],
+                   sidecars.tags_of("test_sidecar/a.bin").unwrap().unwrap());
+        assert_eq!(None, sidecars.tags_of("test_sidecar/b.bin").unwrap());
+        assert_eq!(ErrorKind::InvalidData, sidecars.tags_of("test_sidecar/c.bin").unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidData, sidecars.tags_of("test_sidecar/d.bin").unwrap_err().kind());
+        assert_eq!(4, Sidecars::new(".json", "").tags_of("test_sidecar/a.bin").unwrap().unwrap().len());
+
+        {
+            let mut db = BinacleManager::create("test_sidecar.db", true, 1 << 30, 6, 16).unwrap();
+            db.set_deterministic(true);
+            db.set_sidecars(Some(sidecars));
+            db.insert_dir_recursive("test_sidecar").unwrap();
+
+            // the sidecars are not indexed, the malformed ones do not stop their file
+            assert_eq!(4, db.nb_file());
+            assert!(db.entries().all(|(_, e)| e.path.ends_with(".bin")));
+            assert!(db.search(b"emotet").unwrap().is_empty());
+            let tags = |path: &str| db.entries().find(|&(_, e)| e.path.ends_with(path)).unwrap().1.tags.clone();
+            assert_eq!(3, tags("a.bin").unwrap().len());
+            assert_eq!(None, tags("b.bin"));
+            assert_eq!(None, tags("c.bin"));
+
+            let response = db.run(&SearchRequest::pattern(b"GetProc").tag("family:emotet")).unwrap();
+            assert_eq!(vec!["test_sidecar/a.bin"], response.entries);
+        }
+        cleanup("test_sidecar");
+        let _ = fs::remove_dir_all("test_sidecar");
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::*;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rustc_serialize::json::{Json, ToJson};
+
+use binacle::binacle_manager::BinacleManager;
+use binacle::search::SearchResponse;
+
+// Search results written to a file instead of stdout. Each format is a
+// ResultSink: the query is given first, then the hits one by one, nothing
+// is visible before finish(). The plain, TSV and JSON files are written
+// next to their path and renamed over it; the .hits table is appended to,
+// its query row is written after its hits and marks them complete.
+
+
+// the query the hits come from
+pub struct QueryRecord {
+    pub query_id: String,
+    pub query: String,
+    // command line options of the search
+    pub options: Vec<String>,
+    // in seconds since the epoch
+    pub matched_at: u64,
+}
+
+impl QueryRecord {
+
+    pub fn new(query: &str, options: Vec<String>) -> QueryRecord {
+        QueryRecord {
+            query_id: String::from("1"),
+            query: String::from(query),
+            options: options,
+            matched_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        }
+    }
+
+    fn to_json(&self) -> BTreeMap<String, Json> {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("query_id"), self.query_id.to_json());
+        obj.insert(String::from("query"), self.query.to_json());
+        obj.insert(String::from("options"), self.options.to_json());
+        obj.insert(String::from("matched_at"), self.matched_at.to_json());
+        obj
+    }
+}
+
+// what is known of a result, paths and hashes only with a map
+pub struct Hit<'a> {
+    pub id: u32,
+    pub path: Option<&'a str>,
+    pub sha256: Option<&'a str>,
+    pub size: Option<u64>,
+}
+
+impl<'a> Hit<'a> {
+
+    fn to_json(&self) -> BTreeMap<String, Json> {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("id"), self.id.to_json());
+        obj.insert(String::from("path"), self.path.map(String::from).to_json());
+        obj.insert(String::from("sha256"), self.sha256.map(String::from).to_json());
+        obj.insert(String::from("size"), self.size.to_json());
+        obj
+    }
+}
+
+pub trait ResultSink {
+    fn begin(&mut self, query: &QueryRecord) -> Result<()>;
+    fn hit(&mut self, hit: &Hit) -> Result<()>;
+    // the results are visible once this returned
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+// the format forced, or guessed from the extension
+pub fn open(path: &str, format: &str) -> Result<Box<dyn ResultSink>> {
+
+    let format = match format {
+        "" => match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("tsv") => "tsv",
+            Some("json") => "json",
+            Some("hits") => "table",
+            Some("sqlite") | Some("db") => "sqlite",
+            _ => "plain",
+        },
+        f => f,
+    };
+    match format {
+        "plain" => Ok(Box::new(PlainSink(try!(AtomicFile::create(path))))),
+        "tsv" => Ok(Box::new(TsvSink { out: try!(AtomicFile::create(path)), query_id: String::new(), matched_at: 0 })),
+        "json" => Ok(Box::new(JsonSink { out: try!(AtomicFile::create(path)), first: true })),
+        "table" => Ok(Box::new(try!(TableSink::open(path)))),
+        "sqlite" => Err(Error::new(ErrorKind::InvalidInput,
+            "this build has no SQLite binding, write a .hits table instead (--format=table)")),
+        f => Err(Error::new(ErrorKind::InvalidInput, format!("unknown output format: {}", f))),
+    }
+}
+
+// write the hits of a response, returns their number
+pub fn write_response(db: &BinacleManager, response: &SearchResponse, sink: &mut dyn ResultSink, query: &QueryRecord) -> Result<usize> {
+
+    try!(sink.begin(query));
+    for (i, id) in response.ids.iter().enumerate() {
+        let entry = db.entry(*id);
+        try!(sink.hit(&Hit {
+            id: *id,
+            path: response.entries.get(i).map(|p| p.as_str()),
+            sha256: entry.and_then(|e| e.sha256.as_ref()).map(|h| h.as_str()),
+            size: entry.and_then(|e| e.provenance.as_ref()).map(|p| p.size),
+        }));
+    }
+    Ok(response.ids.len())
+}
+
+// written beside the destination, renamed over it when complete
+struct AtomicFile {
+    out: BufWriter<File>,
+    tmp: String,
+    path: String,
+}
+
+impl AtomicFile {
+
+    fn create(path: &str) -> Result<AtomicFile> {
+        let tmp = format!("{}.tmp", path);
+        Ok(AtomicFile {
+            out: BufWriter::new(try!(File::create(&tmp))),
+            tmp: tmp,
+            path: String::from(path),
+        })
+    }
+
+    fn commit(mut self) -> Result<()> {
+        try!(self.out.flush());
+        try!(self.out.get_ref().sync_all());
+        fs::rename(&self.tmp, &self.path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.out.write(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.out.flush()
+    }
+}
+
+// tabs, line breaks and backslashes escaped, one record a line
+fn escape_tsv(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+// one path (or id) a line, the query in a comment
+struct PlainSink(AtomicFile);
+
+impl ResultSink for PlainSink {
+
+    fn begin(&mut self, query: &QueryRecord) -> Result<()> {
+        writeln!(self.0, "# query: {}", escape_tsv(&query.query))
+    }
+
+    fn hit(&mut self, hit: &Hit) -> Result<()> {
+        match hit.path {
+            Some(path) => writeln!(self.0, "{}", escape_tsv(path)),
+            None => writeln!(self.0, "{}", hit.id),
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.0.commit()
+    }
+}
+
+struct TsvSink {
+    out: AtomicFile,
+    query_id: String,
+    matched_at: u64,
+}
+
+impl ResultSink for TsvSink {
+
+    fn begin(&mut self, query: &QueryRecord) -> Result<()> {
+        self.query_id = query.query_id.clone();
+        self.matched_at = query.matched_at;
+        try!(writeln!(self.out, "# query: {}", escape_tsv(&query.query)));
+        try!(writeln!(self.out, "# options: {}", escape_tsv(&query.options.join(" "))));
+        writeln!(self.out, "query_id\tid\tpath\tsha256\tsize\tmatched_at")
+    }
+
+    fn hit(&mut self, hit: &Hit) -> Result<()> {
+        writeln!(self.out, "{}\t{}\t{}\t{}\t{}\t{}", self.query_id, hit.id,
+                 escape_tsv(hit.path.unwrap_or("")), hit.sha256.unwrap_or(""),
+                 hit.size.map(|s| s.to_string()).unwrap_or(String::new()), self.matched_at)
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.out.commit()
+    }
+}
+
+// {"query": {...}, "hits": [...]}, the hits are streamed
+struct JsonSink {
+    out: AtomicFile,
+    first: bool,
+}
+
+impl ResultSink for JsonSink {
+
+    fn begin(&mut self, query: &QueryRecord) -> Result<()> {
+        write!(self.out, "{{\"query\":{},\"hits\":[", Json::Object(query.to_json()))
+    }
+
+    fn hit(&mut self, hit: &Hit) -> Result<()> {
+        if !self.first {
+            try!(write!(self.out, ","));
+        }
+        self.first = false;
+        write!(self.out, "{}", Json::Object(hit.to_json()))
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        try!(writeln!(self.out, "]}}"));
+        self.out.commit()
+    }
+}
+
+// Table of the hits of several queries, a JSON row a line:
+//   {"table": "hits", "query_id": ..., "id": ..., "path": ..., "sha256": ..., "size": ..., "matched_at": ...}
+//   {"table": "queries", "query_id": ..., "query": ..., "options": [...], "matched_at": ...}
+// The hits of a query without its query row were left by an interrupted
+// write and are ignored.
+struct TableSink {
+    path: String,
+    // the rows of this query, appended to the table by finish()
+    rows: BufWriter<File>,
+    rows_path: String,
+    query: BTreeMap<String, Json>,
+    matched_at: u64,
+    query_id: String,
+}
+
+// complete queries of a table, in order
+pub fn table_queries(path: &str) -> Result<Vec<String>> {
+
+    let file = match OpenOptions::new().read(true).open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut queries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(Json::Object(row)) = Json::from_str(&try!(line)) {
+            if row.get("table").and_then(|t| t.as_string()) == Some("queries") {
+                if let Some(id) = row.get("query_id").and_then(|q| q.as_string()) {
+                    queries.push(String::from(id));
+                }
+            }
+        }
+    }
+    Ok(queries)
+}
+
+impl TableSink {
+
+    fn open(path: &str) -> Result<TableSink> {
+        let queries = try!(table_queries(path));
+        let rows_path = format!("{}.rows", path);
+        Ok(TableSink {
+            path: String::from(path),
+            rows: BufWriter::new(try!(File::create(&rows_path))),
+            rows_path: rows_path,
+            query: BTreeMap::new(),
+            matched_at: 0,
+            query_id: (queries.len() + 1).to_string(),
+        })
+    }
+}
+
+impl ResultSink for TableSink {
+
+    fn begin(&mut self, query: &QueryRecord) -> Result<()> {
+        self.query = query.to_json();
+        self.query.insert(String::from("query_id"), self.query_id.to_json());
+        self.query.insert(String::from("table"), "queries".to_json());
+        self.matched_at = query.matched_at;
+        Ok(())
+    }
+
+    fn hit(&mut self, hit: &Hit) -> Result<()> {
+        let mut row = hit.to_json();
+        row.insert(String::from("table"), "hits".to_json());
+        row.insert(String::from("query_id"), self.query_id.to_json());
+        row.insert(String::from("matched_at"), self.matched_at.to_json());
+        writeln!(self.rows, "{}", Json::Object(row))
+    }
+
+    // the rows are appended in one write, then the query row
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        let query = Json::Object(self.query.clone());
+        try!(writeln!(self.rows, "{}", query));
+        try!(self.rows.flush());
+
+        let mut table = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        try!(copy(&mut try!(File::open(&self.rows_path)), &mut table));
+        try!(table.sync_all());
+        fs::remove_file(&self.rows_path)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn hits<'a>(paths: &'a [String]) -> Vec<Hit<'a>> {
+        paths.iter().enumerate().map(|(i, p)| Hit {
+            id: i as u32 + 1,
+            path: Some(p.as_str()),
+            sha256: Some("00ff"),
+            size: Some(i as u64),
+        }).collect()
+    }
+
+    fn write_all(path: &str, format: &str, hits: &[Hit]) {
+        let mut sink = open(path, format).unwrap();
+        sink.begin(&QueryRecord::new("GetProcAddress", vec![String::from("--json")])).unwrap();
+        for hit in hits {
+            sink.hit(hit).unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn special_paths() {
+
+        let paths = vec![String::from("dir/with\ttab"), String::from("line\nbreak \"quoted\" \\ é")];
+        let hits = hits(&paths);
+
+        write_all("test_sink.tsv", "", &hits);
+        let tsv = fs::read_to_string("test_sink.tsv").unwrap();
+        let rows: Vec<&str> = tsv.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(3, rows.len());
+        assert!(rows[1].starts_with("1\t1\tdir/with\\ttab\t00ff\t0\t"));
+        assert!(rows[2].starts_with("1\t2\tline\\nbreak \"quoted\" \\\\ é\t00ff\t1\t"));
+
+        write_all("test_sink.json", "", &hits);
+        let json = Json::from_str(&fs::read_to_string("test_sink.json").unwrap()).unwrap();
+        assert_eq!(Some("GetProcAddress"), json.find_path(&["query", "query"]).and_then(|q| q.as_string()));
+        let found: Vec<&str> = json.find("hits").unwrap().as_array().unwrap().iter()
+            .map(|h| h.find("path").unwrap().as_string().unwrap()).collect();
+        assert_eq!(paths.iter().map(|p| p.as_str()).collect::<Vec<&str>>(), found);
+        assert!(fs::metadata("test_sink.json.tmp").is_err());
+
+        assert!(open("test_sink.sqlite", "").is_err());
+
+        let _ = fs::remove_file("test_sink.tsv");
+        let _ = fs::remove_file("test_sink.json");
+    }
+
+    #[test]
+    fn large_sets_and_table() {
+
+        let paths: Vec<String> = (0 .. 200000).map(|i| format!("/samples/{:02}/{}.bin", i % 100, i)).collect();
+        let hits = hits(&paths);
+
+        write_all("test_sink.txt", "", &hits);
+        assert_eq!(200001, fs::read_to_string("test_sink.txt").unwrap().lines().count());
+
+        let _ = fs::remove_file("test_sink.hits");
+        write_all("test_sink.hits", "", &hits[.. 1000]);
+        write_all("test_sink.hits", "table", &hits);
+        assert_eq!(vec!["1", "2"], table_queries("test_sink.hits").unwrap());
+        assert_eq!(201002, fs::read_to_string("test_sink.hits").unwrap().lines().count());
+        assert!(fs::metadata("test_sink.hits.rows").is_err());
+
+        let _ = fs::remove_file("test_sink.txt");
+        let _ = fs::remove_file("test_sink.hits");
+    }
+}
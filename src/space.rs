@@ -0,0 +1,299 @@
+extern crate fs2;
+
+use std::fs::metadata;
+use std::io::*;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use binacle_manager::BinacleManager;
+use chains;
+use params;
+
+// Disk space of the operations that grow a database. An ingestion, a bulk
+// build, a compaction or a reindexing that meets a full disk stops at an
+// arbitrary write, a shard half written. The space they need is projected
+// before they start, with the growth model of the planner (params.rs), and
+// they are refused when the filesystem has less available. While they run,
+// the available space is checked again at their checkpoints (between two
+// files, two sorted runs, two shards): under a floor they stop there, what
+// is done is kept. The manifest ingestions and the reindexings resume from
+// there when run again.
+//
+// The projections are conservative, --ignore-space-check skips both checks.
+
+
+// space left to the other writers of the filesystem
+pub const DEFAULT_FLOOR: u64 = 64 << 20;
+
+// map bytes of an entry, its path, hash, type and provenance
+pub const MAP_ENTRY_BYTES: u64 = 512;
+
+// space an operation needs, by part
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Need {
+    pub parts: Vec<(String, u64)>,
+}
+
+impl Need {
+
+    pub fn add(&mut self, what: &str, bytes: u64) {
+        if bytes > 0 {
+            self.parts.push((String::from(what), bytes));
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.parts.iter().map(|p| p.1).sum()
+    }
+
+    // "1200 bytes (index 1000, map 200)"
+    pub fn describe(&self) -> String {
+        let parts: Vec<String> = self.parts.iter().map(|&(ref what, bytes)| format!("{} {}", what, bytes)).collect();
+        format!("{} bytes ({})", self.total(), parts.join(", "))
+    }
+}
+
+pub fn available_space(dir: &Path) -> Result<u64> {
+    fs2::available_space(dir)
+}
+
+// the directory holding a file of the database
+fn dir_of(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// how the space is checked, the available space is given by the tests
+#[derive(Clone, Copy)]
+pub struct SpaceCheck {
+    pub floor: u64,
+    available: fn(&Path) -> Result<u64>,
+}
+
+impl SpaceCheck {
+
+    pub fn new() -> SpaceCheck {
+        SpaceCheck { floor: DEFAULT_FLOOR, available: available_space }
+    }
+
+    pub fn floor(mut self, floor: u64) -> SpaceCheck {
+        self.floor = floor;
+        self
+    }
+
+    pub fn with_available(mut self, available: fn(&Path) -> Result<u64>) -> SpaceCheck {
+        self.available = available;
+        self
+    }
+
+    // on the filesystem of a file of the database
+    pub fn available(&self, path: &str) -> Result<u64> {
+        let dir = dir_of(path);
+        (self.available)(&dir).map_err(|e| Error::new(e.kind(), format!("{}: free space unknown: {}", dir.display(), e)))
+    }
+
+    // before an operation, the floor kept
+    pub fn require(&self, path: &str, need: &Need) -> Result<()> {
+        let available = try!(self.available(path));
+        if need.total().saturating_add(self.floor) > available {
+            return Err(Error::new(ErrorKind::Other, format!(
+                "not enough disk space for {}: {} needed and {} kept free, {} available. \
+                 Free some space, or --ignore-space-check", path, need.describe(), self.floor, available)));
+        }
+        Ok(())
+    }
+
+    // between two steps of an operation
+    pub fn checkpoint(&self, path: &str) -> Result<()> {
+        let available = try!(self.available(path));
+        if available < self.floor {
+            return Err(Error::new(ErrorKind::Other, format!(
+                "not enough disk space for {}: {} bytes available, under the floor of {}, stopped before the next step",
+                path, available, self.floor)));
+        }
+        Ok(())
+    }
+}
+
+// number and bytes of the files of a directory
+pub fn dir_content(dir: &str) -> (u64, u64) {
+    let mut files = 0;
+    let mut bytes = 0;
+    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+        match metadata(entry.path()) {
+            Ok(ref m) if m.is_file() => {
+                files += 1;
+                bytes += m.len();
+            },
+            _ => {},
+        }
+    }
+    (files, bytes)
+}
+
+pub fn files_content<'a, I: Iterator<Item = &'a str>>(paths: I) -> (u64, u64) {
+    let mut files = 0;
+    let mut bytes = 0;
+    for path in paths {
+        files += 1;
+        bytes += metadata(path).map(|m| m.len()).unwrap_or(0);
+    }
+    (files, bytes)
+}
+
+// the map is written aside then renamed: its old and its new copy
+fn map_need(db: &BinacleManager, files: u64) -> u64 {
+    match db.is_map() {
+        true => metadata(db.map_path()).map(|m| m.len()).unwrap_or(0) + files * MAP_ENTRY_BYTES,
+        false => 0,
+    }
+}
+
+// insertion of files in a database, at the growth measured on its shards
+pub fn ingest_need(db: &mut BinacleManager, files: u64, bytes: u64) -> Need {
+    let growth = params::measured_growth(db);
+    let mut need = Need::default();
+    need.add("index", params::projected_index(bytes, growth, db.ngram_size(), db.alignment(), db.max_index_size()));
+    need.add("map", map_need(db, files));
+    need
+}
+
+// bulk build of a new database: the pairs of the sorted runs spilled over
+// the memory, 8 bytes for each byte in the worst case, then the index
+pub fn bulk_need(files: u64, bytes: u64, memory: u64, ngram_size: u8, alignment: u8, max_index_size: u64) -> Need {
+    let mut need = Need::default();
+    need.add("sorted runs", bytes.saturating_mul(8).saturating_sub(memory));
+    need.add("index", params::projected_index(bytes, None, ngram_size, alignment, max_index_size));
+    need.add("map", files * MAP_ENTRY_BYTES);
+    need
+}
+
+// the longest chains of each shard rewritten in new blocks
+pub fn compaction_need(db: &mut BinacleManager, top: usize) -> Result<Need> {
+    let block = 1u64 << db.alignment();
+    let mut blocks = 0u64;
+    for shard in db.open_shards() {
+        let shard = try!(shard);
+        blocks += chains::longest_chains(&shard, top).iter().map(|&(_, len)| len as u64).sum::<u64>();
+    }
+    let mut need = Need::default();
+    need.add("compacted lists", blocks * block);
+    Ok(need)
+}
+
+// the shards are rewritten one at a time, the largest one is copied
+pub fn rewrite_need(db: &mut BinacleManager) -> Result<Need> {
+    let mut largest = 0;
+    for shard in db.open_shards() {
+        largest = largest.max(try!(shard).file_size());
+    }
+    let mut need = Need::default();
+    need.add("largest shard", largest);
+    Ok(need)
+}
+
+// a copy of every shard, larger by the ratio of the blocks when the
+// alignment grows, and of the map
+pub fn reindex_need(db: &mut BinacleManager, alignment: u8) -> Result<Need> {
+    let growth = 1u64 << alignment.saturating_sub(db.alignment());
+    let mut shards = 0u64;
+    for shard in db.open_shards() {
+        shards += try!(shard).file_size();
+    }
+    let mut need = Need::default();
+    need.add("shards", shards * growth);
+    need.add("map", map_need(db, 0));
+    Ok(need)
+}
+
+// a copy of every shard of the other database, and the map grown by its own
+pub fn merge_need(db: &BinacleManager, other: &mut BinacleManager) -> Result<Need> {
+    let mut shards = 0u64;
+    for shard in other.open_shards() {
+        shards += try!(shard).file_size();
+    }
+    let mut need = Need::default();
+    need.add("shards", shards);
+    need.add("map", map_need(db, 0) + map_need(other, 0));
+    Ok(need)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::tests::cleanup;
+
+    fn full(_: &Path) -> Result<u64> {
+        Ok(1 << 20)
+    }
+
+    fn plenty(_: &Path) -> Result<u64> {
+        Ok(1 << 40)
+    }
+
+    #[test]
+    fn projected() {
+        let mut need = Need::default();
+        need.add("index", 1000);
+        need.add("map", 0);
+        need.add("map", 200);
+        assert_eq!("1200 bytes (index 1000, map 200)", need.describe());
+
+        let check = SpaceCheck::new().floor(100).with_available(full);
+        assert!(check.require("db/test.db", &need).is_ok());
+        need.add("runs", 1 << 20);
+        let err = check.require("db/test.db", &need).unwrap_err();
+        assert!(err.to_string().starts_with("not enough disk space for db/test.db"), "{}", err);
+        assert!(SpaceCheck::new().floor(2 << 20).with_available(full).checkpoint("test.db").is_err());
+        assert!(SpaceCheck::new().with_available(full).checkpoint("test.db").is_err());
+        assert!(SpaceCheck::new().with_available(plenty).checkpoint("test.db").is_ok());
+        assert_eq!(PathBuf::from("."), dir_of("test.db"));
+
+        // the growth measured once something is indexed
+        let mut db = BinacleManager::create("test_space.db", true, 1 << 30, 6, 16).unwrap();
+        let fresh = ingest_need(&mut db, 10, 1000);
+        assert_eq!(4000 + params::header_size(16, 6), fresh.parts[0].1);
+        db.insert_reader(&b"call GetProcAddress, call LoadLibraryA"[..], "a.bin", 1, true);
+        let growth = params::measured_growth(&mut db).unwrap();
+        assert!(growth > 0.0);
+        drop(db);
+        cleanup("test_space");
+    }
+
+    #[test]
+    fn refused_and_stopped() {
+
+        let _ = fs::remove_dir_all("test_space_dir");
+        fs::create_dir_all("test_space_dir").unwrap();
+        for i in 0 .. 4 {
+            File::create(format!("test_space_dir/{}.bin", i)).unwrap().write_all(b"GetProcAddress LoadLibraryA").unwrap();
+        }
+        {
+            let mut db = BinacleManager::create("test_space2.db", true, 1 << 30, 6, 16).unwrap();
+            let (files, bytes) = dir_content("test_space_dir");
+            assert_eq!((4, 4 * 27), (files, bytes));
+
+            // refused before anything is written
+            let need = ingest_need(&mut db, files, bytes);
+            assert!(SpaceCheck::new().with_available(full).require(&db.db_path, &need).is_err());
+            assert!(SpaceCheck::new().with_available(plenty).require(&db.db_path, &need).is_ok());
+
+            // stopped at the first checkpoint
+            db.set_space_check(Some(SpaceCheck::new().with_available(full)));
+            let err = db.insert_dir_recursive("test_space_dir").unwrap_err();
+            assert!(err.to_string().contains("under the floor"), "{}", err);
+            assert_eq!(0, db.nb_file());
+
+            db.set_space_check(Some(SpaceCheck::new().with_available(plenty)));
+            db.insert_dir_recursive("test_space_dir").unwrap();
+            assert_eq!(4, db.nb_file());
+        }
+        cleanup("test_space2");
+        let _ = fs::remove_dir_all("test_space_dir");
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::*;
+use rustc_serialize::json::{Json, Object};
+
+use binacle::BinacleFile;
+use binacle_manager::BinacleManager;
+use chains::{self, ChainStats};
+use collection::Collections;
+
+// Statistics of a database (--stats): the parameters of its meta and for
+// each shard its sizes, the lists allocated in its header and the length of
+// their chains. Printed as pairs and lines, or as a JSON object with --json
+// for the tools following the growth of the shards. The header is scanned
+// through the occupancy bitmap of the shard when it has one, the slots
+// skipped are reported.
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShardStats {
+    // size of the file on disk
+    pub file_size: u64,
+    // bytes used, the header and the blocks allocated
+    pub size: u64,
+    // header entries not null
+    pub lists: u64,
+    pub header_fill: f64,
+    // header slots the scans skipped, None without an occupancy bitmap
+    pub header_skipped: Option<u64>,
+    pub header_slots: u64,
+    pub finalized: bool,
+    pub chains: ChainStats,
+}
+
+impl ShardStats {
+
+    pub fn of(shard: &BinacleFile, finalized: bool, threshold: u32) -> ShardStats {
+        let lists = shard.nb_lists();
+        ShardStats {
+            file_size: shard.file_size(),
+            size: shard.get_size(),
+            lists: lists,
+            header_fill: lists as f64 / shard.header_slots() as f64,
+            header_skipped: shard.header_skipped(),
+            header_slots: shard.header_slots(),
+            finalized: finalized,
+            chains: chains::chain_stats(shard, threshold),
+        }
+    }
+}
+
+// the statistics of each shard, the error of the ones that could not be opened
+pub fn shard_stats(db: &mut BinacleManager, threshold: u32) -> Vec<Result<ShardStats>> {
+    let shards = db.open_shards();
+    shards.into_iter().enumerate().map(|(n, shard)| {
+        shard.map(|shard| ShardStats::of(&shard, db.shard_is_finalized(n), threshold))
+    }).collect()
+}
+
+fn shard_json(n: usize, stats: &Result<ShardStats>) -> Json {
+    let mut shard = Object::new();
+    shard.insert(String::from("shard"), Json::U64(n as u64));
+    match *stats {
+        Ok(ref stats) => {
+            let mut chains = Object::new();
+            chains.insert(String::from("mean"), Json::F64(stats.chains.mean));
+            chains.insert(String::from("p50"), Json::U64(stats.chains.p50 as u64));
+            chains.insert(String::from("p95"), Json::U64(stats.chains.p95 as u64));
+            chains.insert(String::from("max"), Json::U64(stats.chains.max as u64));
+            shard.insert(String::from("finalized"), Json::Boolean(stats.finalized));
+            shard.insert(String::from("file_size"), Json::U64(stats.file_size));
+            shard.insert(String::from("size"), Json::U64(stats.size));
+            shard.insert(String::from("lists"), Json::U64(stats.lists));
+            shard.insert(String::from("header_fill"), Json::F64(stats.header_fill));
+            shard.insert(String::from("header_skipped"), stats.header_skipped.map_or(Json::Null, Json::U64));
+            shard.insert(String::from("chain_length"), Json::Object(chains));
+        },
+        Err(ref e) => { shard.insert(String::from("error"), Json::String(e.to_string())); },
+    }
+    Json::Object(shard)
+}
+
+// the statistics as a JSON object, the fields of the pairs printed
+pub fn to_json(db: &BinacleManager, shards: &[Result<ShardStats>]) -> Json {
+
+    let mut root = Object::new();
+    root.insert(String::from("map"), Json::Boolean(db.is_map()));
+    root.insert(String::from("sealed"), Json::Boolean(db.is_sealed()));
+    root.insert(String::from("ngram_size"), Json::U64(db.ngram_size() as u64));
+    root.insert(String::from("alignment"), Json::U64(db.alignment() as u64));
+    root.insert(String::from("offset_size"), Json::U64(db.offset_size() as u64));
+    root.insert(String::from("max_index_size"), Json::U64(db.max_index_size() as u64));
+    root.insert(String::from("max_addressable_size"), Json::U64(db.max_addressable_size() as u64));
+    root.insert(String::from("header_size"), Json::U64(db.header_size()));
+    root.insert(String::from("min_reliable_pattern_len"), Json::U64(db.min_reliable_pattern_len() as u64));
+    root.insert(String::from("nb_file"), Json::U64(db.nb_file() as u64));
+    root.insert(String::from("last_id"), Json::U64(db.last_id() as u64));
+    root.insert(String::from("nb_index"), Json::U64(db.nb_index() as u64));
+    root.insert(String::from("durability"), Json::String(db.durability().to_string()));
+    root.insert(String::from("translation"), db.translation().map_or(Json::Null, |t| Json::String(t.describe())));
+    root.insert(String::from("quarantined"), Json::U64(db.quarantine().len() as u64));
+
+    let mut collections = Object::new();
+    if let Ok(sets) = Collections::open(&db.db_path) {
+        for (name, count) in sets.list() {
+            collections.insert(String::from(name), Json::U64(count as u64));
+        }
+    }
+    root.insert(String::from("collections"), Json::Object(collections));
+    root.insert(String::from("shards"), Json::Array(shards.iter().enumerate().map(|(n, s)| shard_json(n, s)).collect()));
+    Json::Object(root)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use binacle_manager::tests::cleanup;
+
+    #[test]
+    fn header_scanned() {
+        let mut db = BinacleManager::create("test_stats.db", true, 1 << 30, 6, 16).unwrap();
+        db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+        db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "b.bin", 2, true);
+
+        let shards = shard_stats(&mut db, chains::DEFAULT_RECHAIN_THRESHOLD);
+        assert_eq!(1, shards.len());
+        let stats = shards[0].as_ref().unwrap().clone();
+        // the header scan counts the lists the chains walk
+        assert!(stats.lists > 0);
+        assert_eq!(stats.chains.lists, stats.lists);
+        assert_eq!(stats.lists as f64 / 65536.0, stats.header_fill);
+        assert!(stats.size <= stats.file_size && stats.size > 65536);
+        assert!(!stats.finalized);
+        // the bitmap of the new shard, the empty slots skipped
+        assert_eq!(Some(65536 - stats.lists), stats.header_skipped);
+
+        let json = to_json(&db, &shards);
+        assert_eq!(Some(&Json::U64(16)), json.find("ngram_size"));
+        assert_eq!(Some(&Json::U64(2)), json.find("nb_file"));
+        assert_eq!(Some(&Json::U64(2)), json.find("last_id"));
+        let shard = &json.find("shards").unwrap().as_array().unwrap()[0];
+        assert_eq!(Some(&Json::U64(stats.lists)), shard.find("lists"));
+        assert_eq!(Some(&Json::U64(stats.file_size)), shard.find("file_size"));
+        assert!(shard.find_path(&["chain_length", "mean"]).unwrap().is_f64());
+        assert_eq!(Some(&Json::U64(65536 - stats.lists)), shard.find("header_skipped"));
+        drop(db);
+        cleanup("test_stats");
+    }
+}
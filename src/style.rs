@@ -0,0 +1,287 @@
+use std::env;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Human output on a terminal: aligned columns, paths cut to its width,
+// the matches highlighted, the warnings and the errors colored. Piped or
+// redirected, the output is the plain one of the scripts, byte for byte:
+// with Style::plain() every helper gives back what was printed before.
+//
+// --color=always|never|auto overrides the detection of the terminal for
+// the colors, NO_COLOR is respected in auto.
+
+
+const RED: &'static str = "\x1b[31m";
+const YELLOW: &'static str = "\x1b[33m";
+const HIGHLIGHT: &'static str = "\x1b[1;7m";
+const RESET: &'static str = "\x1b[0m";
+
+// narrowest room left to a cut column
+const MIN_CUT: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+
+    pub fn parse(s: &str) -> Result<ColorChoice, String> {
+        match s {
+            "" | "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            other => Err(format!("invalid --color value: {} (always, never or auto)", other)),
+        }
+    }
+
+    fn colors(&self, tty: bool) -> bool {
+        match *self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => tty && env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Style {
+    pub color: bool,
+    // aligned columns instead of the tab separated ones
+    pub aligned: bool,
+    // columns of the terminal the paths are cut to, none with --no-truncate
+    pub width: Option<usize>,
+}
+
+impl Style {
+
+    pub fn plain() -> Style {
+        Style { color: false, aligned: false, width: None }
+    }
+
+    pub fn new(tty: bool, choice: ColorChoice, truncate: bool, columns: usize) -> Style {
+        Style {
+            color: choice.colors(tty),
+            aligned: tty,
+            width: if tty && truncate { Some(columns) } else { None },
+        }
+    }
+
+    // of the standard output, $COLUMNS wide or 80
+    pub fn stdout(choice: ColorChoice, truncate: bool) -> Style {
+        let columns = env::var("COLUMNS").ok().and_then(|c| c.parse::<usize>().ok()).unwrap_or(80);
+        Style::new(io::stdout().is_terminal(), choice, truncate, columns)
+    }
+
+    fn paint(&self, code: &str, s: &str) -> String {
+        match self.color {
+            true => format!("{}{}{}", code, s, RESET),
+            false => String::from(s),
+        }
+    }
+
+    pub fn highlight(&self, s: &str) -> String {
+        self.paint(HIGHLIGHT, s)
+    }
+
+    pub fn warning(&self, s: &str) -> String {
+        self.paint(YELLOW, s)
+    }
+
+    pub fn error(&self, s: &str) -> String {
+        self.paint(RED, s)
+    }
+
+    // rows of cells, separated by tabs or aligned, the cells of the column
+    // cut (the paths) shortened to the width of the terminal
+    pub fn table(&self, rows: &[Vec<String>], cut: usize) -> Vec<String> {
+
+        if !self.aligned {
+            return rows.iter().map(|r| r.join("\t")).collect();
+        }
+
+        let mut rows = rows.to_vec();
+        if let Some(width) = self.width {
+            let widths = column_widths(&rows);
+            let others: usize = widths.iter().enumerate().filter(|&(c, _)| c != cut).map(|(_, w)| w + 2).sum();
+            let room = width.saturating_sub(others).max(MIN_CUT);
+            for row in &mut rows {
+                if let Some(cell) = row.get_mut(cut) {
+                    *cell = cut_middle(cell, room);
+                }
+            }
+        }
+
+        let widths = column_widths(&rows);
+        rows.iter().map(|row| {
+            let mut line = String::new();
+            for (c, cell) in row.iter().enumerate() {
+                line.push_str(cell);
+                if c + 1 < row.len() {
+                    line.push_str(&" ".repeat(widths[c] - cell.chars().count() + 2));
+                }
+            }
+            line
+        }).collect()
+    }
+
+    // a row of a table printed as soon as it is known, the widths of the
+    // columns given instead of measured on all the rows
+    pub fn row(&self, row: &[String], widths: &[usize], cut: usize) -> String {
+
+        if !self.aligned {
+            return row.join("\t");
+        }
+
+        let room = self.width.map(|width| {
+            let others: usize = widths.iter().enumerate().filter(|&(c, _)| c != cut).map(|(_, w)| w + 2).sum();
+            width.saturating_sub(others).max(MIN_CUT)
+        });
+        let mut line = String::new();
+        for (c, cell) in row.iter().enumerate() {
+            let (cell, width) = match room {
+                Some(room) if c == cut => (cut_middle(cell, room), widths[c].min(room)),
+                _ => (cell.clone(), widths[c]),
+            };
+            line.push_str(&cell);
+            if c + 1 < row.len() {
+                line.push_str(&" ".repeat(width.saturating_sub(cell.chars().count()) + 2));
+            }
+        }
+        line
+    }
+
+    // "name: value" lines, the values aligned
+    pub fn pairs(&self, pairs: &[(&str, String)]) -> Vec<String> {
+        let width = match self.aligned {
+            true => pairs.iter().map(|p| p.0.chars().count()).max().unwrap_or(0),
+            false => 0,
+        };
+        pairs.iter().map(|&(name, ref value)| {
+            let pad = " ".repeat(width - name.chars().count().min(width));
+            format!("{}: {}{}", name, pad, value)
+        }).collect()
+    }
+}
+
+fn column_widths(rows: &[Vec<String>]) -> Vec<usize> {
+    let mut widths = Vec::new();
+    for row in rows {
+        for (c, cell) in row.iter().enumerate() {
+            if c == widths.len() {
+                widths.push(0);
+            }
+            widths[c] = widths[c].max(cell.chars().count());
+        }
+    }
+    widths
+}
+
+// the start and the end of s kept, an ellipsis between them
+pub fn cut_middle(s: &str, width: usize) -> String {
+    let chars: Vec<char> = s.chars().collect();
+    if chars.len() <= width || width == 0 {
+        return String::from(s);
+    }
+    let kept = width - 1;
+    let head = (kept + 1) / 2;
+    let tail = kept - head;
+    let mut cut: String = chars[.. head].iter().collect();
+    cut.push('…');
+    cut.extend(&chars[chars.len() - tail ..]);
+    cut
+}
+
+
+// the messages of stderr, colored when it is a terminal
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+
+pub fn init(choice: ColorChoice) {
+    STDERR_COLOR.store(choice.colors(io::stderr().is_terminal()), Ordering::Relaxed);
+}
+
+fn stderr() -> Style {
+    Style { color: STDERR_COLOR.load(Ordering::Relaxed), ..Style::plain() }
+}
+
+// "Error: message" in red
+pub fn error<T: Display>(message: T) {
+    eprintln!("{}", stderr().error(&format!("Error: {}", message)));
+}
+
+// "warning: message" in yellow
+pub fn warning<T: Display>(message: T) {
+    eprintln!("{}", stderr().warning(&format!("warning: {}", message)));
+}
+
+// a line of its own in yellow, as the recommendations of --stats
+pub fn warning_line<T: Display>(line: T) {
+    eprintln!("{}", stderr().warning(&line.to_string()));
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn rows() -> Vec<Vec<String>> {
+        vec![
+            vec![String::from("1"), String::from("1024"), String::from("/samples/a.exe")],
+            vec![String::from("12"), String::from("-"), String::from("/samples/very/deep/directory/of/the/corpus/b.dll")],
+        ]
+    }
+
+    #[test]
+    fn piped_is_plain() {
+        let style = Style::new(false, ColorChoice::Auto, true, 40);
+        assert_eq!(Style::plain(), style);
+        assert_eq!(vec!["1\t1024\t/samples/a.exe", "12\t-\t/samples/very/deep/directory/of/the/corpus/b.dll"],
+                   style.table(&rows(), 2));
+        assert_eq!(vec!["map: yes", "ngram_size: 16"],
+                   style.pairs(&[("map", String::from("yes")), ("ngram_size", String::from("16"))]));
+        assert_eq!("FAILED", style.error("FAILED"));
+        assert_eq!("XX", style.highlight("XX"));
+        // forced
+        assert!(Style::new(false, ColorChoice::Always, true, 40).color);
+    }
+
+    #[test]
+    fn terminal_rendering() {
+        let style = Style::new(true, ColorChoice::Never, true, 40);
+        assert_eq!(vec!["1   1024  /samples/a.exe",
+                        "12  -     /samples/very/d…e/corpus/b.dll"],
+                   style.table(&rows(), 2));
+        assert_eq!(40, style.table(&rows(), 2)[1].chars().count());
+        assert_eq!(vec!["map:        yes", "ngram_size: 16"],
+                   style.pairs(&[("map", String::from("yes")), ("ngram_size", String::from("16"))]));
+
+        let untruncated = Style::new(true, ColorChoice::Never, false, 40);
+        assert_eq!("12  -     /samples/very/deep/directory/of/the/corpus/b.dll", untruncated.table(&rows(), 2)[1]);
+
+        // streamed, the same lines for the same widths
+        for style in &[style, untruncated, Style::plain()] {
+            let streamed: Vec<String> = rows().iter().map(|row| style.row(row, &[2, 4, 48], 2)).collect();
+            assert_eq!(style.table(&rows(), 2), streamed);
+        }
+
+        let colored = Style::new(true, ColorChoice::Always, true, 40);
+        assert_eq!("\x1b[31mFAILED\x1b[0m", colored.error("FAILED"));
+        assert_eq!("\x1b[33mwarning\x1b[0m", colored.warning("warning"));
+    }
+
+    #[test]
+    fn cut_in_the_middle() {
+        assert_eq!("abcdef", cut_middle("abcdef", 6));
+        assert_eq!("ab…fg", cut_middle("abcdefg", 5));
+        assert_eq!("ab…ef", cut_middle("abcdef", 5));
+        assert_eq!("…", cut_middle("abcdef", 1));
+        assert_eq!("é…é", cut_middle("ééééé", 3));
+        assert!(ColorChoice::parse("sometimes").is_err());
+        assert_eq!(Ok(ColorChoice::Always), ColorChoice::parse("always"));
+    }
+}
@@ -0,0 +1,264 @@
+use std::fs::OpenOptions;
+use std::io::*;
+use rustc_serialize::hex::FromHex;
+
+// Byte translation applied to the contents before their n-grams are formed,
+// so that trivially reformatted documents (CRLF or LF, runs of spaces, case)
+// still match. It is chosen when the database is created and kept in its
+// meta, the patterns of the searches go through the same translation: a
+// translated database can not be searched with raw patterns.
+//
+// Each byte is dropped, or mapped to another one, and the runs of a
+// squeezed byte (after the mapping) collapse to one. The provenance offsets
+// of a translated file are offsets of the translated content.
+
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Translation {
+    // byte -> byte, 256 entries
+    pub table: Vec<u8>,
+    // bytes removed
+    pub drop: Vec<u8>,
+    // bytes whose runs collapse to one
+    pub squeeze: Vec<u8>,
+}
+
+impl Translation {
+
+    pub fn identity() -> Translation {
+        Translation {
+            table: (0 ..= 255u8).collect(),
+            drop: Vec::new(),
+            squeeze: Vec::new(),
+        }
+    }
+
+    // a raw table of 256 bytes, or one rule a line, '#' starts a comment:
+    //   <hex> <hex>     map a byte
+    //   <hex> -         drop it
+    //   <hex> squeeze   collapse its runs
+    pub fn load(path: &str) -> Result<Translation> {
+
+        let mut content = Vec::new();
+        try!(try!(OpenOptions::new().read(true).open(path)).read_to_end(&mut content));
+        if content.len() == 256 {
+            let mut translation = Translation::identity();
+            translation.table = content;
+            return Ok(translation);
+        }
+        let content = try!(String::from_utf8(content).map_err(|_| {
+            Error::new(ErrorKind::InvalidData, format!("{}: neither a 256-byte table nor a rules file", path))
+        }));
+
+        let mut translation = Translation::identity();
+        for (nb, line) in content.lines().enumerate() {
+
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("{}:{}: invalid translation rule", path, nb + 1));
+            let byte = |hex: &str| match hex.from_hex() {
+                Ok(ref b) if b.len() == 1 => Ok(b[0]),
+                _ => Err(invalid()),
+            };
+
+            if fields.len() != 2 {
+                return Err(invalid());
+            }
+            let from = try!(byte(fields[0]));
+            match fields[1] {
+                "-" => translation.drop.push(from),
+                "squeeze" => translation.squeeze.push(from),
+                to => translation.table[from as usize] = try!(byte(to)),
+            }
+        }
+        translation.drop.sort();
+        translation.drop.dedup();
+        translation.squeeze.sort();
+        translation.squeeze.dedup();
+        Ok(translation)
+    }
+
+    // the translation of a whole content
+    pub fn apply(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        TranslateReader::new(bytes, Some(self)).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    // a short description for the statistics
+    pub fn describe(&self) -> String {
+        let mapped = self.table.iter().enumerate().filter(|&(b, t)| b != *t as usize).count();
+        format!("{} byte(s) mapped, {} dropped, {} squeezed", mapped, self.drop.len(), self.squeeze.len())
+    }
+}
+
+// translates what is read, a read returns at least 4 bytes unless the end
+// is reached: the insertion takes a shorter read for the end of the file
+pub struct TranslateReader<R> {
+    inner: R,
+    // None when there is no translation
+    table: Option<[u8; 256]>,
+    dropped: [bool; 256],
+    squeezed: [bool; 256],
+    // last byte given, for the squeezed runs across two reads
+    last: Option<u8>,
+}
+
+impl<R: Read> TranslateReader<R> {
+
+    pub fn new(inner: R, translation: Option<&Translation>) -> TranslateReader<R> {
+        let mut reader = TranslateReader {
+            inner: inner,
+            table: None,
+            dropped: [false; 256],
+            squeezed: [false; 256],
+            last: None,
+        };
+        if let Some(t) = translation {
+            let mut table = [0u8; 256];
+            table.copy_from_slice(&t.table);
+            reader.table = Some(table);
+            for b in &t.drop {
+                reader.dropped[*b as usize] = true;
+            }
+            for b in &t.squeeze {
+                reader.squeezed[*b as usize] = true;
+            }
+        }
+        reader
+    }
+}
+
+impl<R: Read> Read for TranslateReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+
+        let table = match self.table {
+            Some(ref t) => t,
+            None => return self.inner.read(buf),
+        };
+
+        let mut out = 0;
+        while out < buf.len().min(4) {
+            let len = match self.inner.read(&mut buf[out ..]) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            // in place, the bytes given are never after the ones read
+            for i in out .. out + len {
+                let b = buf[i];
+                if self.dropped[b as usize] {
+                    continue;
+                }
+                let t = table[b as usize];
+                if self.squeezed[t as usize] && self.last == Some(t) {
+                    continue;
+                }
+                buf[out] = t;
+                out += 1;
+                self.last = Some(t);
+            }
+        }
+        Ok(out)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::BinacleManager;
+    use binacle_manager::tests::cleanup;
+    use search::SearchRequest;
+
+    // returns the content a few bytes at a time
+    struct Chunked<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Chunked<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let len = self.1.min(self.0.len()).min(buf.len());
+            buf[.. len].copy_from_slice(&self.0[.. len]);
+            self.0 = &self.0[len ..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn rules() {
+        File::create("test_translate.tr").unwrap()
+            .write_all(b"# CRLF to LF\n0d -\n20 squeeze\n41 61 # A to a\n").unwrap();
+        let t = Translation::load("test_translate.tr").unwrap();
+        assert_eq!(vec![0x0d], t.drop);
+        assert_eq!(b"a b\n\nc".to_vec(), t.apply(b"A  b\r\n\r\nc"));
+        assert_eq!("1 byte(s) mapped, 1 dropped, 1 squeezed", t.describe());
+
+        // runs across reads, and reads of at least 4 bytes
+        let mut out = Vec::new();
+        let mut lens = Vec::new();
+        let mut reader = TranslateReader::new(Chunked(b"\r\r\r\rx \r \r y\r\rzzz  \r\r", 2), Some(&t));
+        let mut buf = [0u8; 5];
+        loop {
+            let len = reader.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            lens.push(len);
+            out.extend_from_slice(&buf[.. len]);
+        }
+        assert_eq!(b"x yzzz ".to_vec(), out);
+        assert!(lens[.. lens.len() - 1].iter().all(|len| *len >= 4), "{:?}", lens);
+
+        // a raw table
+        let mut raw: Vec<u8> = (0 ..= 255u8).collect();
+        raw[b'B' as usize] = b'b';
+        File::create("test_translate.tr").unwrap().write_all(&raw).unwrap();
+        assert_eq!(b"abc".to_vec(), Translation::load("test_translate.tr").unwrap().apply(b"aBc"));
+
+        File::create("test_translate.tr").unwrap().write_all(b"0d 0a 0b\n").unwrap();
+        assert!(Translation::load("test_translate.tr").is_err());
+        let _ = fs::remove_file("test_translate.tr");
+    }
+
+    #[test]
+    fn crlf_found_with_lf() {
+
+        let mut t = Translation::identity();
+        t.drop.push(b'\r');
+        File::create("test_translate.txt").unwrap().write_all(b"first line\r\nsecond line\r\n").unwrap();
+        {
+            let mut db = BinacleManager::create("test_translate.db", true, 1 << 30, 6, 16).unwrap();
+            db.set_translation(Some(t.clone())).unwrap();
+            assert_eq!(1, db.insert_file("test_translate.txt", 1, true).unwrap());
+            // chosen once for all
+            assert!(db.set_translation(None).is_err());
+        }
+
+        let mut db = BinacleManager::open("test_translate.db").unwrap();
+        assert_eq!(Some(&t), db.translation());
+        assert!(db.search(b"line\nsecond").unwrap().contains(&1));
+        // the raw pattern is translated too
+        assert!(db.search(b"line\r\nsecond").unwrap().contains(&1));
+        let response = db.run(&SearchRequest::pattern(b"line\nsecond").exact()).unwrap();
+        assert_eq!(vec![1], response.ids);
+        assert!(db.run(&SearchRequest::pattern(b"line\nthird").exact()).unwrap().ids.is_empty());
+
+        // the same file in a database without translation
+        let mut raw = BinacleManager::create("test_translate_raw.db", true, 1 << 30, 6, 16).unwrap();
+        raw.insert_file("test_translate.txt", 1, true).unwrap();
+        assert!(raw.search(b"line\nsecond").unwrap().is_empty());
+
+        drop(raw);
+        drop(db);
+        cleanup("test_translate");
+        cleanup("test_translate_raw");
+        let _ = fs::remove_file("test_translate.txt");
+    }
+}
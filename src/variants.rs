@@ -0,0 +1,406 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::*;
+use rustc_serialize::hex::FromHex;
+use twoway;
+
+// Patterns whose bytes at some offsets vary from a build to another, the
+// relocations and the immediates of a code pattern for instance. Each
+// offset takes one of its alternatives, the variants of the pattern are
+// the cartesian product of them and the search finds any of them.
+//
+// Over the cap, the offsets with the most alternatives are demoted to
+// wildcards until the product fits: any byte matches there, the index only
+// looks up the runs between the wildcards, 4 bytes long at least.
+//
+// A hex pattern gives its own wildcards as ?? (a signature like
+// 68 ?? ?? ?? 00 ff d0), the runs shorter than 4 bytes are not looked up
+// either.
+//
+// The other forms of a pattern are made here too: its UTF-16LE form (--wide,
+// --both) and its byte-reversed form (--endian=both), of the whole pattern
+// or of each 4-byte word. They compose: a pattern is reversed, then widened.
+
+
+// number of variants a pattern expands to by default
+pub const DEFAULT_VARIANT_CAP: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expansion {
+    // the variants, the bytes at the wildcards are the ones of the pattern
+    pub variants: Vec<Vec<u8>>,
+    // alternatives taken by each variant, "<offset>=<hex>" separated by ','
+    pub labels: Vec<String>,
+    // offsets where any byte matches, sorted
+    pub wildcards: Vec<usize>,
+    // why some offsets were demoted
+    pub warning: Option<String>,
+}
+
+// <offset>=<hex>,<hex>... as given to --variant
+pub fn parse_alternative(spec: &str) -> Result<(usize, Vec<u8>)> {
+
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid variant {}, <offset>=<hex>,<hex>... expected", spec));
+
+    let mut parts = spec.splitn(2, '=');
+    let offset = try!(parts.next().unwrap().trim().parse::<usize>().map_err(|_| invalid()));
+    let mut values = Vec::new();
+    for value in try!(parts.next().ok_or_else(invalid)).split(',') {
+        match value.trim().trim_start_matches("0x").from_hex() {
+            Ok(ref b) if b.len() == 1 => values.push(b[0]),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok((offset, values))
+}
+
+// a hex pattern with ?? at the bytes that can be any, blanks ignored:
+// the bytes (0 at the wildcards) and the offsets of the wildcards
+pub fn parse_masked(hex: &str) -> Result<(Vec<u8>, Vec<usize>)> {
+
+    let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid hex pattern {}: odd number of digits", hex)));
+    }
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    let mut wildcards = Vec::new();
+    for pair in digits.chunks(2) {
+        if pair == ['?', '?'] {
+            wildcards.push(bytes.len());
+            bytes.push(0);
+            continue;
+        }
+        match (pair[0].to_digit(16), pair[1].to_digit(16)) {
+            (Some(h), Some(l)) => bytes.push((h << 4 | l) as u8),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                     "invalid hex pattern {}: {}{} at byte {}, two hex digits or ?? expected", hex, pair[0], pair[1], bytes.len()))),
+        }
+    }
+    Ok((bytes, wildcards))
+}
+
+// the runs between the wildcards long enough to be looked up in the index
+pub fn pieces<'a>(pattern: &'a [u8], wildcards: &[usize]) -> Vec<&'a [u8]> {
+
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for &w in wildcards.iter().chain(Some(&pattern.len())) {
+        if w >= start + 4 {
+            pieces.push(&pattern[start .. w]);
+        }
+        start = w + 1;
+    }
+    pieces
+}
+
+// the pattern is in the content, any byte matching at the wildcards
+pub fn find_masked(content: &[u8], pattern: &[u8], wildcards: &[usize]) -> bool {
+
+    if wildcards.is_empty() {
+        return twoway::find_bytes(content, pattern) != None;
+    }
+
+    // the longest run is searched, the other bytes are compared around it
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for &w in wildcards.iter().chain(Some(&pattern.len())) {
+        if w > start {
+            runs.push((start, w));
+        }
+        start = w + 1;
+    }
+    let (run_start, run_end) = match runs.iter().max_by_key(|&&(s, e)| e - s) {
+        Some(&run) => run,
+        // only wildcards
+        None => return content.len() >= pattern.len(),
+    };
+
+    let mut from = 0;
+    while let Some(found) = twoway::find_bytes(&content[from ..], &pattern[run_start .. run_end]) {
+        let at = from + found;
+        from = at + 1;
+        if at < run_start || at - run_start + pattern.len() > content.len() {
+            continue;
+        }
+        let window = &content[at - run_start .. at - run_start + pattern.len()];
+        if runs.iter().all(|&(s, e)| window[s .. e] == pattern[s .. e]) {
+            return true;
+        }
+    }
+    false
+}
+
+// how a pattern is byte-reversed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteSwap {
+    // the whole pattern
+    Full,
+    // each 4-byte word, the pattern length must be a multiple of 4
+    Words,
+}
+
+impl ByteSwap {
+
+    // the unit of --endian-unit
+    pub fn parse(unit: &str) -> Result<ByteSwap> {
+        match unit {
+            "pattern" => Ok(ByteSwap::Full),
+            "4" => Ok(ByteSwap::Words),
+            other => Err(Error::new(ErrorKind::InvalidInput, format!("invalid --endian-unit value: {}, pattern or 4 expected", other))),
+        }
+    }
+}
+
+pub fn reverse_bytes(pattern: &[u8], swap: ByteSwap) -> Result<Vec<u8>> {
+    match swap {
+        ByteSwap::Full => Ok(pattern.iter().rev().cloned().collect()),
+        ByteSwap::Words => {
+            if pattern.len() % 4 != 0 {
+                return Err(Error::new(ErrorKind::InvalidInput, "pattern length must be a multiple of 4 to reverse its words"));
+            }
+            Ok(pattern.chunks(4).flat_map(|w| w.iter().rev().cloned()).collect())
+        },
+    }
+}
+
+// UTF-16LE encoding of a pattern, bytes that are not valid UTF-8 are widened as is
+pub fn to_wide(pattern: &[u8]) -> Vec<u8> {
+
+    let mut wide = Vec::with_capacity(pattern.len() * 2);
+    match ::std::str::from_utf8(pattern) {
+        Ok(s) => {
+            for c in s.encode_utf16() {
+                wide.push((c & 0xFF) as u8);
+                wide.push((c >> 8) as u8);
+            }
+        },
+        Err(_) => {
+            for b in pattern {
+                wide.push(*b);
+                wide.push(0);
+            }
+        },
+    }
+    wide
+}
+
+// forms of a pattern looked for in the index, one of them must be found:
+// the pattern (wide with wide), its wide form with both_widths, then its
+// byte-reversed form in the width of the first one
+pub fn forms(pattern: &[u8], wide: bool, both_widths: bool, swap: Option<ByteSwap>) -> Result<Vec<Vec<u8>>> {
+    let encode = |p: &[u8]| match wide {
+        true => to_wide(p),
+        false => Vec::from(p),
+    };
+    let mut forms = vec![encode(pattern)];
+    if both_widths {
+        forms.push(to_wide(pattern));
+    }
+    if let Some(swap) = swap {
+        forms.push(encode(&try!(reverse_bytes(pattern, swap))));
+    }
+    Ok(forms)
+}
+
+// the variants of a pattern, at most cap of them
+pub fn expand(pattern: &[u8], alternatives: &BTreeMap<usize, Vec<u8>>, cap: usize) -> Expansion {
+
+    let product = |kept: &[(usize, &Vec<u8>)]| kept.iter().fold(1usize, |p, k| p.saturating_mul(k.1.len()));
+
+    let mut kept: Vec<(usize, &Vec<u8>)> = alternatives.iter().map(|(o, v)| (*o, v)).collect();
+    let total = product(&kept);
+
+    // the offsets with the most alternatives first, the last one on a tie
+    let mut wildcards = Vec::new();
+    while product(&kept) > cap.max(1) {
+        let i = (0 .. kept.len()).max_by_key(|&i| (kept[i].1.len(), kept[i].0)).unwrap();
+        wildcards.push(kept.remove(i).0);
+    }
+    wildcards.sort();
+
+    let warning = match wildcards.is_empty() {
+        true => None,
+        false => {
+            let offsets: Vec<String> = wildcards.iter().map(|w| w.to_string()).collect();
+            Some(format!("{} variant(s) over the cap of {}: offset(s) {} demoted to wildcards",
+                         total, cap, offsets.join(",")))
+        },
+    };
+
+    // the first offset varies the slowest
+    let mut product = vec![(Vec::from(pattern), Vec::new())];
+    for &(offset, values) in &kept {
+        let mut next = Vec::with_capacity(product.len() * values.len());
+        for &(ref bytes, ref label) in &product {
+            for value in values {
+                let mut bytes = bytes.clone();
+                bytes[offset] = *value;
+                let mut label: Vec<String> = label.clone();
+                label.push(format!("{}={:02x}", offset, value));
+                next.push((bytes, label));
+            }
+        }
+        product = next;
+    }
+
+    // the variants differing out of the pieces looked up give the same
+    // lists, they are kept once with all their labels
+    let mut variants = Vec::new();
+    let mut labels: Vec<String> = Vec::new();
+    let mut seen: HashMap<Vec<Vec<u8>>, usize> = HashMap::new();
+    for (bytes, label) in product {
+        let key: Vec<Vec<u8>> = pieces(&bytes, &wildcards).into_iter().map(Vec::from).collect();
+        let label = label.join(",");
+        match seen.get(&key) {
+            Some(&i) => {
+                labels[i].push_str(" | ");
+                labels[i].push_str(&label);
+            },
+            None => {
+                seen.insert(key, variants.len());
+                variants.push(bytes);
+                labels.push(label);
+            },
+        }
+    }
+
+    Expansion {
+        variants: variants,
+        labels: labels,
+        wildcards: wildcards,
+        warning: warning,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn alternatives(list: &[(usize, &[u8])]) -> BTreeMap<usize, Vec<u8>> {
+        list.iter().map(|&(o, v)| (o, Vec::from(v))).collect()
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!((8, vec![0x00, 0x10, 0x40]), parse_alternative("8=00,10,0x40").unwrap());
+        for bad in &["8", "x=00", "8=", "8=100", "8=zz"] {
+            assert!(parse_alternative(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn masked_hex() {
+        assert_eq!((vec![0x68, 0, 0, 0, 0x00, 0xff, 0xd0], vec![1, 2, 3]), parse_masked("68 ?? ?? ?? 00 ff d0").unwrap());
+        assert_eq!((vec![0x68, 0, 0xFF], vec![1]), parse_masked("68??FF").unwrap());
+        assert_eq!((vec![0x41, 0x42], vec![]), parse_masked("4142").unwrap());
+        for bad in &["6", "68 ?", "68 ?0", "6g", "68 ?? 4"] {
+            assert!(parse_masked(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn cartesian_product() {
+        let pattern = b"\x6a\x40\x68\x00\x30\x00\x00\x68\x00\x10\x00\x00";
+        let e = expand(pattern, &alternatives(&[(4, b"\x30\x40"), (9, b"\x10\x20\x30")]), 64);
+        assert_eq!(6, e.variants.len());
+        assert!(e.wildcards.is_empty());
+        assert_eq!(None, e.warning);
+        assert_eq!("4=30,9=10", e.labels[0]);
+        assert_eq!("4=40,9=30", e.labels[5]);
+        assert_eq!(0x40, e.variants[5][4]);
+        assert_eq!(0x30, e.variants[5][9]);
+        // the other bytes are untouched
+        assert!(e.variants.iter().all(|v| v[.. 4] == pattern[.. 4] && v[10 ..] == pattern[10 ..]));
+
+        // nothing to expand
+        let e = expand(pattern, &BTreeMap::new(), 64);
+        assert_eq!(vec![pattern.to_vec()], e.variants);
+        assert_eq!(vec![String::new()], e.labels);
+    }
+
+    #[test]
+    fn cap_demotes_to_wildcards() {
+        let pattern: Vec<u8> = (0 .. 24).collect();
+        let four: &[u8] = &[1, 2, 3, 4];
+        let eight: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+        // 4 * 8 * 4 = 128 over 64, the offset with 8 alternatives goes
+        let e = expand(&pattern, &alternatives(&[(3, four), (12, eight), (20, four)]), 64);
+        assert_eq!(vec![12], e.wildcards);
+        assert_eq!(16, e.variants.len());
+        assert_eq!(Some(String::from("128 variant(s) over the cap of 64: offset(s) 12 demoted to wildcards")), e.warning);
+        // the byte of the pattern is kept at the wildcard
+        assert!(e.variants.iter().all(|v| v[12] == 12));
+
+        // the last offset goes on a tie
+        let e = expand(&pattern, &alternatives(&[(3, four), (20, four)]), 4);
+        assert_eq!(vec![20], e.wildcards);
+        assert_eq!(4, e.variants.len());
+
+        // a cap of 1 keeps the pattern alone
+        let e = expand(&pattern, &alternatives(&[(3, four), (20, four)]), 1);
+        assert_eq!(vec![3, 20], e.wildcards);
+        assert_eq!(1, e.variants.len());
+    }
+
+    #[test]
+    fn duplicates_merged() {
+        let pattern: Vec<u8> = (0 .. 16).collect();
+        let two: &[u8] = &[0xaa, 0xbb];
+        // the offset 14 is in the 2-byte run after the wildcard at 13,
+        // it is not looked up: its variants give the same lists
+        let e = expand(&pattern, &alternatives(&[(2, two), (13, &[1, 2, 3, 4, 5]), (14, two)]), 4);
+        assert_eq!(vec![13], e.wildcards);
+        assert_eq!(2, e.variants.len());
+        assert_eq!("2=aa,14=aa | 2=aa,14=bb", e.labels[0]);
+        assert_eq!(vec![&pattern[.. 13]], pieces(&pattern, &e.wildcards));
+        assert_eq!(1, pieces(&e.variants[1], &e.wildcards).len());
+        assert_eq!(0xbb, pieces(&e.variants[1], &e.wildcards)[0][2]);
+    }
+
+    #[test]
+    fn masked_search() {
+        let content = b"xx\x6a\x40\x68\x00\x30\x99\x00\x68yy";
+        assert!(find_masked(content, b"\x6a\x40\x68\x00\x30\x00\x00\x68", &[5]));
+        assert!(!find_masked(content, b"\x6a\x40\x68\x00\x30\x00\x00\x68", &[]));
+        assert!(find_masked(content, b"\x6a\x40\x68\x00\x30\x00\x00\x68", &[0, 5]));
+        assert!(!find_masked(content, b"\x6a\x40\x68\x00\x31\x00\x00\x68", &[5]));
+        // out of the content on either side
+        assert!(find_masked(content, b"\x00\x00\x6a\x40\x68", &[0, 1]));
+        assert!(!find_masked(content, b"\x00\x00\x00\x6a\x40", &[0, 1, 2]));
+        assert!(!find_masked(content, b"\x68yyzz", &[3]));
+        assert!(!find_masked(content, b"\x68yy?", &[3]));
+        assert!(find_masked(b"\x68yyz", b"\x68yy?", &[3]));
+
+        assert_eq!(vec![&b"abcd"[..], &b"fghij"[..]], pieces(b"abcdefghij", &[4]));
+        assert_eq!(vec![&b"fghij"[..]], pieces(b"abcdefghij", &[1, 4]));
+        assert!(pieces(b"abcdefg", &[3]).is_empty());
+    }
+
+    #[test]
+    fn wide_encoding() {
+        assert_eq!(vec![b'a', 0, b'b', 0], to_wide(b"ab"));
+        assert_eq!(vec![0xe9, 0], to_wide("é".as_bytes()));
+        assert_eq!(vec![0xff, 0], to_wide(&[0xff]));
+        // out of the BMP, a surrogate pair
+        assert_eq!(vec![0x3d, 0xd8, 0x00, 0xde], to_wide("\u{1f600}".as_bytes()));
+    }
+
+    #[test]
+    fn reversed_forms() {
+        assert_eq!(vec![4, 3, 2, 1], reverse_bytes(&[1, 2, 3, 4], ByteSwap::Full).unwrap());
+        assert_eq!(vec![4, 3, 2, 1, 8, 7, 6, 5], reverse_bytes(&[1, 2, 3, 4, 5, 6, 7, 8], ByteSwap::Words).unwrap());
+        assert!(reverse_bytes(&[1, 2, 3, 4, 5], ByteSwap::Words).is_err());
+
+        assert_eq!(ByteSwap::Full, ByteSwap::parse("pattern").unwrap());
+        assert_eq!(ByteSwap::Words, ByteSwap::parse("4").unwrap());
+        for bad in &["8", "2", "", "words"] {
+            assert_eq!(ErrorKind::InvalidInput, ByteSwap::parse(bad).unwrap_err().kind(), "{}", bad);
+        }
+
+        assert_eq!(vec![vec![1, 2, 3, 4], vec![4, 3, 2, 1]], forms(&[1, 2, 3, 4], false, false, Some(ByteSwap::Full)).unwrap());
+        assert_eq!(vec![vec![1, 0, 2, 0, 3, 0, 4, 0], vec![4, 0, 3, 0, 2, 0, 1, 0]],
+                   forms(&[1, 2, 3, 4], true, false, Some(ByteSwap::Full)).unwrap());
+        assert_eq!(vec![b"abcd".to_vec(), vec![b'a', 0, b'b', 0, b'c', 0, b'd', 0]], forms(b"abcd", false, true, None).unwrap());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+use std::io::*;
+use std::time::{Duration, Instant};
+
+use binacle::BinacleFile;
+use binacle_manager::{BinacleManager, ShardWarning};
+use search::SearchRequest;
+
+// Warm-up of the page cache after a reboot. The first searches are slow:
+// each header entry and each list they read is a cold page of a large
+// shard. Two ways of faulting them in, in a time budget:
+//
+// - the headers of the shards, read sequentially, the finalized shards
+//   first (they are the largest and do not change);
+// - better, a file of past queries replayed through the planner only: the
+//   header entries of their n-grams and the leading blocks of their lists
+//   are touched, no result is read. Only the pages the same queries need
+//   are faulted in.
+//
+// A warm-up run as a systemd service (Type=notify) tells it is done with
+// --notify: the socket of --inetd ordered after the service accepts the
+// connections once the index is warm.
+
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// blocks of a list faulted in by the replay, the newest ones a search reads first
+pub const LEADING_BLOCKS: usize = 2;
+
+// pages of the headers touched between two checks of the time budget
+const HEADER_STEP: u64 = 256;
+
+#[derive(Clone, Debug, Default)]
+pub struct WarmupReport {
+    pub shards: usize,
+    // queries replayed to their end
+    pub queries: usize,
+    pub pages: u64,
+    pub bytes: u64,
+    // the time budget ran out before the end
+    pub stopped: bool,
+    // shards that could not be opened, not warmed up
+    pub warnings: Vec<ShardWarning>,
+}
+
+impl WarmupReport {
+
+    pub fn describe(&self) -> String {
+        let queries = match self.queries {
+            0 => String::new(),
+            n => format!(", {} query(ies) replayed", n),
+        };
+        format!("{} page(s) touched, {} bytes read in {} shard(s){}{}", self.pages, self.bytes, self.shards, queries,
+                if self.stopped { ", stopped by the time budget" } else { "" })
+    }
+}
+
+pub struct Warmup {
+    budget: Option<Duration>,
+    start: Instant,
+    // pages touched by the replay, by shard
+    touched: HashMap<usize, HashSet<u64>>,
+    report: WarmupReport,
+    // each region touched: shard, offset and length (tests)
+    hook: Option<Box<dyn FnMut(usize, u64, u64)>>,
+}
+
+impl Warmup {
+
+    pub fn new(budget: Option<Duration>) -> Warmup {
+        Warmup {
+            budget: budget,
+            start: Instant::now(),
+            touched: HashMap::new(),
+            report: WarmupReport::default(),
+            hook: None,
+        }
+    }
+
+    pub fn set_access_hook(&mut self, hook: Box<dyn FnMut(usize, u64, u64)>) {
+        self.hook = Some(hook);
+    }
+
+    fn out_of_time(&mut self) -> bool {
+        if self.budget.map_or(false, |budget| self.start.elapsed() >= budget) {
+            self.report.stopped = true;
+        }
+        self.report.stopped
+    }
+
+    // a page of a shard, counted with the bytes it holds
+    fn touch_page(&mut self, shard: &BinacleFile, page: u64) {
+        let offset = page * PAGE_SIZE;
+        if shard.touch(offset) {
+            self.report.pages += 1;
+            self.report.bytes += PAGE_SIZE.min(shard.file_size().saturating_sub(offset));
+        }
+    }
+
+    // the pages of a region not touched yet by the replay
+    fn touch_region(&mut self, n: usize, shard: &BinacleFile, offset: u64, len: u64) {
+        if let Some(ref mut hook) = self.hook {
+            hook(n, offset, len);
+        }
+        for page in offset / PAGE_SIZE .. (offset + len.max(1) + PAGE_SIZE - 1) / PAGE_SIZE {
+            if self.touched.entry(n).or_insert_with(HashSet::new).insert(page) {
+                self.touch_page(shard, page);
+            }
+        }
+    }
+
+    // the shards opened, the finalized ones first
+    fn shards(&mut self, db: &mut BinacleManager) -> Vec<(usize, BinacleFile)> {
+        let mut shards = Vec::new();
+        for (n, shard) in db.open_shards().into_iter().enumerate() {
+            match shard {
+                Ok(shard) => shards.push((n, shard)),
+                Err(e) => self.report.warnings.push(db.shard_warning(n, &e)),
+            }
+        }
+        shards.sort_by_key(|&(n, _)| (!db.shard_is_finalized(n), n));
+        shards
+    }
+
+    // the headers of the shards, read sequentially
+    pub fn headers(mut self, db: &mut BinacleManager) -> Result<WarmupReport> {
+
+        for (n, shard) in self.shards(db) {
+            if self.out_of_time() {
+                break;
+            }
+            if let Some(ref mut hook) = self.hook {
+                hook(n, 0, shard.header_len());
+            }
+            self.report.shards += 1;
+            let pages = (shard.header_len() + PAGE_SIZE - 1) / PAGE_SIZE;
+            for page in 0 .. pages {
+                if page % HEADER_STEP == 0 && self.out_of_time() {
+                    break;
+                }
+                self.touch_page(&shard, page);
+            }
+        }
+        Ok(self.report)
+    }
+
+    // the header entries and the leading blocks of the lists the queries
+    // read, the planner probes the sizes of the lists as a search would
+    pub fn replay(mut self, db: &mut BinacleManager, requests: &[SearchRequest]) -> Result<WarmupReport> {
+
+        let shards = self.shards(db);
+        self.report.shards = shards.len();
+        let preselect = db.preselect();
+
+        for request in requests {
+            let patterns: Vec<Vec<u8>> = try!(request.looked_up(db)).iter()
+                .map(|p| db.normalize(p).into_owned())
+                .filter(|p| p.len() >= 4)
+                .collect();
+            for &(n, ref shard) in &shards {
+                for pattern in &patterns {
+                    if self.out_of_time() {
+                        return Ok(self.report);
+                    }
+                    let plan = try!(shard.plan(pattern, preselect));
+                    for ngram in &plan.order {
+                        let entry = shard.header_entry(*ngram);
+                        self.touch_region(n, shard, entry, 8);
+                        for (offset, len) in shard.leading_blocks(*ngram, LEADING_BLOCKS) {
+                            self.touch_region(n, shard, offset, len);
+                        }
+                    }
+                }
+            }
+            self.report.queries += 1;
+        }
+        Ok(self.report)
+    }
+}
+
+// READY=1 to systemd, when started by it: false otherwise
+#[cfg(unix)]
+pub fn notify_ready() -> Result<bool> {
+
+    use std::env;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let socket = try!(UnixDatagram::unbound());
+    match path.as_bytes().first() {
+        // in the abstract namespace
+        #[cfg(target_os = "linux")]
+        Some(&b'@') => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            let addr = try!(SocketAddr::from_abstract_name(&path.as_bytes()[1 ..]));
+            try!(socket.send_to_addr(b"READY=1\n", &addr));
+        },
+        _ => {
+            try!(socket.send_to(b"READY=1\n", &path));
+        },
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn notify_ready() -> Result<bool> {
+    Ok(false)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use binacle_manager::tests::cleanup;
+
+    fn fixture(name: &str) -> BinacleManager {
+        let mut db = BinacleManager::create(name, true, 1 << 30, 6, 16).unwrap();
+        db.insert_reader(&b"call GetProcAddress"[..], "a.bin", 1, true);
+        db.insert_reader(&b"GetProcAddress LoadLibraryA"[..], "b.bin", 2, true);
+        db.insert_reader(&b"kernel32.dll"[..], "c.bin", 3, true);
+        db
+    }
+
+    #[test]
+    fn replay_touches_the_lists_read() {
+        let mut db = fixture("test_warmup.db");
+
+        let touched = Rc::new(RefCell::new(Vec::new()));
+        let recorded = touched.clone();
+        let mut warmup = Warmup::new(None);
+        warmup.set_access_hook(Box::new(move |n, offset, len| recorded.borrow_mut().push((n, offset, len))));
+        let report = warmup.replay(&mut db, &[SearchRequest::pattern(b"LoadLibraryA")]).unwrap();
+        assert_eq!(1, report.queries);
+        assert_eq!(1, report.shards);
+        assert!(!report.stopped);
+        assert!(report.pages > 0 && report.bytes <= report.pages * PAGE_SIZE);
+
+        // the header entry and the blocks of each n-gram of the pattern, nothing else
+        let shard = db.open_shards().remove(0).unwrap();
+        let mut expected = Vec::new();
+        for ngram in BinacleFile::split_ngrams(b"LoadLibraryA").unwrap() {
+            expected.push((0, shard.header_entry(ngram), 8));
+            let blocks = shard.leading_blocks(ngram, LEADING_BLOCKS);
+            assert_eq!(1, blocks.len());
+            expected.extend(blocks.into_iter().map(|(offset, len)| (0, offset, len)));
+        }
+        let mut touched = touched.borrow().clone();
+        touched.sort();
+        expected.sort();
+        assert_eq!(expected, touched);
+        assert!(!touched.contains(&(0, shard.header_entry(u32::from_ne_bytes(*b"kern")), 8)));
+        drop(shard);
+
+        // out of time before the first query
+        let report = Warmup::new(Some(Duration::from_secs(0)))
+            .replay(&mut db, &[SearchRequest::pattern(b"LoadLibraryA")]).unwrap();
+        assert!(report.stopped);
+        assert_eq!((0, 0), (report.queries, report.pages));
+        drop(db);
+        cleanup("test_warmup");
+    }
+
+    #[test]
+    fn whole_headers() {
+        let mut db = fixture("test_warmup2.db");
+        let header = db.open_shards().remove(0).unwrap().header_len();
+        let report = Warmup::new(None).headers(&mut db).unwrap();
+        assert_eq!(1, report.shards);
+        assert_eq!((header + PAGE_SIZE - 1) / PAGE_SIZE, report.pages);
+        assert_eq!(header, report.bytes);
+        assert!(report.describe().starts_with(&format!("{} page(s) touched, {} bytes read in 1 shard(s)", report.pages, header)));
+        drop(db);
+        cleanup("test_warmup2");
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::read_dir;
+use std::io::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::WalkDir;
+
+use binacle_manager::{BinacleManager, FileInsertion, InsertOutcome};
+
+// Watch mode (--watch): a directory a feed drops files into, the new files
+// inserted as they appear. There is no notification from the filesystem:
+// the directory is walked at each interval and diffed against the paths of
+// the map, only the files it does not have are inserted, under the next
+// ids. A new file is inserted once its size and its modification time did
+// not change between two scans, a file still being written waits.
+//
+// A file that vanishes between its scan and its insertion is skipped, its
+// id goes to the next one; a file that fails is not tried again until the
+// next start. The map and the meta are written after each scan that
+// inserted files, and within a scan as --durability says. Ctrl-C (or a
+// SIGTERM) stops the watch after the file being inserted, the database
+// written.
+
+
+// steps of the sleep between two scans, a stop is seen after one of them
+const SLEEP_STEP: Duration = Duration::from_millis(200);
+
+// set by SIGINT and SIGTERM once stop_on_interrupt was called
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WatchSummary {
+    pub scans: usize,
+    pub inserted: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl WatchSummary {
+
+    fn add(&mut self, outcome: &InsertOutcome) {
+        match *outcome {
+            InsertOutcome::Inserted(_) => self.inserted += 1,
+            InsertOutcome::Skipped(_) => self.skipped += 1,
+            InsertOutcome::Failed(_) => self.failed += 1,
+        }
+    }
+}
+
+pub struct Watch {
+    dir: String,
+    // the paths of the map and the ones given up, not inserted again
+    known: HashSet<String>,
+    // size and modification time of the new files at the last scan
+    pending: HashMap<String, (u64, Option<SystemTime>)>,
+    // the id of the next file, a failed file keeps its own
+    next_id: u32,
+}
+
+impl Watch {
+
+    pub fn new(db: &BinacleManager, dir: &str) -> Result<Watch> {
+        if !db.is_map() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "{} has no map, the new files of {} are told by the paths of the map", db.db_path, dir)));
+        }
+        try!(read_dir(dir).map_err(|e| Error::new(e.kind(), format!("{}: {}", dir, e))));
+        Ok(Watch {
+            dir: String::from(dir),
+            known: db.entries().filter(|&(id, _)| !db.is_tombstoned(id)).map(|(_, e)| e.path.clone()).collect(),
+            pending: HashMap::new(),
+            next_id: try!(db.next_id()),
+        })
+    }
+
+    // the new files unchanged since the last scan, by path
+    pub fn scan(&mut self) -> Vec<String> {
+
+        let mut seen = HashMap::new();
+        for entry in WalkDir::new(&self.dir).into_iter().filter_map(|e| e.ok()) {
+            let meta = match entry.metadata() {
+                Ok(ref meta) if meta.is_file() => (meta.len(), meta.modified().ok()),
+                _ => continue,
+            };
+            match entry.path().to_str() {
+                Some(path) if !self.known.contains(path) => {
+                    seen.insert(String::from(path), meta);
+                },
+                _ => continue,
+            }
+        }
+
+        let mut stable: Vec<String> = seen.iter()
+            .filter(|&(path, meta)| self.pending.get(path) == Some(meta))
+            .map(|(path, _)| path.clone())
+            .collect();
+        stable.sort();
+        for path in &stable {
+            seen.remove(path);
+        }
+        self.pending = seen;
+        stable
+    }
+
+    // the files inserted under the next ids, each one told to f. Stops
+    // before the next file when stop is set or the disk space runs low
+    pub fn insert<F: FnMut(&FileInsertion)>(&mut self, db: &mut BinacleManager, files: Vec<String>,
+                                            stop: &AtomicBool, f: &mut F) -> Result<WatchSummary> {
+
+        let mut summary = WatchSummary::default();
+        for file in files {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(e) = db.check_space() {
+                try!(db.sync());
+                return Err(Error::new(e.kind(), format!("{}: {:?} and the next files not inserted", e, file)));
+            }
+
+            let id = self.next_id.max(try!(db.next_id()));
+            let outcome = match db.insert(&file, id, false) {
+                InsertOutcome::Failed(ref e) if e.kind() == ErrorKind::NotFound => InsertOutcome::Skipped(format!("vanished: {}", e)),
+                outcome => outcome,
+            };
+            self.next_id = match outcome {
+                InsertOutcome::Skipped(_) => id,
+                _ => id + 1,
+            };
+            // a file that vanished is inserted if it comes back
+            match outcome {
+                InsertOutcome::Skipped(ref reason) if reason.starts_with("vanished") => {},
+                _ => {
+                    self.known.insert(file.clone());
+                },
+            }
+            summary.add(&outcome);
+            f(&FileInsertion { id: id, path: file, outcome: outcome, size: None });
+        }
+        if summary.inserted > 0 {
+            try!(db.sync());
+        }
+        Ok(summary)
+    }
+
+    // a scan and its insertions every interval, until stop is set
+    pub fn run<F: FnMut(&FileInsertion)>(&mut self, db: &mut BinacleManager, interval: Duration,
+                                         stop: &AtomicBool, mut f: F) -> Result<WatchSummary> {
+
+        let mut summary = WatchSummary::default();
+        while !stop.load(Ordering::SeqCst) {
+            let files = self.scan();
+            let step = try!(self.insert(db, files, stop, &mut f));
+            summary.scans += 1;
+            summary.inserted += step.inserted;
+            summary.skipped += step.skipped;
+            summary.failed += step.failed;
+
+            let start = Instant::now();
+            while !stop.load(Ordering::SeqCst) && start.elapsed() < interval {
+                thread::sleep(SLEEP_STEP.min(interval.saturating_sub(start.elapsed())));
+            }
+        }
+        try!(db.sync());
+        Ok(summary)
+    }
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+#[cfg(unix)]
+extern "C" fn interrupted(_: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// set by Ctrl-C and SIGTERM instead of the process being killed
+#[cfg(unix)]
+pub fn stop_on_interrupt() -> &'static AtomicBool {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    unsafe {
+        signal(SIGINT, interrupted);
+        signal(SIGTERM, interrupted);
+    }
+    &INTERRUPTED
+}
+
+// the process is still killed
+#[cfg(not(unix))]
+pub fn stop_on_interrupt() -> &'static AtomicBool {
+    &INTERRUPTED
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use binacle_manager::tests::cleanup;
+
+    fn write(path: &str, content: &str) {
+        File::create(path).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn new_files() {
+
+        let dir = "test_watch1.dir";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+        let (a, b, c, d) = ("test_watch1.dir/a.bin", "test_watch1.dir/b.bin", "test_watch1.dir/c.bin", "test_watch1.dir/d.bin");
+        write(a, "already GetProcAddress");
+        write(b, "then LoadLibraryA");
+        {
+            let mut db = BinacleManager::create("test_watch1.db", true, 1 << 30, 6, 20).unwrap();
+            db.insert_file(a, 1, true).unwrap();
+            let mut watch = Watch::new(&db, dir).unwrap();
+            let stop = AtomicBool::new(false);
+            let mut told = Vec::new();
+
+            // the first scan only sees b, the second one finds it unchanged
+            assert!(watch.scan().is_empty());
+            assert_eq!(vec![b], watch.scan());
+            let summary = watch.insert(&mut db, vec![String::from(b)], &stop,
+                                       &mut |i: &FileInsertion| told.push((i.id, i.path.clone(), i.outcome.status()))).unwrap();
+            assert_eq!(WatchSummary { scans: 0, inserted: 1, skipped: 0, failed: 0 }, summary);
+
+            write(c, "being written");
+            assert!(watch.scan().is_empty());
+            write(c, "being written, grown");
+            write(d, "a file that vanishes");
+            assert!(watch.scan().is_empty());
+            assert_eq!(vec![c, d], watch.scan());
+
+            fs::remove_file(d).unwrap();
+            let summary = watch.insert(&mut db, vec![String::from(d), String::from(c)], &stop,
+                                       &mut |i: &FileInsertion| told.push((i.id, i.path.clone(), i.outcome.status()))).unwrap();
+            assert_eq!(WatchSummary { scans: 0, inserted: 1, skipped: 1, failed: 0 }, summary);
+            assert_eq!(vec![(2, String::from(b), "inserted"), (3, String::from(d), "skipped"), (3, String::from(c), "inserted")], told);
+            assert_eq!(Some(c), db.path_of(3));
+
+            // d inserted when it comes back, the others are known
+            write(d, "back again");
+            assert!(watch.scan().is_empty());
+            assert_eq!(vec![d], watch.scan());
+
+            // stopped before the next file, the meta written
+            stop.store(true, Ordering::SeqCst);
+            let summary = watch.run(&mut db, Duration::from_secs(60), &stop, |_| panic!("no file after a stop")).unwrap();
+            assert_eq!(0, summary.scans);
+        }
+        let db = BinacleManager::open("test_watch1.db").unwrap();
+        assert_eq!(3, db.last_id());
+        assert_eq!(Some(b), db.path_of(2));
+        drop(db);
+        cleanup("test_watch1");
+        fs::remove_dir_all(dir).unwrap();
+
+        let db = BinacleManager::create("test_watch2.db", false, 1 << 30, 6, 20).unwrap();
+        assert_eq!(ErrorKind::InvalidInput, Watch::new(&db, "src").err().unwrap().kind());
+        drop(db);
+        cleanup("test_watch2");
+    }
+}
@@ -0,0 +1,131 @@
+use std::any::Any;
+use std::io::*;
+use std::time::{Duration, Instant};
+
+// Per-file watchdog: a file taking more than its time budget to be read
+// (a stalled network mount, a file growing while read) is abandoned, the
+// budget is checked between two reads. Such files, and the ones making the
+// extraction panic, are quarantined: listed in the meta with the reason and
+// skipped by the next insertions.
+
+
+// a file left out of the database
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Quarantined {
+    pub path: String,
+    pub reason: String,
+}
+
+pub struct DeadlineReader<R> {
+    inner: R,
+    budget: Option<Duration>,
+    start: Instant,
+}
+
+impl<R: Read> DeadlineReader<R> {
+
+    pub fn new(inner: R, budget: Option<Duration>) -> DeadlineReader<R> {
+        DeadlineReader {
+            inner: inner,
+            budget: budget,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if let Some(budget) = self.budget {
+            if self.start.elapsed() >= budget {
+                return Err(Error::new(ErrorKind::TimedOut,
+                    format!("time budget of {} ms exceeded", budget.as_millis())));
+            }
+        }
+        self.inner.read(buf)
+    }
+}
+
+// message of a caught panic
+pub fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        String::from(*s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::{self, File};
+    use std::thread::sleep;
+    use binacle_manager::{BinacleManager, InsertOutcome};
+    use binacle_manager::tests::cleanup;
+
+    // a few bytes, then a pause, at each read
+    struct Slow(usize);
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            sleep(Duration::from_millis(20));
+            self.0 += 1;
+            let len = 16.min(buf.len());
+            for b in &mut buf[.. len] {
+                *b = self.0 as u8;
+            }
+            Ok(len)
+        }
+    }
+
+    struct Panicking;
+
+    impl Read for Panicking {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize> {
+            panic!("broken extractor");
+        }
+    }
+
+    #[test]
+    fn quarantine() {
+
+        File::create("test_wd.bin").unwrap().write_all(b"GetProcAddress").unwrap();
+        {
+            let mut db = BinacleManager::create("test_wd.db", true, 1 << 30, 6, 16).unwrap();
+            db.set_file_budget(Some(Duration::from_millis(100)));
+
+            match db.insert_reader(Slow(0), "test_wd.bin", 1, true) {
+                InsertOutcome::Failed(ref e) if e.kind() == ErrorKind::TimedOut => {},
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert!(db.is_tombstoned(1));
+            match db.insert_reader(Panicking, "other.bin", 2, true) {
+                InsertOutcome::Failed(ref e) => assert!(e.to_string().contains("broken extractor")),
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            assert_eq!(2, db.quarantine().len());
+            assert_eq!(0, db.nb_file());
+
+            // the writer still works
+            match db.insert("test_wd.bin", 3, true) {
+                InsertOutcome::Skipped(ref reason) => assert!(reason.starts_with("quarantined")),
+                o => panic!("unexpected outcome {:?}", o),
+            }
+            db.set_retry_quarantined(true);
+            assert_eq!(3, db.insert_file("test_wd.bin", 3, true).unwrap());
+            assert_eq!(vec![3], db.search(b"GetProcAddress").unwrap().into_iter().collect::<Vec<u32>>());
+            assert_eq!(1, db.quarantine().len());
+        }
+
+        // kept in the meta
+        let db = BinacleManager::open("test_wd.db").unwrap();
+        assert_eq!("other.bin", db.quarantine()[0].path);
+        drop(db);
+        cleanup("test_wd");
+        let _ = fs::remove_file("test_wd.bin");
+    }
+}
@@ -0,0 +1,794 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::OpenOptions;
+use std::io::*;
+
+use binacle_manager::BinacleManager;
+use search::SearchRequest;
+use variants;
+
+// Pre-filtering of YARA rules with the index: the text and hex strings of
+// each rule are searched and the files that may match its condition are its
+// candidates, to be given to YARA.
+//
+// The rules are not compiled, only read: the strings are taken from their
+// section, the regular expressions, the strings shorter than 4 bytes and the
+// ones whose bytes are not known (nocase, xor, base64) are ignored. A hex
+// string is cut at its jumps and alternatives, the pieces having 4 known
+// bytes in a row must all be found. The condition is read conservatively,
+// a file is never left out when the rule could match it: `all of` needs all
+// the strings of its set, `any of` and `n of` one of them, `$a` its string,
+// `and` both sides and `or` one side. Anything else (`not`, counts, offsets,
+// modules, filesize...) does not filter, and a rule whose condition can be
+// true without one of the strings searched has all the files as candidates.
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Width {
+    Ascii,
+    Wide,
+    Both,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Needle {
+    Text(Vec<u8>, Width),
+    // the pieces of a hex string, with the offsets of their wildcards
+    Hex(Vec<(Vec<u8>, Vec<usize>)>),
+    // why the string is not searched
+    Ignored(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct YaraString {
+    // with its $, only $ for an anonymous string
+    pub name: String,
+    pub needle: Needle,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct YaraRule {
+    pub name: String,
+    pub strings: Vec<YaraString>,
+    pub condition: String,
+}
+
+// what a file must have to be a candidate of a rule
+#[derive(Debug, PartialEq)]
+pub enum Filter {
+    // nothing, all the files are candidates
+    Every,
+    // the string of this index
+    Str(usize),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+pub struct RuleCandidates {
+    pub rule: String,
+    // sorted, None when the rule can not be pre-filtered
+    pub ids: Option<Vec<u32>>,
+    pub searched: usize,
+    // the strings ignored, with the reason
+    pub ignored: Vec<(String, String)>,
+}
+
+struct Cursor<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Cursor<'a> {
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.at).cloned()
+    }
+
+    fn error(&self, msg: String) -> Error {
+        let line = self.text[.. self.at.min(self.text.len())].iter().filter(|&&c| c == b'\n').count() + 1;
+        Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+    }
+
+    // the blanks and the comments
+    fn skip_blank(&mut self) {
+        loop {
+            match (self.peek(), self.text.get(self.at + 1).cloned()) {
+                (Some(c), _) if c.is_ascii_whitespace() => self.at += 1,
+                (Some(b'/'), Some(b'/')) => {
+                    while self.peek().map_or(false, |c| c != b'\n') {
+                        self.at += 1;
+                    }
+                },
+                (Some(b'/'), Some(b'*')) => {
+                    self.at = match self.text[self.at + 2 ..].windows(2).position(|w| w == b"*/") {
+                        Some(end) => self.at + 2 + end + 2,
+                        None => self.text.len(),
+                    };
+                },
+                _ => return,
+            }
+        }
+    }
+
+    fn word(&mut self) -> String {
+        self.skip_blank();
+        let start = self.at;
+        while self.peek().map_or(false, |c| c.is_ascii_alphanumeric() || c == b'_') {
+            self.at += 1;
+        }
+        String::from_utf8_lossy(&self.text[start .. self.at]).into_owned()
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        self.skip_blank();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.at += 1;
+                Ok(())
+            },
+            Some(c) => Err(self.error(format!("expected {:?}, found {:?}", expected as char, c as char))),
+            None => Err(self.error(format!("expected {:?}, found the end of the rules", expected as char))),
+        }
+    }
+
+    // a "..." literal, its escapes decoded
+    fn literal(&mut self) -> Result<Vec<u8>> {
+        try!(self.expect(b'"'));
+        let mut bytes = Vec::new();
+        loop {
+            let c = match self.peek() {
+                Some(b'\n') | None => return Err(self.error(String::from("unterminated string"))),
+                Some(c) => c,
+            };
+            self.at += 1;
+            match c {
+                b'"' => return Ok(bytes),
+                b'\\' => {
+                    let escaped = try!(self.peek().ok_or(self.error(String::from("unterminated string"))));
+                    self.at += 1;
+                    match escaped {
+                        b'n' => bytes.push(b'\n'),
+                        b't' => bytes.push(b'\t'),
+                        b'r' => bytes.push(b'\r'),
+                        b'x' => {
+                            let hex = String::from_utf8_lossy(self.text.get(self.at .. self.at + 2).unwrap_or(b"")).into_owned();
+                            bytes.push(try!(u8::from_str_radix(&hex, 16).map_err(|_| self.error(format!("invalid escape \\x{}", hex)))));
+                            self.at += 2;
+                        },
+                        c => bytes.push(c),
+                    }
+                },
+                c => bytes.push(c),
+            }
+        }
+    }
+
+    // the text up to this byte, not included
+    fn until(&mut self, end: u8) -> Result<&'a str> {
+        let start = self.at;
+        match self.text[start ..].iter().position(|&c| c == end) {
+            Some(len) => {
+                self.at += len;
+                Ok(::std::str::from_utf8(&self.text[start .. start + len]).unwrap_or(""))
+            },
+            None => Err(self.error(format!("missing {:?}", end as char))),
+        }
+    }
+
+    // a /.../ regular expression and its flags
+    fn skip_regex(&mut self) -> Result<()> {
+        try!(self.expect(b'/'));
+        loop {
+            match self.peek() {
+                Some(b'\n') | None => return Err(self.error(String::from("unterminated regular expression"))),
+                Some(b'\\') => self.at += 2,
+                Some(b'/') => {
+                    self.at += 1;
+                    while self.peek().map_or(false, |c| c == b'i' || c == b's') {
+                        self.at += 1;
+                    }
+                    return Ok(());
+                },
+                Some(_) => self.at += 1,
+            }
+        }
+    }
+
+    // the modifiers following a string
+    fn modifiers(&mut self) -> Result<Vec<String>> {
+        let mut modifiers = Vec::new();
+        loop {
+            let start = self.at;
+            let word = self.word();
+            match word.as_str() {
+                "ascii" | "wide" | "nocase" | "fullword" | "private" | "xor" | "base64" | "base64wide" => {},
+                _ => {
+                    self.at = start;
+                    return Ok(modifiers);
+                },
+            }
+            // xor(1-255), base64("...")
+            self.skip_blank();
+            if self.peek() == Some(b'(') {
+                while self.peek().map_or(false, |c| c != b')') {
+                    match self.peek() {
+                        Some(b'"') => {
+                            try!(self.literal());
+                        },
+                        _ => self.at += 1,
+                    }
+                }
+                try!(self.expect(b')'));
+            }
+            modifiers.push(word);
+        }
+    }
+}
+
+// the pieces of a hex string searched: cut at the jumps and the
+// alternatives, the nibble and ~ bytes are wildcards
+fn hex_pieces(hex: &str) -> Result<Vec<(Vec<u8>, Vec<usize>)>> {
+
+    let mut pieces = Vec::new();
+    let mut bytes = Vec::new();
+    let mut wildcards = Vec::new();
+    let mut cut = |bytes: &mut Vec<u8>, wildcards: &mut Vec<usize>| {
+        // the wildcards at its ends add nothing
+        let first = (0 .. bytes.len()).find(|i| !wildcards.contains(i)).unwrap_or(bytes.len());
+        let last = (0 .. bytes.len()).rev().find(|i| !wildcards.contains(i)).map_or(first, |i| i + 1);
+        let piece = bytes[first .. last].to_vec();
+        let masked: Vec<usize> = wildcards.iter().filter(|&&w| w >= first && w < last).map(|w| w - first).collect();
+        if !variants::pieces(&piece, &masked).is_empty() {
+            pieces.push((piece, masked));
+        }
+        bytes.clear();
+        wildcards.clear();
+    };
+
+    let digits: Vec<char> = hex.lines()
+        .flat_map(|line| line.splitn(2, "//").next().unwrap_or("").chars())
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let mut i = 0;
+    while i < digits.len() {
+        match digits[i] {
+            '[' => {
+                let end = try!(digits[i ..].iter().position(|&c| c == ']').ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("unterminated jump in {{{}}}", hex.trim()))
+                }));
+                cut(&mut bytes, &mut wildcards);
+                i += end + 1;
+            },
+            '(' => {
+                let mut depth = 0;
+                let mut end = None;
+                for (j, &c) in digits[i ..].iter().enumerate() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' if depth == 1 => {
+                            end = Some(j);
+                            break;
+                        },
+                        ')' => depth -= 1,
+                        _ => {},
+                    }
+                }
+                let end = try!(end.ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("unterminated alternative in {{{}}}", hex.trim()))
+                }));
+                cut(&mut bytes, &mut wildcards);
+                i += end + 1;
+            },
+            c => {
+                let not = c == '~';
+                let start = if not { i + 1 } else { i };
+                let pair: String = digits.iter().skip(start).take(2).collect();
+                if pair.len() != 2 || !pair.chars().all(|c| c == '?' || c.is_digit(16)) {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("invalid byte {} in {{{}}}", pair, hex.trim())));
+                }
+                match u8::from_str_radix(&pair, 16) {
+                    Ok(b) if !not => bytes.push(b),
+                    _ => {
+                        wildcards.push(bytes.len());
+                        bytes.push(0);
+                    },
+                }
+                i = start + 2;
+            },
+        }
+    }
+    cut(&mut bytes, &mut wildcards);
+    Ok(pieces)
+}
+
+fn string(c: &mut Cursor) -> Result<YaraString> {
+
+    try!(c.expect(b'$'));
+    let name = format!("${}", c.word());
+    try!(c.expect(b'='));
+    c.skip_blank();
+    let needle = match c.peek() {
+        Some(b'"') => {
+            let bytes = try!(c.literal());
+            let modifiers = try!(c.modifiers());
+            let has = |m: &str| modifiers.iter().any(|n| n == m);
+            match ["nocase", "xor", "base64", "base64wide"].iter().find(|m| has(m)) {
+                Some(m) => Needle::Ignored(format!("{} string", m)),
+                None if bytes.len() < 4 => Needle::Ignored(String::from("shorter than 4 bytes")),
+                None => Needle::Text(bytes, match (has("ascii"), has("wide")) {
+                    (true, true) => Width::Both,
+                    (false, true) => Width::Wide,
+                    _ => Width::Ascii,
+                }),
+            }
+        },
+        Some(b'{') => {
+            c.at += 1;
+            let hex = try!(c.until(b'}'));
+            c.at += 1;
+            try!(c.modifiers());
+            match try!(hex_pieces(hex).map_err(|e| c.error(format!("{}: {}", name, e)))) {
+                ref pieces if pieces.is_empty() => Needle::Ignored(String::from("no 4 known bytes in a row")),
+                pieces => Needle::Hex(pieces),
+            }
+        },
+        Some(b'/') => {
+            try!(c.skip_regex());
+            try!(c.modifiers());
+            Needle::Ignored(String::from("regular expression"))
+        },
+        _ => return Err(c.error(format!("invalid value of {}", name))),
+    };
+    Ok(YaraString {
+        name: name,
+        needle: needle,
+    })
+}
+
+fn rule(c: &mut Cursor) -> Result<YaraRule> {
+
+    let name = c.word();
+    if name.is_empty() {
+        return Err(c.error(String::from("rule without a name")));
+    }
+    // its tags
+    c.skip_blank();
+    if c.peek() == Some(b':') {
+        c.at += 1;
+        while !c.word().is_empty() {}
+    }
+    try!(c.expect(b'{'));
+
+    let mut strings = Vec::new();
+    loop {
+        let section = c.word();
+        try!(c.expect(b':'));
+        match section.as_str() {
+            "meta" => loop {
+                let start = c.at;
+                c.word();
+                c.skip_blank();
+                if c.peek() != Some(b'=') {
+                    c.at = start;
+                    break;
+                }
+                c.at += 1;
+                c.skip_blank();
+                match c.peek() {
+                    Some(b'"') => {
+                        try!(c.literal());
+                    },
+                    Some(b'-') => {
+                        c.at += 1;
+                        c.word();
+                    },
+                    _ => {
+                        c.word();
+                    },
+                }
+            },
+            "strings" => loop {
+                c.skip_blank();
+                if c.peek() != Some(b'$') {
+                    break;
+                }
+                strings.push(try!(string(c)));
+            },
+            "condition" => {
+                let start = c.at;
+                // up to the end of the rule, the literals skipped
+                loop {
+                    match c.peek() {
+                        Some(b'"') => {
+                            try!(c.literal());
+                        },
+                        Some(b'}') | None => break,
+                        Some(_) => c.at += 1,
+                    }
+                }
+                let condition = String::from_utf8_lossy(&c.text[start .. c.at]).trim().to_string();
+                try!(c.expect(b'}'));
+                return Ok(YaraRule {
+                    name: name,
+                    strings: strings,
+                    condition: condition,
+                });
+            },
+            _ => return Err(c.error(format!("unknown section {:?} in rule {}", section, name))),
+        }
+    }
+}
+
+// the rules of a YARA source, the imports and the includes skipped
+pub fn parse(source: &str) -> Result<Vec<YaraRule>> {
+
+    let mut c = Cursor {
+        text: source.as_bytes(),
+        at: 0,
+    };
+    let mut rules = Vec::new();
+    loop {
+        let word = c.word();
+        match word.as_str() {
+            "" if c.peek().is_none() => return Ok(rules),
+            "import" | "include" => {
+                try!(c.literal());
+            },
+            "private" | "global" => {},
+            "rule" => rules.push(try!(rule(&mut c))),
+            "" => return Err(c.error(format!("unexpected {:?}", c.peek().unwrap() as char))),
+            word => return Err(c.error(format!("unexpected {}", word))),
+        }
+    }
+}
+
+pub fn read_rules(path: &str) -> Result<Vec<YaraRule>> {
+
+    let mut source = String::new();
+    let read = OpenOptions::new().read(true).open(path).and_then(|mut f| f.read_to_string(&mut source));
+    try!(read.map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
+    parse(&source).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn tokens(condition: &str) -> Vec<String> {
+
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = condition.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        i += 1;
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '$' || c == '#' || c == '@' || c == '!' || c.is_alphanumeric() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '*' || chars[i] == '.') {
+                i += 1;
+            }
+        } else if c == '"' {
+            while i < chars.len() && chars[i] != '"' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(chars.len());
+        }
+        tokens.push(chars[start .. i].iter().collect());
+    }
+    tokens
+}
+
+// the parts of the tokens separated by this word, out of the parentheses
+fn split<'t>(tokens: &'t [String], word: &str) -> Vec<&'t [String]> {
+
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0i32, 0);
+    for (i, token) in tokens.iter().enumerate() {
+        match token.as_str() {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            t if t == word && depth == 0 => {
+                parts.push(&tokens[start .. i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    parts.push(&tokens[start ..]);
+    parts
+}
+
+// the strings of a set: them, or ($a, $b*)
+fn string_set(tokens: &[String], strings: &[YaraString]) -> Vec<usize> {
+
+    if tokens.first().map(|t| t.as_str()) == Some("them") {
+        return (0 .. strings.len()).collect();
+    }
+    let mut set = Vec::new();
+    for token in tokens.iter().filter(|t| t.starts_with('$')) {
+        for (i, s) in strings.iter().enumerate() {
+            let matches = match token.ends_with('*') {
+                true => s.name.starts_with(&token[.. token.len() - 1]),
+                false => s.name == *token,
+            };
+            if matches && !set.contains(&i) {
+                set.push(i);
+            }
+        }
+    }
+    set
+}
+
+fn operand(tokens: &[String], strings: &[YaraString]) -> Filter {
+
+    let first = tokens.first().map_or("", |t| t.as_str());
+    // (...) as a whole
+    if first == "(" && tokens.last().map(|t| t.as_str()) == Some(")") {
+        let inner = &tokens[1 .. tokens.len() - 1];
+        if inner.iter().fold(Some(0i32), |d, t| d.and_then(|d| match t.as_str() {
+            "(" => Some(d + 1),
+            ")" if d == 0 => None,
+            ")" => Some(d - 1),
+            _ => Some(d),
+        })) == Some(0) {
+            return expression(inner, strings);
+        }
+    }
+    // all of them, any of ($a*), 2 of ($a, $b), 50% of them
+    let of = match tokens.get(1).map(|t| t.as_str()) {
+        Some("of") => Some(2),
+        Some("%") if tokens.get(2).map(|t| t.as_str()) == Some("of") => Some(3),
+        _ => None,
+    };
+    if let Some(set_at) = of {
+        let set = string_set(&tokens[set_at ..], strings).into_iter().map(Filter::Str).collect::<Vec<_>>();
+        if set.is_empty() {
+            return Filter::Every;
+        }
+        return match first {
+            "all" => Filter::And(set),
+            "none" | "0" => Filter::Every,
+            _ => Filter::Or(set),
+        };
+    }
+    // $a, $a at 0, $a in (0..100)
+    if first.starts_with('$') && !first.ends_with('*') && (tokens.len() == 1 || ["at", "in"].contains(&tokens[1].as_str())) {
+        if let Some(i) = strings.iter().position(|s| s.name == first) {
+            return Filter::Str(i);
+        }
+    }
+    Filter::Every
+}
+
+fn expression(tokens: &[String], strings: &[YaraString]) -> Filter {
+
+    let mut any = Vec::new();
+    for part in split(tokens, "or") {
+        let all: Vec<Filter> = split(part, "and").into_iter().map(|t| operand(t, strings)).collect();
+        any.push(match all.len() {
+            1 => all.into_iter().next().unwrap(),
+            _ => Filter::And(all),
+        });
+    }
+    match any.len() {
+        1 => any.into_iter().next().unwrap(),
+        _ => Filter::Or(any),
+    }
+}
+
+// the filter of the condition of a rule
+pub fn filter(rule: &YaraRule) -> Filter {
+    expression(&tokens(&rule.condition), &rule.strings)
+}
+
+// the ids of the files that may have a string, its pieces all found
+fn string_ids(db: &mut BinacleManager, needle: &Needle) -> Result<Option<BTreeSet<u32>>> {
+
+    let requests = match *needle {
+        Needle::Text(ref bytes, width) => vec![match width {
+            Width::Ascii => SearchRequest::pattern(bytes),
+            Width::Wide => SearchRequest::pattern(bytes).wide(),
+            Width::Both => SearchRequest::pattern(bytes).both_widths(),
+        }],
+        Needle::Hex(ref pieces) => pieces.iter().map(|&(ref bytes, ref wildcards)| {
+            wildcards.iter().fold(SearchRequest::pattern(bytes), |request, &w| request.wildcard(w))
+        }).collect(),
+        Needle::Ignored(_) => return Ok(None),
+    };
+    let mut ids: Option<BTreeSet<u32>> = None;
+    for request in requests {
+        // a shard not searched would leave candidates out
+        let found: BTreeSet<u32> = try!(db.run(&request.strict())).ids.into_iter().collect();
+        ids = Some(match ids {
+            Some(ids) => ids.intersection(&found).cloned().collect(),
+            None => found,
+        });
+    }
+    Ok(ids)
+}
+
+// None when all the files are candidates
+fn evaluate(db: &mut BinacleManager, filter: &Filter, rule: &YaraRule, found: &mut HashMap<usize, Option<BTreeSet<u32>>>)
+            -> Result<Option<BTreeSet<u32>>> {
+
+    match *filter {
+        Filter::Every => Ok(None),
+        Filter::Str(i) => {
+            if !found.contains_key(&i) {
+                let ids = try!(string_ids(db, &rule.strings[i].needle));
+                found.insert(i, ids);
+            }
+            Ok(found[&i].clone())
+        },
+        Filter::And(ref filters) => {
+            let mut ids: Option<BTreeSet<u32>> = None;
+            for f in filters {
+                if let Some(other) = try!(evaluate(db, f, rule, found)) {
+                    ids = Some(match ids {
+                        Some(ids) => ids.intersection(&other).cloned().collect(),
+                        None => other,
+                    });
+                }
+            }
+            Ok(ids)
+        },
+        Filter::Or(ref filters) => {
+            let mut ids = BTreeSet::new();
+            for f in filters {
+                match try!(evaluate(db, f, rule, found)) {
+                    Some(other) => ids.extend(other),
+                    None => return Ok(None),
+                }
+            }
+            Ok(Some(ids))
+        },
+    }
+}
+
+// the candidates of each rule, in the order of the rules
+pub fn prefilter(db: &mut BinacleManager, rules: &[YaraRule]) -> Result<Vec<RuleCandidates>> {
+
+    let mut report = Vec::new();
+    for rule in rules {
+        let mut found = HashMap::new();
+        let ids = try!(evaluate(db, &filter(rule), rule, &mut found));
+        report.push(RuleCandidates {
+            rule: rule.name.clone(),
+            ids: ids.map(|ids| ids.into_iter().collect()),
+            searched: found.values().filter(|ids| ids.is_some()).count(),
+            ignored: rule.strings.iter().filter_map(|s| match s.needle {
+                Needle::Ignored(ref reason) => Some((s.name.clone(), reason.clone())),
+                _ => None,
+            }).collect(),
+        });
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::File;
+    use binacle_manager::tests::cleanup;
+
+    const RULES: &'static str = r#"
+import "pe"
+
+/* a comment { with braces } */
+private rule Loader : dropper apt {
+    meta:
+        author = "someone \"quoted\""
+        version = 2
+        score = -1
+    strings:
+        $api = "GetProcAddress" // the import
+        $w = "kernel32" wide ascii
+        $short = "MZ"
+        $re = /https?:\/\/[a-z]{3,}\//
+        $hex = { 4D 5A 90 00 ?? 00 [2-4] 50 45 00 00 ( 4C 01 | 64 86 ) 0B 01 }
+        $case = "LoadLibrary" nocase
+    condition:
+        uint16(0) == 0x5A4D and all of them
+}
+
+rule Any {
+    strings:
+        $ = "abcd\x41\n"
+        $b = { E8 ?? ?? ?? ?? 5D C3 }
+    condition:
+        any of them
+}
+"#;
+
+    #[test]
+    fn rules() {
+
+        let rules = parse(RULES).unwrap();
+        assert_eq!(2, rules.len());
+        assert_eq!("Loader", rules[0].name);
+        assert_eq!("uint16(0) == 0x5A4D and all of them", rules[0].condition);
+
+        let needles: Vec<&Needle> = rules[0].strings.iter().map(|s| &s.needle).collect();
+        assert_eq!(&Needle::Text(b"GetProcAddress".to_vec(), Width::Ascii), needles[0]);
+        assert_eq!(&Needle::Text(b"kernel32".to_vec(), Width::Both), needles[1]);
+        assert_eq!(&Needle::Ignored(String::from("shorter than 4 bytes")), needles[2]);
+        assert_eq!(&Needle::Ignored(String::from("regular expression")), needles[3]);
+        // cut at the jump and at the alternative, the piece of 2 bytes dropped
+        assert_eq!(&Needle::Hex(vec![(vec![0x4d, 0x5a, 0x90, 0, 0, 0], vec![4]), (vec![0x50, 0x45, 0, 0], vec![])]), needles[4]);
+        assert_eq!(&Needle::Ignored(String::from("nocase string")), needles[5]);
+
+        assert_eq!("$", rules[1].strings[0].name);
+        assert_eq!(Needle::Text(b"abcdA\n".to_vec(), Width::Ascii), rules[1].strings[0].needle);
+        assert_eq!(Needle::Ignored(String::from("no 4 known bytes in a row")), rules[1].strings[1].needle);
+
+        assert!(parse("rule { condition: true }").unwrap_err().to_string().contains("rule without a name"));
+        assert!(parse("rule a {\n strings:\n $a = { 4D 5Z }\n condition: $a }").unwrap_err().to_string().starts_with("line 3: $a: invalid byte 5Z"));
+    }
+
+    #[test]
+    fn conditions() {
+
+        let rule = |condition: &str| YaraRule {
+            name: String::from("r"),
+            strings: ["$a1", "$a2", "$b"].iter().map(|n| YaraString {
+                name: String::from(*n),
+                needle: Needle::Text(b"abcd".to_vec(), Width::Ascii),
+            }).collect(),
+            condition: String::from(condition),
+        };
+        let all = || Filter::And(vec![Filter::Str(0), Filter::Str(1), Filter::Str(2)]);
+        let any = || Filter::Or(vec![Filter::Str(0), Filter::Str(1), Filter::Str(2)]);
+
+        assert_eq!(all(), filter(&rule("all of them")));
+        assert_eq!(any(), filter(&rule("any of them")));
+        assert_eq!(any(), filter(&rule("2 of them")));
+        assert_eq!(any(), filter(&rule("50% of them")));
+        assert_eq!(Filter::Or(vec![Filter::Str(0), Filter::Str(1)]), filter(&rule("any of ($a*)")));
+        assert_eq!(Filter::And(vec![Filter::Str(0), Filter::Str(2)]), filter(&rule("all of ($a1, $b)")));
+        assert_eq!(Filter::And(vec![Filter::Every, Filter::Str(2)]), filter(&rule("filesize < 1MB and $b at 0")));
+        assert_eq!(Filter::Or(vec![Filter::Str(0), Filter::And(vec![Filter::Str(1), Filter::Str(2)])]),
+                   filter(&rule("$a1 or ($a2 and $b)")));
+        assert_eq!(Filter::Or(vec![Filter::Every, Filter::Str(2)]), filter(&rule("not $a1 or $b")));
+        assert_eq!(Filter::Every, filter(&rule("#a1 > 2")));
+        assert_eq!(Filter::Every, filter(&rule("none of them")));
+        assert_eq!(Filter::Every, filter(&rule("any of ($c*)")));
+        assert_eq!(Filter::Every, filter(&rule("pe.imports(\"kernel32.dll\", \"or\")")));
+        assert_eq!(Filter::Or(vec![Filter::Every, Filter::Str(2)]), filter(&rule("(filesize > 10) or ($b)")));
+    }
+
+    #[test]
+    fn candidates() {
+
+        let files: [&[u8]; 4] = [b"MZ\x90\x00 GetProcAddress k\x00e\x00r\x00n\x00e\x00l\x003\x002\x00",
+                                 b"GetProcAddress kernel32",
+                                 b"LoadLibraryA abcdA\n",
+                                 b"nothing"];
+        let rules = parse(r#"
+rule All { strings: $a = "GetProcAddress" $b = "kernel32" wide condition: all of them }
+rule Any { strings: $a = "GetProcAddress" $b = "abcd\x41\n" condition: any of them }
+rule Nocase { strings: $a = "GetProcAddress" $b = "loadlibrary" nocase condition: $a or $b }
+rule Header { strings: $a = "LoadLibrary" $r = /Get.*Address/ condition: $a and $r }
+"#).unwrap();
+        {
+            let mut db = BinacleManager::create("test_yara.db", true, 1 << 30, 6, 16).unwrap();
+            for (i, content) in files.iter().enumerate() {
+                let path = format!("test_yara_{}.bin", i + 1);
+                File::create(&path).unwrap().write_all(content).unwrap();
+                db.insert_file(&path, i as u32 + 1, true).unwrap();
+            }
+            let report = prefilter(&mut db, &rules).unwrap();
+            assert_eq!(Some(vec![1]), report[0].ids);
+            assert_eq!(2, report[0].searched);
+            assert_eq!(Some(vec![1, 2, 3]), report[1].ids);
+            // the nocase string can be anywhere
+            assert_eq!(None, report[2].ids);
+            assert_eq!(vec![(String::from("$b"), String::from("nocase string"))], report[2].ignored);
+            // the regular expression does not filter, the other string does
+            assert_eq!(Some(vec![3]), report[3].ids);
+        }
+        cleanup("test_yara");
+        for i in 1 .. 5 {
+            let _ = ::std::fs::remove_file(format!("test_yara_{}.bin", i));
+        }
+    }
+}
@@ -0,0 +1,235 @@
+extern crate binacle;
+extern crate rustc_serialize;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use rustc_serialize::json::Json;
+
+use binacle::binacle_manager::BinacleManager;
+use binacle::search::SearchRequest;
+
+// build a small map database in a fresh directory
+fn setup(name: &str) -> (PathBuf, String) {
+
+    let dir = env::temp_dir().join(format!("binacle_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("corpus")).unwrap();
+
+    let files: &[(&str, &[u8])] = &[
+        ("a.bin", b"xx GetProcAddress LoadLibraryA yy"),
+        ("b.bin", b"GetProcAddress only"),
+        ("c.bin", b"LoadLibraryA only"),
+        ("d.bin", b"GetPr etPro tProc rocAd ocAdd cAddr Address"),
+    ];
+    for &(name, content) in files {
+        File::create(dir.join("corpus").join(name)).unwrap().write_all(content).unwrap();
+    }
+
+    let db = dir.join("test.db").to_str().unwrap().to_string();
+    {
+        let mut m = BinacleManager::create(&db, true, 1 << 30, 6, 16).unwrap();
+        m.insert_dir_recursive(dir.join("corpus").to_str().unwrap()).unwrap();
+    }
+    (dir, db)
+}
+
+fn cli(args: &[&str]) -> Vec<String> {
+    let out = Command::new(env!("CARGO_BIN_EXE_binacle")).args(args).output().unwrap();
+    assert!(out.status.success());
+    // {"ids", "paths", "count"}
+    let hits = Json::from_str(String::from_utf8(out.stdout).unwrap().trim()).unwrap();
+    let mut paths: Vec<String> = hits["paths"].as_array().unwrap().iter().map(|p| p.as_string().unwrap().to_string()).collect();
+    paths.sort();
+    paths
+}
+
+fn builder(db: &str, request: &SearchRequest) -> Vec<String> {
+    let mut m = BinacleManager::open(db).unwrap();
+    let mut paths = m.run(request).unwrap().entries;
+    paths.sort();
+    paths
+}
+
+#[test]
+fn cli_and_builder_agree() {
+
+    let (dir, db) = setup("agree");
+
+    let cases: Vec<(Vec<&str>, SearchRequest)> = vec![
+        (vec!["-s", "GetProcAddress"], SearchRequest::pattern(b"GetProcAddress")),
+        (vec!["-s", "exact", "GetProcAddress"], SearchRequest::pattern(b"GetProcAddress").exact()),
+        (vec!["-s", "GetProcAddress", "--exclude=LoadLibraryA"],
+            SearchRequest::pattern(b"GetProcAddress").exclude(b"LoadLibraryA")),
+        (vec!["-s", "hex", "4c6f61644c696272617279"], SearchRequest::pattern(b"LoadLibrary")),
+        (vec!["-s", "GetProcAddress", "--limit=1"], SearchRequest::pattern(b"GetProcAddress").limit(1)),
+    ];
+
+    for (args, request) in cases {
+        let mut full = vec![db.as_str()];
+        full.extend(args.iter());
+        full.push("--json");
+        let expected = builder(&db, &request);
+        assert!(!expected.is_empty());
+        assert_eq!(expected, cli(&full), "{:?}", args);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn response_flags() {
+
+    let (dir, db) = setup("flags");
+    {
+        let mut m = BinacleManager::open(&db).unwrap();
+
+        let all = m.run(&SearchRequest::pattern(b"GetProcAddress")).unwrap();
+        assert_eq!(3, all.total);
+        assert!(!all.truncated);
+        assert!(all.explain.is_none());
+
+        let exact = m.run(&SearchRequest::pattern(b"GetProcAddress").exact()).unwrap();
+        assert_eq!(2, exact.total);
+
+        let limited = m.run(&SearchRequest::pattern(b"GetProcAddress").limit(2).explain()).unwrap();
+        assert_eq!(3, limited.total);
+        assert_eq!(2, limited.ids.len());
+        assert!(limited.truncated);
+        assert!(limited.explain.unwrap().len() > 0);
+
+        let range = m.run(&SearchRequest::pattern(b"GetProcAddress").id_range(0..=0)).unwrap();
+        assert_eq!(0, range.total);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exit_codes() {
+
+    let (dir, db) = setup("codes");
+    let run = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_binacle")).args(args).output().unwrap();
+        (out.status.code().unwrap(), String::from_utf8(out.stderr).unwrap())
+    };
+
+    assert_eq!(0, run(&[&db, "-s", "GetProcAddress"]).0);
+    assert_eq!(1, run(&[&db, "-s", "NotInTheCorpus"]).0);
+
+    // the command line
+    let (code, stderr) = run(&[&db, "-f", "twelve", "a.bin"]);
+    assert_eq!(64, code);
+    assert_eq!(1, stderr.lines().filter(|l| l.starts_with("Error: ")).count(), "{}", stderr);
+    assert!(stderr.contains("invalid <id>: \"twelve\""), "{}", stderr);
+    let (code, stderr) = run(&[&db, "-s", "hex", "4d5g"]);
+    assert_eq!(64, code);
+    assert!(stderr.contains("invalid hex pattern 4d5g"), "{}", stderr);
+    assert_eq!(64, run(&[&db, "-s", "GetProcAddress", "--limit=many"]).0);
+    assert_eq!(64, run(&[&db, "--no-such-option"]).0);
+
+    // the files
+    let missing = dir.join("missing.db").to_str().unwrap().to_string();
+    let (code, stderr) = run(&[&missing, "-s", "GetProcAddress"]);
+    assert_eq!(74, code);
+    assert!(stderr.contains(&format!("{}: ", missing)), "{}", stderr);
+    File::create(&db).unwrap().write_all(b"{\"nb_index\":").unwrap();
+    let (code, stderr) = run(&[&db, "-s", "GetProcAddress"]);
+    assert_eq!(74, code);
+    assert_eq!(1, stderr.lines().count(), "{}", stderr);
+    assert!(stderr.contains(&format!("{}: ", db)), "{}", stderr);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// the widest n-grams: the header of 2^32 slots does not fit 32 bits, the
+// reduction of the n-grams is the identity
+#[test]
+fn widest_ngram_sizes() {
+
+    let dir = env::temp_dir().join(format!("binacle_widest_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let sample = dir.join("a.bin");
+    File::create(&sample).unwrap().write_all(b"xx GetProcAddress LoadLibraryA yy").unwrap();
+    let sample = sample.to_str().unwrap();
+
+    for &(ngram_size, header_size) in &[(31u8, 10737418304u64), (32, 21474836544)] {
+        let db = dir.join(format!("n{}.db", ngram_size)).to_str().unwrap().to_string();
+        let out = Command::new(env!("CARGO_BIN_EXE_binacle"))
+            .args(&["-c", &db, "map", "68719476736", "6", &ngram_size.to_string()]).output().unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        let out = Command::new(env!("CARGO_BIN_EXE_binacle")).args(&[&db, "-f", "1", sample]).output().unwrap();
+        assert!(out.status.success(), "{:?}", out);
+
+        assert_eq!(vec![sample.to_string()], cli(&[&db, "-s", "GetProcAddress", "--json"]));
+        assert_eq!(vec![sample.to_string()], builder(&db, &SearchRequest::pattern(b"LoadLibraryA").exact()));
+        assert!(builder(&db, &SearchRequest::pattern(b"NotInTheCorpus")).is_empty());
+
+        let out = Command::new(env!("CARGO_BIN_EXE_binacle")).args(&[&db, "--stats", "--json"]).output().unwrap();
+        let stats = Json::from_str(String::from_utf8(out.stdout).unwrap().trim()).unwrap();
+        assert_eq!(Some(header_size), stats["header_size"].as_u64());
+        assert_eq!(header_size, BinacleManager::open(&db).unwrap().header_size());
+        // one list a distinct n-gram, none shared by a reduction
+        let shard = &stats["shards"][0];
+        assert_eq!(Some(30), shard["lists"].as_u64());
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// a server dropping root still answers and reloads, run as root only
+#[test]
+fn serve_in_sandbox() {
+
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpStream;
+    use std::process::Stdio;
+
+    let uid = Command::new("id").arg("-u").output().map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+    if uid.ok().as_ref().map(String::as_str) != Some("0") {
+        return;
+    }
+
+    let dir = env::temp_dir().join(format!("binacle_sandbox_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    File::create(dir.join("a.bin")).unwrap().write_all(b"xx GetProcAddress yy").unwrap();
+    File::create(dir.join("b.bin")).unwrap().write_all(b"LoadLibraryA only").unwrap();
+    let binacle = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_binacle")).args(args).current_dir(&dir).output().unwrap();
+        assert!(out.status.success(), "{:?}", out);
+    };
+    // the paths of the database relative to the directory served
+    binacle(&["-c", "t.db", "map", "1", "6", "16"]);
+    binacle(&["t.db", "-f", "1", "a.bin"]);
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_binacle"))
+        .args(&["t.db", "--serve", "127.0.0.1:0", "--user=65534", "--group=65534", "--chroot=."])
+        .current_dir(&dir).stderr(Stdio::piped()).spawn().unwrap();
+    let mut line = String::new();
+    BufReader::new(server.stderr.as_mut().unwrap()).read_line(&mut line).unwrap();
+    assert!(line.contains("served on"), "{}", line);
+    let addr = line.trim().rsplit(' ').next().unwrap().to_string();
+
+    let post = |target: &str, body: &str| {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        write!(stream, "POST {} HTTP/1.0\r\nContent-Length: {}\r\n\r\n{}", target, body.len(), body).unwrap();
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.0 200"), "{}", answer);
+        Json::from_str(&answer[answer.find("\r\n\r\n").unwrap() + 4 ..]).unwrap()
+    };
+    assert_eq!(Some(1), post("/search", "GetProcAddress")[0]["id"].as_u64());
+
+    // the new shard of a writer opened in the sandbox
+    binacle(&["t.db", "-f", "2", "b.bin"]);
+    assert_eq!(Some(true), post("/reload", "")["reloaded"].as_boolean());
+    assert_eq!(Some(2), post("/search", "LoadLibraryA")[0]["id"].as_u64());
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}